@@ -0,0 +1,58 @@
+use bytes::BytesMut;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use how_to_create_bgp::bgp_type::Ipv4Network;
+use how_to_create_bgp::packets::message::Message;
+use how_to_create_bgp::packets::update::UpdateMessage;
+use how_to_create_bgp::path_attribute::{AsPath, Origin, PathAttribute};
+use std::net::Ipv4Addr;
+
+/// 1つのUpdateMessageに含めるNLRIの数。900個の/24で最大長4096 octetsに近くなる。
+const NLRI_COUNTS: [usize; 3] = [1, 100, 900];
+
+fn networks(n: usize) -> Vec<Ipv4Network> {
+    (0..n as u32)
+        .map(|i| {
+            let addr = Ipv4Addr::from(0x0a00_0000 | (i << 8));
+            ipnetwork::Ipv4Network::new(addr, 24).unwrap().into()
+        })
+        .collect()
+}
+
+fn update_message(n: usize) -> UpdateMessage {
+    UpdateMessage::new(
+        vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::AsSequence(vec![64513.into(), 64514.into()])),
+            PathAttribute::NextHop("10.200.100.3".parse().unwrap()),
+        ],
+        networks(n),
+        vec![],
+    )
+}
+
+fn encode_update(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode_update");
+    for n in NLRI_COUNTS {
+        let update = update_message(n);
+        group.throughput(Throughput::Elements(n as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &update, |b, update| {
+            b.iter(|| BytesMut::from(black_box(update.clone())))
+        });
+    }
+    group.finish();
+}
+
+fn decode_update(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode_update");
+    for n in NLRI_COUNTS {
+        let bytes = BytesMut::from(update_message(n)).freeze();
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &bytes, |b, bytes| {
+            b.iter(|| Message::try_from(black_box(bytes.clone())).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, encode_update, decode_update);
+criterion_main!(benches);
@@ -0,0 +1,120 @@
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput,
+};
+use how_to_create_bgp::aspa::AspaState;
+use how_to_create_bgp::config::Config;
+use how_to_create_bgp::packets::update::UpdateMessage;
+use how_to_create_bgp::path_attribute::{AsPath, Origin, PathAttribute};
+use how_to_create_bgp::routing::{AdjRibIn, AdjRibOut, LocRib, RibEntry};
+use how_to_create_bgp::update_group::NegotiatedCapabilities;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+/// フルルートの規模まで、ルートの数を変えて測定する。
+const ROUTE_COUNTS: [usize; 3] = [1_000, 100_000, 1_000_000];
+
+/// 10.0.0.0/24から順に並べたn個の/24のルート。Path Attributeは全ルートで共有する。
+fn routes(n: usize) -> Vec<RibEntry> {
+    let path_attributes = Arc::new(vec![
+        PathAttribute::Origin(Origin::Igp),
+        PathAttribute::AsPath(AsPath::AsSequence(vec![64513.into()])),
+        PathAttribute::NextHop("10.200.100.3".parse().unwrap()),
+    ]);
+    (0..n as u32)
+        .map(|i| RibEntry {
+            network_address: ipnetwork::Ipv4Network::new(
+                Ipv4Addr::from(0x0a00_0000 + (i << 8)),
+                24,
+            )
+            .unwrap()
+            .into(),
+            path_attributes: Arc::clone(&path_attributes),
+            aspa_state: AspaState::default(),
+            weight: 0,
+            provenance: None,
+        })
+        .collect()
+}
+
+fn loc_rib(n: usize) -> LocRib {
+    let mut loc_rib = LocRib::empty();
+    loc_rib.install_from_adj_rib_in(&mut AdjRibIn::from(routes(n)));
+    loc_rib
+}
+
+fn loc_rib_insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("loc_rib_insert");
+    group.sample_size(10);
+    for n in ROUTE_COUNTS {
+        let routes = routes(n);
+        group.throughput(Throughput::Elements(n as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &routes, |b, routes| {
+            b.iter_batched(
+                || (LocRib::empty(), AdjRibIn::from(routes.clone())),
+                |(mut loc_rib, mut adj_rib_in)| {
+                    loc_rib.install_from_adj_rib_in(&mut adj_rib_in);
+                    loc_rib
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn loc_rib_lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("loc_rib_longest_match");
+    for n in ROUTE_COUNTS {
+        let loc_rib = loc_rib(n);
+        // 登録したルートに含まれるアドレスと、どのルートにも含まれないアドレスを交互に引く。
+        let addresses: Vec<Ipv4Addr> = (0..1000u32)
+            .map(|i| Ipv4Addr::from(0x0a00_0001 + (((i * 7919) % n as u32) << 8)))
+            .chain((0..1000u32).map(|i| Ipv4Addr::from(0xc0a8_0000 + i)))
+            .collect();
+        group.throughput(Throughput::Elements(addresses.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(n),
+            &addresses,
+            |b, addresses| {
+                b.iter(|| {
+                    for address in addresses {
+                        black_box(loc_rib.lookup(*address));
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn adj_rib_out_generation(c: &mut Criterion) {
+    let config: Config = "64512 10.200.100.2 64513 10.200.100.3 active"
+        .parse()
+        .unwrap();
+    let mut group = c.benchmark_group("adj_rib_out_generation");
+    group.sample_size(10);
+    for n in ROUTE_COUNTS {
+        let loc_rib = loc_rib(n);
+        group.throughput(Throughput::Elements(n as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &loc_rib, |b, loc_rib| {
+            b.iter(|| {
+                let mut adj_rib_out = AdjRibOut::new();
+                adj_rib_out.install_from_loc_rib(
+                    loc_rib,
+                    &config,
+                    NegotiatedCapabilities::default(),
+                );
+                Vec::<UpdateMessage>::from(&adj_rib_out)
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    loc_rib_insert,
+    loc_rib_lookup,
+    adj_rib_out_generation
+);
+criterion_main!(benches);
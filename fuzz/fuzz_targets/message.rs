@@ -0,0 +1,10 @@
+#![no_main]
+
+use bytes::BytesMut;
+use how_to_create_bgp::packets::message::Message;
+use libfuzzer_sys::fuzz_target;
+
+// 受信したbytes列は、どのような内容でもpanicせずにMessageかエラーになる。
+fuzz_target!(|data: &[u8]| {
+    let _ = Message::try_from(BytesMut::from(data));
+});
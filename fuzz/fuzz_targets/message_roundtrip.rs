@@ -0,0 +1,16 @@
+#![no_main]
+
+use bytes::BytesMut;
+use how_to_create_bgp::packets::header::MAXIMUM_MESSAGE_LENGTH;
+use how_to_create_bgp::packets::message::Message;
+use libfuzzer_sys::fuzz_target;
+
+// Arbitraryで生成したMessageは、bytes列にしてから読み取ると元のMessageに戻る。
+fuzz_target!(|message: Message| {
+    let bytes: BytesMut = message.clone().into();
+    if bytes.len() > MAXIMUM_MESSAGE_LENGTH as usize {
+        // 最大長を超えるMessageは送信できないため対象外とする。
+        return;
+    }
+    assert_eq!(Message::try_from(bytes).unwrap(), message);
+});
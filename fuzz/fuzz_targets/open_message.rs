@@ -0,0 +1,9 @@
+#![no_main]
+
+use bytes::BytesMut;
+use how_to_create_bgp::packets::open::OpenMessage;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = OpenMessage::try_from(BytesMut::from(data));
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use bytes::BytesMut;
+use how_to_create_bgp::packets::update::UpdateMessage;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(update) = UpdateMessage::try_from(BytesMut::from(data)) {
+        // 読み取れたUpdateMessageは、bytes列に戻してもう一度読み取れる。
+        let bytes: BytesMut = update.clone().into();
+        assert_eq!(UpdateMessage::try_from(bytes).unwrap(), update);
+    }
+});
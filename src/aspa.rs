@@ -0,0 +1,253 @@
+use crate::bgp_type::AutonomousSystemNumber;
+use crate::error::ConfigParseError;
+use crate::path_attribute::AsPath;
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::str::FromStr;
+
+/// ASPA(Autonomous System Provider Authorization)によるAS Pathの検証結果。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord, Default)]
+pub enum AspaState {
+    Valid,
+    Invalid,
+    #[default]
+    Unknown,
+}
+
+impl FromStr for AspaState {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "valid" => Ok(AspaState::Valid),
+            "invalid" => Ok(AspaState::Invalid),
+            "unknown" => Ok(AspaState::Unknown),
+            _ => Err(ConfigParseError::InvalidValue {
+                kind: "aspa state",
+                value: s.to_owned(),
+            }),
+        }
+    }
+}
+
+/// 対向機器が自分から見てどういう関係のASであるか。
+/// ASPAの検証では、Providerから受信したルートとそれ以外から受信したルートで検証方法が異なる。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord, Default)]
+pub enum PeerRole {
+    Customer,
+    #[default]
+    Peer,
+    Provider,
+}
+
+impl FromStr for PeerRole {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "customer" => Ok(PeerRole::Customer),
+            "peer" => Ok(PeerRole::Peer),
+            "provider" => Ok(PeerRole::Provider),
+            _ => Err(ConfigParseError::InvalidValue {
+                kind: "peer role",
+                value: s.to_owned(),
+            }),
+        }
+    }
+}
+
+/// 隣り合う2つのASの関係を、ASPAで確認した結果。
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum Hop {
+    /// 上流側のASが、下流側のASのASPAにProviderとして含まれている。
+    Provider,
+    /// 下流側のASのASPAはあるが、上流側のASが含まれていない。
+    NotProvider,
+    /// 下流側のASのASPAがない。
+    NoAttestation,
+}
+
+/// Customer AS番号ごとの、Provider AS番号の集合。
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct AspaTable(HashMap<AutonomousSystemNumber, HashSet<AutonomousSystemNumber>>);
+
+impl AspaTable {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn insert(
+        &mut self,
+        customer: AutonomousSystemNumber,
+        providers: impl IntoIterator<Item = AutonomousSystemNumber>,
+    ) {
+        self.0.entry(customer).or_default().extend(providers);
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// `Customer AS番号 Provider AS番号...`を1行に1つ並べたファイルからASPAを読み込む。
+    /// `#`から行末まではコメントとして扱う。
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .context(format!("ASPAのファイル{:?}を読み込めませんでした。", path))?;
+        contents.parse()
+    }
+
+    /// customerのASPAにproviderが含まれているか。
+    fn hop(&self, customer: AutonomousSystemNumber, provider: AutonomousSystemNumber) -> Hop {
+        match self.0.get(&customer) {
+            Some(providers) if providers.contains(&provider) => Hop::Provider,
+            Some(_) => Hop::NotProvider,
+            None => Hop::NoAttestation,
+        }
+    }
+
+    /// roleの対向機器から受信したAS Pathを検証する。
+    /// AS Pathの先頭が対向機器のAS、末尾がルートを生成したASである。
+    pub fn verify(&self, as_path: &AsPath, role: PeerRole) -> AspaState {
+        let as_numbers = match as_path {
+            AsPath::AsSequence(as_numbers) => as_numbers,
+            // AS_SETを含むAS Pathは検証できないためInvalidとする。
+            AsPath::AsSet(_) => return AspaState::Invalid,
+        };
+        // prependされたAS番号は1つにまとめ、ルートを生成したASから順に並べる。
+        let mut path: Vec<AutonomousSystemNumber> = as_numbers.iter().rev().copied().collect();
+        path.dedup();
+        let n = path.len();
+        if n <= 1 {
+            return AspaState::Valid;
+        }
+
+        // up ramp: ルートを生成したASから、CustomerからProviderへ向かう区間。
+        let up: Vec<Hop> = path.windows(2).map(|w| self.hop(w[0], w[1])).collect();
+        // down ramp: 対向機器のASから逆順に辿り、ProviderからCustomerへ向かう区間。
+        let down: Vec<Hop> = path
+            .windows(2)
+            .rev()
+            .map(|w| self.hop(w[1], w[0]))
+            .collect();
+        let ramp = |hops: &[Hop], accept: fn(Hop) -> bool| {
+            1 + hops.iter().take_while(|h| accept(**h)).count()
+        };
+        let max_up = ramp(&up, |h| h != Hop::NotProvider);
+        let min_up = ramp(&up, |h| h == Hop::Provider);
+
+        match role {
+            PeerRole::Customer | PeerRole::Peer => {
+                if max_up < n {
+                    AspaState::Invalid
+                } else if min_up == n {
+                    AspaState::Valid
+                } else {
+                    AspaState::Unknown
+                }
+            }
+            PeerRole::Provider => {
+                let max_down = ramp(&down, |h| h != Hop::NotProvider);
+                let min_down = ramp(&down, |h| h == Hop::Provider);
+                if max_up + max_down < n {
+                    AspaState::Invalid
+                } else if min_up + min_down >= n {
+                    AspaState::Valid
+                } else {
+                    AspaState::Unknown
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for AspaTable {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut table = AspaTable::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut as_numbers = line.split_whitespace().map(|a| {
+                a.parse::<u32>()
+                    .map(AutonomousSystemNumber::from)
+                    .context(format!(
+                        "{}行目の`{}`をAS番号にparseできませんでした。",
+                        i + 1,
+                        a
+                    ))
+            });
+            let customer = match as_numbers.next() {
+                Some(customer) => customer?,
+                None => continue,
+            };
+            let providers = as_numbers.collect::<Result<Vec<_>>>()?;
+            table.insert(customer, providers);
+        }
+        Ok(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn as_path(as_numbers: &[u32]) -> AsPath {
+        AsPath::AsSequence(as_numbers.iter().map(|a| (*a).into()).collect())
+    }
+
+    fn table() -> AspaTable {
+        // 64514 -> 64513 -> 64512 の順にCustomerからProviderへつながっている。
+        // 64515は64512のCustomerである。
+        "64514 64513\n64513 64512 # comment\n64515 64512\n64512\n"
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn load_aspa_table_from_text() {
+        assert_eq!(table().len(), 4);
+        assert!(!table().is_empty());
+        assert!(AspaTable::new().is_empty());
+        assert!("64512 abc".parse::<AspaTable>().is_err());
+    }
+
+    #[test]
+    fn verify_upstream_path() {
+        let table = table();
+        // 64513から、64514が生成したルートを受信した。
+        assert_eq!(
+            table.verify(&as_path(&[64513, 64514, 64514]), PeerRole::Customer),
+            AspaState::Valid
+        );
+        // 64514は64515をProviderとして登録していない(route leak)。
+        assert_eq!(
+            table.verify(&as_path(&[64515, 64514]), PeerRole::Customer),
+            AspaState::Invalid
+        );
+        // ルートを生成した64517のASPAはない。
+        assert_eq!(
+            table.verify(&as_path(&[64516, 64517]), PeerRole::Peer),
+            AspaState::Unknown
+        );
+    }
+
+    #[test]
+    fn verify_downstream_path() {
+        let table = table();
+        // 64514 -> 64513 -> 64512と上り、64512 -> 64515と下って受信した。
+        assert_eq!(
+            table.verify(&as_path(&[64515, 64512, 64513, 64514]), PeerRole::Provider),
+            AspaState::Valid
+        );
+        // 同じAS Pathでも、Customerから受信した場合は谷になっているためInvalidである。
+        assert_eq!(
+            table.verify(&as_path(&[64515, 64512, 64513, 64514]), PeerRole::Customer),
+            AspaState::Invalid
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use crate::config::Config;
+use crate::connection::InMemoryTransport;
+use crate::peer::{Peer, PeerHandle};
+use crate::routing::{AdjRibIn, Ipv4Network, LocRib};
+use crate::state::State;
+use crate::statistics::PeerStatistics;
+use anyhow::{Context, Result};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+/// 広告するルートの最大数。10.0.0.0/8を/24に分けたprefixを使う。
+pub const MAXIMUM_ROUTES: usize = 1 << 16;
+/// セッションの確立からすべてのルートが伝搬するまでを待つ最大の時間。
+const BENCH_TIMEOUT: Duration = Duration::from_secs(300);
+/// ピアの状態や受信したルートの数を確認する間隔。
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// `howbgp bench`の結果。
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct BenchReport {
+    pub routes: usize,
+    pub peers: usize,
+    /// 送信元のピアからルートを広告してから、ほかのすべてのピアのAdj-RIB-Inに届くまでの時間。
+    pub convergence_time: Duration,
+    /// 測定対象のピアが送信したUpdateMessageの数。
+    pub update_messages: u64,
+    /// プロセスの最大の常駐メモリ(kB)。/proc/self/statusのVmHWMで、読めない環境ではNone。
+    pub peak_memory_kb: Option<u64>,
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "routes:               {}", self.routes)?;
+        writeln!(f, "peers:                {}", self.peers)?;
+        writeln!(
+            f,
+            "convergence time:     {:.3}s",
+            self.convergence_time.as_secs_f64()
+        )?;
+        writeln!(f, "update messages:      {}", self.update_messages)?;
+        match self.peak_memory_kb {
+            Some(kb) => write!(f, "peak memory:          {} kB", kb),
+            None => write!(f, "peak memory:          unknown"),
+        }
+    }
+}
+
+/// 測定対象のデーモンのピアと、インメモリのConnectionでつながった対向の合成ピア。
+struct BenchPeer {
+    handle: PeerHandle,
+    statistics: Arc<Mutex<PeerStatistics>>,
+    synthetic_statistics: Arc<Mutex<PeerStatistics>>,
+    synthetic_loc_rib: Arc<Mutex<LocRib>>,
+    synthetic_handle: PeerHandle,
+    /// 合成ピアが受信したルート。
+    received: Arc<Mutex<AdjRibIn>>,
+}
+
+/// 1つのLocRibを共有するpeers個のピアをプロセス内で動かし、それぞれを合成ピアとつなぐ。
+/// 1つ目の合成ピアからroutes個のルートを広告し、ほかのすべての合成ピアが受信するまでの時間と、
+/// その間に送信したUpdateMessageの数、プロセスの最大のメモリ使用量を測る。
+/// TCP Connectionやカーネルのルーティングテーブルは使わない。
+pub async fn run(routes: usize, peers: usize) -> Result<BenchReport> {
+    if peers < 2 {
+        return Err(anyhow::anyhow!(
+            "ルートを伝搬させるために、ピアは2つ以上必要です。"
+        ));
+    }
+    if routes > MAXIMUM_ROUTES {
+        return Err(anyhow::anyhow!(
+            "ルートの数は{}以下にしてください。",
+            MAXIMUM_ROUTES
+        ));
+    }
+    let loc_rib = Arc::new(Mutex::new(LocRib::empty()));
+    let mut tasks = vec![];
+    let mut bench_peers = vec![];
+    for i in 0..peers {
+        let (bench_peer, peer_tasks) = spawn_pair(i, Arc::clone(&loc_rib))?;
+        bench_peers.push(bench_peer);
+        tasks.extend(peer_tasks);
+    }
+    let result = tokio::time::timeout(BENCH_TIMEOUT, measure(&bench_peers, &loc_rib, routes))
+        .await
+        .context("制限時間内にルートが伝搬しませんでした。");
+    for task in &tasks {
+        task.abort();
+    }
+    let convergence_time = result??;
+
+    let mut update_messages = 0;
+    for bench_peer in &bench_peers {
+        update_messages += bench_peer.statistics.lock().await.messages_sent.update;
+    }
+    Ok(BenchReport {
+        routes,
+        peers,
+        convergence_time,
+        update_messages,
+        peak_memory_kb: peak_memory_kb(),
+    })
+}
+
+/// i番目のピアと合成ピアを作成し、それぞれのタスクで動かす。
+fn spawn_pair(i: usize, loc_rib: Arc<Mutex<LocRib>>) -> Result<(BenchPeer, Vec<JoinHandle<()>>)> {
+    let remote_as = 65001 + i;
+    let remote_ip = Ipv4Addr::from(u32::from(Ipv4Addr::new(127, 1, 0, 1)) + i as u32);
+    let config: Config = format!("64512 127.0.0.1 {} {} active mrai 0", remote_as, remote_ip)
+        .parse()
+        .context("ピアの設定を作成できませんでした。")?;
+    let synthetic_config: Config =
+        format!("{} {} 64512 127.0.0.1 passive mrai 0", remote_as, remote_ip)
+            .parse()
+            .context("合成ピアの設定を作成できませんでした。")?;
+
+    let (transport, synthetic_transport) = InMemoryTransport::pair();
+    let synthetic_loc_rib = Arc::new(Mutex::new(LocRib::empty()));
+    let mut peer = Peer::new(config, loc_rib);
+    let mut synthetic = Peer::new(synthetic_config, Arc::clone(&synthetic_loc_rib));
+    peer.set_transport(Arc::new(transport));
+    synthetic.set_transport(Arc::new(synthetic_transport));
+    let bench_peer = BenchPeer {
+        handle: peer.handle(),
+        statistics: peer.statistics(),
+        synthetic_statistics: synthetic.statistics(),
+        synthetic_loc_rib,
+        synthetic_handle: synthetic.handle(),
+        received: synthetic.adj_rib_in(),
+    };
+    let tasks = [peer, synthetic]
+        .into_iter()
+        .map(|mut peer| {
+            peer.start();
+            tokio::spawn(async move {
+                loop {
+                    peer.next().await;
+                }
+            })
+        })
+        .collect();
+    Ok((bench_peer, tasks))
+}
+
+/// すべてのセッションが確立してから、1つ目の合成ピアにルートを広告させ、
+/// ほかのすべての合成ピアがroutes個のルートを受信するまでの時間を返す。
+async fn measure(
+    bench_peers: &[BenchPeer],
+    loc_rib: &Mutex<LocRib>,
+    routes: usize,
+) -> Result<Duration> {
+    for bench_peer in bench_peers {
+        while bench_peer.statistics.lock().await.state != State::Established
+            || bench_peer.synthetic_statistics.lock().await.state != State::Established
+        {
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    let source = &bench_peers[0];
+    let started = Instant::now();
+    {
+        let mut synthetic_loc_rib = source.synthetic_loc_rib.lock().await;
+        for i in 0..routes {
+            synthetic_loc_rib.originate(network(i), Ipv4Addr::new(127, 1, 0, 1), vec![]);
+        }
+    }
+    source.synthetic_handle.notify_loc_rib_changed();
+
+    // ピアは受信したルートをLocRibにインストールするだけなので、
+    // デーモンのPeerManagerと同じように、LocRibが変わったらすべてのピアに知らせる。
+    let mut version = loc_rib.lock().await.version();
+    loop {
+        let current = loc_rib.lock().await.version();
+        if current != version {
+            version = current;
+            for bench_peer in bench_peers {
+                bench_peer.handle.notify_loc_rib_changed();
+            }
+        }
+        let mut converged = true;
+        for bench_peer in &bench_peers[1..] {
+            if bench_peer.received.lock().await.len() < routes {
+                converged = false;
+                break;
+            }
+        }
+        if converged {
+            return Ok(started.elapsed());
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// i番目に広告するルートのprefix。10.0.0.0/24から順に/24ずつ進める。
+pub(crate) fn network(i: usize) -> Ipv4Network {
+    let address = Ipv4Addr::from(u32::from(Ipv4Addr::new(10, 0, 0, 0)) + ((i as u32) << 8));
+    ipnetwork::Ipv4Network::new(address, 24)
+        .expect("/24は有効なprefix長である。")
+        .into()
+}
+
+/// /proc/self/statusのVmHWM(kB)。
+fn peak_memory_kb() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn routes_from_one_peer_reach_all_other_peers() {
+        let report = run(50, 3).await.unwrap();
+        assert_eq!(report.routes, 50);
+        assert_eq!(report.peers, 3);
+        // 2つの受信側のピアに、少なくとも1つずつUpdateMessageを送信している。
+        assert!(report.update_messages >= 2);
+    }
+
+    #[tokio::test]
+    async fn bench_requires_two_peers() {
+        assert!(run(10, 1).await.is_err());
+        assert!(run(MAXIMUM_ROUTES + 1, 2).await.is_err());
+    }
+}
@@ -1,20 +1,51 @@
-use crate::error::ConvertBytesToBgpMessageError;
+use crate::error::{ConfigParseError, ConvertBytesToBgpMessageError, NotificationBuilder};
+use anyhow::Context;
+use bytes::{BufMut, BytesMut};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
+use std::time::Duration;
 
+/// AS番号。4オクテットのAS番号(RFC 6793)も表せる。
+/// 4オクテットAS Capabilityを交換していないピアにはOpenMessageやAS_PATHを2オクテットで送るため、
+/// 2オクテットで表せないAS番号はAS_TRANSで代用し、AS_PATHの本来のAS番号はAS4_PATHで送る。
 #[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
-pub struct AutonomousSystemNumber(u16);
+pub struct AutonomousSystemNumber(u32);
 
-impl From<AutonomousSystemNumber> for u16 {
-    fn from(as_number: AutonomousSystemNumber) -> u16 {
+/// 2オクテットで表せないAS番号の代わりに使うAS番号(RFC 6793)。
+pub const AS_TRANS: AutonomousSystemNumber = AutonomousSystemNumber(23456);
+
+impl AutonomousSystemNumber {
+    /// 2オクテットで表せないAS番号か。
+    pub fn is_four_octet(self) -> bool {
+        self.0 > u32::from(u16::MAX)
+    }
+
+    /// 2オクテットで表す。表せないAS番号はAS_TRANSにする。
+    pub fn to_two_octet(self) -> u16 {
+        u16::try_from(self.0).unwrap_or(AS_TRANS.0 as u16)
+    }
+}
+
+impl From<AutonomousSystemNumber> for u32 {
+    fn from(as_number: AutonomousSystemNumber) -> u32 {
         as_number.0
     }
 }
 
-impl From<u16> for AutonomousSystemNumber {
-    fn from(as_number: u16) -> Self {
+impl From<u32> for AutonomousSystemNumber {
+    fn from(as_number: u32) -> Self {
         Self(as_number)
     }
 }
 
+impl fmt::Display for AutonomousSystemNumber {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
 pub struct HoldTime(u16);
 
@@ -40,8 +71,30 @@ impl HoldTime {
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// RFC 4271では、Hold Timeは0か3秒以上でなければならない。
+    pub fn is_acceptable(&self) -> bool {
+        self.0 == 0 || self.0 >= 3
+    }
+
+    /// 0のときは、Hold TimerもKeepalive Timerも動かさない。
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs(self.0 as u64)
+    }
+
+    /// KeepaliveMessageを送信する間隔。Hold Timeの1/3とする。
+    pub fn keepalive_interval(&self) -> Duration {
+        Duration::from_secs(self.0 as u64 / 3)
+    }
 }
 
+/// 対応しているBGPのVersion。RFC 4271のBGP-4だけに対応する。
+pub const SUPPORTED_VERSION: u8 = 4;
+
 #[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
 pub struct Version(u8);
 
@@ -55,20 +108,25 @@ impl TryFrom<u8> for Version {
     type Error = ConvertBytesToBgpMessageError;
 
     fn try_from(v: u8) -> Result<Self, Self::Error> {
-        if v <= 4 {
+        // 古いVersionで動作を続けると、対向機器とMessageの解釈が食い違うため受け入れない。
+        if v == SUPPORTED_VERSION {
             Ok(Version(v))
         } else {
-            Err(Self::Error::from(anyhow::anyhow!(
-                "BGPのVersionは1-4が期待されていますが、{}が渡されました。",
-                v
-            )))
+            Err(
+                NotificationBuilder::unsupported_version_number(SUPPORTED_VERSION)
+                    .build(format!(
+                        "BGPのVersionは{}だけに対応していますが、{}が渡されました。",
+                        SUPPORTED_VERSION, v
+                    ))
+                    .into(),
+            )
         }
     }
 }
 
 impl Default for Version {
     fn default() -> Self {
-        Version(4)
+        Version(SUPPORTED_VERSION)
     }
 }
 
@@ -77,3 +135,193 @@ impl Version {
         Default::default()
     }
 }
+
+/// IPv4のprefix長の最大値。
+pub const MAXIMUM_PREFIX_LENGTH: u8 = 32;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct Ipv4Network(ipnetwork::Ipv4Network);
+
+impl Deref for Ipv4Network {
+    type Target = ipnetwork::Ipv4Network;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for Ipv4Network {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl From<ipnetwork::Ipv4Network> for Ipv4Network {
+    fn from(ip_network: ipnetwork::Ipv4Network) -> Self {
+        Self(ip_network)
+    }
+}
+
+impl From<&Ipv4Network> for BytesMut {
+    fn from(network: &Ipv4Network) -> BytesMut {
+        let prefix = network.prefix();
+
+        let n = network.network().octets();
+        let network_bytes = match prefix {
+            0 => vec![],
+            1..9 => n[0..1].into(),
+            9..17 => n[0..2].into(),
+            17..25 => n[0..3].into(),
+            25..33 => n[0..4].into(),
+            _ => panic!("prefixが0..32の間ではありません！"),
+        };
+        let mut bytes = BytesMut::new();
+        bytes.put_u8(prefix);
+        bytes.put(&network_bytes[..]);
+        bytes
+    }
+}
+
+impl FromStr for Ipv4Network {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let network = s
+            .parse::<ipnetwork::Ipv4Network>()
+            .context("s: {:?}を、Ipv4Networkにparse出来ませんでした")?;
+        Ok(Self(network))
+    }
+}
+
+impl Ipv4Network {
+    pub fn bytes_len(&self) -> usize {
+        match self.prefix() {
+            0 => 1,
+            1..9 => 2,
+            9..17 => 3,
+            17..25 => 4,
+            25..33 => 5,
+            _ => panic!("prefixが0..32の間ではありません！"),
+        }
+    }
+
+    /// Withdrawn RoutesやNLRIを表すbytes列をIpv4Networkの列に変換する。
+    /// 各ルートのbytes表現は以下の通り。
+    /// [prefix長 (1 octet)]
+    /// [prefix (prefix長を表すのに必要な最小のoctet数)]
+    pub fn from_u8_slice(bytes: &[u8]) -> Result<Vec<Self>, ConvertBytesToBgpMessageError> {
+        let mut networks = vec![];
+        let mut i = 0;
+        while i < bytes.len() {
+            let prefix = bytes[i];
+            if prefix > 32 {
+                return Err(ConvertBytesToBgpMessageError::InvalidValue {
+                    field: "prefix長",
+                    value: u32::from(prefix),
+                });
+            }
+            let octets_len = (prefix as usize).div_ceil(8);
+            let octets = bytes
+                .get(i + 1..i + 1 + octets_len)
+                .ok_or(ConvertBytesToBgpMessageError::Truncated { field: "prefix" })?;
+            let mut addr = [0u8; 4];
+            addr[..octets_len].copy_from_slice(octets);
+            // prefix長より後ろのbitは無視し、ネットワークアドレスにそろえる。
+            let network = ipnetwork::Ipv4Network::new(Ipv4Addr::from(addr), prefix)
+                .and_then(|n| ipnetwork::Ipv4Network::new(n.network(), prefix))
+                .context(format!(
+                    "prefix長{}のネットワークを作成できませんでした。",
+                    prefix
+                ))?;
+            networks.push(network.into());
+            i += 1 + octets_len;
+        }
+        Ok(networks)
+    }
+}
+
+pub const AFI_IPV4: u16 = 1;
+pub const AFI_IPV6: u16 = 2;
+pub const SAFI_UNICAST: u8 = 1;
+
+/// AFI(Address Family Identifier)とSAFI(Subsequent Address Family Identifier)の組(RFC 4760)。
+/// RIBはこの組ごとにテーブルを分けて持つ。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct AddressFamily {
+    pub afi: u16,
+    pub safi: u8,
+}
+
+impl AddressFamily {
+    pub const IPV4_UNICAST: Self = Self::new(AFI_IPV4, SAFI_UNICAST);
+    pub const IPV6_UNICAST: Self = Self::new(AFI_IPV6, SAFI_UNICAST);
+
+    pub const fn new(afi: u16, safi: u8) -> Self {
+        Self { afi, safi }
+    }
+}
+
+impl fmt::Display for AddressFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::IPV4_UNICAST => write!(f, "ipv4-unicast"),
+            Self::IPV6_UNICAST => write!(f, "ipv6-unicast"),
+            Self { afi, safi } => write!(f, "{}/{}", afi, safi),
+        }
+    }
+}
+
+impl FromStr for AddressFamily {
+    type Err = ConfigParseError;
+
+    /// `ipv4-unicast`、`ipv6-unicast`か、`1/1`のようにAFIとSAFIの値を`/`で区切ったもの。
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ipv4-unicast" => return Ok(Self::IPV4_UNICAST),
+            "ipv6-unicast" => return Ok(Self::IPV6_UNICAST),
+            _ => {}
+        }
+        let invalid = || ConfigParseError::InvalidValue {
+            kind: "address family",
+            value: s.to_string(),
+        };
+        let (afi, safi) = s.split_once('/').ok_or_else(invalid)?;
+        Ok(Self::new(
+            afi.parse().map_err(|_| invalid())?,
+            safi.parse().map_err(|_| invalid())?,
+        ))
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for AutonomousSystemNumber {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self(u.arbitrary()?))
+    }
+}
+
+/// OpenMessageで受け入れられる、0か3秒以上のHold Timeのみを生成する。
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for HoldTime {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let hold_time = Self(u.arbitrary()?);
+        Ok(if hold_time.is_acceptable() {
+            hold_time
+        } else {
+            Self(0)
+        })
+    }
+}
+
+/// NLRIとして受信したときと同じく、prefix長より後ろのbitが0のネットワークを生成する。
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Ipv4Network {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let prefix = u.int_in_range(0..=MAXIMUM_PREFIX_LENGTH)?;
+        let addr = Ipv4Addr::from(u.arbitrary::<u32>()?);
+        let network = ipnetwork::Ipv4Network::new(addr, prefix)
+            .and_then(|n| ipnetwork::Ipv4Network::new(n.network(), prefix))
+            .expect("prefix長は0..=32の範囲で生成している。");
+        Ok(Self(network))
+    }
+}
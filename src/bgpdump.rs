@@ -0,0 +1,160 @@
+use crate::bgp_type::AutonomousSystemNumber;
+use crate::packets::update::UpdateMessage;
+use crate::path_attribute::{AsPath, Origin, PathAttribute};
+use crate::routing::RibEntry;
+use std::net::Ipv4Addr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// `bgpdump -m`と同じ形式の行で、受信したUpdateMessageを表す。
+/// 広告したprefixごとに`BGP4MP|<time>|A|...`、取り消したprefixごとに`BGP4MP|<time>|W|...`の行にする。
+pub fn update_lines(
+    time: SystemTime,
+    peer_ip: Ipv4Addr,
+    peer_as: AutonomousSystemNumber,
+    update: &UpdateMessage,
+) -> Vec<String> {
+    let header = |type_: &str| {
+        format!(
+            "BGP4MP|{}|{}|{}|{}|",
+            unix_time(time),
+            type_,
+            peer_ip,
+            u32::from(peer_as)
+        )
+    };
+    let mut lines: Vec<String> = update
+        .network_layer_reachability_information()
+        .iter()
+        .map(|network| {
+            format!(
+                "{}{}|{}",
+                header("A"),
+                **network,
+                attribute_fields(update.path_attributes())
+            )
+        })
+        .collect();
+    lines.extend(
+        update
+            .withdrawn_routes()
+            .iter()
+            .map(|network| format!("{}{}", header("W"), **network)),
+    );
+    lines
+}
+
+/// `bgpdump -m`と同じ形式の`TABLE_DUMP2|<time>|B|...`の行で、RIBのルートを表す。
+/// timeにはルートを受信した時刻を使う。
+pub fn rib_entry_line(
+    time: SystemTime,
+    peer_ip: Ipv4Addr,
+    peer_as: AutonomousSystemNumber,
+    entry: &RibEntry,
+) -> String {
+    format!(
+        "TABLE_DUMP2|{}|B|{}|{}|{}|{}",
+        unix_time(time),
+        peer_ip,
+        u32::from(peer_as),
+        *entry.network_address,
+        attribute_fields(&entry.path_attributes)
+    )
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// `<AS Path>|<Origin>|<Next Hop>|<Local Pref>|<MED>|<Community>|<Atomic Aggregate>|<Aggregator>|`。
+/// 本実装で扱わないMED、ATOMIC_AGGREGATE、AGGREGATORは、bgpdumpで属性がない場合と同じ値にする。
+fn attribute_fields(path_attributes: &[PathAttribute]) -> String {
+    let mut as_path = String::new();
+    let mut origin = "";
+    let mut next_hop = String::new();
+    let mut local_pref = 0;
+    let mut communities = String::new();
+    for path_attribute in path_attributes {
+        match path_attribute {
+            PathAttribute::AsPath(AsPath::AsSequence(as_numbers)) => {
+                as_path = as_numbers
+                    .iter()
+                    .map(|a| u32::from(*a).to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            }
+            PathAttribute::AsPath(AsPath::AsSet(as_numbers)) => {
+                as_path = format!(
+                    "{{{}}}",
+                    as_numbers
+                        .iter()
+                        .map(|a| u32::from(*a).to_string())
+                        .collect::<Vec<_>>()
+                        .join(",")
+                )
+            }
+            PathAttribute::Origin(Origin::Igp) => origin = "IGP",
+            PathAttribute::Origin(Origin::Egp) => origin = "EGP",
+            PathAttribute::Origin(Origin::Incomplete) => origin = "INCOMPLETE",
+            PathAttribute::NextHop(address) => next_hop = address.to_string(),
+            PathAttribute::LocalPref(value) => local_pref = *value,
+            // bgpdumpは既知のCommunityも名前ではなく数値で表示する。
+            PathAttribute::Communities(values) => {
+                communities = values
+                    .iter()
+                    .map(|c| format!("{}:{}", c.0 >> 16, c.0 & 0xFFFF))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            }
+            _ => (),
+        }
+    }
+    format!(
+        "{}|{}|{}|{}|0|{}|NAG||",
+        as_path, origin, next_hop, local_pref, communities
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path_attribute::Community;
+    use std::time::Duration;
+
+    fn path_attributes() -> Vec<PathAttribute> {
+        vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::AsSequence(vec![64513.into(), 64514.into()])),
+            PathAttribute::NextHop("127.0.0.2".parse().unwrap()),
+            PathAttribute::Communities(vec![Community(64513 << 16 | 100), Community::NO_EXPORT]),
+        ]
+    }
+
+    #[test]
+    fn update_is_formatted_as_bgp4mp_lines() {
+        let update = UpdateMessage::new(
+            path_attributes(),
+            vec!["10.100.220.0/24".parse().unwrap()],
+            vec!["10.100.230.0/24".parse().unwrap()],
+        );
+        let time = UNIX_EPOCH + Duration::from_secs(1367366400);
+
+        assert_eq!(
+            update_lines(time, "127.0.0.2".parse().unwrap(), 64513.into(), &update),
+            vec![
+                "BGP4MP|1367366400|A|127.0.0.2|64513|10.100.220.0/24|64513 64514|IGP|127.0.0.2|0|0|64513:100 65535:65281|NAG||",
+                "BGP4MP|1367366400|W|127.0.0.2|64513|10.100.230.0/24",
+            ]
+        );
+    }
+
+    #[test]
+    fn rib_entry_is_formatted_as_table_dump2_line() {
+        let entry = RibEntry::for_test("10.100.220.0/24").with_path_attributes(path_attributes());
+        assert_eq!(
+            rib_entry_line(UNIX_EPOCH, "127.0.0.2".parse().unwrap(), 64513.into(), &entry),
+            "TABLE_DUMP2|0|B|127.0.0.2|64513|10.100.220.0/24|64513 64514|IGP|127.0.0.2|0|0|64513:100 65535:65281|NAG||"
+        );
+    }
+}
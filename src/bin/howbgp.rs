@@ -0,0 +1,260 @@
+use anyhow::{Context, Result};
+use how_to_create_bgp::bench;
+use how_to_create_bgp::config::Config;
+use how_to_create_bgp::control::{self, DEFAULT_CONTROL_SOCKET};
+use how_to_create_bgp::flood::{self, FloodOptions};
+use how_to_create_bgp::packets::decode::{decode_messages, parse_hex};
+use how_to_create_bgp::replay;
+use how_to_create_bgp::rib_diff::{RibDiff, RibSnapshot};
+use std::env;
+use std::path::{Path, PathBuf};
+
+const USAGE: &str = "usage:
+    howbgp replay <mrt file> [--speed <倍率>]
+    howbgp check-config <config file>
+    howbgp decode <hex|binary file>
+    howbgp diff-rib <old mrt dump> <new mrt dump>
+    howbgp bench --routes <N> --peers <M>
+    howbgp flood <neighbor config> [--prefixes <N>] [--attribute-sets <N>] [--withdraw-percent <N>] [--rounds <N>] [--rate <updates/s>]
+    howbgp [--socket <path>] show neighbors
+    howbgp [--socket <path>] show history [neighbor]
+    howbgp [--socket <path>] show capture [neighbor]
+    howbgp [--socket <path>] show rib [prefix] [--json|--bgpdump|--format csv|json] [--export <file>]
+    howbgp [--socket <path>] show route <address> [--json]
+    howbgp [--socket <path>] show memory
+    howbgp [--socket <path>] show churn [seconds]
+    howbgp [--socket <path>] monitor [neighbor] [--json|--bgpdump]
+    howbgp [--socket <path>] neighbor <neighbor> disable|enable
+    howbgp [--socket <path>] maintenance [on|off]
+    howbgp [--socket <path>] apply-config <config file>
+    howbgp [--socket <path>] diff-rib <mrt dump in mrt-dump-dir>
+
+`--export <file>`を指定すると、結果を表示する代わりにfileに書き込む。
+コントロールAPIで認証する場合は、`--token <token>`か環境変数HOWBGP_TOKENでトークンを指定する。";
+
+#[tokio::main]
+async fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let socket = take_socket_option(&mut args);
+    let token = take_token_option(&mut args).or_else(|| env::var("HOWBGP_TOKEN").ok());
+    let result = match args.first().map(|s| s.as_str()) {
+        Some("replay") => replay_command(&args[1..]).await,
+        Some("check-config") => check_config_command(&args[1..]),
+        Some("decode") => decode_command(&args[1..]),
+        Some("diff-rib") if args.len() == 3 => diff_rib_command(&args[1], &args[2]),
+        Some("bench") => bench_command(&args[1..]).await,
+        Some("flood") => flood_command(&args[1..]).await,
+        Some("show" | "neighbor" | "maintenance" | "apply-config" | "diff-rib") => {
+            control_command(&socket, token.as_deref(), &args).await
+        }
+        Some("monitor") => monitor_command(&socket, token.as_deref(), &args).await,
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("{:?}", e);
+        std::process::exit(1);
+    }
+}
+
+/// MRTファイルのUpdateMessageをデコードしてRIBにインストールし、結果を表示する。
+/// `--speed`を指定しない場合はMRTのTimestampを無視して最大速度で処理する。
+async fn replay_command(args: &[String]) -> Result<()> {
+    let path = args.first().context(USAGE)?;
+    let speed = match args.get(1).map(|s| s.as_str()) {
+        Some("--speed") => args
+            .get(2)
+            .context(USAGE)?
+            .parse::<f64>()
+            .context("`--speed`には数値を指定してください。")?,
+        Some(_) => return Err(anyhow::anyhow!(USAGE)),
+        None => 0.0,
+    };
+    let report = replay::replay(Path::new(path), speed).await?;
+    println!("{}", report);
+    if report.parse_failures > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// 設定ファイルを読み込んで検証し、問題があればすべて表示して終了コード1で終了する。
+/// デーモンを起動したり、apply-configで反映したりする前に設定を確かめるために使う。
+fn check_config_command(args: &[String]) -> Result<()> {
+    let path = args.first().context(USAGE)?;
+    let text = std::fs::read_to_string(path)
+        .context(format!("設定ファイル{}を読み込めませんでした。", path))?;
+    let diagnostics = Config::check(&text);
+    if !diagnostics.is_empty() {
+        for diagnostic in &diagnostics {
+            eprintln!("{}: {}", path, diagnostic);
+        }
+        std::process::exit(1);
+    }
+    let neighbors = Config::parse_lines(&text)?.len();
+    println!("{}: {}個のピアの設定に問題はありません。", path, neighbors);
+    Ok(())
+}
+
+/// BGPのメッセージが連続したbytes列をデコードして、メッセージごとに内容かエラーを表示する。
+/// 引数が存在するファイルのパスであればその内容を、そうでなければ16進数の文字列を読み取る。
+fn decode_command(args: &[String]) -> Result<()> {
+    let input = args.first().context(USAGE)?;
+    let bytes = if Path::new(input).is_file() {
+        std::fs::read(input).context(format!("{}を読み込めませんでした。", input))?
+    } else {
+        parse_hex(&args.join(" "))?
+    };
+    let messages = decode_messages(&bytes);
+    for message in &messages {
+        println!("{}", message);
+    }
+    if messages.iter().any(|m| m.result.is_err()) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// 2つのMRTのテーブルダンプを比べて、追加、削除、変更されたルートを表示する。
+/// 差分があれば終了コード1で終了する。
+fn diff_rib_command(old: &str, new: &str) -> Result<()> {
+    let diff = RibDiff::new(
+        &RibSnapshot::load(Path::new(old))?,
+        &RibSnapshot::load(Path::new(new))?,
+    );
+    print!("{}", diff);
+    if !diff.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// プロセス内の合成ピアで、N個のルートがM-1個のピアに伝搬するまでの時間などを測って表示する。
+async fn bench_command(args: &[String]) -> Result<()> {
+    let mut routes = None;
+    let mut peers = None;
+    let mut args = args.iter();
+    while let Some(option) = args.next() {
+        let value = match option.as_str() {
+            "--routes" => &mut routes,
+            "--peers" => &mut peers,
+            _ => return Err(anyhow::anyhow!(USAGE)),
+        };
+        *value = Some(
+            args.next()
+                .context(USAGE)?
+                .parse::<usize>()
+                .context(format!("`{}`には数値を指定してください。", option))?,
+        );
+    }
+    let report = bench::run(routes.context(USAGE)?, peers.context(USAGE)?).await?;
+    println!("{}", report);
+    Ok(())
+}
+
+/// `64513 127.0.0.2 64512 127.0.0.1 active`のような設定の行で対向機器とセッションを確立し、
+/// 合成のUpdateMessageを送信して結果を表示する。
+async fn flood_command(args: &[String]) -> Result<()> {
+    let options_start = args
+        .iter()
+        .position(|a| a.starts_with("--"))
+        .unwrap_or(args.len());
+    if options_start == 0 {
+        return Err(anyhow::anyhow!(USAGE));
+    }
+    let config: Config = args[..options_start].join(" ").parse()?;
+    let mut options = FloodOptions::default();
+    let mut args = args[options_start..].iter();
+    while let Some(option) = args.next() {
+        let value = args.next().context(USAGE)?;
+        let invalid = || format!("`{}`には数値を指定してください。", option);
+        match option.as_str() {
+            "--prefixes" => options.prefixes = value.parse().context(invalid())?,
+            "--attribute-sets" => options.attribute_sets = value.parse().context(invalid())?,
+            "--withdraw-percent" => options.withdraw_percent = value.parse().context(invalid())?,
+            "--rounds" => options.rounds = value.parse().context(invalid())?,
+            "--rate" => options.rate = value.parse().context(invalid())?,
+            _ => return Err(anyhow::anyhow!(USAGE)),
+        }
+    }
+    let report = flood::run(&config, &options).await?;
+    println!("{}", report);
+    Ok(())
+}
+
+/// `--socket <path>`が指定されていればargsから取り除き、コントロールAPIのsocketのパスを返す。
+fn take_socket_option(args: &mut Vec<String>) -> PathBuf {
+    match args.iter().position(|a| a == "--socket") {
+        Some(i) if i + 1 < args.len() => {
+            let path = args.remove(i + 1);
+            args.remove(i);
+            PathBuf::from(path)
+        }
+        _ => PathBuf::from(DEFAULT_CONTROL_SOCKET),
+    }
+}
+
+/// `--token <token>`が指定されていればargsから取り除き、コントロールAPIの認証に使うトークンを返す。
+fn take_token_option(args: &mut Vec<String>) -> Option<String> {
+    let i = args.iter().position(|a| a == "--token")?;
+    if i + 1 >= args.len() {
+        return None;
+    }
+    let token = args.remove(i + 1);
+    args.remove(i);
+    Some(token)
+}
+
+/// `--export <file>`が指定されていればargsから取り除き、結果を書き込むファイルを返す。
+fn take_export_option(args: &mut Vec<String>) -> Option<PathBuf> {
+    let i = args.iter().position(|a| a == "--export")?;
+    if i + 1 >= args.len() {
+        return None;
+    }
+    let path = args.remove(i + 1);
+    args.remove(i);
+    Some(PathBuf::from(path))
+}
+
+/// コントロールAPIに送るコマンドの行。tokenがあれば先頭に`auth <token>`を付ける。
+fn command_line(token: Option<&str>, args: &[String]) -> String {
+    match token {
+        Some(token) => format!("auth {} {}", token, args.join(" ")),
+        None => args.join(" "),
+    }
+}
+
+/// 起動中のデーモンのコントロールAPIにコマンドを送り、結果を表示する。
+async fn control_command(socket: &Path, token: Option<&str>, args: &[String]) -> Result<()> {
+    let mut args = args.to_vec();
+    let export = take_export_option(&mut args);
+    if let [command, path] = &mut args[..] {
+        // 設定ファイルやダンプはデーモンが読むため、デーモンの作業ディレクトリによらないパスにする。
+        if command == "apply-config" || command == "diff-rib" {
+            *path = std::fs::canonicalize(&path)
+                .with_context(|| format!("{}を開けません。", path))?
+                .display()
+                .to_string();
+        }
+    }
+    let response = control::request(socket, &command_line(token, &args)).await?;
+    match export {
+        Some(path) => {
+            std::fs::write(&path, &response)
+                .with_context(|| format!("{:?}に書き込めませんでした。", path))?;
+            println!("{:?}に書き込みました。", path);
+        }
+        None => print!("{}", response),
+    }
+    Ok(())
+}
+
+/// デーモンが送受信したUpdateMessage、NotificationMessageを、Ctrl-Cで止めるまで表示し続ける。
+async fn monitor_command(socket: &Path, token: Option<&str>, args: &[String]) -> Result<()> {
+    control::monitor(socket, &command_line(token, args), |line| {
+        println!("{}", line)
+    })
+    .await
+}
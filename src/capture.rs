@@ -0,0 +1,111 @@
+use crate::debug::{hexdump, Direction};
+use crate::packets::message::Message;
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 送受信したメッセージのデフォルトの保持件数。
+pub const DEFAULT_MESSAGE_CAPTURE_SIZE: usize = 16;
+
+/// 送受信した1つのメッセージの記録。
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct CapturedMessage {
+    pub timestamp: SystemTime,
+    pub direction: Direction,
+    /// Headerを含むメッセージ全体のbytes列。デコードできなかったメッセージもそのまま保持する。
+    pub bytes: Bytes,
+}
+
+impl fmt::Display for CapturedMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let timestamp = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let arrow = match self.direction {
+            Direction::Send => "->",
+            Direction::Receive => "<-",
+        };
+        writeln!(
+            f,
+            "{}.{:03} {} {} bytes",
+            timestamp.as_secs(),
+            timestamp.subsec_millis(),
+            arrow,
+            self.bytes.len()
+        )?;
+        write!(f, "{}", hexdump(&self.bytes))?;
+        // 保持するときにはデコードせず、表示するときだけデコードする。
+        match Message::try_from(self.bytes.clone()) {
+            Ok(message) => write!(f, "{:#?}", message),
+            Err(e) => write!(f, "メッセージとしてデコードできませんでした。{:?}", e),
+        }
+    }
+}
+
+/// 直近capacity件の送受信したメッセージを保持するリングバッファ。
+/// デバッグログを有効にしていなくても、セッションがリセットされる直前のメッセージを調べられる。
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct MessageCapture {
+    messages: VecDeque<CapturedMessage>,
+    capacity: usize,
+}
+
+impl MessageCapture {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            messages: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, direction: Direction, bytes: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.messages.len() == self.capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(CapturedMessage {
+            timestamp: SystemTime::now(),
+            direction,
+            bytes: Bytes::copy_from_slice(bytes),
+        });
+    }
+
+    /// 古いものから順にメッセージを返す。
+    pub fn iter(&self) -> impl Iterator<Item = &CapturedMessage> {
+        self.messages.iter()
+    }
+}
+
+impl Default for MessageCapture {
+    fn default() -> Self {
+        Self::new(DEFAULT_MESSAGE_CAPTURE_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+
+    #[test]
+    fn capture_keeps_latest_messages_with_hex_and_decoded_form() {
+        let mut capture = MessageCapture::new(2);
+        let keepalive: BytesMut = Message::new_keepalive().into();
+        capture.push(Direction::Send, &keepalive);
+        capture.push(Direction::Receive, &keepalive);
+        capture.push(Direction::Receive, &[0xff; 19]);
+
+        let messages: Vec<&CapturedMessage> = capture.iter().collect();
+        assert_eq!(messages.len(), 2);
+        let keepalive = messages[0].to_string();
+        assert!(keepalive.contains(" <- 19 bytes\n0000: ff ff ff ff"));
+        assert!(keepalive.contains("0010: 00 13 04\nKeepalive("));
+        assert!(messages[1]
+            .to_string()
+            .contains("メッセージとしてデコードできませんでした。"));
+    }
+}
@@ -0,0 +1,247 @@
+use crate::clock::{Clock, SystemClock};
+use crate::hook::{MessageHook, PeerContext};
+use crate::packets::message::Message;
+use crate::routing::Ipv4Network;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Write};
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// `show churn`で集計できる最も長い期間。これより古い記録は捨てる。
+pub const MAXIMUM_CHURN_WINDOW: Duration = Duration::from_secs(3600);
+
+/// 保持する記録の数の上限。フルルートの再送などで急増しても、メモリを使い過ぎないようにする。
+const MAXIMUM_CHURN_RECORDS: usize = 100_000;
+
+/// `show churn`で表示する、prefixとピアのそれぞれの件数。
+const CHURN_TOP_ENTRIES: usize = 10;
+
+/// 受信したUpdateMessageで、1つのprefixが広告または取り消されたこと。
+#[derive(Debug, Clone, Copy)]
+struct ChurnRecord {
+    time: Instant,
+    peer: Ipv4Addr,
+    network: Ipv4Network,
+    withdrawn: bool,
+}
+
+/// 広告と取り消しの回数。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChurnCount {
+    pub announced: u64,
+    pub withdrawn: u64,
+}
+
+impl ChurnCount {
+    pub fn total(&self) -> u64 {
+        self.announced + self.withdrawn
+    }
+
+    fn add(&mut self, record: &ChurnRecord) {
+        if record.withdrawn {
+            self.withdrawn += 1;
+        } else {
+            self.announced += 1;
+        }
+    }
+}
+
+/// 直近のwindowの間に変化の多かったprefixとピア。回数の多い順に並べる。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChurnReport {
+    pub window: Duration,
+    pub prefixes: Vec<(Ipv4Network, ChurnCount)>,
+    pub peers: Vec<(Ipv4Addr, ChurnCount)>,
+}
+
+impl fmt::Display for ChurnReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Churn in the last {}s", self.window.as_secs())?;
+        writeln!(
+            f,
+            "  {:<18} {:>9} {:>9}",
+            "Prefix", "Announced", "Withdrawn"
+        )?;
+        for (network, count) in &self.prefixes {
+            writeln!(
+                f,
+                "  {:<18} {:>9} {:>9}",
+                (**network).to_string(),
+                count.announced,
+                count.withdrawn
+            )?;
+        }
+        writeln!(f, "  {:<18} {:>9} {:>9}", "Peer", "Announced", "Withdrawn")?;
+        for (peer, count) in &self.peers {
+            writeln!(
+                f,
+                "  {:<18} {:>9} {:>9}",
+                peer.to_string(),
+                count.announced,
+                count.withdrawn
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// ピアから受信したprefixの広告と取り消しを記録し、フラップしているprefixや
+/// UpdateMessageを多く送ってくるピアを見つけるMessageHook。
+/// すべてのピアに同じChurnTrackerを追加し、コントロールAPIの`show churn`で集計する。
+#[derive(Debug, Clone)]
+pub struct ChurnTracker {
+    records: Arc<Mutex<VecDeque<ChurnRecord>>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ChurnTracker {
+    pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+
+    pub(crate) fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            records: Default::default(),
+            clock,
+        }
+    }
+
+    fn record(&self, peer: Ipv4Addr, networks: &[Ipv4Network], withdrawn: bool) {
+        let time = self.clock.now();
+        let mut records = self.records.lock().unwrap();
+        for network in networks {
+            if records.len() == MAXIMUM_CHURN_RECORDS {
+                records.pop_front();
+            }
+            records.push_back(ChurnRecord {
+                time,
+                peer,
+                network: *network,
+                withdrawn,
+            });
+        }
+        while let Some(oldest) = records.front() {
+            if time.duration_since(oldest.time) <= MAXIMUM_CHURN_WINDOW {
+                break;
+            }
+            records.pop_front();
+        }
+    }
+
+    /// 直近のwindowの間の記録を集計する。windowはMAXIMUM_CHURN_WINDOWまでに制限する。
+    pub fn report(&self, window: Duration) -> ChurnReport {
+        let window = window.min(MAXIMUM_CHURN_WINDOW);
+        let now = self.clock.now();
+        let mut prefixes: HashMap<Ipv4Network, ChurnCount> = HashMap::new();
+        let mut peers: HashMap<Ipv4Addr, ChurnCount> = HashMap::new();
+        for record in self.records.lock().unwrap().iter().rev() {
+            if now.duration_since(record.time) > window {
+                break;
+            }
+            prefixes.entry(record.network).or_default().add(record);
+            peers.entry(record.peer).or_default().add(record);
+        }
+        ChurnReport {
+            window,
+            prefixes: top_entries(prefixes),
+            peers: top_entries(peers),
+        }
+    }
+}
+
+impl Default for ChurnTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 回数の多い順にCHURN_TOP_ENTRIES件を返す。回数が同じ場合はキーの順にする。
+fn top_entries<K: Ord + Copy>(counts: HashMap<K, ChurnCount>) -> Vec<(K, ChurnCount)> {
+    let mut entries: Vec<(K, ChurnCount)> = counts.into_iter().collect();
+    entries.sort_by(|(a_key, a), (b_key, b)| b.total().cmp(&a.total()).then(a_key.cmp(b_key)));
+    entries.truncate(CHURN_TOP_ENTRIES);
+    entries
+}
+
+impl MessageHook for ChurnTracker {
+    fn on_inbound(&self, context: &PeerContext, message: Message) -> Option<Message> {
+        if let Message::Update(update) = &message {
+            let peer = context.config.remote_ip;
+            self.record(peer, update.network_layer_reachability_information(), false);
+            self.record(peer, update.withdrawn_routes(), true);
+        }
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::config::Config;
+    use crate::packets::update::UpdateMessage;
+    use crate::state::State;
+
+    #[test]
+    fn flapping_prefixes_and_noisy_peers_are_listed_first() {
+        let clock = Arc::new(MockClock::new());
+        let tracker = ChurnTracker::with_clock(clock.clone());
+        let peer = |config: &str| config.parse::<Config>().unwrap();
+        let (noisy, quiet) = (
+            peer("64512 127.0.0.1 64513 127.0.0.2 active"),
+            peer("64512 127.0.0.1 64514 127.0.0.3 active"),
+        );
+        let receive = |config: &Config, announced: &[&str], withdrawn: &[&str]| {
+            let context = PeerContext {
+                config,
+                state: State::Established,
+                remote_as: None,
+            };
+            let networks =
+                |networks: &[&str]| networks.iter().map(|n| n.parse().unwrap()).collect();
+            let update = UpdateMessage::new(vec![], networks(announced), networks(withdrawn));
+            tracker.on_inbound(&context, Message::Update(update));
+        };
+
+        receive(&quiet, &["10.100.230.0/24"], &[]);
+        clock.advance(Duration::from_secs(600));
+        receive(&noisy, &["10.100.220.0/24"], &[]);
+        receive(&noisy, &[], &["10.100.220.0/24"]);
+        receive(&noisy, &["10.100.220.0/24"], &[]);
+        receive(&quiet, &["10.100.240.0/24"], &[]);
+
+        let report = tracker.report(Duration::from_secs(300));
+        assert_eq!(
+            report.prefixes,
+            vec![
+                (
+                    "10.100.220.0/24".parse().unwrap(),
+                    ChurnCount {
+                        announced: 2,
+                        withdrawn: 1
+                    }
+                ),
+                (
+                    "10.100.240.0/24".parse().unwrap(),
+                    ChurnCount {
+                        announced: 1,
+                        withdrawn: 0
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            report
+                .peers
+                .iter()
+                .map(|(p, c)| (*p, c.total()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("127.0.0.2".parse().unwrap(), 3),
+                ("127.0.0.3".parse().unwrap(), 1)
+            ]
+        );
+        assert_eq!(tracker.report(Duration::from_secs(900)).prefixes.len(), 3);
+    }
+}
@@ -1,25 +1,208 @@
-use crate::bgp_type::AutonomousSystemNumber;
+use crate::aspa::{AspaTable, PeerRole};
+use crate::bgp_type::{AddressFamily, AutonomousSystemNumber, HoldTime};
+use crate::capture::DEFAULT_MESSAGE_CAPTURE_SIZE;
+use crate::connection::{Dscp, Transport, DEFAULT_OUTBOUND_QUEUE_CAPACITY};
+use crate::control::DEFAULT_CONTROL_SOCKET;
+use crate::control_auth::ControlTokens;
+use crate::debug::DebugFlags;
+use crate::discovery::DEFAULT_DISCOVERY_INTERVAL;
 use crate::error::ConfigParseError;
-use crate::routing::Ipv4Network;
+use crate::ha::{HaKey, HaRole};
+use crate::history::DEFAULT_STATE_HISTORY_SIZE;
+use crate::log::{LogOutput, SyslogDestination};
+use crate::packets::capability::MAXIMUM_LLGR_STALE_TIME;
+use crate::path_attribute::Community;
+use crate::policy::{Policy, PolicyAction};
+use crate::policy_file::PolicySet;
+use crate::routing::{
+    Ipv4Network, RouteProtocol, RouteTable, DEFAULT_LOCAL_PREF, MAXIMUM_PREFIX_LENGTH,
+};
+use crate::table_dump::{DEFAULT_MRT_DUMP_FILES, DEFAULT_MRT_DUMP_INTERVAL};
+#[cfg(feature = "api-tls")]
+use crate::tls;
 use anyhow::{Context, Result};
-use std::net::Ipv4Addr;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
 // FromStrは文字列をパースして構造体に変換する処理を実装するトレイト
 use std::str::FromStr;
 
+/// TCP Connectionの確立に失敗してから、接続し直すまでの時間(秒)の既定値。
+pub const DEFAULT_CONNECT_RETRY_TIME: u64 = 120;
+
+/// IPv6のルートで受け入れるprefix長の上限の既定値。
+/// 多くのネットワークで、インターネットに広告されるIPv6のprefixは/48までに制限されている。
+pub const DEFAULT_MAX_PREFIX_LENGTH_IPV6: u8 = 48;
+
+/// IPv6のprefix長の最大値。
+const MAXIMUM_IPV6_PREFIX_LENGTH: u8 = 128;
+
+/// max-prefixに対する割合(%)で、ピアから受信したルートの数がこれに達したら警告する閾値の既定値。
+pub const DEFAULT_MAX_PREFIX_WARNING_THRESHOLD: u8 = 75;
+
 #[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
 pub struct Config {
     pub local_as: AutonomousSystemNumber,
     pub local_ip: Ipv4Addr,
-    remote_as: AutonomousSystemNumber,
+    pub remote_as: RemoteAs,
     pub remote_ip: Ipv4Addr,
+    /// 対向機器をIPアドレスではなくホスト名で指定した場合のホスト名。
+    /// 設定を読み込むときには名前解決せず、TCP Connectionを確立するたびに名前解決してremote_ipを更新する。
+    pub remote_host: Option<String>,
+    /// 空でなければ、この設定はピアのテンプレートである。
+    /// ホスト名を定期的に名前解決し、得られたIPv4アドレスごとにremote ipだけを変えたピアを作成する。
+    /// テンプレート自体のremote ipは使わない。
+    pub discover: Vec<String>,
+    /// discoverのホスト名を名前解決し直す間隔(秒)。
+    pub discovery_interval: u64,
+    /// ログや`show neighbors`でピアを区別するための説明。
+    pub description: Option<String>,
     pub mode: Mode,
     pub networks: Vec<Ipv4Network>,
+    /// 広告するIPv6のネットワーク。MP-BGPで広告するまでは、LocRibで保持するだけである。
+    pub ipv6_networks: Vec<ipnetwork::Ipv6Network>,
+    /// `network 10.0.0.0/24 backdoor`で指定したネットワーク。
+    /// ピアから学習したルートはLocRibに入れて広告するが、カーネルのルーティングテーブルには書き込まず、
+    /// IGPなどで書き込まれているカーネルのルートを優先する。自分からは広告しない。
+    pub backdoor_networks: Vec<Ipv4Network>,
+    pub export_policy: Policy,
+    /// 受信したルートをAdjRibInに入れる前に適用するポリシー。
+    pub import_policy: Policy,
+    /// policy-fileで定義した、export policyの後に適用するPolicyの名前。
+    pub export_policy_name: Option<String>,
+    /// policy-fileで定義した、import policyの後に適用するPolicyの名前。
+    pub import_policy_name: Option<String>,
+    /// 名前付きのPolicyを定義したファイル。変更すると、再起動せずに読み込み直して反映する。
+    pub policy_file: Option<PathBuf>,
+    /// trueのとき、import policyを適用する前の受信したルートを保持しておき、
+    /// import policyを変更したときにセッションを張り直さずに適用し直す。
+    pub soft_reconfiguration_inbound: bool,
+    /// カーネルのルーティングテーブルにルートを書き込むときのmetric(priority)。
+    pub route_metric: u32,
+    /// カーネルのルーティングテーブルにルートを書き込むときのprotocol。
+    pub route_protocol: RouteProtocol,
+    /// ルートを書き込み、広告するネットワークを探すカーネルのルーティングテーブル。
+    pub route_table: RouteTable,
+    /// このピアについて出力するデバッグログの種類。
+    /// 有効にした種類のメッセージは、hexdumpとデコードした構造でログに出力する。
+    pub debug: DebugFlags,
+    /// コントロールAPIを提供するUnix Domain Socketのパス。
+    pub control_socket: PathBuf,
+    /// コントロールAPIのクライアントを認証するトークンのファイル。Noneの場合は認証しない。
+    pub control_token_file: Option<PathBuf>,
+    /// コントロールAPIから参照できる状態遷移の履歴の保持件数。
+    pub state_history_size: usize,
+    /// コントロールAPIの`show capture`で参照できる、送受信したメッセージの保持件数。
+    pub message_capture_size: usize,
+    /// Minimum Route Advertisement Interval(秒)。Noneの場合はeBGP/iBGPに応じたデフォルト値を使う。
+    mrai: Option<u64>,
+    /// 受信したIPv4のルートのうち、prefix長がこれより長いものはAdjRibInに入れずに破棄する。
+    pub max_prefix_length: u8,
+    /// max_prefix_lengthと同じく、IPv6のルートで受け入れるprefix長の上限。
+    pub max_prefix_length_ipv6: u8,
+    /// ピアから受け入れるルートの数の上限。超えた場合はNotificationMessageを送ってセッションを切断する。
+    pub max_prefix: Option<usize>,
+    /// max-prefixに対する割合(%)。ルートの数がこれに達したら、切断される前に警告する。
+    pub max_prefix_warning_threshold: u8,
+    /// trueのとき、iBGPのピアにもNEXT_HOPを自分のIPに書き換えて広告する。
+    pub next_hop_self: bool,
+    /// 自分とeBGPのピアが共有しているサブネット。
+    /// NEXT_HOPがこのサブネットに含まれるルートは、NEXT_HOPを書き換えずに広告する。
+    pub shared_subnet: Option<Ipv4Network>,
+    /// OpenMessageで提案するHold Time(秒)。0のときはKeepaliveMessageを送受信しない。
+    /// Noneの場合は設定ファイル全体の`hold-time`か、HoldTime::newの値を使う。
+    hold_time: Option<HoldTime>,
+    /// KeepaliveMessageを送信する間隔(秒)。Noneの場合は交渉したHold Timeの1/3とする。
+    keepalive: Option<u64>,
+    /// TCP Connectionの確立に失敗してから、接続し直すまでの時間(秒)。
+    connect_retry: Option<u64>,
+    /// 受信したAS PathをASPAで検証するときに使う、ASPAを列挙したファイル。
+    pub aspa_file: Option<PathBuf>,
+    /// 対向機器が自分から見てCustomer, Peer, Providerのどれであるか。
+    pub remote_role: PeerRole,
+    /// 自分が広告するルートに付けるAIGPのmetric。
+    pub aigp: Option<u64>,
+    /// このピアとAIGPを送受信するか。Noneの場合はaigp_session()でiBGPのピアとだけ送受信する。
+    pub aigp_session: Option<bool>,
+    /// trueのとき、NO_EXPORTなどのwell-known communityが付いたルートも広告する。デバッグ用。
+    pub ignore_well_known_communities: bool,
+    /// trueのとき、このピアからルートを受信するだけで、AdjRibOutを作らずUpdateMessageも送信しない。
+    pub route_collector: bool,
+    /// LocRibをTABLE_DUMP_V2形式で定期的に書き出すディレクトリ。Noneの場合は書き出さない。
+    pub mrt_dump_dir: Option<PathBuf>,
+    /// MRTのテーブルダンプを書き出す間隔(秒)。
+    pub mrt_dump_interval: u64,
+    /// 保持するMRTのテーブルダンプのファイル数。0のときは古いファイルを削除しない。
+    pub mrt_dump_files: usize,
+    /// trueのとき、LocRibに加えて各ピアのAdjRibInもテーブルダンプとして書き出す。
+    pub mrt_dump_adj_rib_in: bool,
+    /// BGPのメッセージを送受信するトランスポート。
+    pub transport: Transport,
+    /// Active/Standby構成での役割。Noneの場合は単独で動作する。
+    pub ha: Option<HaRole>,
+    /// PrimaryとSecondaryが互いを認証し、同期する内容を改ざんされていないか確認する共有鍵のファイル。
+    pub ha_key_file: Option<PathBuf>,
+    /// LocRibのルートを保存するディレクトリ。Noneの場合はメモリ上にだけ保持する。
+    pub rib_store: Option<PathBuf>,
+    /// Long-Lived Graceful Restartで、セッションが切れた後にルートを保持する最大の秒数。
+    /// Noneの場合はLLGRのCapabilityを広告せず、セッションが切れたらすぐにルートを削除する。
+    pub llgr_stale_time: Option<u32>,
+    /// このピアとルートを交換するAFI/SAFI。Multiprotocol Capabilityで広告し、
+    /// 対向機器も広告したAFI/SAFIのテーブルだけをこのセッションで同期する。
+    pub address_families: Vec<AddressFamily>,
+    /// `/healthz`と`/readyz`に応答するHTTPサーバのアドレス。Noneの場合は起動しない。
+    pub health_listen: Option<SocketAddr>,
+    /// `/readyz`がreadyを返すのに必要な、Establishedのセッションの数。
+    /// Noneの場合はすべてのセッションがEstablishedになる必要がある。
+    pub ready_quorum: Option<usize>,
+    /// VIPのホストルートを広告、取り消しするHTTPのAPIのアドレス。Noneの場合は起動しない。
+    /// APIの認証にはcontrol-token-fileのトークンを使う。
+    pub vip_listen: Option<SocketAddr>,
+    /// VIPのAPIで広告するすべてのルートに付けるCommunity。
+    pub vip_communities: Vec<Community>,
+    /// HTTPのAPI(health-listen, vip-listen)をTLSで提供するための、PEMの証明書と秘密鍵のファイル。
+    /// Noneの場合はTLSを使わない。
+    pub api_tls_cert: Option<PathBuf>,
+    pub api_tls_key: Option<PathBuf>,
+    /// HTTPのAPIのクライアントに証明書を要求し、検証するCAの証明書のファイル。
+    /// Noneの場合はクライアントの証明書を要求しない。
+    pub api_tls_client_ca: Option<PathBuf>,
+    /// このピアから受信したUpdateMessageを処理する、1秒あたりの最大の数。
+    /// 超えた分は受信を止めてTCPのフロー制御で待たせる。Noneの場合は制限しない。
+    pub update_rate_limit: Option<u32>,
+    /// FSMのEvent、状態遷移、RIBの変更をJSON Linesで追記するファイル。Noneの場合は書き出さない。
+    pub trace_file: Option<PathBuf>,
+    /// UpdateMessageの処理とFSMの状態遷移のSpanを、OTLP/HTTPで送るコレクタのアドレス。
+    /// Noneの場合は送らない。
+    pub otlp_endpoint: Option<SocketAddr>,
+    /// 起動してから、自分のUpdateMessageの送信とカーネルのルーティングテーブルへの書き込みを
+    /// 待つ最大の秒数。すべてのピアからEnd-of-RIBを受信したら、経過前でも待つのをやめる。
+    /// Noneの場合は待たない。
+    pub update_delay: Option<u64>,
+    /// ログを送るsyslogのコレクタ。Noneの場合は標準出力にだけ書き出す。
+    pub syslog: Option<SyslogDestination>,
+    /// ログを書き出す先。指定しなければ、syslogを設定した場合は標準出力とsyslogの両方に書き出す。
+    pub log_output: LogOutput,
+    /// eBGPのピアから受け入れたルートに、import policyを適用する前に付けるLOCAL_PREF。
+    /// Noneの場合は設定ファイル全体の`default-local-pref`か、DEFAULT_LOCAL_PREFを使う。
+    default_local_pref: Option<u32>,
+    /// このピアから受け入れたルートに付けるweight。import policyで上書きできる。
+    pub weight: u32,
+    /// 書き込みタスクに渡して、まだ送信していないデータを溜めておける数。
+    pub outbound_queue_size: usize,
+    /// このピアとのTCP Connectionのパケットに付けるDSCP。Noneの場合は設定ファイル全体の`dscp`を使い、
+    /// それもなければ設定しない。
+    pub dscp: Option<Dscp>,
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
 pub enum Mode {
     Passive,
     Active,
+    /// ピアへの接続と、ピアからの接続の待ち受けを同時に行う。
+    /// 両方のTCP Connectionが確立した場合は、OpenMessageのBGP IdentifierでConnection Collisionを解決する。
+    Both,
 }
 
 impl FromStr for Mode {
@@ -28,7 +211,68 @@ impl FromStr for Mode {
         match s {
             "passive" | "Passive" => Ok(Mode::Passive),
             "active" | "Active" => Ok(Mode::Active),
-            _ => Err(ConfigParseError::from(anyhow::anyhow!("cannot parse {s}"))),
+            "both" | "Both" => Ok(Mode::Both),
+            _ => Err(ConfigParseError::InvalidValue {
+                kind: "mode",
+                value: s.to_owned(),
+            }),
+        }
+    }
+}
+
+/// 対向機器のAS番号として受け入れる値。
+/// AS番号を事前に知らないピアとも接続できるように、OpenMessageのAS番号を条件で受け入れられる。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum RemoteAs {
+    Number(AutonomousSystemNumber),
+    /// どのAS番号でも受け入れる。
+    Any,
+    /// 自分と異なるAS番号だけを受け入れる。
+    External,
+    /// 自分と同じAS番号だけを受け入れる。
+    Internal,
+}
+
+impl RemoteAs {
+    /// 対向機器から受信したOpenMessageのAS番号を受け入れるか。
+    /// remote_asはAS_TRANSではなく、4オクテットAS Capabilityで受け取った本来のAS番号である。
+    pub fn accepts(
+        &self,
+        local_as: AutonomousSystemNumber,
+        remote_as: AutonomousSystemNumber,
+    ) -> bool {
+        match self {
+            RemoteAs::Number(as_number) => *as_number == remote_as,
+            RemoteAs::Any => true,
+            RemoteAs::External => local_as != remote_as,
+            RemoteAs::Internal => local_as == remote_as,
+        }
+    }
+}
+
+impl FromStr for RemoteAs {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(RemoteAs::Any),
+            "external" => Ok(RemoteAs::External),
+            "internal" => Ok(RemoteAs::Internal),
+            _ => Ok(RemoteAs::Number(AutonomousSystemNumber::from(
+                s.parse::<u32>()
+                    .context(format!("cannot parse remote as `{s}`"))?,
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for RemoteAs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RemoteAs::Number(as_number) => write!(f, "{}", as_number),
+            RemoteAs::Any => write!(f, "any"),
+            RemoteAs::External => write!(f, "external"),
+            RemoteAs::Internal => write!(f, "internal"),
         }
     }
 }
@@ -38,7 +282,7 @@ impl FromStr for Config {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let config: Vec<&str> = s.split(' ').collect();
-        let local_as = AutonomousSystemNumber::from(config[0].parse::<u16>().context(format!(
+        let local_as = AutonomousSystemNumber::from(config[0].parse::<u32>().context(format!(
             "cannot parse 1st part of config, `{0}`, \
              as as-number and config is {1}",
             config[0], s
@@ -48,35 +292,1394 @@ impl FromStr for Config {
         as as-number and config is {1}",
             config[1], s
         ))?;
-        let remote_as = AutonomousSystemNumber::from(config[2].parse::<u16>().context(format!(
+        let remote_as: RemoteAs = config[2].parse().context(format!(
             "cannot parse 3rd part of config, `{0}`, \
              as as-number and config is {1}",
             config[2], s
-        ))?);
-        let remote_ip: Ipv4Addr = config[3].parse().context(format!(
-            "cannot parse 4th part of config, `{0}`, \
-         as as-number and config is {1}",
-            config[3], s
         ))?;
+        // IPアドレスとして読み取れなければホスト名とする。名前解決は接続するときに行うため、
+        // それまでのremote_ipは未指定のアドレスにしておく。
+        let (remote_ip, remote_host) = match config[3].parse::<Ipv4Addr>() {
+            Ok(remote_ip) => (remote_ip, None),
+            Err(_) if is_host_name(config[3]) => {
+                (Ipv4Addr::UNSPECIFIED, Some(config[3].to_owned()))
+            }
+            Err(e) => Err(e).context(format!(
+                "cannot parse 4th part of config, `{0}`, \
+                 as ip address or host name and config is {1}",
+                config[3], s
+            ))?,
+        };
         let mode: Mode = config[4].parse().context(format!(
             "cannot parse 5th part of config, `{0}`, \
          as as-number and config is {1}",
             config[4], s
         ))?;
         let mut networks: Vec<Ipv4Network> = vec![];
-        for network in &config[5..] {
-            networks.push(network.parse().context(format!(
-                "cannot parse config[5..], `{0}` as Ipv4Network and config is {1}",
-                network, s
-            ))?)
+        let mut ipv6_networks = vec![];
+        let mut backdoor_networks = vec![];
+        let mut description = None;
+        let mut export_policy = Policy::new();
+        let mut import_policy = Policy::new();
+        let mut export_policy_name = None;
+        let mut import_policy_name = None;
+        let mut policy_file = None;
+        let mut soft_reconfiguration_inbound = false;
+        let mut route_metric = 20;
+        let mut route_protocol = RouteProtocol::default();
+        let mut route_table = RouteTable::default();
+        let mut debug = DebugFlags::default();
+        let mut control_socket = PathBuf::from(DEFAULT_CONTROL_SOCKET);
+        let mut control_token_file = None;
+        let mut state_history_size = DEFAULT_STATE_HISTORY_SIZE;
+        let mut message_capture_size = DEFAULT_MESSAGE_CAPTURE_SIZE;
+        let mut mrai = None;
+        let mut max_prefix_length = MAXIMUM_PREFIX_LENGTH;
+        let mut max_prefix_length_ipv6 = DEFAULT_MAX_PREFIX_LENGTH_IPV6;
+        let mut next_hop_self = false;
+        let mut shared_subnet = None;
+        let mut hold_time = None;
+        let mut keepalive = None;
+        let mut connect_retry = None;
+        let mut aspa_file = None;
+        let mut remote_role = PeerRole::default();
+        let mut aigp = None;
+        let mut aigp_session = None;
+        let mut ignore_well_known_communities = false;
+        let mut route_collector = false;
+        let mut mrt_dump_dir = None;
+        let mut mrt_dump_interval = DEFAULT_MRT_DUMP_INTERVAL;
+        let mut mrt_dump_files = DEFAULT_MRT_DUMP_FILES;
+        let mut mrt_dump_adj_rib_in = false;
+        let mut transport = Transport::default();
+        let mut ha = None;
+        let mut ha_key_file = None;
+        let mut rib_store = None;
+        let mut llgr_stale_time = None;
+        let mut address_families = None;
+        let mut health_listen = None;
+        let mut ready_quorum = None;
+        let mut vip_listen = None;
+        let mut api_tls_cert = None;
+        let mut api_tls_key = None;
+        let mut api_tls_client_ca = None;
+        let mut vip_communities = vec![];
+        let mut update_rate_limit = None;
+        let mut trace_file = None;
+        let mut otlp_endpoint = None;
+        let mut update_delay = None;
+        let mut discover = vec![];
+        let mut discovery_interval = DEFAULT_DISCOVERY_INTERVAL;
+        let mut syslog = None;
+        let mut log_output = None;
+        let mut default_local_pref = None;
+        let mut weight = 0;
+        let mut outbound_queue_size = DEFAULT_OUTBOUND_QUEUE_CAPACITY;
+        let mut dscp = None;
+        let mut max_prefix = None;
+        let mut max_prefix_warning_threshold = DEFAULT_MAX_PREFIX_WARNING_THRESHOLD;
+        // config[5..]にはNetworkと、キーワードから始まるオプションが並ぶ。
+        // 例: `10.100.220.0/24 export set as-path prepend 64512 64512`
+        let mut tokens = config[5..].iter().copied().peekable();
+        while let Some(token) = tokens.next() {
+            match token {
+                "network" => {
+                    let network = parse_option_value(token, &mut tokens)?;
+                    if tokens.next_if_eq(&"backdoor").is_some() {
+                        backdoor_networks.push(network);
+                    } else {
+                        networks.push(network);
+                    }
+                }
+                "description" => description = Some(parse_quoted_value(token, &mut tokens)?),
+                "export" => export_policy.push(PolicyAction::parse_from_tokens(&mut tokens)?),
+                "import" => import_policy.push(PolicyAction::parse_from_tokens(&mut tokens)?),
+                "export-policy" => {
+                    export_policy_name = Some(parse_option_value(token, &mut tokens)?)
+                }
+                "import-policy" => {
+                    import_policy_name = Some(parse_option_value(token, &mut tokens)?)
+                }
+                "policy-file" => policy_file = Some(parse_option_value(token, &mut tokens)?),
+                "soft-reconfiguration-inbound" => soft_reconfiguration_inbound = true,
+                "aspa-file" => aspa_file = Some(parse_option_value(token, &mut tokens)?),
+                "remote-role" => remote_role = parse_option_value(token, &mut tokens)?,
+                "aigp" => aigp = Some(parse_option_value(token, &mut tokens)?),
+                "aigp-session" => aigp_session = Some(parse_option_value(token, &mut tokens)?),
+                "route-metric" => route_metric = parse_option_value(token, &mut tokens)?,
+                "route-protocol" => route_protocol = parse_option_value(token, &mut tokens)?,
+                "route-table" => route_table = parse_option_value(token, &mut tokens)?,
+                "debug-messages" => debug = DebugFlags::all_messages(),
+                "debug" => debug = parse_option_value(token, &mut tokens)?,
+                "next-hop-self" => next_hop_self = true,
+                "ignore-well-known-communities" => ignore_well_known_communities = true,
+                "route-collector" => route_collector = true,
+                "rib-store" => rib_store = Some(parse_option_value(token, &mut tokens)?),
+                "mrt-dump-dir" => mrt_dump_dir = Some(parse_option_value(token, &mut tokens)?),
+                "mrt-dump-interval" => {
+                    mrt_dump_interval = parse_option_value(token, &mut tokens)?;
+                    if mrt_dump_interval == 0 {
+                        return Err(ConfigParseError::OutOfRange {
+                            key: "mrt-dump-interval",
+                            expected: "1以上".to_owned(),
+                        });
+                    }
+                }
+                "mrt-dump-files" => mrt_dump_files = parse_option_value(token, &mut tokens)?,
+                "mrt-dump-adj-rib-in" => mrt_dump_adj_rib_in = true,
+                "trace-file" => trace_file = Some(parse_option_value(token, &mut tokens)?),
+                "otlp-endpoint" => otlp_endpoint = Some(parse_option_value(token, &mut tokens)?),
+                "update-delay" => update_delay = Some(parse_option_value(token, &mut tokens)?),
+                "discover" => {
+                    let hosts: String = parse_option_value(token, &mut tokens)?;
+                    discover = hosts.split(',').map(|h| h.to_owned()).collect();
+                }
+                "discovery-interval" => {
+                    discovery_interval = parse_option_value(token, &mut tokens)?;
+                    if discovery_interval == 0 {
+                        return Err(ConfigParseError::OutOfRange {
+                            key: "discovery-interval",
+                            expected: "1以上".to_owned(),
+                        });
+                    }
+                }
+                "syslog" => syslog = Some(parse_option_value(token, &mut tokens)?),
+                "log-output" => log_output = Some(parse_option_value(token, &mut tokens)?),
+                "transport" => transport = parse_option_value(token, &mut tokens)?,
+                "ha-primary" | "ha-secondary" => {
+                    let address: String = parse_option_value(token, &mut tokens)?;
+                    ha = Some(HaRole::parse(token, &address)?);
+                }
+                "ha-key-file" => ha_key_file = Some(parse_option_value(token, &mut tokens)?),
+                "shared-subnet" => shared_subnet = Some(parse_option_value(token, &mut tokens)?),
+                "control-socket" => control_socket = parse_option_value(token, &mut tokens)?,
+                "control-token-file" => {
+                    control_token_file = Some(parse_option_value(token, &mut tokens)?)
+                }
+                "health-listen" => health_listen = Some(parse_option_value(token, &mut tokens)?),
+                "vip-listen" => vip_listen = Some(parse_option_value(token, &mut tokens)?),
+                "vip-community" => vip_communities.push(parse_option_value(token, &mut tokens)?),
+                #[cfg(not(feature = "api-tls"))]
+                "api-tls-cert" | "api-tls-key" | "api-tls-client-ca" => {
+                    return Err(ConfigParseError::FeatureDisabled {
+                        option: "HTTPのAPIのTLS",
+                        feature: "api-tls",
+                    })
+                }
+                "api-tls-cert" => api_tls_cert = Some(parse_option_value(token, &mut tokens)?),
+                "api-tls-key" => api_tls_key = Some(parse_option_value(token, &mut tokens)?),
+                "api-tls-client-ca" => {
+                    api_tls_client_ca = Some(parse_option_value(token, &mut tokens)?)
+                }
+                "ready-quorum" => {
+                    let quorum = parse_option_value(token, &mut tokens)?;
+                    if quorum == 0 {
+                        return Err(ConfigParseError::OutOfRange {
+                            key: "ready-quorum",
+                            expected: "1以上".to_owned(),
+                        });
+                    }
+                    ready_quorum = Some(quorum);
+                }
+                "update-rate-limit" => {
+                    let rate = parse_option_value(token, &mut tokens)?;
+                    if rate == 0 {
+                        return Err(ConfigParseError::OutOfRange {
+                            key: "update-rate-limit",
+                            expected: "1以上".to_owned(),
+                        });
+                    }
+                    update_rate_limit = Some(rate);
+                }
+                "weight" => weight = parse_option_value(token, &mut tokens)?,
+                "dscp" => dscp = Some(parse_option_value(token, &mut tokens)?),
+                "outbound-queue-size" => {
+                    outbound_queue_size = parse_option_value(token, &mut tokens)?;
+                    if outbound_queue_size == 0 {
+                        return Err(ConfigParseError::OutOfRange {
+                            key: "outbound-queue-size",
+                            expected: "1以上".to_owned(),
+                        });
+                    }
+                }
+                "default-local-pref" => {
+                    default_local_pref = Some(parse_option_value(token, &mut tokens)?)
+                }
+                "mrai" => mrai = Some(parse_option_value(token, &mut tokens)?),
+                "max-prefix-length" => {
+                    max_prefix_length = parse_option_value(token, &mut tokens)?;
+                    if max_prefix_length > MAXIMUM_PREFIX_LENGTH {
+                        return Err(ConfigParseError::OutOfRange {
+                            key: "max-prefix-length",
+                            expected: format!("{}以下", MAXIMUM_PREFIX_LENGTH),
+                        });
+                    }
+                }
+                "max-prefix-length-ipv6" => {
+                    max_prefix_length_ipv6 = parse_option_value(token, &mut tokens)?;
+                    if max_prefix_length_ipv6 > MAXIMUM_IPV6_PREFIX_LENGTH {
+                        return Err(ConfigParseError::OutOfRange {
+                            key: "max-prefix-length-ipv6",
+                            expected: format!("{}以下", MAXIMUM_IPV6_PREFIX_LENGTH),
+                        });
+                    }
+                }
+                "max-prefix" => {
+                    let limit = parse_option_value(token, &mut tokens)?;
+                    if limit == 0 {
+                        return Err(ConfigParseError::OutOfRange {
+                            key: "max-prefix",
+                            expected: "1以上".to_owned(),
+                        });
+                    }
+                    max_prefix = Some(limit);
+                }
+                "max-prefix-warning" => {
+                    max_prefix_warning_threshold = parse_option_value(token, &mut tokens)?;
+                    if !(1..=100).contains(&max_prefix_warning_threshold) {
+                        return Err(ConfigParseError::OutOfRange {
+                            key: "max-prefix-warning",
+                            expected: "1から100まで".to_owned(),
+                        });
+                    }
+                }
+                "hold-time" => hold_time = Some(parse_hold_time(token, &mut tokens)?),
+                "keepalive" => keepalive = Some(parse_seconds("keepalive", &mut tokens)?),
+                "connect-retry" => {
+                    connect_retry = Some(parse_seconds("connect-retry", &mut tokens)?)
+                }
+                "llgr-stale-time" => {
+                    let stale_time = parse_option_value(token, &mut tokens)?;
+                    if stale_time > MAXIMUM_LLGR_STALE_TIME {
+                        return Err(ConfigParseError::OutOfRange {
+                            key: "llgr-stale-time",
+                            expected: format!("{}以下", MAXIMUM_LLGR_STALE_TIME),
+                        });
+                    }
+                    llgr_stale_time = Some(stale_time);
+                }
+                "afi-safi" => {
+                    let value: String = parse_option_value(token, &mut tokens)?;
+                    let mut families = vec![];
+                    for family in value.split(',') {
+                        let family: AddressFamily = family.parse()?;
+                        if !families.contains(&family) {
+                            families.push(family);
+                        }
+                    }
+                    address_families = Some(families);
+                }
+                "state-history-size" => {
+                    state_history_size = parse_option_value(token, &mut tokens)?
+                }
+                "message-capture-size" => {
+                    message_capture_size = parse_option_value(token, &mut tokens)?
+                }
+                network if network.contains(':') => {
+                    ipv6_networks.push(network.parse().context(format!(
+                        "cannot parse config[5..], `{0}` as Ipv6Network and config is {1}",
+                        network, s
+                    ))?)
+                }
+                network => networks.push(network.parse().context(format!(
+                    "cannot parse config[5..], `{0}` as Ipv4Network and config is {1}",
+                    network, s
+                ))?),
+            }
+        }
+        // 指定されていなければIPv4 Unicastを交換し、
+        // IPv6のネットワークを広告する場合はIPv6 Unicastも交換する。
+        let address_families = address_families.unwrap_or_else(|| {
+            let mut families = vec![AddressFamily::IPV4_UNICAST];
+            if !ipv6_networks.is_empty() {
+                families.push(AddressFamily::IPV6_UNICAST);
+            }
+            families
+        });
+        let log_output = match (log_output, &syslog) {
+            (None, None) => LogOutput::Stdout,
+            (None, Some(_)) => LogOutput::Both,
+            (Some(LogOutput::Syslog | LogOutput::Both), None) => {
+                return Err(anyhow::anyhow!(
+                    "log-outputでsyslogに書き出すには、syslogでコレクタを指定してください。"
+                )
+                .into())
+            }
+            (Some(output), _) => output,
+        };
+        if (export_policy_name.is_some() || import_policy_name.is_some()) && policy_file.is_none() {
+            return Err(anyhow::anyhow!(
+                "export-policyとimport-policyで参照するポリシーを、policy-fileで指定してください。"
+            )
+            .into());
+        }
+        if vip_listen.is_some() && control_token_file.is_none() {
+            return Err(anyhow::anyhow!(
+                "vip-listenを指定する場合は、APIを認証するcontrol-token-fileも指定してください。"
+            )
+            .into());
+        }
+        if api_tls_cert.is_some() != api_tls_key.is_some() {
+            return Err(
+                anyhow::anyhow!("api-tls-certとapi-tls-keyは両方を指定してください。").into(),
+            );
+        }
+        if api_tls_client_ca.is_some() && api_tls_cert.is_none() {
+            return Err(anyhow::anyhow!(
+                "api-tls-client-caを指定する場合は、api-tls-certとapi-tls-keyも指定してください。"
+            )
+            .into());
+        }
+        if vip_listen.is_some_and(|address: SocketAddr| !address.ip().is_loopback())
+            && api_tls_cert.is_none()
+        {
+            return Err(anyhow::anyhow!(
+                "ループバック以外のアドレスでvip-listenを指定する場合は、トークンを暗号化するためにapi-tls-certとapi-tls-keyも指定してください。"
+            )
+            .into());
+        }
+        if ha.is_some() && ha_key_file.is_none() {
+            return Err(anyhow::anyhow!(
+                "ha-primaryかha-secondaryを指定する場合は、Primaryと共有する鍵のha-key-fileも指定してください。"
+            )
+            .into());
         }
         Ok(Self {
             local_as,
             local_ip,
             remote_as,
             remote_ip,
+            remote_host,
+            discover,
+            discovery_interval,
+            description,
             mode,
             networks,
+            ipv6_networks,
+            backdoor_networks,
+            export_policy,
+            import_policy,
+            export_policy_name,
+            import_policy_name,
+            policy_file,
+            soft_reconfiguration_inbound,
+            route_metric,
+            route_protocol,
+            route_table,
+            debug,
+            control_socket,
+            control_token_file,
+            state_history_size,
+            message_capture_size,
+            mrai,
+            max_prefix_length,
+            max_prefix_length_ipv6,
+            max_prefix,
+            max_prefix_warning_threshold,
+            next_hop_self,
+            shared_subnet,
+            hold_time,
+            keepalive,
+            connect_retry,
+            aspa_file,
+            remote_role,
+            aigp,
+            // RFC 7311では、AIGPは既定では同じAS内のピアとだけ送受信する。
+            aigp_session,
+            ignore_well_known_communities,
+            route_collector,
+            mrt_dump_dir,
+            mrt_dump_interval,
+            mrt_dump_files,
+            mrt_dump_adj_rib_in,
+            transport,
+            ha,
+            ha_key_file,
+            rib_store,
+            llgr_stale_time,
+            address_families,
+            health_listen,
+            ready_quorum,
+            vip_listen,
+            vip_communities,
+            api_tls_cert,
+            api_tls_key,
+            api_tls_client_ca,
+            update_rate_limit,
+            trace_file,
+            otlp_endpoint,
+            update_delay,
+            syslog,
+            log_output,
+            default_local_pref,
+            weight,
+            outbound_queue_size,
+            dscp,
         })
     }
 }
+
+impl Config {
+    /// 1行に1つのピアの設定を書いたテキストを読み取る。空行と`#`から始まる行は無視する。
+    /// `default-local-pref 200`の行は、`default-local-pref`を指定していないすべてのピアに適用する。
+    /// `hold-time`, `keepalive`, `connect-retry`, `mrai`, `dscp`の行も同じく、ピアごとの指定がなければ適用する。
+    pub fn parse_lines(text: &str) -> Result<Vec<Config>, ConfigParseError> {
+        let mut default_local_pref = None;
+        let mut hold_time = None;
+        let mut keepalive = None;
+        let mut connect_retry = None;
+        let mut mrai = None;
+        let mut dscp = None;
+        let mut configs = vec![];
+        for line in text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        {
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some(token @ "default-local-pref") => {
+                    default_local_pref = Some(parse_option_value(token, &mut tokens)?)
+                }
+                Some(token @ "hold-time") => hold_time = Some(parse_hold_time(token, &mut tokens)?),
+                Some("keepalive") => keepalive = Some(parse_seconds("keepalive", &mut tokens)?),
+                Some("connect-retry") => {
+                    connect_retry = Some(parse_seconds("connect-retry", &mut tokens)?)
+                }
+                Some(token @ "mrai") => mrai = Some(parse_option_value(token, &mut tokens)?),
+                Some(token @ "dscp") => dscp = Some(parse_option_value(token, &mut tokens)?),
+                _ => configs.push(line.parse::<Config>()?),
+            }
+        }
+        for config in &mut configs {
+            config.default_local_pref = config.default_local_pref.or(default_local_pref);
+            config.hold_time = config.hold_time.or(hold_time);
+            config.keepalive = config.keepalive.or(keepalive);
+            config.connect_retry = config.connect_retry.or(connect_retry);
+            config.mrai = config.mrai.or(mrai);
+            config.dscp = config.dscp.or(dscp);
+        }
+        Ok(configs)
+    }
+
+    /// parse_linesで読み取るテキストを検証し、見つかった問題をすべて返す。`howbgp check-config`で使う。
+    /// 各行を読み取れるかに加えて、ピアの重複と、設定で参照しているファイルの内容も確かめる。
+    pub fn check(text: &str) -> Vec<String> {
+        let mut diagnostics = vec![];
+        for (i, line) in text.lines().enumerate() {
+            if let Err(e) = Config::parse_lines(line) {
+                diagnostics.push(format!("{}行目: {:#}", i + 1, e));
+            }
+        }
+        if !diagnostics.is_empty() {
+            return diagnostics;
+        }
+        let configs = match Config::parse_lines(text) {
+            Ok(configs) => configs,
+            Err(e) => return vec![format!("{:#}", e)],
+        };
+        let mut peers: Vec<&Config> = vec![];
+        // テンプレートのremote ipは使わないため、重複していてもよい。
+        for config in configs.iter().filter(|c| !c.is_template()) {
+            if peers.iter().any(|p| p.is_same_peer(config)) {
+                diagnostics.push(format!("{}のピアが重複しています。", config.display_name()));
+            }
+            peers.push(config);
+            if config.local_ip == config.remote_ip {
+                diagnostics.push(format!(
+                    "{}のピアのlocal ipとremote ipが同じです。",
+                    config.remote_ip
+                ));
+            }
+            if let Some(path) = &config.aspa_file {
+                if let Err(e) = AspaTable::load(path) {
+                    diagnostics.push(format!("{}のピアのaspa-file: {:#}", config.remote_ip, e));
+                }
+            }
+            if let Some(path) = &config.control_token_file {
+                if let Err(e) = ControlTokens::load(path) {
+                    diagnostics.push(format!(
+                        "{}のピアのcontrol-token-file: {:#}",
+                        config.remote_ip, e
+                    ));
+                }
+            }
+            #[cfg(feature = "api-tls")]
+            if let (Some(cert), Some(key)) = (&config.api_tls_cert, &config.api_tls_key) {
+                if let Err(e) = tls::server_config(cert, key, config.api_tls_client_ca.as_deref()) {
+                    diagnostics.push(format!("{}のピアのapi-tls-cert: {:#}", config.remote_ip, e));
+                }
+            }
+            if let Some(path) = &config.ha_key_file {
+                if let Err(e) = HaKey::load(path) {
+                    diagnostics.push(format!("{}のピアのha-key-file: {:#}", config.remote_ip, e));
+                }
+            }
+            if let Some(path) = &config.policy_file {
+                match PolicySet::load(path) {
+                    Ok(policies) => {
+                        for name in config.policy_names() {
+                            if policies.get(name).is_none() {
+                                diagnostics.push(format!(
+                                    "{}のピアが参照しているポリシー{}が、{:?}にありません。",
+                                    config.remote_ip, name, path
+                                ));
+                            }
+                        }
+                    }
+                    Err(e) => diagnostics
+                        .push(format!("{}のピアのpolicy-file: {:#}", config.remote_ip, e)),
+                }
+            }
+        }
+        diagnostics
+    }
+
+    /// 設定をotherに変えるときに、セッションを張り直す必要があるか。
+    /// export policyなど、AdjRibOutを作り直せば反映できる設定だけが違う場合はfalseを返す。
+    pub fn requires_session_reset(&self, other: &Config) -> bool {
+        let mut other = other.clone();
+        other.description = self.description.clone();
+        other.export_policy = self.export_policy.clone();
+        other.export_policy_name = self.export_policy_name.clone();
+        other.policy_file = self.policy_file.clone();
+        if self.soft_reconfiguration_inbound && other.soft_reconfiguration_inbound {
+            other.import_policy = self.import_policy.clone();
+            other.import_policy_name = self.import_policy_name.clone();
+        }
+        other.mrai = self.mrai;
+        other.max_prefix = self.max_prefix;
+        other.max_prefix_warning_threshold = self.max_prefix_warning_threshold;
+        other.connect_retry = self.connect_retry;
+        other.next_hop_self = self.next_hop_self;
+        other.aigp = self.aigp;
+        other.ignore_well_known_communities = self.ignore_well_known_communities;
+        other.debug = self.debug;
+        *self != other
+    }
+
+    /// export-policyとimport-policyで参照している、policy-fileのPolicyの名前。
+    pub fn policy_names(&self) -> impl Iterator<Item = &String> {
+        self.export_policy_name
+            .iter()
+            .chain(self.import_policy_name.iter())
+    }
+
+    /// discoverでピアを作成するテンプレートであるか。
+    pub fn is_template(&self) -> bool {
+        !self.discover.is_empty()
+    }
+
+    /// テンプレートから、remote_ipと接続するピアの設定を作成する。
+    pub fn instantiate(&self, remote_ip: Ipv4Addr) -> Config {
+        let mut config = self.clone();
+        config.remote_ip = remote_ip;
+        config.remote_host = None;
+        config.discover = vec![];
+        config
+    }
+
+    /// 受信したルートの数がこれ以上になったら警告する数。max-prefixを設定していなければNone。
+    pub fn max_prefix_warning_count(&self) -> Option<usize> {
+        self.max_prefix
+            .map(|limit| limit * self.max_prefix_warning_threshold as usize / 100)
+    }
+
+    /// 同じ対向機器のピアの設定であるか。ホスト名で指定したピアは、名前解決する前の
+    /// remote_ipが決まっていないため、ホスト名で比べる。
+    pub fn is_same_peer(&self, other: &Config) -> bool {
+        match (&self.remote_host, &other.remote_host) {
+            (None, None) => self.remote_ip == other.remote_ip,
+            (host, other_host) => host == other_host,
+        }
+    }
+
+    /// ログに出力するときのピアの名前。descriptionがあれば`description (IP)`とする。
+    /// ホスト名で指定したピアは、IPアドレスの代わりにホスト名を使う。
+    pub fn display_name(&self) -> String {
+        let address = match &self.remote_host {
+            Some(host) => host.clone(),
+            None => self.remote_ip.to_string(),
+        };
+        match &self.description {
+            Some(description) => format!("{} ({})", description, address),
+            None => address,
+        }
+    }
+
+    /// 対向機器が別のASに属する(eBGPの)ピアであるか。
+    /// remote-asが`any`の場合はOpenMessageを受信するまで分からないため、eBGPとして扱う。
+    /// OpenMessageを受け入れた後は、Peerがremote_asを受信したAS番号に置き換えて判断する。
+    pub fn is_ebgp(&self) -> bool {
+        match self.remote_as {
+            RemoteAs::Number(as_number) => self.local_as != as_number,
+            RemoteAs::Any | RemoteAs::External => true,
+            RemoteAs::Internal => false,
+        }
+    }
+
+    /// 受信したnetworkのprefix長が、アドレスファミリごとのmax-prefix-length以下か。
+    pub fn accepts_prefix_length(&self, network: ipnetwork::IpNetwork) -> bool {
+        match network {
+            ipnetwork::IpNetwork::V4(network) => network.prefix() <= self.max_prefix_length,
+            ipnetwork::IpNetwork::V6(network) => network.prefix() <= self.max_prefix_length_ipv6,
+        }
+    }
+
+    /// このピアとAIGPを送受信するか。設定しない場合はiBGPのピアとだけ送受信する(RFC 7311)。
+    /// remote-asが`any`の場合は、is_ebgpと同じくOpenMessageで受信したAS番号で判断する。
+    pub fn aigp_session(&self) -> bool {
+        self.aigp_session.unwrap_or(!self.is_ebgp())
+    }
+
+    /// eBGPのピアから受け入れたルートに付けるLOCAL_PREF。
+    pub fn default_local_pref(&self) -> u32 {
+        self.default_local_pref.unwrap_or(DEFAULT_LOCAL_PREF)
+    }
+
+    /// OpenMessageで提案するHold Time。
+    pub fn hold_time(&self) -> HoldTime {
+        self.hold_time.unwrap_or_default()
+    }
+
+    /// 交渉したhold_timeのセッションで、KeepaliveMessageを送信する間隔。
+    /// 設定した間隔がhold_timeの1/3より長い場合は、Hold Timerが切れないように1/3に縮める。
+    pub fn keepalive_interval(&self, hold_time: HoldTime) -> Duration {
+        let default = hold_time.keepalive_interval();
+        self.keepalive
+            .map_or(default, |seconds| Duration::from_secs(seconds).min(default))
+    }
+
+    /// TCP Connectionの確立に失敗してから、接続し直すまでの時間。
+    /// 設定されていない場合はRFC 4271で推奨されている120秒とする。
+    pub fn connect_retry_time(&self) -> Duration {
+        Duration::from_secs(self.connect_retry.unwrap_or(DEFAULT_CONNECT_RETRY_TIME))
+    }
+
+    /// 同じprefixのUpdateMessageを続けて送信するときの最小の間隔。
+    /// 設定されていない場合はeBGPでは30秒、iBGPでは5秒とする。
+    pub fn mrai(&self) -> Duration {
+        let default = if self.is_ebgp() { 30 } else { 5 };
+        Duration::from_secs(self.mrai.unwrap_or(default))
+    }
+
+    /// NEXT_HOPがlearnedのルートをこのピアに広告するときのNEXT_HOP。
+    /// eBGPでは自分のIPに書き換え、iBGPでは学習したNEXT_HOPをそのまま使う。
+    /// ただしeBGPでも、ピアと共有しているサブネット内のNEXT_HOPはそのまま使う(third-party next hop)。
+    pub fn advertised_next_hop(&self, learned: Ipv4Addr) -> Ipv4Addr {
+        if self.next_hop_self {
+            return self.local_ip;
+        }
+        if !self.is_ebgp() {
+            return learned;
+        }
+        match self.shared_subnet {
+            Some(subnet) if subnet.contains(learned) => learned,
+            _ => self.local_ip,
+        }
+    }
+}
+
+/// `route-metric 20`のような`キーワード 値`形式のオプションの値を読み取る。
+fn parse_option_value<'a, T, I>(key: &str, tokens: &mut I) -> Result<T, ConfigParseError>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+    I: Iterator<Item = &'a str>,
+{
+    let value = tokens
+        .next()
+        .ok_or_else(|| ConfigParseError::MissingValue {
+            key: key.to_owned(),
+        })?;
+    value
+        .parse()
+        .map_err(|e| ConfigParseError::InvalidOptionValue {
+            key: key.to_owned(),
+            value: value.to_owned(),
+            source: Box::new(e),
+        })
+}
+
+/// Hold Timeを読み取る。0か3以上である必要がある。
+fn parse_hold_time<'a, I>(key: &str, tokens: &mut I) -> Result<HoldTime, ConfigParseError>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let hold_time = HoldTime::from(parse_option_value::<u16, _>(key, tokens)?);
+    if !hold_time.is_acceptable() {
+        return Err(ConfigParseError::OutOfRange {
+            key: "hold-time",
+            expected: "0か3以上".to_owned(),
+        });
+    }
+    Ok(hold_time)
+}
+
+/// 1以上の秒数を読み取る。
+fn parse_seconds<'a, I>(key: &'static str, tokens: &mut I) -> Result<u64, ConfigParseError>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let seconds = parse_option_value(key, tokens)?;
+    if seconds == 0 {
+        return Err(ConfigParseError::OutOfRange {
+            key,
+            expected: "1以上".to_owned(),
+        });
+    }
+    Ok(seconds)
+}
+
+/// keyの後に続く文字列を読み取る。
+/// `"`で囲んだ場合は、空白を含む文字列を1つの値として扱う。
+fn parse_quoted_value<'a, I>(key: &str, tokens: &mut I) -> Result<String, ConfigParseError>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let first = tokens
+        .next()
+        .ok_or_else(|| ConfigParseError::MissingValue {
+            key: key.to_owned(),
+        })?;
+    let quoted = match first.strip_prefix('"') {
+        Some(quoted) => quoted,
+        None => return Ok(first.to_owned()),
+    };
+    let mut words = vec![];
+    let mut word = quoted;
+    loop {
+        if let Some(last) = word.strip_suffix('"') {
+            words.push(last);
+            return Ok(words.join(" "));
+        }
+        words.push(word);
+        word = tokens
+            .next()
+            .ok_or_else(|| ConfigParseError::UnterminatedQuote {
+                key: key.to_owned(),
+            })?;
+    }
+}
+
+/// ピアを指定するホスト名として使える文字列であるか。英数字とハイフンのラベルを`.`で区切ったもので、
+/// `127.0.0.x`のように打ち間違えたIPv4アドレスに見えるものはホスト名として扱わない。
+fn is_host_name(s: &str) -> bool {
+    let labels: Vec<&str> = s.split('.').collect();
+    let valid_labels = labels.iter().all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+    });
+    let numeric_labels = labels
+        .iter()
+        .filter(|label| label.chars().all(|c| c.is_ascii_digit()))
+        .count();
+    let looks_like_ipv4_address = labels.len() == 4 && numeric_labels >= 3;
+    s.len() <= 253 && valid_labels && !looks_like_ipv4_address
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bgp_type::AS_TRANS;
+
+    #[test]
+    fn timers_in_neighbor_line_override_global_lines() {
+        let configs = Config::parse_lines(
+            "hold-time 180\n\
+             keepalive 60\n\
+             connect-retry 30\n\
+             mrai 10\n\
+             64512 127.0.0.1 64513 127.0.0.2 active\n\
+             64512 127.0.0.1 64514 127.0.0.3 active hold-time 9 keepalive 1 connect-retry 5 mrai 0\n",
+        )
+        .unwrap();
+
+        assert_eq!(configs[0].hold_time(), HoldTime::from(180));
+        assert_eq!(
+            configs[0].keepalive_interval(HoldTime::from(180)),
+            Duration::from_secs(60)
+        );
+        assert_eq!(configs[0].connect_retry_time(), Duration::from_secs(30));
+        assert_eq!(configs[0].mrai(), Duration::from_secs(10));
+        assert_eq!(configs[1].hold_time(), HoldTime::from(9));
+        assert_eq!(
+            configs[1].keepalive_interval(HoldTime::from(9)),
+            Duration::from_secs(1)
+        );
+        assert_eq!(configs[1].connect_retry_time(), Duration::from_secs(5));
+        assert_eq!(configs[1].mrai(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn neighbor_can_be_given_by_host_name() {
+        let config: Config = "64512 127.0.0.1 64513 localhost active".parse().unwrap();
+
+        assert_eq!(config.remote_ip, Ipv4Addr::UNSPECIFIED);
+        assert_eq!(config.remote_host.as_deref(), Some("localhost"));
+        let by_address: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        assert_eq!(by_address.remote_host, None);
+    }
+
+    #[test]
+    fn dscp_in_neighbor_line_overrides_global_line() {
+        let configs = Config::parse_lines(
+            "dscp cs6\n\
+             64512 127.0.0.1 64513 127.0.0.2 active\n\
+             64512 127.0.0.1 64514 127.0.0.3 active dscp af41\n",
+        )
+        .unwrap();
+
+        assert_eq!(configs[0].dscp, Some(Dscp::CS6));
+        assert_eq!(configs[1].dscp, Some("af41".parse().unwrap()));
+        let default: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        assert_eq!(default.dscp, None);
+    }
+
+    #[test]
+    fn keepalive_interval_is_at_most_one_third_of_hold_time() {
+        let default: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let configured: Config = "64512 127.0.0.1 64513 127.0.0.2 active keepalive 60"
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            default.keepalive_interval(HoldTime::from(90)),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            default.connect_retry_time(),
+            Duration::from_secs(DEFAULT_CONNECT_RETRY_TIME)
+        );
+        assert_eq!(
+            configured.keepalive_interval(HoldTime::from(90)),
+            Duration::from_secs(30)
+        );
+        assert!(matches!(
+            "64512 127.0.0.1 64513 127.0.0.2 active keepalive 0".parse::<Config>(),
+            Err(ConfigParseError::OutOfRange {
+                key: "keepalive",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn mrai_defaults_depend_on_ebgp_or_ibgp() {
+        let ebgp: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let ibgp: Config = "64512 127.0.0.1 64512 127.0.0.2 active".parse().unwrap();
+        let configured: Config = "64512 127.0.0.1 64513 127.0.0.2 active mrai 0"
+            .parse()
+            .unwrap();
+
+        assert_eq!(ebgp.mrai(), Duration::from_secs(30));
+        assert_eq!(ibgp.mrai(), Duration::from_secs(5));
+        assert_eq!(configured.mrai(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn advertised_next_hop_depends_on_ebgp_or_ibgp() {
+        let learned: Ipv4Addr = "10.200.100.4".parse().unwrap();
+        let ebgp: Config = "64512 10.200.100.2 64513 10.200.100.3 active"
+            .parse()
+            .unwrap();
+        let ibgp: Config = "64512 10.200.100.2 64512 10.200.100.3 active"
+            .parse()
+            .unwrap();
+        let next_hop_self: Config = "64512 10.200.100.2 64512 10.200.100.3 active next-hop-self"
+            .parse()
+            .unwrap();
+        let third_party: Config =
+            "64512 10.200.100.2 64513 10.200.100.3 active shared-subnet 10.200.100.0/24"
+                .parse()
+                .unwrap();
+
+        assert_eq!(ebgp.advertised_next_hop(learned), ebgp.local_ip);
+        assert_eq!(ibgp.advertised_next_hop(learned), learned);
+        assert_eq!(
+            next_hop_self.advertised_next_hop(learned),
+            next_hop_self.local_ip
+        );
+        assert_eq!(third_party.advertised_next_hop(learned), learned);
+        assert_eq!(
+            third_party.advertised_next_hop("10.200.101.4".parse().unwrap()),
+            third_party.local_ip
+        );
+    }
+
+    #[test]
+    fn wildcard_remote_as_accepts_matching_as_numbers() {
+        let local_as = AutonomousSystemNumber::from(64512);
+        let config = |remote_as: &str| {
+            format!("64512 127.0.0.1 {remote_as} 127.0.0.2 passive")
+                .parse::<Config>()
+                .unwrap()
+                .remote_as
+        };
+
+        assert!(config("any").accepts(local_as, 64513.into()));
+        assert!(config("external").accepts(local_as, 64513.into()));
+        assert!(!config("external").accepts(local_as, 64512.into()));
+        assert!(config("internal").accepts(local_as, 64512.into()));
+        assert!(!config("64514").accepts(local_as, 64513.into()));
+        assert!("64512 127.0.0.1 everyone 127.0.0.2 passive"
+            .parse::<Config>()
+            .is_err());
+    }
+
+    #[test]
+    fn four_octet_remote_as_does_not_accept_as_trans() {
+        let config: Config = "64512 127.0.0.1 4200000000 127.0.0.2 passive"
+            .parse()
+            .unwrap();
+        let local_as = config.local_as;
+
+        assert!(!config.remote_as.accepts(local_as, AS_TRANS));
+        assert!(config.remote_as.accepts(local_as, 4200000000.into()));
+        assert!(!config.remote_as.accepts(local_as, 64513.into()));
+        assert!(config.is_ebgp());
+    }
+
+    #[test]
+    fn quic_transport_requires_quic_feature() {
+        let default: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let quic = "64512 127.0.0.1 64513 127.0.0.2 active transport quic".parse::<Config>();
+
+        assert_eq!(default.transport, Transport::Tcp);
+        assert_eq!(quic.is_ok(), cfg!(feature = "quic"));
+    }
+
+    #[test]
+    fn syslog_is_used_alongside_stdout_unless_log_output_is_specified() {
+        let default: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let both: Config = "64512 127.0.0.1 64513 127.0.0.2 active syslog udp:192.0.2.1:514"
+            .parse()
+            .unwrap();
+        let syslog_only: Config =
+            "64512 127.0.0.1 64513 127.0.0.2 active syslog unix:/dev/log log-output syslog"
+                .parse()
+                .unwrap();
+
+        assert_eq!(default.log_output, LogOutput::Stdout);
+        assert_eq!(both.log_output, LogOutput::Both);
+        assert_eq!(
+            both.syslog,
+            Some(SyslogDestination::Udp("192.0.2.1:514".parse().unwrap()))
+        );
+        assert_eq!(syslog_only.log_output, LogOutput::Syslog);
+        assert!("64512 127.0.0.1 64513 127.0.0.2 active log-output both"
+            .parse::<Config>()
+            .is_err());
+    }
+
+    #[test]
+    fn import_policy_change_requires_reset_without_soft_reconfiguration() {
+        let base = "64512 127.0.0.1 64513 127.0.0.2 active policy-file /etc/howbgp/policy";
+        let config = |options: &str| {
+            format!("{} {}", base, options)
+                .trim()
+                .parse::<Config>()
+                .unwrap()
+        };
+
+        assert!(config("").requires_session_reset(&config("import set local-pref 200")));
+        assert!(
+            !config("soft-reconfiguration-inbound").requires_session_reset(&config(
+                "soft-reconfiguration-inbound import-policy in import set local-pref 200"
+            ))
+        );
+        assert!(!config("").requires_session_reset(&config("export-policy out")));
+        assert!("64512 127.0.0.1 64513 127.0.0.2 active import-policy in"
+            .parse::<Config>()
+            .is_err());
+    }
+
+    #[test]
+    fn vip_listen_requires_control_token_file() {
+        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active \
+                              control-token-file /etc/howbgp/tokens vip-listen 127.0.0.1:8180 \
+                              vip-community 64512:100 vip-community no-export"
+            .parse()
+            .unwrap();
+
+        assert_eq!(config.vip_listen, Some("127.0.0.1:8180".parse().unwrap()));
+        assert_eq!(
+            config.vip_communities,
+            vec!["64512:100".parse().unwrap(), Community::NO_EXPORT]
+        );
+        assert!(
+            "64512 127.0.0.1 64513 127.0.0.2 active vip-listen 127.0.0.1:8180"
+                .parse::<Config>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "api-tls"))]
+    fn api_tls_requires_api_tls_feature() {
+        assert!(matches!(
+            "64512 127.0.0.1 64513 127.0.0.2 active api-tls-cert /etc/howbgp/api.pem"
+                .parse::<Config>(),
+            Err(ConfigParseError::FeatureDisabled {
+                feature: "api-tls",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "api-tls")]
+    fn vip_listen_on_non_loopback_address_requires_tls() {
+        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active \
+                              control-token-file /etc/howbgp/tokens vip-listen 10.0.0.1:8180 \
+                              api-tls-cert /etc/howbgp/api.pem api-tls-key /etc/howbgp/api.key \
+                              api-tls-client-ca /etc/howbgp/ca.pem"
+            .parse()
+            .unwrap();
+
+        assert_eq!(config.api_tls_cert, Some("/etc/howbgp/api.pem".into()));
+        assert_eq!(config.api_tls_key, Some("/etc/howbgp/api.key".into()));
+        assert_eq!(config.api_tls_client_ca, Some("/etc/howbgp/ca.pem".into()));
+        for invalid in [
+            "control-token-file /etc/howbgp/tokens vip-listen 10.0.0.1:8180",
+            "api-tls-cert /etc/howbgp/api.pem",
+            "api-tls-client-ca /etc/howbgp/ca.pem",
+        ] {
+            assert!(
+                format!("64512 127.0.0.1 64513 127.0.0.2 active {}", invalid)
+                    .parse::<Config>()
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn ha_requires_ha_key_file() {
+        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active \
+                              ha-secondary 10.0.0.1:1790 ha-key-file /etc/howbgp/ha.key"
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            config.ha,
+            Some(HaRole::Secondary("10.0.0.1:1790".parse().unwrap()))
+        );
+        assert_eq!(config.ha_key_file, Some("/etc/howbgp/ha.key".into()));
+        assert!(
+            "64512 127.0.0.1 64513 127.0.0.2 active ha-primary 0.0.0.0:1790"
+                .parse::<Config>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn template_instantiates_peer_for_discovered_address() {
+        let template: Config = "64512 127.0.0.1 64513 0.0.0.0 passive \
+                                discover rs-clients.example,rs-clients2.example \
+                                discovery-interval 10"
+            .parse()
+            .unwrap();
+        assert!(template.is_template());
+        assert_eq!(
+            template.discover,
+            vec!["rs-clients.example", "rs-clients2.example"]
+        );
+        assert_eq!(template.discovery_interval, 10);
+
+        let peer = template.instantiate("10.0.0.5".parse().unwrap());
+        assert!(!peer.is_template());
+        assert_eq!(
+            peer,
+            "64512 127.0.0.1 64513 10.0.0.5 passive discovery-interval 10"
+                .parse()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn update_rate_limit_must_be_positive() {
+        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active update-rate-limit 100"
+            .parse()
+            .unwrap();
+
+        assert_eq!(config.update_rate_limit, Some(100));
+        assert!(matches!(
+            "64512 127.0.0.1 64513 127.0.0.2 active update-rate-limit 0".parse::<Config>(),
+            Err(ConfigParseError::OutOfRange {
+                key: "update-rate-limit",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn outbound_queue_size_must_be_positive() {
+        let default: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let configured: Config = "64512 127.0.0.1 64513 127.0.0.2 active outbound-queue-size 16"
+            .parse()
+            .unwrap();
+
+        assert_eq!(default.outbound_queue_size, DEFAULT_OUTBOUND_QUEUE_CAPACITY);
+        assert_eq!(configured.outbound_queue_size, 16);
+        assert!(matches!(
+            "64512 127.0.0.1 64513 127.0.0.2 active outbound-queue-size 0".parse::<Config>(),
+            Err(ConfigParseError::OutOfRange {
+                key: "outbound-queue-size",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn description_can_contain_spaces_when_quoted() {
+        let quoted: Config =
+            r#"64512 127.0.0.1 64513 127.0.0.2 active description "transit A" 10.0.0.0/24"#
+                .parse()
+                .unwrap();
+        let single: Config = "64512 127.0.0.1 64513 127.0.0.2 active description transit"
+            .parse()
+            .unwrap();
+
+        assert_eq!(quoted.description.as_deref(), Some("transit A"));
+        assert_eq!(quoted.networks.len(), 1);
+        assert_eq!(quoted.display_name(), "transit A (127.0.0.2)");
+        assert_eq!(single.display_name(), "transit (127.0.0.2)");
+        assert!(matches!(
+            r#"64512 127.0.0.1 64513 127.0.0.2 active description "transit"#.parse::<Config>(),
+            Err(ConfigParseError::UnterminatedQuote { .. })
+        ));
+    }
+
+    #[test]
+    fn backdoor_network_is_not_advertised() {
+        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active \
+             network 10.100.210.0/24 network 10.100.220.0/24 backdoor 10.100.230.0/24"
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            config.networks,
+            vec![
+                "10.100.210.0/24".parse().unwrap(),
+                "10.100.230.0/24".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            config.backdoor_networks,
+            vec!["10.100.220.0/24".parse().unwrap()]
+        );
+    }
+
+    #[test]
+    fn networks_can_contain_both_ipv4_and_ipv6_prefixes() {
+        let config: Config =
+            "64512 127.0.0.1 64513 127.0.0.2 active 10.100.220.0/24 2001:db8:1::/48 route-metric 30"
+                .parse()
+                .unwrap();
+
+        assert_eq!(config.networks, vec!["10.100.220.0/24".parse().unwrap()]);
+        assert_eq!(
+            config.ipv6_networks,
+            vec!["2001:db8:1::/48".parse::<ipnetwork::Ipv6Network>().unwrap()]
+        );
+        assert!("64512 127.0.0.1 64513 127.0.0.2 active 2001:db8::zz/48"
+            .parse::<Config>()
+            .is_err());
+    }
+
+    #[test]
+    fn only_export_side_changes_can_be_applied_without_session_reset() {
+        let running: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let export_changed: Config =
+            "64512 127.0.0.1 64513 127.0.0.2 active export set as-path prepend 64512"
+                .parse()
+                .unwrap();
+        let hold_time_changed: Config = "64512 127.0.0.1 64513 127.0.0.2 active hold-time 30"
+            .parse()
+            .unwrap();
+        assert!(!running.requires_session_reset(&export_changed));
+        assert!(running.requires_session_reset(&hold_time_changed));
+    }
+
+    #[test]
+    fn configs_are_parsed_line_by_line() {
+        let configs = Config::parse_lines(
+            "# 上流\n64512 127.0.0.1 64513 127.0.0.2 active\n\n64512 127.0.0.1 64514 127.0.0.3 passive\n",
+        )
+        .unwrap();
+        assert_eq!(configs.len(), 2);
+        assert_eq!(
+            configs[1].remote_ip,
+            "127.0.0.3".parse::<Ipv4Addr>().unwrap()
+        );
+        assert!(Config::parse_lines("64512 127.0.0.1 64513 127.0.0.x active").is_err());
+    }
+
+    #[test]
+    fn check_reports_every_invalid_line_and_duplicate_neighbors() {
+        let diagnostics = Config::check(
+            "# 検証用\n\
+             64512 127.0.0.1 64513 127.0.0.2 active hold-time 1\n\
+             64512 127.0.0.1 64514 127.0.0.3 passive\n\
+             64512 127.0.0.1 64515 127.0.0.4 unknown\n",
+        );
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics[0].starts_with("2行目: "));
+        assert!(diagnostics[1].starts_with("4行目: "));
+
+        let diagnostics = Config::check(
+            "64512 127.0.0.1 64513 127.0.0.2 active\n\
+             64512 127.0.0.1 64514 127.0.0.2 passive aspa-file /nonexistent/aspa\n",
+        );
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0], "127.0.0.2のピアが重複しています。");
+        assert!(diagnostics[1].starts_with("127.0.0.2のピアのaspa-file: "));
+
+        assert!(Config::check("64512 127.0.0.1 64513 127.0.0.2 active\n").is_empty());
+    }
+
+    #[test]
+    fn check_compares_host_name_peers_by_host_name() {
+        let diagnostics = Config::check(
+            "64512 127.0.0.1 64513 peer1.example active\n\
+             64512 127.0.0.1 64514 peer2.example active\n",
+        );
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+
+        let diagnostics = Config::check(
+            "64512 127.0.0.1 64513 peer1.example active\n\
+             64512 127.0.0.1 64514 peer1.example passive\n",
+        );
+        assert_eq!(diagnostics, vec!["peer1.exampleのピアが重複しています。"]);
+    }
+
+    #[test]
+    fn max_prefix_warning_count_is_percentage_of_limit() {
+        let default: Config = "64512 127.0.0.1 64513 127.0.0.2 active max-prefix 1000"
+            .parse()
+            .unwrap();
+        let configured: Config =
+            "64512 127.0.0.1 64513 127.0.0.2 active max-prefix 1000 max-prefix-warning 90"
+                .parse()
+                .unwrap();
+        let unlimited: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+
+        assert_eq!(default.max_prefix_warning_count(), Some(750));
+        assert_eq!(configured.max_prefix_warning_count(), Some(900));
+        assert_eq!(unlimited.max_prefix_warning_count(), None);
+        assert!(matches!(
+            "64512 127.0.0.1 64513 127.0.0.2 active max-prefix-warning 101".parse::<Config>(),
+            Err(ConfigParseError::OutOfRange {
+                key: "max-prefix-warning",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn default_local_pref_line_applies_to_neighbors_without_their_own() {
+        let configs = Config::parse_lines(
+            "default-local-pref 200\n\
+             64512 127.0.0.1 64513 127.0.0.2 active\n\
+             64512 127.0.0.1 64514 127.0.0.3 active default-local-pref 50\n",
+        )
+        .unwrap();
+        assert_eq!(configs.len(), 2);
+        assert_eq!(configs[0].default_local_pref(), 200);
+        assert_eq!(configs[1].default_local_pref(), 50);
+
+        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        assert_eq!(config.default_local_pref(), DEFAULT_LOCAL_PREF);
+    }
+
+    #[test]
+    fn address_families_can_be_configured_with_afi_safi() {
+        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        assert_eq!(config.address_families, vec![AddressFamily::IPV4_UNICAST]);
+        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active 2001:db8:1::/48"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            config.address_families,
+            vec![AddressFamily::IPV4_UNICAST, AddressFamily::IPV6_UNICAST]
+        );
+
+        let config: Config =
+            "64512 127.0.0.1 64513 127.0.0.2 active afi-safi ipv6-unicast,ipv4-unicast,ipv6-unicast"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            config.address_families,
+            vec![AddressFamily::IPV6_UNICAST, AddressFamily::IPV4_UNICAST]
+        );
+        assert!(matches!(
+            "64512 127.0.0.1 64513 127.0.0.2 active afi-safi ipv4-multicast".parse::<Config>(),
+            Err(ConfigParseError::InvalidValue {
+                kind: "address family",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn max_prefix_length_must_be_valid_ipv4_prefix_length() {
+        let default: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let configured: Config = "64512 127.0.0.1 64513 127.0.0.2 active max-prefix-length 24"
+            .parse()
+            .unwrap();
+
+        assert_eq!(default.max_prefix_length, 32);
+        assert_eq!(configured.max_prefix_length, 24);
+        assert!(matches!(
+            "64512 127.0.0.1 64513 127.0.0.2 active max-prefix-length 33".parse::<Config>(),
+            Err(ConfigParseError::OutOfRange {
+                key: "max-prefix-length",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn max_prefix_length_is_applied_per_address_family() {
+        let default: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let configured: Config =
+            "64512 127.0.0.1 64513 127.0.0.2 active max-prefix-length 24 max-prefix-length-ipv6 64"
+                .parse()
+                .unwrap();
+        let network = |s: &str| s.parse::<ipnetwork::IpNetwork>().unwrap();
+
+        assert_eq!(default.max_prefix_length_ipv6, 48);
+        assert!(default.accepts_prefix_length(network("10.100.220.1/32")));
+        assert!(default.accepts_prefix_length(network("2001:db8:1::/48")));
+        assert!(!default.accepts_prefix_length(network("2001:db8:1:1::/64")));
+        assert!(!configured.accepts_prefix_length(network("10.100.220.0/25")));
+        assert!(configured.accepts_prefix_length(network("2001:db8:1:1::/64")));
+        assert!(matches!(
+            "64512 127.0.0.1 64513 127.0.0.2 active max-prefix-length-ipv6 129".parse::<Config>(),
+            Err(ConfigParseError::OutOfRange {
+                key: "max-prefix-length-ipv6",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn option_errors_tell_which_option_is_invalid() {
+        assert!(matches!(
+            "64512 127.0.0.1 64513 127.0.0.2 active route-metric".parse::<Config>(),
+            Err(ConfigParseError::MissingValue { key }) if key == "route-metric"
+        ));
+        assert!(matches!(
+            "64512 127.0.0.1 64513 127.0.0.2 active route-metric low".parse::<Config>(),
+            Err(ConfigParseError::InvalidOptionValue { key, value, .. })
+                if key == "route-metric" && value == "low"
+        ));
+        assert!(matches!(
+            "64512 127.0.0.1 64513 127.0.0.2 active remote-role boss".parse::<Config>(),
+            Err(ConfigParseError::InvalidOptionValue { key, .. }) if key == "remote-role"
+        ));
+    }
+}
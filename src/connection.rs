@@ -1,99 +1,763 @@
 use anyhow::{Context, Result};
-use bytes::{BufMut, BytesMut};
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio::io::{
+    self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf,
+};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
 
+use crate::capture::MessageCapture;
 use crate::config::{Config, Mode};
+use crate::debug::{self, DebugFlags, Direction};
+use crate::error::ConfigParseError;
+use crate::error::ConvertBytesToBgpMessageError;
 use crate::error::CreateConnectionError;
+use crate::packets::codec::BgpCodec;
+use crate::packets::header::MINIMUM_MESSAGE_LENGTH;
 use crate::packets::message::Message;
+#[cfg(feature = "quic")]
+use crate::quic::QuicStream;
+use crate::statistics::OutboundQueueStatistics;
+use crate::{log_error, log_warning};
+
+/// Connectionがメッセージを読み書きするストリームです。
+/// TcpStreamのほか、テスト用にプロセス内で完結するtokio::io::DuplexStreamも扱えます。
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + fmt::Debug {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + fmt::Debug> Stream for T {}
+
+/// ピアとの間にStreamを張る方法。
+/// 張ったStreamでのメッセージの送受信はConnectionが行うので、FSMはトランスポートの種類を意識しない。
+pub trait BgpTransport: fmt::Debug + Send + Sync {
+    /// Activeモードで、ピアに接続する。
+    fn connect<'a>(&'a self, config: &'a Config) -> BoxFuture<'a, Result<Box<dyn Stream>>>;
+    /// Passiveモードで、ピアからの接続を待ち受ける。
+    fn accept<'a>(&'a self, config: &'a Config) -> BoxFuture<'a, Result<Box<dyn Stream>>>;
+}
+
+/// TCPの179番ポートでピアと接続するトランスポート。
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TcpTransport;
+
+/// 待ち受けるTCP Connectionの要求のキューの長さ。
+const LISTEN_BACKLOG: u32 = 1024;
+
+/// addressで待ち受けるTcpListenerを作成する。
+/// デーモンを再起動した直後や、セッションを張り直すときに、前の接続がTIME_WAITで残っていても
+/// "address in use"で失敗しないように、SO_REUSEADDRを設定する。
+/// SO_REUSEPORTは、ほかのプロセスが同じアドレスで待ち受けて接続を横取りできてしまうので設定しない。
+/// dscpを指定した場合は、受け付けたTCP Connectionのパケットにも付くように待ち受けるソケットに設定する。
+pub(crate) fn bind_reusable_listener(
+    address: SocketAddr,
+    dscp: Option<Dscp>,
+) -> io::Result<TcpListener> {
+    let socket = match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    if let Some(dscp) = dscp {
+        socket.set_tos_v4(dscp.tos())?;
+    }
+    socket.bind(address)?;
+    socket.listen(LISTEN_BACKLOG)
+}
+
+/// 受け付けたTCP Connectionを待っているピアの、送信元のアドレスごとの(待ち始めた順の番号, 通知先)。
+type Waiters = Arc<std::sync::Mutex<HashMap<Ipv4Addr, (u64, oneshot::Sender<TcpStream>)>>>;
+
+/// 1つのアドレスで待ち受け、受け付けたTCP Connectionを送信元のアドレスがremote_ipのピアに渡す。
+/// 同じlocal_ipで待ち受けるPassiveのピアが複数あっても、別のピアのTCP Connectionを受け取らないようにする。
+/// 待っているピアがいない送信元からのTCP Connectionは、すぐに閉じる。
+#[derive(Debug)]
+struct SharedListener {
+    waiters: Waiters,
+    /// 次にaccept_fromで待ち始めるピアに付ける番号。
+    next_waiter: AtomicU64,
+    accepting: JoinHandle<()>,
+}
+
+/// 待ち受けているアドレスごとのSharedListener。
+/// apply-configでピアを作り直すたびにbindし直すと、前の待ち受けを閉じ終える前にbindして失敗しうるので、
+/// 待っているピアがいなくなっても待ち受けを続ける。
+static SHARED_LISTENERS: OnceLock<std::sync::Mutex<HashMap<SocketAddr, Arc<SharedListener>>>> =
+    OnceLock::new();
+
+impl SharedListener {
+    /// addressで待ち受けているSharedListenerを返す。まだ待ち受けていなければ待ち受けを始める。
+    /// 待ち受けていたtokioのランタイムが終了していれば、今のランタイムで待ち受け直す。
+    fn get_or_bind(address: SocketAddr, dscp: Option<Dscp>) -> io::Result<Arc<Self>> {
+        let mut listeners = SHARED_LISTENERS
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap();
+        if let Some(listener) = listeners
+            .get(&address)
+            .filter(|listener| !listener.accepting.is_finished())
+        {
+            return Ok(Arc::clone(listener));
+        }
+        let listener = bind_reusable_listener(address, dscp)?;
+        let waiters = Waiters::default();
+        let accepting = tokio::spawn(Self::dispatch(listener, Arc::clone(&waiters)));
+        let shared = Arc::new(Self {
+            waiters,
+            next_waiter: AtomicU64::new(0),
+            accepting,
+        });
+        listeners.insert(address, Arc::clone(&shared));
+        Ok(shared)
+    }
+
+    async fn dispatch(listener: TcpListener, waiters: Waiters) {
+        loop {
+            let (stream, remote) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log_error!("TCP Connectionの要求を受け付けられませんでした。{:?}", e);
+                    continue;
+                }
+            };
+            let waiter = match remote {
+                SocketAddr::V4(remote) => waiters.lock().unwrap().remove(remote.ip()),
+                SocketAddr::V6(_) => None,
+            };
+            match waiter {
+                // 待っていたピアがすでにいなければ、streamは破棄されて閉じる。
+                Some((_, waiter)) => {
+                    let _ = waiter.send(stream);
+                }
+                None => log_warning!(
+                    "ピアとして待っていない{}からのTCP Connectionを閉じました。",
+                    remote
+                ),
+            }
+        }
+    }
+
+    /// remote_ipからのTCP Connectionを受け付けるまで待つ。
+    async fn accept_from(self: Arc<Self>, remote_ip: Ipv4Addr) -> Result<TcpStream> {
+        let (sender, receiver) = oneshot::channel();
+        let id = self.next_waiter.fetch_add(1, Ordering::SeqCst);
+        self.waiters.lock().unwrap().insert(remote_ip, (id, sender));
+        let _waiter = WaiterGuard {
+            waiters: &self.waiters,
+            remote_ip,
+            id,
+        };
+        receiver.await.context(format!(
+            "{}からのTCP Connectionを待つのをやめました。",
+            remote_ip
+        ))
+    }
+}
+
+/// accept_fromを待つのをやめたら、登録した通知先を取り除く。
+/// 同じremote_ipで後から待ち始めたピアの通知先は残す。
+struct WaiterGuard<'a> {
+    waiters: &'a Waiters,
+    remote_ip: Ipv4Addr,
+    id: u64,
+}
+
+impl Drop for WaiterGuard<'_> {
+    fn drop(&mut self) {
+        let mut waiters = self.waiters.lock().unwrap();
+        if waiters
+            .get(&self.remote_ip)
+            .is_some_and(|(id, _)| *id == self.id)
+        {
+            waiters.remove(&self.remote_ip);
+        }
+    }
+}
+
+/// local_ipから接続するTcpSocket。
+/// 前の接続がTIME_WAITで残っていても同じアドレスからすぐに接続し直せるように、SO_REUSEADDRを設定する。
+fn reusable_socket(local_ip: Ipv4Addr, dscp: Option<Dscp>) -> io::Result<TcpSocket> {
+    let socket = TcpSocket::new_v4()?;
+    socket.set_reuseaddr(true)?;
+    if let Some(dscp) = dscp {
+        socket.set_tos_v4(dscp.tos())?;
+    }
+    socket.bind(SocketAddr::from((local_ip, 0)))?;
+    Ok(socket)
+}
+
+/// ホスト名を名前解決し、ピアに接続するIPv4アドレスを返す。
+/// TCPもQUICもIPv4で接続するため、IPv6のアドレスは使わない。
+pub async fn resolve_host(host: &str) -> Result<Ipv4Addr> {
+    // ポート番号は名前解決の結果に関係しない。
+    let addresses = tokio::net::lookup_host((host, 0))
+        .await
+        .context(format!("{}を名前解決できませんでした。", host))?;
+    first_ipv4_address(host, addresses)
+}
+
+fn first_ipv4_address(
+    host: &str,
+    mut addresses: impl Iterator<Item = SocketAddr>,
+) -> Result<Ipv4Addr> {
+    addresses
+        .find_map(|address| match address {
+            SocketAddr::V4(address) => Some(*address.ip()),
+            SocketAddr::V6(_) => None,
+        })
+        .context(format!("{}にIPv4のアドレスがありません。", host))
+}
+
+/// BGPのセッションのパケットのIPヘッダに付けるDSCP。
+/// QoSで輻輳したリンクでも、KeepaliveMessageが捨てられてHold Timerが切れないようにする。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct Dscp(u8);
+
+impl Dscp {
+    /// ネットワーク制御用のクラス。RFC 4594でルーティングプロトコルに推奨されている。
+    pub const CS6: Dscp = Dscp(48);
+
+    /// IP_TOSに設定する値。DSCPはTOSの上位6bitである。
+    pub fn tos(self) -> u32 {
+        (self.0 as u32) << 2
+    }
+}
+
+impl FromStr for Dscp {
+    type Err = ConfigParseError;
+
+    /// `cs0`から`cs7`, `af11`から`af43`, `ef`の名前か、0から63の数値を読み取る。
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ConfigParseError::InvalidValue {
+            kind: "dscp",
+            value: s.to_owned(),
+        };
+        let name = s.to_ascii_lowercase();
+        let digits = |prefix: &str| -> Option<Vec<u8>> {
+            let digits = name.strip_prefix(prefix)?;
+            digits
+                .chars()
+                .map(|c| c.to_digit(10).map(|d| d as u8))
+                .collect()
+        };
+        let value = if name == "ef" {
+            46
+        } else if let Some(digits) = digits("cs") {
+            match digits[..] {
+                [class @ 0..=7] => class * 8,
+                _ => return Err(invalid()),
+            }
+        } else if let Some(digits) = digits("af") {
+            match digits[..] {
+                [class @ 1..=4, drop @ 1..=3] => class * 8 + drop * 2,
+                _ => return Err(invalid()),
+            }
+        } else {
+            s.parse().map_err(|_| invalid())?
+        };
+        if value > 63 {
+            return Err(invalid());
+        }
+        Ok(Dscp(value))
+    }
+}
+
+impl BgpTransport for TcpTransport {
+    fn connect<'a>(&'a self, config: &'a Config) -> BoxFuture<'a, Result<Box<dyn Stream>>> {
+        Box::pin(async move {
+            let bgp_port = 179;
+            let stream = reusable_socket(config.local_ip, config.dscp)
+                .context(format!(
+                    "{}から接続するソケットを作成できませんでした。",
+                    config.local_ip
+                ))?
+                .connect(SocketAddr::from((config.remote_ip, bgp_port)))
+                .await
+                .context(format!(
+                    "cannot connect to remote peer {0}:{1}",
+                    config.remote_ip, bgp_port
+                ))?;
+            Ok(Box::new(stream) as Box<dyn Stream>)
+        })
+    }
+
+    fn accept<'a>(&'a self, config: &'a Config) -> BoxFuture<'a, Result<Box<dyn Stream>>> {
+        Box::pin(async move {
+            let bgp_port = 179;
+            let address = SocketAddr::from((config.local_ip, bgp_port));
+            let listener = SharedListener::get_or_bind(address, config.dscp).context(format!(
+                "{0}:{1}にbindすることが出来ませんでした。",
+                config.local_ip, bgp_port
+            ))?;
+            let stream = listener.accept_from(config.remote_ip).await?;
+            Ok(Box::new(stream) as Box<dyn Stream>)
+        })
+    }
+}
+
+/// QUICのストリームでピアと接続するトランスポート。
+#[cfg(feature = "quic")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct QuicTransport;
+
+#[cfg(feature = "quic")]
+impl BgpTransport for QuicTransport {
+    fn connect<'a>(&'a self, config: &'a Config) -> BoxFuture<'a, Result<Box<dyn Stream>>> {
+        Box::pin(async move {
+            let stream = QuicStream::connect_to_remote_peer(config).await?;
+            Ok(Box::new(stream) as Box<dyn Stream>)
+        })
+    }
+
+    fn accept<'a>(&'a self, config: &'a Config) -> BoxFuture<'a, Result<Box<dyn Stream>>> {
+        Box::pin(async move {
+            let stream = QuicStream::wait_connection_from_remote_peer(config).await?;
+            Ok(Box::new(stream) as Box<dyn Stream>)
+        })
+    }
+}
+
+/// 与えられたプロセス内のストリームを1度だけ返すトランスポート。
+/// テストで、ネットワークを使わずにピアをつないだり、壊れたデータを送り込んだりするのに使う。
+#[derive(Debug)]
+pub struct InMemoryTransport(std::sync::Mutex<Option<DuplexStream>>);
+
+impl InMemoryTransport {
+    pub fn new(stream: DuplexStream) -> Self {
+        Self(std::sync::Mutex::new(Some(stream)))
+    }
+
+    /// 互いにつながった2つのトランスポートを作成する。
+    pub fn pair() -> (Self, Self) {
+        let max_buffer_size = 65536;
+        let (local, remote) = io::duplex(max_buffer_size);
+        (Self::new(local), Self::new(remote))
+    }
+
+    fn take(&self) -> Result<Box<dyn Stream>> {
+        let stream = self
+            .0
+            .lock()
+            .unwrap()
+            .take()
+            .context("InMemoryTransportのストリームはすでに使われています。")?;
+        Ok(Box::new(stream))
+    }
+}
+
+impl BgpTransport for InMemoryTransport {
+    fn connect<'a>(&'a self, _: &'a Config) -> BoxFuture<'a, Result<Box<dyn Stream>>> {
+        Box::pin(async move { self.take() })
+    }
+
+    fn accept<'a>(&'a self, _: &'a Config) -> BoxFuture<'a, Result<Box<dyn Stream>>> {
+        Box::pin(async move { self.take() })
+    }
+}
+
+/// BGPのメッセージを送受信するトランスポート。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord, Default)]
+pub enum Transport {
+    #[default]
+    Tcp,
+    /// quic featureを有効にしてビルドした場合のみ使える、実験的なトランスポート。
+    #[cfg(feature = "quic")]
+    Quic,
+}
+
+impl Transport {
+    /// 設定で選んだトランスポートの実装。
+    pub fn to_bgp_transport(self) -> Arc<dyn BgpTransport> {
+        match self {
+            Transport::Tcp => Arc::new(TcpTransport),
+            #[cfg(feature = "quic")]
+            Transport::Quic => Arc::new(QuicTransport),
+        }
+    }
+}
+
+impl FromStr for Transport {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(Transport::Tcp),
+            #[cfg(feature = "quic")]
+            "quic" => Ok(Transport::Quic),
+            #[cfg(not(feature = "quic"))]
+            "quic" => Err(ConfigParseError::FeatureDisabled {
+                option: "QUIC",
+                feature: "quic",
+            }),
+            _ => Err(ConfigParseError::InvalidValue {
+                kind: "transport",
+                value: s.to_owned(),
+            }),
+        }
+    }
+}
+
+/// 書き込みタスクに渡して、まだ送信していないデータを溜めておける数の既定値。
+pub const DEFAULT_OUTBOUND_QUEUE_CAPACITY: usize = 1024;
 
 /// 通信に関する処理を担当する構造体です。
 /// TcpConnectionを張ったり、crate::packets::message::Messageのデータを送受信したりします。
+/// ストリームは読み込み側と書き込み側に分け、書き込みは別タスクで行うので、
+/// 大きなデータの送信中も受信したメッセージの処理を止めません。
 #[derive(Debug)]
 pub struct Connection {
-    conn: TcpStream,
+    reader: ReadHalf<Box<dyn Stream>>,
+    /// 書き込みタスクに送信するデータを渡すチャネル。
+    /// 送信が遅いピアのデータを際限なく溜めないように、容量を制限する。
+    writer: mpsc::Sender<Bytes>,
+    outbound_queue_capacity: usize,
+    /// 送信キューが満杯で、空くのを待った回数。
+    stalls: u64,
+    /// 書き込みタスクが終了していて、捨てたデータの数。
+    drops: u64,
+    /// 書き込みタスクで書き込みに失敗したか。
+    write_failed: Arc<AtomicBool>,
     buffer: BytesMut,
+    /// ログに出力するときのピアの名前。
+    peer_name: String,
+    /// 送受信したメッセージのうち、ログに出力するものの種類。
+    debug: DebugFlags,
+    /// 送受信したメッセージを記録するリングバッファ。Noneの場合は記録しない。
+    capture: Option<Arc<std::sync::Mutex<MessageCapture>>>,
+    /// 対向機器がTCP Connectionを閉じたか、読み込みでエラーが発生したか。
+    closed: bool,
+    /// 4オクテットAS Capabilityを交換したか。受信したUpdateMessageの読み取り方が変わる。
+    four_octet_as: bool,
 }
 
 impl Connection {
-    pub async fn connect(config: &Config) -> Result<Self, CreateConnectionError> {
+    /// config.modeに応じて、transportでピアに接続するか、ピアからの接続を待ち受ける。
+    /// Mode::Bothではピアに接続する。待ち受けとConnection Collisionの解決はPeerが行う。
+    pub async fn connect(
+        transport: &dyn BgpTransport,
+        config: &Config,
+    ) -> Result<Self, CreateConnectionError> {
         let conn = match config.mode {
-            Mode::Active => Self::connect_to_remote_peer(config).await,
-            Mode::Passive => Self::wait_connection_from_remote_peer(config).await,
+            Mode::Active | Mode::Both => transport
+                .connect(config)
+                .await
+                .map_err(CreateConnectionError::Connect),
+            Mode::Passive => transport
+                .accept(config)
+                .await
+                .map_err(CreateConnectionError::Accept),
         }?;
+        Ok(Self::from_stream(conn, config))
+    }
+
+    /// tokioのランタイム上で呼び出す必要がある。書き込みタスクをspawnするため。
+    fn from_stream(conn: Box<dyn Stream>, config: &Config) -> Self {
         let buffer = BytesMut::with_capacity(1500);
-        Ok(Self { conn, buffer })
+        let (reader, writer) = io::split(conn);
+        let (sender, receiver) = mpsc::channel(config.outbound_queue_size);
+        let write_failed = Arc::new(AtomicBool::new(false));
+        tokio::spawn(Self::write_to_tcp_connection(
+            writer,
+            receiver,
+            config.display_name(),
+            Arc::clone(&write_failed),
+        ));
+        Self {
+            reader,
+            writer: sender,
+            outbound_queue_capacity: config.outbound_queue_size,
+            stalls: 0,
+            drops: 0,
+            write_failed,
+            buffer,
+            peer_name: config.display_name(),
+            debug: config.debug,
+            capture: None,
+            closed: false,
+            four_octet_as: false,
+        }
+    }
+
+    /// 以降に受信したUpdateMessageのAS_PATHを、four_octet_asがtrueであれば4オクテットのAS番号で読み取る。
+    pub fn set_four_octet_as(&mut self, four_octet_as: bool) {
+        self.four_octet_as = four_octet_as;
+    }
+
+    /// 以降に送受信したメッセージをcaptureに記録する。
+    /// セッションを張り直しても記録を続けられるように、captureはPeerが持つ。
+    pub fn set_capture(&mut self, capture: Arc<std::sync::Mutex<MessageCapture>>) {
+        self.capture = Some(capture);
+    }
+
+    fn record(&self, direction: Direction, bytes: &[u8]) {
+        if let Some(capture) = &self.capture {
+            capture.lock().unwrap().push(direction, bytes);
+        }
+    }
+
+    /// TCP Connectionが閉じられたか。
+    /// 閉じられる前に受信したメッセージはget_messageで取り出すことができる。
+    pub fn is_closed(&self) -> bool {
+        self.closed || self.write_failed.load(Ordering::SeqCst)
+    }
+
+    /// 送信キューに空きがあるか。空きがない場合は、呼び出し側が送信を先送りしたものとしてstallを数える。
+    /// AdjRibOutのように大量に送信する前に確認し、送信の遅いピアを待たずに後で送り直すために使う。
+    pub fn outbound_queue_has_room(&mut self) -> bool {
+        if self.writer.capacity() > 0 {
+            return true;
+        }
+        self.stalls += 1;
+        false
+    }
+
+    pub fn outbound_queue_statistics(&self) -> OutboundQueueStatistics {
+        OutboundQueueStatistics {
+            depth: self.outbound_queue_capacity - self.writer.capacity(),
+            stalls: self.stalls,
+            drops: self.drops,
+        }
     }
 
     pub async fn send(&mut self, message: Message) {
+        if self.debug.logs_message(Some(&message)) {
+            let bytes: BytesMut = message.clone().into();
+            debug::log_message(Direction::Send, &self.peer_name, &bytes, Some(&message));
+        }
         let bytes: BytesMut = message.into();
-        self.conn.write_all(&bytes[..]).await;
+        self.record(Direction::Send, &bytes);
+        self.write(bytes.freeze()).await;
     }
 
-    pub async fn get_message(&mut self) -> Option<Message> {
-        self.read_data_from_tcp_connection().await;
-        let buffer = self.split_buffer_at_message_separator()?;
-        Message::try_from(buffer).ok()
+    /// エンコード済みのメッセージの列をそのまま送信する。
+    /// Update Groupで作成したUpdateMessageを複数のピアで使い回すときに使う。
+    pub async fn send_bytes(&mut self, bytes: &[u8]) {
+        if self.debug.logs_any_message() || self.capture.is_some() {
+            let mut rest = bytes;
+            while rest.len() >= MINIMUM_MESSAGE_LENGTH as usize {
+                let length = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+                let (message_bytes, remained) =
+                    rest.split_at(length.clamp(MINIMUM_MESSAGE_LENGTH as usize, rest.len()));
+                self.record(Direction::Send, message_bytes);
+                if self.debug.logs_any_message() {
+                    let message =
+                        Message::decode(Bytes::copy_from_slice(message_bytes), self.four_octet_as)
+                            .ok();
+                    if self.debug.logs_message(message.as_ref()) {
+                        debug::log_message(
+                            Direction::Send,
+                            &self.peer_name,
+                            message_bytes,
+                            message.as_ref(),
+                        );
+                    }
+                }
+                rest = remained;
+            }
+        }
+        self.write(Bytes::copy_from_slice(bytes)).await;
     }
 
-    /// self.bufferから1つのbgp messageを表すbyteを切り出す。
-    fn split_buffer_at_message_separator(&mut self) -> Option<BytesMut> {
-        let index = self.get_index_of_message_separator().ok()?;
-        if self.buffer.len() < index {
-            // 1つのBGPメッセージ全体を表すデータが受信できていない。
-            // 半端に受信されているか一切受信されていない。
-            return None;
+    /// 書き込みタスクにbytesを渡す。実際の書き込みの完了は待たない。
+    /// 送信キューが満杯の場合は、このピアのタスクだけが空くまで待つ。
+    async fn write(&mut self, bytes: Bytes) {
+        let bytes = match self.writer.try_send(bytes) {
+            Ok(()) => return,
+            Err(TrySendError::Full(bytes)) => {
+                self.stalls += 1;
+                bytes
+            }
+            Err(TrySendError::Closed(_)) => {
+                self.drops += 1;
+                self.write_failed.store(true, Ordering::SeqCst);
+                return;
+            }
+        };
+        if self.writer.send(bytes).await.is_err() {
+            // 待っている間に書き込みタスクが終了した。
+            self.drops += 1;
+            self.write_failed.store(true, Ordering::SeqCst);
         }
-        Some(self.buffer.split_to(index))
     }
 
-    /// self.bufferのうちどこまでが1つのbgp messageを表すbytesであるか返す。
-    fn get_index_of_message_separator(&self) -> Result<usize> {
-        let minimum_message_length = 19;
-        if self.buffer.len() < 19 {
-            return Err(anyhow::anyhow!("messageのseparatorを表すデータまでbufferに入っていません。データの受信が半端であることが想定されます。"));
+    /// receiverで受け取ったデータを順番に書き込む。
+    /// Connectionが破棄されてチャネルが閉じられたら、残りのデータを書き込んでから終了する。
+    async fn write_to_tcp_connection(
+        mut writer: WriteHalf<Box<dyn Stream>>,
+        mut receiver: mpsc::Receiver<Bytes>,
+        peer_name: String,
+        write_failed: Arc<AtomicBool>,
+    ) {
+        while let Some(bytes) = receiver.recv().await {
+            if let Err(e) = writer.write_all(&bytes).await {
+                log_error!("{}へのメッセージの送信に失敗しました。{:?}", peer_name, e);
+                write_failed.store(true, Ordering::SeqCst);
+                return;
+            }
+        }
+        let _ = writer.shutdown().await;
+    }
+
+    /// 1つのBGP Messageを表すデータを受信していればMessageに変換して返す。
+    /// 受信したデータをMessageに変換できなかった場合はErrを返す。
+    pub async fn get_message(&mut self) -> Option<Result<Message, ConvertBytesToBgpMessageError>> {
+        self.read_data_from_tcp_connection().await;
+        let buffer = BgpCodec::split_frame(&mut self.buffer)?;
+        self.record(Direction::Receive, &buffer);
+        if self.debug.logs_any_message() {
+            let bytes = buffer.clone();
+            let message = Message::decode(buffer, self.four_octet_as);
+            if self.debug.logs_message(message.as_ref().ok()) {
+                debug::log_message(
+                    Direction::Receive,
+                    &self.peer_name,
+                    &bytes,
+                    message.as_ref().ok(),
+                );
+            }
+            return Some(message);
         }
-        Ok(u16::from_be_bytes([self.buffer[16], self.buffer[17]]) as usize)
+        Some(Message::decode(buffer, self.four_octet_as))
     }
 
     async fn read_data_from_tcp_connection(&mut self) {
-        loop {
-            let mut buf: Vec<u8> = vec![];
-            match self.conn.try_read_buf(&mut buf) {
-                Ok(0) => (),                        // TCP ConnectionがCloseされたことを意味している。
-                Ok(n) => self.buffer.put(&buf[..]), // n bytesのデータを受信した。
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break, // 今readできるデータがないことを意味する。
-                Err(e) => panic!("read data from tcp connectionでエラー{:?}が発生しました", e),
+        while !self.closed {
+            // 一時的なバッファを介さずに、self.bufferへ直接readする。
+            self.buffer.reserve(1500);
+            // 1度だけpollし、Pendingであれば今readできるデータがないとみなす。
+            match self.reader.read_buf(&mut self.buffer).now_or_never() {
+                None => break,                     // 今readできるデータがないことを意味する。
+                Some(Ok(0)) => self.closed = true, // TCP ConnectionがCloseされたことを意味している。
+                Some(Ok(n)) => (),                 // n bytesのデータを受信した。
+                Some(Err(e)) => {
+                    log_error!("read data from tcp connectionでエラー{:?}が発生しました", e);
+                    self.closed = true;
+                }
             }
         }
     }
+}
 
-    async fn connect_to_remote_peer(config: &Config) -> Result<TcpStream> {
-        let bgp_port = 179;
-        TcpStream::connect((config.remote_ip, bgp_port))
-            .await
-            .context(format!(
-                "cannot connect to remote peer {0}:{1}",
-                config.remote_ip, bgp_port
-            ))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn listener_can_be_bound_again_while_previous_connection_is_in_time_wait() {
+        let listener = bind_reusable_listener("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = TcpStream::connect(address).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        // 待ち受けていた側から閉じると、その側の接続がTIME_WAITで残る。
+        drop(server);
+        drop(listener);
+        drop(client);
+
+        assert!(bind_reusable_listener(address, None).is_ok());
     }
 
-    async fn wait_connection_from_remote_peer(config: &Config) -> Result<TcpStream> {
-        let bgp_port = 179;
-        let listener = TcpListener::bind((config.local_ip, bgp_port))
-            .await
-            .context(format!(
-                "{0}:{1}にbindすることが出来ませんでした。",
-                config.local_ip, bgp_port
-            ))?;
-        Ok(listener
-            .accept()
+    #[tokio::test]
+    async fn shared_listener_hands_connections_only_to_the_peer_with_that_address() {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let listener = SharedListener::get_or_bind(address, None).unwrap();
+        let accept_from = |remote_ip: &str| {
+            let listener = SharedListener::get_or_bind(address, None).unwrap();
+            tokio::spawn(listener.accept_from(remote_ip.parse().unwrap()))
+        };
+        let from_2 = accept_from("127.0.0.2");
+        let from_3 = accept_from("127.0.0.3");
+        while listener.waiters.lock().unwrap().len() < 2 {
+            tokio::task::yield_now().await;
+        }
+        let connect_from = |local_ip: &str| {
+            reusable_socket(local_ip.parse().unwrap(), None)
+                .unwrap()
+                .connect(address)
+        };
+
+        // 待っているピアのいない送信元からのTCP Connectionは閉じられる。
+        let mut stranger = connect_from("127.0.0.4").await.unwrap();
+        let mut bytes = vec![];
+        stranger.read_to_end(&mut bytes).await.unwrap();
+        assert!(bytes.is_empty());
+
+        let client = connect_from("127.0.0.3").await.unwrap();
+        let accepted = from_3.await.unwrap().unwrap();
+        assert_eq!(accepted.peer_addr().unwrap(), client.local_addr().unwrap());
+        assert!(!from_2.is_finished());
+
+        // 待つのをやめたピアの通知先は残らない。
+        from_2.abort();
+        let _ = from_2.await;
+        assert!(listener.waiters.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn host_name_is_resolved_to_ipv4_address() {
+        assert_eq!(
+            resolve_host("localhost").await.unwrap(),
+            Ipv4Addr::new(127, 0, 0, 1)
+        );
+    }
+
+    #[test]
+    fn dscp_can_be_given_by_name_or_number() {
+        assert_eq!("cs6".parse::<Dscp>().unwrap(), Dscp::CS6);
+        assert_eq!("AF41".parse::<Dscp>().unwrap(), Dscp(34));
+        assert_eq!("ef".parse::<Dscp>().unwrap(), Dscp(46));
+        assert_eq!("10".parse::<Dscp>().unwrap(), Dscp(10));
+        assert_eq!(Dscp::CS6.tos(), 0xc0);
+        for invalid in ["cs8", "af14", "64", "best-effort"] {
+            assert!(invalid.parse::<Dscp>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn connecting_socket_applies_dscp() {
+        let listener =
+            bind_reusable_listener("127.0.0.1:0".parse().unwrap(), Some(Dscp::CS6)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let socket = reusable_socket("127.0.0.1".parse().unwrap(), Some(Dscp::CS6)).unwrap();
+        assert_eq!(socket.tos_v4().unwrap(), Dscp::CS6.tos());
+        socket.connect(address).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn full_outbound_queue_is_counted_as_stall() {
+        let (local, mut remote) = io::duplex(1024);
+        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active outbound-queue-size 1"
+            .parse()
+            .unwrap();
+        let mut conn = Connection::connect(&InMemoryTransport::new(local), &config)
             .await
-            .context(format!(
-                "{0}:{1}にてリモートからのTCP Connectionの要求を完遂することが出来ませんでした。
-                リモートからTCP Connectionの要求が来ていない可能性が高いです。",
-                config.local_ip, bgp_port
-            ))?
-            .0)
+            .unwrap();
+
+        // 書き込みタスクがまだ取り出していないので、1つでキューが満杯になる。
+        conn.send(Message::new_keepalive()).await;
+        assert!(!conn.outbound_queue_has_room());
+        assert_eq!(
+            conn.outbound_queue_statistics(),
+            OutboundQueueStatistics {
+                depth: 1,
+                stalls: 1,
+                drops: 0
+            }
+        );
+
+        let mut bytes = [0; MINIMUM_MESSAGE_LENGTH as usize];
+        remote.read_exact(&mut bytes).await.unwrap();
+        assert!(conn.outbound_queue_has_room());
+        assert_eq!(conn.outbound_queue_statistics().depth, 0);
     }
 }
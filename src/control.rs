@@ -0,0 +1,1300 @@
+use crate::bgp_type::AutonomousSystemNumber;
+use crate::bgpdump;
+use crate::capture::MessageCapture;
+use crate::churn::ChurnTracker;
+use crate::config::{Config, RemoteAs};
+use crate::control_auth::{self, ControlTokens, Role};
+use crate::event::Event;
+use crate::log_error;
+use crate::memory::MemoryUsage;
+use crate::monitor::{format_path_attribute, json_string, Monitor, MonitorFormat};
+use crate::path_attribute::AsPath;
+use crate::peer::{Peer, PeerHandle};
+use crate::peer_manager::PeerManager;
+use crate::rib_diff::{RibDiff, RibSnapshot};
+use crate::routing::{AdjRibIn, Ipv4Network, LocRib, Provenance, RibEntry};
+use crate::statistics::PeerStatistics;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::Ipv4Addr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+
+pub const DEFAULT_CONTROL_SOCKET: &str = "/tmp/howbgp.sock";
+
+/// `show churn`で期間を指定しなかった場合に集計する期間。
+const DEFAULT_CHURN_WINDOW: Duration = Duration::from_secs(300);
+
+/// コントロールAPIから参照するピアの情報。
+#[derive(Debug, Clone)]
+pub struct Neighbor {
+    pub config: Config,
+    pub statistics: Arc<Mutex<PeerStatistics>>,
+    /// `show rib`で、ベストパス以外の候補も表示するために参照する。
+    pub adj_rib_in: Arc<Mutex<AdjRibIn>>,
+    /// `show capture`で表示する、直近に送受信したメッセージ。
+    pub message_capture: Arc<std::sync::Mutex<MessageCapture>>,
+    pub handle: PeerHandle,
+}
+
+impl From<&Peer> for Neighbor {
+    fn from(peer: &Peer) -> Self {
+        Self {
+            config: peer.config().clone(),
+            statistics: peer.statistics(),
+            adj_rib_in: peer.adj_rib_in(),
+            message_capture: peer.message_capture(),
+            handle: peer.handle(),
+        }
+    }
+}
+
+/// 動作中のピアの一覧。`apply-config`でピアを追加、削除すると、
+/// 同じ一覧を参照しているコントロールAPIやヘルスチェックにも反映される。
+#[derive(Debug, Clone, Default)]
+pub struct NeighborList(Arc<RwLock<Vec<Neighbor>>>);
+
+impl NeighborList {
+    pub fn new(neighbors: Vec<Neighbor>) -> Self {
+        Self(Arc::new(RwLock::new(neighbors)))
+    }
+
+    /// 現在のピアの一覧の複製。
+    pub fn snapshot(&self) -> Vec<Neighbor> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn replace(&self, neighbors: Vec<Neighbor>) {
+        *self.0.write().unwrap() = neighbors;
+    }
+}
+
+impl From<Vec<Neighbor>> for NeighborList {
+    fn from(neighbors: Vec<Neighbor>) -> Self {
+        Self::new(neighbors)
+    }
+}
+
+/// `show rib`の出力の形式。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RibFormat {
+    Text,
+    /// prefixごとにパスをまとめたJSON。
+    Json,
+    Bgpdump,
+    /// 1つのパスを1行で表すCSV。表計算ソフトやスクリプトで読み込むために使う。
+    Csv,
+    /// 1つのパスを1つのオブジェクトで表す、入れ子のないJSONの配列。
+    FlatJson,
+}
+
+/// `show rib`で表示する、1つのprefixの1つのパス。
+#[derive(Debug)]
+struct RibPath {
+    /// パスを学習したピア。自分が広告しているルートの場合はNone。
+    from: Option<Ipv4Addr>,
+    best: bool,
+    entry: RibEntry,
+}
+
+/// Unix Domain Socketで1行のコマンドを受け付け、結果のテキストを返して接続を閉じるサーバ。
+/// `monitor`コマンドだけは接続を閉じず、クライアントが切断するまでイベントを送り続ける。
+#[derive(Debug, Clone)]
+pub struct ControlServer {
+    neighbors: NeighborList,
+    loc_rib: Arc<Mutex<LocRib>>,
+    monitor: Option<Monitor>,
+    /// `show churn`で集計する記録。Noneの場合は`show churn`を受け付けない。
+    churn: Option<ChurnTracker>,
+    /// `apply-config`でピアを追加、削除、変更する。Noneの場合は`apply-config`を受け付けない。
+    peer_manager: Option<Arc<Mutex<PeerManager>>>,
+    /// クライアントを認証するトークン。Noneの場合は認証せず、すべてのコマンドを受け付ける。
+    tokens: Option<Arc<ControlTokens>>,
+}
+
+impl ControlServer {
+    pub fn new(neighbors: impl Into<NeighborList>, loc_rib: Arc<Mutex<LocRib>>) -> Self {
+        Self {
+            neighbors: neighbors.into(),
+            loc_rib,
+            monitor: None,
+            churn: None,
+            peer_manager: None,
+            tokens: None,
+        }
+    }
+
+    /// tokensで認証したクライアントのコマンドだけを、トークンのRoleの範囲で受け付ける。
+    pub fn set_tokens(&mut self, tokens: ControlTokens) {
+        self.tokens = Some(Arc::new(tokens));
+    }
+
+    pub fn set_peer_manager(&mut self, peer_manager: Arc<Mutex<PeerManager>>) {
+        self.peer_manager = Some(peer_manager);
+    }
+
+    /// `monitor`コマンドで配信するイベントを、monitorから購読する。
+    pub fn set_monitor(&mut self, monitor: Monitor) {
+        self.monitor = Some(monitor);
+    }
+
+    pub fn set_churn_tracker(&mut self, churn: ChurnTracker) {
+        self.churn = Some(churn);
+    }
+
+    pub async fn serve(self, path: &Path) -> Result<()> {
+        // 前回起動時のsocketファイルが残っているとbindできないため削除する。
+        let _ = std::fs::remove_file(path);
+        let listener =
+            UnixListener::bind(path).context(format!("{:?}にbindできませんでした。", path))?;
+        // 他のユーザがトークンを試せないように、socketにはこのプロセスのユーザだけが接続できるようにする。
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).context(format!(
+            "{:?}のパーミッションを変更できませんでした。",
+            path
+        ))?;
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.handle_client(stream).await {
+                    log_error!("コントロールAPIのリクエストの処理に失敗しました。{:?}", e);
+                }
+            });
+        }
+    }
+
+    async fn handle_client(&self, stream: UnixStream) -> Result<()> {
+        let mut stream = BufReader::new(stream);
+        let line = tokio::time::timeout(
+            control_auth::REQUEST_TIMEOUT,
+            control_auth::read_line(&mut stream),
+        )
+        .await
+        .context("コマンドが時間内に届きませんでした。")??;
+        let response = match self.authorize(line.trim()) {
+            Ok(command) => {
+                if let ["monitor", args @ ..] = &command.split_whitespace().collect::<Vec<_>>()[..]
+                {
+                    return self.monitor(stream.get_mut(), args).await;
+                }
+                self.handle_command(command).await
+            }
+            Err(response) => response,
+        };
+        stream.get_mut().write_all(response.as_bytes()).await?;
+        stream.get_mut().shutdown().await?;
+        Ok(())
+    }
+
+    /// 行の先頭の`auth <token>`でクライアントを認証し、実行してよいコマンドを返す。
+    /// 実行できない場合は、クライアントに返すエラーをErrで返す。
+    fn authorize<'a>(&self, line: &'a str) -> Result<&'a str, String> {
+        let tokens = match &self.tokens {
+            Some(tokens) => tokens,
+            None => return Ok(line),
+        };
+        match tokens.authenticate(line) {
+            None => Err("authentication required\n".to_owned()),
+            Some((Role::ReadOnly, command))
+                if !control_auth::is_read_only(&command.split_whitespace().collect::<Vec<_>>()) =>
+            {
+                Err("permission denied\n".to_owned())
+            }
+            Some((_, command)) => Ok(command),
+        }
+    }
+
+    async fn handle_command(&self, command: &str) -> String {
+        let command: Vec<&str> = command.split_whitespace().collect();
+        match command[..] {
+            ["show", "neighbors"] => self.show_neighbors().await,
+            ["show", "history"] => self.show_history(None).await,
+            ["show", "history", neighbor] => self.show_history(Some(neighbor)).await,
+            ["show", "capture"] => self.show_capture(None),
+            ["show", "capture", neighbor] => self.show_capture(Some(neighbor)),
+            ["show", "rib", ref args @ ..] => self.show_rib(args).await,
+            ["show", "route", address] => self.show_route(address, false).await,
+            ["show", "route", address, "--json"] => self.show_route(address, true).await,
+            ["show", "memory"] => self.show_memory().await,
+            ["show", "churn"] => self.show_churn(None),
+            ["show", "churn", seconds] => self.show_churn(Some(seconds)),
+            ["apply-config", path] => self.apply_config(Path::new(path)).await,
+            ["diff-rib", path] => self.diff_rib(Path::new(path)).await,
+            ["neighbor", neighbor, "disable"] => self.set_neighbor_enabled(neighbor, false).await,
+            ["neighbor", neighbor, "enable"] => self.set_neighbor_enabled(neighbor, true).await,
+            ["maintenance"] => self.show_maintenance().await,
+            ["maintenance", "on"] => self.set_maintenance(true).await,
+            ["maintenance", "off"] => self.set_maintenance(false).await,
+            _ => format!("unknown command `{}`\n", command.join(" ")),
+        }
+    }
+
+    async fn show_neighbors(&self) -> String {
+        let mut output = String::new();
+        for neighbor in &self.neighbors.snapshot() {
+            let statistics = neighbor.statistics.lock().await;
+            output += &format_neighbor(&neighbor.config, &statistics);
+        }
+        output
+    }
+
+    /// 状態遷移の履歴を表示する。neighborを指定した場合はそのピアの履歴のみ表示する。
+    async fn show_history(&self, neighbor: Option<&str>) -> String {
+        let mut output = String::new();
+        for n in &self.neighbors.snapshot() {
+            if neighbor.is_some_and(|ip| ip != n.config.remote_ip.to_string()) {
+                continue;
+            }
+            writeln!(output, "Neighbor {}", n.config.remote_ip).unwrap();
+            for transition in n.statistics.lock().await.state_history.iter() {
+                writeln!(output, "  {}", transition).unwrap();
+            }
+        }
+        output
+    }
+
+    /// 直近に送受信したメッセージを、16進数とデコードした構造で表示する。
+    /// neighborを指定した場合はそのピアのメッセージのみ表示する。
+    fn show_capture(&self, neighbor: Option<&str>) -> String {
+        let mut output = String::new();
+        for n in &self.neighbors.snapshot() {
+            if neighbor.is_some_and(|ip| ip != n.config.remote_ip.to_string()) {
+                continue;
+            }
+            writeln!(output, "Neighbor {}", n.config.remote_ip).unwrap();
+            for message in n.message_capture.lock().unwrap().iter() {
+                writeln!(output, "{}", message).unwrap();
+            }
+        }
+        output
+    }
+
+    /// `show rib [prefix] [--json|--bgpdump|--format csv|json]`。LocRibの各prefixについて、
+    /// ベストパスと各ピアから受信したほかのパスを表示する。
+    /// prefixにアドレスを指定した場合は、そのアドレスを含む最長一致のprefixを表示する。
+    async fn show_rib(&self, args: &[&str]) -> String {
+        let mut format = RibFormat::Text;
+        let mut prefix = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match *arg {
+                "--json" => format = RibFormat::Json,
+                "--bgpdump" => format = RibFormat::Bgpdump,
+                "--format" => {
+                    format = match args.next() {
+                        Some(&"csv") => RibFormat::Csv,
+                        Some(&"json") => RibFormat::FlatJson,
+                        _ => return "--formatにはcsvかjsonを指定してください。\n".to_owned(),
+                    }
+                }
+                arg if !arg.starts_with("--") => prefix = Some(arg),
+                _ => (),
+            }
+        }
+        let loc_rib = self.loc_rib.lock().await;
+        let best_routes: Vec<RibEntry> = match prefix {
+            None => loc_rib.iter().cloned().collect(),
+            Some(prefix) => {
+                let route = match (prefix.parse::<Ipv4Network>(), prefix.parse::<Ipv4Addr>()) {
+                    (_, Ok(address)) => loc_rib.lookup(address),
+                    (Ok(network), _) => loc_rib.get(&network),
+                    _ => return format!("`{}`をprefixとして読み取れませんでした。\n", prefix),
+                };
+                route.cloned().into_iter().collect()
+            }
+        };
+        // ピアのタスクとロックの順序が逆にならないように、LocRibのロックを解放してからAdjRibInを参照する。
+        drop(loc_rib);
+
+        let mut candidates: HashMap<Ipv4Network, Vec<(Ipv4Addr, RibEntry)>> = best_routes
+            .iter()
+            .map(|r| (r.network_address, vec![]))
+            .collect();
+        let mut peer_as = HashMap::new();
+        for neighbor in &self.neighbors.snapshot() {
+            if let RemoteAs::Number(as_number) = neighbor.config.remote_as {
+                peer_as.insert(neighbor.config.remote_ip, as_number);
+            }
+            for route in neighbor.adj_rib_in.lock().await.iter() {
+                if let Some(paths) = candidates.get_mut(&route.network_address) {
+                    paths.push((neighbor.config.remote_ip, route.clone()));
+                }
+            }
+        }
+
+        let routes: Vec<(Ipv4Network, Vec<RibPath>)> = best_routes
+            .into_iter()
+            .map(|best| {
+                let mut paths: Vec<RibPath> = candidates
+                    .remove(&best.network_address)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(from, entry)| RibPath {
+                        from: Some(from),
+                        best: entry == best,
+                        entry,
+                    })
+                    .collect();
+                if !paths.iter().any(|p| p.best) {
+                    paths.insert(
+                        0,
+                        RibPath {
+                            from: None,
+                            best: true,
+                            entry: best.clone(),
+                        },
+                    );
+                }
+                (best.network_address, paths)
+            })
+            .collect();
+        match format {
+            RibFormat::Text => format_rib(&routes),
+            RibFormat::Json => format_rib_as_json(&routes),
+            RibFormat::Bgpdump => format_rib_as_bgpdump(&routes, &peer_as),
+            RibFormat::Csv => format_rib_as_csv(&routes),
+            RibFormat::FlatJson => format_rib_as_flat_json(&routes),
+        }
+    }
+
+    /// `show churn [seconds]`。直近seconds秒(既定では5分)の間に、
+    /// 広告と取り消しの多かったprefixとピアを表示する。
+    fn show_churn(&self, seconds: Option<&str>) -> String {
+        let churn = match &self.churn {
+            Some(churn) => churn,
+            None => return "churn tracking is not enabled\n".to_owned(),
+        };
+        let window = match seconds.map(|s| s.parse::<u64>()) {
+            None => DEFAULT_CHURN_WINDOW,
+            Some(Ok(seconds)) if seconds > 0 => Duration::from_secs(seconds),
+            Some(_) => {
+                return format!("`{}`を秒数として読み取れませんでした。\n", seconds.unwrap())
+            }
+        };
+        churn.report(window).to_string()
+    }
+
+    /// `show route <address> [--json]`。addressを含む最長一致のprefixのベストパスだけを表示する。
+    /// addressへのパケットがどのルートで転送されるかを調べるのに使う。
+    async fn show_route(&self, address: &str, json: bool) -> String {
+        let address = match address.parse::<Ipv4Addr>() {
+            Ok(address) => address,
+            Err(_) => return format!("`{}`をIPv4アドレスとして読み取れませんでした。\n", address),
+        };
+        let routes: Vec<(Ipv4Network, Vec<RibPath>)> = self
+            .loc_rib
+            .lock()
+            .await
+            .lookup(address)
+            .map(|best| {
+                let path = RibPath {
+                    from: best.provenance.map(|p| p.peer),
+                    best: true,
+                    entry: best.clone(),
+                };
+                (best.network_address, vec![path])
+            })
+            .into_iter()
+            .collect();
+        if json {
+            format_rib_as_json(&routes)
+        } else if routes.is_empty() {
+            format!("{}を含むルートはありません。\n", address)
+        } else {
+            format_rib(&routes)
+        }
+    }
+
+    /// `diff-rib <snapshot>`。LocRibのダンプと比べて、ダンプした後に変わったルートを表示する。
+    /// LocRibのダンプと同じく、ルートは設定の最初のピアのlocal ipが持つものとして比べる。
+    /// デーモンの権限で任意のファイルを読ませないように、読み込めるのはmrt-dump-dirの中のファイルだけとする。
+    async fn diff_rib(&self, path: &Path) -> String {
+        let first = self.neighbors.snapshot().first().map(|n| n.config.clone());
+        let dump_dir = match first.as_ref().and_then(|c| c.mrt_dump_dir.as_ref()) {
+            Some(dump_dir) => dump_dir,
+            None => return "diff-rib requires mrt-dump-dir\n".to_owned(),
+        };
+        let snapshot = match dump_path_in(dump_dir, path).and_then(|p| RibSnapshot::load(&p)) {
+            Ok(snapshot) => snapshot,
+            Err(e) => return format!("{:#}\n", e),
+        };
+        let local_ip = first.map_or(Ipv4Addr::UNSPECIFIED, |c| c.local_ip);
+        let live = RibSnapshot::from_loc_rib(&*self.loc_rib.lock().await, local_ip);
+        RibDiff::new(&snapshot, &live).to_string()
+    }
+
+    /// pathの設定を読み込み、動作中の設定との差分だけを反映する。
+    /// 設定を読み取れなかった場合は何も変更しない。
+    async fn apply_config(&self, path: &Path) -> String {
+        let peer_manager = match &self.peer_manager {
+            Some(peer_manager) => peer_manager,
+            None => return "apply-config is not enabled\n".to_owned(),
+        };
+        let configs = match std::fs::read_to_string(path)
+            .context(format!("{:?}を読み込めませんでした。", path))
+            .and_then(|text| Ok(Config::parse_lines(&text)?))
+        {
+            Ok(configs) => configs,
+            Err(e) => return format!("{:#}\n", e),
+        };
+        peer_manager.lock().await.apply(configs).await.to_string()
+    }
+
+    /// LocRib、共有しているPath Attribute、各ピアのAdjRibInとEventのキューについて、
+    /// 要素の数とおおよそのメモリ使用量を表示する。
+    async fn show_memory(&self) -> String {
+        let mut usages = vec![];
+        {
+            let loc_rib = self.loc_rib.lock().await;
+            usages.push(("loc-rib".to_owned(), loc_rib.memory_usage()));
+            usages.push((
+                "path-attributes".to_owned(),
+                loc_rib.path_attribute_memory_usage(),
+            ));
+        }
+        for neighbor in &self.neighbors.snapshot() {
+            let remote_ip = neighbor.config.remote_ip;
+            usages.push((
+                format!("adj-rib-in {}", remote_ip),
+                neighbor.adj_rib_in.lock().await.memory_usage(),
+            ));
+            let queued_events = neighbor.statistics.lock().await.queued_events;
+            usages.push((
+                format!("event-queue {}", remote_ip),
+                MemoryUsage::of::<Event>(queued_events),
+            ));
+        }
+        format_memory_usages(&usages)
+    }
+
+    /// `neighbor <ip> disable|enable`。設定ファイルを変えずに、ピアのセッションを停止したままにするか、
+    /// 開始し直す。設定を読み込み直してピアを作り直した場合は、有効な状態に戻る。
+    async fn set_neighbor_enabled(&self, neighbor: &str, enabled: bool) -> String {
+        let found = self
+            .neighbors
+            .snapshot()
+            .into_iter()
+            .find(|n| n.config.remote_ip.to_string() == neighbor);
+        match found {
+            Some(n) => {
+                n.handle.set_enabled(enabled);
+                let state = if enabled { "enabled" } else { "disabled" };
+                format!("neighbor {} {}\n", neighbor, state)
+            }
+            None => format!("neighbor {} is not configured\n", neighbor),
+        }
+    }
+
+    async fn show_maintenance(&self) -> String {
+        let maintenance = self.loc_rib.lock().await.in_maintenance();
+        format!("maintenance {}\n", if maintenance { "on" } else { "off" })
+    }
+
+    /// 計画作業の前に、すべてのピアに広告しているルートの優先度を下げ、
+    /// 対向機器にほかの経路へ切り替えてもらう。offにすると元の属性で広告し直す。
+    async fn set_maintenance(&self, maintenance: bool) -> String {
+        self.loc_rib.lock().await.set_maintenance(maintenance);
+        for neighbor in &self.neighbors.snapshot() {
+            neighbor.handle.notify_loc_rib_changed();
+        }
+        self.show_maintenance().await
+    }
+
+    /// `monitor [neighbor] [--json|--bgpdump]`。neighborを指定した場合はそのピアのイベントのみ送る。
+    async fn monitor(&self, stream: &mut UnixStream, args: &[&str]) -> Result<()> {
+        let format = if args.contains(&"--json") {
+            MonitorFormat::Json
+        } else if args.contains(&"--bgpdump") {
+            MonitorFormat::Bgpdump
+        } else {
+            MonitorFormat::Line
+        };
+        let neighbor = args.iter().find(|a| !a.starts_with("--"));
+        let mut receiver = match &self.monitor {
+            Some(monitor) => monitor.subscribe(),
+            None => {
+                stream
+                    .write_all("monitor is not enabled\n".as_bytes())
+                    .await?;
+                return Ok(stream.shutdown().await?);
+            }
+        };
+        loop {
+            let line = match receiver.recv().await {
+                Ok(event) => {
+                    if neighbor.is_some_and(|ip| *ip != event.neighbor.to_string()) {
+                        continue;
+                    }
+                    match event.format(format) {
+                        line if line.is_empty() => continue,
+                        line => line,
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    format!(
+                        "# 読み出しが追いつかず、{}個のイベントを捨てました。",
+                        skipped
+                    )
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            };
+            // クライアントが切断すると書き込みに失敗し、購読をやめる。
+            stream.write_all(format!("{}\n", line).as_bytes()).await?;
+        }
+    }
+}
+
+/// コントロールAPIに`monitor`コマンドを送り、受け取ったイベントを1行ずつfに渡す。
+/// デーモンが接続を閉じるまで返らない。
+pub async fn monitor(path: &Path, command: &str, mut f: impl FnMut(&str)) -> Result<()> {
+    let mut stream = UnixStream::connect(path).await.context(format!(
+        "{:?}に接続できませんでした。デーモンが起動しているか確認してください。",
+        path
+    ))?;
+    stream
+        .write_all(format!("{}\n", command).as_bytes())
+        .await?;
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(line) = lines.next_line().await? {
+        f(&line);
+    }
+    Ok(())
+}
+
+/// コントロールAPIにcommandを送り、結果のテキストを受け取る。
+pub async fn request(path: &Path, command: &str) -> Result<String> {
+    let mut stream = UnixStream::connect(path).await.context(format!(
+        "{:?}に接続できませんでした。デーモンが起動しているか確認してください。",
+        path
+    ))?;
+    stream
+        .write_all(format!("{}\n", command).as_bytes())
+        .await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    Ok(response)
+}
+
+/// dirからのpathを、dirの中のファイルの絶対パスにする。
+/// `..`やシンボリックリンクでdirの外のファイルを指す場合はエラーを返す。
+fn dump_path_in(dir: &Path, path: &Path) -> Result<PathBuf> {
+    let dir = std::fs::canonicalize(dir).context(format!("{:?}を開けません。", dir))?;
+    let path =
+        std::fs::canonicalize(dir.join(path)).context(format!("{:?}を開けません。", path))?;
+    if !path.starts_with(&dir) {
+        return Err(anyhow::anyhow!(
+            "{:?}はmrt-dump-dir {:?}の中のファイルではありません。",
+            path,
+            dir
+        ));
+    }
+    Ok(path)
+}
+
+fn format_neighbor(config: &Config, statistics: &PeerStatistics) -> String {
+    let mut output = String::new();
+    let uptime = statistics
+        .uptime()
+        .map(format_duration)
+        .unwrap_or_else(|| "never".to_owned());
+    let notification = |n: &Option<_>| match n {
+        Some(n) => format!("{}", n),
+        None => "none".to_owned(),
+    };
+    let sent = &statistics.messages_sent;
+    let received = &statistics.messages_received;
+    writeln!(
+        output,
+        "Neighbor {}, remote AS {}, state {:?}, up for {}",
+        config.remote_ip, config.remote_as, statistics.state, uptime
+    )
+    .unwrap();
+    if let Some(description) = &config.description {
+        writeln!(output, "  Description: {}", description).unwrap();
+    }
+    if statistics.disabled {
+        writeln!(output, "  Administratively disabled").unwrap();
+    }
+    if let Some(limit) = config.max_prefix {
+        writeln!(
+            output,
+            "  Maximum prefixes: {} (warning at {}%){}",
+            limit,
+            config.max_prefix_warning_threshold,
+            if statistics.max_prefix_warning {
+                ", threshold reached"
+            } else {
+                ""
+            }
+        )
+        .unwrap();
+    }
+    writeln!(output, "  Messages:        Sent       Rcvd").unwrap();
+    for (name, s, r) in [
+        ("Open", sent.open, received.open),
+        ("Update", sent.update, received.update),
+        ("Notification", sent.notification, received.notification),
+        ("Keepalive", sent.keepalive, received.keepalive),
+        ("Total", sent.total(), received.total()),
+    ] {
+        writeln!(output, "    {:<12} {:>7} {:>10}", name, s, r).unwrap();
+    }
+    writeln!(
+        output,
+        "  Prefixes: accepted {}, rejected {}, advertised {}",
+        statistics.prefixes_accepted, statistics.prefixes_rejected, statistics.prefixes_advertised
+    )
+    .unwrap();
+    writeln!(output, "  FSM transitions: {}", statistics.fsm_transitions).unwrap();
+    writeln!(
+        output,
+        "  Outbound queue: depth {}, stalls {}, drops {}",
+        statistics.outbound_queue.depth,
+        statistics.outbound_queue.stalls,
+        statistics.outbound_queue.drops
+    )
+    .unwrap();
+    writeln!(
+        output,
+        "  Last notification sent: {}",
+        notification(&statistics.last_notification_sent)
+    )
+    .unwrap();
+    writeln!(
+        output,
+        "  Last notification received: {}",
+        notification(&statistics.last_notification_received)
+    )
+    .unwrap();
+    output
+}
+
+fn format_memory_usages(usages: &[(String, MemoryUsage)]) -> String {
+    let mut output = format!("{:<32} {:>10} {:>12}\n", "Structure", "Entries", "Bytes");
+    let mut total = MemoryUsage::default();
+    for (name, usage) in usages {
+        writeln!(
+            output,
+            "{:<32} {:>10} {:>12}",
+            name, usage.entries, usage.bytes
+        )
+        .unwrap();
+        total += *usage;
+    }
+    writeln!(output, "{:<32} {:>10} {:>12}", "Total", "", total.bytes).unwrap();
+    output
+}
+
+/// ベストパスには`*>`を付けて、1つのパスを1行で表示する。
+/// Ageは、ピアから学習したルートのPath Attributeが最後に変わってからの経過時間である。
+fn format_rib(routes: &[(Ipv4Network, Vec<RibPath>)]) -> String {
+    let mut output = format!(
+        "   {:<18} {:<15} {:<8} {}\n",
+        "Network", "From", "Age", "Path Attributes"
+    );
+    let now = SystemTime::now();
+    for (network, paths) in routes {
+        for path in paths {
+            let from = path
+                .from
+                .map_or_else(|| "local".to_owned(), |ip| ip.to_string());
+            let age = path.entry.provenance.map_or_else(
+                || "-".to_owned(),
+                |p| format_duration(now.duration_since(p.changed_at).unwrap_or_default()),
+            );
+            let path_attributes: Vec<String> = path
+                .entry
+                .path_attributes
+                .iter()
+                .map(format_path_attribute)
+                .collect();
+            writeln!(
+                output,
+                "{} {:<18} {:<15} {:<8} {}",
+                if path.best { "*>" } else { "* " },
+                network.to_string(),
+                from,
+                age,
+                path_attributes.join(" ")
+            )
+            .unwrap();
+        }
+    }
+    output
+}
+
+/// `bgpdump -m`と同じ`TABLE_DUMP2|...`の行で、RIBのすべてのパスを表す。
+/// ピアのAS番号は、設定で決まっていなければAS Pathの先頭のAS番号にする。
+/// 自分が広告しているルートは、ピアのアドレスを0.0.0.0、AS番号を0にする。
+fn format_rib_as_bgpdump(
+    routes: &[(Ipv4Network, Vec<RibPath>)],
+    peer_as: &HashMap<Ipv4Addr, AutonomousSystemNumber>,
+) -> String {
+    let mut output = String::new();
+    for (_, paths) in routes {
+        for path in paths {
+            let peer_ip = path.from.unwrap_or(Ipv4Addr::UNSPECIFIED);
+            let as_number = match path.from {
+                None => AutonomousSystemNumber::from(0),
+                Some(from) => {
+                    peer_as
+                        .get(&from)
+                        .copied()
+                        .unwrap_or_else(|| match path.entry.as_path() {
+                            Some(AsPath::AsSequence(as_numbers)) if !as_numbers.is_empty() => {
+                                as_numbers[0]
+                            }
+                            _ => AutonomousSystemNumber::from(0),
+                        })
+                }
+            };
+            let time = path
+                .entry
+                .provenance
+                .map_or_else(SystemTime::now, |p| p.changed_at);
+            writeln!(
+                output,
+                "{}",
+                bgpdump::rib_entry_line(time, peer_ip, as_number, &path.entry)
+            )
+            .unwrap();
+        }
+    }
+    output
+}
+
+fn format_rib_as_json(routes: &[(Ipv4Network, Vec<RibPath>)]) -> String {
+    let routes: Vec<String> = routes
+        .iter()
+        .map(|(network, paths)| {
+            let paths: Vec<String> = paths
+                .iter()
+                .map(|path| {
+                    let from = path
+                        .from
+                        .map_or_else(|| "local".to_owned(), |ip| ip.to_string());
+                    let path_attributes: Vec<String> = path
+                        .entry
+                        .path_attributes
+                        .iter()
+                        .map(|p| json_string(&format_path_attribute(p)))
+                        .collect();
+                    format!(
+                        r#"{{"best":{},"from":"{}",{},"path_attributes":[{}]}}"#,
+                        path.best,
+                        from,
+                        provenance_to_json(path.entry.provenance),
+                        path_attributes.join(",")
+                    )
+                })
+                .collect();
+            format!(
+                r#"{{"prefix":"{}","paths":[{}]}}"#,
+                **network,
+                paths.join(",")
+            )
+        })
+        .collect();
+    format!("{{\"routes\":[{}]}}\n", routes.join(","))
+}
+
+/// 1つのパスを`prefix,next_hop,as_path,communities,from,best`の1行で表す。
+/// AS PathとCommunityは空白で区切り、AS_SETは`{}`で囲む。
+fn format_rib_as_csv(routes: &[(Ipv4Network, Vec<RibPath>)]) -> String {
+    let mut output = "prefix,next_hop,as_path,communities,from,best\n".to_owned();
+    for (network, paths) in routes {
+        for path in paths {
+            let fields = FlatRibPath::new(path);
+            writeln!(
+                output,
+                "{},{},{},{},{},{}",
+                **network,
+                fields.next_hop,
+                fields.as_path,
+                fields.communities.join(" "),
+                fields.from,
+                path.best
+            )
+            .unwrap();
+        }
+    }
+    output
+}
+
+/// 1つのパスを、CSVと同じ項目を持つJSONのオブジェクトで表す。
+fn format_rib_as_flat_json(routes: &[(Ipv4Network, Vec<RibPath>)]) -> String {
+    let paths: Vec<String> = routes
+        .iter()
+        .flat_map(|(network, paths)| {
+            paths.iter().map(move |path| {
+                let fields = FlatRibPath::new(path);
+                let communities: Vec<String> =
+                    fields.communities.iter().map(|c| json_string(c)).collect();
+                format!(
+                    r#"{{"prefix":"{}","next_hop":{},"as_path":{},"communities":[{}],"from":"{}","best":{}}}"#,
+                    **network,
+                    json_string(&fields.next_hop),
+                    json_string(&fields.as_path),
+                    communities.join(","),
+                    fields.from,
+                    path.best
+                )
+            })
+        })
+        .collect();
+    format!("[{}]\n", paths.join(","))
+}
+
+/// CSVと入れ子のないJSONで、1つのパスを表す項目。
+struct FlatRibPath {
+    next_hop: String,
+    as_path: String,
+    communities: Vec<String>,
+    from: String,
+}
+
+impl FlatRibPath {
+    fn new(path: &RibPath) -> Self {
+        fn join<'a>(as_numbers: impl Iterator<Item = &'a AutonomousSystemNumber>) -> String {
+            as_numbers
+                .map(|a| a.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+        Self {
+            next_hop: path
+                .entry
+                .next_hop()
+                .map_or_else(String::new, |n| n.to_string()),
+            as_path: match path.entry.as_path() {
+                Some(AsPath::AsSequence(sequence)) => join(sequence.iter()),
+                Some(AsPath::AsSet(set)) => format!("{{{}}}", join(set.iter())),
+                None => String::new(),
+            },
+            communities: path
+                .entry
+                .communities()
+                .iter()
+                .map(|c| c.to_string())
+                .collect(),
+            from: path
+                .from
+                .map_or_else(|| "local".to_owned(), |ip| ip.to_string()),
+        }
+    }
+}
+
+/// 受信した時刻と変わった時刻を、UNIX時間の秒で表す。自分が広告しているルートではnullにする。
+fn provenance_to_json(provenance: Option<Provenance>) -> String {
+    let unix_seconds = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            .to_string()
+    };
+    let (received_at, changed_at) = provenance.map_or_else(
+        || ("null".to_owned(), "null".to_owned()),
+        |p| (unix_seconds(p.received_at), unix_seconds(p.changed_at)),
+    );
+    format!(
+        r#""received_at":{},"changed_at":{}"#,
+        received_at, changed_at
+    )
+}
+
+/// 経過時間を`hh:mm:ss`の形式にする。
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hook::{MessageHook, PeerContext};
+    use crate::packets::message::Message;
+    use crate::packets::notification::{cease, ErrorCode};
+    use crate::path_attribute::{Origin, PathAttribute};
+    use crate::state::State;
+
+    #[test]
+    fn format_duration_as_hours_minutes_seconds() {
+        assert_eq!(format_duration(Duration::from_secs(3723)), "01:02:03");
+    }
+
+    #[test]
+    fn read_only_token_cannot_run_commands_changing_state() {
+        let mut server = ControlServer::new(vec![], Arc::new(Mutex::new(LocRib::empty())));
+        assert_eq!(server.authorize("maintenance on"), Ok("maintenance on"));
+
+        let mut tokens = ControlTokens::new();
+        tokens.insert("viewer".to_owned(), Role::ReadOnly);
+        tokens.insert("operator".to_owned(), Role::ReadWrite);
+        server.set_tokens(tokens);
+        assert_eq!(
+            server.authorize("show neighbors"),
+            Err("authentication required\n".to_owned())
+        );
+        assert_eq!(
+            server.authorize("auth viewer show neighbors"),
+            Ok("show neighbors")
+        );
+        assert_eq!(
+            server.authorize("auth viewer maintenance on"),
+            Err("permission denied\n".to_owned())
+        );
+        assert_eq!(
+            server.authorize("auth operator maintenance on"),
+            Ok("maintenance on")
+        );
+    }
+
+    #[tokio::test]
+    async fn diff_rib_reads_only_files_in_mrt_dump_dir() {
+        let dir = std::env::temp_dir().join(format!("howbgp-diff-rib-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let outside =
+            std::env::temp_dir().join(format!("howbgp-diff-rib-{}.mrt", std::process::id()));
+        std::fs::write(&outside, b"").unwrap();
+        let config = |options: &str| -> Config {
+            format!("64512 127.0.0.1 64513 127.0.0.2 active{}", options)
+                .parse()
+                .unwrap()
+        };
+        let server = |config: Config| {
+            let peer = Peer::new(config, Arc::new(Mutex::new(LocRib::empty())));
+            ControlServer::new(
+                vec![Neighbor::from(&peer)],
+                Arc::new(Mutex::new(LocRib::empty())),
+            )
+        };
+
+        let command = format!("diff-rib {}", outside.display());
+        assert_eq!(
+            server(config("")).handle_command(&command).await,
+            "diff-rib requires mrt-dump-dir\n"
+        );
+        let server = server(config(&format!(" mrt-dump-dir {}", dir.display())));
+        assert!(server
+            .handle_command(&command)
+            .await
+            .contains("の中のファイルではありません。"));
+        let escaped = format!(
+            "diff-rib ../{}",
+            outside.file_name().unwrap().to_str().unwrap()
+        );
+        assert!(server
+            .handle_command(&escaped)
+            .await
+            .contains("の中のファイルではありません。"));
+
+        std::fs::remove_file(&outside).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn neighbor_can_be_disabled_without_changing_config() {
+        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let mut peer = Peer::new(config, Arc::new(Mutex::new(LocRib::empty())));
+        let server = ControlServer::new(
+            vec![Neighbor::from(&peer)],
+            Arc::new(Mutex::new(LocRib::empty())),
+        );
+
+        assert_eq!(
+            server.handle_command("neighbor 127.0.0.2 disable").await,
+            "neighbor 127.0.0.2 disabled\n"
+        );
+        peer.next().await;
+        assert!(server
+            .handle_command("show neighbors")
+            .await
+            .contains("  Administratively disabled\n"));
+        assert_eq!(
+            server.handle_command("neighbor 127.0.0.9 enable").await,
+            "neighbor 127.0.0.9 is not configured\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn show_neighbors_through_control_socket() {
+        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let statistics = Arc::new(Mutex::new(PeerStatistics::new()));
+        statistics.lock().await.prefixes_accepted = 3;
+        let server = ControlServer::new(
+            vec![Neighbor {
+                config,
+                statistics,
+                adj_rib_in: Arc::new(Mutex::new(AdjRibIn::new())),
+                message_capture: Default::default(),
+                handle: PeerHandle::detached(),
+            }],
+            Arc::new(Mutex::new(LocRib::empty())),
+        );
+        let path = std::env::temp_dir().join("howbgp-control-test.sock");
+        let _ = std::fs::remove_file(&path);
+        let server_path = path.clone();
+        tokio::spawn(async move { server.serve(&server_path).await });
+        // serverがbindするまで待つ。
+        while !path.exists() {
+            tokio::task::yield_now().await;
+        }
+
+        let response = request(&path, "show neighbors").await.unwrap();
+        assert!(response.starts_with("Neighbor 127.0.0.2, remote AS 64513, state Idle"));
+        assert!(response.contains("accepted 3"));
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[tokio::test]
+    async fn monitor_streams_events_of_specified_neighbor() {
+        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let other: Config = "64512 127.0.0.1 64514 127.0.0.3 active".parse().unwrap();
+        let monitor = Monitor::new();
+        let mut server = ControlServer::new(vec![], Arc::new(Mutex::new(LocRib::empty())));
+        server.set_monitor(monitor.clone());
+        let path = std::env::temp_dir().join("howbgp-monitor-test.sock");
+        let _ = std::fs::remove_file(&path);
+        let server_path = path.clone();
+        tokio::spawn(async move { server.serve(&server_path).await });
+        while !path.exists() {
+            tokio::task::yield_now().await;
+        }
+
+        let (sender, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let client_path = path.clone();
+        tokio::spawn(async move {
+            super::monitor(&client_path, "monitor 127.0.0.2", |line: &str| {
+                sender.send(line.to_owned()).unwrap();
+            })
+            .await
+        });
+        let notification =
+            Message::new_notification(ErrorCode::Cease, cease::PEER_DE_CONFIGURED, vec![]);
+        // クライアントが購読を始めるまで送り続ける。
+        let line = loop {
+            for config in [&other, &config] {
+                let context = PeerContext {
+                    config,
+                    state: State::Established,
+                    remote_as: None,
+                };
+                monitor.on_inbound(&context, notification.clone());
+            }
+            tokio::select! {
+                line = received.recv() => break line.unwrap(),
+                _ = tokio::time::sleep(Duration::from_millis(10)) => {}
+            }
+        };
+        assert!(line.starts_with("[127.0.0.2] received NOTIFICATION Cease"));
+    }
+
+    #[tokio::test]
+    async fn show_rib_marks_best_path_among_paths_from_neighbors() {
+        let route = |as_path: &[u32], next_hop: &str| {
+            RibEntry::for_test("10.100.220.0/24")
+                .with_as_path(as_path)
+                .with_next_hop(next_hop)
+        };
+        let neighbor = |config: &str, route: RibEntry| Neighbor {
+            config: config.parse().unwrap(),
+            statistics: Arc::new(Mutex::new(PeerStatistics::new())),
+            adj_rib_in: Arc::new(Mutex::new(AdjRibIn::from(vec![route]))),
+            message_capture: Default::default(),
+            handle: PeerHandle::detached(),
+        };
+        let neighbors = vec![
+            neighbor(
+                "64512 127.0.0.1 64513 127.0.0.2 active",
+                route(&[64513, 64515], "127.0.0.2"),
+            ),
+            neighbor(
+                "64512 127.0.0.1 64514 127.0.0.3 active",
+                route(&[64514], "127.0.0.3").with_provenance(Provenance {
+                    peer: "127.0.0.3".parse().unwrap(),
+                    received_at: UNIX_EPOCH + Duration::from_secs(2000),
+                    changed_at: UNIX_EPOCH + Duration::from_secs(1000),
+                    internal: false,
+                }),
+            ),
+        ];
+        let loc_rib = Arc::new(Mutex::new(LocRib::empty()));
+        for n in &neighbors {
+            loc_rib
+                .lock()
+                .await
+                .install_from_adj_rib_in(&mut *n.adj_rib_in.lock().await);
+        }
+        let server = ControlServer::new(neighbors, loc_rib);
+
+        assert_eq!(
+            server.handle_command("show rib").await,
+            server.handle_command("show rib 10.100.220.0/24").await
+        );
+        let output = server.handle_command("show rib 10.100.220.1").await;
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines[..2],
+            [
+                "   Network            From            Age      Path Attributes",
+                "*  10.100.220.0/24    127.0.0.2       -        origin=Igp as-path=[64513 64515] next-hop=127.0.0.2",
+            ]
+        );
+        assert!(lines[2].starts_with("*> 10.100.220.0/24    127.0.0.3 "));
+        assert!(lines[2].ends_with(" origin=Igp as-path=[64514] next-hop=127.0.0.3"));
+        assert_eq!(
+            server.handle_command("show rib 10.100.220.0/24 --json").await,
+            r#"{"routes":[{"prefix":"10.100.220.0/24","paths":[{"best":false,"from":"127.0.0.2","received_at":null,"changed_at":null,"path_attributes":["origin=Igp","as-path=[64513 64515]","next-hop=127.0.0.2"]},{"best":true,"from":"127.0.0.3","received_at":2000,"changed_at":1000,"path_attributes":["origin=Igp","as-path=[64514]","next-hop=127.0.0.3"]}]}]}"#
+                .to_owned()
+                + "\n"
+        );
+        assert_eq!(
+            server.handle_command("show rib 10.100.0.0/16 --json").await,
+            "{\"routes\":[]}\n"
+        );
+        let output = server
+            .handle_command("show rib 10.100.220.0/24 --bgpdump")
+            .await;
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(lines[0].starts_with("TABLE_DUMP2|"));
+        assert!(lines[0]
+            .ends_with("|B|127.0.0.2|64513|10.100.220.0/24|64513 64515|IGP|127.0.0.2|0|0||NAG||"));
+        assert_eq!(
+            lines[1],
+            "TABLE_DUMP2|1000|B|127.0.0.3|64514|10.100.220.0/24|64514|IGP|127.0.0.3|0|0||NAG||"
+        );
+        assert_eq!(
+            server.handle_command("show rib --format csv").await,
+            "prefix,next_hop,as_path,communities,from,best\n\
+             10.100.220.0/24,127.0.0.2,64513 64515,,127.0.0.2,false\n\
+             10.100.220.0/24,127.0.0.3,64514,,127.0.0.3,true\n"
+        );
+        assert_eq!(
+            server
+                .handle_command("show rib 10.100.220.1 --format json")
+                .await,
+            r#"[{"prefix":"10.100.220.0/24","next_hop":"127.0.0.2","as_path":"64513 64515","communities":[],"from":"127.0.0.2","best":false},{"prefix":"10.100.220.0/24","next_hop":"127.0.0.3","as_path":"64514","communities":[],"from":"127.0.0.3","best":true}]"#
+                .to_owned()
+                + "\n"
+        );
+        assert_eq!(
+            server.handle_command("show rib --format xml").await,
+            "--formatにはcsvかjsonを指定してください。\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn show_route_returns_best_path_of_longest_matching_prefix() {
+        let route = |network: &str, next_hop: &str| {
+            RibEntry::for_test(network).with_path_attributes(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::NextHop(next_hop.parse().unwrap()),
+            ])
+        };
+        let loc_rib = Arc::new(Mutex::new(LocRib::empty()));
+        loc_rib
+            .lock()
+            .await
+            .install_from_adj_rib_in(&mut AdjRibIn::from(vec![
+                route("10.100.0.0/16", "127.0.0.2"),
+                route("10.100.220.0/24", "127.0.0.3"),
+            ]));
+        let server = ControlServer::new(vec![], loc_rib);
+
+        assert_eq!(
+            server.handle_command("show route 10.100.220.1 --json").await,
+            r#"{"routes":[{"prefix":"10.100.220.0/24","paths":[{"best":true,"from":"local","received_at":null,"changed_at":null,"path_attributes":["origin=Igp","next-hop=127.0.0.3"]}]}]}"#
+                .to_owned()
+                + "\n"
+        );
+        assert!(server
+            .handle_command("show route 10.100.1.1")
+            .await
+            .contains("*> 10.100.0.0/16 "));
+        assert_eq!(
+            server.handle_command("show route 10.200.0.1").await,
+            "10.200.0.1を含むルートはありません。\n"
+        );
+        assert_eq!(
+            server.handle_command("show route 10.100.220.0/24").await,
+            "`10.100.220.0/24`をIPv4アドレスとして読み取れませんでした。\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn show_memory_reports_entries_of_each_structure() {
+        let route = |network: &str| RibEntry::for_test(network).with_next_hop("127.0.0.2");
+        let statistics = Arc::new(Mutex::new(PeerStatistics::new()));
+        statistics.lock().await.queued_events = 4;
+        let adj_rib_in = AdjRibIn::from(vec![route("10.100.220.0/24"), route("10.100.230.0/24")]);
+        let loc_rib = Arc::new(Mutex::new(LocRib::empty()));
+        loc_rib
+            .lock()
+            .await
+            .install_from_adj_rib_in(&mut adj_rib_in.clone());
+        let server = ControlServer::new(
+            vec![Neighbor {
+                config: "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap(),
+                statistics,
+                adj_rib_in: Arc::new(Mutex::new(adj_rib_in)),
+                message_capture: Default::default(),
+                handle: PeerHandle::detached(),
+            }],
+            loc_rib,
+        );
+
+        let response = server.handle_command("show memory").await;
+        let entries = |name: &str| {
+            response
+                .lines()
+                .find(|line| line.starts_with(name))
+                .and_then(|line| line[32..].split_whitespace().next())
+                .map(|entries| entries.parse::<usize>().unwrap())
+        };
+        assert_eq!(entries("loc-rib"), Some(2));
+        assert_eq!(entries("path-attributes"), Some(1));
+        assert_eq!(entries("adj-rib-in 127.0.0.2"), Some(2));
+        assert_eq!(entries("event-queue 127.0.0.2"), Some(4));
+        assert!(response.lines().last().unwrap().starts_with("Total"));
+    }
+
+    #[tokio::test]
+    async fn maintenance_command_depreferences_advertisements() {
+        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let loc_rib = Arc::new(Mutex::new(LocRib::empty()));
+        let server = ControlServer::new(
+            vec![Neighbor {
+                config,
+                statistics: Arc::new(Mutex::new(PeerStatistics::new())),
+                adj_rib_in: Arc::new(Mutex::new(AdjRibIn::new())),
+                message_capture: Default::default(),
+                handle: PeerHandle::detached(),
+            }],
+            Arc::clone(&loc_rib),
+        );
+
+        assert_eq!(
+            server.handle_command("maintenance on").await,
+            "maintenance on\n"
+        );
+        assert!(loc_rib.lock().await.in_maintenance());
+        assert_eq!(
+            server.handle_command("maintenance off").await,
+            "maintenance off\n"
+        );
+        assert!(!loc_rib.lock().await.in_maintenance());
+    }
+}
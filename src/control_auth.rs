@@ -0,0 +1,240 @@
+use anyhow::{Context, Result};
+use hmac_sha256::Hash;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+/// 認証する前のクライアントから、リクエストを受け取り終えるまで待つ最大の時間。
+pub(crate) const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// コントロールAPIやHTTPのAPIで、クライアントから受け付ける1行の最大のバイト数。
+const MAXIMUM_LINE_LENGTH: u64 = 8192;
+
+/// HTTPのAPIで受け付けるヘッダの最大の数と、ヘッダ全体の最大のバイト数。
+const MAXIMUM_HEADER_COUNT: usize = 64;
+const MAXIMUM_HEADER_LENGTH: usize = 16 * 1024;
+
+/// readerから改行までの1行を読み込む。接続が閉じられた場合は空の文字列を返す。
+/// MAXIMUM_LINE_LENGTHまで読んでも改行がなければエラーにし、認証する前のクライアントに
+/// 際限なくメモリを使わせない。
+pub(crate) async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<String> {
+    let mut line = String::new();
+    let length = reader
+        .take(MAXIMUM_LINE_LENGTH)
+        .read_line(&mut line)
+        .await?;
+    if length as u64 == MAXIMUM_LINE_LENGTH && !line.ends_with('\n') {
+        return Err(anyhow::anyhow!("line is too long"));
+    }
+    Ok(line)
+}
+
+/// readerから空行までのHTTPのヘッダを読み込む。接続が閉じられた場合はそこまでのヘッダを返す。
+/// ヘッダの数か全体のバイト数が上限を超えた場合はエラーにする。
+pub(crate) async fn read_headers<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Vec<String>> {
+    let mut headers = vec![];
+    let mut length = 0;
+    loop {
+        let header = read_line(reader).await?;
+        if header.trim_end().is_empty() {
+            return Ok(headers);
+        }
+        length += header.len();
+        if headers.len() >= MAXIMUM_HEADER_COUNT || length > MAXIMUM_HEADER_LENGTH {
+            return Err(anyhow::anyhow!("headers are too large"));
+        }
+        headers.push(header);
+    }
+}
+
+/// トークンに許可するコントロールAPIの操作。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum Role {
+    /// `show`や`monitor`のように、状態を参照するコマンドだけを実行できる。
+    ReadOnly,
+    /// `apply-config`や`neighbor disable`のように、状態を変えるコマンドも実行できる。
+    ReadWrite,
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "read-only" => Ok(Role::ReadOnly),
+            "read-write" => Ok(Role::ReadWrite),
+            _ => Err(anyhow::anyhow!(
+                "`{}`はread-onlyかread-writeである必要があります。",
+                s
+            )),
+        }
+    }
+}
+
+/// コントロールAPIのクライアントを認証するトークンと、そのRole。
+/// 設定した場合、クライアントはコマンドの前に`auth <token>`を付けて送る必要がある。
+/// トークンはSHA-256のダイジェストで持ち、応答時間から一致した部分を推測させないように、
+/// すべてのトークンと定数時間で比べる。
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct ControlTokens(Vec<([u8; 32], Role)>);
+
+impl ControlTokens {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn insert(&mut self, token: String, role: Role) {
+        let digest = Hash::hash(token.as_bytes());
+        self.0.retain(|(known, _)| *known != digest);
+        self.0.push((digest, role));
+    }
+
+    /// 1行に`<token> <read-only|read-write>`を書いたファイルを読み込む。
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path).context(format!(
+            "コントロールAPIのトークンのファイル{:?}を読み込めませんでした。",
+            path
+        ))?;
+        contents.parse()
+    }
+
+    /// tokenのRole。知らないトークンの場合はNoneを返す。
+    pub fn role(&self, token: &str) -> Option<Role> {
+        let digest = Hash::hash(token.as_bytes());
+        let mut found = None;
+        for (known, role) in &self.0 {
+            if constant_time_eq(known, &digest) {
+                found = Some(*role);
+            }
+        }
+        found
+    }
+
+    /// コマンドの行から`auth <token>`を取り除き、トークンのRoleと残りのコマンドを返す。
+    /// トークンがないか、知らないトークンの場合はNoneを返す。
+    pub fn authenticate<'a>(&self, line: &'a str) -> Option<(Role, &'a str)> {
+        let rest = line.strip_prefix("auth ")?.trim_start();
+        let (token, command) = rest.split_once(' ').unwrap_or((rest, ""));
+        let role = self.role(token)?;
+        Some((role, command.trim_start()))
+    }
+}
+
+/// aとbが等しいかを、異なるバイトの位置によらない時間で比べる。
+fn constant_time_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    let difference = a
+        .iter()
+        .zip(b)
+        .fold(0, |difference, (a, b)| difference | (a ^ b));
+    std::hint::black_box(difference) == 0
+}
+
+impl FromStr for ControlTokens {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut tokens = ControlTokens::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                [] => continue,
+                [token, role] => {
+                    let role = role.parse().context(format!("{}行目のRole", i + 1))?;
+                    tokens.insert(token.to_owned(), role);
+                }
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "{}行目は`<token> <read-only|read-write>`の形式である必要があります。",
+                        i + 1
+                    ))
+                }
+            }
+        }
+        Ok(tokens)
+    }
+}
+
+/// commandが状態を参照するだけのコマンドであり、Role::ReadOnlyでも実行できるか。
+/// ここにないコマンドは、後から追加したコマンドも含めてRole::ReadWriteが必要である。
+pub fn is_read_only(command: &[&str]) -> bool {
+    matches!(
+        command,
+        ["show", ..] | ["monitor", ..] | ["maintenance"] | ["diff-rib", _]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_file_assigns_role_to_each_token() {
+        let tokens: ControlTokens = "# 監視用\n\
+                                     s3cr3t read-only\n\
+                                     adm1n read-write # 運用者\n"
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            tokens.authenticate("auth s3cr3t show neighbors"),
+            Some((Role::ReadOnly, "show neighbors"))
+        );
+        assert_eq!(
+            tokens.authenticate("auth adm1n maintenance on"),
+            Some((Role::ReadWrite, "maintenance on"))
+        );
+        assert_eq!(tokens.authenticate("auth unknown show neighbors"), None);
+        assert_eq!(tokens.authenticate("show neighbors"), None);
+        assert!("s3cr3t admin".parse::<ControlTokens>().is_err());
+    }
+
+    #[test]
+    fn only_listed_commands_are_read_only() {
+        assert!(is_read_only(&["maintenance"]));
+        assert!(is_read_only(&["show", "rib"]));
+        assert!(is_read_only(&["monitor"]));
+        assert!(is_read_only(&["diff-rib", "rib.mrt"]));
+        assert!(!is_read_only(&["apply-config", "/etc/howbgp.conf"]));
+        assert!(!is_read_only(&["maintenance", "on"]));
+        assert!(!is_read_only(&["neighbor", "127.0.0.2", "disable"]));
+        assert!(!is_read_only(&["unknown"]));
+        assert!(!is_read_only(&[]));
+    }
+
+    #[tokio::test]
+    async fn headers_are_read_up_to_limits() {
+        let request = b"Authorization: Bearer s3cr3t\r\nHost: localhost\r\n\r\nbody";
+        let mut reader = tokio::io::BufReader::new(&request[..]);
+        assert_eq!(read_headers(&mut reader).await.unwrap().len(), 2);
+
+        let many = "X-Padding: a\r\n".repeat(MAXIMUM_HEADER_COUNT + 1);
+        assert!(
+            read_headers(&mut tokio::io::BufReader::new(many.as_bytes()))
+                .await
+                .is_err()
+        );
+        let long = format!("X-Padding: {}\r\n", "a".repeat(4096)).repeat(5);
+        assert!(
+            read_headers(&mut tokio::io::BufReader::new(long.as_bytes()))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn line_longer_than_limit_is_rejected() {
+        let mut reader = tokio::io::BufReader::new(&b"auth s3cr3t show neighbors\n"[..]);
+        assert_eq!(
+            read_line(&mut reader).await.unwrap(),
+            "auth s3cr3t show neighbors\n"
+        );
+        assert_eq!(read_line(&mut reader).await.unwrap(), "");
+
+        let long = vec![b'a'; MAXIMUM_LINE_LENGTH as usize * 2];
+        assert!(read_line(&mut tokio::io::BufReader::new(&long[..]))
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use crate::error::ConfigParseError;
+use crate::log_debug;
+use crate::packets::message::Message;
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// 送受信したメッセージの向き。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum Direction {
+    Send,
+    Receive,
+}
+
+/// ピアごとに有効にするデバッグログの種類。
+/// 問題のあるピアだけ詳細なログを出力し、他のピアのログに埋もれないようにする。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord, Default)]
+pub struct DebugFlags {
+    /// 状態遷移をログに出力する。
+    pub fsm: bool,
+    pub opens: bool,
+    pub updates: bool,
+    pub keepalives: bool,
+    pub notifications: bool,
+}
+
+impl DebugFlags {
+    /// すべての種類のメッセージをログに出力する。
+    pub fn all_messages() -> Self {
+        Self {
+            fsm: false,
+            opens: true,
+            updates: true,
+            keepalives: true,
+            notifications: true,
+        }
+    }
+
+    pub fn logs_any_message(&self) -> bool {
+        self.opens || self.updates || self.keepalives || self.notifications
+    }
+
+    /// messageをログに出力するか。
+    /// デコードできなかったメッセージ(None)は、いずれかの種類のメッセージを出力する場合に出力する。
+    pub fn logs_message(&self, message: Option<&Message>) -> bool {
+        match message {
+            Some(Message::Open(_)) => self.opens,
+            Some(Message::Update(_)) => self.updates,
+            Some(Message::Keepalive(_)) => self.keepalives,
+            Some(Message::Notification(_)) => self.notifications,
+            None => self.logs_any_message(),
+        }
+    }
+}
+
+impl FromStr for DebugFlags {
+    type Err = ConfigParseError;
+
+    /// `fsm,updates`のように、カンマ区切りで有効にする種類を並べた文字列をparseする。
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut flags = DebugFlags::default();
+        for flag in s.split(',') {
+            match flag {
+                "fsm" => flags.fsm = true,
+                "opens" => flags.opens = true,
+                "updates" => flags.updates = true,
+                "keepalives" => flags.keepalives = true,
+                "notifications" => flags.notifications = true,
+                "messages" => {
+                    flags = DebugFlags {
+                        fsm: flags.fsm,
+                        ..DebugFlags::all_messages()
+                    }
+                }
+                _ => {
+                    return Err(ConfigParseError::InvalidValue {
+                        kind: "debug flag",
+                        value: flag.to_owned(),
+                    })
+                }
+            }
+        }
+        Ok(flags)
+    }
+}
+
+/// bytesを1行16 byteずつ、`オフセット: 16進数表現`の形式の文字列にする。
+pub fn hexdump(bytes: &[u8]) -> String {
+    let mut dump = String::new();
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        writeln!(dump, "{:04x}: {}", i * 16, hex.join(" ")).unwrap();
+    }
+    dump
+}
+
+/// 送受信したメッセージを、hexdumpとデコードした構造の両方でログに出力する。
+/// messageはbytesのデコードに失敗した場合はNoneとする。
+pub fn log_message(direction: Direction, peer_name: &str, bytes: &[u8], message: Option<&Message>) {
+    let arrow = match direction {
+        Direction::Send => "->",
+        Direction::Receive => "<-",
+    };
+    let decoded = match message {
+        Some(message) => format!("{:#?}", message),
+        None => "メッセージとしてデコードできませんでした。".to_owned(),
+    };
+    log_debug!(
+        "[{arrow} {peer_name}] {} bytes\n{}{}",
+        bytes.len(),
+        hexdump(bytes),
+        decoded
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_flags_select_message_types() {
+        let flags: DebugFlags = "fsm,updates".parse().unwrap();
+
+        assert!(flags.fsm);
+        assert!(!flags.logs_message(Some(&Message::new_keepalive())));
+        assert!(flags.logs_message(None));
+        assert!("fsm,routes".parse::<DebugFlags>().is_err());
+    }
+
+    #[test]
+    fn hexdump_splits_bytes_every_16_bytes() {
+        let bytes: Vec<u8> = (0..18).collect();
+        let expected = "0000: 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f\n\
+                        0010: 10 11\n";
+        assert_eq!(hexdump(&bytes), expected);
+    }
+}
@@ -0,0 +1,75 @@
+use crate::peer_manager::PeerManager;
+use crate::{log_info, log_warning};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// `discovery-interval`を指定しない場合に、テンプレートのホスト名を名前解決し直す間隔(秒)。
+pub const DEFAULT_DISCOVERY_INTERVAL: u64 = 30;
+
+/// hostを名前解決した、すべてのIPv4アドレス。
+/// Kubernetesのheadless Serviceのように、1つの名前が増減する複数のアドレスを返す場合に使う。
+pub async fn resolve_all(host: &str) -> Result<Vec<Ipv4Addr>> {
+    // ポート番号は名前解決の結果に関係しない。
+    let mut addresses: Vec<Ipv4Addr> = tokio::net::lookup_host((host, 0))
+        .await
+        .context(format!("{}を名前解決できませんでした。", host))?
+        .filter_map(|address| match address.ip() {
+            IpAddr::V4(address) => Some(address),
+            IpAddr::V6(_) => None,
+        })
+        .collect();
+    addresses.sort();
+    addresses.dedup();
+    Ok(addresses)
+}
+
+/// peer_managerのテンプレートのホスト名を定期的に名前解決し、結果に合わせてピアを作成、削除する。
+/// 名前解決に失敗したホスト名は、一時的な障害でピアを削除しないように前回の結果を使い続ける。
+pub async fn run(peer_manager: Arc<Mutex<PeerManager>>) {
+    loop {
+        let (hosts, interval, previous) = {
+            let peer_manager = peer_manager.lock().await;
+            (
+                peer_manager.discovery_hosts(),
+                peer_manager.discovery_interval(),
+                peer_manager.discovered().clone(),
+            )
+        };
+        let mut discovered = HashMap::new();
+        for host in hosts {
+            match resolve_all(&host).await {
+                Ok(addresses) => {
+                    discovered.insert(host, addresses);
+                }
+                Err(e) => {
+                    log_warning!("{:?}", e);
+                    if let Some(addresses) = previous.get(&host) {
+                        discovered.insert(host, addresses.clone());
+                    }
+                }
+            }
+        }
+        let diff = peer_manager.lock().await.apply_discovered(discovered).await;
+        if diff != Default::default() {
+            log_info!("名前解決の結果に合わせてピアを変更しました。\n{}", diff);
+        }
+        // apply-configでテンプレートが追加される場合に備えて、テンプレートがなくても確認を続ける。
+        let interval = interval.unwrap_or_else(|| Duration::from_secs(DEFAULT_DISCOVERY_INTERVAL));
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn resolve_all_returns_ipv4_addresses_only() {
+        let addresses = resolve_all("localhost").await.unwrap();
+        assert!(addresses.contains(&Ipv4Addr::LOCALHOST));
+    }
+}
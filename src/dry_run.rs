@@ -0,0 +1,93 @@
+use crate::config::Config;
+use crate::fib::MockFib;
+use crate::monitor::format_path_attribute;
+use crate::replay;
+use crate::routing::{AdjRibOut, LocRib};
+use crate::update_group::NegotiatedCapabilities;
+use anyhow::Result;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// `--dry-run`で計算した、各ピアへ広告するルート。
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DryRunReport {
+    pub loc_rib_routes: usize,
+    /// 設定の順の、ピアとそのAdjRibOut。
+    pub neighbors: Vec<(Config, AdjRibOut)>,
+}
+
+impl fmt::Display for DryRunReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "loc-rib routes: {}", self.loc_rib_routes)?;
+        for (config, adj_rib_out) in &self.neighbors {
+            write!(
+                f,
+                "\nneighbor {}: {} routes",
+                config.remote_ip,
+                adj_rib_out.len()
+            )?;
+            for route in adj_rib_out.iter() {
+                write!(f, "\n  {}", *route.network_address)?;
+                for path_attribute in route.path_attributes.iter() {
+                    write!(f, " {}", format_path_attribute(path_attribute))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// TCPの接続もカーネルのルーティングテーブルの操作もせずに、configsの各ピアへ
+/// export policyを適用した後に広告するルートを計算する。
+/// LocRibには、設定で広告するネットワークがカーネルのルーティングテーブルにあるものとして入れる。
+/// mrtを指定した場合は、さらにMRTファイルのUpdateMessageのルートを入れる。
+pub async fn dry_run(configs: &[Config], mrt: Option<&Path>) -> Result<DryRunReport> {
+    let mut loc_rib = match configs.first() {
+        Some(config) => {
+            let fib = Arc::new(MockFib::new(config.networks.clone()));
+            LocRib::with_fib(config, fib).await?
+        }
+        None => LocRib::empty(),
+    };
+    if let Some(path) = mrt {
+        replay::install_into(path, 0.0, &mut loc_rib).await?;
+    }
+
+    let neighbors = configs
+        .iter()
+        .map(|config| {
+            let mut adj_rib_out = AdjRibOut::new();
+            adj_rib_out.install_from_loc_rib(&loc_rib, config, NegotiatedCapabilities::default());
+            (config.clone(), adj_rib_out)
+        })
+        .collect();
+    Ok(DryRunReport {
+        loc_rib_routes: loc_rib.len(),
+        neighbors,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn routes_are_computed_per_neighbor_after_export_policy() {
+        let configs = Config::parse_lines(
+            "64512 127.0.0.1 64513 127.0.0.2 active 10.100.220.0/24 10.100.230.0/24\n\
+             64512 127.0.0.1 64514 127.0.0.3 active 10.100.220.0/24 10.100.230.0/24 \
+             export deny as-path-length 0",
+        )
+        .unwrap();
+
+        let report = dry_run(&configs, None).await.unwrap();
+
+        assert_eq!(report.loc_rib_routes, 2);
+        assert_eq!(report.neighbors[0].1.len(), 2);
+        assert_eq!(report.neighbors[1].1.len(), 0);
+        let output = report.to_string();
+        assert!(output.contains("neighbor 127.0.0.3: 0 routes"));
+        assert!(output.contains("  10.100.220.0/24 origin=Igp as-path=[64512] next-hop=127.0.0.1"));
+    }
+}
@@ -1,29 +1,290 @@
+use crate::packets::header::MessageType;
+use crate::packets::notification::{
+    finite_state_machine_error, message_header_error, open_message_error, update_message_error,
+    ErrorCode, NotificationMessage,
+};
+use crate::state::State;
+use bytes::BytesMut;
 use thiserror::Error;
 
+/// 設定を読み取れなかった理由。
 #[derive(Error, Debug)]
-#[error(transparent)]
-pub struct ConfigParseError {
-    #[from]
-    source: anyhow::Error,
+pub enum ConfigParseError {
+    /// kind(`peer role`や`debug flag`など)として受け付けない値が指定された。
+    #[error("cannot parse {kind} `{value}`")]
+    InvalidValue { kind: &'static str, value: String },
+    /// オプションのキーワードの後に値が指定されていない。
+    #[error("`{key}`の後に値が指定されていません。")]
+    MissingValue { key: String },
+    /// オプションの値を、そのオプションの型として読み取れなかった。
+    #[error("cannot parse value of `{key}`, `{value}`")]
+    InvalidOptionValue {
+        key: String,
+        value: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// オプションの値が、受け付ける範囲の外だった。expectedは`1以上`のような受け付ける範囲。
+    #[error("`{key}`は{expected}である必要があります。")]
+    OutOfRange { key: &'static str, expected: String },
+    /// `"`で始まる値の`"`が閉じられていない。
+    #[error("`{key}`の値の`\"`が閉じられていません。")]
+    UnterminatedQuote { key: String },
+    /// optionを使うのに必要なfeatureを有効にせずにビルドされている。
+    #[error("{option}を使うには、{feature} featureを有効にしてビルドしてください。")]
+    FeatureDisabled {
+        option: &'static str,
+        feature: &'static str,
+    },
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
 }
 
+/// bytes列をBGPのメッセージやその構成要素に変換できなかった理由。
 #[derive(Error, Debug)]
-#[error(transparent)]
-pub struct ConvertBytesToBgpMessageError {
-    #[from]
-    source: anyhow::Error,
+pub enum ConvertBytesToBgpMessageError {
+    /// 受信したメッセージが不正であり、NotificationMessageで対向機器に通知すべきエラー。
+    #[error(transparent)]
+    Notification(#[from] NotificationError),
+    /// bytes列のMessage Typeが、変換しようとしたMessageのものではない。
+    #[error("bytes列のtypeが{expected:?}ではありません。(type: {actual:?})")]
+    UnexpectedMessageType {
+        expected: MessageType,
+        actual: MessageType,
+    },
+    /// fieldの途中でbytes列が終わっている。
+    #[error("{field}が途中で途切れています。")]
+    Truncated { field: &'static str },
+    /// fieldの長さが、そのfieldで受け付ける長さではない。
+    #[error("{field}の長さ{length}は不正です。")]
+    InvalidLength { field: &'static str, length: usize },
+    /// fieldの値が、そのfieldで受け付ける値ではない。
+    #[error("{field}の値{value}は不正です。")]
+    InvalidValue { field: &'static str, value: u32 },
+    /// BgpCodecでストリームから読み込めなかった。
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
 }
 
+impl ConvertBytesToBgpMessageError {
+    /// エラーの原因がNotificationMessageで対向機器に通知すべきものであれば、それを返す。
+    pub fn notification_error(&self) -> Option<&NotificationError> {
+        match self {
+            Self::Notification(e) => Some(e),
+            // contextを付けたエラーでは、原因の中からNotificationErrorを探す。
+            Self::Other(e) => e.chain().find_map(|e| {
+                e.downcast_ref::<NotificationError>().or_else(|| {
+                    e.downcast_ref::<ConvertBytesToBgpMessageError>()
+                        .and_then(|e| e.notification_error())
+                })
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// 受信したメッセージが不正であり、NotificationMessageで対向機器に通知すべきエラー。
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("{description} (error code: {error_code:?}, subcode: {error_subcode})")]
+pub struct NotificationError {
+    pub error_code: ErrorCode,
+    pub error_subcode: u8,
+    pub data: BytesMut,
+    description: String,
+}
+
+impl NotificationError {
+    pub fn new(
+        error_code: ErrorCode,
+        error_subcode: u8,
+        data: BytesMut,
+        description: impl Into<String>,
+    ) -> Self {
+        Self {
+            error_code,
+            error_subcode,
+            data,
+            description: description.into(),
+        }
+    }
+
+    pub fn to_notification(&self) -> NotificationMessage {
+        NotificationMessage::new(self.error_code, self.error_subcode, self.data.clone())
+    }
+}
+
+/// 検出したエラーの種類から、NotificationMessageのError Code, Error Subcode, Dataを決める。
+/// 同じ種類のエラーは、どこで検出しても同じNotificationMessageで通知されるように、
+/// NotificationErrorやNotificationMessageは原則としてこれを通して作成する。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotificationBuilder {
+    error_code: ErrorCode,
+    error_subcode: u8,
+    data: BytesMut,
+}
+
+impl NotificationBuilder {
+    pub fn new(error_code: ErrorCode, error_subcode: u8) -> Self {
+        Self {
+            error_code,
+            error_subcode,
+            data: BytesMut::new(),
+        }
+    }
+
+    pub fn data(mut self, data: &[u8]) -> Self {
+        self.data = BytesMut::from(data);
+        self
+    }
+
+    pub fn connection_not_synchronized() -> Self {
+        Self::new(
+            ErrorCode::MessageHeaderError,
+            message_header_error::CONNECTION_NOT_SYNCHRONIZED,
+        )
+    }
+
+    /// Dataには不正なLengthフィールドの値を入れる。
+    pub fn bad_message_length(length: u16) -> Self {
+        Self::new(
+            ErrorCode::MessageHeaderError,
+            message_header_error::BAD_MESSAGE_LENGTH,
+        )
+        .data(&length.to_be_bytes())
+    }
+
+    /// Dataには不正なTypeフィールドの値を入れる。
+    pub fn bad_message_type(type_: u8) -> Self {
+        Self::new(
+            ErrorCode::MessageHeaderError,
+            message_header_error::BAD_MESSAGE_TYPE,
+        )
+        .data(&[type_])
+    }
+
+    /// Dataには、対応しているVersionのうち最大のものを2 octetsで入れる。
+    pub fn unsupported_version_number(largest_supported_version: u8) -> Self {
+        Self::new(
+            ErrorCode::OpenMessageError,
+            open_message_error::UNSUPPORTED_VERSION_NUMBER,
+        )
+        .data(&(largest_supported_version as u16).to_be_bytes())
+    }
+
+    pub fn bad_peer_as() -> Self {
+        Self::new(ErrorCode::OpenMessageError, open_message_error::BAD_PEER_AS)
+    }
+
+    pub fn unacceptable_hold_time() -> Self {
+        Self::new(
+            ErrorCode::OpenMessageError,
+            open_message_error::UNACCEPTABLE_HOLD_TIME,
+        )
+    }
+
+    pub fn malformed_attribute_list() -> Self {
+        Self::new(
+            ErrorCode::UpdateMessageError,
+            update_message_error::MALFORMED_ATTRIBUTE_LIST,
+        )
+    }
+
+    /// Path Attributeの値が不正な場合のエラー。
+    /// Dataには不正なPath Attribute全体(Flag, Type Code, Length, Value)を入れる。
+    pub fn attribute_error(subcode: u8, attribute: &[u8]) -> Self {
+        Self::new(ErrorCode::UpdateMessageError, subcode).data(attribute)
+    }
+
+    pub fn invalid_network_field() -> Self {
+        Self::new(
+            ErrorCode::UpdateMessageError,
+            update_message_error::INVALID_NETWORK_FIELD,
+        )
+    }
+
+    pub fn hold_timer_expired() -> Self {
+        Self::new(ErrorCode::HoldTimerExpired, 0)
+    }
+
+    /// stateで受信することを想定していないメッセージを受信した。
+    /// Error SubcodeはRFC 6608に従い、受信したときのstateで決める。
+    /// Dataには受信したメッセージのTypeを入れる。
+    pub fn unexpected_message(state: State, type_: MessageType) -> Self {
+        let subcode = match state {
+            State::OpenSent => {
+                finite_state_machine_error::RECEIVE_UNEXPECTED_MESSAGE_IN_OPEN_SENT_STATE
+            }
+            State::OpenConfirm => {
+                finite_state_machine_error::RECEIVE_UNEXPECTED_MESSAGE_IN_OPEN_CONFIRM_STATE
+            }
+            State::Established => {
+                finite_state_machine_error::RECEIVE_UNEXPECTED_MESSAGE_IN_ESTABLISHED_STATE
+            }
+            _ => finite_state_machine_error::UNSPECIFIED_ERROR,
+        };
+        Self::new(ErrorCode::FiniteStateMachineError, subcode).data(&[type_.into()])
+    }
+
+    /// descriptionをエラーの説明として持つNotificationErrorを作成する。
+    pub fn build(self, description: impl Into<String>) -> NotificationError {
+        NotificationError::new(self.error_code, self.error_subcode, self.data, description)
+    }
+
+    pub fn to_notification(&self) -> NotificationMessage {
+        NotificationMessage::new(self.error_code, self.error_subcode, self.data.clone())
+    }
+}
+
+/// BGPのメッセージをbytes列に変換して送信できなかった理由。
+#[derive(Error, Debug)]
+pub enum ConvertBgpMessageToBytesError {
+    /// メッセージの長さが最大長を超えている。
+    #[error("メッセージの長さ{length}が最大長を超えています。")]
+    TooLong { length: usize },
+    /// BgpCodecでストリームに書き込めなかった。
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// UpdateMessageBuilderで組み立てたUpdateMessageが、送信できない内容だった理由。
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum BuildUpdateMessageError {
+    /// NLRIを広告するのに必須のPath Attributeがない。
+    #[error("NLRIを広告するUpdateMessageには{0}が必要です。")]
+    MissingPathAttribute(&'static str),
+    #[error("NLRIのないUpdateMessageにはPath Attributeを含められません。")]
+    PathAttributesWithoutNlri,
+    /// メッセージの長さが最大長を超えている。
+    #[error("UpdateMessageの長さ{length}がメッセージの最大長を超えています。")]
+    TooLong { length: usize },
+}
+
+/// ピアとの間にConnectionを張れなかった理由。
 #[derive(Error, Debug)]
-#[error(transparent)]
-pub struct ConvertBgpMessageToBytesError {
-    #[from]
-    source: anyhow::Error,
+pub enum CreateConnectionError {
+    /// Activeモードで、ピアに接続できなかった。
+    #[error("ピアに接続できませんでした。")]
+    Connect(#[source] anyhow::Error),
+    /// Passiveモードで、ピアからの接続を受け付けられなかった。
+    #[error("ピアからの接続を受け付けられませんでした。")]
+    Accept(#[source] anyhow::Error),
 }
 
+/// MRTファイルを読み取れなかった理由。
 #[derive(Error, Debug)]
-#[error(transparent)]
-pub struct CreateConnectionError {
-    #[from]
-    source: anyhow::Error,
+pub enum MrtParseError {
+    /// recordの種類のレコードが途中で途切れている。
+    #[error("{record}レコードが途中で途切れています。")]
+    Truncated { record: &'static str },
+    #[error("BGP4MPレコードのAddress Family {0}には対応していません。")]
+    UnsupportedAddressFamily(u16),
+    /// レコードのLengthが、読み込む最大の長さを超えている。
+    #[error("MRTレコードの長さ{0}は長すぎます。")]
+    TooLong(u32),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
 }
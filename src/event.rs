@@ -1,13 +1,98 @@
-use crate::packets::{keepalive::KeepaliveMessage, open::OpenMessage, update::UpdateMessage};
+use crate::packets::{
+    keepalive::KeepaliveMessage, notification::NotificationMessage, open::OpenMessage,
+    update::UpdateMessage,
+};
 
 #[derive(PartialEq, Eq, Debug, Clone, Hash)]
 pub enum Event {
     ManualStart,
+    /// 管理者が停止したのではなくセッションが切れた後に、ConnectRetryTimerが期限切れになった。
+    /// ManualStartと同じように、TCP Connectionの確立を始める。
+    AutomaticStart,
+    /// 管理者がセッションを停止した。対向機器に送るShutdown Communicationを持つ。
+    ManualStop(Option<String>),
     TcpConnectionConfirmed,
+    /// 対向機器がTCP Connectionを閉じたか、TCP Connectionでエラーが発生した。
+    TcpConnectionFails,
     BgpOpen(OpenMessage),
     KeepAliveMsg(KeepaliveMessage),
     UpdateMsg(UpdateMessage),
+    NotifMsg(NotificationMessage),
+    /// 受信したメッセージのHeaderが不正だった。送信するNotificationMessageを持つ。
+    BgpHeaderErr(NotificationMessage),
+    /// 受信したOpenMessageが不正だった。送信するNotificationMessageを持つ。
+    BgpOpenMsgErr(NotificationMessage),
+    /// 受信したUpdateMessageが不正だった。送信するNotificationMessageを持つ。
+    UpdateMsgErr(NotificationMessage),
     Established,
+    AdjRibInChanged,
     LocRibChanged,
     AdjRibOutChanged,
+    /// MRAIタイマーが期限切れになった。
+    MraiTimerExpires,
+    /// Hold Timerが期限切れになった。対向機器から一定時間メッセージを受信していない。
+    HoldTimerExpires,
+    /// Keepalive Timerが期限切れになった。KeepaliveMessageを送信する。
+    KeepaliveTimerExpires,
+    /// LLGRでstaleとして保持していたルートの保持期間が終わった。
+    LlgrStaleTimerExpires,
+    /// ConnectRetryTimerが期限切れになった。TCP Connectionの確立をやり直す。
+    ConnectRetryTimerExpires,
+}
+
+impl Event {
+    /// ログや状態遷移の履歴に記録するためのEventの名前。
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::ManualStart => "ManualStart",
+            Event::AutomaticStart => "AutomaticStart",
+            Event::ManualStop(_) => "ManualStop",
+            Event::TcpConnectionConfirmed => "TcpConnectionConfirmed",
+            Event::TcpConnectionFails => "TcpConnectionFails",
+            Event::BgpOpen(_) => "BgpOpen",
+            Event::KeepAliveMsg(_) => "KeepAliveMsg",
+            Event::UpdateMsg(_) => "UpdateMsg",
+            Event::NotifMsg(_) => "NotifMsg",
+            Event::BgpHeaderErr(_) => "BgpHeaderErr",
+            Event::BgpOpenMsgErr(_) => "BgpOpenMsgErr",
+            Event::UpdateMsgErr(_) => "UpdateMsgErr",
+            Event::Established => "Established",
+            Event::AdjRibInChanged => "AdjRibInChanged",
+            Event::LocRibChanged => "LocRibChanged",
+            Event::AdjRibOutChanged => "AdjRibOutChanged",
+            Event::MraiTimerExpires => "MraiTimerExpires",
+            Event::HoldTimerExpires => "HoldTimerExpires",
+            Event::KeepaliveTimerExpires => "KeepaliveTimerExpires",
+            Event::LlgrStaleTimerExpires => "LlgrStaleTimerExpires",
+            Event::ConnectRetryTimerExpires => "ConnectRetryTimerExpires",
+        }
+    }
+
+    /// ルートの受信・計算・広告のための、大量に発生しうるEventであるか。
+    /// これらはEventQueueで他のEventより後回しにされる。
+    pub fn is_bulk(&self) -> bool {
+        matches!(
+            self,
+            Event::UpdateMsg(_)
+                | Event::AdjRibInChanged
+                | Event::LocRibChanged
+                | Event::AdjRibOutChanged
+        )
+    }
+
+    /// セッションに使っているTCP Connectionから受信したメッセージや、その切断によるEventであるか。
+    /// Connection Collisionで別のTCP Connectionに切り替えた場合は、これらを破棄する。
+    pub fn is_from_connection(&self) -> bool {
+        matches!(
+            self,
+            Event::TcpConnectionFails
+                | Event::BgpOpen(_)
+                | Event::KeepAliveMsg(_)
+                | Event::UpdateMsg(_)
+                | Event::NotifMsg(_)
+                | Event::BgpHeaderErr(_)
+                | Event::BgpOpenMsgErr(_)
+                | Event::UpdateMsgErr(_)
+        )
+    }
 }
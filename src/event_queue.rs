@@ -1,19 +1,91 @@
 use crate::event::Event;
 use std::collections::VecDeque;
 
+/// 優先度の低いEventを溜めておける数の既定値。
+pub const DEFAULT_EVENT_QUEUE_CAPACITY: usize = 1024;
+
+/// 管理操作・タイマー・セッション制御のEventを、ルートの処理のEventより先に取り出すキュー。
+/// 大量のUpdateMessageを受信しても、KeepaliveMessageの処理などが後回しにならないようにする。
 #[derive(PartialEq, Eq, Debug, Clone, Hash)]
-pub struct EventQueue(VecDeque<Event>);
+pub struct EventQueue {
+    high: VecDeque<Event>,
+    low: VecDeque<Event>,
+    capacity: usize,
+}
 
 impl EventQueue {
     pub fn new() -> Self {
-        EventQueue(VecDeque::new())
+        Self::with_capacity(DEFAULT_EVENT_QUEUE_CAPACITY)
     }
 
+    pub fn with_capacity(capacity: usize) -> Self {
+        EventQueue {
+            high: VecDeque::new(),
+            low: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// 優先度の高いEventは容量に関係なく追加する。
+    /// 優先度の低いEventが容量を超えている間は、is_fullで呼び出し側に受信を止めてもらう。
     pub fn enqueue(&mut self, event: Event) {
-        self.0.push_front(event);
+        if event.is_bulk() {
+            self.low.push_back(event);
+        } else {
+            self.high.push_back(event);
+        }
     }
 
     pub fn dequeue(&mut self) -> Option<Event> {
-        self.0.pop_back()
+        self.high.pop_front().or_else(|| self.low.pop_front())
+    }
+
+    /// 優先度の低いEventが容量まで溜まっているか。
+    /// trueの間はTCP Connectionからの読み込みを止めて、対向機器に送信を待ってもらう。
+    pub fn is_full(&self) -> bool {
+        self.low.len() >= self.capacity
+    }
+
+    /// fがtrueを返すEventだけを、順番を変えずに残す。
+    pub fn retain(&mut self, mut f: impl FnMut(&Event) -> bool) {
+        self.high.retain(&mut f);
+        self.low.retain(&mut f);
+    }
+
+    pub fn len(&self) -> usize {
+        self.high.len() + self.low.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn high_priority_events_are_dequeued_first() {
+        let mut queue = EventQueue::new();
+        queue.enqueue(Event::AdjRibInChanged);
+        queue.enqueue(Event::LocRibChanged);
+        queue.enqueue(Event::MraiTimerExpires);
+
+        assert_eq!(queue.dequeue(), Some(Event::MraiTimerExpires));
+        assert_eq!(queue.dequeue(), Some(Event::AdjRibInChanged));
+        assert_eq!(queue.dequeue(), Some(Event::LocRibChanged));
+        assert_eq!(queue.dequeue(), None);
+    }
+
+    #[test]
+    fn queue_is_full_only_by_bulk_events() {
+        let mut queue = EventQueue::with_capacity(2);
+        queue.enqueue(Event::AdjRibInChanged);
+        queue.enqueue(Event::ManualStart);
+        assert!(!queue.is_full());
+
+        queue.enqueue(Event::AdjRibInChanged);
+        assert!(queue.is_full());
+        queue.dequeue();
+        assert!(queue.is_full());
+        queue.dequeue();
+        assert!(!queue.is_full());
     }
 }
@@ -0,0 +1,607 @@
+#[cfg(target_os = "linux")]
+mod netlink;
+
+use crate::config::Config;
+use crate::routing::{Ipv4Network, RouteTable};
+use anyhow::Result;
+use futures::future::BoxFuture;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+#[cfg(target_os = "linux")]
+pub use netlink::NetlinkFib;
+
+/// `set next-hop blackhole`で設定する、宛先のトラフィックを破棄するルートのNEXT_HOP。
+/// RTBHで広く使われているTEST-NET-1のアドレスで、受信側のルータはこのアドレスを破棄に向けておく。
+/// このNEXT_HOPのルートは、カーネルのルーティングテーブルにblackholeのルートとして書き込む。
+pub const BLACKHOLE_NEXT_HOP: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+/// カーネルのルーティングテーブルに書き込むルート。
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct FibRoute {
+    pub network_address: Ipv4Network,
+    pub next_hop: Ipv4Addr,
+}
+
+impl FibRoute {
+    /// 転送せずに破棄するルートか。
+    pub fn is_blackhole(&self) -> bool {
+        self.next_hop == BLACKHOLE_NEXT_HOP
+    }
+}
+
+/// Fibのinstallやremoveで、一部のネットワークのリクエストが失敗したことを表すエラー。
+/// Fibがこのエラー以外で失敗した場合は、すべてのネットワークのリクエストが失敗したとみなす。
+#[derive(Error, Debug)]
+#[error("{total}個のリクエストのうち{}個が失敗しました。", failed.len())]
+pub struct PartialFibError {
+    pub total: usize,
+    /// リクエストが失敗したネットワーク。
+    pub failed: BTreeSet<Ipv4Network>,
+    /// 最初に失敗したリクエストのエラー。
+    #[source]
+    pub source: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl PartialFibError {
+    /// errorのうち、PartialFibErrorで失敗を報告されたネットワーク。
+    /// PartialFibErrorでなければ、networksのすべてを返す。
+    fn failed_networks(
+        error: &anyhow::Error,
+        networks: impl Iterator<Item = Ipv4Network>,
+    ) -> BTreeSet<Ipv4Network> {
+        match error.downcast_ref::<PartialFibError>() {
+            Some(e) => e.failed.clone(),
+            None => networks.collect(),
+        }
+    }
+}
+
+/// カーネルのルーティングテーブル(FIB)に対する操作。
+/// Linuxではnetlinkで実装し、それ以外の環境やテストではカーネルに触れない実装を使う。
+pub trait Fib: fmt::Debug + Send + Sync {
+    /// tableのIPv4のルートのうち、networksと一致するルートを返す。
+    fn lookup_ipv4<'a>(
+        &'a self,
+        networks: &'a [Ipv4Network],
+        table: RouteTable,
+    ) -> BoxFuture<'a, Result<Vec<Ipv4Network>>>;
+
+    /// tableのIPv6のルートのうち、networksと一致するルートを返す。
+    fn lookup_ipv6<'a>(
+        &'a self,
+        networks: &'a [ipnetwork::Ipv6Network],
+        table: RouteTable,
+    ) -> BoxFuture<'a, Result<Vec<ipnetwork::Ipv6Network>>>;
+
+    /// configのテーブル、protocol、metricで書き込まれているIPv4のルートを返す。
+    /// 起動したときに、前回の起動で書き込んだまま残っているルートを見つけるために使う。
+    fn lookup_installed<'a>(&'a self, config: &'a Config) -> BoxFuture<'a, Result<Vec<FibRoute>>>;
+
+    /// configのテーブル、protocol、metricでroutesを書き込む。
+    /// 同じ宛先とmetricのルートがすでにあれば、configのprotocolで書き込まれたルートの場合だけ置き換える。
+    /// ほかのprotocolのルートは置き換えずに、そのルートの書き込みを失敗とする。
+    fn install<'a>(
+        &'a self,
+        routes: &'a [FibRoute],
+        config: &'a Config,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// このプロセスがconfigのprotocolで書き込んだルートのうち、networksのルートを削除する。
+    /// すでにルートがない場合は、削除できたものとする。
+    ///
+    /// installとremoveは、一部のネットワークだけが失敗した場合にPartialFibErrorを返す。
+    fn remove<'a>(
+        &'a self,
+        networks: &'a [Ipv4Network],
+        config: &'a Config,
+    ) -> BoxFuture<'a, Result<()>>;
+}
+
+/// LocRibに設定したFib。
+/// このプロセスが書き込んだルートをメモリに持ち、LocRibで変わったネットワークのルートだけを
+/// 書き込み済みのルートと比べて書き込む。カーネルのルーティングテーブルは読み直さないため、
+/// 同じprotocolでルートを書き込むほかのプロセスのルートには触れない。
+/// Fibはルーティングテーブルの内容ではないため、LocRibの比較では常に等しいとみなす。
+#[derive(Debug, Clone)]
+pub struct AttachedFib {
+    fib: Arc<dyn Fib>,
+    /// 書き込みを待っている、ネットワークごとのルートのNEXT_HOP。Noneのネットワークはルートを削除する。
+    staged: Arc<Mutex<BTreeMap<Ipv4Network, Option<Ipv4Addr>>>>,
+    /// このプロセスが書き込んだルート。書き込みを1つずつ行うためのロックを兼ねる。
+    installed: Arc<tokio::sync::Mutex<BTreeMap<Ipv4Network, Ipv4Addr>>>,
+}
+
+impl AttachedFib {
+    pub fn new(fib: Arc<dyn Fib>) -> Self {
+        Self {
+            fib,
+            staged: Default::default(),
+            installed: Default::default(),
+        }
+    }
+
+    /// configのprotocolとmetricで書き込まれているルートを、このプロセスが書き込んだルートとして扱う。
+    /// 引き継いだルートのネットワークを返す。
+    pub async fn adopt(&self, config: &Config) -> Result<Vec<Ipv4Network>> {
+        let mut installed = self.installed.lock().await;
+        let routes = self.fib.lookup_installed(config).await?;
+        for route in &routes {
+            installed.insert(route.network_address, route.next_hop);
+        }
+        Ok(routes.iter().map(|r| r.network_address).collect())
+    }
+
+    /// networkのルートをnext_hopで書き込むように予約する。next_hopがNoneならば削除するように予約する。
+    /// 書き込む前に同じネットワークを予約し直した場合は、後の予約で置き換える。
+    pub fn stage(&self, network: Ipv4Network, next_hop: Option<Ipv4Addr>) {
+        self.staged.lock().unwrap().insert(network, next_hop);
+    }
+
+    /// 予約したルートのうち、書き込み済みのルートと異なるものを書き込み、削除する。
+    /// 書き込みは1つずつ行い、その時点で最後に予約された内容を書き込むため、
+    /// LocRibのロックを外してから呼び出しても古いルートで上書きしない。
+    /// 書き込みや削除に失敗したルートは、次に呼び出したときに書き込み直すように予約し直す。
+    /// 一部のルートだけが失敗した場合も、成功したルートは書き込み済みとして扱う。
+    pub async fn flush(&self, config: &Config) -> Result<()> {
+        let mut installed = self.installed.lock().await;
+        let staged = std::mem::take(&mut *self.staged.lock().unwrap());
+        let mut routes = vec![];
+        let mut removed = vec![];
+        for (network_address, next_hop) in staged {
+            match (next_hop, installed.get(&network_address)) {
+                (Some(next_hop), Some(current)) if next_hop == *current => {}
+                (Some(next_hop), _) => routes.push(FibRoute {
+                    network_address,
+                    next_hop,
+                }),
+                (None, Some(_)) => removed.push(network_address),
+                (None, None) => {}
+            }
+        }
+        let remove_result = if removed.is_empty() {
+            Ok(())
+        } else {
+            self.fib.remove(&removed, config).await
+        };
+        let failed = match &remove_result {
+            Ok(()) => BTreeSet::new(),
+            Err(e) => PartialFibError::failed_networks(e, removed.iter().copied()),
+        };
+        for network_address in removed {
+            if failed.contains(&network_address) {
+                self.restage(network_address, None);
+            } else {
+                installed.remove(&network_address);
+            }
+        }
+        if routes.is_empty() {
+            return remove_result;
+        }
+        let install_result = self.fib.install(&routes, config).await;
+        let failed = match &install_result {
+            Ok(()) => BTreeSet::new(),
+            Err(e) => PartialFibError::failed_networks(e, routes.iter().map(|r| r.network_address)),
+        };
+        for route in routes {
+            if failed.contains(&route.network_address) {
+                self.restage(route.network_address, Some(route.next_hop));
+            } else {
+                installed.insert(route.network_address, route.next_hop);
+            }
+        }
+        remove_result.and(install_result)
+    }
+
+    /// 失敗したルートを、書き込みの間に新しく予約されていなければ予約し直す。
+    fn restage(&self, network: Ipv4Network, next_hop: Option<Ipv4Addr>) {
+        self.staged
+            .lock()
+            .unwrap()
+            .entry(network)
+            .or_insert(next_hop);
+    }
+}
+
+impl PartialEq for AttachedFib {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for AttachedFib {}
+
+/// 動作している環境のカーネルを操作するFib。
+#[cfg(target_os = "linux")]
+pub fn default_fib() -> Arc<dyn Fib> {
+    Arc::new(NetlinkFib::new())
+}
+
+/// Linux以外の環境では、カーネルのルーティングテーブルを操作しない。
+#[cfg(not(target_os = "linux"))]
+pub fn default_fib() -> Arc<dyn Fib> {
+    Arc::new(NoopFib)
+}
+
+/// 何もしないFib。ルートの検索では常に何も見つからない。
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopFib;
+
+impl Fib for NoopFib {
+    fn lookup_ipv4<'a>(
+        &'a self,
+        _: &'a [Ipv4Network],
+        _: RouteTable,
+    ) -> BoxFuture<'a, Result<Vec<Ipv4Network>>> {
+        Box::pin(async { Ok(vec![]) })
+    }
+
+    fn lookup_ipv6<'a>(
+        &'a self,
+        _: &'a [ipnetwork::Ipv6Network],
+        _: RouteTable,
+    ) -> BoxFuture<'a, Result<Vec<ipnetwork::Ipv6Network>>> {
+        Box::pin(async { Ok(vec![]) })
+    }
+
+    fn lookup_installed<'a>(&'a self, _: &'a Config) -> BoxFuture<'a, Result<Vec<FibRoute>>> {
+        Box::pin(async { Ok(vec![]) })
+    }
+
+    fn install<'a>(&'a self, _: &'a [FibRoute], _: &'a Config) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn remove<'a>(&'a self, _: &'a [Ipv4Network], _: &'a Config) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// メモリ上のルーティングテーブルを操作するFib。テストで書き込まれたルートを確認するのに使う。
+#[derive(Debug, Default)]
+pub struct MockFib {
+    /// 検索で見つかるネットワーク。
+    connected: Vec<Ipv4Network>,
+    installed: Mutex<BTreeMap<Ipv4Network, Ipv4Addr>>,
+    /// installで書き込まれたルートの延べ数。
+    writes: AtomicUsize,
+    /// installやremoveのリクエストを失敗させるネットワーク。
+    failing: Mutex<BTreeSet<Ipv4Network>>,
+}
+
+impl MockFib {
+    pub fn new(connected: Vec<Ipv4Network>) -> Self {
+        Self {
+            connected,
+            installed: Default::default(),
+            writes: Default::default(),
+            failing: Default::default(),
+        }
+    }
+
+    /// 以降のinstallとremoveで、networkのリクエストを失敗させる。failがfalseならば成功させる。
+    pub fn set_failing(&self, network: Ipv4Network, fail: bool) {
+        let mut failing = self.failing.lock().unwrap();
+        if fail {
+            failing.insert(network);
+        } else {
+            failing.remove(&network);
+        }
+    }
+
+    /// networksのうち、失敗させるネットワークがあればPartialFibErrorを返す。
+    fn check_failing(&self, networks: impl Iterator<Item = Ipv4Network>) -> Result<()> {
+        let failing = self.failing.lock().unwrap();
+        let networks: Vec<_> = networks.collect();
+        let failed: BTreeSet<_> = networks
+            .iter()
+            .filter(|n| failing.contains(n))
+            .copied()
+            .collect();
+        if failed.is_empty() {
+            return Ok(());
+        }
+        Err(PartialFibError {
+            total: networks.len(),
+            failed,
+            source: "failed".into(),
+        }
+        .into())
+    }
+
+    /// installで書き込まれたルートの延べ数を返す。
+    pub fn writes(&self) -> usize {
+        self.writes.load(Ordering::SeqCst)
+    }
+
+    /// 書き込まれたルートを、ネットワークアドレスの順に返す。
+    pub fn installed(&self) -> Vec<FibRoute> {
+        self.installed
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(network_address, next_hop)| FibRoute {
+                network_address: *network_address,
+                next_hop: *next_hop,
+            })
+            .collect()
+    }
+}
+
+impl Fib for MockFib {
+    fn lookup_ipv4<'a>(
+        &'a self,
+        networks: &'a [Ipv4Network],
+        _: RouteTable,
+    ) -> BoxFuture<'a, Result<Vec<Ipv4Network>>> {
+        Box::pin(async move {
+            Ok(self
+                .connected
+                .iter()
+                .filter(|n| networks.contains(n))
+                .copied()
+                .collect())
+        })
+    }
+
+    fn lookup_ipv6<'a>(
+        &'a self,
+        _: &'a [ipnetwork::Ipv6Network],
+        _: RouteTable,
+    ) -> BoxFuture<'a, Result<Vec<ipnetwork::Ipv6Network>>> {
+        Box::pin(async { Ok(vec![]) })
+    }
+
+    fn lookup_installed<'a>(&'a self, _: &'a Config) -> BoxFuture<'a, Result<Vec<FibRoute>>> {
+        Box::pin(async move { Ok(self.installed()) })
+    }
+
+    fn install<'a>(&'a self, routes: &'a [FibRoute], _: &'a Config) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let failing = self.failing.lock().unwrap().clone();
+            let mut installed = self.installed.lock().unwrap();
+            for route in routes {
+                if !failing.contains(&route.network_address) {
+                    self.writes.fetch_add(1, Ordering::SeqCst);
+                    installed.insert(route.network_address, route.next_hop);
+                }
+            }
+            drop(installed);
+            self.check_failing(routes.iter().map(|r| r.network_address))
+        })
+    }
+
+    fn remove<'a>(
+        &'a self,
+        networks: &'a [Ipv4Network],
+        _: &'a Config,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let failing = self.failing.lock().unwrap().clone();
+            let mut installed = self.installed.lock().unwrap();
+            for network in networks {
+                if !failing.contains(network) {
+                    installed.remove(network);
+                }
+            }
+            drop(installed);
+            self.check_failing(networks.iter().copied())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routing::{AdjRibIn, LocRib, RibEntry};
+
+    #[tokio::test]
+    async fn loc_rib_writes_learned_routes_to_fib() {
+        let config: Config = "64512 10.200.100.2 64513 10.200.100.3 active 10.100.210.0/24"
+            .parse()
+            .unwrap();
+        let fib = Arc::new(MockFib::new(vec!["10.100.210.0/24".parse().unwrap()]));
+        let mut loc_rib = LocRib::with_fib(&config, fib.clone()).await.unwrap();
+        let learned = RibEntry::for_test("10.100.220.0/24");
+        let mut adj_rib_in = AdjRibIn::from(vec![learned.clone()]);
+        loc_rib.install_from_adj_rib_in(&mut adj_rib_in);
+
+        loc_rib
+            .write_to_kernel_routing_table(&config)
+            .await
+            .unwrap();
+        // 自分が広告しているネットワークのルートは書き込まない。
+        assert_eq!(loc_rib.len(), 2);
+        assert_eq!(
+            fib.installed(),
+            vec![FibRoute {
+                network_address: learned.network_address,
+                next_hop: "10.200.100.3".parse().unwrap(),
+            }]
+        );
+
+        loc_rib.remove_routes_learned_from(&adj_rib_in);
+        loc_rib
+            .write_to_kernel_routing_table(&config)
+            .await
+            .unwrap();
+        assert!(fib.installed().is_empty());
+    }
+
+    #[tokio::test]
+    async fn learned_route_of_backdoor_network_is_not_written_to_fib() {
+        let config: Config =
+            "64512 10.200.100.2 64513 10.200.100.3 active network 10.100.220.0/24 backdoor"
+                .parse()
+                .unwrap();
+        let fib = Arc::new(MockFib::new(vec![]));
+        let mut loc_rib = LocRib::with_fib(&config, fib.clone()).await.unwrap();
+        let learned = RibEntry::for_test("10.100.220.0/24");
+        loc_rib.install_from_adj_rib_in(&mut AdjRibIn::from(vec![learned]));
+
+        loc_rib
+            .write_to_kernel_routing_table(&config)
+            .await
+            .unwrap();
+        // LocRibには入れて広告するが、カーネルのルートを優先する。
+        assert_eq!(loc_rib.len(), 1);
+        assert!(fib.installed().is_empty());
+    }
+
+    #[tokio::test]
+    async fn learned_route_is_written_to_fib_after_update_delay() {
+        let config: Config = "64512 10.200.100.2 64513 10.200.100.3 active"
+            .parse()
+            .unwrap();
+        let fib = Arc::new(MockFib::new(vec![]));
+        let mut loc_rib = LocRib::with_fib(&config, fib.clone()).await.unwrap();
+        loc_rib.set_read_only(true);
+        let learned = RibEntry::for_test("10.100.220.0/24");
+        loc_rib.install_from_adj_rib_in(&mut AdjRibIn::from(vec![learned]));
+
+        loc_rib
+            .write_to_kernel_routing_table(&config)
+            .await
+            .unwrap();
+        assert!(fib.installed().is_empty());
+
+        loc_rib.set_read_only(false);
+        loc_rib
+            .write_to_kernel_routing_table(&config)
+            .await
+            .unwrap();
+        assert_eq!(fib.installed().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn only_changed_routes_written_by_this_process_are_synced() {
+        let config: Config = "64512 10.200.100.2 64513 10.200.100.3 active"
+            .parse()
+            .unwrap();
+        let fib = Arc::new(MockFib::new(vec![]));
+        let mut loc_rib = LocRib::with_fib(&config, fib.clone()).await.unwrap();
+        // 同じprotocolでほかのプロセスが書き込んだルート。
+        let other = FibRoute {
+            network_address: "10.100.210.0/24".parse().unwrap(),
+            next_hop: "10.200.100.4".parse().unwrap(),
+        };
+        fib.install(&[other], &config).await.unwrap();
+        let learned = RibEntry::for_test("10.100.220.0/24");
+        loc_rib.install_from_adj_rib_in(&mut AdjRibIn::from(vec![learned.clone()]));
+
+        loc_rib
+            .write_to_kernel_routing_table(&config)
+            .await
+            .unwrap();
+        let written = FibRoute {
+            network_address: learned.network_address,
+            next_hop: "10.200.100.3".parse().unwrap(),
+        };
+        assert_eq!(fib.installed(), vec![other, written]);
+        assert_eq!(fib.writes(), 2);
+
+        // 変わっていないルートは書き込み直さない。
+        loc_rib
+            .write_to_kernel_routing_table(&config)
+            .await
+            .unwrap();
+        assert_eq!(fib.writes(), 2);
+
+        // 書き込んだルートだけを削除し、ほかのプロセスのルートは残す。
+        let removed = loc_rib.remove_routes_learned_from(&AdjRibIn::from(vec![learned]));
+        assert_eq!(removed.len(), 1);
+        loc_rib
+            .write_to_kernel_routing_table(&config)
+            .await
+            .unwrap();
+        assert_eq!(fib.installed(), vec![other]);
+    }
+
+    #[tokio::test]
+    async fn only_failed_routes_are_written_again_after_partial_failure() {
+        let config: Config = "64512 10.200.100.2 64513 10.200.100.3 active"
+            .parse()
+            .unwrap();
+        let fib = Arc::new(MockFib::new(vec![]));
+        let mut loc_rib = LocRib::with_fib(&config, fib.clone()).await.unwrap();
+        let (a, b) = (
+            RibEntry::for_test("10.100.220.0/24"),
+            RibEntry::for_test("10.100.221.0/24"),
+        );
+        loc_rib.install_from_adj_rib_in(&mut AdjRibIn::from(vec![a.clone(), b.clone()]));
+        fib.set_failing(b.network_address, true);
+
+        let e = loc_rib
+            .write_to_kernel_routing_table(&config)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<PartialFibError>().unwrap().failed,
+            BTreeSet::from([b.network_address])
+        );
+        assert_eq!(fib.installed().len(), 1);
+
+        // 書き込めたルートは書き込み直さず、失敗したルートだけを書き込み直す。
+        fib.set_failing(b.network_address, false);
+        loc_rib
+            .write_to_kernel_routing_table(&config)
+            .await
+            .unwrap();
+        assert_eq!(fib.installed().len(), 2);
+        assert_eq!(fib.writes(), 2);
+    }
+
+    #[tokio::test]
+    async fn routes_left_by_previous_process_are_removed_unless_learned_again() {
+        let config: Config = "64512 10.200.100.2 64513 10.200.100.3 active"
+            .parse()
+            .unwrap();
+        let fib = Arc::new(MockFib::new(vec![]));
+        let learned = FibRoute {
+            network_address: "10.100.220.0/24".parse().unwrap(),
+            next_hop: "10.200.100.3".parse().unwrap(),
+        };
+        let stale = FibRoute {
+            network_address: "10.100.221.0/24".parse().unwrap(),
+            next_hop: "10.200.100.3".parse().unwrap(),
+        };
+        fib.install(&[learned, stale], &config).await.unwrap();
+
+        let mut loc_rib = LocRib::with_fib(&config, fib.clone()).await.unwrap();
+        assert_eq!(loc_rib.adopt_kernel_routes(&config).await.unwrap(), 2);
+        loc_rib.install_from_adj_rib_in(&mut AdjRibIn::from(vec![RibEntry::for_test(
+            "10.100.220.0/24",
+        )]));
+        loc_rib
+            .write_to_kernel_routing_table(&config)
+            .await
+            .unwrap();
+        // 学習し直したルートは書き込み直さず、学習し直さなかったルートを削除する。
+        assert_eq!(fib.installed(), vec![learned]);
+        assert_eq!(fib.writes(), 2);
+    }
+
+    #[tokio::test]
+    async fn staged_routes_can_be_flushed_after_loc_rib_is_updated_again() {
+        let config: Config = "64512 10.200.100.2 64513 10.200.100.3 active"
+            .parse()
+            .unwrap();
+        let fib = Arc::new(MockFib::new(vec![]));
+        let mut loc_rib = LocRib::with_fib(&config, fib.clone()).await.unwrap();
+        let learned = RibEntry::for_test("10.100.220.0/24");
+        let mut adj_rib_in = AdjRibIn::from(vec![learned]);
+        loc_rib.install_from_adj_rib_in(&mut adj_rib_in);
+        let staged = loc_rib.stage_kernel_routes(&config);
+        // 書き込む前にルートが取り消されても、最後の予約の内容を書き込む。
+        loc_rib.remove_routes_learned_from(&adj_rib_in);
+        let latest = loc_rib.stage_kernel_routes(&config);
+
+        latest.flush(&config).await.unwrap();
+        staged.flush(&config).await.unwrap();
+        assert!(fib.installed().is_empty());
+    }
+}
@@ -0,0 +1,388 @@
+use super::{Fib, FibRoute, PartialFibError, BLACKHOLE_NEXT_HOP};
+use crate::config::Config;
+use crate::log_warning;
+use crate::routing::{Ipv4Network, RouteTable};
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use rtnetlink::packet::route::Nla;
+use rtnetlink::packet::{RouteMessage, AF_INET, RTN_BLACKHOLE, RT_TABLE_COMPAT};
+use rtnetlink::{new_connection, Handle, IpVersion, RouteAddRequest};
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use tokio::sync::OnceCell;
+
+/// 応答を待たずに送信するnetlinkのリクエストの最大数。
+/// 1つずつ応答を待つと数千のルートの書き込みに時間がかかるため、まとめて送信する。
+/// 同時に送信する数を制限し、カーネルのソケットの受信バッファがあふれないようにする。
+const NETLINK_BATCH_SIZE: usize = 128;
+
+/// 同じ宛先とmetricのルートがすでにあるときに、NLM_F_EXCLで追加したリクエストが返すerrno。
+const EEXIST: i32 = 17;
+
+/// 削除しようとしたルートがないときに返るerrno。
+const ESRCH: i32 = 3;
+
+/// netlinkのリクエストをNETLINK_BATCH_SIZEずつ並行に実行する。
+/// 一部のリクエストが失敗しても残りのリクエストは実行し、失敗したリクエストはすべてログに出す。
+/// 失敗したリクエストのネットワークとエラーを返す。
+async fn execute_in_batches<F>(requests: Vec<(Ipv4Network, F)>) -> Vec<(Ipv4Network, anyhow::Error)>
+where
+    F: Future<Output = Result<()>>,
+{
+    let errors: Vec<(Ipv4Network, anyhow::Error)> = stream::iter(requests)
+        .map(|(network, request)| async move { (network, request.await) })
+        .buffer_unordered(NETLINK_BATCH_SIZE)
+        .filter_map(|(network, result)| async move { result.err().map(|e| (network, e)) })
+        .collect()
+        .await;
+    for (_, e) in &errors {
+        log_warning!("{:?}", e);
+    }
+    errors
+}
+
+/// total個のリクエストのうち失敗したものがあれば、失敗したネットワークと最初のエラーを含む
+/// PartialFibErrorを返す。
+fn into_result(total: usize, errors: Vec<(Ipv4Network, anyhow::Error)>) -> Result<()> {
+    let failed = errors.iter().map(|(network, _)| *network).collect();
+    match errors.into_iter().next() {
+        None => Ok(()),
+        Some((_, first)) => Err(PartialFibError {
+            total,
+            failed,
+            source: first.into(),
+        }
+        .into()),
+    }
+}
+
+/// routeが含まれるテーブルのID。
+/// 256以上のテーブルIDはヘッダに収まらないため、RTA_TABLE属性の値を優先する。
+fn route_table(route: &RouteMessage) -> u32 {
+    route
+        .nlas
+        .iter()
+        .find_map(|nla| match nla {
+            Nla::Table(table) => Some(*table),
+            _ => None,
+        })
+        .unwrap_or(route.header.table as u32)
+}
+
+/// 書き込むルートのヘッダとRTA_TABLE属性にテーブルIDを設定する。
+fn set_route_table(route: &mut RouteMessage, table: RouteTable) {
+    let table = u32::from(table);
+    route.header.table = u8::try_from(table).unwrap_or(RT_TABLE_COMPAT);
+    route.nlas.push(Nla::Table(table));
+}
+
+/// ルートを書き込むリクエストを作成する。既存のルートを置き換える場合は、呼び出し側でreplaceを設定する。
+fn route_add_request(
+    handle: &Handle,
+    route: &FibRoute,
+    config: &Config,
+) -> RouteAddRequest<Ipv4Addr> {
+    let mut request = handle.route().add().v4().destination_prefix(
+        route.network_address.network(),
+        route.network_address.prefix(),
+    );
+    if route.is_blackhole() {
+        // blackholeのルートにはゲートウェイを付けず、宛先のパケットをカーネルで破棄させる。
+        request.message_mut().header.kind = RTN_BLACKHOLE;
+    } else {
+        request = request.gateway(route.next_hop);
+    }
+    let mut request = request.protocol(config.route_protocol.into());
+    request
+        .message_mut()
+        .nlas
+        .push(Nla::Priority(config.route_metric));
+    set_route_table(request.message_mut(), config.route_table);
+    request
+}
+
+/// routeのmetric。RTA_PRIORITY属性がなければ0である。
+fn route_metric(route: &RouteMessage) -> u32 {
+    route
+        .nlas
+        .iter()
+        .find_map(|nla| match nla {
+            Nla::Priority(metric) => Some(*metric),
+            _ => None,
+        })
+        .unwrap_or(0)
+}
+
+/// routeのゲートウェイ。RTA_GATEWAY属性がないか、IPv4のアドレスでなければNoneを返す。
+fn route_gateway(route: &RouteMessage) -> Option<Ipv4Addr> {
+    route.nlas.iter().find_map(|nla| match nla {
+        Nla::Gateway(bytes) => <[u8; 4]>::try_from(&bytes[..]).ok().map(Ipv4Addr::from),
+        _ => None,
+    })
+}
+
+/// rtnetlinkでLinuxカーネルのルーティングテーブルを操作するFib。
+/// リクエストごとに接続を作らず、最初に使うときに接続して以降は同じ接続を使い回す。
+#[derive(Debug, Default)]
+pub struct NetlinkFib(OnceCell<Handle>);
+
+impl NetlinkFib {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    async fn handle(&self) -> Result<&Handle> {
+        self.0
+            .get_or_try_init(|| async {
+                let (connection, handle, _) = new_connection()?;
+                tokio::spawn(connection);
+                Ok(handle)
+            })
+            .await
+    }
+
+    /// tableの経路のうち、宛先がIPのversionに一致するものを、宛先とともに返す。
+    async fn dump(
+        &self,
+        version: IpVersion,
+        table: RouteTable,
+    ) -> Result<Vec<(RouteMessage, IpAddr, u8)>> {
+        let mut routes = self.handle().await?.route().get(version).execute();
+        let mut results = vec![];
+        while let Some(route) = routes.try_next().await? {
+            if route_table(&route) != u32::from(table) {
+                continue;
+            }
+            if let Some((addr, prefix)) = route.destination_prefix() {
+                results.push((route, addr, prefix));
+            }
+        }
+        Ok(results)
+    }
+}
+
+impl Fib for NetlinkFib {
+    fn lookup_ipv4<'a>(
+        &'a self,
+        networks: &'a [Ipv4Network],
+        table: RouteTable,
+    ) -> BoxFuture<'a, Result<Vec<Ipv4Network>>> {
+        Box::pin(async move {
+            let mut results = vec![];
+            for (_, addr, prefix) in self.dump(IpVersion::V4, table).await? {
+                if let IpAddr::V4(addr) = addr {
+                    let destination = ipnetwork::Ipv4Network::new(addr, prefix)?.into();
+                    if networks.contains(&destination) {
+                        results.push(destination);
+                    }
+                }
+            }
+            Ok(results)
+        })
+    }
+
+    fn lookup_ipv6<'a>(
+        &'a self,
+        networks: &'a [ipnetwork::Ipv6Network],
+        table: RouteTable,
+    ) -> BoxFuture<'a, Result<Vec<ipnetwork::Ipv6Network>>> {
+        Box::pin(async move {
+            let mut results = vec![];
+            for (_, addr, prefix) in self.dump(IpVersion::V6, table).await? {
+                if let IpAddr::V6(addr) = addr {
+                    let destination = ipnetwork::Ipv6Network::new(addr, prefix)?;
+                    if networks.contains(&destination) {
+                        results.push(destination);
+                    }
+                }
+            }
+            Ok(results)
+        })
+    }
+
+    fn lookup_installed<'a>(&'a self, config: &'a Config) -> BoxFuture<'a, Result<Vec<FibRoute>>> {
+        Box::pin(async move {
+            let protocol: u8 = config.route_protocol.into();
+            let mut results = vec![];
+            for (route, addr, prefix) in self.dump(IpVersion::V4, config.route_table).await? {
+                if route.header.protocol != protocol || route_metric(&route) != config.route_metric
+                {
+                    continue;
+                }
+                let IpAddr::V4(addr) = addr else {
+                    continue;
+                };
+                let next_hop = if route.header.kind == RTN_BLACKHOLE {
+                    BLACKHOLE_NEXT_HOP
+                } else {
+                    match route_gateway(&route) {
+                        Some(gateway) => gateway,
+                        None => continue,
+                    }
+                };
+                results.push(FibRoute {
+                    network_address: ipnetwork::Ipv4Network::new(addr, prefix)?.into(),
+                    next_hop,
+                });
+            }
+            Ok(results)
+        })
+    }
+
+    fn install<'a>(
+        &'a self,
+        routes: &'a [FibRoute],
+        config: &'a Config,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let handle = self.handle().await?;
+            // NLM_F_EXCLで追加し、ほかのプロセスが書き込んだ同じ宛先とmetricのルートを置き換えない。
+            // EEXISTで失敗したルートは、既存のルートがこのプロセスのprotocolの場合だけ後で置き換える。
+            let existing = Arc::new(Mutex::new(vec![]));
+            let mut requests = vec![];
+            for route in routes {
+                let request = route_add_request(handle, route, config);
+                let existing = Arc::clone(&existing);
+                let route = *route;
+                let request = async move {
+                    match request.execute().await {
+                        Err(rtnetlink::Error::NetlinkError(e)) if e.code == -EEXIST => {
+                            existing.lock().unwrap().push(route);
+                            Ok(())
+                        }
+                        result => result.context(format!(
+                            "{:?}をカーネルのルーティングテーブルに書き込めませんでした。",
+                            route.network_address
+                        )),
+                    }
+                };
+                requests.push((route.network_address, request));
+            }
+            let mut errors = execute_in_batches(requests).await;
+            let existing = std::mem::take(&mut *existing.lock().unwrap());
+            if existing.is_empty() {
+                return into_result(routes.len(), errors);
+            }
+
+            let owned: BTreeSet<Ipv4Network> = match self.lookup_installed(config).await {
+                Ok(installed) => installed.iter().map(|r| r.network_address).collect(),
+                Err(e) => {
+                    for route in existing {
+                        errors.push((route.network_address, anyhow::anyhow!("{:#}", e)));
+                    }
+                    return into_result(routes.len(), errors);
+                }
+            };
+            let mut requests: Vec<(Ipv4Network, BoxFuture<'_, Result<()>>)> = vec![];
+            for route in existing {
+                let network_address = route.network_address;
+                if !owned.contains(&network_address) {
+                    let request = Box::pin(async move {
+                        Err(anyhow::anyhow!(
+                            "{:?}にはほかのprotocolのルートがあるため、カーネルのルーティングテーブルに書き込みません。",
+                            network_address
+                        ))
+                    });
+                    requests.push((network_address, request));
+                    continue;
+                }
+                let request = route_add_request(handle, &route, config).replace();
+                let request = Box::pin(async move {
+                    request.execute().await.context(format!(
+                        "{:?}をカーネルのルーティングテーブルに書き込めませんでした。",
+                        network_address
+                    ))
+                });
+                requests.push((network_address, request));
+            }
+            errors.extend(execute_in_batches(requests).await);
+            into_result(routes.len(), errors)
+        })
+    }
+
+    fn remove<'a>(
+        &'a self,
+        networks: &'a [Ipv4Network],
+        config: &'a Config,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            if networks.is_empty() {
+                return Ok(());
+            }
+            let handle = self.handle().await?;
+            let mut requests = vec![];
+            for network in networks {
+                // ルーティングテーブルを読み直さずに、宛先とテーブルとprotocolを指定して削除する。
+                // カーネルはprotocolが一致するルートだけを削除するため、ほかのプロセスのルートは削除しない。
+                let mut message = RouteMessage::default();
+                message.header.address_family = AF_INET as u8;
+                message.header.destination_prefix_length = network.prefix();
+                message.header.protocol = config.route_protocol.into();
+                message
+                    .nlas
+                    .push(Nla::Destination(network.network().octets().to_vec()));
+                set_route_table(&mut message, config.route_table);
+                let request = handle.route().del(message).execute();
+                let network = *network;
+                requests.push((network, async move {
+                    match request.await {
+                        // すでに削除されているルートは、削除できたものとする。
+                        Err(rtnetlink::Error::NetlinkError(e)) if e.code == -ESRCH => Ok(()),
+                        result => result.context(format!(
+                            "{:?}をカーネルのルーティングテーブルから削除できませんでした。",
+                            network
+                        )),
+                    }
+                }));
+            }
+            into_result(networks.len(), execute_in_batches(requests).await)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn execute_in_batches_runs_all_requests_and_counts_errors() {
+        let executed = Arc::new(AtomicUsize::new(0));
+        let network = |i: usize| {
+            Ipv4Network::from(
+                ipnetwork::Ipv4Network::new(Ipv4Addr::from(0x0a00_0000 + i as u32), 32).unwrap(),
+            )
+        };
+        let requests = (0..NETLINK_BATCH_SIZE * 2 + 1).map(|i| {
+            let executed = Arc::clone(&executed);
+            let request = async move {
+                executed.fetch_add(1, Ordering::SeqCst);
+                if i == 1 || i == NETLINK_BATCH_SIZE + 1 {
+                    Err(anyhow::anyhow!("failed"))
+                } else {
+                    Ok(())
+                }
+            };
+            (network(i), request)
+        });
+
+        let errors = execute_in_batches(requests.collect()).await;
+        let e = into_result(NETLINK_BATCH_SIZE * 2 + 1, errors).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "{}個のリクエストのうち2個が失敗しました。",
+                NETLINK_BATCH_SIZE * 2 + 1
+            )
+        );
+        assert_eq!(e.root_cause().to_string(), "failed");
+        assert_eq!(
+            e.downcast_ref::<PartialFibError>().unwrap().failed,
+            BTreeSet::from([network(1), network(NETLINK_BATCH_SIZE + 1)])
+        );
+        assert_eq!(executed.load(Ordering::SeqCst), NETLINK_BATCH_SIZE * 2 + 1);
+    }
+}
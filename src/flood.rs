@@ -0,0 +1,373 @@
+use crate::bench::{self, MAXIMUM_ROUTES};
+use crate::config::Config;
+use crate::connection::{BgpTransport, Connection};
+use crate::packets::capability::Capability;
+use crate::packets::message::Message;
+use crate::packets::notification::{cease, NotificationMessage};
+use crate::packets::open::OpenMessage;
+use crate::packets::update::UpdateMessage;
+use crate::path_attribute::{AsPath, Community, Origin, PathAttribute};
+use crate::routing::Ipv4Network;
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// OpenMessageとKeepaliveMessageの交換を待つ最大の時間。RFC 4271のOpenSentのHold Timeと同じ4分とする。
+const SESSION_TIMEOUT: Duration = Duration::from_secs(240);
+/// 最後にCeaseを送信してから、対向機器が接続を閉じるのを待つ最大の時間。
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+/// 対向機器からのメッセージを待つ間に、受信を確認する間隔。
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// 送信する合成のUpdateMessageの量と内容。
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct FloodOptions {
+    /// 広告するprefixの数。10.0.0.0/24から順に/24ずつ進める。
+    pub prefixes: usize,
+    /// Path Attributeの組の種類。prefixごとに、Communityの異なる組を順に割り当てる。
+    pub attribute_sets: usize,
+    /// 2巡目以降に取り消すprefixの割合(%)。残りのprefixはPath Attributeの組を変えて広告し直す。
+    pub withdraw_percent: u8,
+    /// すべてのprefixについて広告か取り消しを送る回数。1巡目はすべてのprefixを広告する。
+    pub rounds: usize,
+    /// 1秒あたりに送信するUpdateMessageの数。0の場合は待たずに送信する。
+    pub rate: u32,
+}
+
+impl Default for FloodOptions {
+    fn default() -> Self {
+        Self {
+            prefixes: 1000,
+            attribute_sets: 1,
+            withdraw_percent: 0,
+            rounds: 1,
+            rate: 0,
+        }
+    }
+}
+
+/// `howbgp flood`の結果。
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct FloodReport {
+    pub updates: u64,
+    pub announced_prefixes: u64,
+    pub withdrawn_prefixes: u64,
+    /// セッションを確立してから、最後のUpdateMessageを送信するまでの時間。
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for FloodReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.elapsed.as_secs_f64();
+        writeln!(f, "update messages:      {}", self.updates)?;
+        writeln!(f, "announced prefixes:   {}", self.announced_prefixes)?;
+        writeln!(f, "withdrawn prefixes:   {}", self.withdrawn_prefixes)?;
+        writeln!(f, "elapsed:              {:.3}s", seconds)?;
+        let rate = if seconds > 0.0 {
+            self.updates as f64 / seconds
+        } else {
+            0.0
+        };
+        write!(f, "updates per second:   {:.0}", rate)
+    }
+}
+
+/// configの対向機器とセッションを確立し、optionsに従って合成のUpdateMessageを送信する。
+/// 送信し終えたらCease(Administrative Shutdown)を送ってセッションを閉じる。
+/// このデーモンやほかの実装の、大量のUpdateMessageに対する負荷試験や相互接続の試験に使う。
+pub async fn run(config: &Config, options: &FloodOptions) -> Result<FloodReport> {
+    let transport = config.transport.to_bgp_transport();
+    flood(transport.as_ref(), config, options).await
+}
+
+async fn flood(
+    transport: &dyn BgpTransport,
+    config: &Config,
+    options: &FloodOptions,
+) -> Result<FloodReport> {
+    if !(1..=MAXIMUM_ROUTES).contains(&options.prefixes) {
+        return Err(anyhow::anyhow!(
+            "prefixの数は1以上{}以下にしてください。",
+            MAXIMUM_ROUTES
+        ));
+    }
+    if options.attribute_sets == 0 || options.rounds == 0 {
+        return Err(anyhow::anyhow!(
+            "Path Attributeの組の種類と回数は1以上にしてください。"
+        ));
+    }
+    if options.withdraw_percent > 100 {
+        return Err(anyhow::anyhow!(
+            "取り消すprefixの割合は100以下にしてください。"
+        ));
+    }
+
+    let mut session = Session::open(transport, config).await?;
+    let started = Instant::now();
+    let mut report = FloodReport::default();
+    for round in 0..options.rounds {
+        for update in round_updates(config, options, round) {
+            if options.rate > 0 {
+                let due =
+                    started + Duration::from_secs_f64(report.updates as f64 / options.rate as f64);
+                tokio::time::sleep_until(due.into()).await;
+            }
+            session.poll().await?;
+            report.announced_prefixes +=
+                update.network_layer_reachability_information().len() as u64;
+            report.withdrawn_prefixes += update.withdrawn_routes().len() as u64;
+            session.connection.send(Message::Update(update)).await;
+            report.updates += 1;
+        }
+    }
+    report.elapsed = started.elapsed();
+    session.close().await;
+    Ok(report)
+}
+
+/// round巡目に送信するUpdateMessage。
+/// 2巡目以降は、prefixごとに巡目でずらしながらwithdraw_percent%を取り消し、
+/// 残りは前の巡目と異なるPath Attributeの組で広告し直す。
+fn round_updates(config: &Config, options: &FloodOptions, round: usize) -> Vec<UpdateMessage> {
+    let mut withdrawn = vec![];
+    let mut announced: BTreeMap<usize, Vec<Ipv4Network>> = BTreeMap::new();
+    for i in 0..options.prefixes {
+        if round > 0 && (i + round) % 100 < options.withdraw_percent as usize {
+            withdrawn.push(bench::network(i));
+        } else {
+            announced
+                .entry((i + round) % options.attribute_sets)
+                .or_default()
+                .push(bench::network(i));
+        }
+    }
+    let mut updates = UpdateMessage::withdrawals(withdrawn);
+    for (attribute_set, networks) in announced {
+        updates.extend(UpdateMessage::announcements(
+            path_attributes(config, attribute_set),
+            networks,
+        ));
+    }
+    updates
+}
+
+/// attribute_set番目のPath Attributeの組。`<local_as>:<attribute_set>`のCommunityで区別する。
+fn path_attributes(config: &Config, attribute_set: usize) -> Vec<PathAttribute> {
+    let as_path = if config.is_ebgp() {
+        vec![config.local_as]
+    } else {
+        vec![]
+    };
+    vec![
+        PathAttribute::Origin(Origin::Igp),
+        PathAttribute::AsPath(AsPath::AsSequence(as_path)),
+        PathAttribute::NextHop(config.local_ip),
+        PathAttribute::Communities(vec![Community(
+            u32::from(config.local_as) << 16 | attribute_set as u32,
+        )]),
+    ]
+}
+
+/// FSMを持たずに、OpenMessageの交換とKeepaliveMessageの送信だけを行うセッション。
+struct Session {
+    connection: Connection,
+    peer_name: String,
+    /// 交渉したHold Timeから決めた間隔。Hold Timeが0の場合はNoneで、KeepaliveMessageを送らない。
+    keepalive_interval: Option<Duration>,
+    last_keepalive: Instant,
+}
+
+impl Session {
+    async fn open(transport: &dyn BgpTransport, config: &Config) -> Result<Self> {
+        let peer_name = config.display_name();
+        let connection = Connection::connect(transport, config)
+            .await
+            .context(format!("{}と接続できませんでした。", peer_name))?;
+        let mut session = Self {
+            connection,
+            peer_name,
+            keepalive_interval: None,
+            last_keepalive: Instant::now(),
+        };
+        let capabilities: Vec<Capability> = config
+            .address_families
+            .iter()
+            .copied()
+            .map(Capability::Multiprotocol)
+            .collect();
+        let open = OpenMessage::new(config.local_as, config.local_ip, config.hold_time())
+            .with_capabilities(&capabilities);
+        session.connection.send(Message::Open(open)).await;
+        let hold_time = match session.receive().await? {
+            Message::Open(open) => config.hold_time().min(open.hold_time()),
+            message => return Err(session.unexpected(&message)),
+        };
+        session.connection.send(Message::new_keepalive()).await;
+        match session.receive().await? {
+            Message::Keepalive(_) => (),
+            message => return Err(session.unexpected(&message)),
+        }
+        if !hold_time.is_zero() {
+            session.keepalive_interval = Some(config.keepalive_interval(hold_time));
+        }
+        session.last_keepalive = Instant::now();
+        Ok(session)
+    }
+
+    /// 次のメッセージを受信するまで待つ。NotificationMessageを受信した場合はエラーにする。
+    async fn receive(&mut self) -> Result<Message> {
+        let deadline = Instant::now() + SESSION_TIMEOUT;
+        loop {
+            if let Some(message) = self.connection.get_message().await {
+                let message = message.context(format!(
+                    "{}から受信したメッセージを読み取れませんでした。",
+                    self.peer_name
+                ))?;
+                if let Message::Notification(notification) = &message {
+                    return Err(self.notified(notification));
+                }
+                return Ok(message);
+            }
+            if self.connection.is_closed() {
+                return Err(anyhow::anyhow!("{}が接続を閉じました。", self.peer_name));
+            }
+            if Instant::now() >= deadline {
+                return Err(anyhow::anyhow!(
+                    "{}からの応答を待つ間にタイムアウトしました。",
+                    self.peer_name
+                ));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// 受信済みのメッセージを読み捨て、必要であればKeepaliveMessageを送信する。
+    /// 対向機器がセッションを閉じていればエラーにする。
+    async fn poll(&mut self) -> Result<()> {
+        while let Some(message) = self.connection.get_message().await {
+            if let Ok(Message::Notification(notification)) = &message {
+                return Err(self.notified(notification));
+            }
+        }
+        if self.connection.is_closed() {
+            return Err(anyhow::anyhow!("{}が接続を閉じました。", self.peer_name));
+        }
+        if let Some(interval) = self.keepalive_interval {
+            if self.last_keepalive.elapsed() >= interval {
+                self.connection.send(Message::new_keepalive()).await;
+                self.last_keepalive = Instant::now();
+            }
+        }
+        Ok(())
+    }
+
+    /// Cease(Administrative Shutdown)を送信し、対向機器が接続を閉じるまで少し待つ。
+    async fn close(mut self) {
+        let cease = NotificationMessage::new_cease(cease::ADMINISTRATIVE_SHUTDOWN, None);
+        self.connection.send(Message::Notification(cease)).await;
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        while Instant::now() < deadline {
+            while self.connection.get_message().await.is_some() {}
+            if self.connection.is_closed() {
+                return;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    fn notified(&self, notification: &NotificationMessage) -> anyhow::Error {
+        anyhow::anyhow!(
+            "{}からNotificationMessageを受信しました。{}",
+            self.peer_name,
+            notification
+        )
+    }
+
+    fn unexpected(&self, message: &Message) -> anyhow::Error {
+        anyhow::anyhow!(
+            "{}から想定していないメッセージ{:?}を受信しました。",
+            self.peer_name,
+            message
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::InMemoryTransport;
+    use crate::packets::notification::ErrorCode;
+    use crate::peer::Peer;
+    use crate::routing::LocRib;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    fn generator_config() -> Config {
+        "64513 127.0.0.2 64512 127.0.0.1 active".parse().unwrap()
+    }
+
+    #[test]
+    fn later_rounds_withdraw_and_reannounce_prefixes() {
+        let options = FloodOptions {
+            prefixes: 100,
+            attribute_sets: 3,
+            withdraw_percent: 30,
+            rounds: 2,
+            rate: 0,
+        };
+        let config = generator_config();
+
+        let first = round_updates(&config, &options, 0);
+        assert_eq!(first.len(), 3);
+        assert!(first.iter().all(|u| u.withdrawn_routes().is_empty()));
+
+        let second = round_updates(&config, &options, 1);
+        let withdrawn: Vec<Ipv4Network> = second
+            .iter()
+            .flat_map(|u| u.withdrawn_routes().clone())
+            .collect();
+        assert_eq!(withdrawn.len(), 30);
+        assert!(withdrawn.contains(&bench::network(0)));
+        assert!(!withdrawn.contains(&bench::network(29)));
+        let announced: usize = second
+            .iter()
+            .map(|u| u.network_layer_reachability_information().len())
+            .sum();
+        assert_eq!(announced, 70);
+    }
+
+    #[tokio::test]
+    async fn target_receives_all_updates_and_cease() {
+        let (transport, target_transport) = InMemoryTransport::pair();
+        let target_config = "64512 127.0.0.1 64513 127.0.0.2 passive".parse().unwrap();
+        let mut target = Peer::new(target_config, Arc::new(Mutex::new(LocRib::empty())));
+        target.set_transport(Arc::new(target_transport));
+        let statistics = target.statistics();
+        target.start();
+        let task = tokio::spawn(async move {
+            loop {
+                target.next().await;
+            }
+        });
+
+        let options = FloodOptions {
+            prefixes: 2000,
+            attribute_sets: 2,
+            withdraw_percent: 50,
+            rounds: 2,
+            rate: 0,
+        };
+        let report = flood(&transport, &generator_config(), &options)
+            .await
+            .unwrap();
+        task.abort();
+
+        assert_eq!(report.announced_prefixes, 3000);
+        assert_eq!(report.withdrawn_prefixes, 1000);
+        let statistics = statistics.lock().await;
+        assert_eq!(statistics.messages_received.update, report.updates);
+        let notification = statistics.last_notification_received.clone().unwrap();
+        assert_eq!(notification.error_code(), ErrorCode::Cease);
+    }
+}
@@ -0,0 +1,738 @@
+use crate::config::Config;
+use crate::connection::bind_reusable_listener;
+use crate::control::NeighborList;
+use crate::error::ConfigParseError;
+use crate::routing::{Ipv4Network, LocRib, RibEntry};
+use crate::state::State;
+use crate::{log_error, log_info, log_warning};
+use anyhow::{Context, Result};
+use bytes::{BufMut, BytesMut};
+use hmac_sha256::HMAC;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+/// PrimaryがLocRibやセッションの状態の変化を確認し、Secondaryに送る間隔。
+/// 変化がなくても、この間隔でハートビートとして空の差分を送る。
+pub const HA_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Secondaryが、Primaryからの受信が途絶えたとみなすまでの時間。
+const HA_HOLD_TIME: Duration = Duration::from_secs(3);
+
+/// Secondaryが処理を引き継いだ後、ピアから受信し直していないPrimaryのルートを保持する時間。
+/// この間にEnd-of-RIBを受信したピアのルートは、その時点で受信し直したかを確認する。
+pub const HA_STALE_TIME: Duration = Duration::from_secs(300);
+
+/// 1つのHaUpdateの最大の長さ。これより長いLengthを受信した場合は、メモリを確保せずにエラーにする。
+const MAXIMUM_UPDATE_LENGTH: u32 = 256 * 1024 * 1024;
+
+/// 認証で交換するnonceの長さ。
+const NONCE_LENGTH: usize = 16;
+
+/// Active/Standby構成での自分の役割。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum HaRole {
+    /// ピアとセッションを張り、addressで待ち受けてSecondaryに状態を送る。
+    Primary(SocketAddr),
+    /// addressのPrimaryから状態を受け取り、Primaryとの接続が切れたら処理を引き継ぐ。
+    Secondary(SocketAddr),
+}
+
+impl HaRole {
+    /// `ha-primary ADDRESS`, `ha-secondary ADDRESS`のオプションから作成する。
+    pub fn parse(option: &str, address: &str) -> Result<Self, ConfigParseError> {
+        let address = SocketAddr::from_str(address)
+            .context(format!("cannot parse {} address `{}`", option, address))?;
+        match option {
+            "ha-primary" => Ok(HaRole::Primary(address)),
+            "ha-secondary" => Ok(HaRole::Secondary(address)),
+            _ => Err(ConfigParseError::InvalidValue {
+                kind: "ha role",
+                value: option.to_owned(),
+            }),
+        }
+    }
+}
+
+/// PrimaryとSecondaryが共有する鍵。ログに出力しないよう、Debugでは内容を表示しない。
+#[derive(Clone)]
+pub struct HaKey(Arc<[u8]>);
+
+impl HaKey {
+    pub fn new(key: impl Into<Vec<u8>>) -> Self {
+        Self(key.into().into())
+    }
+
+    /// ファイルの内容から、前後の空白を除いたものを鍵として読み込む。
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path).context(format!(
+            "HAの鍵のファイル{:?}を読み込めませんでした。",
+            path
+        ))?;
+        let key = contents.trim();
+        if key.is_empty() {
+            return Err(anyhow::anyhow!("HAの鍵のファイル{:?}が空です。", path));
+        }
+        Ok(Self::new(key))
+    }
+
+    fn mac(&self, parts: &[&[u8]]) -> [u8; 32] {
+        mac(&self.0, parts)
+    }
+}
+
+impl fmt::Debug for HaKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HaKey(..)")
+    }
+}
+
+fn mac(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut hmac = HMAC::new(key);
+    for part in parts {
+        hmac.update(part);
+    }
+    hmac.finalize()
+}
+
+fn verify_mac(key: &[u8], parts: &[&[u8]], expected: &[u8; 32]) -> bool {
+    let mut hmac = HMAC::new(key);
+    for part in parts {
+        hmac.update(part);
+    }
+    hmac.finalize_verify(expected)
+}
+
+fn nonce() -> Result<[u8; NONCE_LENGTH]> {
+    let mut nonce = [0; NONCE_LENGTH];
+    getrandom::fill(&mut nonce)
+        .map_err(|e| anyhow::anyhow!("nonceを生成できませんでした。{}", e))?;
+    Ok(nonce)
+}
+
+/// 認証した接続で使う鍵と、次に送受信するHaUpdateの番号。
+/// 番号をMACに含め、HaUpdateの再送や並べ替えを検出する。
+struct HaSession {
+    key: [u8; 32],
+    sequence: u64,
+}
+
+impl HaSession {
+    fn new(key: &HaKey, secondary_nonce: &[u8], primary_nonce: &[u8]) -> Self {
+        Self {
+            key: key.mac(&[b"session", secondary_nonce, primary_nonce]),
+            sequence: 0,
+        }
+    }
+}
+
+/// Primaryとして、接続してきたSecondaryと互いに鍵を知っていることを確認する。
+/// 1. SecondaryがNonce Sを送る。
+/// 2. PrimaryがNonce PとHMAC(key, "primary" + S + P)を送る。
+/// 3. SecondaryがHMAC(key, "secondary" + P + S)を送る。
+///
+/// 以降のHaUpdateは、両方のnonceから作ったセッションの鍵でMACを付けて送る。
+async fn authenticate_secondary<S>(key: &HaKey, stream: &mut S) -> Result<HaSession>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut secondary_nonce = [0; NONCE_LENGTH];
+    stream.read_exact(&mut secondary_nonce).await?;
+    let primary_nonce = nonce()?;
+    stream.write_all(&primary_nonce).await?;
+    stream
+        .write_all(&key.mac(&[b"primary", &secondary_nonce, &primary_nonce]))
+        .await?;
+    let mut proof = [0; 32];
+    stream.read_exact(&mut proof).await?;
+    if !verify_mac(
+        &key.0,
+        &[b"secondary", &primary_nonce, &secondary_nonce],
+        &proof,
+    ) {
+        return Err(anyhow::anyhow!("Secondaryを認証できませんでした。"));
+    }
+    Ok(HaSession::new(key, &secondary_nonce, &primary_nonce))
+}
+
+/// Secondaryとして、authenticate_secondaryと逆の手順でPrimaryと互いに認証する。
+async fn authenticate_primary<S>(key: &HaKey, stream: &mut S) -> Result<HaSession>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let secondary_nonce = nonce()?;
+    stream.write_all(&secondary_nonce).await?;
+    let mut primary_nonce = [0; NONCE_LENGTH];
+    stream.read_exact(&mut primary_nonce).await?;
+    let mut proof = [0; 32];
+    stream.read_exact(&mut proof).await?;
+    if !verify_mac(
+        &key.0,
+        &[b"primary", &secondary_nonce, &primary_nonce],
+        &proof,
+    ) {
+        return Err(anyhow::anyhow!("Primaryを認証できませんでした。"));
+    }
+    stream
+        .write_all(&key.mac(&[b"secondary", &primary_nonce, &secondary_nonce]))
+        .await?;
+    Ok(HaSession::new(key, &secondary_nonce, &primary_nonce))
+}
+
+/// PrimaryからSecondaryへ送る、セッションの状態とLocRibの内容。
+/// 接続した直後はLocRibの全体を送り、その後は前回から変わったルートだけを送る。
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct HaUpdate {
+    /// routesがLocRibの全体か。falseの場合は、前回からの差分である。
+    pub full: bool,
+    /// ピアのIPごとのBGPの状態。
+    pub sessions: Vec<(Ipv4Addr, State)>,
+    /// 追加されたか変わったルート。fullの場合はすべてのルート。
+    pub routes: Vec<RibEntry>,
+    /// 削除されたルートのネットワーク。
+    pub withdrawn: Vec<Ipv4Network>,
+}
+
+impl HaUpdate {
+    /// bytes表現は以下の通り。
+    /// [Full (1 octet)]
+    /// [Number of Sessions (2 octets)]
+    /// [Remote IP (4 octets) + State (1 octet)] * Number of Sessions
+    /// [Withdrawn Length (4 octets)][Withdrawn (NLRIと同じ表現)]
+    /// [Route Length (4 octets) + RibEntry] * ルートの数
+    fn to_bytes(&self) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        bytes.put_u8(self.full.into());
+        bytes.put_u16(self.sessions.len() as u16);
+        for (ip, state) in &self.sessions {
+            bytes.put(&ip.octets()[..]);
+            bytes.put_u8(state_to_u8(*state));
+        }
+        let mut withdrawn = BytesMut::new();
+        for network in &self.withdrawn {
+            withdrawn.put::<BytesMut>(network.into());
+        }
+        bytes.put_u32(withdrawn.len() as u32);
+        bytes.put(withdrawn);
+        for route in &self.routes {
+            let route: BytesMut = route.into();
+            bytes.put_u32(route.len() as u32);
+            bytes.put(route);
+        }
+        bytes
+    }
+}
+
+/// bytesの先頭4 octetsを長さとして読み取り、その長さのbytesと残りを返す。
+fn split_length_prefixed<'a>(bytes: &'a [u8], field: &str) -> Result<(&'a [u8], &'a [u8])> {
+    let length = u32::from_be_bytes(
+        bytes
+            .get(0..4)
+            .context(format!("{}の長さを読み取れませんでした。", field))?
+            .try_into()
+            .unwrap(),
+    ) as usize;
+    let value = bytes
+        .get(4..4 + length)
+        .context(format!("{}が途中で途切れています。", field))?;
+    Ok((value, &bytes[4 + length..]))
+}
+
+impl TryFrom<&[u8]> for HaUpdate {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        let full = match bytes.first().context("Fullを読み取れませんでした。")? {
+            0 => false,
+            1 => true,
+            value => return Err(anyhow::anyhow!("Fullの値{}は不正です。", value)),
+        };
+        let number_of_sessions = u16::from_be_bytes(
+            bytes
+                .get(1..3)
+                .context("Number of Sessionsを読み取れませんでした。")?
+                .try_into()
+                .unwrap(),
+        ) as usize;
+        let sessions_end = 3 + 5 * number_of_sessions;
+        let sessions = bytes
+            .get(3..sessions_end)
+            .context("セッションの状態が途中で途切れています。")?
+            .chunks(5)
+            .map(|c| {
+                let ip = Ipv4Addr::new(c[0], c[1], c[2], c[3]);
+                Ok((ip, state_from_u8(c[4])?))
+            })
+            .collect::<Result<_>>()?;
+
+        let (withdrawn, mut rest) =
+            split_length_prefixed(&bytes[sessions_end..], "削除されたルート")?;
+        let withdrawn = Ipv4Network::from_u8_slice(withdrawn)?;
+        let mut routes = vec![];
+        while !rest.is_empty() {
+            let (route, next) = split_length_prefixed(rest, "ルート")?;
+            routes.push(RibEntry::try_from(route)?);
+            rest = next;
+        }
+        Ok(Self {
+            full,
+            sessions,
+            routes,
+            withdrawn,
+        })
+    }
+}
+
+/// BGP4-MIBのbgpPeerStateと同じ値を使う。
+fn state_to_u8(state: State) -> u8 {
+    match state {
+        State::Idle => 1,
+        State::Connect => 2,
+        State::OpenSent => 4,
+        State::OpenConfirm => 5,
+        State::Established => 6,
+    }
+}
+
+fn state_from_u8(state: u8) -> Result<State> {
+    match state {
+        1 => Ok(State::Idle),
+        2 => Ok(State::Connect),
+        4 => Ok(State::OpenSent),
+        5 => Ok(State::OpenConfirm),
+        6 => Ok(State::Established),
+        _ => Err(anyhow::anyhow!(
+            "{}をBGPの状態に変換できませんでした。",
+            state
+        )),
+    }
+}
+
+/// [Length (4 octets)][HaUpdate][MAC (32 octets)]の形で書き込む。
+/// MACはセッションの鍵による、HaUpdateの番号 (8 octets)とHaUpdateのHMAC-SHA256である。
+async fn write_update<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    session: &mut HaSession,
+    update: &HaUpdate,
+) -> Result<()> {
+    let bytes = update.to_bytes();
+    let mac = mac(&session.key, &[&session.sequence.to_be_bytes(), &bytes]);
+    session.sequence += 1;
+    writer.write_u32(bytes.len() as u32).await?;
+    writer.write_all(&bytes).await?;
+    writer.write_all(&mac).await?;
+    Ok(())
+}
+
+async fn read_update<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    session: &mut HaSession,
+) -> Result<HaUpdate> {
+    let length = reader
+        .read_u32()
+        .await
+        .context("Primaryとの接続が切れました。")?;
+    if length > MAXIMUM_UPDATE_LENGTH {
+        return Err(anyhow::anyhow!(
+            "HaUpdateの長さ{}が上限の{}を超えています。",
+            length,
+            MAXIMUM_UPDATE_LENGTH
+        ));
+    }
+    let mut bytes = vec![0; length as usize];
+    let mut expected = [0; 32];
+    reader
+        .read_exact(&mut bytes)
+        .await
+        .context("受信の途中でPrimaryとの接続が切れました。")?;
+    reader
+        .read_exact(&mut expected)
+        .await
+        .context("受信の途中でPrimaryとの接続が切れました。")?;
+    if !verify_mac(
+        &session.key,
+        &[&session.sequence.to_be_bytes(), &bytes],
+        &expected,
+    ) {
+        return Err(anyhow::anyhow!("HaUpdateのMACが一致しません。"));
+    }
+    session.sequence += 1;
+    HaUpdate::try_from(&bytes[..])
+}
+
+/// 接続してきたSecondaryに、LocRibの全体を送った後、LocRibとセッションの状態の差分を送り続ける。
+#[derive(Debug, Clone)]
+pub struct HaPrimary {
+    loc_rib: Arc<Mutex<LocRib>>,
+    neighbors: NeighborList,
+    key: HaKey,
+}
+
+impl HaPrimary {
+    pub fn new(
+        loc_rib: Arc<Mutex<LocRib>>,
+        neighbors: impl Into<NeighborList>,
+        key: HaKey,
+    ) -> Self {
+        Self {
+            loc_rib,
+            neighbors: neighbors.into(),
+            key,
+        }
+    }
+
+    pub async fn serve(self, address: SocketAddr) -> Result<()> {
+        let listener = bind_reusable_listener(address, None)
+            .context(format!("{}にbindできませんでした。", address))?;
+        loop {
+            let (stream, secondary) = listener.accept().await?;
+            let primary = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = primary.replicate(stream).await {
+                    log_warning!("Secondary {}への同期を終了しました。{:?}", secondary, e);
+                }
+            });
+        }
+    }
+
+    async fn sessions(&self) -> Vec<(Ipv4Addr, State)> {
+        let mut sessions = vec![];
+        for neighbor in &self.neighbors.snapshot() {
+            let state = neighbor.statistics.lock().await.state;
+            sessions.push((neighbor.config.remote_ip, state));
+        }
+        sessions
+    }
+
+    /// Secondaryを認証した後、最初にLocRibの全体を送り、その後はHA_SYNC_INTERVALごとに前回から変わったルートを送る。
+    /// LocRibの比較にはversionを使い、変化がなければルートを読み出さずに空の差分を送る。
+    async fn replicate<S: AsyncRead + AsyncWrite + Unpin>(&self, mut stream: S) -> Result<()> {
+        let mut session =
+            tokio::time::timeout(HA_HOLD_TIME, authenticate_secondary(&self.key, &mut stream))
+                .await
+                .context("Secondaryの認証が時間内に終わりませんでした。")??;
+        let mut sent_version = None;
+        let mut sent_routes: BTreeMap<Ipv4Network, RibEntry> = BTreeMap::new();
+        let mut interval = tokio::time::interval(HA_SYNC_INTERVAL);
+        loop {
+            interval.tick().await;
+            let sessions = self.sessions().await;
+            let loc_rib = self.loc_rib.lock().await;
+            let mut update = HaUpdate {
+                full: sent_version.is_none(),
+                sessions,
+                ..Default::default()
+            };
+            if sent_version != Some(loc_rib.version()) {
+                update.routes = loc_rib
+                    .iter()
+                    .filter(|route| sent_routes.get(&route.network_address) != Some(route))
+                    .cloned()
+                    .collect();
+                update.withdrawn = sent_routes
+                    .keys()
+                    .filter(|network| loc_rib.get(network).is_none())
+                    .copied()
+                    .collect();
+                sent_version = Some(loc_rib.version());
+            }
+            drop(loc_rib);
+            for network in &update.withdrawn {
+                sent_routes.remove(network);
+            }
+            for route in &update.routes {
+                sent_routes.insert(route.network_address, route.clone());
+            }
+            write_update(&mut stream, &mut session, &update).await?;
+        }
+    }
+}
+
+/// Primaryから受け取ったLocRibの内容を、自分のLocRibに反映する。
+/// Standbyの間はピアとセッションを張らず、カーネルのルーティングテーブルにも書き込まない。
+#[derive(Debug)]
+pub struct HaSecondary {
+    loc_rib: Arc<Mutex<LocRib>>,
+    key: HaKey,
+    /// PrimaryからLocRibの全体を受信したか。
+    synced: bool,
+    sessions: Vec<(Ipv4Addr, State)>,
+}
+
+impl HaSecondary {
+    pub fn new(loc_rib: Arc<Mutex<LocRib>>, key: HaKey) -> Self {
+        Self {
+            loc_rib,
+            key,
+            synced: false,
+            sessions: vec![],
+        }
+    }
+
+    /// Primaryに接続し、LocRibの内容を受信し続ける。
+    /// 同期した後に、接続が切れるかHA_HOLD_TIMEの間何も受信しなくなった場合にエラーを返す。
+    /// 呼び出し側はその後にピアを開始し、Primaryの処理を引き継ぐ。
+    /// 一度も同期できていない間は、Primaryに接続できなくても引き継がずに接続し直す。
+    pub async fn run(&mut self, address: SocketAddr) -> Result<()> {
+        loop {
+            let result = match TcpStream::connect(address).await {
+                Ok(stream) => self.receive(stream).await,
+                Err(e) => Err(e).context(format!("Primary {}に接続できませんでした。", address)),
+            };
+            if self.synced {
+                return result;
+            }
+            if let Err(e) = result {
+                log_warning!("Primaryと同期できていないため、接続し直します。{:?}", e);
+            }
+            tokio::time::sleep(HA_SYNC_INTERVAL).await;
+        }
+    }
+
+    async fn receive<S: AsyncRead + AsyncWrite + Unpin>(&mut self, mut stream: S) -> Result<()> {
+        let mut session =
+            tokio::time::timeout(HA_HOLD_TIME, authenticate_primary(&self.key, &mut stream))
+                .await
+                .context("Primaryの認証が時間内に終わりませんでした。")??;
+        loop {
+            let update = tokio::time::timeout(HA_HOLD_TIME, read_update(&mut stream, &mut session))
+                .await
+                .context("Primaryからの受信が途絶えました。")??;
+            self.apply(update).await;
+        }
+    }
+
+    async fn apply(&mut self, update: HaUpdate) {
+        let mut loc_rib = self.loc_rib.lock().await;
+        if update.full {
+            loc_rib.replace_routes(update.routes);
+            self.synced = true;
+        } else if !update.routes.is_empty() || !update.withdrawn.is_empty() {
+            loc_rib.update_routes(update.routes, &update.withdrawn);
+        }
+        self.sessions = update.sessions;
+    }
+
+    /// 最後に受信した、Primaryのピアごとのセッションの状態。
+    pub fn sessions(&self) -> &[(Ipv4Addr, State)] {
+        &self.sessions
+    }
+}
+
+/// Secondaryが処理を引き継いでからHA_STALE_TIMEが経った後、ピアから受信し直していない
+/// Primaryのルートを、LocRibとカーネルのルーティングテーブルから削除する。
+/// End-of-RIBを送ってこないピアや、セッションを張り直せないピアのルートを残さないために使う。
+pub async fn flush_replicated_routes_after_stale_time(loc_rib: Arc<Mutex<LocRib>>, config: Config) {
+    tokio::time::sleep(HA_STALE_TIME).await;
+    let mut loc_rib = loc_rib.lock().await;
+    let version = loc_rib.version();
+    let removed = loc_rib.flush_replicated_routes();
+    if loc_rib.version() == version {
+        return;
+    }
+    log_info!(
+        "Primaryから同期した後に受信し直さなかったルートを削除し、{}個のネットワークを取り消しました。",
+        removed.len()
+    );
+    if let Err(e) = loc_rib.write_to_kernel_routing_table(&config).await {
+        log_error!("{:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::control::Neighbor;
+    use crate::peer::PeerHandle;
+    use crate::routing::AdjRibIn;
+    use crate::statistics::PeerStatistics;
+
+    fn key() -> HaKey {
+        HaKey::new("shared key")
+    }
+
+    #[test]
+    fn parse_ha_role() {
+        assert_eq!(
+            HaRole::parse("ha-secondary", "10.0.0.1:1790").unwrap(),
+            HaRole::Secondary("10.0.0.1:1790".parse().unwrap())
+        );
+        assert!(HaRole::parse("ha-primary", "10.0.0.1").is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn secondary_mirrors_primary_until_connection_is_lost() {
+        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let route = RibEntry::for_test("10.100.220.0/24").with_next_hop("127.0.0.2");
+        let mut loc_rib = LocRib::empty();
+        loc_rib.install_from_adj_rib_in(&mut AdjRibIn::from(vec![route.clone()]));
+        let statistics = PeerStatistics {
+            state: State::Established,
+            ..Default::default()
+        };
+        let primary_loc_rib = Arc::new(Mutex::new(loc_rib));
+        let primary = HaPrimary::new(
+            Arc::clone(&primary_loc_rib),
+            vec![Neighbor {
+                config,
+                statistics: Arc::new(Mutex::new(statistics)),
+                adj_rib_in: Arc::new(Mutex::new(AdjRibIn::new())),
+                message_capture: Default::default(),
+                handle: PeerHandle::detached(),
+            }],
+            key(),
+        );
+        let secondary_loc_rib = Arc::new(Mutex::new(LocRib::empty()));
+        let mut secondary = HaSecondary::new(Arc::clone(&secondary_loc_rib), key());
+
+        let (stream, mut reader) = tokio::io::duplex(4096);
+        let replication = tokio::spawn(async move { primary.replicate(stream).await });
+        let mut session = authenticate_primary(&key(), &mut reader).await.unwrap();
+        let update = read_update(&mut reader, &mut session).await.unwrap();
+        assert!(update.full);
+        secondary.apply(update).await;
+
+        assert_eq!(
+            secondary_loc_rib.lock().await.iter().collect::<Vec<_>>(),
+            vec![&route]
+        );
+        assert_eq!(
+            secondary.sessions(),
+            vec![("127.0.0.2".parse().unwrap(), State::Established)]
+        );
+
+        // 変化がなければ、ハートビートとして空の差分を送る。
+        let update = read_update(&mut reader, &mut session).await.unwrap();
+        assert!(!update.full);
+        assert!(update.routes.is_empty() && update.withdrawn.is_empty());
+
+        // LocRibが変わると、変わったルートだけを送る。
+        let added = RibEntry::for_test("10.100.221.0/24").with_next_hop("127.0.0.2");
+        {
+            let mut loc_rib = primary_loc_rib.lock().await;
+            loc_rib.install_from_adj_rib_in(&mut AdjRibIn::from(vec![added.clone()]));
+            loc_rib.remove_routes_learned_from(&AdjRibIn::from(vec![route.clone()]));
+        }
+        let update = read_update(&mut reader, &mut session).await.unwrap();
+        assert!(!update.full);
+        assert_eq!(update.routes, vec![added.clone()]);
+        assert_eq!(update.withdrawn, vec![route.network_address]);
+        secondary.apply(update).await;
+        assert_eq!(
+            secondary_loc_rib.lock().await.iter().collect::<Vec<_>>(),
+            vec![&added]
+        );
+
+        // Primaryが停止すると、receiveはエラーを返して引き継ぎを促す。
+        replication.abort();
+        let _ = replication.await;
+        assert!(secondary.receive(reader).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn secondary_detects_lost_heartbeat() {
+        let mut secondary = HaSecondary::new(Arc::new(Mutex::new(LocRib::empty())), key());
+        let (mut stream, reader) = tokio::io::duplex(4096);
+        let primary = async {
+            let mut session = authenticate_secondary(&key(), &mut stream).await.unwrap();
+            let full = HaUpdate {
+                full: true,
+                ..Default::default()
+            };
+            write_update(&mut stream, &mut session, &full)
+                .await
+                .unwrap();
+        };
+
+        // 接続が切れていなくても、HA_HOLD_TIMEの間何も受信しなければエラーにする。
+        let (received, _) = tokio::join!(secondary.receive(reader), primary);
+        assert!(received.is_err());
+        assert!(secondary.synced);
+        drop(stream);
+    }
+
+    #[tokio::test]
+    async fn primary_and_secondary_with_different_keys_do_not_sync() {
+        let (mut stream, mut reader) = tokio::io::duplex(4096);
+        // 認証に失敗した側は接続を切るため、相手も待ち続けずにエラーになる。
+        let (primary, secondary) = tokio::join!(
+            async move { authenticate_secondary(&key(), &mut stream).await },
+            async move { authenticate_primary(&HaKey::new("another key"), &mut reader).await }
+        );
+
+        assert!(secondary.is_err());
+        assert!(primary.is_err());
+    }
+
+    #[tokio::test]
+    async fn secondary_rejects_oversized_or_tampered_update() {
+        let key = key();
+        let (mut stream, mut reader) = tokio::io::duplex(4096);
+        let (primary, secondary) = tokio::join!(
+            authenticate_secondary(&key, &mut stream),
+            authenticate_primary(&key, &mut reader)
+        );
+        let (mut primary, mut secondary) = (primary.unwrap(), secondary.unwrap());
+
+        // 長さだけを送ってきても、上限を超えていればメモリを確保せずにエラーにする。
+        stream.write_u32(u32::MAX).await.unwrap();
+        assert!(read_update(&mut reader, &mut secondary).await.is_err());
+
+        // MACが一致しないHaUpdateは反映しない。
+        let update = HaUpdate {
+            full: true,
+            ..Default::default()
+        };
+        let bytes = update.to_bytes();
+        stream.write_u32(bytes.len() as u32).await.unwrap();
+        stream.write_all(&bytes).await.unwrap();
+        stream.write_all(&[0; 32]).await.unwrap();
+        assert!(read_update(&mut reader, &mut secondary).await.is_err());
+
+        // 正しいMACでも、番号が飛んでいれば再送や並べ替えとみなす。
+        primary.sequence += 1;
+        write_update(&mut stream, &mut primary, &update)
+            .await
+            .unwrap();
+        assert!(read_update(&mut reader, &mut secondary).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn secondary_does_not_take_over_before_first_sync() {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut secondary = HaSecondary::new(Arc::new(Mutex::new(LocRib::empty())), key());
+
+        // Primaryに接続できなくても、一度も同期していなければ接続し直し続ける。
+        let run = tokio::time::timeout(HA_SYNC_INTERVAL * 2, secondary.run(address)).await;
+        assert!(run.is_err());
+        assert!(!secondary.synced);
+    }
+
+    #[test]
+    fn ha_update_bytes_keep_routes_and_withdrawn_networks() {
+        let update = HaUpdate {
+            full: false,
+            sessions: vec![("127.0.0.2".parse().unwrap(), State::Established)],
+            routes: vec![RibEntry {
+                weight: 100,
+                ..RibEntry::for_test("10.100.220.0/24")
+            }],
+            withdrawn: vec![
+                "10.100.221.0/24".parse().unwrap(),
+                "10.100.222.0/23".parse().unwrap(),
+            ],
+        };
+        assert_eq!(HaUpdate::try_from(&update.to_bytes()[..]).unwrap(), update);
+    }
+}
@@ -0,0 +1,243 @@
+use crate::bgp_type::{AutonomousSystemNumber, HoldTime};
+use crate::clock::MockClock;
+use crate::config::{Config, Mode};
+use crate::connection::{Connection, InMemoryTransport};
+use crate::event::Event;
+use crate::packets::message::Message;
+use crate::peer::Peer;
+use crate::routing::{AdjRibIn, LocRib};
+use crate::state::State;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// 1つのPeerのFSMを単体でテストするためのハーネス。
+/// 対向機器の代わりにインメモリのConnectionを持ち、任意のEventの注入、
+/// 対向機器からのMessageの送信、MockClockによる時刻の操作を行える。
+/// Peerが送信したMessageはsent_messagesで取り出して確認する。
+pub struct PeerHarness {
+    pub peer: Peer,
+    pub clock: Arc<MockClock>,
+    loc_rib: Arc<Mutex<LocRib>>,
+    /// 対向機器の側のConnection。disconnectで閉じるとNoneになる。
+    remote: Option<Connection>,
+    /// Peerが送信し、まだsent_messagesで取り出していないMessage。
+    sent: Vec<Message>,
+}
+
+impl PeerHarness {
+    pub async fn new(config: Config) -> Self {
+        let (local_transport, remote_transport) = InMemoryTransport::pair();
+        let loc_rib = Arc::new(Mutex::new(LocRib::empty()));
+        let clock = Arc::new(MockClock::new());
+        let mut remote_config = config.clone();
+        remote_config.mode = match config.mode {
+            Mode::Active => Mode::Passive,
+            Mode::Passive | Mode::Both => Mode::Active,
+        };
+        let remote = Connection::connect(&remote_transport, &remote_config)
+            .await
+            .expect("InMemoryTransportのストリームは1度だけ取り出している。");
+
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.set_transport(Arc::new(local_transport));
+        peer.set_clock(clock.clone());
+        Self {
+            peer,
+            clock,
+            loc_rib,
+            remote: Some(remote),
+            sent: vec![],
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.peer.state()
+    }
+
+    /// PeerのEventのキューにeventを追加する。処理されるのはstepを呼んだときである。
+    pub fn inject(&mut self, event: Event) {
+        self.peer.enqueue_event(event);
+    }
+
+    /// 対向機器からmessageを送信する。
+    pub async fn receive(&mut self, message: Message) {
+        self.remote
+            .as_mut()
+            .expect("disconnectした後はMessageを送信できません。")
+            .send(message)
+            .await;
+        // Connectionの書き込みタスクに、messageを書き込ませる。
+        tokio::task::yield_now().await;
+    }
+
+    /// Peerをsteps回進め、その間にPeerが送信したMessageを受け取る。
+    pub async fn step(&mut self, steps: usize) {
+        for _ in 0..steps {
+            self.peer.next().await;
+            self.collect_sent_messages().await;
+        }
+    }
+
+    /// Peerが指定した状態になるまで、最大max_steps回ステップを進める。
+    /// 指定した状態になったらtrueを返す。
+    pub async fn run_until(&mut self, state: State, max_steps: usize) -> bool {
+        for _ in 0..max_steps {
+            if self.state() == state {
+                return true;
+            }
+            self.step(1).await;
+        }
+        self.state() == state
+    }
+
+    /// 時刻をdurationだけ進める。期限切れになったタイマーのEventは次のstepで発生する。
+    pub fn advance(&self, duration: Duration) {
+        self.clock.advance(duration);
+    }
+
+    /// Peerが送信したMessageを、送信した順に取り出す。
+    pub async fn sent_messages(&mut self) -> Vec<Message> {
+        self.collect_sent_messages().await;
+        std::mem::take(&mut self.sent)
+    }
+
+    /// 対向機器の側からTCP Connectionを閉じる。
+    pub fn disconnect(&mut self) {
+        self.remote = None;
+    }
+
+    async fn collect_sent_messages(&mut self) {
+        // Peerの書き込みタスクに、送信したMessageを書き込ませる。
+        tokio::task::yield_now().await;
+        let remote = match self.remote.as_mut() {
+            Some(remote) => remote,
+            None => return,
+        };
+        while let Some(message) = remote.get_message().await {
+            self.sent
+                .push(message.expect("Peerが送信したデータをMessageに変換できませんでした。"));
+        }
+    }
+
+    pub fn loc_rib(&self) -> Arc<Mutex<LocRib>> {
+        Arc::clone(&self.loc_rib)
+    }
+
+    pub fn adj_rib_in(&self) -> Arc<Mutex<AdjRibIn>> {
+        self.peer.adj_rib_in()
+    }
+
+    /// ManualStartからOpenMessage、KeepaliveMessageを交換してEstablishedにする。
+    /// 交換したMessageは取り出して捨てる。
+    pub async fn establish(&mut self, remote_as: AutonomousSystemNumber, remote_ip: Ipv4Addr) {
+        self.peer.start();
+        assert!(self.run_until(State::OpenSent, 10).await);
+        self.receive(Message::new_open(remote_as, remote_ip, HoldTime::new()))
+            .await;
+        assert!(self.run_until(State::OpenConfirm, 10).await);
+        self.receive(Message::new_keepalive()).await;
+        assert!(self.run_until(State::Established, 10).await);
+        self.sent_messages().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::notification::{finite_state_machine_error, ErrorCode};
+    use crate::packets::open::OpenMessage;
+    use crate::packets::update::UpdateMessage;
+    use crate::path_attribute::{AsPath, Origin, PathAttribute};
+
+    async fn harness() -> PeerHarness {
+        let config = "64512 127.0.0.1 64513 127.0.0.2 active hold-time 90"
+            .parse()
+            .unwrap();
+        PeerHarness::new(config).await
+    }
+
+    async fn established_harness() -> PeerHarness {
+        let mut harness = harness().await;
+        harness
+            .establish(64513.into(), "127.0.0.2".parse().unwrap())
+            .await;
+        harness
+    }
+
+    #[tokio::test]
+    async fn peer_sends_open_and_keepalive_while_establishing() {
+        let mut harness = harness().await;
+        harness.peer.start();
+        assert!(harness.run_until(State::OpenSent, 10).await);
+        let sent = harness.sent_messages().await;
+        assert!(matches!(sent[..], [Message::Open(_)]));
+
+        harness
+            .receive(Message::new_open(
+                64513.into(),
+                "127.0.0.2".parse().unwrap(),
+                HoldTime::new(),
+            ))
+            .await;
+        assert!(harness.run_until(State::OpenConfirm, 10).await);
+        let sent = harness.sent_messages().await;
+        assert!(matches!(sent[..], [Message::Keepalive(_)]));
+    }
+
+    #[tokio::test]
+    async fn hold_timer_expiry_sends_notification() {
+        let mut harness = established_harness().await;
+
+        harness.advance(Duration::from_secs(90));
+        assert!(harness.run_until(State::Idle, 5).await);
+        match &harness.sent_messages().await[..] {
+            [.., Message::Notification(notification)] => {
+                assert_eq!(notification.error_code(), ErrorCode::HoldTimerExpired)
+            }
+            sent => panic!("NotificationMessageを送信していません。{:?}", sent),
+        }
+    }
+
+    #[tokio::test]
+    async fn injected_open_in_established_is_fsm_error() {
+        let mut harness = established_harness().await;
+
+        let open = OpenMessage::new(64513.into(), "127.0.0.2".parse().unwrap(), HoldTime::new());
+        harness.inject(Event::BgpOpen(open));
+
+        assert!(harness.run_until(State::Idle, 5).await);
+        match &harness.sent_messages().await[..] {
+            [.., Message::Notification(notification)] => assert_eq!(
+                notification.error_subcode(),
+                finite_state_machine_error::RECEIVE_UNEXPECTED_MESSAGE_IN_ESTABLISHED_STATE
+            ),
+            sent => panic!("NotificationMessageを送信していません。{:?}", sent),
+        }
+    }
+
+    #[tokio::test]
+    async fn received_update_is_installed_to_loc_rib() {
+        let mut harness = established_harness().await;
+
+        let update = UpdateMessage::new(
+            vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::AsSequence(vec![64513.into()])),
+                PathAttribute::NextHop("127.0.0.2".parse().unwrap()),
+            ],
+            vec!["10.100.220.0/24".parse().unwrap()],
+            vec![],
+        );
+        harness.receive(Message::Update(update)).await;
+        harness.step(5).await;
+
+        assert_eq!(harness.adj_rib_in().lock().await.len(), 1);
+        let loc_rib = harness.loc_rib();
+        let loc_rib = loc_rib.lock().await;
+        assert!(loc_rib
+            .iter()
+            .any(|r| r.network_address == "10.100.220.0/24".parse().unwrap()));
+    }
+}
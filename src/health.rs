@@ -0,0 +1,244 @@
+use crate::control::NeighborList;
+use crate::control_auth;
+use crate::log_error;
+use crate::state::State;
+use crate::tls;
+use anyhow::{Context, Result};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+/// KubernetesのprobeやsystemdのwatchdogからBGPの収束を確認するための、HTTPのエンドポイント。
+/// `/healthz`はプロセスが動いていれば、`/readyz`は必要な数のセッションが
+/// Establishedであれば200を返す。
+#[derive(Debug, Clone)]
+pub struct HealthServer {
+    neighbors: NeighborList,
+    /// readyとするのに必要なEstablishedのセッションの数。Noneの場合はすべてのセッション。
+    quorum: Option<usize>,
+    tls: Option<tls::TlsConfig>,
+}
+
+/// HTTPのレスポンスのステータスコードと本文。
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    pub(crate) body: String,
+}
+
+impl Response {
+    pub(crate) fn new(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            body: body.into(),
+        }
+    }
+
+    /// レスポンスを書き込んで接続を閉じる。head_onlyの場合は本文を書き込まない。
+    pub(crate) async fn write<S>(&self, stream: &mut S, head_only: bool) -> Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let mut bytes = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.reason(),
+            self.body.len()
+        );
+        if !head_only {
+            bytes += &self.body;
+        }
+        stream.write_all(bytes.as_bytes()).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            201 => "Created",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            409 => "Conflict",
+            503 => "Service Unavailable",
+            _ => "",
+        }
+    }
+}
+
+impl HealthServer {
+    pub fn new(neighbors: impl Into<NeighborList>, quorum: Option<usize>) -> Self {
+        Self {
+            neighbors: neighbors.into(),
+            quorum,
+            tls: None,
+        }
+    }
+
+    /// リクエストをconfigのTLSの接続で受け付ける。
+    pub fn set_tls(&mut self, config: tls::TlsConfig) {
+        self.tls = Some(config);
+    }
+
+    pub async fn serve(self, address: SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(address)
+            .await
+            .context(format!("{}にbindできませんでした。", address))?;
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                let result = match tls::accept(server.tls.as_ref(), stream).await {
+                    Ok(stream) => server.handle_client(stream).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    log_error!("ヘルスチェックのリクエストの処理に失敗しました。{:?}", e);
+                }
+            });
+        }
+    }
+
+    /// 1つのリクエストに応答し、接続を閉じる。
+    async fn handle_client<S>(&self, stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut stream = BufReader::new(stream);
+        let request = async {
+            let request_line = control_auth::read_line(&mut stream).await?;
+            // ヘッダは使わないので、空行まで読み飛ばす。
+            control_auth::read_headers(&mut stream).await?;
+            anyhow::Ok(request_line)
+        };
+        let request_line = match tokio::time::timeout(control_auth::REQUEST_TIMEOUT, request).await
+        {
+            Ok(request_line) => request_line?,
+            Err(_) => {
+                return Response::new(408, "request timeout\n")
+                    .write(stream.get_mut(), false)
+                    .await
+            }
+        };
+        let response = self.handle_request(&request_line).await;
+        let head_only = request_line.starts_with("HEAD ");
+        response.write(stream.get_mut(), head_only).await
+    }
+
+    async fn handle_request(&self, request_line: &str) -> Response {
+        let (method, path) = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+            [method, path, ..] => (method, path),
+            _ => return Response::new(404, "not found\n"),
+        };
+        if method != "GET" && method != "HEAD" {
+            return Response::new(405, "method not allowed\n");
+        }
+        match path {
+            "/healthz" => Response::new(200, "ok\n"),
+            "/readyz" => self.readiness().await,
+            _ => Response::new(404, "not found\n"),
+        }
+    }
+
+    async fn readiness(&self) -> Response {
+        let neighbors = self.neighbors.snapshot();
+        let mut established = 0;
+        for neighbor in &neighbors {
+            if neighbor.statistics.lock().await.state == State::Established {
+                established += 1;
+            }
+        }
+        let required = self
+            .quorum
+            .map_or(neighbors.len(), |quorum| quorum.min(neighbors.len()));
+        let summary = format!(
+            "{}/{} sessions established ({} required)\n",
+            established,
+            neighbors.len(),
+            required
+        );
+        if established >= required {
+            Response::new(200, format!("ready: {}", summary))
+        } else {
+            Response::new(503, format!("not ready: {}", summary))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::Neighbor;
+    use crate::peer::PeerHandle;
+    use crate::routing::AdjRibIn;
+    use crate::statistics::PeerStatistics;
+    use std::sync::Arc;
+    use tokio::io::AsyncReadExt;
+    use tokio::sync::Mutex;
+
+    fn neighbor(state: State) -> Neighbor {
+        Neighbor {
+            config: "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap(),
+            statistics: Arc::new(Mutex::new(PeerStatistics {
+                state,
+                ..Default::default()
+            })),
+            adj_rib_in: Arc::new(Mutex::new(AdjRibIn::new())),
+            message_capture: Default::default(),
+            handle: PeerHandle::detached(),
+        }
+    }
+
+    #[tokio::test]
+    async fn readyz_requires_all_sessions_or_quorum_to_be_established() {
+        let neighbors = vec![neighbor(State::Established), neighbor(State::Connect)];
+
+        let server = HealthServer::new(neighbors.clone(), None);
+        assert_eq!(
+            server.handle_request("GET /readyz HTTP/1.1\r\n").await,
+            Response::new(503, "not ready: 1/2 sessions established (2 required)\n")
+        );
+        let server = HealthServer::new(neighbors, Some(1));
+        assert_eq!(
+            server.handle_request("GET /readyz HTTP/1.1\r\n").await,
+            Response::new(200, "ready: 1/2 sessions established (1 required)\n")
+        );
+        assert_eq!(
+            server
+                .handle_request("POST /readyz HTTP/1.1\r\n")
+                .await
+                .status,
+            405
+        );
+        assert_eq!(
+            server
+                .handle_request("GET /metrics HTTP/1.1\r\n")
+                .await
+                .status,
+            404
+        );
+    }
+
+    #[tokio::test]
+    async fn healthz_responds_over_http() {
+        let server = HealthServer::new(vec![neighbor(State::Idle)], None);
+        let (mut client, stream) = tokio::io::duplex(1024);
+        let handle = tokio::spawn(async move { server.handle_client(stream).await });
+
+        client
+            .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        handle.await.unwrap().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nok\n"));
+    }
+}
@@ -0,0 +1,104 @@
+use crate::state::State;
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 状態遷移の履歴のデフォルトの保持件数。
+pub const DEFAULT_STATE_HISTORY_SIZE: usize = 32;
+
+/// 1回の状態遷移の記録。
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct StateTransition {
+    pub timestamp: SystemTime,
+    pub old_state: State,
+    pub new_state: State,
+    /// 状態遷移のきっかけになったEventの名前。
+    pub event: &'static str,
+    pub error: Option<String>,
+}
+
+impl fmt::Display for StateTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let timestamp = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        write!(
+            f,
+            "{}.{:03} {:?} -> {:?} ({})",
+            timestamp.as_secs(),
+            timestamp.subsec_millis(),
+            self.old_state,
+            self.new_state,
+            self.event
+        )?;
+        if let Some(error) = &self.error {
+            write!(f, " error: {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// 直近capacity件の状態遷移を保持するリングバッファ。
+/// セッションがflapした後に原因を調べるために使う。
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct StateHistory {
+    transitions: VecDeque<StateTransition>,
+    capacity: usize,
+}
+
+impl StateHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            transitions: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, transition: StateTransition) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.transitions.len() == self.capacity {
+            self.transitions.pop_front();
+        }
+        self.transitions.push_back(transition);
+    }
+
+    /// 古いものから順に状態遷移を返す。
+    pub fn iter(&self) -> impl Iterator<Item = &StateTransition> {
+        self.transitions.iter()
+    }
+}
+
+impl Default for StateHistory {
+    fn default() -> Self {
+        Self::new(DEFAULT_STATE_HISTORY_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transition(new_state: State) -> StateTransition {
+        StateTransition {
+            timestamp: SystemTime::now(),
+            old_state: State::Idle,
+            new_state,
+            event: "ManualStart",
+            error: None,
+        }
+    }
+
+    #[test]
+    fn state_history_keeps_only_latest_transitions() {
+        let mut history = StateHistory::new(2);
+        history.push(transition(State::Connect));
+        history.push(transition(State::OpenSent));
+        history.push(transition(State::OpenConfirm));
+
+        let states: Vec<State> = history.iter().map(|t| t.new_state).collect();
+        assert_eq!(states, vec![State::OpenSent, State::OpenConfirm]);
+    }
+}
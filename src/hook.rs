@@ -0,0 +1,54 @@
+use crate::bgp_type::AutonomousSystemNumber;
+use crate::config::Config;
+use crate::log_info;
+use crate::packets::message::Message;
+use crate::state::State;
+use std::fmt;
+
+/// MessageHookに渡す、メッセージを送受信したピアの情報。
+#[derive(Debug, Clone, Copy)]
+pub struct PeerContext<'a> {
+    pub config: &'a Config,
+    pub state: State,
+    /// 受け入れたOpenMessageに含まれていた対向機器のAS番号。
+    pub remote_as: Option<AutonomousSystemNumber>,
+}
+
+/// Peerが送受信するすべてのMessageに対して呼び出されるフック。
+/// Peerのコードを変更せずに、監査ログ、故意に壊れたメッセージを送るカオステスト、
+/// Path Attributeの付与などを追加できる。
+///
+/// 変更したMessageを返すと、Peerは元のMessageの代わりにそれを扱い、
+/// Noneを返すとMessageを破棄する。
+/// Peerに複数のフックを追加した場合は、追加した順に呼び出す。
+pub trait MessageHook: fmt::Debug + Send + Sync {
+    /// 受信したMessageをFSMで処理する前に呼び出される。
+    fn on_inbound(&self, _context: &PeerContext, message: Message) -> Option<Message> {
+        Some(message)
+    }
+
+    /// Messageを送信する前に呼び出される。
+    fn on_outbound(&self, _context: &PeerContext, message: Message) -> Option<Message> {
+        Some(message)
+    }
+}
+
+/// 送受信したMessageを標準出力に出すMessageHook。
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AuditLog;
+
+impl MessageHook for AuditLog {
+    fn on_inbound(&self, context: &PeerContext, message: Message) -> Option<Message> {
+        log_info!(
+            "{} から受信しました: {:?}",
+            context.config.remote_ip,
+            message
+        );
+        Some(message)
+    }
+
+    fn on_outbound(&self, context: &PeerContext, message: Message) -> Option<Message> {
+        log_info!("{} へ送信します: {:?}", context.config.remote_ip, message);
+        Some(message)
+    }
+}
@@ -2,14 +2,112 @@
 #![feature(backtrace, exclusive_range_pattern)]
 #![allow(dead_code, unused)]
 
-mod bgp_type;
+//! BGPのメッセージのエンコード・デコードを行うcodecと、その上に作られたBGPのデーモン。
+//! `default-features = false`でビルドすると、tokioやrtnetlinkに依存しないcodecのみを使える。
+
+// codec: BGPのメッセージとその構成要素。
+pub mod bgp_type;
+pub mod error;
+pub mod packets;
+pub mod path_attribute;
+pub mod state;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+
+// daemon: FSM、RIB、カーネルのルーティングテーブルの操作など。
+#[cfg(feature = "daemon")]
+pub mod aspa;
+#[cfg(feature = "daemon")]
+pub mod bench;
+#[cfg(feature = "daemon")]
+pub mod bgpdump;
+#[cfg(feature = "daemon")]
+mod capture;
+#[cfg(feature = "daemon")]
+pub mod churn;
+#[cfg(feature = "daemon")]
+mod clock;
+#[cfg(feature = "daemon")]
 pub mod config;
+#[cfg(feature = "daemon")]
 mod connection;
-mod error;
+#[cfg(feature = "daemon")]
+pub mod control;
+#[cfg(feature = "daemon")]
+pub mod control_auth;
+#[cfg(feature = "daemon")]
+mod debug;
+#[cfg(feature = "daemon")]
+pub mod discovery;
+#[cfg(feature = "daemon")]
+pub mod dry_run;
+#[cfg(feature = "daemon")]
 mod event;
+#[cfg(feature = "daemon")]
 mod event_queue;
-mod packets;
-mod path_attribute;
+#[cfg(feature = "daemon")]
+pub mod fib;
+#[cfg(feature = "daemon")]
+pub mod flood;
+#[cfg(feature = "daemon")]
+pub mod ha;
+#[cfg(all(test, feature = "daemon"))]
+mod harness;
+#[cfg(feature = "daemon")]
+pub mod health;
+#[cfg(feature = "daemon")]
+mod history;
+#[cfg(feature = "daemon")]
+pub mod hook;
+#[cfg(feature = "daemon")]
+pub mod log;
+#[cfg(feature = "daemon")]
+pub mod memory;
+#[cfg(feature = "daemon")]
+pub mod monitor;
+#[cfg(feature = "daemon")]
+mod mrt;
+#[cfg(feature = "daemon")]
+pub mod otel;
+#[cfg(feature = "daemon")]
 pub mod peer;
+#[cfg(feature = "daemon")]
+pub mod peer_manager;
+#[cfg(feature = "daemon")]
+mod policy;
+#[cfg(feature = "daemon")]
+pub mod policy_file;
+#[cfg(feature = "daemon")]
+mod prefix_trie;
+#[cfg(feature = "quic")]
+mod quic;
+#[cfg(feature = "daemon")]
+mod rate_limit;
+#[cfg(feature = "daemon")]
+pub mod replay;
+#[cfg(feature = "daemon")]
+pub mod rib_diff;
+#[cfg(feature = "daemon")]
+pub mod rib_store;
+#[cfg(feature = "daemon")]
 pub mod routing;
-mod state;
+#[cfg(feature = "scripting")]
+mod script;
+#[cfg(all(test, feature = "daemon"))]
+mod simulation;
+#[cfg(feature = "daemon")]
+mod statistics;
+#[cfg(feature = "daemon")]
+pub mod table_dump;
+#[cfg(feature = "daemon")]
+mod timer;
+#[cfg(feature = "daemon")]
+pub mod tls;
+#[cfg(feature = "daemon")]
+pub mod trace;
+#[cfg(feature = "daemon")]
+pub mod update_delay;
+#[cfg(feature = "daemon")]
+pub mod update_group;
+#[cfg(feature = "daemon")]
+pub mod vip;
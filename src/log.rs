@@ -0,0 +1,411 @@
+use crate::error::ConfigParseError;
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// RFC 5424のAPP-NAME。
+const APP_NAME: &str = "how-to-create-bgp";
+/// RFC 5424のfacilityのうち、システムのデーモンを表すdaemon(3)。
+const FACILITY_DAEMON: u8 = 3;
+/// TCPのsyslogコレクタへの書き込みが詰まった場合に、BGPの処理を待たせる最大の時間。
+const TCP_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+/// TCPのsyslogコレクタへの接続を待つ最大の時間。
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// 接続に失敗してから、次に接続を試みるまでの最初の間隔。失敗が続くと2倍ずつ延ばす。
+const INITIAL_RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+const MAXIMUM_RECONNECT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// デーモンのログを書き出す先。`log_info!`などのマクロはここに書き出す。
+static LOGGER: RwLock<Logger> = RwLock::new(Logger {
+    stdout: true,
+    syslog: None,
+});
+
+/// RFC 5424のseverityのうち、デーモンのログで使うもの。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error = 3,
+    Warning = 4,
+    Informational = 6,
+    Debug = 7,
+}
+
+/// ログを標準出力とsyslogのどちらに書き出すか。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum LogOutput {
+    Stdout,
+    Syslog,
+    Both,
+}
+
+impl FromStr for LogOutput {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stdout" => Ok(LogOutput::Stdout),
+            "syslog" => Ok(LogOutput::Syslog),
+            "both" => Ok(LogOutput::Both),
+            _ => Err(ConfigParseError::InvalidValue {
+                kind: "log output",
+                value: s.to_owned(),
+            }),
+        }
+    }
+}
+
+/// ログを送るsyslogのコレクタ。
+/// `unix:<path>`はローカルのsyslogのソケット、`udp:<address>`と`tcp:<address>`はリモートのコレクタである。
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum SyslogDestination {
+    Unix(PathBuf),
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+}
+
+impl FromStr for SyslogDestination {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ConfigParseError::InvalidValue {
+            kind: "syslog destination",
+            value: s.to_owned(),
+        };
+        match s.split_once(':') {
+            Some(("unix", path)) if !path.is_empty() => Ok(Self::Unix(PathBuf::from(path))),
+            Some(("udp", address)) => Ok(Self::Udp(address.parse().map_err(|_| invalid())?)),
+            Some(("tcp", address)) => Ok(Self::Tcp(address.parse().map_err(|_| invalid())?)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum SyslogConnection {
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+/// syslogのコレクタとの接続と、失敗した場合の再接続の待ち時間。
+#[derive(Debug)]
+struct SyslogState {
+    /// 送信に失敗した場合はNoneにして、次のログを送るときに接続し直す。
+    connection: Option<SyslogConnection>,
+    /// この時刻までは接続を試みない。
+    next_attempt: Option<Instant>,
+    reconnect_interval: Duration,
+}
+
+impl SyslogState {
+    /// 接続か送信に失敗したので、次に接続を試みる時刻を延ばす。
+    fn back_off(&mut self) {
+        self.connection = None;
+        self.next_attempt = Some(Instant::now() + self.reconnect_interval);
+        self.reconnect_interval = (self.reconnect_interval * 2).min(MAXIMUM_RECONNECT_INTERVAL);
+    }
+}
+
+/// RFC 5424の形式で、1つのログを1つのメッセージとしてsyslogのコレクタに送る。
+/// ログはBGPの処理から同期的に書き出すため、コレクタに接続できない間は、
+/// 待ち時間を延ばしながら接続を試み、ログごとに接続を待たせないようにする。
+#[derive(Debug)]
+pub struct Syslog {
+    destination: SyslogDestination,
+    hostname: String,
+    state: Mutex<SyslogState>,
+}
+
+impl Syslog {
+    pub fn new(destination: SyslogDestination) -> Self {
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|h| h.trim().to_owned())
+            .unwrap_or_default();
+        Self {
+            destination,
+            hostname,
+            state: Mutex::new(SyslogState {
+                connection: None,
+                next_attempt: None,
+                reconnect_interval: INITIAL_RECONNECT_INTERVAL,
+            }),
+        }
+    }
+
+    fn connect(&self) -> Result<SyslogConnection> {
+        let connection = match &self.destination {
+            SyslogDestination::Unix(path) => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                SyslogConnection::Unix(socket)
+            }
+            SyslogDestination::Udp(address) => {
+                let local: SocketAddr = if address.is_ipv4() {
+                    "0.0.0.0:0".parse()?
+                } else {
+                    "[::]:0".parse()?
+                };
+                let socket = UdpSocket::bind(local)?;
+                socket.connect(address)?;
+                SyslogConnection::Udp(socket)
+            }
+            SyslogDestination::Tcp(address) => {
+                let stream = TcpStream::connect_timeout(address, TCP_CONNECT_TIMEOUT)?;
+                stream.set_write_timeout(Some(TCP_WRITE_TIMEOUT))?;
+                SyslogConnection::Tcp(stream)
+            }
+        };
+        Ok(connection)
+    }
+
+    pub fn send(&self, severity: Severity, message: &str) -> Result<()> {
+        let line = format_rfc5424(
+            severity,
+            SystemTime::now(),
+            &self.hostname,
+            std::process::id(),
+            message,
+        );
+        let mut state = self.state.lock().unwrap();
+        if state.connection.is_none() {
+            if state.next_attempt.is_some_and(|next| Instant::now() < next) {
+                return Err(anyhow::anyhow!(
+                    "syslogのコレクタ{:?}に再接続するまで待っています。",
+                    self.destination
+                ));
+            }
+            match self.connect() {
+                Ok(connection) => {
+                    state.connection = Some(connection);
+                    state.next_attempt = None;
+                    state.reconnect_interval = INITIAL_RECONNECT_INTERVAL;
+                }
+                Err(e) => {
+                    state.back_off();
+                    return Err(e.context(format!(
+                        "syslogのコレクタ{:?}に接続できませんでした。",
+                        self.destination
+                    )));
+                }
+            }
+        }
+        let result = match state.connection.as_mut().unwrap() {
+            SyslogConnection::Unix(socket) => socket.send(line.as_bytes()).map(|_| ()),
+            SyslogConnection::Udp(socket) => socket.send(line.as_bytes()).map(|_| ()),
+            // RFC 6587のoctet countingで、メッセージの前にバイト数を付けて区切る。
+            SyslogConnection::Tcp(stream) => {
+                stream.write_all(format!("{} {}", line.len(), line).as_bytes())
+            }
+        };
+        if result.is_err() {
+            state.back_off();
+        }
+        result.context("syslogのコレクタにログを送れませんでした。")
+    }
+}
+
+/// ログの書き出し先。
+#[derive(Debug)]
+struct Logger {
+    stdout: bool,
+    syslog: Option<Syslog>,
+}
+
+/// これ以降のログを、outputに従って標準出力とsyslogに書き出す。
+/// syslogに書き出す場合は、destinationを指定する必要がある。
+pub fn init(output: LogOutput, destination: Option<SyslogDestination>) {
+    let syslog = match output {
+        LogOutput::Stdout => None,
+        LogOutput::Syslog | LogOutput::Both => destination.map(Syslog::new),
+    };
+    *LOGGER.write().unwrap() = Logger {
+        stdout: output != LogOutput::Syslog || syslog.is_none(),
+        syslog,
+    };
+}
+
+/// `log_info!`などのマクロから呼び出す。
+/// syslogに送れなかった場合は、ログを失わないように標準出力に書き出す。
+pub fn write(severity: Severity, message: &str) {
+    let logger = LOGGER.read().unwrap();
+    let sent = match &logger.syslog {
+        Some(syslog) => match syslog.send(severity, message) {
+            Ok(()) => true,
+            Err(e) => {
+                println!("{:?}", e);
+                false
+            }
+        },
+        None => false,
+    };
+    if logger.stdout || !sent {
+        println!("{}", message);
+    }
+}
+
+/// RFC 5424のメッセージにする。MSGIDとSTRUCTURED-DATAは使わない。
+fn format_rfc5424(
+    severity: Severity,
+    time: SystemTime,
+    hostname: &str,
+    process_id: u32,
+    message: &str,
+) -> String {
+    let hostname = if hostname.is_empty() { "-" } else { hostname };
+    format!(
+        "<{}>1 {} {} {} {} - - {}",
+        FACILITY_DAEMON * 8 + severity as u8,
+        format_timestamp(time),
+        hostname,
+        APP_NAME,
+        process_id,
+        message
+    )
+}
+
+/// UTCの`YYYY-MM-DDThh:mm:ss.ssssssZ`の形式にする。
+fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        seconds % 86400 / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+        since_epoch.subsec_micros()
+    )
+}
+
+/// 1970-01-01からの日数を、グレゴリオ暦の年月日にする。
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let month = month as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// 通常の動作を表すログを書き出す。
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Severity::Informational, &format!($($arg)*))
+    };
+}
+
+/// 処理を続けられるが、運用者が確認すべきログを書き出す。
+#[macro_export]
+macro_rules! log_warning {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Severity::Warning, &format!($($arg)*))
+    };
+}
+
+/// 処理に失敗したことを表すログを書き出す。
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Severity::Error, &format!($($arg)*))
+    };
+}
+
+/// debugオプションで有効にした、詳細なログを書き出す。
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Severity::Debug, &format!($($arg)*))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_is_formatted_as_rfc5424() {
+        let time = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        assert_eq!(
+            format_rfc5424(Severity::Warning, time, "router1", 42, "hello"),
+            "<28>1 2023-11-14T22:13:20.123456Z router1 how-to-create-bgp 42 - - hello"
+        );
+        assert_eq!(
+            format_rfc5424(Severity::Error, UNIX_EPOCH, "", 1, "x"),
+            "<27>1 1970-01-01T00:00:00.000000Z - how-to-create-bgp 1 - - x"
+        );
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+    }
+
+    #[test]
+    fn destination_is_parsed_with_transport_prefix() {
+        assert_eq!(
+            "unix:/dev/log".parse::<SyslogDestination>().unwrap(),
+            SyslogDestination::Unix(PathBuf::from("/dev/log"))
+        );
+        assert_eq!(
+            "udp:192.0.2.1:514".parse::<SyslogDestination>().unwrap(),
+            SyslogDestination::Udp("192.0.2.1:514".parse().unwrap())
+        );
+        assert_eq!(
+            "tcp:192.0.2.1:601".parse::<SyslogDestination>().unwrap(),
+            SyslogDestination::Tcp("192.0.2.1:601".parse().unwrap())
+        );
+        assert!("192.0.2.1:514".parse::<SyslogDestination>().is_err());
+        assert!("udp:collector".parse::<SyslogDestination>().is_err());
+    }
+
+    #[test]
+    fn syslog_sends_one_datagram_per_message() {
+        let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let syslog = Syslog::new(SyslogDestination::Udp(collector.local_addr().unwrap()));
+
+        syslog
+            .send(Severity::Informational, "セッションを確立しました。")
+            .unwrap();
+
+        let mut buffer = [0; 1024];
+        let n = collector.recv(&mut buffer).unwrap();
+        let message = std::str::from_utf8(&buffer[..n]).unwrap();
+        assert!(message.starts_with("<30>1 "));
+        assert!(message.ends_with(" - - セッションを確立しました。"));
+    }
+
+    #[test]
+    fn unreachable_collector_is_not_reconnected_until_interval_passes() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let syslog = Syslog::new(SyslogDestination::Tcp(address));
+
+        let e = syslog.send(Severity::Error, "x").unwrap_err();
+        assert!(format!("{:#}", e).contains("接続できませんでした"));
+        let e = syslog.send(Severity::Error, "x").unwrap_err();
+        assert!(format!("{:#}", e).contains("再接続するまで待っています"));
+        assert_eq!(
+            syslog.state.lock().unwrap().reconnect_interval,
+            INITIAL_RECONNECT_INTERVAL * 2
+        );
+    }
+}
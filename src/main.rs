@@ -1,33 +1,226 @@
+use how_to_create_bgp::aspa::AspaTable;
+use how_to_create_bgp::churn::ChurnTracker;
 use how_to_create_bgp::config::Config;
-use how_to_create_bgp::peer::Peer;
+use how_to_create_bgp::control::ControlServer;
+use how_to_create_bgp::control_auth::ControlTokens;
+use how_to_create_bgp::discovery;
+use how_to_create_bgp::dry_run::dry_run;
+use how_to_create_bgp::ha::{
+    flush_replicated_routes_after_stale_time, HaKey, HaPrimary, HaRole, HaSecondary,
+};
+use how_to_create_bgp::health::HealthServer;
+use how_to_create_bgp::monitor::Monitor;
+use how_to_create_bgp::otel::OtlpExporter;
+use how_to_create_bgp::peer_manager::{propagate_loc_rib_changes, PeerManager};
+use how_to_create_bgp::policy_file::{self, PolicySet};
+use how_to_create_bgp::rib_store;
+use how_to_create_bgp::routing::LocRib;
+use how_to_create_bgp::table_dump::TableDumpScheduler;
+use how_to_create_bgp::tls;
+use how_to_create_bgp::trace::Tracer;
+use how_to_create_bgp::update_delay;
+use how_to_create_bgp::vip::VipServer;
+use how_to_create_bgp::{log, log_error, log_info, log_warning};
 use std::env;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
 
 #[tokio::main]
 async fn main() {
-    let config = env::args().skip(1).fold("".to_owned(), |mut acc, s| {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // `--dry-run [--mrt <file>]`の場合は、ピアと接続せずに広告するルートを表示して終了する。
+    let dry_run_mode = match args.iter().position(|a| a == "--dry-run") {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    };
+    let mrt = match args.iter().position(|a| a == "--mrt") {
+        Some(i) if i + 1 < args.len() => {
+            let path = args.remove(i + 1);
+            args.remove(i);
+            Some(PathBuf::from(path))
+        }
+        _ => None,
+    };
+    let config = args.iter().fold("".to_owned(), |mut acc, s| {
         acc += &(s.to_owned() + " ");
         acc
     });
     let config = config.trim_end();
-    let configs = vec![Config::from_str(&config).unwrap()];
+    let configs = vec![Config::from_str(config).unwrap()];
 
-    let mut peers: Vec<Peer> = configs.into_iter().map(Peer::new).collect();
-    for peer in &mut peers {
-        peer.start();
+    if dry_run_mode {
+        match dry_run(&configs, mrt.as_deref()).await {
+            Ok(report) => println!("{}", report),
+            Err(e) => {
+                eprintln!("{:?}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
     }
 
-    let mut handles = vec![];
-    for mut peer in peers {
-        let handle = tokio::spawn(async move {
-            loop {
-                peer.next().await;
+    log::init(configs[0].log_output, configs[0].syslog.clone());
+    let mut loc_rib = LocRib::new(&configs[0]).await.unwrap();
+    // 前回の起動で書き込んだまま残っているルートは、学習し直さなければ削除する。
+    let adopted = loc_rib.adopt_kernel_routes(&configs[0]).await.unwrap();
+    if adopted > 0 {
+        log_info!(
+            "前回の起動で書き込んだ{}個のルートを引き継ぎました。",
+            adopted
+        );
+    }
+    if let Some(path) = &configs[0].rib_store {
+        loc_rib
+            .attach_store(rib_store::open(path).unwrap())
+            .unwrap();
+    }
+    // update-delayの間は、ルートを受信するだけで広告もカーネルへの書き込みも行わない。
+    let update_delay = configs[0].update_delay.map(Duration::from_secs);
+    if update_delay.is_some() {
+        loc_rib.set_read_only(true);
+    }
+    let loc_rib = Arc::new(Mutex::new(loc_rib));
+    let ha = configs[0].ha;
+    let ha_key = configs[0]
+        .ha_key_file
+        .as_ref()
+        .map(|path| HaKey::load(path).unwrap());
+    if let (Some(HaRole::Secondary(address)), Some(key)) = (ha, &ha_key) {
+        // Primaryが動作している間は待機し、接続が切れたらピアを開始して処理を引き継ぐ。
+        let mut secondary = HaSecondary::new(Arc::clone(&loc_rib), key.clone());
+        if let Err(e) = secondary.run(address).await {
+            log_warning!("Primaryから処理を引き継ぎます。{:?}", e);
+        }
+        // 同期したルートはピアから受信し直すまで使い、受信し直さなかったルートは後で削除する。
+        let mut synced = loc_rib.lock().await;
+        synced.mark_replicated_routes_stale();
+        log_info!(
+            "Primaryから同期した{}個のルートを広告します。",
+            synced.len()
+        );
+        drop(synced);
+        tokio::spawn(flush_replicated_routes_after_stale_time(
+            Arc::clone(&loc_rib),
+            configs[0].clone(),
+        ));
+    }
+    let control_socket = configs[0].control_socket.clone();
+    let control_token_file = configs[0].control_token_file.clone();
+    let health_listen = configs[0].health_listen;
+    let ready_quorum = configs[0].ready_quorum;
+    let vip_listen = configs[0].vip_listen;
+    let vip_communities = configs[0].vip_communities.clone();
+    let api_tls = match (&configs[0].api_tls_cert, &configs[0].api_tls_key) {
+        (Some(cert), Some(key)) => {
+            Some(tls::server_config(cert, key, configs[0].api_tls_client_ca.as_deref()).unwrap())
+        }
+        _ => None,
+    };
+    let local_ip = configs[0].local_ip;
+    let aspa_file = configs[0].aspa_file.clone();
+    let mrt_dump_config = configs[0].clone();
+    let trace_file = configs[0].trace_file.clone();
+    let otlp_endpoint = configs[0].otlp_endpoint;
+    let policy_file = configs[0].policy_file.clone();
+    let aspa_table = match &aspa_file {
+        Some(path) => Arc::new(AspaTable::load(path).unwrap()),
+        None => Arc::new(AspaTable::new()),
+    };
+    let mut peer_manager = PeerManager::new(Arc::clone(&loc_rib), aspa_table);
+    let monitor = Monitor::new();
+    peer_manager.add_message_hook(Arc::new(monitor.clone()));
+    let churn = ChurnTracker::new();
+    peer_manager.add_message_hook(Arc::new(churn.clone()));
+    if let Some(path) = &trace_file {
+        peer_manager.set_tracer(Tracer::open(path).unwrap());
+    }
+    if let Some(endpoint) = otlp_endpoint {
+        peer_manager.set_otlp_exporter(OtlpExporter::spawn(endpoint));
+    }
+    if let Some(path) = &policy_file {
+        peer_manager
+            .set_policies(PolicySet::load(path).unwrap())
+            .await;
+    }
+    // discoverのテンプレートからは、名前解決した後にピアを作成する。
+    peer_manager.apply(configs).await;
+    let neighbors = peer_manager.neighbors();
+    tokio::spawn(propagate_loc_rib_changes(
+        Arc::clone(&loc_rib),
+        neighbors.clone(),
+    ));
+    if let Some(delay) = update_delay {
+        tokio::spawn(update_delay::run(
+            delay,
+            Arc::clone(&loc_rib),
+            neighbors.clone(),
+        ));
+    }
+
+    if let Some(mut scheduler) = TableDumpScheduler::new(&mrt_dump_config, Arc::clone(&loc_rib)) {
+        if mrt_dump_config.mrt_dump_adj_rib_in {
+            for neighbor in neighbors.snapshot() {
+                scheduler.add_adj_rib_in(&neighbor.config, neighbor.adj_rib_in);
             }
-        });
-        handles.push(handle);
+        }
+        tokio::spawn(scheduler.run());
     }
 
-    for handle in handles {
-        handle.await;
+    if let (Some(HaRole::Primary(address)), Some(key)) = (ha, ha_key) {
+        let primary = HaPrimary::new(Arc::clone(&loc_rib), neighbors.clone(), key);
+        tokio::spawn(async move {
+            if let Err(e) = primary.serve(address).await {
+                log_error!("{:?}", e);
+            }
+        });
+    }
+    if let Some(address) = health_listen {
+        let mut health_server = HealthServer::new(neighbors.clone(), ready_quorum);
+        if let Some(config) = &api_tls {
+            health_server.set_tls(config.clone());
+        }
+        tokio::spawn(async move {
+            if let Err(e) = health_server.serve(address).await {
+                log_error!("{:?}", e);
+            }
+        });
+    }
+    if let (Some(address), Some(path)) = (vip_listen, &control_token_file) {
+        let mut vip_server = VipServer::new(
+            neighbors.clone(),
+            Arc::clone(&loc_rib),
+            ControlTokens::load(path).unwrap(),
+            local_ip,
+        );
+        vip_server.set_communities(vip_communities);
+        if let Some(config) = api_tls {
+            vip_server.set_tls(config);
+        }
+        tokio::spawn(async move {
+            if let Err(e) = vip_server.serve(address).await {
+                log_error!("{:?}", e);
+            }
+        });
+    }
+    let mut control_server = ControlServer::new(neighbors, Arc::clone(&loc_rib));
+    control_server.set_monitor(monitor);
+    control_server.set_churn_tracker(churn);
+    let peer_manager = Arc::new(Mutex::new(peer_manager));
+    tokio::spawn(discovery::run(Arc::clone(&peer_manager)));
+    if let Some(path) = policy_file {
+        tokio::spawn(policy_file::watch(path, Arc::clone(&peer_manager)));
+    }
+    control_server.set_peer_manager(peer_manager);
+    if let Some(path) = &control_token_file {
+        control_server.set_tokens(ControlTokens::load(path).unwrap());
+    }
+    if let Err(e) = control_server.serve(&control_socket).await {
+        log_error!("{:?}", e);
     }
 }
@@ -0,0 +1,41 @@
+use std::mem::size_of;
+use std::ops::AddAssign;
+
+/// 構造体のおおよそのメモリ使用量。コントロールAPIの`show memory`で表示する。
+/// アロケータのオーバーヘッドや未使用の確保済み領域は含めない見積もりである。
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct MemoryUsage {
+    /// ルートやEventなど、構造体が持つ要素の数。
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+impl MemoryUsage {
+    pub fn new(entries: usize, bytes: usize) -> Self {
+        Self { entries, bytes }
+    }
+
+    /// ヒープを参照しないTの要素を、entries個持つ構造体のメモリ使用量。
+    pub fn of<T>(entries: usize) -> Self {
+        Self::new(entries, entries * size_of::<T>())
+    }
+}
+
+impl AddAssign for MemoryUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.entries += other.entries;
+        self.bytes += other.bytes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usages_can_be_summed() {
+        let mut total = MemoryUsage::of::<u64>(3);
+        total += MemoryUsage::new(1, 10);
+        assert_eq!(total, MemoryUsage::new(4, 34));
+    }
+}
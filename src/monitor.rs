@@ -0,0 +1,324 @@
+use crate::bgp_type::AutonomousSystemNumber;
+use crate::bgpdump;
+use crate::debug::Direction;
+use crate::hook::{MessageHook, PeerContext};
+use crate::packets::message::Message;
+use crate::packets::update::UpdateMessage;
+use crate::path_attribute::{AsPath, PathAttribute};
+use std::fmt::Write;
+use std::net::Ipv4Addr;
+use std::time::SystemTime;
+use tokio::sync::broadcast;
+
+/// 購読者が読み出す前に保持しておくMonitorEventの数。
+/// 読み出しが追いつかない購読者には、古いイベントを捨てたことを通知する。
+const MONITOR_CHANNEL_CAPACITY: usize = 1024;
+
+/// ピアが送受信したUpdateMessageかNotificationMessage。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MonitorEvent {
+    pub neighbor: Ipv4Addr,
+    /// OpenMessageで受信したピアのAS番号。セッションを確立する前はNone。
+    pub remote_as: Option<AutonomousSystemNumber>,
+    pub time: SystemTime,
+    pub direction: Direction,
+    pub message: Message,
+}
+
+/// `howbgp monitor`で表示するMonitorEventの形式。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum MonitorFormat {
+    /// 1イベントを1行のテキストで表す。
+    #[default]
+    Line,
+    /// 1イベントを1行のJSONで表す。
+    Json,
+    /// 受信したUpdateMessageを、`bgpdump -m`と同じ`BGP4MP|...`の行で表す。
+    /// 既存の解析スクリプトで読めるように、ほかのイベントは出力しない。
+    Bgpdump,
+}
+
+impl MonitorEvent {
+    pub fn format(&self, format: MonitorFormat) -> String {
+        match format {
+            MonitorFormat::Line => self.to_line(),
+            MonitorFormat::Json => self.to_json(),
+            MonitorFormat::Bgpdump => self.to_bgpdump(),
+        }
+    }
+
+    /// 出力しないイベントの場合は空文字列を返す。
+    fn to_bgpdump(&self) -> String {
+        match (&self.message, self.direction) {
+            (Message::Update(update), Direction::Receive) => bgpdump::update_lines(
+                self.time,
+                self.neighbor,
+                self.remote_as.unwrap_or(AutonomousSystemNumber::from(0)),
+                update,
+            )
+            .join("\n"),
+            _ => String::new(),
+        }
+    }
+
+    fn direction_name(&self) -> &'static str {
+        match self.direction {
+            Direction::Send => "sent",
+            Direction::Receive => "received",
+        }
+    }
+
+    fn to_line(&self) -> String {
+        let mut line = format!("[{}] {} ", self.neighbor, self.direction_name());
+        match &self.message {
+            Message::Update(update) => {
+                line += "UPDATE";
+                for network in update.network_layer_reachability_information() {
+                    write!(line, " +{}", **network).unwrap();
+                }
+                for network in update.withdrawn_routes() {
+                    write!(line, " -{}", **network).unwrap();
+                }
+                for path_attribute in update.path_attributes() {
+                    write!(line, " {}", format_path_attribute(path_attribute)).unwrap();
+                }
+            }
+            Message::Notification(notification) => {
+                write!(line, "NOTIFICATION {}", notification).unwrap();
+            }
+            message => write!(line, "{:?}", message).unwrap(),
+        }
+        line
+    }
+
+    fn to_json(&self) -> String {
+        let mut json = format!(
+            r#"{{"neighbor":"{}","direction":"{}","#,
+            self.neighbor,
+            self.direction_name()
+        );
+        match &self.message {
+            Message::Update(update) => {
+                write!(json, r#""type":"update",{}"#, update_to_json(update)).unwrap();
+            }
+            Message::Notification(notification) => write!(
+                json,
+                r#""type":"notification","error_code":{},"error_subcode":{},"description":{}"#,
+                u8::from(notification.error_code()),
+                notification.error_subcode(),
+                json_string(&notification.to_string())
+            )
+            .unwrap(),
+            message => write!(
+                json,
+                r#""type":"other","description":{}"#,
+                json_string(&format!("{:?}", message))
+            )
+            .unwrap(),
+        }
+        json + "}"
+    }
+}
+
+pub(crate) fn update_to_json(update: &UpdateMessage) -> String {
+    let networks = |networks: &[crate::bgp_type::Ipv4Network]| {
+        networks
+            .iter()
+            .map(|n| format!(r#""{}""#, **n))
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    let path_attributes = update
+        .path_attributes()
+        .iter()
+        .map(|p| json_string(&format_path_attribute(p)))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        r#""announced":[{}],"withdrawn":[{}],"path_attributes":[{}]"#,
+        networks(update.network_layer_reachability_information()),
+        networks(update.withdrawn_routes()),
+        path_attributes
+    )
+}
+
+pub(crate) fn format_path_attribute(path_attribute: &PathAttribute) -> String {
+    match path_attribute {
+        PathAttribute::Origin(origin) => format!("origin={:?}", origin),
+        PathAttribute::AsPath(AsPath::AsSequence(as_numbers)) => format!(
+            "as-path=[{}]",
+            as_numbers
+                .iter()
+                .map(|a| a.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        ),
+        PathAttribute::AsPath(AsPath::AsSet(as_numbers)) => format!(
+            "as-path={{{}}}",
+            as_numbers
+                .iter()
+                .map(|a| a.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        ),
+        PathAttribute::NextHop(next_hop) => format!("next-hop={}", next_hop),
+        PathAttribute::LocalPref(local_pref) => format!("local-pref={}", local_pref),
+        PathAttribute::Communities(communities) => format!(
+            "communities=[{}]",
+            communities
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        ),
+        path_attribute => format!("{:?}", path_attribute),
+    }
+}
+
+/// JSONの文字列リテラルにする。
+pub(crate) fn json_string(s: &str) -> String {
+    let mut json = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => json += "\\\"",
+            '\\' => json += "\\\\",
+            c if c.is_control() => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json + "\""
+}
+
+/// ピアが送受信したUpdateMessage、NotificationMessageを購読者に配信するMessageHook。
+/// すべてのピアに同じMonitorを追加し、コントロールAPIの`monitor`コマンドで購読する。
+#[derive(Debug, Clone)]
+pub struct Monitor(broadcast::Sender<MonitorEvent>);
+
+impl Monitor {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(MONITOR_CHANNEL_CAPACITY);
+        Self(sender)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<MonitorEvent> {
+        self.0.subscribe()
+    }
+
+    fn publish(&self, context: &PeerContext, direction: Direction, message: &Message) {
+        if !matches!(message, Message::Update(_) | Message::Notification(_)) {
+            return;
+        }
+        // 購読者がいないときは送信に失敗するが、捨ててよい。
+        let _ = self.0.send(MonitorEvent {
+            neighbor: context.config.remote_ip,
+            remote_as: context.remote_as,
+            time: SystemTime::now(),
+            direction,
+            message: message.clone(),
+        });
+    }
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MessageHook for Monitor {
+    fn on_inbound(&self, context: &PeerContext, message: Message) -> Option<Message> {
+        self.publish(context, Direction::Receive, &message);
+        Some(message)
+    }
+
+    fn on_outbound(&self, context: &PeerContext, message: Message) -> Option<Message> {
+        self.publish(context, Direction::Send, &message);
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::packets::notification::{cease, ErrorCode};
+    use crate::path_attribute::Origin;
+    use crate::state::State;
+
+    fn event(message: Message) -> MonitorEvent {
+        MonitorEvent {
+            neighbor: "127.0.0.2".parse().unwrap(),
+            remote_as: Some(64513.into()),
+            time: std::time::UNIX_EPOCH,
+            direction: Direction::Receive,
+            message,
+        }
+    }
+
+    fn update() -> Message {
+        Message::Update(UpdateMessage::new(
+            vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::AsSequence(vec![64513.into()])),
+                PathAttribute::NextHop("127.0.0.2".parse().unwrap()),
+            ],
+            vec!["10.100.220.0/24".parse().unwrap()],
+            vec!["10.100.230.0/24".parse().unwrap()],
+        ))
+    }
+
+    #[test]
+    fn update_event_can_be_formatted_as_line_and_json() {
+        assert_eq!(
+            event(update()).format(MonitorFormat::Line),
+            "[127.0.0.2] received UPDATE +10.100.220.0/24 -10.100.230.0/24 \
+             origin=Igp as-path=[64513] next-hop=127.0.0.2"
+        );
+        assert_eq!(
+            event(update()).format(MonitorFormat::Json),
+            r#"{"neighbor":"127.0.0.2","direction":"received","type":"update","announced":["10.100.220.0/24"],"withdrawn":["10.100.230.0/24"],"path_attributes":["origin=Igp","as-path=[64513]","next-hop=127.0.0.2"]}"#
+        );
+    }
+
+    #[test]
+    fn only_received_updates_are_formatted_as_bgpdump() {
+        assert_eq!(
+            event(update()).format(MonitorFormat::Bgpdump),
+            "BGP4MP|0|A|127.0.0.2|64513|10.100.220.0/24|64513|IGP|127.0.0.2|0|0||NAG||\n\
+             BGP4MP|0|W|127.0.0.2|64513|10.100.230.0/24"
+        );
+        let keepalive = event(Message::new_keepalive());
+        assert_eq!(keepalive.format(MonitorFormat::Bgpdump), "");
+    }
+
+    #[test]
+    fn notification_description_is_escaped_in_json() {
+        let mut data = vec![7];
+        data.extend_from_slice(b"say \"hi\"");
+        let notification =
+            Message::new_notification(ErrorCode::Cease, cease::ADMINISTRATIVE_SHUTDOWN, data);
+        let json = event(notification).format(MonitorFormat::Json);
+        assert!(json.contains(r#""error_code":6"#));
+        assert!(json.contains(r#"\"hi\""#));
+    }
+
+    #[tokio::test]
+    async fn monitor_publishes_only_updates_and_notifications() {
+        let monitor = Monitor::new();
+        let mut receiver = monitor.subscribe();
+        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let context = PeerContext {
+            config: &config,
+            state: State::Established,
+            remote_as: None,
+        };
+
+        monitor.on_inbound(&context, Message::new_keepalive());
+        monitor.on_outbound(&context, update());
+
+        let event = receiver.recv().await.unwrap();
+        assert_eq!(event.direction, Direction::Send);
+        assert_eq!(event.message, update());
+        assert!(receiver.try_recv().is_err());
+    }
+}
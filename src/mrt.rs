@@ -0,0 +1,615 @@
+use crate::bgp_type::AutonomousSystemNumber;
+use crate::error::MrtParseError;
+use crate::path_attribute::{AsPath, PathAttribute};
+use crate::routing::{Ipv4Network, RibEntry};
+use anyhow::Context;
+use bytes::{BufMut, BytesMut};
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+/// MRT(RFC 6396)のType。
+pub const TABLE_DUMP_V2: u16 = 13;
+pub const BGP4MP: u16 = 16;
+pub const BGP4MP_ET: u16 = 17;
+
+/// TABLE_DUMP_V2のSubtype。
+const PEER_INDEX_TABLE: u16 = 1;
+const RIB_IPV4_UNICAST: u16 = 2;
+
+/// BGP4MPのSubtype。
+pub(crate) const BGP4MP_MESSAGE: u16 = 1;
+pub(crate) const BGP4MP_MESSAGE_AS4: u16 = 4;
+const BGP4MP_MESSAGE_LOCAL: u16 = 6;
+const BGP4MP_MESSAGE_AS4_LOCAL: u16 = 7;
+
+/// 読み込むMRTレコードのMessageの最大の長さ。
+/// 壊れたファイルのLengthに従って巨大なバッファを確保しないように制限する。
+/// TABLE_DUMP_V2のRIBレコードは1つのプレフィックスの全ピアの経路を含むため、BGPメッセージより大きくなる。
+pub const MAX_MRT_RECORD_LENGTH: u32 = 16 * 1024 * 1024;
+
+/// MRTファイルに含まれる1つのレコード。
+/// MRTのCommon Headerのbytes表現は以下の通り。
+/// [Timestamp (4 octets)]
+/// [Type (2 octets)]
+/// [Subtype (2 octets)]
+/// [Length (4 octets)]
+/// [Message (Length octets)]
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct MrtRecord {
+    pub timestamp: u32,
+    /// Extended Timestamp(BGP4MP_ET)の場合のみ存在するマイクロ秒。
+    pub microsecond_timestamp: Option<u32>,
+    pub type_: u16,
+    pub subtype: u16,
+    pub message: BytesMut,
+}
+
+/// MRTファイルからMrtRecordを順に読み取るIterator。
+pub struct MrtReader<R> {
+    reader: R,
+}
+
+impl<R: Read> MrtReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader }
+    }
+
+    fn read_record(&mut self) -> Result<Option<MrtRecord>, MrtParseError> {
+        let mut header = [0u8; 12];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let timestamp = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let type_ = u16::from_be_bytes([header[4], header[5]]);
+        let subtype = u16::from_be_bytes([header[6], header[7]]);
+        let length = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+        if length > MAX_MRT_RECORD_LENGTH {
+            return Err(MrtParseError::TooLong(length));
+        }
+
+        let mut message = vec![0u8; length as usize];
+        self.reader.read_exact(&mut message).context(format!(
+            "MRTレコードのMessage({} octets)を読み取れませんでした。",
+            length
+        ))?;
+        let mut message = BytesMut::from(&message[..]);
+
+        let microsecond_timestamp = if type_ == BGP4MP_ET {
+            if message.len() < 4 {
+                return Err(MrtParseError::Truncated {
+                    record: "BGP4MP_ET",
+                });
+            }
+            let microsecond = message.split_to(4);
+            Some(u32::from_be_bytes([
+                microsecond[0],
+                microsecond[1],
+                microsecond[2],
+                microsecond[3],
+            ]))
+        } else {
+            None
+        };
+
+        Ok(Some(MrtRecord {
+            timestamp,
+            microsecond_timestamp,
+            type_,
+            subtype,
+            message,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for MrtReader<R> {
+    type Item = Result<MrtRecord, MrtParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// BGP4MP_MESSAGE系のレコードに含まれる、ピアとの間で送受信されたBGPメッセージ。
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Bgp4mpMessage {
+    pub peer_as: u32,
+    pub local_as: u32,
+    pub peer_ip: IpAddr,
+    pub local_ip: IpAddr,
+    /// AS番号が4 octetsで表現されるセッションのメッセージであるか。
+    pub as4: bool,
+    pub bgp_message: BytesMut,
+}
+
+impl Bgp4mpMessage {
+    /// MrtRecordがBGP4MP_MESSAGE系のレコードであれば、Bgp4mpMessageに変換する。
+    /// それ以外(State Changeやテーブルダンプなど)のレコードの場合はNoneを返す。
+    pub fn from_record(record: &MrtRecord) -> Result<Option<Self>, MrtParseError> {
+        if record.type_ != BGP4MP && record.type_ != BGP4MP_ET {
+            return Ok(None);
+        }
+        let as4 = match record.subtype {
+            BGP4MP_MESSAGE | BGP4MP_MESSAGE_LOCAL => false,
+            BGP4MP_MESSAGE_AS4 | BGP4MP_MESSAGE_AS4_LOCAL => true,
+            _ => return Ok(None),
+        };
+
+        let bytes = &record.message[..];
+        let as_length = if as4 { 4 } else { 2 };
+        // Peer AS, Local AS, Interface Index (2 octets), Address Family (2 octets)
+        let fixed_length = as_length * 2 + 2 + 2;
+        if bytes.len() < fixed_length {
+            return Err(MrtParseError::Truncated { record: "BGP4MP" });
+        }
+        let read_as = |b: &[u8]| {
+            b.iter()
+                .fold(0u32, |acc, octet| (acc << 8) | u32::from(*octet))
+        };
+        let peer_as = read_as(&bytes[0..as_length]);
+        let local_as = read_as(&bytes[as_length..as_length * 2]);
+        let address_family = u16::from_be_bytes([bytes[fixed_length - 2], bytes[fixed_length - 1]]);
+        let ip_length = match address_family {
+            1 => 4,
+            2 => 16,
+            _ => return Err(MrtParseError::UnsupportedAddressFamily(address_family)),
+        };
+        let ip_end = fixed_length + ip_length * 2;
+        if bytes.len() < ip_end {
+            return Err(MrtParseError::Truncated { record: "BGP4MP" });
+        }
+        let read_ip = |b: &[u8]| -> IpAddr {
+            if b.len() == 4 {
+                Ipv4Addr::from(<[u8; 4]>::try_from(b).unwrap()).into()
+            } else {
+                Ipv6Addr::from(<[u8; 16]>::try_from(b).unwrap()).into()
+            }
+        };
+        let peer_ip = read_ip(&bytes[fixed_length..fixed_length + ip_length]);
+        let local_ip = read_ip(&bytes[fixed_length + ip_length..ip_end]);
+
+        Ok(Some(Self {
+            peer_as,
+            local_as,
+            peer_ip,
+            local_ip,
+            as4,
+            bgp_message: BytesMut::from(&bytes[ip_end..]),
+        }))
+    }
+}
+
+impl MrtRecord {
+    /// Common Headerを付けたbytes列にする。
+    pub fn to_bytes(&self) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        bytes.put_u32(self.timestamp);
+        bytes.put_u16(self.type_);
+        bytes.put_u16(self.subtype);
+        let microsecond_length = if self.microsecond_timestamp.is_some() {
+            4
+        } else {
+            0
+        };
+        bytes.put_u32((microsecond_length + self.message.len()) as u32);
+        if let Some(microsecond) = self.microsecond_timestamp {
+            bytes.put_u32(microsecond);
+        }
+        bytes.put(&self.message[..]);
+        bytes
+    }
+}
+
+/// TABLE_DUMP_V2のPEER_INDEX_TABLEに載せるピア。
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct MrtPeer {
+    pub bgp_id: Ipv4Addr,
+    pub ip: Ipv4Addr,
+    pub as_number: AutonomousSystemNumber,
+}
+
+/// TABLE_DUMP_V2形式で書き出す1つのRIBのダンプ。
+/// (PEER_INDEX_TABLEでのピアの番号, Path Attribute)。RIB_IPV4_UNICASTのレコードの1エントリ。
+type RibEntryRecord = (u16, Arc<Vec<PathAttribute>>);
+
+/// PEER_INDEX_TABLEのレコードと、prefixごとのRIB_IPV4_UNICASTのレコードからなる。
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct TableDump {
+    collector_bgp_id: Ipv4Addr,
+    view_name: String,
+    peers: Vec<MrtPeer>,
+    /// prefixごとの、RIB_IPV4_UNICASTのエントリの列。
+    ribs: BTreeMap<Ipv4Network, Vec<RibEntryRecord>>,
+}
+
+impl TableDump {
+    pub fn new(collector_bgp_id: Ipv4Addr, view_name: &str) -> Self {
+        Self {
+            collector_bgp_id,
+            view_name: view_name.to_owned(),
+            peers: vec![],
+            ribs: BTreeMap::new(),
+        }
+    }
+
+    /// PEER_INDEX_TABLEにピアを追加し、その番号を返す。
+    pub fn add_peer(&mut self, peer: MrtPeer) -> u16 {
+        self.peers.push(peer);
+        (self.peers.len() - 1) as u16
+    }
+
+    pub fn add_route(&mut self, peer_index: u16, route: &RibEntry) {
+        self.ribs
+            .entry(route.network_address)
+            .or_default()
+            .push((peer_index, Arc::clone(&route.path_attributes)));
+    }
+
+    /// TABLE_DUMP_V2形式のダンプを読み込む。to_bytesで書き出したダンプを読み戻すためのもので、
+    /// IPv4のピアとRIB_IPV4_UNICASTのレコードだけを扱い、ほかのレコードは読み飛ばす。
+    pub fn read<R: Read>(reader: R) -> Result<Self, MrtParseError> {
+        let mut dump: Option<Self> = None;
+        for record in MrtReader::new(reader) {
+            let record = record?;
+            match (record.type_, record.subtype, &mut dump) {
+                (TABLE_DUMP_V2, PEER_INDEX_TABLE, _) => {
+                    dump = Some(Self::read_peer_index_table(&record.message)?)
+                }
+                (TABLE_DUMP_V2, RIB_IPV4_UNICAST, Some(dump)) => {
+                    dump.read_rib_ipv4_unicast(&record.message)?
+                }
+                (TABLE_DUMP_V2, RIB_IPV4_UNICAST, None) => {
+                    return Err(anyhow::anyhow!(
+                        "PEER_INDEX_TABLEより前にRIB_IPV4_UNICASTのレコードがあります。"
+                    )
+                    .into())
+                }
+                _ => (),
+            }
+        }
+        dump.ok_or_else(|| anyhow::anyhow!("PEER_INDEX_TABLEのレコードがありません。").into())
+    }
+
+    fn read_peer_index_table(mut bytes: &[u8]) -> Result<Self, MrtParseError> {
+        let record = "PEER_INDEX_TABLE";
+        let collector_bgp_id = read_ipv4_addr(&mut bytes, record)?;
+        let view_name_length = read_u16(&mut bytes, record)? as usize;
+        let view_name = String::from_utf8_lossy(take(&mut bytes, view_name_length, record)?);
+        let mut dump = Self::new(collector_bgp_id, &view_name);
+        for _ in 0..read_u16(&mut bytes, record)? {
+            let peer_type = take(&mut bytes, 1, record)?[0];
+            if peer_type & 0b01 != 0 {
+                return Err(MrtParseError::UnsupportedAddressFamily(2));
+            }
+            let bgp_id = read_ipv4_addr(&mut bytes, record)?;
+            let ip = read_ipv4_addr(&mut bytes, record)?;
+            let as_number = if peer_type & 0b10 != 0 {
+                read_u32(&mut bytes, record)?
+            } else {
+                u32::from(read_u16(&mut bytes, record)?)
+            };
+            dump.add_peer(MrtPeer {
+                bgp_id,
+                ip,
+                as_number: as_number.into(),
+            });
+        }
+        Ok(dump)
+    }
+
+    fn read_rib_ipv4_unicast(&mut self, mut bytes: &[u8]) -> Result<(), MrtParseError> {
+        let record = "RIB_IPV4_UNICAST";
+        take(&mut bytes, 4, record)?; // Sequence Number
+        let prefix_length = *bytes.first().ok_or(MrtParseError::Truncated { record })?;
+        let prefix = take(
+            &mut bytes,
+            1 + prefix_length.saturating_add(7) as usize / 8,
+            record,
+        )?;
+        let network = Ipv4Network::from_u8_slice(prefix)
+            .context("RIB_IPV4_UNICASTのPrefixを読み取れませんでした。")?[0];
+        for _ in 0..read_u16(&mut bytes, record)? {
+            let peer_index = read_u16(&mut bytes, record)?;
+            if peer_index as usize >= self.peers.len() {
+                return Err(anyhow::anyhow!(
+                    "Peer Index {}はPEER_INDEX_TABLEにありません。",
+                    peer_index
+                )
+                .into());
+            }
+            take(&mut bytes, 4, record)?; // Originated Time
+            let attribute_length = read_u16(&mut bytes, record)? as usize;
+            let path_attributes =
+                decode_path_attributes(take(&mut bytes, attribute_length, record)?)?;
+            self.ribs
+                .entry(network)
+                .or_default()
+                .push((peer_index, Arc::new(path_attributes)));
+        }
+        Ok(())
+    }
+
+    /// ダンプに含まれるすべてのルートを、prefixの順に(prefix, ルートを持つピア, Path Attribute)で返す。
+    pub fn routes(&self) -> impl Iterator<Item = (Ipv4Network, MrtPeer, &Arc<Vec<PathAttribute>>)> {
+        self.ribs.iter().flat_map(move |(network, entries)| {
+            entries.iter().map(move |(peer_index, path_attributes)| {
+                (*network, self.peers[*peer_index as usize], path_attributes)
+            })
+        })
+    }
+
+    pub fn records(&self, timestamp: u32) -> Vec<MrtRecord> {
+        let mut records = vec![MrtRecord {
+            timestamp,
+            microsecond_timestamp: None,
+            type_: TABLE_DUMP_V2,
+            subtype: PEER_INDEX_TABLE,
+            message: self.peer_index_table(),
+        }];
+        for (sequence_number, (network, entries)) in self.ribs.iter().enumerate() {
+            // [Sequence Number (4 octets)][Prefix Length (1 octet)][Prefix (可変長)]
+            // [Entry Count (2 octets)][RIB Entries (可変長)]
+            let mut message = BytesMut::new();
+            message.put_u32(sequence_number as u32);
+            message.put(BytesMut::from(network));
+            message.put_u16(entries.len() as u16);
+            for (peer_index, path_attributes) in entries {
+                let attributes = encode_path_attributes(path_attributes);
+                // [Peer Index (2 octets)][Originated Time (4 octets)]
+                // [Attribute Length (2 octets)][BGP Attributes (可変長)]
+                // ルートを受信した時刻は記録していないため、ダンプした時刻とする。
+                message.put_u16(*peer_index);
+                message.put_u32(timestamp);
+                message.put_u16(attributes.len() as u16);
+                message.put(attributes);
+            }
+            records.push(MrtRecord {
+                timestamp,
+                microsecond_timestamp: None,
+                type_: TABLE_DUMP_V2,
+                subtype: RIB_IPV4_UNICAST,
+                message,
+            });
+        }
+        records
+    }
+
+    pub fn to_bytes(&self, timestamp: u32) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        for record in self.records(timestamp) {
+            bytes.put(record.to_bytes());
+        }
+        bytes
+    }
+
+    /// [Collector BGP ID (4 octets)][View Name Length (2 octets)][View Name (可変長)]
+    /// [Peer Count (2 octets)][Peer Entries (可変長)]
+    fn peer_index_table(&self) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        bytes.put(&self.collector_bgp_id.octets()[..]);
+        bytes.put_u16(self.view_name.len() as u16);
+        bytes.put(self.view_name.as_bytes());
+        bytes.put_u16(self.peers.len() as u16);
+        for peer in &self.peers {
+            // Peer Typeの下位1bitが0のときはPeer IPがIPv4アドレスである。
+            // 下位2bit目が1のときはPeer ASが4 octets、0のときは2 octetsである。
+            if peer.as_number.is_four_octet() {
+                bytes.put_u8(0b10);
+                bytes.put(&peer.bgp_id.octets()[..]);
+                bytes.put(&peer.ip.octets()[..]);
+                bytes.put_u32(u32::from(peer.as_number));
+            } else {
+                bytes.put_u8(0);
+                bytes.put(&peer.bgp_id.octets()[..]);
+                bytes.put(&peer.ip.octets()[..]);
+                bytes.put_u16(peer.as_number.to_two_octet());
+            }
+        }
+        bytes
+    }
+}
+
+/// bytesの先頭からlength octetsを取り出す。足りない場合はrecordが途切れているとする。
+fn take<'a>(
+    bytes: &mut &'a [u8],
+    length: usize,
+    record: &'static str,
+) -> Result<&'a [u8], MrtParseError> {
+    if bytes.len() < length {
+        return Err(MrtParseError::Truncated { record });
+    }
+    let (head, rest) = bytes.split_at(length);
+    *bytes = rest;
+    Ok(head)
+}
+
+fn read_u16(bytes: &mut &[u8], record: &'static str) -> Result<u16, MrtParseError> {
+    let b = take(bytes, 2, record)?;
+    Ok(u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn read_u32(bytes: &mut &[u8], record: &'static str) -> Result<u32, MrtParseError> {
+    let b = take(bytes, 4, record)?;
+    Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn read_ipv4_addr(bytes: &mut &[u8], record: &'static str) -> Result<Ipv4Addr, MrtParseError> {
+    Ok(Ipv4Addr::from(read_u32(bytes, record)?))
+}
+
+/// RIB EntryのBGP Attributesを読み取る。encode_path_attributesと同じく、
+/// AS_PATHのAS番号は4 octetsで表現されているものとして読む。
+fn decode_path_attributes(bytes: &[u8]) -> Result<Vec<PathAttribute>, MrtParseError> {
+    let mut path_attributes = vec![];
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let record = "RIB_IPV4_UNICASTのBGP Attributes";
+        let start = rest;
+        let header = take(&mut rest, 2, record)?;
+        let (flag, type_code) = (header[0], header[1]);
+        let length = if flag & 0b00010000 != 0 {
+            read_u16(&mut rest, record)? as usize
+        } else {
+            take(&mut rest, 1, record)?[0] as usize
+        };
+        let value = take(&mut rest, length, record)?;
+        if type_code == 2 {
+            path_attributes.push(PathAttribute::AsPath(
+                AsPath::from_bytes(value, 4).context("AS_PATHを読み取れませんでした。")?,
+            ));
+        } else {
+            let attribute = &start[..start.len() - rest.len()];
+            path_attributes.extend(
+                PathAttribute::from_u8_slice(attribute)
+                    .context("Path Attributeを読み取れませんでした。")?,
+            );
+        }
+    }
+    Ok(path_attributes)
+}
+
+/// RIB EntryのBGP Attributesをbytes列にする。
+/// RFC 6396に従い、AS_PATHのAS番号はセッションに関係なく4 octetsで表現する。
+fn encode_path_attributes(path_attributes: &[PathAttribute]) -> BytesMut {
+    let mut bytes = BytesMut::new();
+    for path_attribute in path_attributes {
+        let as_path = match path_attribute {
+            PathAttribute::AsPath(as_path) => as_path,
+            p => {
+                bytes.put(BytesMut::from(p));
+                continue;
+            }
+        };
+        let (path_segment_type, as_numbers): (u8, Vec<AutonomousSystemNumber>) = match as_path {
+            AsPath::AsSet(set) => (1, set.iter().copied().collect()),
+            AsPath::AsSequence(seq) => (2, seq.clone()),
+        };
+        let attribute_length = 2 + 4 * as_numbers.len();
+        if attribute_length < 256 {
+            bytes.put_u8(0b01000000);
+            bytes.put_u8(2);
+            bytes.put_u8(attribute_length as u8);
+        } else {
+            bytes.put_u8(0b01010000);
+            bytes.put_u8(2);
+            bytes.put_u16(attribute_length as u16);
+        }
+        bytes.put_u8(path_segment_type);
+        bytes.put_u8(as_numbers.len() as u8);
+        for as_number in as_numbers {
+            bytes.put_u32(u32::from(as_number));
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::message::Message;
+
+    #[test]
+    fn read_bgp4mp_message_from_mrt_bytes() {
+        let keepalive: BytesMut = Message::new_keepalive().into();
+        let mut body = vec![];
+        body.extend_from_slice(&64513u16.to_be_bytes());
+        body.extend_from_slice(&64512u16.to_be_bytes());
+        body.extend_from_slice(&0u16.to_be_bytes());
+        body.extend_from_slice(&1u16.to_be_bytes());
+        body.extend_from_slice(&[10, 200, 100, 3]);
+        body.extend_from_slice(&[10, 200, 100, 2]);
+        body.extend_from_slice(&keepalive[..]);
+
+        let mut mrt = vec![];
+        mrt.extend_from_slice(&1650000000u32.to_be_bytes());
+        mrt.extend_from_slice(&BGP4MP.to_be_bytes());
+        mrt.extend_from_slice(&BGP4MP_MESSAGE.to_be_bytes());
+        mrt.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        mrt.extend_from_slice(&body);
+
+        let records: Vec<MrtRecord> = MrtReader::new(&mrt[..]).collect::<Result<_, _>>().unwrap();
+        assert_eq!(records.len(), 1);
+        let message = Bgp4mpMessage::from_record(&records[0]).unwrap().unwrap();
+        assert_eq!(message.peer_as, 64513);
+        assert_eq!(message.peer_ip, "10.200.100.3".parse::<IpAddr>().unwrap());
+        assert_eq!(message.bgp_message, keepalive);
+    }
+
+    #[test]
+    fn read_bgp4mp_message_as4_with_4_octet_as_numbers() {
+        let keepalive: BytesMut = Message::new_keepalive().into();
+        let mut body = vec![];
+        body.extend_from_slice(&4200000001u32.to_be_bytes());
+        body.extend_from_slice(&64512u32.to_be_bytes());
+        body.extend_from_slice(&0u16.to_be_bytes());
+        body.extend_from_slice(&1u16.to_be_bytes());
+        body.extend_from_slice(&[10, 200, 100, 3]);
+        body.extend_from_slice(&[10, 200, 100, 2]);
+        body.extend_from_slice(&keepalive[..]);
+        let record = MrtRecord {
+            timestamp: 1650000000,
+            microsecond_timestamp: None,
+            type_: BGP4MP,
+            subtype: BGP4MP_MESSAGE_AS4,
+            message: BytesMut::from(&body[..]),
+        };
+
+        let message = Bgp4mpMessage::from_record(&record).unwrap().unwrap();
+        assert!(message.as4);
+        assert_eq!(message.peer_as, 4200000001);
+        assert_eq!(message.local_as, 64512);
+        assert_eq!(message.bgp_message, keepalive);
+    }
+
+    #[test]
+    fn too_long_mrt_record_is_rejected_before_allocation() {
+        let mut mrt = vec![];
+        mrt.extend_from_slice(&1650000000u32.to_be_bytes());
+        mrt.extend_from_slice(&BGP4MP.to_be_bytes());
+        mrt.extend_from_slice(&BGP4MP_MESSAGE.to_be_bytes());
+        mrt.extend_from_slice(&u32::MAX.to_be_bytes());
+
+        let result = MrtReader::new(&mrt[..]).next().unwrap();
+        assert!(matches!(result, Err(MrtParseError::TooLong(u32::MAX))));
+    }
+
+    #[test]
+    fn table_dump_encodes_as_path_with_4_octet_as_numbers() {
+        let mut dump = TableDump::new("10.200.100.2".parse().unwrap(), "loc-rib");
+        let peer_index = dump.add_peer(MrtPeer {
+            bgp_id: "10.200.100.3".parse().unwrap(),
+            ip: "10.200.100.3".parse().unwrap(),
+            as_number: 64513.into(),
+        });
+        dump.add_route(
+            peer_index,
+            &RibEntry::for_test("10.100.220.0/24").with_path_attributes(vec![
+                PathAttribute::AsPath(AsPath::AsSequence(vec![64513.into()])),
+            ]),
+        );
+
+        let bytes = dump.to_bytes(1650000000);
+        let records: Vec<MrtRecord> = MrtReader::new(&bytes[..])
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].subtype, PEER_INDEX_TABLE);
+        assert_eq!(records[1].subtype, RIB_IPV4_UNICAST);
+        let expected_rib: Vec<u8> = [
+            &0u32.to_be_bytes()[..], // Sequence Number
+            &[24, 10, 100, 220],     // Prefix
+            &1u16.to_be_bytes(),     // Entry Count
+            &0u16.to_be_bytes(),     // Peer Index
+            &1650000000u32.to_be_bytes(),
+            &9u16.to_be_bytes(), // Attribute Length
+            &[0b01000000, 2, 6, 2, 1],
+            &64513u32.to_be_bytes(),
+        ]
+        .concat();
+        assert_eq!(&records[1].message[..], &expected_rib[..]);
+        assert_eq!(TableDump::read(&bytes[..]).unwrap(), dump);
+    }
+}
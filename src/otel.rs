@@ -0,0 +1,315 @@
+use crate::log_warning;
+use crate::monitor::json_string;
+use anyhow::{Context, Result};
+use std::collections::hash_map::RandomState;
+use std::fmt::Write as _;
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+/// 送信を待っているSpanを保持しておく数。コレクタが止まっている間に溜まった分は捨てる。
+const SPAN_CHANNEL_CAPACITY: usize = 4096;
+/// 1回のリクエストで送るSpanの最大の数。
+const MAXIMUM_SPANS_PER_EXPORT: usize = 512;
+/// 溜まったSpanをコレクタに送る間隔。
+const EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// OTLP/HTTPでSpanを受け付けるパス。
+const TRACES_PATH: &str = "/v1/traces";
+const SERVICE_NAME: &str = "how-to-create-bgp";
+
+/// Spanが属するトレースのID。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceId(u128);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpanId(u64);
+
+impl TraceId {
+    pub fn random() -> Self {
+        Self(((random_u64() as u128) << 64) | random_u64() as u128)
+    }
+}
+
+impl SpanId {
+    pub fn random() -> Self {
+        Self(random_u64())
+    }
+}
+
+/// 0にならない乱数。OpenTelemetryでは、すべて0のIDは無効である。
+fn random_u64() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    loop {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        hasher.write_u128(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+        );
+        let value = hasher.finish();
+        if value != 0 {
+            return value;
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttributeValue {
+    String(String),
+    Int(i64),
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_owned())
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+
+impl From<usize> for AttributeValue {
+    fn from(value: usize) -> Self {
+        Self::Int(value as i64)
+    }
+}
+
+impl From<u64> for AttributeValue {
+    fn from(value: u64) -> Self {
+        Self::Int(value as i64)
+    }
+}
+
+/// 1つの処理の開始から終了までを表すSpan。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub trace_id: TraceId,
+    pub span_id: SpanId,
+    /// トレースの最初のSpanの場合はNone。
+    pub parent_span_id: Option<SpanId>,
+    pub name: &'static str,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(&'static str, AttributeValue)>,
+}
+
+impl Span {
+    /// 新しいトレースの最初のSpanを作成する。
+    pub fn root(name: &'static str, start: SystemTime, end: SystemTime) -> Self {
+        Self {
+            trace_id: TraceId::random(),
+            span_id: SpanId::random(),
+            parent_span_id: None,
+            name,
+            start,
+            end,
+            attributes: vec![],
+        }
+    }
+
+    /// このSpanの中で行った処理を表す、子のSpanを作成する。
+    pub fn child(&self, name: &'static str, start: SystemTime, end: SystemTime) -> Self {
+        Self {
+            trace_id: self.trace_id,
+            span_id: SpanId::random(),
+            parent_span_id: Some(self.span_id),
+            name,
+            start,
+            end,
+            attributes: vec![],
+        }
+    }
+
+    pub fn with_attribute(mut self, key: &'static str, value: impl Into<AttributeValue>) -> Self {
+        self.attributes.push((key, value.into()));
+        self
+    }
+
+    fn to_json(&self) -> String {
+        let unix_nanos = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos()
+        };
+        let mut json = format!(
+            r#"{{"traceId":"{:032x}","spanId":"{:016x}","#,
+            self.trace_id.0, self.span_id.0
+        );
+        if let Some(parent) = self.parent_span_id {
+            write!(json, r#""parentSpanId":"{:016x}","#, parent.0).unwrap();
+        }
+        let attributes: Vec<String> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| attribute_to_json(key, value))
+            .collect();
+        write!(
+            json,
+            r#""name":{},"kind":1,"startTimeUnixNano":"{}","endTimeUnixNano":"{}","attributes":[{}]}}"#,
+            json_string(self.name),
+            unix_nanos(self.start),
+            unix_nanos(self.end),
+            attributes.join(",")
+        )
+        .unwrap();
+        json
+    }
+}
+
+/// OTLPのJSONでは、64ビットの整数は文字列で表す。
+fn attribute_to_json(key: &str, value: &AttributeValue) -> String {
+    let value = match value {
+        AttributeValue::String(s) => format!(r#"{{"stringValue":{}}}"#, json_string(s)),
+        AttributeValue::Int(i) => format!(r#"{{"intValue":"{}"}}"#, i),
+    };
+    format!(r#"{{"key":{},"value":{}}}"#, json_string(key), value)
+}
+
+/// OTLP/HTTPのExportTraceServiceRequestをJSONで表す。
+pub fn export_request_json(spans: &[Span]) -> String {
+    let spans: Vec<String> = spans.iter().map(Span::to_json).collect();
+    format!(
+        r#"{{"resourceSpans":[{{"resource":{{"attributes":[{}]}},"scopeSpans":[{{"scope":{{"name":{}}},"spans":[{}]}}]}}]}}"#,
+        attribute_to_json("service.name", &SERVICE_NAME.into()),
+        json_string(SERVICE_NAME),
+        spans.join(",")
+    )
+}
+
+/// SpanをOTLP/HTTP(JSON)でJaegerやTempoなどのコレクタに送るExporter。
+/// すべてのピアで同じExporterを共有する。Spanはまとめて送り、
+/// コレクタに送れなかった場合や送信が追いつかない場合は捨てる。
+#[derive(Debug, Clone)]
+pub struct OtlpExporter(mpsc::Sender<Span>);
+
+impl OtlpExporter {
+    /// endpointのコレクタにSpanを送るタスクを起動する。tokioのランタイムの中で呼び出す必要がある。
+    pub fn spawn(endpoint: SocketAddr) -> Self {
+        let (sender, receiver) = mpsc::channel(SPAN_CHANNEL_CAPACITY);
+        tokio::spawn(export_spans(endpoint, receiver));
+        Self(sender)
+    }
+
+    pub fn export(&self, span: Span) {
+        // 送信が追いつかない場合は、BGPの処理を待たせずに捨てる。
+        let _ = self.0.try_send(span);
+    }
+}
+
+async fn export_spans(endpoint: SocketAddr, mut receiver: mpsc::Receiver<Span>) {
+    let mut spans = vec![];
+    loop {
+        let first = match receiver.recv().await {
+            Some(span) => span,
+            None => return,
+        };
+        spans.push(first);
+        let deadline = tokio::time::sleep(EXPORT_INTERVAL);
+        tokio::pin!(deadline);
+        while spans.len() < MAXIMUM_SPANS_PER_EXPORT {
+            tokio::select! {
+                span = receiver.recv() => match span {
+                    Some(span) => spans.push(span),
+                    None => break,
+                },
+                _ = &mut deadline => break,
+            }
+        }
+        if let Err(e) = post(endpoint, &export_request_json(&spans)).await {
+            log_warning!("{}個のSpanを送れませんでした。{:?}", spans.len(), e);
+        }
+        spans.clear();
+    }
+}
+
+/// bodyをendpointにPOSTし、2xxの応答が返ってこなければエラーにする。
+async fn post(endpoint: SocketAddr, body: &str) -> Result<()> {
+    let mut stream = TcpStream::connect(endpoint).await.context(format!(
+        "OTLPのコレクタ{}に接続できませんでした。",
+        endpoint
+    ))?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        TRACES_PATH,
+        endpoint,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or("");
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(anyhow::anyhow!(
+            "OTLPのコレクタがエラーを返しました。{}",
+            status_line
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn child_span_belongs_to_trace_of_parent() {
+        let start = UNIX_EPOCH + Duration::from_secs(1);
+        let root = Span::root("bgp.update", start, start + Duration::from_millis(5));
+        let child = root
+            .child("bgp.best-path", start, start + Duration::from_millis(1))
+            .with_attribute("loc-rib.routes", 3usize)
+            .with_attribute("bgp.peer", "127.0.0.2");
+
+        assert_eq!(child.trace_id, root.trace_id);
+        assert_eq!(child.parent_span_id, Some(root.span_id));
+        assert_ne!(child.span_id, root.span_id);
+        let json = export_request_json(&[child]);
+        assert!(json.starts_with(
+            r#"{"resourceSpans":[{"resource":{"attributes":[{"key":"service.name","value":{"stringValue":"how-to-create-bgp"}}]}"#
+        ));
+        assert!(json.contains(&format!(r#""parentSpanId":"{:016x}""#, root.span_id.0)));
+        assert!(json.contains(
+            r#""name":"bgp.best-path","kind":1,"startTimeUnixNano":"1000000000","endTimeUnixNano":"1001000000""#
+        ));
+        assert!(json.contains(
+            r#""attributes":[{"key":"loc-rib.routes","value":{"intValue":"3"}},{"key":"bgp.peer","value":{"stringValue":"127.0.0.2"}}]"#
+        ));
+    }
+
+    #[tokio::test]
+    async fn exporter_posts_spans_to_collector() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let exporter = OtlpExporter::spawn(listener.local_addr().unwrap());
+        let now = SystemTime::now();
+        exporter.export(Span::root("bgp.fsm.transition", now, now));
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = vec![];
+        let mut buffer = [0; 4096];
+        while !String::from_utf8_lossy(&request).contains("bgp.fsm.transition") {
+            let n = stream.read(&mut buffer).await.unwrap();
+            assert!(n > 0);
+            request.extend_from_slice(&buffer[..n]);
+        }
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        let request = String::from_utf8_lossy(&request);
+        assert!(request.starts_with("POST /v1/traces HTTP/1.1\r\n"));
+        assert!(request.contains("Content-Type: application/json\r\n"));
+    }
+}
@@ -1,5 +1,10 @@
-mod header;
+pub mod capability;
+#[cfg(feature = "tokio-codec")]
+pub mod codec;
+pub mod decode;
+pub mod header;
 pub mod keepalive;
 pub mod message;
+pub mod notification;
 pub mod open;
 pub mod update;
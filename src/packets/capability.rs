@@ -0,0 +1,247 @@
+use crate::bgp_type::{AddressFamily, AutonomousSystemNumber};
+use crate::error::ConvertBytesToBgpMessageError;
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// OpenMessageのOptional ParameterのうちCapabilities(RFC 5492)のParameter Type。
+const CAPABILITIES_PARAMETER_TYPE: u8 = 2;
+
+/// Multiprotocol Extensions Capability(RFC 4760)のCapability Code。
+const MULTIPROTOCOL_EXTENSIONS: u8 = 1;
+
+/// 4オクテットAS番号のSupport Capability(RFC 6793)のCapability Code。
+const FOUR_OCTET_AS_NUMBER: u8 = 65;
+
+/// Long-Lived Graceful Restart Capability(RFC 9494)のCapability Code。
+const LONG_LIVED_GRACEFUL_RESTART: u8 = 71;
+
+/// Long-lived Stale Timeは24bitで表すため、これより長くはできない。
+pub const MAXIMUM_LLGR_STALE_TIME: u32 = 0xFF_FFFF;
+
+/// OpenMessageで広告する1つのCapability。
+#[derive(PartialEq, Eq, Debug, Clone, Hash)]
+pub enum Capability {
+    /// Multiprotocol Extensions。このAFI/SAFIのルートを交換できることを表す。
+    Multiprotocol(AddressFamily),
+    /// 4オクテットのAS番号を扱えることを表す。値は広告した機器の本来のAS番号。
+    FourOctetAs(AutonomousSystemNumber),
+    /// Long-Lived Graceful Restart。ルートを長時間staleとして保持できるAFI/SAFIを並べる。
+    LongLivedGracefulRestart(Vec<LlgrFamily>),
+    /// 対応していないCapability。受信したまま保持する。
+    Unknown { code: u8, value: Bytes },
+}
+
+/// Long-Lived Graceful Restart Capabilityの、1つのAFI/SAFIの設定。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub struct LlgrFamily {
+    pub afi: u16,
+    pub safi: u8,
+    /// 再起動の間もこのAFI/SAFIの転送を続けていたか(F bit)。
+    pub forwarding_state_preserved: bool,
+    /// セッションが切れた後、ルートをstaleとして保持する秒数。
+    pub stale_time: u32,
+}
+
+impl LlgrFamily {
+    pub fn ipv4_unicast(stale_time: u32) -> Self {
+        let family = AddressFamily::IPV4_UNICAST;
+        Self {
+            afi: family.afi,
+            safi: family.safi,
+            forwarding_state_preserved: false,
+            stale_time: stale_time.min(MAXIMUM_LLGR_STALE_TIME),
+        }
+    }
+
+    pub fn family(&self) -> AddressFamily {
+        AddressFamily::new(self.afi, self.safi)
+    }
+
+    pub fn is_ipv4_unicast(&self) -> bool {
+        self.family() == AddressFamily::IPV4_UNICAST
+    }
+}
+
+impl Capability {
+    /// OpenMessageのOptional Parametersに含まれるCapabilityを読み取る。
+    /// Capabilities以外のOptional Parameterは読み飛ばす。
+    pub fn from_optional_parameters(
+        bytes: &[u8],
+    ) -> Result<Vec<Capability>, ConvertBytesToBgpMessageError> {
+        let mut capabilities = vec![];
+        for (parameter_type, value) in split_tlvs(bytes, "Optional Parameter")? {
+            if parameter_type != CAPABILITIES_PARAMETER_TYPE {
+                continue;
+            }
+            for (code, value) in split_tlvs(value, "Capability")? {
+                capabilities.push(Capability::from_code_and_value(code, value)?);
+            }
+        }
+        Ok(capabilities)
+    }
+
+    fn from_code_and_value(code: u8, value: &[u8]) -> Result<Self, ConvertBytesToBgpMessageError> {
+        match code {
+            MULTIPROTOCOL_EXTENSIONS => match value {
+                [afi_high, afi_low, _reserved, safi] => Ok(Capability::Multiprotocol(
+                    AddressFamily::new(u16::from_be_bytes([*afi_high, *afi_low]), *safi),
+                )),
+                _ => Err(ConvertBytesToBgpMessageError::InvalidLength {
+                    field: "Multiprotocol Extensions Capability",
+                    length: value.len(),
+                }),
+            },
+            FOUR_OCTET_AS_NUMBER => match value {
+                [a, b, c, d] => Ok(Capability::FourOctetAs(AutonomousSystemNumber::from(
+                    u32::from_be_bytes([*a, *b, *c, *d]),
+                ))),
+                _ => Err(ConvertBytesToBgpMessageError::InvalidLength {
+                    field: "4-octet AS Number Capability",
+                    length: value.len(),
+                }),
+            },
+            LONG_LIVED_GRACEFUL_RESTART => {
+                if !value.len().is_multiple_of(7) {
+                    return Err(ConvertBytesToBgpMessageError::InvalidLength {
+                        field: "Long-Lived Graceful Restart Capability",
+                        length: value.len(),
+                    });
+                }
+                let families = value
+                    .chunks(7)
+                    .map(|family| LlgrFamily {
+                        afi: u16::from_be_bytes([family[0], family[1]]),
+                        safi: family[2],
+                        forwarding_state_preserved: family[3] & 0x80 != 0,
+                        stale_time: u32::from_be_bytes([0, family[4], family[5], family[6]]),
+                    })
+                    .collect();
+                Ok(Capability::LongLivedGracefulRestart(families))
+            }
+            code => Ok(Capability::Unknown {
+                code,
+                value: Bytes::copy_from_slice(value),
+            }),
+        }
+    }
+
+    /// capabilitiesを1つのCapabilities Optional Parameterにする。
+    /// capabilitiesが空であればOptional Parameterも空にする。
+    pub fn to_optional_parameters(capabilities: &[Capability]) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        if capabilities.is_empty() {
+            return bytes;
+        }
+        let mut value = BytesMut::new();
+        for capability in capabilities {
+            let (code, capability_value) = capability.code_and_value();
+            value.put_u8(code);
+            value.put_u8(capability_value.len() as u8);
+            value.put(&capability_value[..]);
+        }
+        bytes.put_u8(CAPABILITIES_PARAMETER_TYPE);
+        bytes.put_u8(value.len() as u8);
+        bytes.put(&value[..]);
+        bytes
+    }
+
+    fn code_and_value(&self) -> (u8, BytesMut) {
+        match self {
+            Capability::Multiprotocol(family) => {
+                let mut value = BytesMut::new();
+                value.put_u16(family.afi);
+                value.put_u8(0);
+                value.put_u8(family.safi);
+                (MULTIPROTOCOL_EXTENSIONS, value)
+            }
+            Capability::FourOctetAs(as_number) => {
+                let mut value = BytesMut::new();
+                value.put_u32(u32::from(*as_number));
+                (FOUR_OCTET_AS_NUMBER, value)
+            }
+            Capability::LongLivedGracefulRestart(families) => {
+                let mut value = BytesMut::new();
+                for family in families {
+                    value.put_u16(family.afi);
+                    value.put_u8(family.safi);
+                    value.put_u8(if family.forwarding_state_preserved {
+                        0x80
+                    } else {
+                        0
+                    });
+                    value.put(&family.stale_time.to_be_bytes()[1..]);
+                }
+                (LONG_LIVED_GRACEFUL_RESTART, value)
+            }
+            Capability::Unknown { code, value } => (*code, BytesMut::from(&value[..])),
+        }
+    }
+}
+
+/// 1オクテットのType、1オクテットのLength、Valueが並んだbytes列を分割する。
+fn split_tlvs<'a>(
+    mut bytes: &'a [u8],
+    field: &'static str,
+) -> Result<Vec<(u8, &'a [u8])>, ConvertBytesToBgpMessageError> {
+    let mut tlvs = vec![];
+    while !bytes.is_empty() {
+        let (type_, length) = match bytes {
+            [type_, length, ..] => (*type_, *length as usize),
+            _ => return Err(ConvertBytesToBgpMessageError::Truncated { field }),
+        };
+        let value = bytes
+            .get(2..2 + length)
+            .ok_or(ConvertBytesToBgpMessageError::Truncated { field })?;
+        tlvs.push((type_, value));
+        bytes = &bytes[2 + length..];
+    }
+    Ok(tlvs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capabilities_round_trip_through_optional_parameters() {
+        let capabilities = vec![
+            Capability::Multiprotocol(AddressFamily::IPV6_UNICAST),
+            Capability::LongLivedGracefulRestart(vec![LlgrFamily::ipv4_unicast(86400)]),
+            Capability::FourOctetAs(4200000000.into()),
+            Capability::Unknown {
+                code: 70,
+                value: Bytes::new(),
+            },
+        ];
+        let bytes = Capability::to_optional_parameters(&capabilities);
+        assert_eq!(
+            Capability::from_optional_parameters(&bytes).unwrap(),
+            capabilities
+        );
+    }
+
+    #[test]
+    fn multiprotocol_capability_is_encoded_with_reserved_octet() {
+        let bytes = Capability::to_optional_parameters(&[Capability::Multiprotocol(
+            AddressFamily::IPV4_UNICAST,
+        )]);
+        assert_eq!(&bytes[..], &[2, 6, 1, 4, 0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn llgr_stale_time_is_limited_to_24_bits() {
+        assert_eq!(
+            LlgrFamily::ipv4_unicast(u32::MAX).stale_time,
+            MAXIMUM_LLGR_STALE_TIME
+        );
+    }
+
+    #[test]
+    fn truncated_capability_is_error() {
+        assert!(matches!(
+            Capability::from_optional_parameters(&[2, 4, 71, 7, 0, 1]),
+            Err(ConvertBytesToBgpMessageError::Truncated {
+                field: "Capability"
+            })
+        ));
+    }
+}
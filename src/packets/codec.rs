@@ -0,0 +1,117 @@
+use crate::error::{ConvertBgpMessageToBytesError, ConvertBytesToBgpMessageError};
+use crate::packets::header::{MAXIMUM_MESSAGE_LENGTH, MINIMUM_MESSAGE_LENGTH};
+use crate::packets::message::Message;
+use bytes::{Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// BGPのMessageの区切りでストリームを分割する、tokio-utilのcodec。
+/// `Framed<TcpStream, BgpCodec>`とすると、Messageを送受信するStreamとSinkになる。
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BgpCodec;
+
+impl BgpCodec {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// bufferの先頭から1つのMessageを表すbytes列を切り出す。
+    /// 1つのMessage全体を受信できていなければNoneを返す。
+    /// Headerのlengthが不正な場合はHeaderだけを切り出し、
+    /// Message::try_fromでBad Message Lengthのエラーにする。
+    pub fn split_frame(buffer: &mut BytesMut) -> Option<Bytes> {
+        if buffer.len() < MINIMUM_MESSAGE_LENGTH as usize {
+            return None;
+        }
+        let mut length = u16::from_be_bytes([buffer[16], buffer[17]]) as usize;
+        if !(MINIMUM_MESSAGE_LENGTH as usize..=MAXIMUM_MESSAGE_LENGTH as usize).contains(&length) {
+            length = MINIMUM_MESSAGE_LENGTH as usize;
+        }
+        if buffer.len() < length {
+            // 残りを受信したときに、bufferを確保し直さずに済むようにする。
+            buffer.reserve(length - buffer.len());
+            return None;
+        }
+        // 切り出したbytes列を1度だけfreezeし、
+        // Path Attributeの値などはコピーせずにこのbytes列を参照させる。
+        Some(buffer.split_to(length).freeze())
+    }
+}
+
+impl Decoder for BgpCodec {
+    type Item = Message;
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, Self::Error> {
+        Self::split_frame(src).map(Message::try_from).transpose()
+    }
+}
+
+impl Encoder<Message> for BgpCodec {
+    type Error = ConvertBgpMessageToBytesError;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let bytes: BytesMut = message.into();
+        if bytes.len() > MAXIMUM_MESSAGE_LENGTH as usize {
+            return Err(ConvertBgpMessageToBytesError::TooLong {
+                length: bytes.len(),
+            });
+        }
+        dst.extend_from_slice(&bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bgp_type::HoldTime;
+    use crate::packets::notification::ErrorCode;
+    use futures::{SinkExt, StreamExt};
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    #[tokio::test]
+    async fn messages_can_be_sent_and_received_through_framed_stream() {
+        let (writer, reader) = tokio::io::duplex(64);
+        let messages = vec![
+            Message::new_open(64512.into(), "127.0.0.1".parse().unwrap(), HoldTime::new()),
+            Message::new_keepalive(),
+            Message::new_notification(ErrorCode::Cease, 2, vec![1]),
+        ];
+        let mut writer = FramedWrite::new(writer, BgpCodec::new());
+        let mut reader = FramedRead::new(reader, BgpCodec::new());
+
+        let sent = messages.clone();
+        let send = tokio::spawn(async move {
+            for message in sent {
+                writer.send(message).await.unwrap();
+            }
+        });
+        for expected in messages {
+            assert_eq!(reader.next().await.unwrap().unwrap(), expected);
+        }
+        send.await.unwrap();
+    }
+
+    #[test]
+    fn partial_message_is_not_decoded_until_whole_message_is_received() {
+        let bytes: BytesMut = Message::new_keepalive().into();
+        let mut buffer = BytesMut::from(&bytes[..10]);
+        assert!(BgpCodec.decode(&mut buffer).unwrap().is_none());
+
+        buffer.extend_from_slice(&bytes[10..]);
+        assert_eq!(
+            BgpCodec.decode(&mut buffer).unwrap(),
+            Some(Message::new_keepalive())
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn message_with_invalid_length_is_decoding_error() {
+        let mut buffer = BytesMut::from(&[0xff; 16][..]);
+        buffer.extend_from_slice(&[0, 5, 4]);
+        let error = BgpCodec.decode(&mut buffer).unwrap_err();
+        assert!(error.notification_error().is_some());
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,112 @@
+use crate::error::ConvertBytesToBgpMessageError;
+use crate::packets::header::Header;
+use crate::packets::message::Message;
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use std::fmt;
+
+/// bytes列から読み取った1つのメッセージ。`howbgp decode`で表示する。
+#[derive(Debug)]
+pub struct DecodedMessage {
+    /// bytes列の先頭からのメッセージの位置。
+    pub offset: usize,
+    pub result: Result<Message, ConvertBytesToBgpMessageError>,
+}
+
+impl fmt::Display for DecodedMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.result {
+            Ok(message) => write!(f, "offset {}: {:#?}", self.offset, message),
+            Err(e) => write!(f, "offset {}: error: {}", self.offset, e),
+        }
+    }
+}
+
+/// 1つ以上のBGPのメッセージが連続したbytes列を、メッセージごとに読み取る。
+/// Headerを読み取れない場合や、Headerのlengthよりbytes列が短い場合は、
+/// 以降のメッセージの境界が分からないため、そのエラーを最後の要素として返す。
+pub fn decode_messages(bytes: &[u8]) -> Vec<DecodedMessage> {
+    let mut messages = vec![];
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let rest = &bytes[offset..];
+        let length = match Header::try_from(rest) {
+            Ok(header) => header.length() as usize,
+            Err(e) => {
+                messages.push(DecodedMessage {
+                    offset,
+                    result: Err(e),
+                });
+                break;
+            }
+        };
+        if rest.len() < length {
+            messages.push(DecodedMessage {
+                offset,
+                result: Err(ConvertBytesToBgpMessageError::Truncated { field: "Message" }),
+            });
+            break;
+        }
+        messages.push(DecodedMessage {
+            offset,
+            result: Message::try_from(Bytes::copy_from_slice(&rest[..length])),
+        });
+        offset += length;
+    }
+    messages
+}
+
+/// 16進数の文字列をbytes列にする。空白、`:`の区切りと、先頭の`0x`は無視する。
+pub fn parse_hex(text: &str) -> Result<Vec<u8>> {
+    let text = text.trim();
+    let digits: Vec<char> = text
+        .strip_prefix("0x")
+        .unwrap_or(text)
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ':')
+        .collect();
+    digits
+        .chunks(2)
+        .enumerate()
+        .map(|(i, pair)| {
+            if pair.len() < 2 {
+                return Err(anyhow::anyhow!("16進数の桁数が奇数です。"));
+            }
+            let pair: String = pair.iter().collect();
+            u8::from_str_radix(&pair, 16)
+                .context(format!("{}バイト目の`{}`は16進数ではありません。", i, pair))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+
+    #[test]
+    fn consecutive_messages_are_decoded_with_their_offsets() {
+        let mut bytes = BytesMut::from(Message::new_keepalive());
+        bytes.extend_from_slice(&BytesMut::from(Message::new_keepalive()));
+        // 3つ目のメッセージはHeaderの途中で途切れている。
+        bytes.extend_from_slice(&[0xff; 10]);
+
+        let messages = decode_messages(&bytes);
+
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1].offset, 19);
+        assert!(matches!(messages[1].result, Ok(Message::Keepalive(_))));
+        assert_eq!(messages[2].offset, 38);
+        assert!(messages[2].result.is_err());
+    }
+
+    #[test]
+    fn hex_may_contain_separators_and_prefix() {
+        assert_eq!(
+            parse_hex("0xff ff:00\n13").unwrap(),
+            vec![0xff, 0xff, 0x00, 0x13]
+        );
+        assert!(parse_hex("fff").is_err());
+        assert!(parse_hex("zz").is_err());
+    }
+}
@@ -1,4 +1,7 @@
-use crate::error::{ConvertBgpMessageToBytesError, ConvertBytesToBgpMessageError};
+use crate::error::{
+    ConvertBgpMessageToBytesError, ConvertBytesToBgpMessageError, NotificationBuilder,
+    NotificationError,
+};
 use bytes::{BufMut, BytesMut};
 
 #[derive(PartialEq, Eq, Debug, Clone, Hash)]
@@ -7,18 +10,87 @@ pub struct Header {
     pub type_: MessageType,
 }
 
+/// BGP Messageの長さの最小値と最大値(Header含む)。
+pub const MINIMUM_MESSAGE_LENGTH: u16 = 19;
+pub const MAXIMUM_MESSAGE_LENGTH: u16 = 4096;
+
 impl Header {
     pub fn new(length: u16, type_: MessageType) -> Self {
         Self { length, type_ }
     }
+
+    /// Headerを含めたMessageのオクテット数。
+    pub fn length(&self) -> u16 {
+        self.length
+    }
+
+    /// Headerのlengthが実際のMessageのbytes列の長さと一致し、
+    /// Message Typeごとの長さの制約を満たしているか確認する。
+    pub fn check_length(&self, bytes_len: usize) -> Result<(), ConvertBytesToBgpMessageError> {
+        let length = self.length as usize;
+        let satisfies_type_constraint = match self.type_ {
+            MessageType::Open => length >= 29,
+            MessageType::Update => length >= 23,
+            MessageType::Notification => length >= 21,
+            MessageType::Keepalive => length == 19,
+        };
+        if length != bytes_len || !satisfies_type_constraint {
+            return Err(bad_message_length(
+                self.length,
+                format!(
+                    "{:?}のHeaderのlength {}が不正です。実際の長さは{}です。",
+                    self.type_, self.length, bytes_len
+                ),
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Bad Message LengthのNotificationError。Dataには不正なLengthフィールドの値を入れる。
+fn bad_message_length(length: u16, description: String) -> NotificationError {
+    NotificationBuilder::bad_message_length(length).build(description)
 }
 
 impl TryFrom<BytesMut> for Header {
     type Error = ConvertBytesToBgpMessageError;
 
     fn try_from(bytes: BytesMut) -> Result<Self, Self::Error> {
+        Header::try_from(&bytes[..])
+    }
+}
+
+impl TryFrom<&[u8]> for Header {
+    type Error = ConvertBytesToBgpMessageError;
+
+    /// bytesの先頭19 octetsをHeaderとして読み取る。
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() < MINIMUM_MESSAGE_LENGTH as usize {
+            let length = bytes.len() as u16;
+            return Err(bad_message_length(
+                length,
+                format!("Headerの長さ{}が19 octetsより短いです。", length),
+            )
+            .into());
+        }
         let marker = &bytes[0..16];
+        if marker != [255u8; 16] {
+            return Err(NotificationBuilder::connection_not_synchronized()
+                .build(format!(
+                    "Markerがすべて1ではありません。marker: {:?}",
+                    marker
+                ))
+                .into());
+        }
         let length = u16::from_be_bytes([bytes[16], bytes[17]]);
+        if !(MINIMUM_MESSAGE_LENGTH..=MAXIMUM_MESSAGE_LENGTH).contains(&length) {
+            return Err(bad_message_length(
+                length,
+                format!("Headerのlength {}が19..4096の範囲外です。", length),
+            )
+            .into());
+        }
         let type_ = bytes[18].try_into()?;
         Ok(Header { length, type_ })
     }
@@ -42,6 +114,7 @@ pub enum MessageType {
     Open,
     Keepalive,
     Update,
+    Notification,
 }
 
 impl TryFrom<u8> for MessageType {
@@ -51,8 +124,14 @@ impl TryFrom<u8> for MessageType {
         match num {
             1 => Ok(MessageType::Open),
             2 => Ok(MessageType::Update),
+            3 => Ok(MessageType::Notification),
             4 => Ok(MessageType::Keepalive),
-            _ => Err(Self::Error::from(anyhow::anyhow!("Num {0}をBGP Message Typeに変換することが出来ませんでした。numは1-4が期待されています。", num))),
+            _ => Err(NotificationBuilder::bad_message_type(num)
+                .build(format!(
+                    "Num {0}をBGP Message Typeに変換することが出来ませんでした。numは1-4が期待されています。",
+                    num
+                ))
+                .into()),
         }
     }
 }
@@ -62,6 +141,7 @@ impl From<MessageType> for u8 {
         match type_ {
             MessageType::Open => 1,
             MessageType::Update => 2,
+            MessageType::Notification => 3,
             MessageType::Keepalive => 4,
         }
     }
@@ -70,6 +150,7 @@ impl From<MessageType> for u8 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::packets::notification::{message_header_error, ErrorCode};
 
     #[test]
     fn convert_bytes_to_header_and_header_to_bytes() {
@@ -79,4 +160,63 @@ mod tests {
 
         assert_eq!(header, header2);
     }
+
+    #[test]
+    fn header_with_wrong_marker_is_connection_not_synchronized_error() {
+        let mut header_bytes: BytesMut = Header::new(19, MessageType::Keepalive).into();
+        header_bytes[3] = 0;
+        let error = Header::try_from(header_bytes).unwrap_err();
+        let notification = error.notification_error().unwrap();
+
+        assert_eq!(notification.error_code, ErrorCode::MessageHeaderError);
+        assert_eq!(
+            notification.error_subcode,
+            message_header_error::CONNECTION_NOT_SYNCHRONIZED
+        );
+    }
+
+    #[test]
+    fn header_length_out_of_range_is_bad_message_length_error() {
+        for length in [0u16, 18, 4097] {
+            let header_bytes: BytesMut = Header::new(length, MessageType::Update).into();
+            let error = Header::try_from(header_bytes).unwrap_err();
+            let notification = error.notification_error().unwrap();
+
+            assert_eq!(
+                notification.error_subcode,
+                message_header_error::BAD_MESSAGE_LENGTH
+            );
+            assert_eq!(&notification.data[..], &length.to_be_bytes()[..]);
+        }
+    }
+
+    #[test]
+    fn header_length_must_match_actual_length() {
+        let header = Header::new(19, MessageType::Keepalive);
+        assert!(header.check_length(19).is_ok());
+        assert!(header.check_length(20).is_err());
+
+        let header = Header::new(20, MessageType::Keepalive);
+        assert!(header.check_length(20).is_err());
+    }
+
+    #[test]
+    fn unknown_message_type_is_bad_message_type_error() {
+        let mut header_bytes: BytesMut = Header::new(19, MessageType::Keepalive).into();
+        header_bytes[18] = 5;
+        let error = Header::try_from(header_bytes).unwrap_err();
+        let notification = error.notification_error().unwrap();
+
+        assert_eq!(
+            notification.error_subcode,
+            message_header_error::BAD_MESSAGE_TYPE
+        );
+        assert_eq!(&notification.data[..], &[5]);
+    }
+
+    #[test]
+    fn too_short_bytes_are_bad_message_length_error() {
+        let error = Header::try_from(&[255u8; 10][..]).unwrap_err();
+        assert!(error.notification_error().is_some());
+    }
 }
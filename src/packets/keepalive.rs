@@ -1,4 +1,4 @@
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 
 use crate::error::ConvertBytesToBgpMessageError;
 
@@ -14,9 +14,21 @@ impl TryFrom<BytesMut> for KeepaliveMessage {
     type Error = ConvertBytesToBgpMessageError;
 
     fn try_from(bytes: BytesMut) -> Result<Self, Self::Error> {
-        let header = Header::try_from(bytes)?;
+        Self::try_from(bytes.freeze())
+    }
+}
+
+impl TryFrom<Bytes> for KeepaliveMessage {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(bytes: Bytes) -> Result<Self, Self::Error> {
+        let header = Header::try_from(&bytes[..])?;
+        header.check_length(bytes.len())?;
         if header.type_ != MessageType::Keepalive {
-            return Err(anyhow::anyhow!("bytes列のtypeがkeepaliveではありません。").into());
+            return Err(ConvertBytesToBgpMessageError::UnexpectedMessageType {
+                expected: MessageType::Keepalive,
+                actual: header.type_,
+            });
         }
         Ok(Self { header })
     }
@@ -35,3 +47,16 @@ impl KeepaliveMessage {
         Self { header }
     }
 }
+
+impl Default for KeepaliveMessage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for KeepaliveMessage {
+    fn arbitrary(_: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self::new())
+    }
+}
@@ -1,11 +1,12 @@
 use std::net::Ipv4Addr;
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 
-use crate::bgp_type::AutonomousSystemNumber;
+use crate::bgp_type::{AutonomousSystemNumber, HoldTime};
 use crate::error::{ConvertBgpMessageToBytesError, ConvertBytesToBgpMessageError};
 use crate::packets::header::{Header, MessageType};
 use crate::packets::keepalive::KeepaliveMessage;
+use crate::packets::notification::{ErrorCode, NotificationMessage};
 use crate::packets::open::OpenMessage;
 use crate::packets::update::UpdateMessage;
 
@@ -14,6 +15,7 @@ pub enum Message {
     Open(OpenMessage),
     Keepalive(KeepaliveMessage),
     Update(UpdateMessage),
+    Notification(NotificationMessage),
 }
 
 // MessageとBytesの相互変換用
@@ -21,19 +23,39 @@ impl TryFrom<BytesMut> for Message {
     type Error = ConvertBytesToBgpMessageError;
 
     fn try_from(bytes: BytesMut) -> Result<Self, Self::Error> {
-        let header_bytes_length = 19;
+        Self::try_from(bytes.freeze())
+    }
+}
+
+/// 受信したbytes列から、コピーせずにMessageを読み取る。
+/// Path Attributeの値などは、bytesと同じバッファを参照する。
+impl TryFrom<Bytes> for Message {
+    type Error = ConvertBytesToBgpMessageError;
 
-        if bytes.len() < header_bytes_length {
-            return Err(Self::Error::from(anyhow::anyhow!(
-                "BytesからMessageに変換できませんでした。Bytesの長さが最小の長さより短いです。"
-            )));
-        };
+    fn try_from(bytes: Bytes) -> Result<Self, Self::Error> {
+        Self::decode(bytes, false)
+    }
+}
 
-        let header = Header::try_from(BytesMut::from(&bytes[0..header_bytes_length]))?;
+impl Message {
+    /// try_fromと同じだが、four_octet_asがtrueの場合は4オクテットAS Capabilityを
+    /// 交換したピアから受信したものとして、UpdateMessageのAS番号を4オクテットで読み取る。
+    pub fn decode(
+        bytes: Bytes,
+        four_octet_as: bool,
+    ) -> Result<Self, ConvertBytesToBgpMessageError> {
+        // Headerの長さのチェックはHeader::try_fromとMessage Typeごとのtry_fromで行う。
+        let header = Header::try_from(&bytes[..])?;
         match header.type_ {
             MessageType::Open => Ok(Message::Open(OpenMessage::try_from(bytes)?)),
             MessageType::Keepalive => Ok(Message::Keepalive(KeepaliveMessage::try_from(bytes)?)),
-            MessageType::Update => Ok(Message::Update(UpdateMessage::try_from(bytes)?)),
+            MessageType::Update => Ok(Message::Update(UpdateMessage::decode(
+                bytes,
+                four_octet_as,
+            )?)),
+            MessageType::Notification => {
+                Ok(Message::Notification(NotificationMessage::try_from(bytes)?))
+            }
         }
     }
 }
@@ -45,16 +67,41 @@ impl From<Message> for BytesMut {
             Message::Open(open) => open.into(),
             Message::Keepalive(keepalive) => keepalive.into(),
             Message::Update(update) => update.into(),
+            Message::Notification(notification) => notification.into(),
         }
     }
 }
 
 impl Message {
-    pub fn new_open(my_as_number: AutonomousSystemNumber, my_ip_addr: Ipv4Addr) -> Self {
-        Self::Open(OpenMessage::new(my_as_number, my_ip_addr))
+    pub fn new_open(
+        my_as_number: AutonomousSystemNumber,
+        my_ip_addr: Ipv4Addr,
+        hold_time: HoldTime,
+    ) -> Self {
+        Self::Open(OpenMessage::new(my_as_number, my_ip_addr, hold_time))
     }
 
     pub fn new_keepalive() -> Self {
         Self::Keepalive(KeepaliveMessage::new())
     }
+
+    pub fn new_notification(
+        error_code: ErrorCode,
+        error_subcode: u8,
+        data: impl Into<Bytes>,
+    ) -> Self {
+        Self::Notification(NotificationMessage::new(error_code, error_subcode, data))
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Message {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(match u.int_in_range(0..=3)? {
+            0 => Message::Open(u.arbitrary()?),
+            1 => Message::Keepalive(u.arbitrary()?),
+            2 => Message::Update(u.arbitrary()?),
+            _ => Message::Notification(u.arbitrary()?),
+        })
+    }
 }
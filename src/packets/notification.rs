@@ -0,0 +1,307 @@
+use std::fmt;
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use super::header::{Header, MessageType};
+use crate::error::ConvertBytesToBgpMessageError;
+
+#[derive(PartialEq, Eq, Debug, Clone, Hash)]
+pub struct NotificationMessage {
+    header: Header,
+    error_code: ErrorCode,
+    error_subcode: u8,
+    data: Bytes,
+}
+
+/// NotificationMessageのError Code。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum ErrorCode {
+    MessageHeaderError,
+    OpenMessageError,
+    UpdateMessageError,
+    HoldTimerExpired,
+    FiniteStateMachineError,
+    Cease,
+    Unknown(u8),
+}
+
+impl From<u8> for ErrorCode {
+    fn from(code: u8) -> Self {
+        match code {
+            1 => ErrorCode::MessageHeaderError,
+            2 => ErrorCode::OpenMessageError,
+            3 => ErrorCode::UpdateMessageError,
+            4 => ErrorCode::HoldTimerExpired,
+            5 => ErrorCode::FiniteStateMachineError,
+            6 => ErrorCode::Cease,
+            n => ErrorCode::Unknown(n),
+        }
+    }
+}
+
+impl From<ErrorCode> for u8 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::MessageHeaderError => 1,
+            ErrorCode::OpenMessageError => 2,
+            ErrorCode::UpdateMessageError => 3,
+            ErrorCode::HoldTimerExpired => 4,
+            ErrorCode::FiniteStateMachineError => 5,
+            ErrorCode::Cease => 6,
+            ErrorCode::Unknown(n) => n,
+        }
+    }
+}
+
+/// Message Header ErrorのError Subcode。
+pub mod message_header_error {
+    pub const CONNECTION_NOT_SYNCHRONIZED: u8 = 1;
+    pub const BAD_MESSAGE_LENGTH: u8 = 2;
+    pub const BAD_MESSAGE_TYPE: u8 = 3;
+}
+
+/// OPEN Message ErrorのError Subcode。
+pub mod open_message_error {
+    pub const UNSUPPORTED_VERSION_NUMBER: u8 = 1;
+    pub const BAD_PEER_AS: u8 = 2;
+    pub const BAD_BGP_IDENTIFIER: u8 = 3;
+    pub const UNSUPPORTED_OPTIONAL_PARAMETER: u8 = 4;
+    pub const UNACCEPTABLE_HOLD_TIME: u8 = 6;
+}
+
+/// UPDATE Message ErrorのError Subcode。
+pub mod update_message_error {
+    pub const MALFORMED_ATTRIBUTE_LIST: u8 = 1;
+    pub const UNRECOGNIZED_WELL_KNOWN_ATTRIBUTE: u8 = 2;
+    pub const MISSING_WELL_KNOWN_ATTRIBUTE: u8 = 3;
+    pub const ATTRIBUTE_FLAGS_ERROR: u8 = 4;
+    pub const ATTRIBUTE_LENGTH_ERROR: u8 = 5;
+    pub const INVALID_ORIGIN_ATTRIBUTE: u8 = 6;
+    pub const INVALID_NEXT_HOP_ATTRIBUTE: u8 = 8;
+    pub const OPTIONAL_ATTRIBUTE_ERROR: u8 = 9;
+    pub const INVALID_NETWORK_FIELD: u8 = 10;
+    pub const MALFORMED_AS_PATH: u8 = 11;
+}
+
+/// Finite State Machine ErrorのError Subcode(RFC 6608)。
+pub mod finite_state_machine_error {
+    pub const UNSPECIFIED_ERROR: u8 = 0;
+    pub const RECEIVE_UNEXPECTED_MESSAGE_IN_OPEN_SENT_STATE: u8 = 1;
+    pub const RECEIVE_UNEXPECTED_MESSAGE_IN_OPEN_CONFIRM_STATE: u8 = 2;
+    pub const RECEIVE_UNEXPECTED_MESSAGE_IN_ESTABLISHED_STATE: u8 = 3;
+}
+
+/// CeaseのError Subcode(RFC 4486, RFC 8538)。
+pub mod cease {
+    pub const MAXIMUM_NUMBER_OF_PREFIXES_REACHED: u8 = 1;
+    pub const ADMINISTRATIVE_SHUTDOWN: u8 = 2;
+    pub const PEER_DE_CONFIGURED: u8 = 3;
+    pub const ADMINISTRATIVE_RESET: u8 = 4;
+    pub const CONNECTION_REJECTED: u8 = 5;
+    pub const OTHER_CONFIGURATION_CHANGE: u8 = 6;
+    pub const CONNECTION_COLLISION_RESOLUTION: u8 = 7;
+    pub const OUT_OF_RESOURCES: u8 = 8;
+    pub const HARD_RESET: u8 = 9;
+
+    /// Shutdown Communicationの最大のオクテット数(RFC 9003)。
+    pub const MAXIMUM_SHUTDOWN_COMMUNICATION_LENGTH: usize = 255;
+
+    pub fn name(subcode: u8) -> Option<&'static str> {
+        match subcode {
+            MAXIMUM_NUMBER_OF_PREFIXES_REACHED => Some("Maximum Number of Prefixes Reached"),
+            ADMINISTRATIVE_SHUTDOWN => Some("Administrative Shutdown"),
+            PEER_DE_CONFIGURED => Some("Peer De-configured"),
+            ADMINISTRATIVE_RESET => Some("Administrative Reset"),
+            CONNECTION_REJECTED => Some("Connection Rejected"),
+            OTHER_CONFIGURATION_CHANGE => Some("Other Configuration Change"),
+            CONNECTION_COLLISION_RESOLUTION => Some("Connection Collision Resolution"),
+            OUT_OF_RESOURCES => Some("Out of Resources"),
+            HARD_RESET => Some("Hard Reset"),
+            _ => None,
+        }
+    }
+
+    /// Shutdown Communicationを付けられるSubcodeであるか。
+    pub fn has_shutdown_communication(subcode: u8) -> bool {
+        subcode == ADMINISTRATIVE_SHUTDOWN || subcode == ADMINISTRATIVE_RESET
+    }
+}
+
+impl NotificationMessage {
+    /// CeaseのNotificationMessageを作成する。
+    /// Administrative Shutdown, Administrative Resetの場合は、
+    /// shutdown_communicationをRFC 9003のShutdown CommunicationとしてDataに含める。
+    /// 255オクテットを超える場合は、UTF-8の文字の境界で切り詰める。
+    pub fn new_cease(subcode: u8, shutdown_communication: Option<&str>) -> Self {
+        let mut data = BytesMut::new();
+        if let Some(message) =
+            shutdown_communication.filter(|_| cease::has_shutdown_communication(subcode))
+        {
+            let mut length = message
+                .len()
+                .min(cease::MAXIMUM_SHUTDOWN_COMMUNICATION_LENGTH);
+            while !message.is_char_boundary(length) {
+                length -= 1;
+            }
+            data.put_u8(length as u8);
+            data.put(&message.as_bytes()[..length]);
+        }
+        Self::new(ErrorCode::Cease, subcode, data)
+    }
+
+    pub fn new(error_code: ErrorCode, error_subcode: u8, data: impl Into<Bytes>) -> Self {
+        let data = data.into();
+        // Header + Error Code (1 octet) + Error Subcode (1 octet) + Data
+        let header = Header::new(19 + 2 + data.len() as u16, MessageType::Notification);
+        Self {
+            header,
+            error_code,
+            error_subcode,
+            data,
+        }
+    }
+
+    pub fn error_code(&self) -> ErrorCode {
+        self.error_code
+    }
+
+    pub fn error_subcode(&self) -> u8 {
+        self.error_subcode
+    }
+
+    pub fn data(&self) -> &Bytes {
+        &self.data
+    }
+
+    /// CeaseのNotificationMessageに含まれるShutdown Communication。
+    /// 含まれていない場合や、長さやUTF-8として不正な場合はNoneを返す。
+    pub fn shutdown_communication(&self) -> Option<String> {
+        if self.error_code != ErrorCode::Cease
+            || !cease::has_shutdown_communication(self.error_subcode)
+        {
+            return None;
+        }
+        let (length, message) = self.data.split_first()?;
+        let message = message.get(..*length as usize)?;
+        String::from_utf8(message.to_vec()).ok()
+    }
+}
+
+impl fmt::Display for NotificationMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} (subcode {}", self.error_code, self.error_subcode)?;
+        if self.error_code == ErrorCode::Cease {
+            if let Some(name) = cease::name(self.error_subcode) {
+                write!(f, ": {}", name)?;
+            }
+        }
+        write!(f, ")")?;
+        if let Some(message) = self.shutdown_communication() {
+            write!(f, " \"{}\"", message)?;
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<BytesMut> for NotificationMessage {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(bytes: BytesMut) -> Result<Self, Self::Error> {
+        Self::try_from(bytes.freeze())
+    }
+}
+
+impl TryFrom<Bytes> for NotificationMessage {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(bytes: Bytes) -> Result<Self, Self::Error> {
+        let header = Header::try_from(&bytes[..])?;
+        if header.type_ != MessageType::Notification {
+            return Err(ConvertBytesToBgpMessageError::UnexpectedMessageType {
+                expected: MessageType::Notification,
+                actual: header.type_,
+            });
+        }
+        header.check_length(bytes.len())?;
+        if bytes.len() < 21 {
+            return Err(ConvertBytesToBgpMessageError::Truncated {
+                field: "NotificationMessageのError Code, Error Subcode",
+            });
+        }
+        let error_code = ErrorCode::from(bytes[19]);
+        let error_subcode = bytes[20];
+        let data = bytes.slice(21..);
+        Ok(Self {
+            header,
+            error_code,
+            error_subcode,
+            data,
+        })
+    }
+}
+
+impl From<NotificationMessage> for BytesMut {
+    fn from(message: NotificationMessage) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        bytes.put::<BytesMut>(message.header.into());
+        bytes.put_u8(message.error_code.into());
+        bytes.put_u8(message.error_subcode);
+        bytes.put(&message.data[..]);
+        bytes
+    }
+}
+
+/// Error CodeはUnknown(1)のように、bytes列から読み取ったときと異なる値を生成しない。
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for NotificationMessage {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let error_code = ErrorCode::from(u.arbitrary::<u8>()?);
+        let data: Vec<u8> = u.arbitrary()?;
+        Ok(Self::new(
+            error_code,
+            u.arbitrary()?,
+            BytesMut::from(&data[..]),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_bytes_to_notification_message_and_notification_message_to_bytes() {
+        let notification =
+            NotificationMessage::new(ErrorCode::Cease, 2, BytesMut::from(&b"shutdown"[..]));
+        let notification_bytes: BytesMut = notification.clone().into();
+        let notification2: NotificationMessage = notification_bytes.try_into().unwrap();
+
+        assert_eq!(notification, notification2);
+    }
+
+    #[test]
+    fn cease_notification_carries_shutdown_communication() {
+        let notification =
+            NotificationMessage::new_cease(cease::ADMINISTRATIVE_SHUTDOWN, Some("メンテナンス中"));
+
+        assert_eq!(
+            notification.shutdown_communication(),
+            Some("メンテナンス中".to_owned())
+        );
+        assert_eq!(
+            notification.to_string(),
+            "Cease (subcode 2: Administrative Shutdown) \"メンテナンス中\""
+        );
+    }
+
+    #[test]
+    fn shutdown_communication_is_truncated_at_char_boundary() {
+        // 3オクテットの文字を100文字並べると300オクテットになる。
+        let message = "あ".repeat(100);
+        let notification =
+            NotificationMessage::new_cease(cease::ADMINISTRATIVE_RESET, Some(&message));
+
+        assert_eq!(notification.data()[0], 255);
+        assert_eq!(notification.shutdown_communication(), Some("あ".repeat(85)));
+    }
+}
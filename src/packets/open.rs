@@ -1,10 +1,11 @@
 use std::net::Ipv4Addr;
 
+use super::capability::Capability;
 use super::header::{self, Header, MessageType};
-use crate::bgp_type::{AutonomousSystemNumber, HoldTime, Version};
-use crate::error::ConvertBytesToBgpMessageError;
+use crate::bgp_type::{AutonomousSystemNumber, HoldTime, Version, AS_TRANS};
+use crate::error::{ConvertBytesToBgpMessageError, NotificationBuilder};
 use anyhow::Context;
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 
 #[derive(PartialEq, Eq, Debug, Clone, Hash)]
 pub struct OpenMessage {
@@ -14,48 +15,133 @@ pub struct OpenMessage {
     hold_time: HoldTime,
     bgp_identifier: Ipv4Addr,
     optional_parameter_length: u8,
-    optional_parameters: BytesMut,
+    optional_parameters: Bytes,
 }
 
 impl OpenMessage {
-    pub fn new(my_as_number: AutonomousSystemNumber, my_ip_addr: Ipv4Addr) -> Self {
+    /// my_as_numberが2オクテットで表せない場合は、My Autonomous SystemをAS_TRANSにする。
+    pub fn new(
+        my_as_number: AutonomousSystemNumber,
+        my_ip_addr: Ipv4Addr,
+        hold_time: HoldTime,
+    ) -> Self {
         let header = Header::new(29, MessageType::Open);
         Self {
             header,
             version: Version::new(),
-            my_as_number,
-            hold_time: HoldTime::new(),
+            my_as_number: AutonomousSystemNumber::from(u32::from(my_as_number.to_two_octet())),
+            hold_time,
             bgp_identifier: my_ip_addr,
             optional_parameter_length: 0,
-            optional_parameters: BytesMut::new(),
+            optional_parameters: Bytes::new(),
         }
     }
 }
 
+impl OpenMessage {
+    pub fn my_as_number(&self) -> AutonomousSystemNumber {
+        self.my_as_number
+    }
+
+    /// 対向機器の本来のAS番号。4オクテットAS Capabilityがあれば、その値を使う。
+    /// My Autonomous SystemがCapabilityの値と矛盾する場合や、Capabilityがないのに
+    /// AS_TRANSである場合は、AS番号が不正であるとしてNoneを返す(RFC 6793)。
+    pub fn as_number(&self) -> Option<AutonomousSystemNumber> {
+        let four_octet_as = self.capabilities().into_iter().find_map(|c| match c {
+            Capability::FourOctetAs(as_number) => Some(as_number),
+            _ => None,
+        });
+        match four_octet_as {
+            Some(as_number) => {
+                let sent_as = AutonomousSystemNumber::from(u32::from(as_number.to_two_octet()));
+                (self.my_as_number == sent_as).then_some(as_number)
+            }
+            None => (self.my_as_number != AS_TRANS).then_some(self.my_as_number),
+        }
+    }
+
+    /// 対向機器が4オクテットAS Capabilityを広告したか。
+    pub fn supports_four_octet_as(&self) -> bool {
+        self.capabilities()
+            .iter()
+            .any(|c| matches!(c, Capability::FourOctetAs(_)))
+    }
+
+    pub fn hold_time(&self) -> HoldTime {
+        self.hold_time
+    }
+
+    /// Connection Collisionの解決で比べる、送信したBGPスピーカーのBGP Identifier。
+    pub fn bgp_identifier(&self) -> Ipv4Addr {
+        self.bgp_identifier
+    }
+
+    /// Optional Parametersをcapabilitiesを含むCapabilities Optional Parameterにする。
+    pub fn with_capabilities(mut self, capabilities: &[Capability]) -> Self {
+        let optional_parameters = Capability::to_optional_parameters(capabilities);
+        self.optional_parameter_length = optional_parameters.len() as u8;
+        self.optional_parameters = optional_parameters.freeze();
+        self.header = Header::new(
+            29 + self.optional_parameter_length as u16,
+            MessageType::Open,
+        );
+        self
+    }
+
+    /// 対向機器が広告したCapability。Optional Parametersは受信したときに検証している。
+    pub fn capabilities(&self) -> Vec<Capability> {
+        Capability::from_optional_parameters(&self.optional_parameters).unwrap_or_default()
+    }
+}
+
 impl TryFrom<BytesMut> for OpenMessage {
     type Error = ConvertBytesToBgpMessageError;
 
     fn try_from(bytes: BytesMut) -> Result<Self, Self::Error> {
-        let header = Header::try_from(BytesMut::from(&bytes[0..19]))?;
+        Self::try_from(bytes.freeze())
+    }
+}
+
+impl TryFrom<Bytes> for OpenMessage {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(bytes: Bytes) -> Result<Self, Self::Error> {
+        let header = Header::try_from(&bytes[..])?;
+        if header.type_ != MessageType::Open {
+            return Err(ConvertBytesToBgpMessageError::UnexpectedMessageType {
+                expected: MessageType::Open,
+                actual: header.type_,
+            });
+        }
+        header.check_length(bytes.len())?;
         let version: Version = bytes[19].try_into()?;
-        let my_as_number = AutonomousSystemNumber::from(u16::from_be_bytes(
+        let my_as_number = AutonomousSystemNumber::from(u32::from(u16::from_be_bytes(
             bytes[20..22].try_into().context(format!(
                 "AS番号のbytes表現`{:?}`からAS番号に変換できませんでした",
                 &bytes[20..22]
             ))?,
-        ));
+        )));
         let hold_time = HoldTime::from(u16::from_be_bytes(bytes[22..24].try_into().context(
             format!(
                 "HoldTimeのbytes表現`{:?}`からHoldTimeに変換できませんでした。",
                 &bytes[22..24]
             ),
         )?));
+        if !hold_time.is_acceptable() {
+            return Err(NotificationBuilder::unacceptable_hold_time()
+                .build(format!(
+                    "Hold Time {:?}は0か3秒以上である必要があります。",
+                    hold_time
+                ))
+                .into());
+        }
         let b: [u8; 4] = bytes[24..28]
             .try_into()
             .context("Ip Addressのoctetsを取得できませんでした。")?;
         let bgp_identifier = Ipv4Addr::from(b);
         let optional_parameter_length = bytes[28];
-        let optional_parameters = BytesMut::from(&bytes[29..]);
+        let optional_parameters = bytes.slice(29..);
+        Capability::from_optional_parameters(&optional_parameters)?;
 
         Ok(OpenMessage {
             header,
@@ -75,7 +161,7 @@ impl From<OpenMessage> for BytesMut {
         let header_bytes: &BytesMut = &message.header.into();
         bytes.put(&header_bytes[..]);
         bytes.put_u8(message.version.into());
-        bytes.put_u16(message.my_as_number.into());
+        bytes.put_u16(message.my_as_number.to_two_octet());
         bytes.put_u16(message.hold_time.into());
         bytes.put(&message.bgp_identifier.octets()[..]);
         bytes.put_u8(message.optional_parameter_length);
@@ -85,16 +171,151 @@ impl From<OpenMessage> for BytesMut {
     }
 }
 
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for OpenMessage {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self::new(u.arbitrary()?, u.arbitrary()?, u.arbitrary()?))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::packets::capability::LlgrFamily;
+    use crate::packets::keepalive::KeepaliveMessage;
+    use crate::packets::notification::open_message_error;
 
     #[test]
     fn convert_bytes_to_open_message_and_open_message_to_bytes() {
-        let open_message = OpenMessage::new(64512.into(), "127.0.0.1".parse().unwrap());
+        let open_message =
+            OpenMessage::new(64512.into(), "127.0.0.1".parse().unwrap(), HoldTime::new());
         let open_message_bytes: BytesMut = open_message.clone().into();
         let open_message2: OpenMessage = open_message_bytes.try_into().unwrap();
 
         assert_eq!(open_message, open_message2);
     }
+
+    #[test]
+    fn open_message_with_capabilities_round_trips() {
+        let capabilities = vec![Capability::LongLivedGracefulRestart(vec![
+            LlgrFamily::ipv4_unicast(3600),
+        ])];
+        let open_message =
+            OpenMessage::new(64512.into(), "127.0.0.1".parse().unwrap(), HoldTime::new())
+                .with_capabilities(&capabilities);
+        let bytes: BytesMut = open_message.clone().into();
+        let open_message2 = OpenMessage::try_from(bytes).unwrap();
+
+        assert_eq!(open_message2, open_message);
+        assert_eq!(open_message2.capabilities(), capabilities);
+    }
+
+    #[test]
+    fn four_octet_as_number_is_sent_as_as_trans() {
+        let open_message = OpenMessage::new(
+            4200000000.into(),
+            "127.0.0.1".parse().unwrap(),
+            HoldTime::new(),
+        );
+        let bytes: BytesMut = open_message.clone().into();
+
+        assert_eq!(&bytes[20..22], &[0x5b, 0xa0]);
+        assert_eq!(
+            OpenMessage::try_from(bytes).unwrap().my_as_number(),
+            crate::bgp_type::AS_TRANS
+        );
+    }
+
+    #[test]
+    fn as_number_is_taken_from_four_octet_as_capability() {
+        let open = |my_as_number: u32, capability: Option<u32>| {
+            let capabilities: Vec<Capability> = capability
+                .map(|a| Capability::FourOctetAs(a.into()))
+                .into_iter()
+                .collect();
+            OpenMessage::new(
+                my_as_number.into(),
+                "127.0.0.1".parse().unwrap(),
+                HoldTime::new(),
+            )
+            .with_capabilities(&capabilities)
+        };
+
+        assert_eq!(
+            open(4200000000, Some(4200000000)).as_number(),
+            Some(4200000000.into())
+        );
+        assert_eq!(open(64512, Some(64512)).as_number(), Some(64512.into()));
+        assert_eq!(open(64512, None).as_number(), Some(64512.into()));
+        assert!(open(4200000000, Some(64512)).supports_four_octet_as());
+        assert_eq!(open(4200000000, Some(64512)).as_number(), None);
+        assert_eq!(open(4200000000, None).as_number(), None);
+    }
+
+    #[test]
+    fn bytes_of_other_message_type_are_not_open_message() {
+        let keepalive: BytesMut = KeepaliveMessage::new().into();
+
+        assert!(matches!(
+            OpenMessage::try_from(keepalive),
+            Err(ConvertBytesToBgpMessageError::UnexpectedMessageType {
+                expected: MessageType::Open,
+                actual: MessageType::Keepalive,
+            })
+        ));
+    }
+
+    #[test]
+    fn open_message_with_hold_time_one_or_two_is_rejected() {
+        for (hold_time, acceptable) in [(0, true), (1, false), (2, false), (3, true)] {
+            let open_message = OpenMessage::new(
+                64512.into(),
+                "127.0.0.1".parse().unwrap(),
+                HoldTime::from(hold_time),
+            );
+            let bytes: BytesMut = open_message.into();
+            let result = OpenMessage::try_from(bytes);
+
+            assert_eq!(result.is_ok(), acceptable);
+            if let Err(e) = result {
+                assert_eq!(
+                    e.notification_error().unwrap().error_subcode,
+                    open_message_error::UNACCEPTABLE_HOLD_TIME
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn open_message_with_unsupported_version_is_rejected() {
+        let open_message =
+            OpenMessage::new(64512.into(), "127.0.0.1".parse().unwrap(), HoldTime::new());
+        let mut bytes: BytesMut = open_message.into();
+        bytes[19] = 5;
+        let error = OpenMessage::try_from(bytes).unwrap_err();
+        let notification = error.notification_error().unwrap();
+
+        assert_eq!(
+            notification.error_subcode,
+            open_message_error::UNSUPPORTED_VERSION_NUMBER
+        );
+        // 対応している最大のVersionである4を、2 octetsで通知する。
+        assert_eq!(&notification.data[..], &[0, 4]);
+    }
+
+    #[test]
+    fn open_message_with_older_version_is_rejected() {
+        let open_message =
+            OpenMessage::new(64512.into(), "127.0.0.1".parse().unwrap(), HoldTime::new());
+        let mut bytes: BytesMut = open_message.into();
+        bytes[19] = 3;
+        let error = OpenMessage::try_from(bytes).unwrap_err();
+        let notification = error.notification_error().unwrap();
+
+        assert_eq!(
+            notification.error_subcode,
+            open_message_error::UNSUPPORTED_VERSION_NUMBER
+        );
+        assert_eq!(&notification.data[..], &[0, 4]);
+    }
 }
@@ -1,12 +1,11 @@
-use std::collections::HashMap;
+use crate::bgp_type::Ipv4Network;
+use bytes::{BufMut, Bytes, BytesMut};
 
-use crate::routing::Ipv4Network;
-use bytes::{BufMut, BytesMut};
-
-use crate::error::ConvertBytesToBgpMessageError;
-use crate::packets::header::Header;
-use crate::path_attribute::{AsPath, Origin, PathAttribute};
-use crate::routing::{AdjRibOut, RibEntry};
+use crate::error::{BuildUpdateMessageError, ConvertBytesToBgpMessageError, NotificationBuilder};
+use crate::packets::header::{Header, MAXIMUM_MESSAGE_LENGTH};
+use crate::path_attribute::{AsPath, Community, Origin, PathAttribute};
+use std::mem::discriminant;
+use std::net::Ipv4Addr;
 
 use super::header::MessageType;
 
@@ -21,16 +20,35 @@ pub struct UpdateMessage {
     // NLRIのオクテット数はBGP UpdateMessageに含めず、
     // Headerのサイズを計算することにしか使用しないため、
     // メンバに含めていない。
+    /// 4オクテットAS Capabilityを交換したピアとの間のUpdateMessageであるか。
+    /// trueの場合は、AS_PATHのAS番号を4オクテットで表す。
+    four_octet_as: bool,
 }
 
 impl UpdateMessage {
-    fn new(
+    pub fn new(
         path_attributes: Vec<PathAttribute>,
         network_layer_reachability_information: Vec<Ipv4Network>,
         withdrawn_routes: Vec<Ipv4Network>,
     ) -> Self {
-        let path_attributes_length =
-            path_attributes.iter().map(|p| p.bytes_len()).sum::<usize>() as u16;
+        Self::with_encoding(
+            path_attributes,
+            network_layer_reachability_information,
+            withdrawn_routes,
+            false,
+        )
+    }
+
+    fn with_encoding(
+        path_attributes: Vec<PathAttribute>,
+        network_layer_reachability_information: Vec<Ipv4Network>,
+        withdrawn_routes: Vec<Ipv4Network>,
+        four_octet_as: bool,
+    ) -> Self {
+        let path_attributes_length = path_attributes
+            .iter()
+            .map(|p| p.encoded_len(four_octet_as))
+            .sum::<usize>() as u16;
         let network_layer_reachability_information_length = network_layer_reachability_information
             .iter()
             .map(|r| r.bytes_len())
@@ -40,8 +58,11 @@ impl UpdateMessage {
             .map(|w| w.bytes_len())
             .sum::<usize>() as u16;
         let header_minimum_length: u16 = 19;
+        // Withdrawn Routes LengthとTotal Path Attribute Lengthのオクテット数。
+        let length_fields_length: u16 = 2 + 2;
         let header = Header::new(
             header_minimum_length
+                + length_fields_length
                 + path_attributes_length
                 + network_layer_reachability_information_length
                 + withdrawn_routes_length,
@@ -54,7 +75,227 @@ impl UpdateMessage {
             path_attributes,
             path_attributes_length,
             network_layer_reachability_information,
+            four_octet_as,
+        }
+    }
+
+    /// four_octet_asがtrueであれば、4オクテットAS Capabilityを交換したピアに送るために
+    /// AS_PATHのAS番号を4オクテットで表すUpdateMessageにする。
+    pub fn with_four_octet_as(self, four_octet_as: bool) -> Self {
+        if self.four_octet_as == four_octet_as {
+            return self;
+        }
+        Self::with_encoding(
+            self.path_attributes,
+            self.network_layer_reachability_information,
+            self.withdrawn_routes,
+            four_octet_as,
+        )
+    }
+
+    /// networksを取り消すUpdateMessage。最大長を超えないように、必要なら複数に分ける。
+    pub fn withdrawals(networks: Vec<Ipv4Network>) -> Vec<UpdateMessage> {
+        // HeaderとWithdrawn Routes Length、Total Path Attribute Lengthのオクテット数。
+        let maximum_withdrawn_routes_length = MAXIMUM_MESSAGE_LENGTH as usize - 19 - 2 - 2;
+        let mut updates = vec![];
+        let mut withdrawn_routes = vec![];
+        let mut withdrawn_routes_length = 0;
+        for network in networks {
+            if withdrawn_routes_length + network.bytes_len() > maximum_withdrawn_routes_length {
+                updates.push(UpdateMessage::new(
+                    vec![],
+                    vec![],
+                    std::mem::take(&mut withdrawn_routes),
+                ));
+                withdrawn_routes_length = 0;
+            }
+            withdrawn_routes_length += network.bytes_len();
+            withdrawn_routes.push(network);
+        }
+        if !withdrawn_routes.is_empty() {
+            updates.push(UpdateMessage::new(vec![], vec![], withdrawn_routes));
+        }
+        updates
+    }
+
+    /// path_attributesでnetworksを広告するUpdateMessage。最大長を超えないように、必要なら複数に分ける。
+    pub fn announcements(
+        path_attributes: Vec<PathAttribute>,
+        networks: Vec<Ipv4Network>,
+    ) -> Vec<UpdateMessage> {
+        // 4オクテットAS Capabilityを交換したピアにも送れるように、長いほうのオクテット数で分ける。
+        let path_attributes_length: usize = path_attributes
+            .iter()
+            .map(|p| p.bytes_len().max(p.encoded_len(true)))
+            .sum();
+        // HeaderとWithdrawn Routes Length、Total Path Attribute Length、Path Attributeのオクテット数。
+        let maximum_nlri_length =
+            (MAXIMUM_MESSAGE_LENGTH as usize - 19 - 2 - 2).saturating_sub(path_attributes_length);
+        let mut updates = vec![];
+        let mut nlri = vec![];
+        let mut nlri_length = 0;
+        for network in networks {
+            if !nlri.is_empty() && nlri_length + network.bytes_len() > maximum_nlri_length {
+                updates.push(UpdateMessage::new(
+                    path_attributes.clone(),
+                    std::mem::take(&mut nlri),
+                    vec![],
+                ));
+                nlri_length = 0;
+            }
+            nlri_length += network.bytes_len();
+            nlri.push(network);
+        }
+        if !nlri.is_empty() {
+            updates.push(UpdateMessage::new(path_attributes, nlri, vec![]));
+        }
+        updates
+    }
+
+    /// Path Attributeの要件やメッセージの長さを確認しながらUpdateMessageを組み立てる。
+    pub fn builder() -> UpdateMessageBuilder {
+        UpdateMessageBuilder::new()
+    }
+
+    pub fn withdrawn_routes(&self) -> &Vec<Ipv4Network> {
+        &self.withdrawn_routes
+    }
+
+    pub fn path_attributes(&self) -> &Vec<PathAttribute> {
+        &self.path_attributes
+    }
+
+    pub fn network_layer_reachability_information(&self) -> &Vec<Ipv4Network> {
+        &self.network_layer_reachability_information
+    }
+
+    /// IPv4 UnicastのEnd-of-RIB(RFC 4724)。
+    pub fn end_of_rib() -> Self {
+        Self::new(vec![], vec![], vec![])
+    }
+
+    /// IPv4 UnicastのEnd-of-RIB(RFC 4724)であるか。
+    /// 初期のルートをすべて送り終えたことを、何も含まないUpdateMessageで表す。
+    pub fn is_end_of_rib(&self) -> bool {
+        self.withdrawn_routes.is_empty()
+            && self.path_attributes.is_empty()
+            && self.network_layer_reachability_information.is_empty()
+    }
+}
+
+/// テストやルートの注入のために、UpdateMessageを1つずつ組み立てるビルダー。
+/// ```ignore
+/// let update = UpdateMessage::builder()
+///     .withdraw("10.100.210.0/24".parse()?)
+///     .announce("10.100.220.0/24".parse()?)
+///     .origin(Origin::Igp)
+///     .as_path(AsPath::AsSequence(vec![64512.into()]))
+///     .next_hop("10.200.100.2".parse()?)
+///     .build()?;
+/// ```
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct UpdateMessageBuilder {
+    withdrawn_routes: Vec<Ipv4Network>,
+    path_attributes: Vec<PathAttribute>,
+    network_layer_reachability_information: Vec<Ipv4Network>,
+}
+
+impl UpdateMessageBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn withdraw(mut self, network: Ipv4Network) -> Self {
+        self.withdrawn_routes.push(network);
+        self
+    }
+
+    pub fn announce(mut self, network: Ipv4Network) -> Self {
+        self.network_layer_reachability_information.push(network);
+        self
+    }
+
+    /// Path Attributeを追加する。同じ種類のPath Attributeがすでにあれば置き換える。
+    pub fn path_attribute(mut self, path_attribute: PathAttribute) -> Self {
+        match self
+            .path_attributes
+            .iter_mut()
+            .find(|p| discriminant(*p) == discriminant(&path_attribute))
+        {
+            Some(p) => *p = path_attribute,
+            None => self.path_attributes.push(path_attribute),
+        }
+        self
+    }
+
+    pub fn origin(self, origin: Origin) -> Self {
+        self.path_attribute(PathAttribute::Origin(origin))
+    }
+
+    pub fn as_path(self, as_path: AsPath) -> Self {
+        self.path_attribute(PathAttribute::AsPath(as_path))
+    }
+
+    pub fn next_hop(self, next_hop: Ipv4Addr) -> Self {
+        self.path_attribute(PathAttribute::NextHop(next_hop))
+    }
+
+    pub fn communities(self, communities: Vec<Community>) -> Self {
+        self.path_attribute(PathAttribute::Communities(communities))
+    }
+
+    /// NLRIを広告する場合は、Well-knownかつ必須のORIGIN、AS_PATH、NEXT_HOPがなければエラーにする。
+    /// NLRIがない場合はPath Attributeを含められない。
+    /// また、メッセージの長さが最大長を超える場合もエラーにする。
+    pub fn build(self) -> Result<UpdateMessage, BuildUpdateMessageError> {
+        if self.network_layer_reachability_information.is_empty() {
+            if !self.path_attributes.is_empty() {
+                return Err(BuildUpdateMessageError::PathAttributesWithoutNlri);
+            }
+        } else {
+            for (name, required) in [
+                ("ORIGIN", PathAttribute::Origin(Origin::Igp)),
+                ("AS_PATH", PathAttribute::AsPath(AsPath::AsSequence(vec![]))),
+                ("NEXT_HOP", PathAttribute::NextHop(Ipv4Addr::UNSPECIFIED)),
+            ] {
+                if !self
+                    .path_attributes
+                    .iter()
+                    .any(|p| discriminant(p) == discriminant(&required))
+                {
+                    return Err(BuildUpdateMessageError::MissingPathAttribute(name));
+                }
+            }
         }
+
+        // Header、Withdrawn Routes Length、Total Path Attribute Lengthのオクテット数。
+        let length = 19
+            + 2
+            + 2
+            + self
+                .withdrawn_routes
+                .iter()
+                .map(|w| w.bytes_len())
+                .sum::<usize>()
+            + self
+                .path_attributes
+                .iter()
+                .map(|p| p.bytes_len())
+                .sum::<usize>()
+            + self
+                .network_layer_reachability_information
+                .iter()
+                .map(|r| r.bytes_len())
+                .sum::<usize>();
+        if length > MAXIMUM_MESSAGE_LENGTH as usize {
+            return Err(BuildUpdateMessageError::TooLong { length });
+        }
+
+        Ok(UpdateMessage::new(
+            self.path_attributes,
+            self.network_layer_reachability_information,
+            self.withdrawn_routes,
+        ))
     }
 }
 
@@ -72,7 +313,7 @@ impl From<UpdateMessage> for BytesMut {
         message
             .path_attributes
             .iter()
-            .for_each(|r| bytes.put::<BytesMut>(r.into()));
+            .for_each(|r| bytes.put(r.to_bytes(message.four_octet_as)));
 
         message
             .network_layer_reachability_information
@@ -84,29 +325,120 @@ impl From<UpdateMessage> for BytesMut {
 
 impl TryFrom<BytesMut> for UpdateMessage {
     type Error = ConvertBytesToBgpMessageError;
-    fn try_from(value: BytesMut) -> Result<Self, Self::Error> {
-        todo!()
+
+    fn try_from(bytes: BytesMut) -> Result<Self, Self::Error> {
+        Self::try_from(bytes.freeze())
     }
 }
 
-/// AdjRibOutからUpdateMessageに変換する。
-/// PathAttributeごとにUpdateMessageが分かれるためVec<UpdateMessage>の戻り値にしている。
-impl From<&AdjRibOut> for Vec<UpdateMessage> {
-    fn from(rib: &AdjRibOut) -> Self {
-        let mut hash_map: HashMap<Vec<PathAttribute>, Vec<Ipv4Network>> = HashMap::new();
-        for entry in &rib.0 {
-            if let Some(routes) = hash_map.get_mut(&entry.path_attributes) {
-                routes.push(entry.network_address);
-            } else {
-                hash_map.insert(entry.path_attributes.clone(), vec![]);
-            }
+impl TryFrom<Bytes> for UpdateMessage {
+    type Error = ConvertBytesToBgpMessageError;
+    fn try_from(bytes: Bytes) -> Result<Self, Self::Error> {
+        Self::decode(bytes, false)
+    }
+}
+
+impl UpdateMessage {
+    /// try_fromと同じだが、four_octet_asがtrueの場合は4オクテットAS Capabilityを
+    /// 交換したピアから受信したものとして、AS_PATHのAS番号を4オクテットで読み取る。
+    pub fn decode(
+        bytes: Bytes,
+        four_octet_as: bool,
+    ) -> Result<Self, ConvertBytesToBgpMessageError> {
+        let header = Header::try_from(&bytes[..])?;
+        if header.type_ != MessageType::Update {
+            return Err(ConvertBytesToBgpMessageError::UnexpectedMessageType {
+                expected: MessageType::Update,
+                actual: header.type_,
+            });
         }
+        header.check_length(bytes.len())?;
 
-        let mut updates = vec![];
-        for (path_attributes, routes) in hash_map.into_iter() {
-            updates.push(UpdateMessage::new(path_attributes, routes, vec![]));
+        // Withdrawn Routes LengthやTotal Path Attribute Lengthが長すぎる場合は
+        // Malformed Attribute List、prefixが不正な場合はInvalid Network Fieldとする。
+        let malformed_attribute_list = |description: &str| {
+            ConvertBytesToBgpMessageError::from(
+                NotificationBuilder::malformed_attribute_list().build(description),
+            )
+        };
+        let invalid_network_field = |e: ConvertBytesToBgpMessageError| {
+            ConvertBytesToBgpMessageError::from(
+                NotificationBuilder::invalid_network_field().build(format!("{:#}", e)),
+            )
+        };
+
+        let withdrawn_routes_length = u16::from_be_bytes(
+            bytes
+                .get(19..21)
+                .ok_or_else(|| {
+                    malformed_attribute_list("Withdrawn Routes Lengthを読み取れませんでした。")
+                })?
+                .try_into()
+                .unwrap(),
+        );
+        let withdrawn_routes_end = 21 + withdrawn_routes_length as usize;
+        let withdrawn_routes =
+            Ipv4Network::from_u8_slice(bytes.get(21..withdrawn_routes_end).ok_or_else(|| {
+                malformed_attribute_list("Withdrawn Routesの長さがbytes列より長いです。")
+            })?)
+            .map_err(invalid_network_field)?;
+
+        let path_attributes_length = u16::from_be_bytes(
+            bytes
+                .get(withdrawn_routes_end..withdrawn_routes_end + 2)
+                .ok_or_else(|| {
+                    malformed_attribute_list("Total Path Attribute Lengthを読み取れませんでした。")
+                })?
+                .try_into()
+                .unwrap(),
+        );
+        let path_attributes_start = withdrawn_routes_end + 2;
+        let path_attributes_end = path_attributes_start + path_attributes_length as usize;
+        if bytes.len() < path_attributes_end {
+            return Err(malformed_attribute_list(
+                "Path Attributesの長さがbytes列より長いです。",
+            ));
         }
-        updates
+        let path_attributes = PathAttribute::decode(
+            &bytes.slice(path_attributes_start..path_attributes_end),
+            four_octet_as,
+        )?;
+
+        let network_layer_reachability_information =
+            Ipv4Network::from_u8_slice(&bytes[path_attributes_end..])
+                .map_err(invalid_network_field)?;
+
+        // 不正なAIGPのように読み飛ばしたPath Attributeがあっても、
+        // bytes列に戻したときに各Lengthが中身と一致するように、受信したLengthは使わない。
+        Ok(Self::with_encoding(
+            path_attributes,
+            network_layer_reachability_information,
+            withdrawn_routes,
+            four_octet_as,
+        ))
+    }
+}
+
+/// Arbitraryで生成するPath Attribute、ルートの最大の数。
+#[cfg(feature = "arbitrary")]
+const MAXIMUM_ARBITRARY_ITEMS: usize = 16;
+
+#[cfg(feature = "arbitrary")]
+fn arbitrary_items<'a, T: arbitrary::Arbitrary<'a>>(
+    u: &mut arbitrary::Unstructured<'a>,
+) -> arbitrary::Result<Vec<T>> {
+    let len = u.int_in_range(0..=MAXIMUM_ARBITRARY_ITEMS)?;
+    (0..len).map(|_| u.arbitrary()).collect()
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for UpdateMessage {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self::new(
+            arbitrary_items(u)?,
+            arbitrary_items(u)?,
+            arbitrary_items(u)?,
+        ))
     }
 }
 
@@ -114,24 +446,176 @@ impl From<&AdjRibOut> for Vec<UpdateMessage> {
 mod tests {
     use super::*;
 
-    async fn update_message_from_adj_rib_out() {
+    #[test]
+    fn withdrawals_are_split_to_fit_in_maximum_message_length() {
+        let networks: Vec<Ipv4Network> = (0..2000u32)
+            .map(|i| format!("10.{}.{}.0/24", i / 256, i % 256).parse().unwrap())
+            .collect();
+        let updates = UpdateMessage::withdrawals(networks.clone());
+
+        assert_eq!(updates.len(), 2);
+        for update in &updates {
+            assert!(BytesMut::from(update.clone()).len() <= MAXIMUM_MESSAGE_LENGTH as usize);
+            assert!(update.network_layer_reachability_information().is_empty());
+        }
+        assert_eq!(
+            updates
+                .iter()
+                .flat_map(|u| u.withdrawn_routes().clone())
+                .collect::<Vec<_>>(),
+            networks
+        );
+        assert!(UpdateMessage::withdrawals(vec![]).is_empty());
+    }
+
+    #[test]
+    fn announcements_are_split_to_fit_in_maximum_message_length() {
+        let networks: Vec<Ipv4Network> = (0..3000u32)
+            .map(|i| format!("10.{}.{}.0/24", i / 256, i % 256).parse().unwrap())
+            .collect();
         let path_attributes = vec![
             PathAttribute::Origin(Origin::Igp),
-            PathAttribute::AsPath(AsPath::AsSequence(vec![64513.into()])),
-            PathAttribute::NextHop("10.200.100.3".parse().unwrap()),
+            PathAttribute::AsPath(AsPath::AsSequence(vec![64512.into()])),
+            PathAttribute::NextHop("10.200.100.2".parse().unwrap()),
         ];
-        let adj_rib_out = AdjRibOut(vec![RibEntry {
-            network_address: "10.100.220.0/24".parse().unwrap(),
-            path_attributes: path_attributes.clone(),
-        }]);
-        let expected_update_message = UpdateMessage::new(
-            path_attributes,
+        let updates = UpdateMessage::announcements(path_attributes.clone(), networks.clone());
+
+        assert_eq!(updates.len(), 3);
+        for update in &updates {
+            assert!(BytesMut::from(update.clone()).len() <= MAXIMUM_MESSAGE_LENGTH as usize);
+            assert_eq!(update.path_attributes(), &path_attributes);
+        }
+        assert_eq!(
+            updates
+                .iter()
+                .flat_map(|u| u.network_layer_reachability_information().clone())
+                .collect::<Vec<_>>(),
+            networks
+        );
+        assert!(UpdateMessage::announcements(path_attributes, vec![]).is_empty());
+    }
+
+    #[test]
+    fn builder_builds_update_message_with_mandatory_path_attributes() {
+        let update = UpdateMessage::builder()
+            .withdraw("10.100.210.0/24".parse().unwrap())
+            .announce("10.100.220.0/24".parse().unwrap())
+            .origin(Origin::Egp)
+            .as_path(AsPath::AsSequence(vec![64512.into()]))
+            .next_hop("10.200.100.2".parse().unwrap())
+            // 同じ種類のPath Attributeは後から設定したものを使う。
+            .origin(Origin::Igp)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            update,
+            UpdateMessage::new(
+                vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::AsSequence(vec![64512.into()])),
+                    PathAttribute::NextHop("10.200.100.2".parse().unwrap()),
+                ],
+                vec!["10.100.220.0/24".parse().unwrap()],
+                vec!["10.100.210.0/24".parse().unwrap()],
+            )
+        );
+    }
+
+    #[test]
+    fn builder_rejects_update_message_that_cannot_be_sent() {
+        // NEXT_HOPがない。
+        assert!(UpdateMessage::builder()
+            .announce("10.100.220.0/24".parse().unwrap())
+            .origin(Origin::Igp)
+            .as_path(AsPath::AsSequence(vec![]))
+            .build()
+            .is_err());
+        // NLRIがないのにPath Attributeがある。
+        assert!(UpdateMessage::builder()
+            .withdraw("10.100.220.0/24".parse().unwrap())
+            .origin(Origin::Igp)
+            .build()
+            .is_err());
+        // 最大長を超える。
+        let too_many_withdrawals = (0..1024u32).fold(UpdateMessage::builder(), |builder, i| {
+            let network = format!("10.{}.{}.0/24", i / 256, i % 256);
+            builder.withdraw(network.parse().unwrap())
+        });
+        assert!(too_many_withdrawals.build().is_err());
+
+        assert!(UpdateMessage::builder()
+            .withdraw("10.100.220.0/24".parse().unwrap())
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn convert_bytes_to_update_message_and_update_message_to_bytes() {
+        let update_message = UpdateMessage::new(
+            vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::AsSequence(vec![64513.into(), 64514.into()])),
+                PathAttribute::NextHop("10.200.100.3".parse().unwrap()),
+            ],
+            vec![
+                "10.100.220.0/24".parse().unwrap(),
+                "10.100.0.0/16".parse().unwrap(),
+            ],
+            vec!["192.168.0.0/23".parse().unwrap()],
+        );
+        let update_message_bytes: BytesMut = update_message.clone().into();
+        assert_eq!(update_message_bytes.len(), 54);
+        let update_message2: UpdateMessage = update_message_bytes.try_into().unwrap();
+
+        assert_eq!(update_message, update_message2);
+    }
+
+    #[test]
+    fn update_message_without_invalid_aigp_can_be_converted_back_to_bytes() {
+        let mut bytes: BytesMut = UpdateMessage::new(
+            vec![PathAttribute::Origin(Origin::Igp)],
             vec!["10.100.220.0/24".parse().unwrap()],
             vec![],
-        );
+        )
+        .into();
+        // Length 1の不正なAIGPを、Originの後ろに追加する。
+        let invalid_aigp = [0b10000000, 26, 1, 0];
+        let path_attributes_end = 23 + 4;
+        let nlri = bytes.split_off(path_attributes_end);
+        bytes.extend_from_slice(&invalid_aigp);
+        bytes.extend_from_slice(&nlri);
+        bytes[21..23].copy_from_slice(&(4u16 + 4).to_be_bytes());
+        let length = bytes.len() as u16;
+        bytes[16..18].copy_from_slice(&length.to_be_bytes());
+
+        let update_message = UpdateMessage::try_from(bytes).unwrap();
         assert_eq!(
-            Vec::<UpdateMessage>::from(&adj_rib_out),
-            vec![expected_update_message]
+            update_message.path_attributes(),
+            &vec![PathAttribute::Origin(Origin::Igp)]
         );
+        let bytes: BytesMut = update_message.clone().into();
+        assert_eq!(UpdateMessage::try_from(bytes).unwrap(), update_message);
+    }
+
+    #[test]
+    fn unknown_path_attribute_borrows_received_buffer() {
+        let unknown = PathAttribute::DontKnow(Bytes::from_static(&[0b11000000, 99, 2, 1, 2]));
+        let bytes: BytesMut = UpdateMessage::new(
+            vec![PathAttribute::Origin(Origin::Igp), unknown.clone()],
+            vec!["10.100.220.0/24".parse().unwrap()],
+            vec![],
+        )
+        .into();
+        let bytes = bytes.freeze();
+
+        let update_message = UpdateMessage::try_from(bytes.clone()).unwrap();
+        match &update_message.path_attributes()[1] {
+            PathAttribute::DontKnow(value) => {
+                assert_eq!(PathAttribute::DontKnow(value.clone()), unknown);
+                assert!(bytes.as_ptr_range().contains(&value.as_ptr()));
+            }
+            attribute => panic!("unexpected attribute {:?}", attribute),
+        }
     }
 }
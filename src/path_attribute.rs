@@ -1,29 +1,305 @@
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 
 use crate::bgp_type::AutonomousSystemNumber;
-use std::{collections::BTreeSet, net::Ipv4Addr};
+use crate::error::{ConfigParseError, ConvertBytesToBgpMessageError, NotificationBuilder};
+use crate::packets::notification::update_message_error;
+use anyhow::Context;
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::sync::Arc;
 
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub enum PathAttribute {
     Origin(Origin),
     AsPath(AsPath),
     NextHop(Ipv4Addr),
-    DontKnow(Vec<u8>), // 対応してないPathAttribute用
+    /// LOCAL_PREF。同じAS内のピアに伝えるルートの優先度で、大きいほど優先する。
+    LocalPref(u32),
+    /// COMMUNITIES(RFC 1997)。
+    Communities(Vec<Community>),
+    /// ACCUMULATED_IGP_METRIC(RFC 7311)。AIGP TLVのmetricを持つ。
+    Aigp(u64),
+    /// BGP Prefix-SID(RFC 8669)。
+    PrefixSid(PrefixSid),
+    DontKnow(Bytes), // 対応してないPathAttribute用
+}
+
+/// 同じ内容のPath Attributeの組を1つのArcで共有するための表。
+/// フルルートを受信しても、Path Attributeの組の種類はルートの数よりずっと少ないため、
+/// RibEntryごとにVec<PathAttribute>を複製せずに済む。
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct PathAttributeTable(HashSet<Arc<Vec<PathAttribute>>>);
+
+impl PathAttributeTable {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// path_attributesと同じ内容のものが表にあればそれを返し、なければ表に追加して返す。
+    pub fn intern(&mut self, path_attributes: &Arc<Vec<PathAttribute>>) -> Arc<Vec<PathAttribute>> {
+        if let Some(interned) = self.0.get(path_attributes.as_ref()) {
+            return Arc::clone(interned);
+        }
+        self.0.insert(Arc::clone(path_attributes));
+        Arc::clone(path_attributes)
+    }
+
+    /// どのRibEntryからも参照されなくなったPath Attributeの組を表から削除する。
+    pub fn remove_unused(&mut self) {
+        self.0.retain(|p| Arc::strong_count(p) > 1);
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// 表にあるPath Attributeの組が使っているおおよそのオクテット数。
+    /// AS_PATHなどがヒープに持つ値の大きさは、bytesにしたときの長さで見積もる。
+    pub fn estimated_bytes(&self) -> usize {
+        self.0
+            .iter()
+            .map(|path_attributes| {
+                // Arcの参照カウントとVec自体の大きさ。
+                2 * std::mem::size_of::<usize>()
+                    + std::mem::size_of::<Vec<PathAttribute>>()
+                    + path_attributes
+                        .iter()
+                        .map(|p| std::mem::size_of::<PathAttribute>() + p.bytes_len())
+                        .sum::<usize>()
+            })
+            .sum()
+    }
 }
 
 impl PathAttribute {
+    /// Attribute Flag, Attribute Type Code, Attribute Lengthを含めた
+    /// bytesにしたときのオクテット数。
     pub fn bytes_len(&self) -> usize {
-        match self {
+        self.encoded_len(false)
+    }
+
+    /// four_octet_asがtrueの場合は、4オクテットAS Capabilityを交換したピアに
+    /// AS番号を4オクテットで表して送るときのオクテット数を返す。
+    pub fn encoded_len(&self, four_octet_as: bool) -> usize {
+        let with_header = |attribute_length: usize| {
+            let attribute_length_octets = if attribute_length < 256 { 1 } else { 2 };
+            1 + 1 + attribute_length_octets + attribute_length
+        };
+        let attribute_length = match self {
             PathAttribute::Origin(o) => 1,
-            PathAttribute::AsPath(a) => a.bytes_len(),
+            PathAttribute::AsPath(a) if four_octet_as => a.bytes_len(4),
+            // AS4_PATHも合わせて送る。
+            PathAttribute::AsPath(a) if a.has_four_octet_as() => {
+                return with_header(a.bytes_len(2)) + with_header(a.bytes_len(4));
+            }
+            PathAttribute::AsPath(a) => a.bytes_len(2),
             PathAttribute::NextHop(_) => 4,
-            PathAttribute::DontKnow(v) => v.len(),
+            PathAttribute::LocalPref(_) => 4,
+            PathAttribute::Communities(c) => 4 * c.len(),
+            PathAttribute::Aigp(_) => AIGP_TLV_LENGTH as usize,
+            PathAttribute::PrefixSid(p) => p.bytes_len(),
+            // DontKnowは受信したbytesをそのまま保持している。
+            PathAttribute::DontKnow(v) => return v.len(),
+        };
+        with_header(attribute_length)
+    }
+
+    /// UpdateMessageのPath Attributes部分のbytes列をPathAttributeの列に変換する。
+    /// 不正なPath Attributeは、その種類に応じたUPDATE Message Errorにする。
+    pub fn from_u8_slice(
+        bytes: &[u8],
+    ) -> Result<Vec<PathAttribute>, ConvertBytesToBgpMessageError> {
+        Self::from_bytes(&Bytes::copy_from_slice(bytes))
+    }
+
+    /// from_u8_sliceと同じだが、DontKnowの値はコピーせずにbytesと同じバッファを参照する。
+    /// AS4_PATHはPathAttributeにせず、AS_PATHと合わせて本来のAS Pathにする。
+    pub fn from_bytes(bytes: &Bytes) -> Result<Vec<PathAttribute>, ConvertBytesToBgpMessageError> {
+        Self::decode(bytes, false)
+    }
+
+    /// from_bytesと同じだが、four_octet_asがtrueの場合は4オクテットAS Capabilityを
+    /// 交換したピアから受信したものとして、AS_PATHのAS番号を4オクテットで読み取る。
+    /// このときAS4_PATHは送られないはずなので、受信しても破棄する(RFC 6793)。
+    pub fn decode(
+        bytes: &Bytes,
+        four_octet_as: bool,
+    ) -> Result<Vec<PathAttribute>, ConvertBytesToBgpMessageError> {
+        let as_octets = if four_octet_as { 4 } else { 2 };
+        let mut path_attributes = vec![];
+        let mut as4_path = None;
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes.len() < i + 3 {
+                return Err(NotificationBuilder::malformed_attribute_list()
+                    .build(format!(
+                        "Path Attributeのヘッダが途中で途切れています。offset: {}",
+                        i
+                    ))
+                    .into());
+            }
+            let attribute_flag = bytes[i];
+            let attribute_type_code = bytes[i + 1];
+            let (attribute_length, attribute_start) = if attribute_flag & 0b00010000 == 0 {
+                (bytes[i + 2] as usize, i + 3)
+            } else {
+                if bytes.len() < i + 4 {
+                    return Err(NotificationBuilder::malformed_attribute_list()
+                        .build(format!(
+                            "Path AttributeのAttribute Lengthが途中で途切れています。offset: {}",
+                            i
+                        ))
+                        .into());
+                }
+                (
+                    u16::from_be_bytes([bytes[i + 2], bytes[i + 3]]) as usize,
+                    i + 4,
+                )
+            };
+            let attribute_end = attribute_start + attribute_length;
+            let value = bytes.get(attribute_start..attribute_end).ok_or_else(|| {
+                NotificationBuilder::attribute_error(
+                    update_message_error::ATTRIBUTE_LENGTH_ERROR,
+                    &bytes[i..],
+                )
+                .build(format!(
+                    "Path Attribute(type code: {})の値の長さ{}が残りのbytes列より長いです。",
+                    attribute_type_code, attribute_length
+                ))
+            })?;
+            let attribute = &bytes[i..attribute_end];
+            let attribute_error = |subcode: u8, e: ConvertBytesToBgpMessageError| {
+                ConvertBytesToBgpMessageError::from(
+                    NotificationBuilder::attribute_error(subcode, attribute)
+                        .build(format!("{:#}", e)),
+                )
+            };
+            let path_attribute = match attribute_type_code {
+                1 => PathAttribute::Origin(Origin::try_from(value).map_err(|e| {
+                    attribute_error(update_message_error::INVALID_ORIGIN_ATTRIBUTE, e)
+                })?),
+                2 => PathAttribute::AsPath(
+                    AsPath::from_bytes(value, as_octets)
+                        .map_err(|e| attribute_error(update_message_error::MALFORMED_AS_PATH, e))?,
+                ),
+                3 => {
+                    let octets: [u8; 4] = value.try_into().map_err(|_| {
+                        attribute_error(
+                            update_message_error::ATTRIBUTE_LENGTH_ERROR,
+                            anyhow::anyhow!(
+                                "NextHopのbytes表現`{:?}`からIpv4Addrに変換できませんでした。",
+                                value
+                            )
+                            .into(),
+                        )
+                    })?;
+                    PathAttribute::NextHop(Ipv4Addr::from(octets))
+                }
+                5 => {
+                    let octets: [u8; 4] = value.try_into().map_err(|_| {
+                        attribute_error(
+                            update_message_error::ATTRIBUTE_LENGTH_ERROR,
+                            anyhow::anyhow!(
+                                "LOCAL_PREFのbytes表現`{:?}`からu32に変換できませんでした。",
+                                value
+                            )
+                            .into(),
+                        )
+                    })?;
+                    PathAttribute::LocalPref(u32::from_be_bytes(octets))
+                }
+                8 => {
+                    if value.len() % 4 != 0 {
+                        return Err(attribute_error(
+                            update_message_error::ATTRIBUTE_LENGTH_ERROR,
+                            anyhow::anyhow!(
+                                "COMMUNITIESの長さ{}は4の倍数である必要があります。",
+                                value.len()
+                            )
+                            .into(),
+                        ));
+                    }
+                    PathAttribute::Communities(
+                        value
+                            .chunks(4)
+                            .map(|c| Community(u32::from_be_bytes([c[0], c[1], c[2], c[3]])))
+                            .collect(),
+                    )
+                }
+                26 => match aigp_from_value(value) {
+                    Some(metric) => PathAttribute::Aigp(metric),
+                    // 不正なAIGPは、RFC 7311に従って属性ごと破棄する。
+                    None => {
+                        i = attribute_end;
+                        continue;
+                    }
+                },
+                40 => PathAttribute::PrefixSid(PrefixSid::try_from(value).map_err(|e| {
+                    attribute_error(update_message_error::OPTIONAL_ATTRIBUTE_ERROR, e)
+                })?),
+                AS4_PATH_TYPE_CODE => {
+                    // 不正なAS4_PATHは、RFC 6793に従って属性ごと破棄する。
+                    if !four_octet_as {
+                        as4_path = AsPath::from_bytes(value, 4).ok();
+                    }
+                    i = attribute_end;
+                    continue;
+                }
+                _ => PathAttribute::DontKnow(bytes.slice(i..attribute_end)),
+            };
+            path_attributes.push(path_attribute);
+            i = attribute_end;
+        }
+        if let Some(as4_path) = as4_path {
+            if let Some(as_path) = path_attributes.iter_mut().find_map(|p| match p {
+                PathAttribute::AsPath(as_path) => Some(as_path),
+                _ => None,
+            }) {
+                as_path.merge_as4_path(as4_path);
+            }
         }
+        Ok(path_attributes)
     }
 }
 
+/// AS4_PATH(RFC 6793)のAttribute Type Code。
+const AS4_PATH_TYPE_CODE: u8 = 17;
+
+/// AIGP TLVのType, Length, Metricを合わせたオクテット数。
+const AIGP_TLV_LENGTH: u16 = 11;
+
+/// AIGPの値からAIGP TLV(Type 1)のmetricを取り出す。
+/// AIGP TLVがない場合や、TLVの長さが不正な場合はNoneを返す。
+fn aigp_from_value(value: &[u8]) -> Option<u64> {
+    let mut rest = value;
+    while rest.len() >= 3 {
+        let tlv_type = rest[0];
+        let tlv_length = u16::from_be_bytes([rest[1], rest[2]]) as usize;
+        let tlv = rest.get(..tlv_length).filter(|_| tlv_length >= 3)?;
+        if tlv_type == 1 {
+            return Some(u64::from_be_bytes(tlv.get(3..)?.try_into().ok()?));
+        }
+        rest = &rest[tlv_length..];
+    }
+    None
+}
+
 impl From<&PathAttribute> for BytesMut {
     fn from(p: &PathAttribute) -> BytesMut {
+        p.to_bytes(false)
+    }
+}
+
+impl PathAttribute {
+    /// four_octet_asがtrueの場合は、4オクテットAS Capabilityを交換したピアに送るため、
+    /// AS_PATHのAS番号を4オクテットで表し、AS4_PATHは送らない。
+    pub fn to_bytes(&self, four_octet_as: bool) -> BytesMut {
         let mut bytes = BytesMut::new();
 
         // PathAttributeのBytes表現は以下の通り
@@ -40,7 +316,7 @@ impl From<&PathAttribute> for BytesMut {
         //           （Well-knownならすべてcomplete）
         // - 4bit目: Attribute Lengthがone octetなら0, two octetsなら1
         // - 5-8bit目: 使用しない。ゼロ
-        match p {
+        match self {
             PathAttribute::Origin(o) => {
                 let attribute_flag = 0b01000000;
                 let attribute_type_code = 1;
@@ -59,7 +335,8 @@ impl From<&PathAttribute> for BytesMut {
                 let mut attribute_flag = 0b01000000;
                 let attribute_type_code = 2;
 
-                let attribute_length = a.bytes_len() as u16;
+                let as_octets = if four_octet_as { 4 } else { 2 };
+                let attribute_length = a.bytes_len(as_octets) as u16;
                 let mut attribute_length_bytes = BytesMut::new();
                 if attribute_length < 256 {
                     attribute_length_bytes.put_u8(attribute_length as u8);
@@ -68,16 +345,37 @@ impl From<&PathAttribute> for BytesMut {
                     attribute_length_bytes.put_u16(attribute_length);
                 }
 
-                let attribute = BytesMut::from(a);
+                let attribute = a.to_bytes(as_octets);
 
                 bytes.put_u8(attribute_flag);
                 bytes.put_u8(attribute_type_code);
                 bytes.put(attribute_length_bytes);
                 bytes.put(attribute);
+
+                // AS_TRANSにしたAS番号を伝えるため、AS4_PATHを続けて送る。
+                if !four_octet_as && a.has_four_octet_as() {
+                    // Optional, Transitive
+                    let mut attribute_flag = 0b11000000;
+                    let attribute_type_code = AS4_PATH_TYPE_CODE;
+
+                    let attribute_length = a.bytes_len(4) as u16;
+                    let mut attribute_length_bytes = BytesMut::new();
+                    if attribute_length < 256 {
+                        attribute_length_bytes.put_u8(attribute_length as u8);
+                    } else {
+                        attribute_flag += 0b00010000;
+                        attribute_length_bytes.put_u16(attribute_length);
+                    }
+
+                    bytes.put_u8(attribute_flag);
+                    bytes.put_u8(attribute_type_code);
+                    bytes.put(attribute_length_bytes);
+                    bytes.put(a.to_bytes(4));
+                }
             }
             PathAttribute::NextHop(n) => {
                 let mut attribute_flag = 0b01000000;
-                let attribute_type_code = 2;
+                let attribute_type_code = 3;
                 let attribute_length = 4;
                 let attribute = n.octets();
 
@@ -86,12 +384,226 @@ impl From<&PathAttribute> for BytesMut {
                 bytes.put_u8(attribute_length);
                 bytes.put(&attribute[..]);
             }
+            PathAttribute::LocalPref(local_pref) => {
+                let attribute_flag = 0b01000000;
+                let attribute_type_code = 5;
+                let attribute_length = 4;
+
+                bytes.put_u8(attribute_flag);
+                bytes.put_u8(attribute_type_code);
+                bytes.put_u8(attribute_length);
+                bytes.put_u32(*local_pref);
+            }
+            PathAttribute::Communities(communities) => {
+                // Optional, Transitive
+                let mut attribute_flag = 0b11000000;
+                let attribute_type_code = 8;
+
+                let attribute_length = (4 * communities.len()) as u16;
+                let mut attribute_length_bytes = BytesMut::new();
+                if attribute_length < 256 {
+                    attribute_length_bytes.put_u8(attribute_length as u8);
+                } else {
+                    attribute_flag += 0b00010000;
+                    attribute_length_bytes.put_u16(attribute_length);
+                }
+
+                bytes.put_u8(attribute_flag);
+                bytes.put_u8(attribute_type_code);
+                bytes.put(attribute_length_bytes);
+                for community in communities {
+                    bytes.put_u32(community.0);
+                }
+            }
+            PathAttribute::Aigp(metric) => {
+                // Optional, Non-transitive
+                let attribute_flag = 0b10000000;
+                let attribute_type_code = 26;
+
+                bytes.put_u8(attribute_flag);
+                bytes.put_u8(attribute_type_code);
+                bytes.put_u8(AIGP_TLV_LENGTH as u8);
+                // AIGP TLV: [Type (1 octet)][Length (2 octets)][Metric (8 octets)]
+                bytes.put_u8(1);
+                bytes.put_u16(AIGP_TLV_LENGTH);
+                bytes.put_u64(*metric);
+            }
+            PathAttribute::PrefixSid(p) => {
+                // Optional, Transitive
+                let mut attribute_flag = 0b11000000;
+                let attribute_type_code = 40;
+
+                let attribute_length = p.bytes_len() as u16;
+                let mut attribute_length_bytes = BytesMut::new();
+                if attribute_length < 256 {
+                    attribute_length_bytes.put_u8(attribute_length as u8);
+                } else {
+                    attribute_flag += 0b00010000;
+                    attribute_length_bytes.put_u16(attribute_length);
+                }
+
+                bytes.put_u8(attribute_flag);
+                bytes.put_u8(attribute_type_code);
+                bytes.put(attribute_length_bytes);
+                bytes.put(BytesMut::from(p));
+            }
             PathAttribute::DontKnow(v) => bytes.put(&v[..]),
         }
         bytes
     }
 }
 
+/// COMMUNITIESに含まれる1つのcommunity。上位16bitがAS番号、下位16bitがAS毎の値である。
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct Community(pub u32);
+
+impl Community {
+    /// このcommunityが付いたルートはAS外のピアに広告しない。
+    pub const NO_EXPORT: Community = Community(0xFFFFFF01);
+    /// このcommunityが付いたルートはどのピアにも広告しない。
+    pub const NO_ADVERTISE: Community = Community(0xFFFFFF02);
+    /// このcommunityが付いたルートはConfederationのメンバーAS外のピアに広告しない。
+    pub const NO_EXPORT_SUBCONFED: Community = Community(0xFFFFFF03);
+    /// このcommunityが付いたルートは、メンテナンスのため間もなく使えなくなる(RFC 8326)。
+    /// 受信したピアはLOCAL_PREFを下げ、ほかの経路に切り替えておく。
+    pub const GRACEFUL_SHUTDOWN: Community = Community(0xFFFF0000);
+    /// Long-Lived Graceful Restartで、セッションが切れた後も保持しているルートに付ける(RFC 9494)。
+    pub const LLGR_STALE: Community = Community(0xFFFF0006);
+    /// このcommunityが付いたルートは、セッションが切れたらLLGRで保持せずに削除する。
+    pub const NO_LLGR: Community = Community(0xFFFF0007);
+}
+
+impl fmt::Display for Community {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Community::NO_EXPORT => write!(f, "no-export"),
+            Community::NO_ADVERTISE => write!(f, "no-advertise"),
+            Community::NO_EXPORT_SUBCONFED => write!(f, "no-export-subconfed"),
+            Community::GRACEFUL_SHUTDOWN => write!(f, "graceful-shutdown"),
+            Community::LLGR_STALE => write!(f, "llgr-stale"),
+            Community::NO_LLGR => write!(f, "no-llgr"),
+            Community(c) => write!(f, "{}:{}", c >> 16, c & 0xFFFF),
+        }
+    }
+}
+
+impl FromStr for Community {
+    type Err = ConfigParseError;
+
+    /// `64512:100`の形式か、well-known communityの名前をparseする。
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "no-export" => return Ok(Community::NO_EXPORT),
+            "no-advertise" => return Ok(Community::NO_ADVERTISE),
+            "no-export-subconfed" => return Ok(Community::NO_EXPORT_SUBCONFED),
+            "graceful-shutdown" => return Ok(Community::GRACEFUL_SHUTDOWN),
+            "llgr-stale" => return Ok(Community::LLGR_STALE),
+            "no-llgr" => return Ok(Community::NO_LLGR),
+            _ => (),
+        }
+        let (high, low) = s
+            .split_once(':')
+            .context(format!("cannot parse community `{s}`"))?;
+        let high: u16 = high
+            .parse()
+            .context(format!("cannot parse community `{s}`"))?;
+        let low: u16 = low
+            .parse()
+            .context(format!("cannot parse community `{s}`"))?;
+        Ok(Community((u32::from(high) << 16) | u32::from(low)))
+    }
+}
+
+/// BGP Prefix-SID属性の値。TLVの列からなる。
+/// Label-Index TLVだけを解釈し、それ以外のTLV(Originator SRGB TLVなど)は
+/// 受信したbytesのまま保持して、そのまま広告し直す。
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub struct PrefixSid {
+    pub label_index: Option<u32>,
+    other_tlvs: Vec<u8>,
+}
+
+impl PrefixSid {
+    const LABEL_INDEX_TLV_TYPE: u8 = 1;
+    /// Label-Index TLVのReserved, Flags, Label Indexを合わせたオクテット数。
+    const LABEL_INDEX_TLV_LENGTH: u16 = 7;
+
+    pub fn new(label_index: u32) -> Self {
+        Self {
+            label_index: Some(label_index),
+            other_tlvs: vec![],
+        }
+    }
+
+    fn bytes_len(&self) -> usize {
+        let label_index_length = match self.label_index {
+            Some(_) => 3 + Self::LABEL_INDEX_TLV_LENGTH as usize,
+            None => 0,
+        };
+        label_index_length + self.other_tlvs.len()
+    }
+}
+
+impl From<&PrefixSid> for BytesMut {
+    fn from(prefix_sid: &PrefixSid) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        if let Some(label_index) = prefix_sid.label_index {
+            // [Type (1 octet)][Length (2 octets)][Reserved (1 octet)][Flags (2 octets)]
+            // [Label Index (4 octets)]
+            bytes.put_u8(PrefixSid::LABEL_INDEX_TLV_TYPE);
+            bytes.put_u16(PrefixSid::LABEL_INDEX_TLV_LENGTH);
+            bytes.put_u8(0);
+            bytes.put_u16(0);
+            bytes.put_u32(label_index);
+        }
+        bytes.put(&prefix_sid.other_tlvs[..]);
+        bytes
+    }
+}
+
+impl TryFrom<&[u8]> for PrefixSid {
+    type Error = ConvertBytesToBgpMessageError;
+
+    /// Prefix-SIDのTLVは[Type (1 octet)][Length (2 octets)][Value (Length octets)]である。
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut label_index = None;
+        let mut other_tlvs = vec![];
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            if rest.len() < 3 {
+                return Err(ConvertBytesToBgpMessageError::Truncated {
+                    field: "Prefix-SIDのTLVのヘッダ",
+                });
+            }
+            let tlv_type = rest[0];
+            let tlv_length = u16::from_be_bytes([rest[1], rest[2]]) as usize;
+            let tlv = rest.get(..3 + tlv_length).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Prefix-SIDのTLV(type: {})の長さ{}が残りのbytes列より長いです。",
+                    tlv_type,
+                    tlv_length
+                )
+            })?;
+            if tlv_type == PrefixSid::LABEL_INDEX_TLV_TYPE {
+                if tlv_length != PrefixSid::LABEL_INDEX_TLV_LENGTH as usize {
+                    return Err(ConvertBytesToBgpMessageError::InvalidLength {
+                        field: "Label-Index TLV",
+                        length: tlv_length,
+                    });
+                }
+                label_index = Some(u32::from_be_bytes([tlv[6], tlv[7], tlv[8], tlv[9]]));
+            } else {
+                other_tlvs.extend_from_slice(tlv);
+            }
+            rest = &rest[3 + tlv_length..];
+        }
+        Ok(Self {
+            label_index,
+            other_tlvs,
+        })
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum Origin {
     Igp,
@@ -99,57 +611,146 @@ pub enum Origin {
     Incomplete,
 }
 
+impl TryFrom<&[u8]> for Origin {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        match bytes {
+            [0] => Ok(Origin::Igp),
+            [1] => Ok(Origin::Egp),
+            [2] => Ok(Origin::Incomplete),
+            [origin] => Err(ConvertBytesToBgpMessageError::InvalidValue {
+                field: "ORIGIN",
+                value: u32::from(*origin),
+            }),
+            _ => Err(ConvertBytesToBgpMessageError::InvalidLength {
+                field: "ORIGIN",
+                length: bytes.len(),
+            }),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub enum AsPath {
     AsSequence(Vec<AutonomousSystemNumber>),
     AsSet(BTreeSet<AutonomousSystemNumber>),
 }
 
+/// AS_PATHのAS番号は2オクテットで表す。2オクテットで表せないAS番号はAS_TRANSにする。
 impl From<&AsPath> for BytesMut {
     fn from(as_path: &AsPath) -> BytesMut {
-        match as_path {
-            AsPath::AsSet(s) => {
-                let mut bytes = BytesMut::new();
+        as_path.to_bytes(2)
+    }
+}
 
-                let path_segment_type = 1;
-                let number_of_ases = s.len();
-                bytes.put_u8(path_segment_type);
-                bytes.put_u8(number_of_ases as u8);
-                bytes.put(
-                    &s.iter()
-                        .map(|a| u16::from(*a).to_be_bytes())
-                        .flatten()
-                        .collect::<Vec<u8>>()[..],
-                );
-                bytes
-            }
-            AsPath::AsSequence(s) => {
-                let mut bytes = BytesMut::new();
+impl TryFrom<&[u8]> for AsPath {
+    type Error = ConvertBytesToBgpMessageError;
 
-                let path_segment_type = 2;
-                let number_of_ases = s.len();
-                bytes.put_u8(path_segment_type);
-                bytes.put_u8(number_of_ases as u8);
-                bytes.put(
-                    &s.iter()
-                        .map(|a| u16::from(*a).to_be_bytes())
-                        .flatten()
-                        .collect::<Vec<u8>>()[..],
-                );
-                bytes
-            }
-        }
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        Self::from_bytes(bytes, 2)
     }
 }
 
 impl AsPath {
-    fn bytes_len(&self) -> usize {
-        let as_bytes_length = match self {
-            AsPath::AsSequence(v) => 2 * v.len(),
-            AsPath::AsSet(s) => 2 * s.len(),
+    /// AS Pathのbytes表現は以下の通り。
+    /// [Path Segment Type (1 octet)]
+    /// [Number of ASes (1 octet)]
+    /// [AS番号 (as_octets octets) * Number of ASes]
+    /// AS_PATHではas_octetsは2、AS4_PATHでは4である。
+    fn to_bytes(&self, as_octets: usize) -> BytesMut {
+        let (path_segment_type, as_numbers): (u8, Vec<AutonomousSystemNumber>) = match self {
+            AsPath::AsSet(s) => (1, s.iter().copied().collect()),
+            AsPath::AsSequence(s) => (2, s.clone()),
+        };
+        let mut bytes = BytesMut::new();
+        bytes.put_u8(path_segment_type);
+        bytes.put_u8(as_numbers.len() as u8);
+        for as_number in as_numbers {
+            if as_octets == 4 {
+                bytes.put_u32(u32::from(as_number));
+            } else {
+                bytes.put_u16(as_number.to_two_octet());
+            }
+        }
+        bytes
+    }
+
+    /// 本実装ではAS Pathは1つのPath Segmentのみからなるものとして扱う。
+    pub(crate) fn from_bytes(
+        bytes: &[u8],
+        as_octets: usize,
+    ) -> Result<Self, ConvertBytesToBgpMessageError> {
+        if bytes.is_empty() {
+            return Ok(AsPath::AsSequence(vec![]));
+        }
+        if bytes.len() < 2 {
+            return Err(ConvertBytesToBgpMessageError::Truncated {
+                field: "AS PathのPath Segment",
+            });
+        }
+        let path_segment_type = bytes[0];
+        let number_of_ases = bytes[1] as usize;
+        if bytes.len() != 2 + as_octets * number_of_ases {
+            return Err(anyhow::anyhow!(
+                "AS Pathの長さ{}がAS数{}と一致しません。\
+                 複数のPath Segmentを含むAS Pathには対応していません。",
+                bytes.len(),
+                number_of_ases
+            )
+            .into());
+        }
+        let as_numbers = bytes[2..].chunks(as_octets).map(|c| match *c {
+            [a, b, c, d] => AutonomousSystemNumber::from(u32::from_be_bytes([a, b, c, d])),
+            [a, b] => AutonomousSystemNumber::from(u32::from(u16::from_be_bytes([a, b]))),
+            _ => unreachable!("AS番号は2か4オクテットで表す。"),
+        });
+        match path_segment_type {
+            1 => Ok(AsPath::AsSet(as_numbers.collect())),
+            2 => Ok(AsPath::AsSequence(as_numbers.collect())),
+            _ => Err(ConvertBytesToBgpMessageError::InvalidValue {
+                field: "Path Segment Type",
+                value: u32::from(path_segment_type),
+            }),
+        }
+    }
+
+    /// AS番号を1つあたりas_octetsオクテットで表したときの、値のオクテット数。
+    fn bytes_len(&self, as_octets: usize) -> usize {
+        let number_of_ases = match self {
+            AsPath::AsSequence(v) => v.len(),
+            AsPath::AsSet(s) => s.len(),
         };
         // AsSetかAsSequenceかを表すoctet + asの数を表すoctet + asのbytesの値
-        1 + 1 + as_bytes_length
+        1 + 1 + as_octets * number_of_ases
+    }
+
+    /// 2オクテットで表せないAS番号を含むか。含む場合はAS4_PATHも合わせて送る。
+    pub fn has_four_octet_as(&self) -> bool {
+        match self {
+            AsPath::AsSequence(v) => v.iter().any(|a| a.is_four_octet()),
+            AsPath::AsSet(s) => s.iter().any(|a| a.is_four_octet()),
+        }
+    }
+
+    /// AS_TRANSを含むAS_PATHと、受信したAS4_PATHから本来のAS Pathを組み立てる(RFC 6793)。
+    /// AS4_PATHのAS数のほうが多い場合は、AS4_PATHを付けた後に経由した2オクテットの
+    /// AS番号しか扱えない機器が、AS_PATHを正しく扱えていないとみなしてAS4_PATHを無視する。
+    fn merge_as4_path(&mut self, as4_path: AsPath) {
+        let as_path_length = self.path_length();
+        let as4_path_length = as4_path.path_length();
+        if as_path_length < as4_path_length {
+            return;
+        }
+        match (&mut *self, as4_path) {
+            (AsPath::AsSequence(seq), AsPath::AsSequence(as4_seq)) => {
+                seq.truncate(as_path_length - as4_path_length);
+                seq.extend(as4_seq);
+            }
+            (AsPath::AsSet(_), as4_path @ AsPath::AsSet(_)) => *self = as4_path,
+            // 1つのPath Segmentでは表せないため、AS_PATHのまま扱う。
+            _ => {}
+        }
     }
 }
 
@@ -162,4 +763,254 @@ impl AsPath {
             }
         };
     }
+
+    /// 経路選択で比較するAS Pathの長さ。AS_SETは1つのASとして数える。
+    pub fn path_length(&self) -> usize {
+        match self {
+            AsPath::AsSequence(seq) => seq.len(),
+            AsPath::AsSet(set) => usize::from(!set.is_empty()),
+        }
+    }
+
+    /// AS Pathの先頭にas_numbersをその順番のまま追加する。
+    pub fn prepend(&mut self, as_numbers: &[AutonomousSystemNumber]) {
+        match self {
+            AsPath::AsSequence(seq) => {
+                seq.splice(0..0, as_numbers.iter().copied());
+            }
+            AsPath::AsSet(set) => set.extend(as_numbers.iter().copied()),
+        };
+    }
+}
+
+/// Arbitraryで生成するAS番号、COMMUNITYの最大の数。
+/// 1つのUpdateMessageが最大長を超えにくいように小さくしている。
+#[cfg(feature = "arbitrary")]
+const MAXIMUM_ARBITRARY_ITEMS: usize = 32;
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Origin {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(match u.int_in_range(0..=2)? {
+            0 => Origin::Igp,
+            1 => Origin::Egp,
+            _ => Origin::Incomplete,
+        })
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for AsPath {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let len = u.int_in_range(0..=MAXIMUM_ARBITRARY_ITEMS)?;
+        let as_numbers = (0..len)
+            .map(|_| u.arbitrary())
+            .collect::<arbitrary::Result<Vec<AutonomousSystemNumber>>>()?;
+        Ok(if u.arbitrary::<u8>()? % 2 == 0 {
+            AsPath::AsSequence(as_numbers)
+        } else {
+            AsPath::AsSet(as_numbers.into_iter().collect())
+        })
+    }
+}
+
+/// 受信したときにDontKnowになるPath Attributeは、bytes列をそのまま持つため生成しない。
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for PathAttribute {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(match u.int_in_range(0..=5)? {
+            0 => PathAttribute::Origin(u.arbitrary()?),
+            1 => PathAttribute::AsPath(u.arbitrary()?),
+            2 => PathAttribute::NextHop(u.arbitrary()?),
+            3 => {
+                let len = u.int_in_range(0..=MAXIMUM_ARBITRARY_ITEMS)?;
+                PathAttribute::Communities(
+                    (0..len)
+                        .map(|_| Ok(Community(u.arbitrary()?)))
+                        .collect::<arbitrary::Result<_>>()?,
+                )
+            }
+            4 => PathAttribute::Aigp(u.arbitrary()?),
+            _ => PathAttribute::PrefixSid(PrefixSid::new(u.arbitrary()?)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_attribute_table_shares_and_releases_sets() {
+        let mut table = PathAttributeTable::new();
+        assert!(table.is_empty());
+        let path_attributes = Arc::new(vec![PathAttribute::Origin(Origin::Igp)]);
+        let interned = table.intern(&path_attributes);
+        let same = table.intern(&Arc::new(vec![PathAttribute::Origin(Origin::Igp)]));
+        assert!(Arc::ptr_eq(&interned, &same));
+        assert_eq!(table.len(), 1);
+
+        drop((path_attributes, interned, same));
+        table.remove_unused();
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn convert_aigp_to_bytes_and_bytes_to_aigp() {
+        let aigp = PathAttribute::Aigp(300);
+        let bytes = BytesMut::from(&aigp);
+
+        assert_eq!(bytes.len(), aigp.bytes_len());
+        assert_eq!(PathAttribute::from_u8_slice(&bytes).unwrap(), vec![aigp]);
+    }
+
+    #[test]
+    fn convert_local_pref_to_bytes_and_bytes_to_local_pref() {
+        let local_pref = PathAttribute::LocalPref(200);
+        let bytes = BytesMut::from(&local_pref);
+
+        assert_eq!(&bytes[..], &[0b01000000, 5, 4, 0, 0, 0, 200]);
+        assert_eq!(bytes.len(), local_pref.bytes_len());
+        assert_eq!(
+            PathAttribute::from_u8_slice(&bytes).unwrap(),
+            vec![local_pref]
+        );
+    }
+
+    #[test]
+    fn four_octet_as_path_is_sent_with_as_trans_and_as4_path() {
+        let as_path =
+            PathAttribute::AsPath(AsPath::AsSequence(vec![64513.into(), 4200000000.into()]));
+        let bytes = BytesMut::from(&as_path);
+
+        assert_eq!(
+            &bytes[..],
+            &[
+                0b01000000, 2, 6, 2, 2, 0xfc, 0x01, 0x5b, 0xa0, // AS_PATH
+                0b11000000, 17, 10, 2, 2, 0, 0, 0xfc, 0x01, 0xfa, 0x56, 0xea,
+                0x00, // AS4_PATH
+            ]
+        );
+        assert_eq!(bytes.len(), as_path.bytes_len());
+        assert_eq!(PathAttribute::from_u8_slice(&bytes).unwrap(), vec![as_path]);
+    }
+
+    #[test]
+    fn as_path_is_sent_with_four_octets_to_four_octet_as_peer() {
+        let as_path =
+            PathAttribute::AsPath(AsPath::AsSequence(vec![64513.into(), 4200000000.into()]));
+        let bytes = as_path.to_bytes(true);
+
+        assert_eq!(
+            &bytes[..],
+            &[0b01000000, 2, 10, 2, 2, 0, 0, 0xfc, 0x01, 0xfa, 0x56, 0xea, 0x00]
+        );
+        assert_eq!(bytes.len(), as_path.encoded_len(true));
+        assert_eq!(
+            PathAttribute::decode(&bytes.freeze(), true).unwrap(),
+            vec![as_path]
+        );
+    }
+
+    #[test]
+    fn as_path_is_reconstructed_from_as4_path() {
+        let as_path = |as_numbers: &[u32]| {
+            AsPath::AsSequence(as_numbers.iter().map(|a| (*a).into()).collect())
+        };
+        let attributes = |as_path: &AsPath, as4_path: &AsPath| {
+            let mut bytes = BytesMut::new();
+            bytes.put_u8(0b01000000);
+            bytes.put_u8(2);
+            bytes.put_u8(as_path.bytes_len(2) as u8);
+            bytes.put(as_path.to_bytes(2));
+            bytes.put_u8(0b11000000);
+            bytes.put_u8(AS4_PATH_TYPE_CODE);
+            bytes.put_u8(as4_path.bytes_len(4) as u8);
+            bytes.put(as4_path.to_bytes(4));
+            PathAttribute::from_u8_slice(&bytes).unwrap()
+        };
+
+        // AS4_PATHを付けた後に、2オクテットのAS番号しか扱えないAS 64513を経由した。
+        assert_eq!(
+            attributes(
+                &as_path(&[64513, 23456, 64514]),
+                &as_path(&[4200000000, 64514])
+            ),
+            vec![PathAttribute::AsPath(as_path(&[64513, 4200000000, 64514]))]
+        );
+        // AS4_PATHのほうが長い場合は、AS4_PATHを無視する。
+        assert_eq!(
+            attributes(&as_path(&[23456]), &as_path(&[4200000000, 64514])),
+            vec![PathAttribute::AsPath(as_path(&[23456]))]
+        );
+    }
+
+    #[test]
+    fn prefix_sid_preserves_unknown_tlvs() {
+        // Label-Index TLV(index 100)と、Originator SRGB TLV(base 16000, range 8000)。
+        let value = [
+            1, 0, 7, 0, 0, 0, 0, 0, 0, 100, //
+            3, 0, 8, 0, 0, 0, 0x3e, 0x80, 0, 0x1f, 0x40,
+        ];
+        let mut bytes = BytesMut::new();
+        bytes.put_u8(0b11000000);
+        bytes.put_u8(40);
+        bytes.put_u8(value.len() as u8);
+        bytes.put(&value[..]);
+
+        let path_attributes = PathAttribute::from_u8_slice(&bytes).unwrap();
+        match &path_attributes[0] {
+            PathAttribute::PrefixSid(prefix_sid) => assert_eq!(prefix_sid.label_index, Some(100)),
+            p => panic!("Prefix-SIDとしてparseされませんでした。{:?}", p),
+        }
+        assert_eq!(BytesMut::from(&path_attributes[0]), bytes);
+        assert_eq!(path_attributes[0].bytes_len(), bytes.len());
+    }
+
+    #[test]
+    fn convert_communities_to_bytes_and_bytes_to_communities() {
+        let communities =
+            PathAttribute::Communities(vec!["64512:100".parse().unwrap(), Community::NO_EXPORT]);
+        let bytes = BytesMut::from(&communities);
+
+        assert_eq!(bytes.len(), communities.bytes_len());
+        assert_eq!(
+            PathAttribute::from_u8_slice(&bytes).unwrap(),
+            vec![communities]
+        );
+        assert_eq!(Community(0xFC000064).to_string(), "64512:100");
+        assert!("64512".parse::<Community>().is_err());
+    }
+
+    #[test]
+    fn malformed_aigp_is_discarded() {
+        // AIGP TLVのLengthが値の長さより長い。
+        let bytes = [0b10000000, 26, 4, 1, 0, 11, 0];
+        assert_eq!(PathAttribute::from_u8_slice(&bytes).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn malformed_attribute_is_notified_with_attribute_as_data() {
+        // ORIGINの値が0-2以外。
+        let bytes = [0b01000000, 1, 1, 3];
+        let error = PathAttribute::from_u8_slice(&bytes).unwrap_err();
+        let notification = error.notification_error().unwrap();
+
+        assert_eq!(
+            notification.error_subcode,
+            update_message_error::INVALID_ORIGIN_ATTRIBUTE
+        );
+        assert_eq!(&notification.data[..], &bytes[..]);
+
+        // NEXT_HOPの長さが4 octetsではない。
+        let bytes = [0b01000000, 1, 1, 0, 0b01000000, 3, 2, 10, 0];
+        let error = PathAttribute::from_u8_slice(&bytes).unwrap_err();
+        let notification = error.notification_error().unwrap();
+
+        assert_eq!(
+            notification.error_subcode,
+            update_message_error::ATTRIBUTE_LENGTH_ERROR
+        );
+        assert_eq!(&notification.data[..], &bytes[4..]);
+    }
 }
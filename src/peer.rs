@@ -1,36 +1,408 @@
+use crate::aspa::AspaTable;
+use crate::bgp_type::{AddressFamily, AutonomousSystemNumber, HoldTime, Ipv4Network};
+use crate::capture::MessageCapture;
+use crate::clock::Clock;
+use crate::error::{ConvertBytesToBgpMessageError, NotificationBuilder};
+use crate::fib::AttachedFib;
+use crate::hook::{MessageHook, PeerContext};
+use crate::otel::{AttributeValue, OtlpExporter, Span};
+use crate::packets::capability::{Capability, LlgrFamily};
+use crate::packets::header::MessageType;
+use crate::packets::notification::{cease, ErrorCode, NotificationMessage};
+use crate::packets::open::OpenMessage;
 use crate::packets::update::UpdateMessage;
-use crate::routing::{AdjRibOut, LocRib};
+use crate::policy;
+use crate::rate_limit::TokenBucket;
+use crate::routing::{AdjRibIn, AdjRibOut, LocRib, Provenance, RibEntry};
+use crate::statistics::PeerStatistics;
+use crate::timer::Timer;
+use crate::trace::Tracer;
+use crate::update_group::{NegotiatedCapabilities, UpdateGroup, UpdateGroups};
 use crate::{
-    config::Config, config::Mode, connection::Connection, event::Event, event_queue::EventQueue,
+    config::Config, config::Mode, config::RemoteAs, connection::resolve_host,
+    connection::BgpTransport, connection::Connection, event::Event, event_queue::EventQueue,
     packets::message::Message, state::State,
 };
+use crate::{log_debug, log_error, log_info, log_warning};
 use anyhow::{Context, Result};
+use bytes::{BufMut, BytesMut};
+use futures::FutureExt;
+use std::collections::{BTreeSet, HashMap};
+use std::net::Ipv4Addr;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex, MutexGuard};
+use tokio::task::JoinHandle;
+
+/// セッションを停止したピアに送るShutdown Communication。
+const PEER_DECONFIGURED: &str = "peer de-configured";
+/// コントロールAPIで無効にしたピアに送るShutdown Communication。
+const PEER_DISABLED: &str = "peer administratively disabled";
+
+/// PeerHandleからPeerへの依頼。Peer::nextで処理する。
+#[derive(Debug)]
+enum PeerRequest {
+    /// FSMに渡すEvent。
+    Event(Event),
+    /// 設定を差し替えてUpdate Groupに参加し直し、AdjRibOutを作り直して広告し直す。
+    UpdateConfig(Box<Config>),
+    /// セッションを停止し、このピアから学習したルートを削除してPeerを終了する。
+    Remove,
+    /// falseの場合はセッションを停止し、trueに戻されるまで張り直さない。
+    SetEnabled(bool),
+}
+
+/// Peerを動かしているタスクの外から、Peerに処理を依頼するためのハンドル。
+#[derive(Debug, Clone)]
+pub struct PeerHandle(mpsc::UnboundedSender<PeerRequest>);
+
+impl PeerHandle {
+    /// LocRibの変更をAdjRibOutに反映し、広告し直させる。
+    /// 他のピアから学習したルート以外の理由でLocRibが変わったときに使う。
+    pub fn notify_loc_rib_changed(&self) {
+        // Peerが終了していれば、依頼する必要もない。
+        let _ = self.0.send(PeerRequest::Event(Event::LocRibChanged));
+    }
+
+    /// セッションを張り直さずに反映できる設定の変更を反映させる。
+    pub fn update_config(&self, config: Config) {
+        let _ = self.0.send(PeerRequest::UpdateConfig(Box::new(config)));
+    }
+
+    /// セッションを停止してPeerを終了させる。終了したかはPeer::is_removedで分かる。
+    pub fn remove(&self) {
+        let _ = self.0.send(PeerRequest::Remove);
+    }
+
+    /// 設定を変えずに、セッションを停止したまま(false)にするか、開始し直す(true)。
+    /// ManualStop, ManualStartとしてFSMに渡す。
+    pub fn set_enabled(&self, enabled: bool) {
+        let _ = self.0.send(PeerRequest::SetEnabled(enabled));
+    }
+
+    /// どのPeerにもつながっていないハンドル。Peerを動かさないテストで使う。
+    #[cfg(test)]
+    pub(crate) fn detached() -> Self {
+        let (sender, _) = mpsc::unbounded_channel();
+        Self(sender)
+    }
+}
+
+/// ピアとのTCP Connectionを確立するタスク。破棄すると、接続や待ち受けを中断する。
+#[derive(Debug)]
+struct ConnectTask(JoinHandle<Result<(Ipv4Addr, Connection)>>);
+
+impl ConnectTask {
+    /// configのピアとのTCP Connectionの確立を始める。modeがActiveであれば接続し、Passiveであれば待ち受ける。
+    /// ホスト名で指定したピアは、接続するたびに名前解決し直し、接続したアドレスを返す。
+    fn spawn(transport: Arc<dyn BgpTransport>, mut config: Config, mode: Mode) -> Self {
+        config.mode = mode;
+        Self(tokio::spawn(async move {
+            if let Some(host) = &config.remote_host {
+                config.remote_ip = resolve_host(host).await?;
+            }
+            let conn = Connection::connect(&*transport, &config)
+                .await
+                .context(format!(
+                    "{}とのTCP Connectionを確立できませんでした。",
+                    config.display_name()
+                ))?;
+            Ok((config.remote_ip, conn))
+        }))
+    }
+
+    /// 確立し終えたか、失敗していれば、その結果を返す。
+    fn try_take(&mut self) -> Option<Result<(Ipv4Addr, Connection)>> {
+        if !self.0.is_finished() {
+            return None;
+        }
+        let result = (&mut self.0).now_or_never()?;
+        Some(result.unwrap_or_else(|e| Err(e.into())))
+    }
+}
+
+impl Drop for ConnectTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Connection Collisionで、セッションに使っているTCP Connectionとは別に確立したTCP Connection。
+#[derive(Debug)]
+struct CollidingConnection {
+    connection: Connection,
+    initiated_locally: bool,
+}
 
 #[derive(Debug)]
 pub struct Peer {
     state: State,
     event_queue: EventQueue,
     tcp_connection: Option<Connection>,
+    /// 確立を待っている、ピアへのTCP Connection。Active、Bothモードで、Establishedになるまで存在する。
+    connecting: Option<ConnectTask>,
+    /// 確立を待っている、ピアからのTCP Connection。Passive、Bothモードで、Establishedになるまで存在する。
+    accepting: Option<ConnectTask>,
+    /// tcp_connectionを自分から開始したか。Connection Collisionで残すTCP Connectionを決めるのに使う。
+    initiated_locally: bool,
+    /// tcp_connectionとは別に確立し、対向機器のOpenMessageを待っているTCP Connection。
+    /// OpenMessageを受信したら、BGP IdentifierでどちらのTCP Connectionを残すかを決める(RFC 4271 6.8)。
+    colliding_connection: Option<CollidingConnection>,
+    /// ManualStartでConnectionを張るときに使うトランスポート。
+    transport: Arc<dyn BgpTransport>,
     config: Config,
     loc_rib: Arc<Mutex<LocRib>>,
-    adj_rib_out: AdjRibOut,
+    /// MRTのテーブルダンプなど、Peerの外からも参照できるように共有する。
+    adj_rib_in: Arc<Mutex<AdjRibIn>>,
+    /// soft-reconfiguration-inboundのために保持する、import policyを適用する前の受信したルート。
+    received_routes: AdjRibIn,
+    /// AdjRibOutは同じexport policyを持つピアの間でUpdate Groupとして共有する。
+    update_group: Arc<Mutex<UpdateGroup>>,
+    /// セッションごとに参加し直すUpdate Groupの一覧。
+    update_groups: UpdateGroups,
+    /// OpenMessageの交換で決まった、AdjRibOutの内容やエンコードを左右する設定。
+    negotiated: NegotiatedCapabilities,
+    statistics: Arc<Mutex<PeerStatistics>>,
+    /// 直近に送受信したメッセージ。セッションを張り直しても引き継ぐ。
+    message_capture: Arc<std::sync::Mutex<MessageCapture>>,
+    /// 受信したAS Pathの検証に使うASPA。すべてのピアで共有する。
+    aspa_table: Arc<AspaTable>,
+    mrai_timer: Timer,
+    hold_timer: Timer,
+    keepalive_timer: Timer,
+    /// LLGRでstaleとして保持しているルートを削除するまでのタイマー。
+    llgr_stale_timer: Timer,
+    /// TCP Connectionの確立に失敗した後、接続し直すまでのタイマー。
+    connect_retry_timer: Timer,
+    /// OpenMessageの交換で決まったHold Time。
+    hold_time: HoldTime,
+    /// 受け入れたOpenMessageに含まれていた対向機器のAS番号。
+    remote_as: Option<AutonomousSystemNumber>,
+    /// remote-asを`any`などにしたピアで、セッションの間だけconfig.remote_asを
+    /// 受け入れたAS番号に置き換えている場合の、設定したremote-as。
+    configured_remote_as: Option<RemoteAs>,
+    /// 自分と対向機器の両方がLLGRを広告した場合に、
+    /// セッションが切れた後もルートをstaleとして保持する時間。
+    llgr_stale_time: Option<Duration>,
+    /// OpenMessageの交換で、自分と対向機器の両方が交換できるとしたAFI/SAFI。
+    address_families: Vec<AddressFamily>,
+    /// MRAIタイマーの動作中にAdjRibOutが変更され、送信を待っているUpdateがあるか。
+    pending_advertisement: bool,
+    /// このセッションで、最初のルートを送り終えたことを表すEnd-of-RIBを送信したか。
+    end_of_rib_sent: bool,
+    /// このセッションで広告し、まだ取り消していないIPv4 Unicastのルート。
+    /// Update Groupに参加し直した後に、AdjRibOutとの差分だけを送信するために使う。
+    advertised_routes: AdjRibOut,
+    /// 前回広告したときのUpdate Groupのgeneration。
+    /// Update Groupに参加し直すとNoneに戻り、advertised_routesとの差分を送る。
+    advertised_generation: Option<u64>,
+    /// 受信したUpdateMessageに含まれていて、まだLocRibに反映していないネットワーク。
+    adj_rib_in_changes: BTreeSet<Ipv4Network>,
+    /// 受信したUpdateMessageの処理の頻度を制限するトークンバケット。
+    /// update-rate-limitを設定していない場合はNoneである。
+    update_rate_limiter: Option<TokenBucket>,
+    /// Event、状態遷移、RIBの変更を書き出すトレース。Noneの場合は書き出さない。
+    tracer: Option<Tracer>,
+    /// UpdateMessageの処理とFSMの状態遷移のSpanを送るExporter。Noneの場合は送らない。
+    otlp_exporter: Option<OtlpExporter>,
+    /// 最後に受信したUpdateMessageを、データの読み込みからMessageに変換するまでの時刻。
+    update_decoded: Option<(SystemTime, SystemTime)>,
+    /// UpdateMessageを受信してから、LocRibのルートをカーネルに書き込むまでのSpan。
+    /// その間に受信したUpdateMessageは同じSpanにまとめる。
+    convergence_span: Option<Span>,
+    /// 送受信するMessageごとに、追加した順に呼び出すフック。
+    message_hooks: Vec<Arc<dyn MessageHook>>,
+    /// PeerHandleからの依頼。
+    requests: mpsc::UnboundedReceiver<PeerRequest>,
+    handle: PeerHandle,
+    /// PeerHandle::removeで終了を依頼されたか。
+    removed: bool,
 }
 
 impl Peer {
     pub fn new(config: Config, loc_rib: Arc<Mutex<LocRib>>) -> Self {
         let state = State::Idle;
         let event_queue = EventQueue::new();
-        let adj_rib_out = AdjRibOut::new();
+        let adj_rib_in = Arc::new(Mutex::new(AdjRibIn::new()));
+        let update_group = Arc::new(Mutex::new(UpdateGroup::new(
+            &config,
+            NegotiatedCapabilities::default(),
+        )));
+        let statistics = Arc::new(Mutex::new(PeerStatistics::with_state_history_size(
+            config.state_history_size,
+        )));
+        let message_capture = Arc::new(std::sync::Mutex::new(MessageCapture::new(
+            config.message_capture_size,
+        )));
+        let (sender, requests) = mpsc::unbounded_channel();
+        let update_rate_limiter = config.update_rate_limit.map(TokenBucket::new);
         Self {
             state,
             event_queue,
+            transport: config.transport.to_bgp_transport(),
             config,
             tcp_connection: None,
+            connecting: None,
+            accepting: None,
+            initiated_locally: false,
+            colliding_connection: None,
             loc_rib,
-            adj_rib_out,
+            adj_rib_in,
+            received_routes: AdjRibIn::new(),
+            update_group,
+            update_groups: UpdateGroups::new(),
+            negotiated: NegotiatedCapabilities::default(),
+            statistics,
+            message_capture,
+            aspa_table: Arc::new(AspaTable::new()),
+            mrai_timer: Timer::new(),
+            hold_timer: Timer::new(),
+            keepalive_timer: Timer::new(),
+            llgr_stale_timer: Timer::new(),
+            connect_retry_timer: Timer::new(),
+            hold_time: HoldTime::new(),
+            remote_as: None,
+            configured_remote_as: None,
+            llgr_stale_time: None,
+            address_families: vec![],
+            pending_advertisement: false,
+            end_of_rib_sent: false,
+            advertised_routes: AdjRibOut::new(),
+            advertised_generation: None,
+            adj_rib_in_changes: BTreeSet::new(),
+            update_rate_limiter,
+            tracer: None,
+            otlp_exporter: None,
+            update_decoded: None,
+            convergence_span: None,
+            message_hooks: vec![],
+            requests,
+            handle: PeerHandle(sender),
+            removed: false,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// 対向機器のAS番号。OpenMessageを受け入れるまではNone。
+    pub fn remote_as(&self) -> Option<AutonomousSystemNumber> {
+        self.remote_as
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// コントロールAPIなど、Peerの外から統計情報を参照するためのハンドル。
+    pub fn statistics(&self) -> Arc<Mutex<PeerStatistics>> {
+        Arc::clone(&self.statistics)
+    }
+
+    /// コントロールAPIの`show capture`で、直近に送受信したメッセージを参照するためのハンドル。
+    pub fn message_capture(&self) -> Arc<std::sync::Mutex<MessageCapture>> {
+        Arc::clone(&self.message_capture)
+    }
+
+    /// タイマーが使う時計を差し替える。動作中のタイマーは止まる。
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.mrai_timer = Timer::with_clock(Arc::clone(&clock));
+        self.hold_timer = Timer::with_clock(Arc::clone(&clock));
+        self.keepalive_timer = Timer::with_clock(Arc::clone(&clock));
+        self.llgr_stale_timer = Timer::with_clock(Arc::clone(&clock));
+        self.connect_retry_timer = Timer::with_clock(Arc::clone(&clock));
+        self.update_rate_limiter = self
+            .config
+            .update_rate_limit
+            .map(|rate| TokenBucket::with_clock(rate, clock));
+    }
+
+    /// コントロールAPIなど、Peerを動かしているタスクの外から処理を依頼するためのハンドル。
+    pub fn handle(&self) -> PeerHandle {
+        self.handle.clone()
+    }
+
+    /// MRTのテーブルダンプなど、Peerの外からAdjRibInを参照するためのハンドル。
+    pub fn adj_rib_in(&self) -> Arc<Mutex<AdjRibIn>> {
+        Arc::clone(&self.adj_rib_in)
+    }
+
+    /// Connectionを張るトランスポートを、configで指定したものから差し替える。
+    /// ManualStartより前に呼び出す必要がある。
+    pub fn set_transport(&mut self, transport: Arc<dyn BgpTransport>) {
+        self.transport = transport;
+    }
+
+    /// 送受信するMessageを観察、変更、破棄するフックを追加する。
+    pub fn add_message_hook(&mut self, hook: Arc<dyn MessageHook>) {
+        self.message_hooks.push(hook);
+    }
+
+    /// Event、状態遷移、RIBの変更をtracerに書き出す。
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// UpdateMessageの受信からFIBへの書き込みまでの処理と、FSMの状態遷移をSpanとして送る。
+    pub fn set_otlp_exporter(&mut self, exporter: OtlpExporter) {
+        self.otlp_exporter = Some(exporter);
+    }
+
+    fn export_span(&self, span: Span) {
+        if let Some(exporter) = &self.otlp_exporter {
+            exporter.export(span);
+        }
+    }
+
+    /// 処理中のconvergence_spanの子として、startから今までのSpanを送る。
+    fn export_convergence_child(
+        &self,
+        name: &'static str,
+        start: SystemTime,
+        attributes: Vec<(&'static str, AttributeValue)>,
+    ) {
+        if let Some(root) = &self.convergence_span {
+            let mut span = root.child(name, start, SystemTime::now());
+            span.attributes = attributes;
+            self.export_span(span);
+        }
+    }
+
+    pub fn set_aspa_table(&mut self, aspa_table: Arc<AspaTable>) {
+        self.aspa_table = aspa_table;
+    }
+
+    /// configに対応するUpdate Groupに参加し、AdjRibOutの生成を他のピアと共有する。
+    pub fn join_update_group(&mut self, update_groups: &UpdateGroups) {
+        self.update_groups = update_groups.clone();
+        self.rejoin_update_group();
+    }
+
+    /// 今の設定とセッションで交渉した内容に対応するUpdate Groupに参加し直す。
+    fn rejoin_update_group(&mut self) {
+        self.update_group = self.update_groups.join(&self.config, self.negotiated);
+        self.advertised_generation = None;
+    }
+
+    /// remote-asを`any`などにしたピアでは、受け入れたOpenMessageのAS番号でeBGPかiBGPかが決まる。
+    /// セッションの間はconfig.remote_asをそのAS番号に置き換える。
+    fn apply_negotiated_remote_as(&mut self) {
+        let Some(remote_as) = self.remote_as else {
+            return;
+        };
+        if matches!(self.config.remote_as, RemoteAs::Number(_)) {
+            return;
+        }
+        self.configured_remote_as = Some(self.config.remote_as);
+        self.config.remote_as = RemoteAs::Number(remote_as);
+    }
+
+    /// apply_negotiated_remote_asで置き換えたconfig.remote_asを、設定した値に戻す。
+    fn restore_configured_remote_as(&mut self) {
+        if let Some(remote_as) = self.configured_remote_as.take() {
+            self.config.remote_as = remote_as;
         }
     }
 
@@ -38,114 +410,2180 @@ impl Peer {
         self.event_queue.enqueue(Event::ManualStart);
     }
 
+    /// FSMにeventを直接渡す。テストで任意のEventの列を発生させるために使う。
+    pub(crate) fn enqueue_event(&mut self, event: Event) {
+        self.event_queue.enqueue(event);
+    }
+
+    /// セッションを停止する。確立済みのセッションでは、
+    /// shutdown_communicationを含むCease(Administrative Shutdown)を送信してから切断する。
+    pub fn stop(&mut self, shutdown_communication: Option<String>) {
+        self.event_queue
+            .enqueue(Event::ManualStop(shutdown_communication));
+    }
+
     pub async fn next(&mut self) {
+        if self.mrai_timer.expired() {
+            self.event_queue.enqueue(Event::MraiTimerExpires);
+        }
+        if self.hold_timer.expired() {
+            self.event_queue.enqueue(Event::HoldTimerExpires);
+        }
+        if self.keepalive_timer.expired() {
+            self.event_queue.enqueue(Event::KeepaliveTimerExpires);
+        }
+        if self.llgr_stale_timer.expired() {
+            self.event_queue.enqueue(Event::LlgrStaleTimerExpires);
+        }
+        if self.connect_retry_timer.expired() {
+            self.event_queue.enqueue(if self.state == State::Idle {
+                Event::AutomaticStart
+            } else {
+                Event::ConnectRetryTimerExpires
+            });
+        }
+        while let Ok(request) = self.requests.try_recv() {
+            self.handle_request(request).await;
+        }
+        self.poll_connecting().await;
+        self.poll_colliding_connection().await;
+
         if let Some(event) = self.event_queue.dequeue() {
             self.handle_event(&event).await;
         }
+        let mut statistics = self.statistics.lock().await;
+        statistics.queued_events = self.event_queue.len();
+        if let Some(conn) = &self.tcp_connection {
+            statistics.outbound_queue = conn.outbound_queue_statistics();
+        }
+        drop(statistics);
+
+        // Eventが溜まっている間や、UpdateMessageの処理が制限を超えている間は受信を止め、
+        // TCPのフロー制御で対向機器に送信を待ってもらう。
+        let receivable = !self.event_queue.is_full() && self.update_rate_limit_allows();
+        if let Some(conn) = self.tcp_connection.as_mut().filter(|_| receivable) {
+            let receiving = SystemTime::now();
+            match conn.get_message().await {
+                Some(Ok(message)) => {
+                    if self.otlp_exporter.is_some() && matches!(message, Message::Update(_)) {
+                        self.update_decoded = Some((receiving, SystemTime::now()));
+                    }
+                    self.handle_message(message).await
+                }
+                Some(Err(e)) => self.handle_message_error(e),
+                None if conn.is_closed() => {
+                    // 受信済みのメッセージをすべて処理した後に、Connectionの切断を扱う。
+                    self.tcp_connection = None;
+                    self.event_queue.enqueue(Event::TcpConnectionFails);
+                }
+                None => (),
+            }
+        }
+
+        // Connectionの書き込みタスクなど、ほかのタスクに実行の機会を与える。
+        tokio::task::yield_now().await;
+    }
 
-        if let Some(conn) = &mut self.tcp_connection {
-            if let Some(message) = conn.get_message().await {
-                self.handle_message(message);
+    async fn handle_request(&mut self, request: PeerRequest) {
+        match request {
+            PeerRequest::Event(event) => self.event_queue.enqueue(event),
+            PeerRequest::UpdateConfig(config) => self.update_config(*config).await,
+            PeerRequest::Remove if self.state == State::Idle => {
+                // LLGRでstaleとして保持しているルートがあれば削除する。
+                self.flush_routes_learned_from_peer().await;
+                self.removed = true;
             }
+            PeerRequest::Remove => {
+                self.removed = true;
+                self.event_queue
+                    .enqueue(Event::ManualStop(Some(PEER_DECONFIGURED.to_owned())));
+            }
+            PeerRequest::SetEnabled(enabled) => {
+                self.statistics.lock().await.disabled = !enabled;
+                self.event_queue.enqueue(if enabled {
+                    Event::ManualStart
+                } else {
+                    Event::ManualStop(Some(PEER_DISABLED.to_owned()))
+                });
+            }
+        }
+    }
+
+    /// セッションを張り直さずに、設定を差し替えて対応するUpdate Groupに参加し直す。
+    /// Establishedであれば、新しい設定で作ったAdjRibOutを広告し直す。
+    /// import policyだけが変わった場合は、保持していた受信したルートに適用し直す。
+    async fn update_config(&mut self, mut config: Config) {
+        let import_policy_changed = self.config.import_policy != config.import_policy;
+        // ホスト名で指定したピアの設定は名前解決していないため、接続したときに解決したアドレスを引き継ぐ。
+        if config.remote_host.is_some() {
+            config.remote_ip = self.config.remote_ip;
+        }
+        self.config = config;
+        self.configured_remote_as = None;
+        if matches!(self.state, State::OpenConfirm | State::Established) {
+            self.apply_negotiated_remote_as();
+        }
+        self.rejoin_update_group();
+        if self.state != State::Established {
+            return;
+        }
+        if import_policy_changed && self.config.soft_reconfiguration_inbound {
+            self.reapply_import_policy().await;
+        }
+        if !self.config.route_collector {
+            let loc_rib = self.loc_rib.lock().await;
+            Self::refresh_update_group(&self.update_group, loc_rib).await;
+            self.event_queue.enqueue(Event::AdjRibOutChanged);
+        }
+    }
+
+    /// soft-reconfiguration-inboundで保持していた受信したルートに今のimport policyを適用し直し、
+    /// AdjRibInとLocRibを作り直す。適用し直した結果なくなったルートはカーネルからも削除する。
+    async fn reapply_import_policy(&mut self) {
+        let reimported = if self.config.import_policy.calls_script() {
+            let (config, aspa_table) = (self.config.clone(), Arc::clone(&self.aspa_table));
+            let received_routes = self.received_routes.clone();
+            policy::apply_blocking(move || {
+                received_routes.reimport(|route| Self::import_route(&config, &aspa_table, route))
+            })
+            .await
+        } else {
+            let (config, aspa_table) = (&self.config, &self.aspa_table);
+            self.received_routes
+                .reimport(|route| Self::import_route(config, aspa_table, route))
+        };
+        let mut adj_rib_in = self.adj_rib_in.lock().await;
+        let mut loc_rib = self.loc_rib.lock().await;
+        *adj_rib_in = reimported;
+        let removed = loc_rib.update_from_adj_rib_in(self.config.remote_ip, &mut adj_rib_in);
+        drop(adj_rib_in);
+        self.trace_removed_routes(&removed);
+        let fib = loc_rib.stage_kernel_routes(&self.config);
+        drop(loc_rib);
+        self.flush_kernel_routes(fib).await;
+        log_info!(
+            "{}から受信したルートに、import policyを適用し直しました。",
+            self.config.display_name()
+        );
+        self.event_queue.enqueue(Event::LocRibChanged);
+    }
+
+    /// PeerHandle::removeで依頼された終了の処理が済み、これ以上動かす必要がないか。
+    pub fn is_removed(&self) -> bool {
+        self.removed && self.state == State::Idle
+    }
+
+    fn context(&self) -> PeerContext<'_> {
+        PeerContext {
+            config: &self.config,
+            state: self.state,
+            remote_as: self.remote_as,
         }
     }
 
-    fn handle_message(&mut self, message: Message) {
+    /// 受信したMessageにフックを順に適用する。いずれかのフックが破棄した場合はNoneを返す。
+    fn apply_inbound_hooks(&self, message: Message) -> Option<Message> {
+        let context = self.context();
+        self.message_hooks
+            .iter()
+            .try_fold(message, |message, hook| hook.on_inbound(&context, message))
+    }
+
+    fn apply_outbound_hooks(&self, message: Message) -> Option<Message> {
+        let context = self.context();
+        self.message_hooks
+            .iter()
+            .try_fold(message, |message, hook| hook.on_outbound(&context, message))
+    }
+
+    /// update-rate-limitの範囲で、次のUpdateMessageを処理できるか。
+    fn update_rate_limit_allows(&mut self) -> bool {
+        match self.update_rate_limiter.as_mut() {
+            Some(limiter) => limiter.has_token(),
+            None => true,
+        }
+    }
+
+    async fn handle_message(&mut self, message: Message) {
+        self.statistics.lock().await.record_received(&message);
+        if let (Message::Update(_), Some(limiter)) = (&message, self.update_rate_limiter.as_mut()) {
+            limiter.consume();
+        }
+        let message = match self.apply_inbound_hooks(message) {
+            Some(message) => message,
+            None => return,
+        };
         match message {
             Message::Open(open) => self.event_queue.enqueue(Event::BgpOpen(open)),
             Message::Keepalive(keepalive) => {
                 self.event_queue.enqueue(Event::KeepAliveMsg(keepalive))
             }
             Message::Update(update) => self.event_queue.enqueue(Event::UpdateMsg(update)),
+            Message::Notification(notification) => {
+                self.event_queue.enqueue(Event::NotifMsg(notification))
+            }
+        }
+    }
+
+    /// 受信したデータをMessageに変換できなかった場合に、
+    /// エラーの種類に応じたEventを発生させる。
+    fn handle_message_error(&mut self, error: ConvertBytesToBgpMessageError) {
+        let notification = match error.notification_error() {
+            Some(e) => e.to_notification(),
+            None => {
+                log_warning!("受信したデータをMessageに変換できませんでした。{:?}", error);
+                return;
+            }
+        };
+        let event = match notification.error_code() {
+            ErrorCode::OpenMessageError => Event::BgpOpenMsgErr(notification),
+            ErrorCode::UpdateMessageError => Event::UpdateMsgErr(notification),
+            _ => Event::BgpHeaderErr(notification),
+        };
+        self.event_queue.enqueue(event);
+    }
+
+    async fn send(&mut self, message: Message) {
+        let message = match self.apply_outbound_hooks(message) {
+            Some(message) => message,
+            None => return,
+        };
+        self.statistics.lock().await.record_sent(&message);
+        if let Some(conn) = self.tcp_connection.as_mut() {
+            conn.send(message).await;
+        }
+    }
+
+    /// AdjRibOutのうち、前回までに広告した内容から変わったルートを広告し、MRAIタイマーを開始する。
+    /// AdjRibOutからなくなったルートは取り消す。セッションを確立して最初の広告では、
+    /// Update Groupでエンコード済みのUpdateMessageをそのまま送信する。
+    /// フックを追加している場合は、フックにUpdateMessageを渡すために1つずつ送信する。
+    async fn advertise_adj_rib_out(&mut self) {
+        // Update GroupのUpdateMessageはIPv4 Unicastのルートだけを含む。
+        if !self.address_families.contains(&AddressFamily::IPV4_UNICAST) {
+            self.pending_advertisement = false;
+            return;
+        }
+        // update-delayの間は送信しない。終了するとLocRibの変更として広告し直す。
+        if self.loc_rib.lock().await.is_read_only() {
+            self.pending_advertisement = false;
+            return;
+        }
+        // 送信キューが満杯のピアは、MRAIタイマーの期限切れまで送信を先送りする。
+        // Update Groupのロックを持ったまま待つと、同じグループのほかのピアまで止まるため。
+        if let Some(conn) = self.tcp_connection.as_mut() {
+            if !conn.outbound_queue_has_room() {
+                self.pending_advertisement = true;
+                self.mrai_timer.start_with_jitter(self.config.mrai());
+                return;
+            }
+        }
+        let group = self.update_group.lock().await;
+        // まだ何も広告していなければ、Update Groupでエンコード済みのbytesをそのまま送る。
+        // 広告済みのルートがあれば、前回広告してから変わったルートと取り消すルートだけを送る。
+        if self.advertised_routes.is_empty() && self.message_hooks.is_empty() {
+            self.statistics
+                .lock()
+                .await
+                .record_sent_updates(group.updates());
+            let bytes = group.bytes();
+            self.advertised_routes = group.adj_rib_out().clone();
+            self.advertised_generation = Some(group.generation());
+            drop(group);
+            if let Some(conn) = self.tcp_connection.as_mut() {
+                conn.send_bytes(&bytes).await;
+            }
+        } else {
+            let adj_rib_out = group.adj_rib_out();
+            let mut networks = match self.advertised_generation {
+                Some(generation) => group.changed_since(generation),
+                None => adj_rib_out.changed_networks(&self.advertised_routes),
+            };
+            // 広告する前に取り消されたルートは、取り消しも送らない。
+            networks.retain(|network| {
+                adj_rib_out.contains(network) || self.advertised_routes.contains(network)
+            });
+            let updates = adj_rib_out.updates_for(&networks);
+            for network in &networks {
+                match adj_rib_out.get(network) {
+                    Some(route) => {
+                        self.advertised_routes
+                            .insert(AddressFamily::IPV4_UNICAST, route.clone());
+                    }
+                    None => {
+                        self.advertised_routes
+                            .remove(AddressFamily::IPV4_UNICAST, network);
+                    }
+                }
+            }
+            self.advertised_generation = Some(group.generation());
+            drop(group);
+            for update in updates {
+                let update = update.with_four_octet_as(self.negotiated.four_octet_as);
+                self.send(Message::Update(update)).await;
+            }
+        }
+        self.send_end_of_rib().await;
+        log_info!("UpdateMessage send!!!!");
+        self.pending_advertisement = false;
+        self.mrai_timer.start_with_jitter(self.config.mrai());
+    }
+
+    /// セッションを確立して最初のルートを送り終えたら、1度だけEnd-of-RIBを送信する。
+    /// 受信したピアは、update-delayやLLGRのstaleなルートの削除をEnd-of-RIBで終えられる。
+    async fn send_end_of_rib(&mut self) {
+        if self.end_of_rib_sent {
+            return;
+        }
+        self.end_of_rib_sent = true;
+        self.send(Message::Update(UpdateMessage::end_of_rib()))
+            .await;
+    }
+
+    /// config.modeに応じて、ピアへの接続とピアからの接続の待ち受けを別のタスクで始める。
+    /// Passiveモードでは接続を待ち続けることがあるので、その間もPeer::nextで依頼やタイマーを処理できるようにする。
+    /// 失敗した場合はConnectRetryTimerを開始し、期限切れになったら接続し直す。待ち受けは失敗するまで続ける。
+    async fn connect(&mut self) {
+        if matches!(self.config.mode, Mode::Active | Mode::Both) {
+            self.connecting = Some(ConnectTask::spawn(
+                Arc::clone(&self.transport),
+                self.config.clone(),
+                Mode::Active,
+            ));
+        }
+        if matches!(self.config.mode, Mode::Passive | Mode::Both) && self.accepting.is_none() {
+            self.accepting = Some(ConnectTask::spawn(
+                Arc::clone(&self.transport),
+                self.config.clone(),
+                Mode::Passive,
+            ));
+        }
+        // すぐに確立できた場合や失敗した場合は、このステップのうちに扱う。
+        tokio::task::yield_now().await;
+        self.poll_connecting().await;
+    }
+
+    /// connectで始めたTCP Connectionの確立が終わっていれば、その結果をFSMに渡す。
+    async fn poll_connecting(&mut self) {
+        for initiated_locally in [true, false] {
+            let task = if initiated_locally {
+                &mut self.connecting
+            } else {
+                &mut self.accepting
+            };
+            let Some(result) = task.as_mut().and_then(ConnectTask::try_take) else {
+                continue;
+            };
+            *task = None;
+            match result {
+                Ok((remote_ip, conn)) => {
+                    self.connection_established(remote_ip, conn, initiated_locally)
+                        .await
+                }
+                Err(e) => {
+                    log_warning!("{:?}", e);
+                    // Bothモードで、もう片方のTCP Connectionを使っている場合は接続し直さない。
+                    if self.tcp_connection.is_none() {
+                        self.connect_retry_timer
+                            .start_with_jitter(self.config.connect_retry_time());
+                    }
+                }
+            }
+        }
+    }
+
+    /// 確立したTCP Connectionを、まだTCP Connectionがなければセッションに使う。
+    /// すでにあればConnection CollisionとしてOpenMessageを送り、対向機器のOpenMessageを待つ。
+    /// Establishedのセッションとの衝突では、新しいTCP Connectionを閉じる。
+    async fn connection_established(
+        &mut self,
+        remote_ip: Ipv4Addr,
+        mut conn: Connection,
+        initiated_locally: bool,
+    ) {
+        conn.set_capture(Arc::clone(&self.message_capture));
+        if self.tcp_connection.is_none() {
+            self.config.remote_ip = remote_ip;
+            self.tcp_connection = Some(conn);
+            self.initiated_locally = initiated_locally;
+            self.event_queue.enqueue(Event::TcpConnectionConfirmed);
+            return;
+        }
+        if self.state == State::Established || self.colliding_connection.is_some() {
+            return;
+        }
+        let open = Message::Open(self.open_message());
+        self.statistics.lock().await.record_sent(&open);
+        conn.send(open).await;
+        self.colliding_connection = Some(CollidingConnection {
+            connection: conn,
+            initiated_locally,
+        });
+    }
+
+    /// Connection Collisionで確立したTCP Connectionから、対向機器のOpenMessageを受信したら衝突を解決する。
+    /// OpenMessage以外を受信した場合や切断された場合は、そのTCP Connectionを閉じる。
+    async fn poll_colliding_connection(&mut self) {
+        let Some(colliding) = self.colliding_connection.as_mut() else {
+            return;
+        };
+        match colliding.connection.get_message().await {
+            Some(Ok(Message::Open(open))) => self.resolve_collision(open).await,
+            Some(_) => self.colliding_connection = None,
+            None if colliding.connection.is_closed() => self.colliding_connection = None,
+            None => (),
+        }
+    }
+
+    /// BGP Identifierが大きい側から開始したTCP Connectionを残し、
+    /// もう片方にはCease(Connection Collision Resolution)を送って閉じる(RFC 4271 6.8)。
+    /// 自分のBGP Identifierはlocal_ip、対向機器のBGP IdentifierはOpenMessageに含まれている。
+    async fn resolve_collision(&mut self, open: OpenMessage) {
+        let Some(colliding) = self.colliding_connection.take() else {
+            return;
+        };
+        let keep_initiated_locally = self.config.local_ip > open.bgp_identifier();
+        let notification = Message::Notification(NotificationMessage::new_cease(
+            cease::CONNECTION_COLLISION_RESOLUTION,
+            None,
+        ));
+        self.statistics.lock().await.record_sent(&notification);
+        if colliding.initiated_locally != keep_initiated_locally {
+            let mut connection = colliding.connection;
+            connection.send(notification).await;
+            return;
+        }
+        log_info!(
+            "{}とのConnection Collisionを解決し、後から確立したTCP Connectionを残します。",
+            self.config.display_name()
+        );
+        if let Some(conn) = self.tcp_connection.as_mut() {
+            conn.send(notification).await;
+        }
+        self.use_colliding_connection(colliding);
+        let event = Event::BgpOpen(open);
+        self.change_state(State::OpenSent, &event).await;
+        self.event_queue.enqueue(event);
+    }
+
+    /// Connection Collisionで確立したTCP Connectionをセッションに使う。
+    /// このTCP ConnectionではOpenMessageを送信済みなので、対向機器のOpenMessageを待つ。
+    /// 閉じるTCP Connectionから受信して、まだ処理していないEventは破棄する。
+    fn use_colliding_connection(&mut self, colliding: CollidingConnection) {
+        self.event_queue.retain(|event| !event.is_from_connection());
+        self.tcp_connection = Some(colliding.connection);
+        self.initiated_locally = colliding.initiated_locally;
+        self.keepalive_timer.stop();
+        self.hold_timer.start(Duration::from_secs(240));
+    }
+
+    /// 自分の設定とCapabilityを含むOpenMessageを作る。
+    fn open_message(&self) -> OpenMessage {
+        OpenMessage::new(
+            self.config.local_as,
+            self.config.local_ip,
+            self.config.hold_time(),
+        )
+        .with_capabilities(&self.capabilities())
+    }
+
+    /// 交渉したHold TimeでHold Timerを開始し直す。Hold Timeが0の場合は何もしない。
+    fn restart_hold_timer(&mut self) {
+        if !self.hold_time.is_zero() {
+            self.hold_timer.start(self.hold_time.duration());
+        }
+    }
+
+    fn restart_keepalive_timer(&mut self) {
+        if !self.hold_time.is_zero() {
+            self.keepalive_timer
+                .start_with_jitter(self.config.keepalive_interval(self.hold_time));
+        }
+    }
+
+    /// 受信したルートをAdjRibInに入れるかを判定する。
+    /// eBGPのピアから受信したルートはASPAで検証し、その結果をimport policyで参照できるようにする。
+    /// updateのNLRIのルートにimport policyを適用し、受け入れたルートを返す。
+    /// import policyがスクリプトを呼び出す場合は、AdjRibInのロックを取る前に
+    /// ブロッキング処理用のスレッドで適用する。
+    async fn import_routes(
+        config: &Config,
+        aspa_table: &Arc<AspaTable>,
+        update: &UpdateMessage,
+    ) -> HashMap<Ipv4Network, RibEntry> {
+        let routes = RibEntry::from_update(update);
+        if !config.import_policy.calls_script() {
+            return Self::import_all(config, aspa_table, routes);
+        }
+        let (config, aspa_table) = (config.clone(), Arc::clone(aspa_table));
+        policy::apply_blocking(move || Self::import_all(&config, &aspa_table, routes)).await
+    }
+
+    fn import_all(
+        config: &Config,
+        aspa_table: &AspaTable,
+        routes: Vec<RibEntry>,
+    ) -> HashMap<Ipv4Network, RibEntry> {
+        routes
+            .into_iter()
+            .filter_map(|mut route| {
+                Self::import_route(config, aspa_table, &mut route)
+                    .then_some((route.network_address, route))
+            })
+            .collect()
+    }
+
+    /// LocRibからUpdate GroupのAdjRibOutを作り直す。
+    /// export policyはLocRibのロックを放してから適用し、スクリプトの評価でほかのピアを待たせない。
+    async fn refresh_update_group(
+        update_group: &Mutex<UpdateGroup>,
+        loc_rib: MutexGuard<'_, LocRib>,
+    ) {
+        let mut update_group = update_group.lock().await;
+        let pending = update_group.prepare_refresh(&loc_rib);
+        drop(loc_rib);
+        if let Some(pending) = pending {
+            update_group.complete_refresh(pending).await;
+        }
+    }
+
+    fn import_route(config: &Config, aspa_table: &AspaTable, route: &mut RibEntry) -> bool {
+        if !config.accepts_prefix_length((*route.network_address).into()) {
+            return false;
+        }
+        if !config.aigp_session() {
+            route.remove_aigp();
+        }
+        route.weight = config.weight;
+        route.provenance = Some(Provenance {
+            internal: !config.is_ebgp(),
+            ..Provenance::new(config.remote_ip)
+        });
+        if config.is_ebgp() {
+            if let Some(as_path) = route.as_path() {
+                route.aspa_state = aspa_table.verify(as_path, config.remote_role);
+            }
+            // 対向機器のASで付けたLOCAL_PREFは使わず、import policyで上書きできるように先に付ける。
+            route.set_local_pref(config.default_local_pref());
+        }
+        config.import_policy.apply(route)
+    }
+
+    /// AdjRibInのルートの数をmax-prefixと比べ、上限を超えていればtrueを返す。
+    /// 警告の閾値に達したときは、セッションが切断される前に運用者が気付けるようにログに出力する。
+    async fn exceeds_max_prefix(&mut self) -> bool {
+        let (limit, warning_count) = match (
+            self.config.max_prefix,
+            self.config.max_prefix_warning_count(),
+        ) {
+            (Some(limit), Some(warning_count)) => (limit, warning_count),
+            _ => return false,
+        };
+        let prefixes = self.adj_rib_in.lock().await.len();
+        if prefixes > limit {
+            log_warning!(
+                "{}から受信したルートの数{}がmax-prefix {}を超えたため、セッションを切断します。",
+                self.config.display_name(),
+                prefixes,
+                limit
+            );
+            return true;
+        }
+        let warning = prefixes >= warning_count;
+        let mut statistics = self.statistics.lock().await;
+        if warning && !statistics.max_prefix_warning {
+            log_warning!(
+                "{}から受信したルートの数{}が、max-prefix {}の{}%に達しました。",
+                self.config.display_name(),
+                prefixes,
+                limit,
+                self.config.max_prefix_warning_threshold
+            );
+        }
+        statistics.max_prefix_warning = warning;
+        false
+    }
+
+    async fn change_state(&mut self, state: State, event: &Event) {
+        self.change_state_with_error(state, event, None).await;
+    }
+
+    async fn change_state_with_error(
+        &mut self,
+        state: State,
+        event: &Event,
+        error: Option<String>,
+    ) {
+        if self.config.debug.fsm {
+            log_debug!(
+                "[{}] {:?} -> {:?} ({})",
+                self.config.display_name(),
+                self.state,
+                state,
+                event.name()
+            );
+        }
+        if let Some(tracer) = &self.tracer {
+            tracer.transition(self.config.remote_ip, self.state, state, event);
+        }
+        let now = SystemTime::now();
+        self.export_span(
+            Span::root("bgp.fsm.transition", now, now)
+                .with_attribute("bgp.peer", self.config.remote_ip.to_string())
+                .with_attribute("bgp.fsm.old_state", format!("{:?}", self.state))
+                .with_attribute("bgp.fsm.new_state", format!("{:?}", state))
+                .with_attribute("bgp.fsm.event", event.name()),
+        );
+        self.state = state;
+        self.statistics
+            .lock()
+            .await
+            .record_transition(state, event.name(), error);
+    }
+
+    /// NotificationMessageを送信した後にTCP Connectionを閉じ、Idle状態に戻る。
+    async fn send_notification_and_reset(
+        &mut self,
+        notification: NotificationMessage,
+        event: &Event,
+    ) {
+        let error = format!("sent notification {}", notification);
+        self.send(Message::Notification(notification)).await;
+        self.reset_session(event, Some(error)).await;
+    }
+
+    /// TCP Connectionを閉じ、このピアから学習したルートを削除してIdle状態に戻る。
+    /// 管理者が停止した場合を除き、ConnectRetryTimerが期限切れになると接続し直す。
+    /// LLGRを交渉したセッションがNotificationMessageを送受信せずに切れた場合は、
+    /// ルートを削除せずにstaleとして保持する。
+    async fn reset_session(&mut self, event: &Event, error: Option<String>) {
+        self.connecting = None;
+        self.accepting = None;
+        self.colliding_connection = None;
+        self.tcp_connection = None;
+        self.mrai_timer.stop();
+        self.hold_timer.stop();
+        self.keepalive_timer.stop();
+        self.connect_retry_timer.stop();
+        self.pending_advertisement = false;
+        self.end_of_rib_sent = false;
+        self.advertised_routes = AdjRibOut::new();
+        self.adj_rib_in_changes.clear();
+        self.received_routes = AdjRibIn::new();
+        self.address_families.clear();
+        self.restore_configured_remote_as();
+        self.negotiated = NegotiatedCapabilities::default();
+        self.rejoin_update_group();
+        match self.llgr_stale_time.take() {
+            Some(stale_time)
+                if matches!(event, Event::TcpConnectionFails | Event::HoldTimerExpires) =>
+            {
+                self.retain_routes_as_stale(stale_time).await
+            }
+            _ => self.flush_routes_learned_from_peer().await,
+        }
+        self.statistics.lock().await.max_prefix_warning = false;
+        self.change_state_with_error(State::Idle, event, error)
+            .await;
+        // 管理者が停止したのでなければ、ConnectRetryTimerの期限切れで自動的に接続し直す。
+        if !matches!(event, Event::ManualStop(_)) {
+            self.connect_retry_timer
+                .start_with_jitter(self.config.connect_retry_time());
+        }
+    }
+
+    /// このピアから学習したルートをstale_timeの間だけ保持する。
+    /// NO_LLGRが付いたルートはすぐに削除する。
+    async fn retain_routes_as_stale(&mut self, stale_time: Duration) {
+        let mut adj_rib_in = self.adj_rib_in.lock().await;
+        let mut loc_rib = self.loc_rib.lock().await;
+        let removed = loc_rib.retain_as_stale(&mut adj_rib_in);
+        drop(adj_rib_in);
+        self.trace_removed_routes(&removed);
+        let fib = loc_rib.stage_kernel_routes(&self.config);
+        drop(loc_rib);
+        self.flush_kernel_routes(fib).await;
+        self.llgr_stale_timer.start(stale_time);
+    }
+
+    /// LLGRでstaleとして保持していたルートを、AdjRibIn, LocRib, カーネルのルーティングテーブルから削除する。
+    async fn flush_stale_routes(&mut self) {
+        self.llgr_stale_timer.stop();
+        let stale = self.adj_rib_in.lock().await.take_llgr_stale_routes();
+        let mut loc_rib = self.loc_rib.lock().await;
+        let removed = loc_rib.remove_routes_learned_from(&stale);
+        self.trace_removed_routes(&removed);
+        let fib = loc_rib.stage_kernel_routes(&self.config);
+        drop(loc_rib);
+        self.flush_kernel_routes(fib).await;
+    }
+
+    /// HAで処理を引き継いだときにPrimaryから同期していたこのピアのルートのうち、
+    /// 受信し直さなかったルートをLocRibとカーネルのルーティングテーブルから削除する。
+    /// LocRibが変わった場合はtrueを返す。
+    async fn flush_replicated_routes(&mut self) -> bool {
+        let adj_rib_in = self.adj_rib_in.lock().await;
+        let mut loc_rib = self.loc_rib.lock().await;
+        let version = loc_rib.version();
+        let removed = loc_rib.flush_replicated_routes_of(self.config.remote_ip, &adj_rib_in);
+        drop(adj_rib_in);
+        self.trace_removed_routes(&removed);
+        if loc_rib.version() == version {
+            return false;
+        }
+        let fib = loc_rib.stage_kernel_routes(&self.config);
+        drop(loc_rib);
+        self.flush_kernel_routes(fib).await;
+        true
+    }
+
+    /// stage_kernel_routesで予約したルートを、カーネルのルーティングテーブルに書き込む。
+    /// カーネルへの書き込みを待つ間もほかのピアがLocRibを更新できるように、LocRibのロックを外してから呼び出す。
+    async fn flush_kernel_routes(&mut self, fib: AttachedFib) {
+        if let Err(e) = fib.flush(&self.config).await {
+            log_error!("{:?}", e);
+        }
+    }
+
+    /// LocRibから削除したルートをトレースに書き出す。
+    fn trace_removed_routes(&self, removed: &[RibEntry]) {
+        if let Some(tracer) = &self.tracer {
+            let withdrawn: Vec<Ipv4Network> = removed.iter().map(|r| r.network_address).collect();
+            tracer.rib_change(self.config.remote_ip, "loc-rib", &[], &withdrawn);
+        }
+    }
+
+    /// 対向機器のOpenMessageから、LLGRでルートを保持する時間を決める。
+    /// 対向機器が広告したIPv4 UnicastのLong-lived Stale Timeを、自分の設定値で制限する。
+    fn negotiate_llgr_stale_time(&self, open: &OpenMessage) -> Option<Duration> {
+        let local = self.config.llgr_stale_time?;
+        let remote = open.capabilities().into_iter().find_map(|c| match c {
+            Capability::LongLivedGracefulRestart(families) => families
+                .into_iter()
+                .find(|f| f.is_ipv4_unicast())
+                .map(|f| f.stale_time),
+            _ => None,
+        })?;
+        Some(Duration::from_secs(local.min(remote).into())).filter(|d| !d.is_zero())
+    }
+
+    /// 対向機器のOpenMessageから、このセッションで交換するAFI/SAFIを決める。
+    /// Multiprotocol Capabilityを1つも広告しない対向機器とは、IPv4 Unicastだけを交換する(RFC 4760)。
+    fn negotiate_address_families(&self, open: &OpenMessage) -> Vec<AddressFamily> {
+        let mut remote: Vec<AddressFamily> = open
+            .capabilities()
+            .into_iter()
+            .filter_map(|c| match c {
+                Capability::Multiprotocol(family) => Some(family),
+                _ => None,
+            })
+            .collect();
+        if remote.is_empty() {
+            remote.push(AddressFamily::IPV4_UNICAST);
+        }
+        self.config
+            .address_families
+            .iter()
+            .copied()
+            .filter(|family| remote.contains(family))
+            .collect()
+    }
+
+    /// このセッションで交換しているAFI/SAFI。Establishedでなければ空である。
+    pub fn address_families(&self) -> &[AddressFamily] {
+        &self.address_families
+    }
+
+    /// OpenMessageで広告するCapability。
+    fn capabilities(&self) -> Vec<Capability> {
+        let mut capabilities: Vec<Capability> = self
+            .config
+            .address_families
+            .iter()
+            .copied()
+            .map(Capability::Multiprotocol)
+            .collect();
+        capabilities.push(Capability::FourOctetAs(self.config.local_as));
+        if let Some(stale_time) = self.config.llgr_stale_time {
+            capabilities.push(Capability::LongLivedGracefulRestart(vec![
+                LlgrFamily::ipv4_unicast(stale_time),
+            ]));
+        }
+        capabilities
+    }
+
+    /// このピアから学習したルートをAdjRibIn, LocRib, カーネルのルーティングテーブルから削除する。
+    async fn flush_routes_learned_from_peer(&mut self) {
+        self.llgr_stale_timer.stop();
+        let mut adj_rib_in = self.adj_rib_in.lock().await;
+        let mut loc_rib = self.loc_rib.lock().await;
+        let removed = loc_rib.remove_routes_learned_from(&adj_rib_in);
+        *adj_rib_in = AdjRibIn::new();
+        drop(adj_rib_in);
+        self.trace_removed_routes(&removed);
+        let fib = loc_rib.stage_kernel_routes(&self.config);
+        drop(loc_rib);
+        self.flush_kernel_routes(fib).await;
+    }
+
+    /// UpdateMessageの処理を始めるときに、処理中のconvergence_spanがなければ作成し、
+    /// 受信したデータをMessageに変換したSpanを送る。
+    fn start_convergence_span(&mut self) {
+        let (received, decoded) = match self.update_decoded.take() {
+            Some(times) => times,
+            None if self.otlp_exporter.is_some() => (SystemTime::now(), SystemTime::now()),
+            None => return,
+        };
+        if self.convergence_span.is_none() {
+            self.convergence_span = Some(
+                Span::root("bgp.convergence", received, received)
+                    .with_attribute("bgp.peer", self.config.remote_ip.to_string()),
+            );
+        }
+        if let Some(root) = &self.convergence_span {
+            self.export_span(root.child("bgp.decode", received, decoded));
+        }
+    }
+
+    /// 現在のstateで受信することを想定していないメッセージであれば、そのTypeを返す。
+    /// Connection Collisionで受信するOpenMessageは、セッションのTCP Connectionとは別に扱う。
+    fn unexpected_message_type(&self, event: &Event) -> Option<MessageType> {
+        match (self.state, event) {
+            (State::OpenSent, Event::KeepAliveMsg(_)) => Some(MessageType::Keepalive),
+            (State::OpenSent | State::OpenConfirm, Event::UpdateMsg(_)) => {
+                Some(MessageType::Update)
+            }
+            (State::OpenConfirm | State::Established, Event::BgpOpen(_)) => Some(MessageType::Open),
+            _ => None,
         }
     }
 
     async fn handle_event(&mut self, event: &Event) {
+        if let Some(tracer) = &self.tracer {
+            tracer.event(self.config.remote_ip, event);
+        }
+        if let Some(type_) = self.unexpected_message_type(event) {
+            let notification =
+                NotificationBuilder::unexpected_message(self.state, type_).to_notification();
+            self.send_notification_and_reset(notification, event).await;
+            return;
+        }
+
+        // 受信したメッセージのエラーは、Idle以外のどの状態でも
+        // NotificationMessageを送信してIdle状態に戻る。
+        match event {
+            Event::BgpHeaderErr(notification)
+            | Event::BgpOpenMsgErr(notification)
+            | Event::UpdateMsgErr(notification)
+                if self.state != State::Idle =>
+            {
+                self.send_notification_and_reset(notification.clone(), event)
+                    .await;
+                return;
+            }
+            Event::ManualStop(shutdown_communication) if self.state != State::Idle => {
+                let notification = NotificationMessage::new_cease(
+                    cease::ADMINISTRATIVE_SHUTDOWN,
+                    shutdown_communication.as_deref(),
+                );
+                self.send_notification_and_reset(notification, event).await;
+                return;
+            }
+            // 対向機器がConnection Collisionを解決し、もう片方のTCP Connectionを残した。
+            Event::NotifMsg(notification)
+                if self.colliding_connection.is_some()
+                    && notification.error_code() == ErrorCode::Cease
+                    && notification.error_subcode() == cease::CONNECTION_COLLISION_RESOLUTION =>
+            {
+                if let Some(colliding) = self.colliding_connection.take() {
+                    self.use_colliding_connection(colliding);
+                }
+                self.change_state(State::OpenSent, event).await;
+                return;
+            }
+            Event::NotifMsg(notification) if self.state != State::Idle => {
+                log_info!(
+                    "{}からNotificationMessageを受信しました。{}",
+                    self.config.display_name(),
+                    notification
+                );
+                let error = format!("received notification {}", notification);
+                self.reset_session(event, Some(error)).await;
+                return;
+            }
+            Event::HoldTimerExpires if self.state != State::Idle => {
+                let notification = NotificationBuilder::hold_timer_expired().to_notification();
+                self.send_notification_and_reset(notification, event).await;
+                return;
+            }
+            Event::KeepaliveTimerExpires
+                if matches!(self.state, State::OpenConfirm | State::Established) =>
+            {
+                self.send(Message::new_keepalive()).await;
+                self.restart_keepalive_timer();
+                return;
+            }
+            Event::LlgrStaleTimerExpires => {
+                self.flush_stale_routes().await;
+                if self.state == State::Established {
+                    self.event_queue.enqueue(Event::LocRibChanged);
+                }
+                return;
+            }
+            Event::TcpConnectionFails if self.state != State::Idle => {
+                self.reset_session(event, Some("TCP connection closed".to_owned()))
+                    .await;
+                return;
+            }
+            _ => (),
+        }
+
         match &self.state {
             State::Idle => match event {
-                Event::ManualStart => {
-                    self.tcp_connection = Connection::connect(&self.config).await.ok();
-                    if self.tcp_connection.is_some() {
-                        self.event_queue.enqueue(Event::TcpConnectionConfirmed);
-                    } else {
-                        panic!("Failed to start TCP Connection. {:?}", self.config)
-                    }
-                    self.state = State::Connect;
+                // 終了を依頼された後は、先に溜まっていたManualStartなどで接続し直さない。
+                Event::ManualStart | Event::AutomaticStart if !self.removed => {
+                    self.connect().await;
+                    self.change_state(State::Connect, event).await;
                 }
+                // 自動的に接続し直すのをやめる。
+                Event::ManualStop(_) => self.connect_retry_timer.stop(),
                 _ => {}
             },
             State::Connect => match event {
+                Event::ConnectRetryTimerExpires => self.connect().await,
                 Event::TcpConnectionConfirmed => {
-                    self.tcp_connection
-                        .as_mut()
-                        .unwrap()
-                        .send(Message::new_open(
-                            self.config.local_as,
-                            self.config.local_ip,
-                        ))
-                        .await;
-                    self.state = State::OpenSent
+                    self.send(Message::Open(self.open_message())).await;
+                    // OpenMessageを受信するまでは、RFC 4271で推奨されている4分をHold Timeとする。
+                    self.hold_timer.start(Duration::from_secs(240));
+                    self.change_state(State::OpenSent, event).await;
                 }
                 _ => {}
             },
             State::OpenSent => match event {
+                Event::BgpOpen(open)
+                    if !matches!(open.as_number(), Some(as_number)
+                        if self.config.remote_as.accepts(self.config.local_as, as_number)) =>
+                {
+                    let notification = NotificationBuilder::bad_peer_as().to_notification();
+                    self.send_notification_and_reset(notification, event).await;
+                }
                 Event::BgpOpen(open) => {
-                    self.tcp_connection
-                        .as_mut()
-                        .unwrap()
-                        .send(Message::new_keepalive())
-                        .await;
-                    self.state = State::OpenConfirm;
+                    self.remote_as = open.as_number();
+                    self.apply_negotiated_remote_as();
+                    // 自分は常に4オクテットAS Capabilityを広告している。
+                    self.negotiated.four_octet_as = open.supports_four_octet_as();
+                    if let Some(connection) = self.tcp_connection.as_mut() {
+                        connection.set_four_octet_as(self.negotiated.four_octet_as);
+                    }
+                    self.rejoin_update_group();
+                    self.llgr_stale_time = self.negotiate_llgr_stale_time(open);
+                    self.negotiated.llgr = self.llgr_stale_time.is_some();
+                    self.address_families = self.negotiate_address_families(open);
+                    // Hold Timeは自分と対向機器が提案した値の小さいほうを使う。
+                    self.hold_time = self.config.hold_time().min(open.hold_time());
+                    if self.hold_time.is_zero() {
+                        self.hold_timer.stop();
+                    }
+                    self.restart_hold_timer();
+                    self.restart_keepalive_timer();
+                    self.send(Message::new_keepalive()).await;
+                    self.change_state(State::OpenConfirm, event).await;
                 }
                 _ => {}
             },
-            State::OpenConfirm => match event {
-                Event::KeepAliveMsg(keepalive) => {
-                    self.state = State::Established;
+            State::OpenConfirm => {
+                if let Event::KeepAliveMsg(_) = event {
+                    self.restart_hold_timer();
+                    // Establishedのセッションとの衝突では新しいTCP Connectionを閉じるので、
+                    // もう片方の接続や待ち受けは必要ない。
+                    self.connecting = None;
+                    self.accepting = None;
+                    self.colliding_connection = None;
+                    self.change_state(State::Established, event).await;
                     self.event_queue.enqueue(Event::Established);
                 }
-                _ => {}
-            },
-            State::Established => match event {
-                Event::Established | Event::LocRibChanged => {
-                    let loc_rib = self.loc_rib.lock().await;
-                    self.adj_rib_out
-                        .install_from_loc_rib(&loc_rib, &self.config);
-                    self.event_queue.enqueue(Event::AdjRibOutChanged);
-                }
-                Event::AdjRibOutChanged => {
-                    let updates: Vec<UpdateMessage> = (&self.adj_rib_out).into();
-                    for update in updates {
-                        self.tcp_connection
-                            .as_mut()
-                            .unwrap()
-                            .send(Message::Update(update))
-                            .await;
+            }
+            State::Established => {
+                match event {
+                    Event::Established => {
+                        if self.config.route_collector {
+                            // ルートを広告しないため、すぐにEnd-of-RIBを送る。
+                            self.send_end_of_rib().await;
+                        } else {
+                            let loc_rib = self.loc_rib.lock().await;
+                            Self::refresh_update_group(&self.update_group, loc_rib).await;
+                            self.event_queue.enqueue(Event::AdjRibOutChanged);
+                        }
                     }
-                    println!("UpdateMessage send!!!!")
+                    Event::KeepAliveMsg(_) => self.restart_hold_timer(),
+                    Event::UpdateMsg(update) if update.is_end_of_rib() => {
+                        // 再接続後に送り直されなかったstaleなルートは、もう到達できない。
+                        self.restart_hold_timer();
+                        self.statistics.lock().await.end_of_rib_received = true;
+                        if self.llgr_stale_timer.is_running() {
+                            self.flush_stale_routes().await;
+                            self.event_queue.enqueue(Event::LocRibChanged);
+                        }
+                        if self.flush_replicated_routes().await {
+                            self.event_queue.enqueue(Event::LocRibChanged);
+                        }
+                    }
+                    Event::UpdateMsg(_)
+                        if !self.address_families.contains(&AddressFamily::IPV4_UNICAST) =>
+                    {
+                        // 交換していないAFI/SAFIのルートはAdjRibInに入れない。
+                        self.restart_hold_timer();
+                    }
+                    Event::UpdateMsg(update) => {
+                        self.restart_hold_timer();
+                        self.start_convergence_span();
+                        let policy_started = SystemTime::now();
+                        let received = update.network_layer_reachability_information().len();
+                        if self.config.soft_reconfiguration_inbound {
+                            self.received_routes
+                                .install_from_update(update.clone(), |_| true);
+                        }
+                        self.adj_rib_in_changes.extend(
+                            update
+                                .withdrawn_routes()
+                                .iter()
+                                .chain(update.network_layer_reachability_information()),
+                        );
+                        let mut imported =
+                            Self::import_routes(&self.config, &self.aspa_table, update).await;
+                        let rejected = self.adj_rib_in.lock().await.install_from_update(
+                            update.clone(),
+                            |route| match imported.remove(&route.network_address) {
+                                Some(imported) => {
+                                    *route = imported;
+                                    true
+                                }
+                                None => false,
+                            },
+                        );
+                        if let Some(tracer) = &self.tracer {
+                            tracer.rib_change(
+                                self.config.remote_ip,
+                                "adj-rib-in",
+                                update.network_layer_reachability_information(),
+                                update.withdrawn_routes(),
+                            );
+                        }
+                        self.export_convergence_child(
+                            "bgp.import-policy",
+                            policy_started,
+                            vec![
+                                ("bgp.update.announced", received.into()),
+                                (
+                                    "bgp.update.withdrawn",
+                                    update.withdrawn_routes().len().into(),
+                                ),
+                                ("bgp.update.rejected", rejected.into()),
+                            ],
+                        );
+                        let mut statistics = self.statistics.lock().await;
+                        statistics.prefixes_accepted += (received - rejected) as u64;
+                        statistics.prefixes_rejected += rejected as u64;
+                        drop(statistics);
+                        if self.exceeds_max_prefix().await {
+                            let notification = NotificationMessage::new(
+                                ErrorCode::Cease,
+                                cease::MAXIMUM_NUMBER_OF_PREFIXES_REACHED,
+                                max_prefix_notification_data(self.config.max_prefix.unwrap_or(0)),
+                            );
+                            self.send_notification_and_reset(notification, event).await;
+                            return;
+                        }
+                        self.event_queue.enqueue(Event::AdjRibInChanged);
+                    }
+                    Event::AdjRibInChanged => {
+                        let best_path_started = SystemTime::now();
+                        let mut loc_rib = self.loc_rib.lock().await;
+                        let changes = std::mem::take(&mut self.adj_rib_in_changes);
+                        let removed = loc_rib.update_networks_from_adj_rib_in(
+                            self.config.remote_ip,
+                            &mut *self.adj_rib_in.lock().await,
+                            &changes,
+                        );
+                        // 取り消されたルートは、LocRibChangedでカーネルのルーティングテーブルから削除する。
+                        self.trace_removed_routes(&removed);
+                        self.export_convergence_child(
+                            "bgp.best-path",
+                            best_path_started,
+                            vec![
+                                ("bgp.loc_rib.version", loc_rib.version().into()),
+                                ("bgp.loc_rib.routes", loc_rib.len().into()),
+                            ],
+                        );
+                        if let Some(tracer) = &self.tracer {
+                            tracer.loc_rib_updated(
+                                self.config.remote_ip,
+                                loc_rib.version(),
+                                loc_rib.len(),
+                            );
+                        }
+                        drop(loc_rib);
+                        self.event_queue.enqueue(Event::LocRibChanged);
+                    }
+                    Event::LocRibChanged => {
+                        let mut loc_rib = self.loc_rib.lock().await;
+                        let fib = loc_rib.stage_kernel_routes(&self.config);
+                        if self.config.route_collector {
+                            drop(loc_rib);
+                        } else {
+                            Self::refresh_update_group(&self.update_group, loc_rib).await;
+                            self.event_queue.enqueue(Event::AdjRibOutChanged);
+                        }
+                        let fib_started = SystemTime::now();
+                        self.flush_kernel_routes(fib).await;
+                        self.export_convergence_child("bgp.fib-program", fib_started, vec![]);
+                        if let Some(mut root) = self.convergence_span.take() {
+                            root.end = SystemTime::now();
+                            self.export_span(root);
+                        }
+                    }
+                    Event::AdjRibOutChanged => {
+                        // MRAIタイマーの動作中は送信せず、期限切れ時にまとめて送信する。
+                        if self.mrai_timer.is_running() {
+                            self.pending_advertisement = true;
+                        } else {
+                            self.advertise_adj_rib_out().await;
+                        }
+                    }
+                    Event::MraiTimerExpires if self.pending_advertisement => {
+                        self.advertise_adj_rib_out().await;
+                    }
+                    _ => {}
                 }
-                _ => {}
-            },
+            }
         }
     }
 }
 
+/// Maximum Number of Prefixes ReachedのNotificationMessageのData(RFC 4486)。
+/// AFI、SAFIと、超えた上限のルートの数を入れる。
+fn max_prefix_notification_data(limit: usize) -> BytesMut {
+    let mut data = BytesMut::new();
+    data.put_u16(AddressFamily::IPV4_UNICAST.afi);
+    data.put_u8(AddressFamily::IPV4_UNICAST.safi);
+    data.put_u32(limit.try_into().unwrap_or(u32::MAX));
+    data
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tokio::time::Duration;
+    use crate::clock::MockClock;
+    use crate::connection::{InMemoryTransport, Stream};
+    use crate::debug::Direction;
+    use crate::harness::PeerHarness;
+    use crate::packets::codec::BgpCodec;
+    use crate::packets::notification::{finite_state_machine_error, open_message_error};
+    use crate::packets::update::UpdateMessage;
+    use crate::path_attribute::{AsPath, Origin, PathAttribute};
+    use crate::policy::PolicyAction;
+    use crate::simulation::Simulation;
+    use futures::future::BoxFuture;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn hold_time_is_negotiated_to_smaller_value() {
+        let local_config = "64512 127.0.0.1 64513 127.0.0.2 active hold-time 90"
+            .parse()
+            .unwrap();
+        let remote_config = "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
+        let mut simulation = Simulation::new(local_config, remote_config);
+        simulation.start();
+
+        assert!(simulation.run_until_both_in(State::Established, 10).await);
+        assert_eq!(simulation.local.hold_time, HoldTime::from(90));
+        assert_eq!(simulation.remote.hold_time, HoldTime::from(90));
+        assert!(simulation.local.hold_timer.is_running());
+        assert!(simulation.local.keepalive_timer.is_running());
+    }
+
+    #[tokio::test]
+    async fn hold_time_zero_disables_hold_and_keepalive_timers() {
+        let local_config = "64512 127.0.0.1 64513 127.0.0.2 active hold-time 0"
+            .parse()
+            .unwrap();
+        let remote_config = "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
+        let mut simulation = Simulation::new(local_config, remote_config);
+        simulation.start();
+
+        assert!(simulation.run_until_both_in(State::Established, 10).await);
+        for peer in [&simulation.local, &simulation.remote] {
+            assert!(peer.hold_time.is_zero());
+            assert!(!peer.hold_timer.is_running());
+            assert!(!peer.keepalive_timer.is_running());
+        }
+    }
+
+    #[tokio::test]
+    async fn hold_timer_expires_when_virtual_time_passes_hold_time() {
+        let local_config = "64512 127.0.0.1 64513 127.0.0.2 active hold-time 90"
+            .parse()
+            .unwrap();
+        let remote_config = "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
+        let mut simulation = Simulation::new(local_config, remote_config);
+        let clock = Arc::new(MockClock::new());
+        simulation.local.set_clock(clock.clone());
+        simulation.start();
+        assert!(simulation.run_until_both_in(State::Established, 10).await);
+
+        // remoteは動かさないので、localはKeepaliveMessageを受信しない。
+        for (seconds, expected) in [
+            (0, State::Established),
+            (89, State::Established),
+            (1, State::Idle),
+        ] {
+            clock.advance(Duration::from_secs(seconds));
+            for _ in 0..5 {
+                simulation.local.next().await;
+            }
+            assert_eq!(simulation.local.state(), expected);
+        }
+    }
+
+    /// 受信したMessageを記録し、KeepaliveMessageだけを破棄するフック。
+    #[derive(Debug, Default)]
+    struct DropKeepaliveAndUpdate(std::sync::Mutex<Vec<Message>>);
+
+    impl MessageHook for DropKeepaliveAndUpdate {
+        fn on_inbound(&self, _: &PeerContext, message: Message) -> Option<Message> {
+            self.0.lock().unwrap().push(message.clone());
+            match message {
+                // Establishedになった対向機器が送るEnd-of-RIBも捨てる。
+                Message::Keepalive(_) | Message::Update(_) => None,
+                message => Some(message),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn disabled_peer_ceases_session_and_stays_idle() {
+        let local_config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let remote_config = "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
+        let mut simulation = Simulation::new(local_config, remote_config);
+        simulation.start();
+        assert!(simulation.run_until_both_in(State::Established, 10).await);
+
+        simulation.local.handle().set_enabled(false);
+        assert!(simulation.run_until_both_in(State::Idle, 10).await);
+        for _ in 0..5 {
+            simulation.step().await;
+        }
+        assert_eq!(simulation.local.state(), State::Idle);
+        assert!(simulation.local.statistics().lock().await.disabled);
+        let received = simulation
+            .remote
+            .statistics()
+            .lock()
+            .await
+            .last_notification_received
+            .clone();
+        assert_eq!(
+            received.map(|n| n.error_subcode()),
+            Some(cease::ADMINISTRATIVE_SHUTDOWN)
+        );
+
+        simulation.local.handle().set_enabled(true);
+        simulation.local.next().await;
+        assert_eq!(simulation.local.state(), State::Connect);
+        assert!(!simulation.local.statistics().lock().await.disabled);
+    }
+
+    #[tokio::test]
+    async fn message_hook_can_drop_inbound_messages() {
+        let local_config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let remote_config = "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
+        let mut simulation = Simulation::new(local_config, remote_config);
+        let hook = Arc::new(DropKeepaliveAndUpdate::default());
+        simulation.local.add_message_hook(hook.clone());
+        simulation.start();
+
+        // localはKeepaliveMessageを受け取れないため、OpenConfirmから先に進まない。
+        assert!(!simulation.run_until_both_in(State::Established, 10).await);
+        assert_eq!(simulation.local.state(), State::OpenConfirm);
+        assert_eq!(simulation.remote.state(), State::Established);
+        let received = hook.0.lock().unwrap();
+        assert!(matches!(received[0], Message::Open(_)));
+        assert!(matches!(received[1], Message::Keepalive(_)));
+    }
+
+    /// 最初のfailures回は接続に失敗し、その後はstreamを返すトランスポート。
+    #[derive(Debug)]
+    struct FlakyTransport {
+        failures: std::sync::Mutex<usize>,
+        stream: InMemoryTransport,
+    }
+
+    impl BgpTransport for FlakyTransport {
+        fn connect<'a>(
+            &'a self,
+            config: &'a Config,
+        ) -> BoxFuture<'a, anyhow::Result<Box<dyn Stream>>> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Box::pin(async { Err(anyhow::anyhow!("connection refused")) });
+            }
+            self.stream.connect(config)
+        }
+
+        fn accept<'a>(
+            &'a self,
+            config: &'a Config,
+        ) -> BoxFuture<'a, anyhow::Result<Box<dyn Stream>>> {
+            self.connect(config)
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_connection_is_retried_after_connect_retry_time() {
+        let config = "64512 127.0.0.1 64513 127.0.0.2 active connect-retry 5"
+            .parse()
+            .unwrap();
+        let mut peer = Peer::new(config, Arc::new(Mutex::new(LocRib::empty())));
+        let clock = Arc::new(MockClock::new());
+        peer.set_clock(clock.clone());
+        let (local, _remote) = tokio::io::duplex(65536);
+        peer.set_transport(Arc::new(FlakyTransport {
+            failures: std::sync::Mutex::new(1),
+            stream: InMemoryTransport::new(local),
+        }));
+        peer.start();
+        peer.next().await;
+        assert_eq!(peer.state(), State::Connect);
+        assert!(peer.connect_retry_timer.is_running());
+
+        clock.advance(Duration::from_secs(3));
+        peer.next().await;
+        assert!(peer.tcp_connection.is_none());
+
+        clock.advance(Duration::from_secs(2));
+        for _ in 0..2 {
+            peer.next().await;
+        }
+        assert_eq!(peer.state(), State::OpenSent);
+    }
+
+    /// 接続も待ち受けも終わらないトランスポート。接続してこないPassiveのピアの代わりに使う。
+    #[derive(Debug)]
+    struct PendingTransport;
+
+    impl BgpTransport for PendingTransport {
+        fn connect<'a>(&'a self, _: &'a Config) -> BoxFuture<'a, anyhow::Result<Box<dyn Stream>>> {
+            Box::pin(futures::future::pending())
+        }
+
+        fn accept<'a>(&'a self, _: &'a Config) -> BoxFuture<'a, anyhow::Result<Box<dyn Stream>>> {
+            Box::pin(futures::future::pending())
+        }
+    }
+
+    #[tokio::test]
+    async fn passive_peer_waiting_for_connection_can_be_disabled() {
+        let config = "64512 127.0.0.1 64513 127.0.0.2 passive".parse().unwrap();
+        let mut peer = Peer::new(config, Arc::new(Mutex::new(LocRib::empty())));
+        peer.set_transport(Arc::new(PendingTransport));
+        peer.start();
+        peer.next().await;
+        assert_eq!(peer.state(), State::Connect);
+        assert!(peer.accepting.is_some());
+
+        peer.handle().set_enabled(false);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            for _ in 0..3 {
+                peer.next().await;
+            }
+        })
+        .await
+        .expect("接続を待っている間も依頼を処理する必要があります。");
+        assert_eq!(peer.state(), State::Idle);
+        assert!(peer.accepting.is_none());
+        assert!(peer.statistics().lock().await.disabled);
+    }
+
+    /// 接続と待ち受けで、別のストリームを返すトランスポート。
+    #[derive(Debug)]
+    struct CollidingTransport {
+        connected: InMemoryTransport,
+        accepted: InMemoryTransport,
+    }
+
+    impl BgpTransport for CollidingTransport {
+        fn connect<'a>(
+            &'a self,
+            config: &'a Config,
+        ) -> BoxFuture<'a, anyhow::Result<Box<dyn Stream>>> {
+            self.connected.connect(config)
+        }
+
+        fn accept<'a>(
+            &'a self,
+            config: &'a Config,
+        ) -> BoxFuture<'a, anyhow::Result<Box<dyn Stream>>> {
+            self.accepted.accept(config)
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_collision_keeps_connection_initiated_by_higher_bgp_identifier() {
+        let local_config = "64512 127.0.0.1 64513 127.0.0.2 both".parse().unwrap();
+        let remote_config = "64513 127.0.0.2 64512 127.0.0.1 both".parse().unwrap();
+        let mut simulation = Simulation::new(local_config, remote_config);
+        // localから開始したTCP Connectionと、remoteから開始したTCP Connectionが両方確立する。
+        let (from_local, to_remote) = InMemoryTransport::pair();
+        let (from_remote, to_local) = InMemoryTransport::pair();
+        simulation.local.set_transport(Arc::new(CollidingTransport {
+            connected: from_local,
+            accepted: to_local,
+        }));
+        simulation
+            .remote
+            .set_transport(Arc::new(CollidingTransport {
+                connected: from_remote,
+                accepted: to_remote,
+            }));
+        simulation.start();
+        assert!(simulation.run_until_both_in(State::Established, 20).await);
+        for _ in 0..5 {
+            simulation.step().await;
+        }
+
+        // BGP Identifierが大きいremoteから開始したTCP Connectionを、両方のピアが残す。
+        assert_eq!(simulation.local.state(), State::Established);
+        assert_eq!(simulation.remote.state(), State::Established);
+        assert!(!simulation.local.initiated_locally);
+        assert!(simulation.remote.initiated_locally);
+        assert!(simulation.local.colliding_connection.is_none());
+        assert!(simulation.remote.colliding_connection.is_none());
+    }
+
+    #[tokio::test]
+    async fn neighbor_connect_retry_is_jittered_after_name_resolution_failure() {
+        for _ in 0..20 {
+            let mut config: Config = "64512 127.0.0.1 64513 127.0.0.2 active connect-retry 60"
+                .parse()
+                .unwrap();
+            // IPv6アドレスしか得られないため、名前解決に失敗する。
+            config.remote_host = Some("::1".to_owned());
+            let mut peer = Peer::new(config, Arc::new(Mutex::new(LocRib::empty())));
+            let clock = Arc::new(MockClock::new());
+            peer.set_clock(clock.clone());
+            peer.start();
+            peer.next().await;
+            assert!(peer.connect_retry_timer.is_running());
+
+            clock.advance(Duration::from_secs(45) - Duration::from_millis(1));
+            assert!(!peer.connect_retry_timer.expired());
+            clock.advance(Duration::from_secs(15) + Duration::from_millis(1));
+            assert!(peer.connect_retry_timer.expired());
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_retry_timer_is_started_with_jitter() {
+        for _ in 0..20 {
+            let config = "64512 127.0.0.1 64513 127.0.0.2 active connect-retry 60"
+                .parse()
+                .unwrap();
+            let mut peer = Peer::new(config, Arc::new(Mutex::new(LocRib::empty())));
+            let clock = Arc::new(MockClock::new());
+            peer.set_clock(clock.clone());
+            let (local, _remote) = tokio::io::duplex(65536);
+            peer.set_transport(Arc::new(FlakyTransport {
+                failures: std::sync::Mutex::new(1),
+                stream: InMemoryTransport::new(local),
+            }));
+            peer.start();
+            peer.next().await;
+
+            // connect-retryの75%より前には接続し直さず、100%までには接続し直す。
+            clock.advance(Duration::from_secs(45) - Duration::from_millis(1));
+            peer.next().await;
+            assert!(peer.tcp_connection.is_none());
+            clock.advance(Duration::from_secs(15) + Duration::from_millis(1));
+            peer.next().await;
+            assert!(peer.tcp_connection.is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn peer_reconnects_after_session_is_reset_but_not_after_manual_stop() {
+        let config = "64512 127.0.0.1 64513 127.0.0.2 active connect-retry 5"
+            .parse()
+            .unwrap();
+        let mut harness = PeerHarness::new(config).await;
+        harness
+            .establish(64513.into(), "127.0.0.2".parse().unwrap())
+            .await;
+        harness.disconnect();
+        assert!(harness.run_until(State::Idle, 10).await);
+        assert!(harness.peer.connect_retry_timer.is_running());
+
+        harness.advance(Duration::from_secs(5));
+        harness.step(2).await;
+        assert_eq!(harness.state(), State::Connect);
+
+        harness.peer.stop(None);
+        harness.step(2).await;
+        assert_eq!(harness.state(), State::Idle);
+        assert!(!harness.peer.connect_retry_timer.is_running());
+    }
+
+    #[tokio::test]
+    async fn remote_as_any_peer_is_ebgp_or_ibgp_by_received_open() {
+        let config = "64512 127.0.0.1 any 127.0.0.2 active".parse().unwrap();
+        let mut harness = PeerHarness::new(config).await;
+        assert!(harness.peer.config.is_ebgp());
+        harness
+            .establish(64512.into(), "127.0.0.2".parse().unwrap())
+            .await;
+        assert!(!harness.peer.config.is_ebgp());
+        assert!(!harness.peer.update_group.lock().await.config().is_ebgp());
+
+        harness.disconnect();
+        assert!(harness.run_until(State::Idle, 10).await);
+        assert_eq!(harness.peer.config.remote_as, RemoteAs::Any);
+        assert!(harness.peer.update_group.lock().await.config().is_ebgp());
+    }
 
     #[tokio::test]
+    async fn remote_as_any_peer_exchanges_aigp_only_when_received_open_is_ibgp() {
+        let config: Config = "64512 127.0.0.1 any 127.0.0.2 active".parse().unwrap();
+        assert!(!config.aigp_session());
+        let mut harness = PeerHarness::new(config).await;
+        harness
+            .establish(64513.into(), "127.0.0.2".parse().unwrap())
+            .await;
+        assert!(!harness.peer.config.aigp_session());
+        assert!(!harness
+            .peer
+            .update_group
+            .lock()
+            .await
+            .config()
+            .aigp_session());
+
+        let config: Config = "64512 127.0.0.1 any 127.0.0.2 active".parse().unwrap();
+        let mut harness = PeerHarness::new(config).await;
+        harness
+            .establish(64512.into(), "127.0.0.2".parse().unwrap())
+            .await;
+        assert!(harness.peer.config.aigp_session());
+    }
+
+    #[tokio::test]
+    async fn four_octet_as_is_taken_from_capability_instead_of_as_trans() {
+        let open = |capabilities: &[Capability]| {
+            Message::Open(
+                OpenMessage::new(
+                    4200000000.into(),
+                    "127.0.0.2".parse().unwrap(),
+                    HoldTime::new(),
+                )
+                .with_capabilities(capabilities),
+            )
+        };
+        let config: Config = "64512 127.0.0.1 4200000000 127.0.0.2 active"
+            .parse()
+            .unwrap();
+
+        // 4オクテットAS Capabilityのない、AS_TRANSだけのOpenMessageは受け入れない。
+        let mut harness = PeerHarness::new(config.clone()).await;
+        harness.peer.start();
+        assert!(harness.run_until(State::OpenSent, 10).await);
+        harness.receive(open(&[])).await;
+        assert!(harness.run_until(State::Idle, 10).await);
+
+        let mut harness = PeerHarness::new(config).await;
+        harness.peer.start();
+        assert!(harness.run_until(State::OpenSent, 10).await);
+        let sent = harness.sent_messages().await;
+        assert!(matches!(&sent[0], Message::Open(open)
+            if open.capabilities().contains(&Capability::FourOctetAs(64512.into()))));
+        harness
+            .receive(open(&[Capability::FourOctetAs(4200000000.into())]))
+            .await;
+        assert!(harness.run_until(State::OpenConfirm, 10).await);
+        assert_eq!(harness.peer.remote_as(), Some(4200000000.into()));
+        assert!(harness.peer.negotiated.four_octet_as);
+        assert!(
+            harness
+                .peer
+                .update_group
+                .lock()
+                .await
+                .negotiated()
+                .four_octet_as
+        );
+    }
+
+    #[tokio::test]
+    async fn peer_resets_session_when_transport_delivers_broken_header() {
+        let config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let mut peer = Peer::new(config, Arc::new(Mutex::new(LocRib::empty())));
+        let (local, mut remote) = tokio::io::duplex(65536);
+        peer.set_transport(Arc::new(InMemoryTransport::new(local)));
+        peer.start();
+        for _ in 0..3 {
+            peer.next().await;
+        }
+        assert_eq!(peer.state(), State::OpenSent);
+
+        // Lengthが最小値の19より小さいHeaderを送り込む。
+        let mut broken_header = vec![0xff; 16];
+        broken_header.extend([0, 5, 1]);
+        remote.write_all(&broken_header).await.unwrap();
+        for _ in 0..3 {
+            peer.next().await;
+        }
+        assert_eq!(peer.state(), State::Idle);
+    }
+
+    #[tokio::test]
+    async fn open_with_unsupported_version_is_answered_with_notification() {
+        let config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let mut peer = Peer::new(config, Arc::new(Mutex::new(LocRib::empty())));
+        let (local, mut remote) = tokio::io::duplex(65536);
+        peer.set_transport(Arc::new(InMemoryTransport::new(local)));
+        peer.start();
+        for _ in 0..3 {
+            peer.next().await;
+        }
+        assert_eq!(peer.state(), State::OpenSent);
+
+        let mut open: BytesMut =
+            Message::new_open(64513.into(), "127.0.0.2".parse().unwrap(), HoldTime::new()).into();
+        open[19] = 3;
+        remote.write_all(&open).await.unwrap();
+        for _ in 0..3 {
+            peer.next().await;
+        }
+        assert_eq!(peer.state(), State::Idle);
+
+        // OpenMessageとNotificationMessageは別々に書き込まれることがある。
+        let mut received = BytesMut::new();
+        let mut sent: Vec<Message> = vec![];
+        while sent.len() < 2 {
+            tokio::time::timeout(Duration::from_secs(1), remote.read_buf(&mut received))
+                .await
+                .expect("NotificationMessageを受信できませんでした。")
+                .unwrap();
+            sent.extend(
+                std::iter::from_fn(|| BgpCodec::split_frame(&mut received))
+                    .map(|bytes| Message::try_from(bytes).unwrap()),
+            );
+        }
+        match &sent[..] {
+            [Message::Open(_), Message::Notification(notification)] => {
+                assert_eq!(notification.error_code(), ErrorCode::OpenMessageError);
+                assert_eq!(
+                    notification.error_subcode(),
+                    open_message_error::UNSUPPORTED_VERSION_NUMBER
+                );
+                assert_eq!(&notification.data()[..], &[0, 4]);
+            }
+            sent => panic!("NotificationMessageを送信していません。{:?}", sent),
+        }
+    }
+
+    #[tokio::test]
+    async fn message_capture_keeps_messages_before_session_reset() {
+        let config = "64512 127.0.0.1 64513 127.0.0.2 active message-capture-size 2"
+            .parse()
+            .unwrap();
+        let mut peer = Peer::new(config, Arc::new(Mutex::new(LocRib::empty())));
+        let (local, mut remote) = tokio::io::duplex(65536);
+        peer.set_transport(Arc::new(InMemoryTransport::new(local)));
+        peer.start();
+        for _ in 0..3 {
+            peer.next().await;
+        }
+        let mut open: BytesMut =
+            Message::new_open(64513.into(), "127.0.0.2".parse().unwrap(), HoldTime::new()).into();
+        open[19] = 3;
+        remote.write_all(&open).await.unwrap();
+        for _ in 0..3 {
+            peer.next().await;
+        }
+        assert_eq!(peer.state(), State::Idle);
+
+        // セッションがリセットされた後も、デコードできなかったOpenMessageと送信したNotificationMessageが残る。
+        let capture = peer.message_capture();
+        let capture = capture.lock().unwrap();
+        let messages: Vec<_> = capture.iter().collect();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].direction, Direction::Receive);
+        assert_eq!(&messages[0].bytes[..], &open[..]);
+        assert_eq!(messages[1].direction, Direction::Send);
+        assert!(matches!(
+            Message::try_from(messages[1].bytes.clone()),
+            Ok(Message::Notification(_))
+        ));
+    }
+
+    async fn establish_llgr_session(harness: &mut PeerHarness, remote_stale_time: u32) {
+        harness.peer.start();
+        assert!(harness.run_until(State::OpenSent, 10).await);
+        let sent = harness.sent_messages().await;
+        match &sent[..] {
+            [Message::Open(open)] => assert_eq!(
+                open.capabilities(),
+                vec![
+                    Capability::Multiprotocol(AddressFamily::IPV4_UNICAST),
+                    Capability::FourOctetAs(64512.into()),
+                    Capability::LongLivedGracefulRestart(vec![LlgrFamily::ipv4_unicast(3600)])
+                ]
+            ),
+            sent => panic!("OpenMessageを送信していません。{:?}", sent),
+        }
+        let open = OpenMessage::new(64513.into(), "127.0.0.2".parse().unwrap(), HoldTime::new())
+            .with_capabilities(&[Capability::LongLivedGracefulRestart(vec![
+                LlgrFamily::ipv4_unicast(remote_stale_time),
+            ])]);
+        harness.receive(Message::Open(open)).await;
+        assert!(harness.run_until(State::OpenConfirm, 10).await);
+        harness.receive(Message::new_keepalive()).await;
+        assert!(harness.run_until(State::Established, 10).await);
+
+        let update = UpdateMessage::new(
+            vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::AsSequence(vec![64513.into()])),
+                PathAttribute::NextHop("127.0.0.2".parse().unwrap()),
+            ],
+            vec!["10.100.220.0/24".parse().unwrap()],
+            vec![],
+        );
+        harness.receive(Message::Update(update)).await;
+        harness.step(5).await;
+    }
+
+    #[tokio::test]
+    async fn llgr_retains_routes_as_stale_until_negotiated_stale_time_passes() {
+        let config = "64512 127.0.0.1 64513 127.0.0.2 active llgr-stale-time 3600"
+            .parse()
+            .unwrap();
+        let mut harness = PeerHarness::new(config).await;
+        // 対向機器の広告した値より、自分の設定値のほうが短い。
+        establish_llgr_session(&mut harness, 86400).await;
+
+        harness.disconnect();
+        assert!(harness.run_until(State::Idle, 10).await);
+        let loc_rib = harness.loc_rib();
+        assert!(loc_rib.lock().await.iter().all(|r| r.is_llgr_stale()));
+        assert_eq!(loc_rib.lock().await.len(), 1);
+
+        harness.advance(Duration::from_secs(3599));
+        harness.step(2).await;
+        assert_eq!(loc_rib.lock().await.len(), 1);
+        harness.advance(Duration::from_secs(1));
+        harness.step(2).await;
+        assert_eq!(loc_rib.lock().await.len(), 0);
+        assert!(harness.adj_rib_in().lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn routes_are_flushed_immediately_after_notification_even_with_llgr() {
+        let config = "64512 127.0.0.1 64513 127.0.0.2 active llgr-stale-time 3600"
+            .parse()
+            .unwrap();
+        let mut harness = PeerHarness::new(config).await;
+        establish_llgr_session(&mut harness, 3600).await;
+
+        harness
+            .receive(Message::new_notification(
+                ErrorCode::Cease,
+                cease::ADMINISTRATIVE_RESET,
+                vec![],
+            ))
+            .await;
+        assert!(harness.run_until(State::Idle, 10).await);
+        assert_eq!(harness.loc_rib().lock().await.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn routes_of_address_family_not_negotiated_are_not_installed() {
+        let config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let mut harness = PeerHarness::new(config).await;
+        harness.peer.start();
+        assert!(harness.run_until(State::OpenSent, 10).await);
+        let open = OpenMessage::new(64513.into(), "127.0.0.2".parse().unwrap(), HoldTime::new())
+            .with_capabilities(&[Capability::Multiprotocol(AddressFamily::IPV6_UNICAST)]);
+        harness.receive(Message::Open(open)).await;
+        assert!(harness.run_until(State::OpenConfirm, 10).await);
+        harness.receive(Message::new_keepalive()).await;
+        assert!(harness.run_until(State::Established, 10).await);
+        assert!(harness.peer.address_families().is_empty());
+
+        let update = UpdateMessage::new(
+            vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::AsSequence(vec![64513.into()])),
+                PathAttribute::NextHop("127.0.0.2".parse().unwrap()),
+            ],
+            vec!["10.100.220.0/24".parse().unwrap()],
+            vec![],
+        );
+        harness.receive(Message::Update(update)).await;
+        harness.step(5).await;
+        assert!(harness.adj_rib_in().lock().await.is_empty());
+        assert_eq!(harness.state(), State::Established);
+    }
+
+    #[tokio::test]
+    async fn peer_without_multiprotocol_capability_exchanges_ipv4_unicast() {
+        let config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let mut harness = PeerHarness::new(config).await;
+        harness
+            .establish(64513.into(), "127.0.0.2".parse().unwrap())
+            .await;
+        assert_eq!(
+            harness.peer.address_families(),
+            &[AddressFamily::IPV4_UNICAST]
+        );
+    }
+
+    #[tokio::test]
+    async fn max_prefix_warns_at_threshold_and_resets_session_over_limit() {
+        let config = "64512 127.0.0.1 64513 127.0.0.2 active max-prefix 2 max-prefix-warning 50"
+            .parse()
+            .unwrap();
+        let mut harness = PeerHarness::new(config).await;
+        harness
+            .establish(64513.into(), "127.0.0.2".parse().unwrap())
+            .await;
+        let update = |network: &str| {
+            Message::Update(UpdateMessage::new(
+                vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::AsSequence(vec![64513.into()])),
+                    PathAttribute::NextHop("127.0.0.2".parse().unwrap()),
+                ],
+                vec![network.parse().unwrap()],
+                vec![],
+            ))
+        };
+        let statistics = harness.peer.statistics();
+
+        harness.receive(update("10.100.220.0/24")).await;
+        harness.step(3).await;
+        assert!(statistics.lock().await.max_prefix_warning);
+        harness.receive(update("10.100.230.0/24")).await;
+        harness.step(3).await;
+        assert_eq!(harness.state(), State::Established);
+
+        harness.receive(update("10.100.240.0/24")).await;
+        assert!(harness.run_until(State::Idle, 10).await);
+        let notification = statistics
+            .lock()
+            .await
+            .last_notification_sent
+            .clone()
+            .unwrap();
+        assert_eq!(notification.error_code(), ErrorCode::Cease);
+        assert_eq!(
+            notification.error_subcode(),
+            cease::MAXIMUM_NUMBER_OF_PREFIXES_REACHED
+        );
+        assert_eq!(&notification.data()[..], &[0, 1, 1, 0, 0, 0, 2]);
+        assert!(harness.adj_rib_in().lock().await.is_empty());
+        assert!(!statistics.lock().await.max_prefix_warning);
+    }
+
+    #[tokio::test]
+    async fn unexpected_open_message_in_established_is_fsm_error() {
+        let local_config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let remote_config = "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
+        let mut simulation = Simulation::new(local_config, remote_config);
+        simulation.start();
+        assert!(simulation.run_until_both_in(State::Established, 10).await);
+
+        let open = OpenMessage::new(64513.into(), "127.0.0.2".parse().unwrap(), HoldTime::new());
+        simulation.local.event_queue.enqueue(Event::BgpOpen(open));
+        for _ in 0..5 {
+            simulation.local.next().await;
+        }
+
+        assert_eq!(simulation.local.state(), State::Idle);
+        let statistics = simulation.local.statistics();
+        let notification = statistics
+            .lock()
+            .await
+            .last_notification_sent
+            .clone()
+            .unwrap();
+        assert_eq!(
+            notification.error_code(),
+            ErrorCode::FiniteStateMachineError
+        );
+        assert_eq!(
+            notification.error_subcode(),
+            finite_state_machine_error::RECEIVE_UNEXPECTED_MESSAGE_IN_ESTABLISHED_STATE
+        );
+        assert_eq!(&notification.data()[..], &[u8::from(MessageType::Open)]);
+    }
+
+    #[tokio::test]
+    async fn end_of_rib_is_sent_once_after_initial_routes() {
+        let config = "64512 127.0.0.1 64513 127.0.0.2 active mrai 0"
+            .parse()
+            .unwrap();
+        let mut harness = PeerHarness::new(config).await;
+        let route = |network: &str| {
+            RibEntry::for_test(network)
+                .with_as_path(&[])
+                .with_next_hop("127.0.0.1")
+        };
+        let loc_rib = harness.loc_rib();
+        loc_rib
+            .lock()
+            .await
+            .install_from_adj_rib_in(&mut AdjRibIn::from(vec![route("10.100.220.0/24")]));
+        harness
+            .establish(64513.into(), "127.0.0.2".parse().unwrap())
+            .await;
+        harness.step(5).await;
+        let updates = |sent: Vec<Message>| -> Vec<UpdateMessage> {
+            sent.into_iter()
+                .filter_map(|m| match m {
+                    Message::Update(update) => Some(update),
+                    _ => None,
+                })
+                .collect()
+        };
+        let sent = updates(harness.sent_messages().await);
+        assert_eq!(sent.len(), 2);
+        assert!(!sent[0].is_end_of_rib());
+        assert!(sent[1].is_end_of_rib());
+
+        loc_rib
+            .lock()
+            .await
+            .install_from_adj_rib_in(&mut AdjRibIn::from(vec![route("10.100.230.0/24")]));
+        harness.inject(Event::LocRibChanged);
+        harness.step(5).await;
+        let sent = updates(harness.sent_messages().await);
+        assert_eq!(sent.len(), 1);
+        assert!(!sent[0].is_end_of_rib());
+    }
+
+    #[tokio::test]
+    async fn routes_removed_from_loc_rib_are_withdrawn() {
+        let config = "64512 127.0.0.1 64513 127.0.0.2 active mrai 0"
+            .parse()
+            .unwrap();
+        let mut harness = PeerHarness::new(config).await;
+        harness
+            .establish(64513.into(), "127.0.0.2".parse().unwrap())
+            .await;
+        let route = |network: &str| {
+            RibEntry::for_test(network)
+                .with_as_path(&[])
+                .with_next_hop("127.0.0.1")
+        };
+        let learned = AdjRibIn::from(vec![route("10.100.220.0/24"), route("10.100.230.0/24")]);
+        let loc_rib = harness.loc_rib();
+        loc_rib
+            .lock()
+            .await
+            .install_from_adj_rib_in(&mut learned.clone());
+        harness.inject(Event::LocRibChanged);
+        harness.step(5).await;
+        harness.sent_messages().await;
+
+        loc_rib
+            .lock()
+            .await
+            .remove_routes_learned_from(&AdjRibIn::from(vec![route("10.100.230.0/24")]));
+        harness.inject(Event::LocRibChanged);
+        harness.step(5).await;
+
+        let sent = harness.sent_messages().await;
+        let withdrawn: Vec<Ipv4Network> = sent
+            .iter()
+            .filter_map(|m| match m {
+                Message::Update(update) => Some(update.withdrawn_routes().clone()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        assert_eq!(withdrawn, vec!["10.100.230.0/24".parse().unwrap()]);
+        // 変わっていないルートは広告し直さない。
+        assert!(!sent.iter().any(|m| matches!(m, Message::Update(update)
+            if !update.network_layer_reachability_information().is_empty())));
+    }
+
+    #[tokio::test]
+    async fn updates_beyond_rate_limit_wait_until_tokens_are_refilled() {
+        let config = "64512 127.0.0.1 64513 127.0.0.2 active update-rate-limit 1"
+            .parse()
+            .unwrap();
+        let mut harness = PeerHarness::new(config).await;
+        harness
+            .establish(64513.into(), "127.0.0.2".parse().unwrap())
+            .await;
+        for network in ["10.100.220.0/24", "10.100.230.0/24", "10.100.240.0/24"] {
+            let update = UpdateMessage::new(
+                vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::AsSequence(vec![64513.into()])),
+                    PathAttribute::NextHop("127.0.0.2".parse().unwrap()),
+                ],
+                vec![network.parse().unwrap()],
+                vec![],
+            );
+            harness.receive(Message::Update(update)).await;
+        }
+
+        harness.step(10).await;
+        assert_eq!(harness.adj_rib_in().lock().await.len(), 1);
+
+        harness.advance(Duration::from_secs(1));
+        harness.step(10).await;
+        assert_eq!(harness.adj_rib_in().lock().await.len(), 2);
+        assert_eq!(harness.state(), State::Established);
+    }
+
+    #[tokio::test]
+    async fn import_policy_is_reapplied_without_session_reset() {
+        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active soft-reconfiguration-inbound"
+            .parse()
+            .unwrap();
+        let mut harness = PeerHarness::new(config.clone()).await;
+        harness
+            .establish(64513.into(), "127.0.0.2".parse().unwrap())
+            .await;
+        let update = |community: &str, network: &str| {
+            UpdateMessage::new(
+                vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::AsSequence(vec![64513.into()])),
+                    PathAttribute::NextHop("127.0.0.2".parse().unwrap()),
+                    PathAttribute::Communities(vec![community.parse().unwrap()]),
+                ],
+                vec![network.parse().unwrap()],
+                vec![],
+            )
+        };
+        harness
+            .receive(Message::Update(update("64512:100", "10.100.220.0/24")))
+            .await;
+        harness
+            .receive(Message::Update(update("64512:666", "10.100.230.0/24")))
+            .await;
+        harness.step(10).await;
+        assert_eq!(harness.loc_rib().lock().await.len(), 2);
+
+        let mut filtered = config.clone();
+        filtered.import_policy.push(PolicyAction::MatchCommunity(
+            "64512:666".parse().unwrap(),
+            Box::new(PolicyAction::DenyAsPathLongerThan(0)),
+        ));
+        harness.peer.handle().update_config(filtered);
+        harness.step(10).await;
+        assert_eq!(harness.state(), State::Established);
+        assert_eq!(harness.adj_rib_in().lock().await.len(), 1);
+        let network = "10.100.230.0/24".parse().unwrap();
+        assert!(harness.loc_rib().lock().await.get(&network).is_none());
+
+        harness.peer.handle().update_config(config);
+        harness.step(10).await;
+        assert!(harness.loc_rib().lock().await.get(&network).is_some());
+    }
+
+    #[tokio::test]
+    async fn route_collector_receives_routes_without_sending_updates() {
+        let local_config = "64512 127.0.0.1 64513 127.0.0.2 active route-collector"
+            .parse()
+            .unwrap();
+        let remote_config = "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
+        let mut simulation = Simulation::new(local_config, remote_config);
+        // route collectorのLocRibにルートがあっても、対向機器には広告しない。
+        simulation
+            .local
+            .loc_rib
+            .lock()
+            .await
+            .install_from_adj_rib_in(&mut AdjRibIn::from(vec![RibEntry::for_test(
+                "10.100.220.0/24",
+            )
+            .with_as_path(&[])
+            .with_next_hop("127.0.0.1")]));
+        simulation.start();
+        assert!(simulation.run_until_both_in(State::Established, 10).await);
+        for _ in 0..10 {
+            simulation.step().await;
+        }
+
+        assert!(simulation
+            .local
+            .update_group
+            .lock()
+            .await
+            .updates()
+            .is_empty());
+        // 送信するUpdateMessageはEnd-of-RIBだけである。
+        let statistics = simulation.local.statistics();
+        assert_eq!(statistics.lock().await.messages_sent.update, 1);
+        assert_eq!(simulation.remote.adj_rib_in.lock().await.len(), 0);
+        assert!(
+            simulation
+                .remote
+                .statistics()
+                .lock()
+                .await
+                .end_of_rib_received
+        );
+    }
+
+    #[tokio::test]
+    async fn routes_are_advertised_after_update_delay_ends() {
+        let local_config = "64512 127.0.0.1 64513 127.0.0.2 active mrai 0"
+            .parse()
+            .unwrap();
+        let remote_config = "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
+        let mut simulation = Simulation::new(local_config, remote_config);
+        {
+            let mut loc_rib = simulation.local.loc_rib.lock().await;
+            loc_rib.set_read_only(true);
+            loc_rib.originate(
+                "10.100.220.0/24".parse().unwrap(),
+                "127.0.0.1".parse().unwrap(),
+                vec![],
+            );
+        }
+        simulation.start();
+        assert!(simulation.run_until_both_in(State::Established, 10).await);
+        for _ in 0..10 {
+            simulation.step().await;
+        }
+        let statistics = simulation.local.statistics();
+        assert_eq!(statistics.lock().await.messages_sent.update, 0);
+        assert_eq!(simulation.remote.adj_rib_in.lock().await.len(), 0);
+
+        simulation.local.loc_rib.lock().await.set_read_only(false);
+        simulation.local.handle().notify_loc_rib_changed();
+        for _ in 0..10 {
+            simulation.step().await;
+        }
+        assert_eq!(simulation.remote.adj_rib_in.lock().await.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
     async fn peer_can_transition_to_connect_state() {
         // 自分のAS番号 自分のIP 対向側のAS番号 対向側のAS番号動作モード active
-        let config: Config = "64512 127.0.0.1 65413 127.0.0.2 active".parse().unwrap();
+        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
         let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
         let mut peer = Peer::new(config, Arc::clone(&loc_rib));
         peer.start();
 
         // 別スレッドでもPeerを立ち上げて対向機器を模擬する
         tokio::spawn(async move {
-            let remote_config = "64513 127.0.0.2 65412 127.0.0.1 passive".parse().unwrap();
+            let remote_config = "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
             let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
             let mut remote_peer = Peer::new(remote_config, Arc::clone(&remote_loc_rib));
             remote_peer.start();
@@ -158,37 +2596,50 @@ mod tests {
         assert_eq!(peer.state, State::Connect);
     }
 
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
     async fn peer_can_transition_to_open_sent_state() {
-        let config: Config = "64512 127.0.0.1 65413 127.0.0.2 active".parse().unwrap();
+        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
         let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
         let mut peer = Peer::new(config, Arc::clone(&loc_rib));
         peer.start();
 
+        // TCP Connectionの確立はnextを止めずに進むので、状態が変わるまでnextを繰り返す。
         tokio::spawn(async move {
-            let remote_config = "64513 127.0.0.2 65412 127.0.0.1 passive".parse().unwrap();
+            let remote_config = "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
             let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
             let mut remote_peer = Peer::new(remote_config, Arc::clone(&remote_loc_rib));
             remote_peer.start();
-            remote_peer.next().await;
-            remote_peer.next().await;
+            let max_step = 50;
+            for _ in 0..max_step {
+                remote_peer.next().await;
+                if remote_peer.state == State::OpenSent {
+                    break;
+                };
+                tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+            }
         });
 
         tokio::time::sleep(Duration::from_secs(1)).await;
-        peer.next().await;
-        peer.next().await;
+        let max_step = 50;
+        for _ in 0..max_step {
+            peer.next().await;
+            if peer.state == State::OpenSent {
+                break;
+            };
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
         assert_eq!(peer.state, State::OpenSent);
     }
 
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
     async fn peer_can_transition_to_open_confirm_state() {
-        let config: Config = "64512 127.0.0.1 65413 127.0.0.2 active".parse().unwrap();
+        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
         let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
         let mut peer = Peer::new(config, Arc::clone(&loc_rib));
         peer.start();
 
         tokio::spawn(async move {
-            let remote_config = "64513 127.0.0.2 65412 127.0.0.1 passive".parse().unwrap();
+            let remote_config = "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
             let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
             let mut remote_peer = Peer::new(remote_config, Arc::clone(&remote_loc_rib));
             remote_peer.start();
@@ -214,9 +2665,9 @@ mod tests {
         assert_eq!(peer.state, State::OpenConfirm);
     }
 
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
     async fn peer_can_transition_to_established_state() {
-        let config: Config = "64512 127.0.0.1 65413 127.0.0.2 active".parse().unwrap();
+        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
         let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
         let mut peer = Peer::new(config, Arc::clone(&loc_rib));
         peer.start();
@@ -224,7 +2675,7 @@ mod tests {
         // 別スレッドでPeer構造体を実行しています。
         // これはネットワーク上で離れた別のマシンを模擬しています。
         tokio::spawn(async move {
-            let remote_config = "64513 127.0.0.2 65412 127.0.0.1 passive".parse().unwrap();
+            let remote_config = "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
             let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
             let mut remote_peer = Peer::new(remote_config, Arc::clone(&remote_loc_rib));
             remote_peer.start();
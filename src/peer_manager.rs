@@ -0,0 +1,537 @@
+use crate::aspa::AspaTable;
+use crate::config::Config;
+use crate::control::{Neighbor, NeighborList};
+use crate::hook::MessageHook;
+use crate::log_warning;
+use crate::otel::OtlpExporter;
+use crate::peer::Peer;
+use crate::policy_file::PolicySet;
+use crate::routing::LocRib;
+use crate::trace::Tracer;
+use crate::update_group::UpdateGroups;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+/// 削除するピアが、Cease NotificationMessageを送ってルートを削除し終えるのを待つ時間。
+/// 過ぎた場合はPeerを動かしているタスクを中断する。
+const REMOVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// LocRibが変わったかを確認する間隔。
+const LOC_RIB_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// ピアを1つずつタスクで動かし、設定の差分に従ってピアを追加、削除、変更する。
+pub struct PeerManager {
+    loc_rib: Arc<Mutex<LocRib>>,
+    update_groups: UpdateGroups,
+    aspa_table: Arc<AspaTable>,
+    /// 作成するすべてのPeerに追加するフック。
+    message_hooks: Vec<Arc<dyn MessageHook>>,
+    /// 作成するすべてのPeerで共有するトレース。
+    tracer: Option<Tracer>,
+    /// 作成するすべてのPeerで共有する、Spanを送るExporter。
+    otlp_exporter: Option<OtlpExporter>,
+    peers: Vec<ManagedPeer>,
+    /// 最後にapplyした設定。discoverのテンプレートも含む。
+    configs: Vec<Config>,
+    /// テンプレートのホスト名ごとに、最後に名前解決したIPv4アドレス。
+    discovered: HashMap<String, Vec<Ipv4Addr>>,
+    /// 設定のexport-policy、import-policyで参照する名前付きのPolicy。
+    policies: PolicySet,
+    /// コントロールAPIなどと共有する、動作中のピアの一覧。
+    neighbors: NeighborList,
+}
+
+/// 動作中のPeerと、それを動かしているタスク。
+struct ManagedPeer {
+    neighbor: Neighbor,
+    task: JoinHandle<()>,
+}
+
+/// apply-configで反映した変更の一覧。ピアは対向機器のIPアドレスで表す。
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ConfigDiff {
+    pub added: Vec<Ipv4Addr>,
+    pub removed: Vec<Ipv4Addr>,
+    /// セッションを張り直さずに設定を反映したピア。
+    pub updated: Vec<Ipv4Addr>,
+    /// 設定の変更を反映するためにセッションを張り直したピア。
+    pub reset: Vec<Ipv4Addr>,
+}
+
+impl fmt::Display for ConfigDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut changed = false;
+        for (name, neighbors) in [
+            ("added", &self.added),
+            ("removed", &self.removed),
+            ("updated", &self.updated),
+            ("reset", &self.reset),
+        ] {
+            for neighbor in neighbors {
+                writeln!(f, "{} {}", name, neighbor)?;
+                changed = true;
+            }
+        }
+        if !changed {
+            writeln!(f, "no changes")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for PeerManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PeerManager")
+            .field("neighbors", &self.neighbors)
+            .finish()
+    }
+}
+
+impl PeerManager {
+    pub fn new(loc_rib: Arc<Mutex<LocRib>>, aspa_table: Arc<AspaTable>) -> Self {
+        Self {
+            loc_rib,
+            update_groups: UpdateGroups::new(),
+            aspa_table,
+            message_hooks: vec![],
+            tracer: None,
+            otlp_exporter: None,
+            peers: vec![],
+            configs: vec![],
+            discovered: HashMap::new(),
+            policies: PolicySet::new(),
+            neighbors: NeighborList::default(),
+        }
+    }
+
+    /// これ以降に作成するPeerに、hookを追加する。
+    pub fn add_message_hook(&mut self, hook: Arc<dyn MessageHook>) {
+        self.message_hooks.push(hook);
+    }
+
+    /// これ以降に作成するPeerに、Event、状態遷移、RIBの変更をtracerに書き出させる。
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// これ以降に作成するPeerに、UpdateMessageの処理とFSMの状態遷移のSpanをexporterで送らせる。
+    pub fn set_otlp_exporter(&mut self, exporter: OtlpExporter) {
+        self.otlp_exporter = Some(exporter);
+    }
+
+    /// 動作中のピアの一覧。ピアを追加、削除すると、返した一覧にも反映される。
+    pub fn neighbors(&self) -> NeighborList {
+        self.neighbors.clone()
+    }
+
+    /// configのPeerを作成して開始し、専用のタスクで動かす。
+    pub fn spawn(&mut self, config: Config) {
+        let mut peer = Peer::new(config, Arc::clone(&self.loc_rib));
+        for hook in &self.message_hooks {
+            peer.add_message_hook(Arc::clone(hook));
+        }
+        peer.set_aspa_table(Arc::clone(&self.aspa_table));
+        if let Some(tracer) = &self.tracer {
+            peer.set_tracer(tracer.clone());
+        }
+        if let Some(exporter) = &self.otlp_exporter {
+            peer.set_otlp_exporter(exporter.clone());
+        }
+        peer.join_update_group(&self.update_groups);
+        peer.start();
+        let neighbor = Neighbor::from(&peer);
+        let task = tokio::spawn(async move {
+            while !peer.is_removed() {
+                peer.next().await;
+            }
+        });
+        self.peers.push(ManagedPeer { neighbor, task });
+        self.publish_neighbors();
+    }
+
+    /// 動作中のピアをcandidatesの設定に合わせる。ピアは対向機器のIPアドレスで対応付ける。
+    /// export policyなどの変更はAdjRibOutを作り直して広告し直すだけで反映し、
+    /// 変更のないピアと合わせて、セッションは張り直さない。
+    /// テンプレートからは、最後に名前解決したアドレスごとにピアを作成する。
+    pub async fn apply(&mut self, candidates: Vec<Config>) -> ConfigDiff {
+        self.configs = candidates;
+        let candidates = self.candidates();
+        self.reconcile(candidates).await
+    }
+
+    /// 名前付きのPolicyをpoliciesに置き換え、参照しているピアに反映する。
+    /// export policyはAdjRibOutを作り直して反映し、import policyは
+    /// soft-reconfiguration-inboundを設定したピアであればセッションを張り直さずに反映する。
+    pub async fn set_policies(&mut self, policies: PolicySet) -> ConfigDiff {
+        self.policies = policies;
+        let candidates = self.candidates();
+        self.reconcile(candidates).await
+    }
+
+    /// 設定で参照しているが、policiesにないPolicyの名前。
+    pub fn missing_policies(&self, policies: &PolicySet) -> Vec<String> {
+        let mut missing: Vec<String> = self
+            .configs
+            .iter()
+            .flat_map(|c| c.policy_names())
+            .filter(|name| policies.get(name).is_none())
+            .cloned()
+            .collect();
+        missing.sort();
+        missing.dedup();
+        missing
+    }
+
+    /// テンプレートのホスト名を名前解決した結果を反映し、作成するピアを増減する。
+    /// 前回と同じ結果であれば何もしない。
+    pub async fn apply_discovered(
+        &mut self,
+        discovered: HashMap<String, Vec<Ipv4Addr>>,
+    ) -> ConfigDiff {
+        if discovered == self.discovered {
+            return ConfigDiff::default();
+        }
+        self.discovered = discovered;
+        let candidates = self.candidates();
+        self.reconcile(candidates).await
+    }
+
+    /// テンプレートのホスト名と、最後に名前解決したIPv4アドレス。
+    pub fn discovered(&self) -> &HashMap<String, Vec<Ipv4Addr>> {
+        &self.discovered
+    }
+
+    /// 名前解決するテンプレートのホスト名。
+    pub fn discovery_hosts(&self) -> Vec<String> {
+        let mut hosts: Vec<String> = self
+            .configs
+            .iter()
+            .flat_map(|c| c.discover.iter().cloned())
+            .collect();
+        hosts.sort();
+        hosts.dedup();
+        hosts
+    }
+
+    /// テンプレートのうち、最も短いdiscovery-interval。テンプレートがなければNone。
+    pub fn discovery_interval(&self) -> Option<Duration> {
+        self.configs
+            .iter()
+            .filter(|c| c.is_template())
+            .map(|c| Duration::from_secs(c.discovery_interval))
+            .min()
+    }
+
+    /// 動作させるピアの設定。テンプレートを展開し、名前付きのPolicyを各ピアのPolicyに追加する。
+    fn candidates(&self) -> Vec<Config> {
+        let mut candidates = self.expand_templates();
+        for config in &mut candidates {
+            for (name, policy) in [
+                (&config.export_policy_name, &mut config.export_policy),
+                (&config.import_policy_name, &mut config.import_policy),
+            ] {
+                let name = match name {
+                    Some(name) => name,
+                    None => continue,
+                };
+                match self.policies.get(name) {
+                    Some(named) => policy.extend(named),
+                    None => log_warning!(
+                        "{}のピアが参照しているポリシー{}が定義されていません。",
+                        config.remote_ip,
+                        name
+                    ),
+                }
+            }
+        }
+        candidates
+    }
+
+    /// テンプレートを、名前解決したアドレスごとのピアの設定に置き換える。
+    /// 同じアドレスのピアがすでにあれば、先に書かれた設定を使う。
+    fn expand_templates(&self) -> Vec<Config> {
+        let mut expanded: Vec<Config> = self
+            .configs
+            .iter()
+            .filter(|c| !c.is_template())
+            .cloned()
+            .collect();
+        for template in self.configs.iter().filter(|c| c.is_template()) {
+            let addresses = template
+                .discover
+                .iter()
+                .filter_map(|host| self.discovered.get(host))
+                .flatten();
+            for address in addresses {
+                if !expanded.iter().any(|c| c.remote_ip == *address) {
+                    expanded.push(template.instantiate(*address));
+                }
+            }
+        }
+        expanded
+    }
+
+    async fn reconcile(&mut self, candidates: Vec<Config>) -> ConfigDiff {
+        let mut diff = ConfigDiff::default();
+        let mut removing = vec![];
+        let mut spawning = vec![];
+        for mut managed in std::mem::take(&mut self.peers) {
+            let running = &managed.neighbor.config;
+            let remote_ip = running.remote_ip;
+            match candidates.iter().find(|c| c.is_same_peer(running)) {
+                None => {
+                    diff.removed.push(remote_ip);
+                    removing.push(managed);
+                }
+                Some(candidate) if candidate == running => self.peers.push(managed),
+                Some(candidate) if running.requires_session_reset(candidate) => {
+                    diff.reset.push(remote_ip);
+                    removing.push(managed);
+                    spawning.push(candidate.clone());
+                }
+                Some(candidate) => {
+                    managed.neighbor.handle.update_config(candidate.clone());
+                    managed.neighbor.config = candidate.clone();
+                    diff.updated.push(remote_ip);
+                    self.peers.push(managed);
+                }
+            }
+        }
+        for candidate in &candidates {
+            let running = self
+                .peers
+                .iter()
+                .map(|p| &p.neighbor)
+                .chain(removing.iter().map(|p| &p.neighbor))
+                .any(|n| n.config.is_same_peer(candidate));
+            if !running && !spawning.iter().any(|c| c.is_same_peer(candidate)) {
+                diff.added.push(candidate.remote_ip);
+                spawning.push(candidate.clone());
+            }
+        }
+        self.publish_neighbors();
+
+        // 削除したピアから学習したルートをLocRibから取り除いてから、残りのピアに広告し直させる。
+        let removed_any = !removing.is_empty();
+        for mut managed in removing {
+            managed.neighbor.handle.remove();
+            if tokio::time::timeout(REMOVE_TIMEOUT, &mut managed.task)
+                .await
+                .is_err()
+            {
+                log_warning!(
+                    "{}のピアが終了しないため、タスクを中断しました。",
+                    managed.neighbor.config.remote_ip
+                );
+                managed.task.abort();
+            }
+        }
+        if removed_any {
+            for managed in &self.peers {
+                managed.neighbor.handle.notify_loc_rib_changed();
+            }
+        }
+        for config in spawning {
+            self.spawn(config);
+        }
+        diff
+    }
+
+    fn publish_neighbors(&self) {
+        self.neighbors
+            .replace(self.peers.iter().map(|p| p.neighbor.clone()).collect());
+    }
+}
+
+/// LocRibのversionが変わるたびに、neighborsのすべてのピアにAdjRibOutを作り直して広告させる。
+/// あるピアから受信したルートや、セッションが切れたピアのルートの削除で選び直した
+/// ベストパスを、ほかのピアにも広告するために使う。
+pub async fn propagate_loc_rib_changes(loc_rib: Arc<Mutex<LocRib>>, neighbors: NeighborList) {
+    let mut version = loc_rib.lock().await.version();
+    loop {
+        tokio::time::sleep(LOC_RIB_CHECK_INTERVAL).await;
+        let current = loc_rib.lock().await.version();
+        if current == version {
+            continue;
+        }
+        version = current;
+        for neighbor in neighbors.snapshot() {
+            neighbor.handle.notify_loc_rib_changed();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::PeerHarness;
+    use crate::packets::message::Message;
+    use crate::policy::{Policy, PolicyAction};
+
+    fn config(s: &str) -> Config {
+        s.parse().unwrap()
+    }
+
+    // 対向機器は接続してこないが、接続を待っているピアもすぐに削除できる。
+    // REMOVE_TIMEOUTまで待たされていないことを確かめるため、時間は止めない。
+    #[tokio::test]
+    async fn apply_starts_removes_and_updates_peers_by_difference() {
+        let started = tokio::time::Instant::now();
+        let loc_rib = Arc::new(Mutex::new(LocRib::empty()));
+        let mut manager = PeerManager::new(loc_rib, Arc::new(AspaTable::new()));
+        let a = "64512 127.0.0.1 64513 127.0.0.2 passive";
+        let b = "64512 127.0.0.1 64514 127.0.0.3 passive";
+        let c = "64512 127.0.0.1 64515 127.0.0.4 passive";
+        manager.spawn(config(a));
+        manager.spawn(config(b));
+        let neighbors = manager.neighbors();
+
+        let diff = manager
+            .apply(vec![
+                config(&format!("{} export set as-path prepend 64512", a)),
+                config(&format!("{} hold-time 30", b)),
+                config(c),
+            ])
+            .await;
+        assert_eq!(
+            diff,
+            ConfigDiff {
+                added: vec!["127.0.0.4".parse().unwrap()],
+                removed: vec![],
+                updated: vec!["127.0.0.2".parse().unwrap()],
+                reset: vec!["127.0.0.3".parse().unwrap()],
+            }
+        );
+        assert_eq!(neighbors.snapshot().len(), 3);
+
+        let diff = manager.apply(vec![config(c)]).await;
+        assert_eq!(diff.removed.len(), 2);
+        assert_eq!(
+            neighbors
+                .snapshot()
+                .iter()
+                .map(|n| n.config.remote_ip.to_string())
+                .collect::<Vec<_>>(),
+            vec!["127.0.0.4"]
+        );
+        assert_eq!(
+            manager.apply(vec![config(c)]).await.to_string(),
+            "no changes\n"
+        );
+        assert!(started.elapsed() < REMOVE_TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn named_policies_are_applied_to_referencing_peers() {
+        let loc_rib = Arc::new(Mutex::new(LocRib::empty()));
+        let mut manager = PeerManager::new(loc_rib, Arc::new(AspaTable::new()));
+        let policies = |text: &str| text.parse::<PolicySet>().unwrap();
+        manager
+            .set_policies(policies("policy out\n  set as-path prepend 64512\n"))
+            .await;
+        manager
+            .apply(vec![
+                config(
+                    "64512 127.0.0.1 64513 127.0.0.2 passive policy-file /etc/howbgp/policy \
+                     export set local-pref 200 export-policy out",
+                ),
+                config("64512 127.0.0.1 64514 127.0.0.3 passive"),
+            ])
+            .await;
+        let export_policy = |manager: &PeerManager| {
+            manager.neighbors().snapshot()[0]
+                .config
+                .export_policy
+                .clone()
+        };
+        let mut expected = Policy::new();
+        expected.push(PolicyAction::SetLocalPref(200));
+        expected.push(PolicyAction::PrependAsPath(vec![64512.into()]));
+        assert_eq!(export_policy(&manager), expected);
+
+        let changed = policies("policy out\n  set as-path prepend 64512 64512\n");
+        assert!(manager.missing_policies(&changed).is_empty());
+        assert_eq!(
+            manager.missing_policies(&policies("policy in\n")),
+            vec!["out"]
+        );
+        let diff = manager.set_policies(changed).await;
+        assert_eq!(diff.to_string(), "updated 127.0.0.2\n");
+        assert_eq!(
+            export_policy(&manager),
+            "64512 127.0.0.1 64513 127.0.0.2 passive export set local-pref 200 \
+             export set as-path prepend 64512 64512"
+                .parse::<Config>()
+                .unwrap()
+                .export_policy
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn templates_create_and_remove_peers_for_discovered_addresses() {
+        let loc_rib = Arc::new(Mutex::new(LocRib::empty()));
+        let mut manager = PeerManager::new(loc_rib, Arc::new(AspaTable::new()));
+        let diff = manager
+            .apply(vec![
+                config("64512 127.0.0.1 64513 127.0.0.2 passive"),
+                config("64512 127.0.0.1 64514 0.0.0.0 passive discover rs-clients.example"),
+            ])
+            .await;
+        assert_eq!(diff.added, vec!["127.0.0.2".parse::<Ipv4Addr>().unwrap()]);
+        assert_eq!(manager.discovery_hosts(), vec!["rs-clients.example"]);
+
+        let discovered = |addresses: &[&str]| {
+            HashMap::from([(
+                "rs-clients.example".to_owned(),
+                addresses.iter().map(|a| a.parse().unwrap()).collect(),
+            )])
+        };
+        let diff = manager
+            .apply_discovered(discovered(&["127.0.0.2", "127.0.0.3", "127.0.0.4"]))
+            .await;
+        // 静的に設定したピアと同じアドレスは、静的な設定を優先する。
+        assert_eq!(diff.to_string(), "added 127.0.0.3\nadded 127.0.0.4\n");
+        assert_eq!(
+            manager
+                .apply_discovered(discovered(&["127.0.0.2", "127.0.0.3", "127.0.0.4"]))
+                .await,
+            ConfigDiff::default()
+        );
+
+        let diff = manager.apply_discovered(discovered(&["127.0.0.4"])).await;
+        assert_eq!(diff.to_string(), "removed 127.0.0.3\n");
+        assert_eq!(manager.neighbors().snapshot().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn loc_rib_changes_are_advertised_to_every_peer() {
+        let mut harness =
+            PeerHarness::new(config("64512 127.0.0.1 64513 127.0.0.2 active mrai 0")).await;
+        harness
+            .establish(64513.into(), "127.0.0.2".parse().unwrap())
+            .await;
+        let loc_rib = harness.loc_rib();
+        let neighbors = NeighborList::new(vec![Neighbor::from(&harness.peer)]);
+        let propagation = tokio::spawn(propagate_loc_rib_changes(Arc::clone(&loc_rib), neighbors));
+        tokio::time::sleep(LOC_RIB_CHECK_INTERVAL).await;
+
+        // このピアから学習したのではないルートの変更も、このピアに広告させる。
+        loc_rib.lock().await.originate(
+            "10.100.220.0/24".parse().unwrap(),
+            "127.0.0.1".parse().unwrap(),
+            vec![],
+        );
+        tokio::time::sleep(LOC_RIB_CHECK_INTERVAL * 2).await;
+        harness.step(5).await;
+        propagation.abort();
+
+        assert!(harness.sent_messages().await.iter().any(|m| matches!(m,
+            Message::Update(update) if update.network_layer_reachability_information()
+                == &vec!["10.100.220.0/24".parse().unwrap()])));
+    }
+}
@@ -0,0 +1,361 @@
+use crate::aspa::AspaState;
+use crate::bgp_type::AutonomousSystemNumber;
+use crate::error::ConfigParseError;
+use crate::fib::BLACKHOLE_NEXT_HOP;
+use crate::log_error;
+use crate::path_attribute::Community;
+use crate::routing::RibEntry;
+#[cfg(feature = "scripting")]
+use crate::script::{Script, Verdict};
+use anyhow::Context;
+use std::iter::Peekable;
+use std::net::Ipv4Addr;
+#[cfg(feature = "scripting")]
+use std::path::PathBuf;
+
+/// ルートを広告・受信するときに適用するポリシーです。
+/// 設定された順番でPolicyActionをRibEntryに適用します。
+#[derive(PartialEq, Eq, Debug, Clone, Default, Hash, PartialOrd, Ord)]
+pub struct Policy(Vec<PolicyAction>);
+
+impl Policy {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn push(&mut self, action: PolicyAction) {
+        self.0.push(action);
+    }
+
+    /// otherのPolicyActionを、このPolicyのPolicyActionの後に追加する。
+    pub fn extend(&mut self, other: &Policy) {
+        self.0.extend(other.0.iter().cloned());
+    }
+
+    /// routeにPolicyActionを順に適用する。
+    /// routeを拒否するPolicyActionがあった場合は、そこで適用をやめてfalseを返す。
+    pub fn apply(&self, route: &mut RibEntry) -> bool {
+        self.0.iter().all(|action| action.apply(route))
+    }
+
+    /// call scriptでスクリプトを呼び出すPolicyActionを含むか。
+    pub fn calls_script(&self) -> bool {
+        self.0.iter().any(PolicyAction::calls_script)
+    }
+}
+
+/// スクリプトを呼び出すPolicyの適用を、tokioのworkerを止めないようにブロッキング処理用のスレッドで行う。
+/// fがpanicした場合はログに出力し、T::default()を返す。
+pub async fn apply_blocking<T, F>(f: F) -> T
+where
+    T: Default + Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(e) => {
+            log_error!("policyを適用できませんでした。{:?}", e);
+            T::default()
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+pub enum PolicyAction {
+    /// `set as-path prepend 64512 64512 64512`
+    /// AS Pathの先頭に指定したAS番号を指定した順番で追加する。
+    PrependAsPath(Vec<AutonomousSystemNumber>),
+    /// `deny aspa invalid`
+    /// ASPAの検証結果が指定した値のルートを拒否する。
+    DenyAspa(AspaState),
+    /// `deny as-path-length 50`
+    /// AS_SETを1つのASと数えたAS Pathの長さが、指定した値を超えるルートを拒否する。
+    DenyAsPathLongerThan(usize),
+    /// `set next-hop 192.0.2.1`
+    /// NEXT_HOPを指定したアドレスに書き換える。
+    /// `set next-hop blackhole`(または`discard`)では、BLACKHOLE_NEXT_HOPに書き換える。
+    SetNextHop(Ipv4Addr),
+    /// `set local-pref 200`
+    /// LOCAL_PREFを指定した値にする。
+    SetLocalPref(u32),
+    /// `set weight 100`
+    /// このルータの中だけで使うweightを指定した値にする。import policyでだけ意味がある。
+    SetWeight(u32),
+    /// `match community 64512:666 set next-hop 192.0.2.1`
+    /// 指定したcommunityが付いたルートにだけ、続くPolicyActionを適用する。
+    MatchCommunity(Community, Box<PolicyAction>),
+    /// `call script /etc/howbgp/policy.lua`
+    /// 組み込みのPolicyActionで書けない判定を、Luaのスクリプトで行う。
+    /// スクリプトがエラーになった場合や、応答を読み取れない場合はルートを拒否する。
+    /// scripting featureを有効にしてビルドした場合だけ使える。
+    #[cfg(feature = "scripting")]
+    CallScript(Script),
+}
+
+impl PolicyAction {
+    fn calls_script(&self) -> bool {
+        match self {
+            #[cfg(feature = "scripting")]
+            PolicyAction::CallScript(_) => true,
+            PolicyAction::MatchCommunity(_, action) => action.calls_script(),
+            _ => false,
+        }
+    }
+
+    /// routeを拒否する場合はfalseを返す。
+    fn apply(&self, route: &mut RibEntry) -> bool {
+        match self {
+            PolicyAction::PrependAsPath(as_numbers) => {
+                route.prepend_as_path(as_numbers);
+                true
+            }
+            PolicyAction::DenyAspa(state) => route.aspa_state != *state,
+            PolicyAction::DenyAsPathLongerThan(maximum) => match route.as_path() {
+                Some(as_path) => as_path.path_length() <= *maximum,
+                None => true,
+            },
+            PolicyAction::SetNextHop(next_hop) => {
+                route.change_next_hop(*next_hop);
+                true
+            }
+            PolicyAction::SetLocalPref(local_pref) => {
+                route.set_local_pref(*local_pref);
+                true
+            }
+            PolicyAction::SetWeight(weight) => {
+                route.weight = *weight;
+                true
+            }
+            PolicyAction::MatchCommunity(community, action) => {
+                !route.communities().contains(community) || action.apply(route)
+            }
+            #[cfg(feature = "scripting")]
+            PolicyAction::CallScript(script) => match script.evaluate(route) {
+                Ok(Verdict::Accept) => true,
+                Ok(Verdict::Reject) => false,
+                Ok(Verdict::Modify(actions)) => actions.iter().all(|action| action.apply(route)),
+                Err(e) => {
+                    log_error!("{:?}", e);
+                    false
+                }
+            },
+        }
+    }
+
+    /// 設定文字列を空白で区切ったtokenの列から、1つのPolicyActionを読み取る。
+    /// `set as-path prepend 64512 64512`のようにAS番号が続く限りtokenを消費する。
+    pub fn parse_from_tokens<'a, I>(tokens: &mut Peekable<I>) -> Result<Self, ConfigParseError>
+    where
+        I: Iterator<Item = &'a str>,
+    {
+        let statement: Vec<&str> = tokens.take(3).collect();
+        match statement[..] {
+            ["set", "as-path", "prepend"] => {
+                let mut as_numbers = vec![];
+                while let Some(as_number) = tokens.peek().and_then(|t| t.parse::<u32>().ok()) {
+                    as_numbers.push(AutonomousSystemNumber::from(as_number));
+                    tokens.next();
+                }
+                if as_numbers.is_empty() {
+                    return Err(ConfigParseError::MissingValue {
+                        key: "set as-path prepend".to_owned(),
+                    });
+                }
+                Ok(PolicyAction::PrependAsPath(as_numbers))
+            }
+            ["deny", "aspa", state] => Ok(PolicyAction::DenyAspa(state.parse()?)),
+            ["deny", "as-path-length", maximum] => Ok(PolicyAction::DenyAsPathLongerThan(
+                maximum
+                    .parse()
+                    .context(format!("cannot parse as-path-length `{maximum}`"))?,
+            )),
+            ["set", "next-hop", "blackhole" | "discard"] => {
+                Ok(PolicyAction::SetNextHop(BLACKHOLE_NEXT_HOP))
+            }
+            ["set", "next-hop", next_hop] => Ok(PolicyAction::SetNextHop(
+                next_hop
+                    .parse()
+                    .context(format!("cannot parse next-hop `{next_hop}`"))?,
+            )),
+            ["set", "local-pref", local_pref] => {
+                Ok(PolicyAction::SetLocalPref(local_pref.parse().context(
+                    format!("cannot parse local-pref `{local_pref}`"),
+                )?))
+            }
+            ["set", "weight", weight] => Ok(PolicyAction::SetWeight(
+                weight
+                    .parse()
+                    .context(format!("cannot parse weight `{weight}`"))?,
+            )),
+            ["match", "community", community] => Ok(PolicyAction::MatchCommunity(
+                community.parse()?,
+                Box::new(PolicyAction::parse_from_tokens(tokens)?),
+            )),
+            #[cfg(feature = "scripting")]
+            ["call", "script", path] => {
+                Ok(PolicyAction::CallScript(Script::new(PathBuf::from(path))))
+            }
+            #[cfg(not(feature = "scripting"))]
+            ["call", "script", _] => Err(ConfigParseError::FeatureDisabled {
+                option: "call script",
+                feature: "scripting",
+            }),
+            _ => Err(ConfigParseError::InvalidValue {
+                kind: "policy statement",
+                value: statement.join(" "),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path_attribute::{AsPath, PathAttribute};
+
+    #[test]
+    fn parse_as_path_prepend_statement() {
+        let mut tokens = "set as-path prepend 64512 64512 10.0.0.0/24"
+            .split(' ')
+            .peekable();
+        let action = PolicyAction::parse_from_tokens(&mut tokens).unwrap();
+
+        assert_eq!(
+            action,
+            PolicyAction::PrependAsPath(vec![64512.into(), 64512.into()])
+        );
+        assert_eq!(tokens.next(), Some("10.0.0.0/24"));
+    }
+
+    #[test]
+    fn deny_aspa_invalid_rejects_only_invalid_routes() {
+        let mut tokens = "deny aspa invalid".split(' ').peekable();
+        let mut policy = Policy::new();
+        policy.push(PolicyAction::parse_from_tokens(&mut tokens).unwrap());
+        let mut route = RibEntry::for_test("10.100.220.0/24").with_path_attributes(vec![]);
+        route.aspa_state = AspaState::Unknown;
+
+        assert!(policy.apply(&mut route));
+        route.aspa_state = AspaState::Invalid;
+        assert!(!policy.apply(&mut route));
+    }
+
+    #[test]
+    fn deny_as_path_length_counts_as_set_as_one() {
+        let mut tokens = "deny as-path-length 2".split(' ').peekable();
+        let mut policy = Policy::new();
+        policy.push(PolicyAction::parse_from_tokens(&mut tokens).unwrap());
+        let route = |as_path: AsPath| {
+            RibEntry::for_test("10.100.220.0/24")
+                .with_path_attributes(vec![PathAttribute::AsPath(as_path)])
+        };
+
+        assert!(policy.apply(&mut route(AsPath::AsSequence(vec![
+            64513.into(),
+            64514.into()
+        ]))));
+        assert!(!policy.apply(&mut route(AsPath::AsSequence(vec![
+            64513.into(),
+            64513.into(),
+            64514.into()
+        ]))));
+        assert!(policy.apply(&mut route(AsPath::AsSet(
+            [64513.into(), 64514.into(), 64515.into()].into()
+        ))));
+        assert!(PolicyAction::parse_from_tokens(
+            &mut "deny as-path-length many".split(' ').peekable()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn set_local_pref_overrides_local_pref() {
+        let mut tokens = "set local-pref 300".split(' ').peekable();
+        let mut policy = Policy::new();
+        policy.push(PolicyAction::parse_from_tokens(&mut tokens).unwrap());
+        let mut route = RibEntry::for_test("10.100.220.0/24")
+            .with_path_attributes(vec![PathAttribute::LocalPref(100)]);
+
+        assert!(policy.apply(&mut route));
+        assert_eq!(route.local_pref(), Some(300));
+
+        let mut tokens = "set weight 50".split(' ').peekable();
+        let mut policy = Policy::new();
+        policy.push(PolicyAction::parse_from_tokens(&mut tokens).unwrap());
+        assert!(policy.apply(&mut route));
+        assert_eq!(route.weight, 50);
+        // weightはPath Attributeではない。
+        assert_eq!(*route.path_attributes, vec![PathAttribute::LocalPref(300)]);
+    }
+
+    #[test]
+    fn next_hop_can_be_set_to_blackhole() {
+        for statement in ["set next-hop blackhole", "set next-hop discard"] {
+            let mut tokens = statement.split(' ').peekable();
+            assert_eq!(
+                PolicyAction::parse_from_tokens(&mut tokens).unwrap(),
+                PolicyAction::SetNextHop(BLACKHOLE_NEXT_HOP)
+            );
+        }
+        let mut tokens = "set next-hop nowhere".split(' ').peekable();
+        assert!(PolicyAction::parse_from_tokens(&mut tokens).is_err());
+    }
+
+    #[test]
+    fn community_driven_actions_apply_only_to_matching_routes() {
+        let mut policy = Policy::new();
+        for statement in [
+            "match community 64512:666 set next-hop 192.0.2.1",
+            "match community 64512:100 set as-path prepend 64512 64512",
+        ] {
+            let mut tokens = statement.split(' ').peekable();
+            policy.push(PolicyAction::parse_from_tokens(&mut tokens).unwrap());
+        }
+        let route = |community: &str| {
+            RibEntry::for_test("10.100.220.0/24").with_communities(vec![community.parse().unwrap()])
+        };
+
+        let mut blackhole = route("64512:666");
+        assert!(policy.apply(&mut blackhole));
+        assert_eq!(blackhole.next_hop(), Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(blackhole.as_path().unwrap().path_length(), 1);
+
+        let mut prepended = route("64512:100");
+        assert!(policy.apply(&mut prepended));
+        assert_eq!(prepended.next_hop(), Some("10.200.100.3".parse().unwrap()));
+        assert_eq!(prepended.as_path().unwrap().path_length(), 3);
+    }
+
+    #[test]
+    fn apply_as_path_prepend_to_rib_entry() {
+        let mut policy = Policy::new();
+        policy.push(PolicyAction::PrependAsPath(vec![
+            64512.into(),
+            64512.into(),
+        ]));
+        let mut route = RibEntry::for_test("10.100.220.0/24");
+        assert!(policy.apply(&mut route));
+
+        assert_eq!(
+            route.path_attributes[1],
+            PathAttribute::AsPath(AsPath::AsSequence(vec![
+                64512.into(),
+                64512.into(),
+                64513.into()
+            ]))
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "scripting"))]
+    fn call_script_requires_scripting_feature() {
+        let mut tokens = "call script /etc/howbgp/policy.lua".split(' ').peekable();
+        assert!(matches!(
+            PolicyAction::parse_from_tokens(&mut tokens),
+            Err(ConfigParseError::FeatureDisabled {
+                feature: "scripting",
+                ..
+            })
+        ));
+    }
+}
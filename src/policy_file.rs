@@ -0,0 +1,189 @@
+use crate::peer_manager::PeerManager;
+use crate::policy::{Policy, PolicyAction};
+use crate::{log_error, log_info};
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Mutex;
+
+/// ポリシーのファイルが変更されたかを確認する間隔。
+const POLICY_FILE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// ピアの設定から`import-policy <name>`、`export-policy <name>`で参照する、名前付きのPolicy。
+///
+/// ファイルには`policy <name>`の行に続けて、その名前のPolicyのPolicyActionを1行に1つずつ書く。
+/// PolicyActionは、ピアの設定の`import`、`export`に続けて書くものと同じ形式である。
+///
+/// ```text
+/// policy customer-in
+///     deny as-path-length 50
+///     match community 64512:666 set next-hop blackhole
+/// policy upstream-out
+///     set as-path prepend 64512 64512
+/// ```
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct PolicySet(BTreeMap<String, Policy>);
+
+impl PolicySet {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path).context(format!(
+            "ポリシーのファイル{:?}を読み込めませんでした。",
+            path
+        ))?;
+        contents.parse()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Policy> {
+        self.0.get(name)
+    }
+}
+
+impl FromStr for PolicySet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut policies = BTreeMap::new();
+        let mut current: Option<(String, Policy)> = None;
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut tokens = line.split_whitespace().peekable();
+            match tokens.peek() {
+                None => continue,
+                Some(&"policy") => {
+                    tokens.next();
+                    let name = match (tokens.next(), tokens.next()) {
+                        (Some(name), None) => name.to_owned(),
+                        _ => {
+                            return Err(anyhow::anyhow!(
+                                "{}行目は`policy <name>`の形式である必要があります。",
+                                i + 1
+                            ))
+                        }
+                    };
+                    if policies.contains_key(&name)
+                        || current.as_ref().map(|(n, _)| n) == Some(&name)
+                    {
+                        return Err(anyhow::anyhow!(
+                            "{}行目: ポリシー{}が重複しています。",
+                            i + 1,
+                            name
+                        ));
+                    }
+                    if let Some((name, policy)) = current.replace((name, Policy::new())) {
+                        policies.insert(name, policy);
+                    }
+                }
+                Some(_) => {
+                    let (_, policy) = current.as_mut().ok_or_else(|| {
+                        anyhow::anyhow!("{}行目の前に`policy <name>`の行が必要です。", i + 1)
+                    })?;
+                    let action = PolicyAction::parse_from_tokens(&mut tokens)
+                        .context(format!("{}行目", i + 1))?;
+                    if let Some(token) = tokens.next() {
+                        return Err(anyhow::anyhow!(
+                            "{}行目: `{}`以降を読み取れませんでした。",
+                            i + 1,
+                            token
+                        ));
+                    }
+                    policy.push(action);
+                }
+            }
+        }
+        if let Some((name, policy)) = current {
+            policies.insert(name, policy);
+        }
+        Ok(Self(policies))
+    }
+}
+
+/// ファイルの最終更新時刻。ファイルを読めない場合はNone。
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// pathのポリシーのファイルを定期的に確認し、変更されていれば読み込み直してpeer_managerに反映する。
+/// 読み取れない場合や、ピアが参照しているポリシーがなくなった場合は、前回のポリシーを使い続ける。
+pub async fn watch(path: PathBuf, peer_manager: Arc<Mutex<PeerManager>>) {
+    let mut last_modified = modified(&path);
+    loop {
+        tokio::time::sleep(POLICY_FILE_CHECK_INTERVAL).await;
+        let current = modified(&path);
+        if current == last_modified {
+            continue;
+        }
+        last_modified = current;
+        let policies = match PolicySet::load(&path) {
+            Ok(policies) => policies,
+            Err(e) => {
+                log_error!("{:?}", e);
+                continue;
+            }
+        };
+        let mut peer_manager = peer_manager.lock().await;
+        let missing = peer_manager.missing_policies(&policies);
+        if !missing.is_empty() {
+            log_error!(
+                "ピアが参照しているポリシー{}が{:?}にないため、変更を反映しません。",
+                missing.join(", "),
+                path
+            );
+            continue;
+        }
+        let diff = peer_manager.set_policies(policies).await;
+        log_info!("{:?}の変更を反映しました。\n{}", path, diff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policies_are_parsed_by_name() {
+        let policies: PolicySet = "# 顧客から受信するルート\n\
+                                   policy customer-in\n\
+                                   \x20   deny as-path-length 50\n\
+                                   \x20   match community 64512:666 set next-hop blackhole\n\
+                                   \n\
+                                   policy upstream-out\n\
+                                   \x20   set as-path prepend 64512 64512\n"
+            .parse()
+            .unwrap();
+
+        let mut upstream_out = Policy::new();
+        upstream_out.push(PolicyAction::PrependAsPath(vec![
+            64512.into(),
+            64512.into(),
+        ]));
+        assert_eq!(policies.get("upstream-out"), Some(&upstream_out));
+        let mut customer_in = Policy::new();
+        customer_in.push(PolicyAction::DenyAsPathLongerThan(50));
+        customer_in.push(PolicyAction::MatchCommunity(
+            "64512:666".parse().unwrap(),
+            Box::new(PolicyAction::SetNextHop(crate::fib::BLACKHOLE_NEXT_HOP)),
+        ));
+        assert_eq!(policies.get("customer-in"), Some(&customer_in));
+        assert_eq!(policies.get("unknown"), None);
+    }
+
+    #[test]
+    fn invalid_policy_file_is_rejected() {
+        for text in [
+            "deny as-path-length 50\n",
+            "policy\n",
+            "policy a\n  set local-pref high\n",
+            "policy a\n  set local-pref 200 300\n",
+            "policy a\npolicy a\n",
+        ] {
+            assert!(text.parse::<PolicySet>().is_err(), "{:?}", text);
+        }
+    }
+}
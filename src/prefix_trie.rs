@@ -0,0 +1,225 @@
+use crate::routing::Ipv4Network;
+use std::net::Ipv4Addr;
+
+/// Ipv4Networkをkeyとする2分木のprefix trie。
+/// ネットワークアドレスの先頭のbitから順に木を辿るので、
+/// 挿入・削除・検索はprefix長に比例する時間で終わる。
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PrefixTrie<T> {
+    root: Node<T>,
+    len: usize,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Node<T> {
+    entry: Option<(Ipv4Network, T)>,
+    children: [Option<Box<Node<T>>>; 2],
+}
+
+impl<T> Default for PrefixTrie<T> {
+    fn default() -> Self {
+        Self {
+            root: Node::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<T> Node<T> {
+    fn new() -> Self {
+        Self {
+            entry: None,
+            children: [None, None],
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entry.is_none() && self.children.iter().all(|c| c.is_none())
+    }
+
+    /// bitsの先頭depth bit目から順に辿り、prefix長の位置にあるエントリを削除する。
+    /// 子が空になった場合はその子を取り除く。
+    fn remove(&mut self, bits: u32, depth: u8, prefix: u8) -> Option<(Ipv4Network, T)> {
+        if depth == prefix {
+            return self.entry.take();
+        }
+        let index = bit_at(bits, depth);
+        let child = self.children[index].as_mut()?;
+        let removed = child.remove(bits, depth + 1, prefix);
+        if child.is_empty() {
+            self.children[index] = None;
+        }
+        removed
+    }
+}
+
+/// addressの先頭からdepth番目のbit。
+fn bit_at(address: u32, depth: u8) -> usize {
+    ((address >> (31 - depth)) & 1) as usize
+}
+
+impl<T> PrefixTrie<T> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// networkのエントリを追加する。既にエントリがあった場合は置き換えて、古い値を返す。
+    pub fn insert(&mut self, network: Ipv4Network, value: T) -> Option<T> {
+        let bits = u32::from(network.network());
+        let mut node = &mut self.root;
+        for depth in 0..network.prefix() {
+            node = node.children[bit_at(bits, depth)].get_or_insert_with(|| Box::new(Node::new()));
+        }
+        let old = node.entry.replace((network, value)).map(|(_, v)| v);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    /// networkと完全に一致するエントリを返す。
+    pub fn get(&self, network: &Ipv4Network) -> Option<&T> {
+        self.find_node(network)?.entry.as_ref().map(|(_, v)| v)
+    }
+
+    pub fn contains(&self, network: &Ipv4Network) -> bool {
+        self.get(network).is_some()
+    }
+
+    pub fn remove(&mut self, network: &Ipv4Network) -> Option<T> {
+        let bits = u32::from(network.network());
+        let removed = self.root.remove(bits, 0, network.prefix()).map(|(_, v)| v);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    /// addressを含むエントリのうち、prefix長が最も長いものを返す。
+    pub fn longest_match(&self, address: Ipv4Addr) -> Option<(&Ipv4Network, &T)> {
+        let bits = u32::from(address);
+        let mut node = &self.root;
+        let mut longest = node.entry.as_ref();
+        for depth in 0..32 {
+            node = match &node.children[bit_at(bits, depth)] {
+                Some(child) => child,
+                None => break,
+            };
+            if node.entry.is_some() {
+                longest = node.entry.as_ref();
+            }
+        }
+        longest.map(|(network, value)| (network, value))
+    }
+
+    /// ネットワークアドレスの昇順に、同じアドレスではprefix長が短い順にエントリを返す。
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            stack: vec![&self.root],
+        }
+    }
+
+    fn find_node(&self, network: &Ipv4Network) -> Option<&Node<T>> {
+        let bits = u32::from(network.network());
+        let mut node = &self.root;
+        for depth in 0..network.prefix() {
+            node = node.children[bit_at(bits, depth)].as_ref()?;
+        }
+        Some(node)
+    }
+}
+
+pub struct Iter<'a, T> {
+    stack: Vec<&'a Node<T>>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (&'a Ipv4Network, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(node) = self.stack.pop() {
+            // 0側の子を先に辿るために、1側の子から積む。
+            for child in node.children.iter().rev().flatten() {
+                self.stack.push(child);
+            }
+            if let Some((network, value)) = &node.entry {
+                return Some((network, value));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(s: &str) -> Ipv4Network {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn insert_get_and_remove() {
+        let mut trie = PrefixTrie::new();
+        assert_eq!(trie.insert(network("10.100.220.0/24"), 1), None);
+        assert_eq!(trie.insert(network("10.100.220.0/24"), 2), Some(1));
+        assert_eq!(trie.insert(network("10.100.0.0/16"), 3), None);
+        assert_eq!(trie.len(), 2);
+
+        assert_eq!(trie.get(&network("10.100.220.0/24")), Some(&2));
+        assert_eq!(trie.get(&network("10.100.220.0/23")), None);
+
+        assert_eq!(trie.remove(&network("10.100.220.0/24")), Some(2));
+        assert_eq!(trie.remove(&network("10.100.220.0/24")), None);
+        assert_eq!(trie.len(), 1);
+        assert_eq!(trie.get(&network("10.100.0.0/16")), Some(&3));
+    }
+
+    #[test]
+    fn longest_match_returns_most_specific_network() {
+        let mut trie = PrefixTrie::new();
+        trie.insert(network("0.0.0.0/0"), "default");
+        trie.insert(network("10.100.0.0/16"), "16");
+        trie.insert(network("10.100.220.0/24"), "24");
+
+        let lookup = |addr: &str| trie.longest_match(addr.parse().unwrap()).map(|(_, v)| *v);
+        assert_eq!(lookup("10.100.220.1"), Some("24"));
+        assert_eq!(lookup("10.100.221.1"), Some("16"));
+        assert_eq!(lookup("192.168.0.1"), Some("default"));
+    }
+
+    #[test]
+    fn iter_returns_entries_in_network_order() {
+        let mut trie = PrefixTrie::new();
+        for (i, n) in [
+            "10.100.221.0/24",
+            "10.100.220.0/24",
+            "10.100.0.0/16",
+            "10.0.0.0/8",
+        ]
+        .iter()
+        .enumerate()
+        {
+            trie.insert(network(n), i);
+        }
+
+        let networks: Vec<String> = trie.iter().map(|(n, _)| n.to_string()).collect();
+        assert_eq!(
+            networks,
+            vec![
+                "10.0.0.0/8",
+                "10.100.0.0/16",
+                "10.100.220.0/24",
+                "10.100.221.0/24"
+            ]
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use crate::config::Config;
+use anyhow::{Context, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// BGPのメッセージを流すQUICの双方向ストリーム。
+/// TcpStreamと同じくAsyncRead, AsyncWriteを実装するので、Connectionからは区別せずに扱える。
+#[derive(Debug)]
+pub struct QuicStream {
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+    /// ストリームを使っている間、EndpointとConnectionを閉じないように保持する。
+    _connection: quinn::Connection,
+    _endpoint: quinn::Endpoint,
+}
+
+impl QuicStream {
+    pub async fn connect_to_remote_peer(config: &Config) -> Result<Self> {
+        let remote = SocketAddr::from((config.remote_ip, BGP_PORT));
+        let mut endpoint = quinn::Endpoint::client(SocketAddr::from((config.local_ip, 0)))
+            .context("QUICのEndpointを作成できませんでした。")?;
+        endpoint.set_default_client_config(client_config()?);
+        let connection = endpoint
+            .connect(remote, SERVER_NAME)?
+            .await
+            .context(format!(
+                "cannot connect to remote peer {} over QUIC",
+                remote
+            ))?;
+        let (send, recv) = connection.open_bi().await?;
+        Ok(Self {
+            send,
+            recv,
+            _connection: connection,
+            _endpoint: endpoint,
+        })
+    }
+
+    pub async fn wait_connection_from_remote_peer(config: &Config) -> Result<Self> {
+        let local = SocketAddr::from((config.local_ip, BGP_PORT));
+        let endpoint = quinn::Endpoint::server(server_config()?, local)
+            .context(format!("{}にbindすることが出来ませんでした。", local))?;
+        let connection = endpoint
+            .accept()
+            .await
+            .context("QUICのEndpointが閉じられました。")?
+            .await
+            .context(format!(
+                "{}にてリモートからのQUIC Connectionの要求を完遂することが出来ませんでした。",
+                local
+            ))?;
+        // 双方向ストリームは、Active側が最初のメッセージを送信したときに受け入れられる。
+        let (send, recv) = connection.accept_bi().await?;
+        Ok(Self {
+            send,
+            recv,
+            _connection: connection,
+            _endpoint: endpoint,
+        })
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+const BGP_PORT: u16 = 179;
+
+/// 実験用のため、証明書は起動のたびに自己署名で作成し、クライアントは検証しない。
+const SERVER_NAME: &str = "howbgp";
+
+fn server_config() -> Result<quinn::ServerConfig> {
+    let cert = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_owned()])?;
+    let cert_der = CertificateDer::from(cert.cert);
+    let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+    Ok(quinn::ServerConfig::with_single_cert(
+        vec![cert_der],
+        key.into(),
+    )?)
+}
+
+fn client_config() -> Result<quinn::ClientConfig> {
+    let crypto = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(SkipServerVerification(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))))
+        .with_no_client_auth();
+    Ok(quinn::ClientConfig::new(Arc::new(
+        quinn::crypto::rustls::QuicClientConfig::try_from(crypto)?,
+    )))
+}
+
+/// サーバの証明書を検証せずに受け入れる。署名だけは確認する。
+#[derive(Debug)]
+struct SkipServerVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
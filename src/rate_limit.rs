@@ -0,0 +1,74 @@
+use crate::clock::{Clock, SystemClock};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// 1秒あたりrate個のトークンを補充し、最大でrate個(1秒分)まで貯めるトークンバケット。
+/// ピアから受信したUpdateMessageの処理の頻度を制限するために使う。
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+    clock: Arc<dyn Clock>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32) -> Self {
+        Self::with_clock(rate, Arc::new(SystemClock))
+    }
+
+    /// clockの時刻でトークンを補充するトークンバケットを作成する。最初は満杯にしておく。
+    pub fn with_clock(rate: u32, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last_refill: clock.now(),
+            clock,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = self.clock.now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+    }
+
+    /// 1つ以上のトークンが残っているか。
+    pub fn has_token(&mut self) -> bool {
+        self.refill();
+        self.tokens >= 1.0
+    }
+
+    /// トークンを1つ使う。残っていない場合は、補充されるまでの分を前借りする。
+    pub fn consume(&mut self) {
+        self.refill();
+        self.tokens -= 1.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::time::Duration;
+
+    #[test]
+    fn tokens_are_refilled_at_rate_up_to_one_second_worth() {
+        let clock = Arc::new(MockClock::new());
+        let mut bucket = TokenBucket::with_clock(2, clock.clone());
+        bucket.consume();
+        bucket.consume();
+        assert!(!bucket.has_token());
+
+        clock.advance(Duration::from_millis(500));
+        assert!(bucket.has_token());
+        bucket.consume();
+        assert!(!bucket.has_token());
+
+        clock.advance(Duration::from_secs(10));
+        bucket.consume();
+        bucket.consume();
+        assert!(!bucket.has_token());
+    }
+}
@@ -0,0 +1,174 @@
+use crate::mrt::{Bgp4mpMessage, MrtReader};
+use crate::packets::message::Message;
+use crate::routing::{AdjRibIn, LocRib};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::path::Path;
+use tokio::time::{sleep, Duration};
+
+/// 表示するパース失敗の最大数。
+const MAX_REPORTED_FAILURES: usize = 20;
+
+/// MRTファイルのリプレイ結果。
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct ReplayReport {
+    pub records: usize,
+    pub bgp_messages: usize,
+    pub updates: usize,
+    /// AS番号が4 octetsのセッションのメッセージ。AS_PATHのAS番号を4 octetsとして読み取る。
+    pub as4_messages: usize,
+    pub parse_failures: usize,
+    /// (レコードの番号, エラー内容)
+    pub failure_details: Vec<(usize, String)>,
+    pub peers: usize,
+    pub adj_rib_in_routes: usize,
+    pub loc_rib_routes: usize,
+}
+
+impl ReplayReport {
+    fn record_failure(&mut self, index: usize, error: impl fmt::Debug) {
+        self.parse_failures += 1;
+        if self.failure_details.len() < MAX_REPORTED_FAILURES {
+            self.failure_details.push((index, format!("{:?}", error)));
+        }
+    }
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "records:              {}", self.records)?;
+        writeln!(f, "bgp messages:         {}", self.bgp_messages)?;
+        writeln!(f, "updates:              {}", self.updates)?;
+        writeln!(f, "as4 messages:         {}", self.as4_messages)?;
+        writeln!(f, "parse failures:       {}", self.parse_failures)?;
+        for (index, error) in &self.failure_details {
+            writeln!(f, "  record #{}: {}", index, error)?;
+        }
+        writeln!(f, "peers:                {}", self.peers)?;
+        writeln!(f, "adj-rib-in routes:    {}", self.adj_rib_in_routes)?;
+        write!(f, "loc-rib routes:       {}", self.loc_rib_routes)
+    }
+}
+
+/// MRTファイルに記録されたBGPメッセージを、UpdateMessageのデコードと
+/// AdjRibIn -> LocRibへのインストールに順に通す。
+/// speedはMRTのTimestampに対する再生速度の倍率で、0の場合は待たずに処理する。
+pub async fn replay(path: &Path, speed: f64) -> Result<ReplayReport> {
+    install_into(path, speed, &mut LocRib::empty()).await
+}
+
+/// MRTファイルに記録されたUpdateMessageのルートを、loc_ribにインストールする。
+/// `--dry-run`で、カーネルのルーティングテーブルの代わりにMRTからLocRibを作るために使う。
+pub async fn install_into(path: &Path, speed: f64, loc_rib: &mut LocRib) -> Result<ReplayReport> {
+    let file = File::open(path).context(format!("{:?}を開けませんでした。", path))?;
+    let mut report = ReplayReport::default();
+    let mut adj_rib_ins: HashMap<IpAddr, AdjRibIn> = HashMap::new();
+    let mut previous_timestamp = None;
+
+    for (index, record) in MrtReader::new(BufReader::new(file)).enumerate() {
+        report.records += 1;
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                // レコードの境界が分からなくなるので、以降は読み進められない。
+                report.record_failure(index, e);
+                break;
+            }
+        };
+
+        if speed > 0.0 {
+            if let Some(previous) = previous_timestamp {
+                let elapsed = record.timestamp.saturating_sub(previous);
+                sleep(Duration::from_secs_f64(elapsed as f64 / speed)).await;
+            }
+            previous_timestamp = Some(record.timestamp);
+        }
+
+        let message = match Bgp4mpMessage::from_record(&record) {
+            Ok(Some(message)) => message,
+            Ok(None) => continue,
+            Err(e) => {
+                report.record_failure(index, e);
+                continue;
+            }
+        };
+        report.bgp_messages += 1;
+        if message.as4 {
+            report.as4_messages += 1;
+        }
+
+        match Message::decode(message.bgp_message.freeze(), message.as4) {
+            Ok(Message::Update(update)) => {
+                report.updates += 1;
+                adj_rib_ins
+                    .entry(message.peer_ip)
+                    .or_default()
+                    .install_from_update(update, |_| true);
+            }
+            Ok(_) => (),
+            Err(e) => report.record_failure(index, e),
+        }
+    }
+
+    for adj_rib_in in adj_rib_ins.values_mut() {
+        loc_rib.install_from_adj_rib_in(adj_rib_in);
+    }
+    report.peers = adj_rib_ins.len();
+    report.adj_rib_in_routes = adj_rib_ins.values().map(|r| r.len()).sum();
+    report.loc_rib_routes = loc_rib.len();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mrt::{MrtRecord, BGP4MP, BGP4MP_MESSAGE_AS4};
+    use crate::packets::update::UpdateMessage;
+    use crate::path_attribute::AsPath;
+    use crate::routing::RibEntry;
+    use bytes::BytesMut;
+
+    #[tokio::test]
+    async fn bgp4mp_message_as4_is_replayed_with_4_octet_as_path() {
+        let route = RibEntry::for_test("10.100.220.0/24").with_as_path(&[4200000001]);
+        let update: BytesMut = UpdateMessage::new(
+            route.path_attributes.to_vec(),
+            vec![route.network_address],
+            vec![],
+        )
+        .with_four_octet_as(true)
+        .into();
+        let mut body = BytesMut::new();
+        body.extend_from_slice(&4200000001u32.to_be_bytes());
+        body.extend_from_slice(&64512u32.to_be_bytes());
+        body.extend_from_slice(&0u16.to_be_bytes());
+        body.extend_from_slice(&1u16.to_be_bytes());
+        body.extend_from_slice(&[10, 200, 100, 3, 10, 200, 100, 2]);
+        body.extend_from_slice(&update);
+        let record = MrtRecord {
+            timestamp: 1650000000,
+            microsecond_timestamp: None,
+            type_: BGP4MP,
+            subtype: BGP4MP_MESSAGE_AS4,
+            message: body,
+        };
+        let path = std::env::temp_dir().join(format!("howbgp-replay-{}.mrt", std::process::id()));
+        std::fs::write(&path, record.to_bytes()).unwrap();
+
+        let mut loc_rib = LocRib::empty();
+        let report = install_into(&path, 0.0, &mut loc_rib).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(report.as4_messages, 1);
+        assert_eq!(report.updates, 1);
+        assert_eq!(report.parse_failures, 0);
+        assert_eq!(
+            loc_rib.get(&route.network_address).unwrap().as_path(),
+            Some(&AsPath::AsSequence(vec![4200000001.into()]))
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use crate::monitor::format_path_attribute;
+use crate::mrt::TableDump;
+use crate::path_attribute::PathAttribute;
+use crate::routing::{Ipv4Network, LocRib};
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::Arc;
+
+/// 比較するためのRIBの内容。prefixとルートを持つピアのアドレスの組ごとに、Path Attributeを持つ。
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RibSnapshot(BTreeMap<(Ipv4Network, Ipv4Addr), Arc<Vec<PathAttribute>>>);
+
+impl RibSnapshot {
+    /// mrt-dump-dirに書き出したTABLE_DUMP_V2形式のダンプを読み込む。
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path).context(format!("{:?}を開けませんでした。", path))?;
+        let dump = TableDump::read(BufReader::new(file)).context(format!(
+            "{:?}をMRTのテーブルダンプとして読み込めませんでした。",
+            path
+        ))?;
+        Ok(Self::from(&dump))
+    }
+
+    /// 動作中のLocRibの内容。LocRibのダンプと同じく、ルートはすべてlocal_ipのピアが持つものとする。
+    pub fn from_loc_rib(loc_rib: &LocRib, local_ip: Ipv4Addr) -> Self {
+        Self(
+            loc_rib
+                .iter()
+                .map(|r| {
+                    (
+                        (r.network_address, local_ip),
+                        Arc::clone(&r.path_attributes),
+                    )
+                })
+                .collect(),
+        )
+    }
+}
+
+impl From<&TableDump> for RibSnapshot {
+    fn from(dump: &TableDump) -> Self {
+        Self(
+            dump.routes()
+                .map(|(network, peer, path_attributes)| {
+                    ((network, peer.ip), Arc::clone(path_attributes))
+                })
+                .collect(),
+        )
+    }
+}
+
+/// 2つのRibSnapshotの差分。メンテナンスの前後でルートが変わっていないかを確かめるのに使う。
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RibDiff {
+    pub added: Vec<(Ipv4Network, Ipv4Addr, Arc<Vec<PathAttribute>>)>,
+    pub removed: Vec<(Ipv4Network, Ipv4Addr, Arc<Vec<PathAttribute>>)>,
+    pub changed: Vec<ChangedRoute>,
+}
+
+/// 両方のRibSnapshotにあるが、Path Attributeが変わったルート。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangedRoute {
+    pub network: Ipv4Network,
+    pub peer: Ipv4Addr,
+    pub old: Arc<Vec<PathAttribute>>,
+    pub new: Arc<Vec<PathAttribute>>,
+}
+
+impl RibDiff {
+    /// oldからnewへの変化を、prefixの順に並べる。
+    pub fn new(old: &RibSnapshot, new: &RibSnapshot) -> Self {
+        let mut diff = Self::default();
+        for ((network, peer), path_attributes) in &old.0 {
+            match new.0.get(&(*network, *peer)) {
+                None => diff
+                    .removed
+                    .push((*network, *peer, Arc::clone(path_attributes))),
+                Some(new_path_attributes) if new_path_attributes != path_attributes => {
+                    diff.changed.push(ChangedRoute {
+                        network: *network,
+                        peer: *peer,
+                        old: Arc::clone(path_attributes),
+                        new: Arc::clone(new_path_attributes),
+                    })
+                }
+                Some(_) => (),
+            }
+        }
+        for ((network, peer), path_attributes) in &new.0 {
+            if !old.0.contains_key(&(*network, *peer)) {
+                diff.added
+                    .push((*network, *peer, Arc::clone(path_attributes)));
+            }
+        }
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+fn format_path_attributes(path_attributes: &[PathAttribute]) -> String {
+    path_attributes
+        .iter()
+        .map(format_path_attribute)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl fmt::Display for RibDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (network, peer, path_attributes) in &self.added {
+            writeln!(
+                f,
+                "+ {} from {}: {}",
+                **network,
+                peer,
+                format_path_attributes(path_attributes)
+            )?;
+        }
+        for (network, peer, path_attributes) in &self.removed {
+            writeln!(
+                f,
+                "- {} from {}: {}",
+                **network,
+                peer,
+                format_path_attributes(path_attributes)
+            )?;
+        }
+        for route in &self.changed {
+            writeln!(
+                f,
+                "~ {} from {}: {} -> {}",
+                *route.network,
+                route.peer,
+                format_path_attributes(&route.old),
+                format_path_attributes(&route.new)
+            )?;
+        }
+        writeln!(
+            f,
+            "added {}, removed {}, changed {}",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mrt::MrtPeer;
+    use crate::path_attribute::{AsPath, Origin};
+    use crate::routing::RibEntry;
+
+    fn dump(routes: &[(&str, u32)]) -> TableDump {
+        let mut dump = TableDump::new("127.0.0.1".parse().unwrap(), "loc-rib");
+        let peer_index = dump.add_peer(MrtPeer {
+            bgp_id: "127.0.0.1".parse().unwrap(),
+            ip: "127.0.0.1".parse().unwrap(),
+            as_number: 64512.into(),
+        });
+        for (network, as_number) in routes {
+            dump.add_route(
+                peer_index,
+                &RibEntry::for_test(network).with_path_attributes(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::AsSequence(vec![(*as_number).into()])),
+                ]),
+            );
+        }
+        dump
+    }
+
+    #[test]
+    fn diff_reports_added_removed_and_changed_prefixes() {
+        let old = RibSnapshot::from(&dump(&[
+            ("10.100.210.0/24", 64513),
+            ("10.100.220.0/24", 64513),
+            ("10.100.230.0/24", 64513),
+        ]));
+        let new = RibSnapshot::from(&dump(&[
+            ("10.100.220.0/24", 64513),
+            ("10.100.230.0/24", 64514),
+            ("10.100.240.0/24", 64513),
+        ]));
+
+        let diff = RibDiff::new(&old, &new);
+
+        assert_eq!(
+            diff.to_string(),
+            "+ 10.100.240.0/24 from 127.0.0.1: origin=Igp as-path=[64513]\n\
+             - 10.100.210.0/24 from 127.0.0.1: origin=Igp as-path=[64513]\n\
+             ~ 10.100.230.0/24 from 127.0.0.1: origin=Igp as-path=[64513] -> origin=Igp as-path=[64514]\n\
+             added 1, removed 1, changed 1\n"
+        );
+        assert!(RibDiff::new(&old, &old).is_empty());
+    }
+}
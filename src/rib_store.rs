@@ -0,0 +1,192 @@
+use crate::log_error;
+use crate::routing::{Ipv4Network, RibEntry};
+use anyhow::{Context, Result};
+use bytes::BytesMut;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// LocRibのルートを保存する先。
+/// LocRibはルートを変更するたびにここへ書き込み、起動時にはここから前回のルートを読み込む。
+pub trait RibStore: fmt::Debug + Send + Sync {
+    fn insert(&self, route: &RibEntry) -> Result<()>;
+    fn remove(&self, network_address: &Ipv4Network) -> Result<()>;
+    /// 保存されているすべてのルートを読み出す。
+    fn load(&self) -> Result<Vec<RibEntry>>;
+}
+
+/// `rib-store`で指定したディレクトリに、ディスク上のRibStoreを作成する。
+#[cfg(feature = "persistent-rib")]
+pub fn open(path: &Path) -> Result<Arc<dyn RibStore>> {
+    Ok(Arc::new(SledRibStore::open(path)?))
+}
+
+#[cfg(not(feature = "persistent-rib"))]
+pub fn open(path: &Path) -> Result<Arc<dyn RibStore>> {
+    Err(anyhow::anyhow!(
+        "{:?}にRIBを保存するには、persistent-rib featureを有効にしてビルドする必要があります。",
+        path
+    ))
+}
+
+/// ルートを保存するときのkey。NLRIと同じbytes表現を使う。
+fn key(network_address: &Ipv4Network) -> BytesMut {
+    network_address.into()
+}
+
+/// プロセスのメモリ上にルートを保存するRibStore。テストで使う。
+#[derive(Debug, Default)]
+pub struct MemoryRibStore(std::sync::Mutex<BTreeMap<Vec<u8>, Vec<u8>>>);
+
+impl MemoryRibStore {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl RibStore for MemoryRibStore {
+    fn insert(&self, route: &RibEntry) -> Result<()> {
+        let value: BytesMut = route.into();
+        self.0
+            .lock()
+            .unwrap()
+            .insert(key(&route.network_address).to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, network_address: &Ipv4Network) -> Result<()> {
+        self.0.lock().unwrap().remove(&key(network_address)[..]);
+        Ok(())
+    }
+
+    fn load(&self) -> Result<Vec<RibEntry>> {
+        self.0
+            .lock()
+            .unwrap()
+            .values()
+            .map(|v| Ok(RibEntry::try_from(&v[..])?))
+            .collect()
+    }
+}
+
+/// sledを使い、ディスク上にルートを保存するRibStore。
+#[cfg(feature = "persistent-rib")]
+#[derive(Debug)]
+pub struct SledRibStore(sled::Db);
+
+#[cfg(feature = "persistent-rib")]
+impl SledRibStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = sled::open(path).context(format!("{:?}のRIBを開けませんでした。", path))?;
+        Ok(Self(db))
+    }
+}
+
+#[cfg(feature = "persistent-rib")]
+impl RibStore for SledRibStore {
+    fn insert(&self, route: &RibEntry) -> Result<()> {
+        let value: BytesMut = route.into();
+        self.0
+            .insert(&key(&route.network_address)[..], &value[..])
+            .context("RIBにルートを書き込めませんでした。")?;
+        Ok(())
+    }
+
+    fn remove(&self, network_address: &Ipv4Network) -> Result<()> {
+        self.0
+            .remove(&key(network_address)[..])
+            .context("RIBからルートを削除できませんでした。")?;
+        Ok(())
+    }
+
+    fn load(&self) -> Result<Vec<RibEntry>> {
+        self.0
+            .iter()
+            .values()
+            .map(|v| {
+                let v = v.context("RIBからルートを読み込めませんでした。")?;
+                Ok(RibEntry::try_from(&v[..])?)
+            })
+            .collect()
+    }
+}
+
+/// LocRibに設定したRibStore。
+/// LocRibの比較やcloneのために、RibStore自体ではなく同じRibStoreを指しているかで比較する。
+#[derive(Debug, Clone, Default)]
+pub struct AttachedRibStore(Option<Arc<dyn RibStore>>);
+
+impl AttachedRibStore {
+    pub fn new(store: Arc<dyn RibStore>) -> Self {
+        Self(Some(store))
+    }
+
+    /// 書き込みに失敗してもLocRibの処理は続けるため、エラーは出力するだけにする。
+    pub fn insert(&self, route: &RibEntry) {
+        if let Some(store) = &self.0 {
+            if let Err(e) = store.insert(route) {
+                log_error!("{:?}", e);
+            }
+        }
+    }
+
+    pub fn remove(&self, network_address: &Ipv4Network) {
+        if let Some(store) = &self.0 {
+            if let Err(e) = store.remove(network_address) {
+                log_error!("{:?}", e);
+            }
+        }
+    }
+}
+
+impl PartialEq for AttachedRibStore {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for AttachedRibStore {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routing::{AdjRibIn, LocRib};
+
+    #[test]
+    fn originated_routes_survive_restart() {
+        let store: Arc<dyn RibStore> = Arc::new(MemoryRibStore::new());
+        let mut loc_rib = LocRib::empty();
+        loc_rib.attach_store(Arc::clone(&store)).unwrap();
+        loc_rib.originate(
+            "10.100.210.0/24".parse().unwrap(),
+            "10.200.100.2".parse().unwrap(),
+            vec![],
+        );
+        loc_rib.originate(
+            "10.100.211.0/24".parse().unwrap(),
+            "10.200.100.2".parse().unwrap(),
+            vec![],
+        );
+        loc_rib.withdraw_originated(&"10.100.211.0/24".parse().unwrap());
+        loc_rib.install_from_adj_rib_in(&mut AdjRibIn::from(vec![RibEntry::for_test(
+            "10.100.220.0/24",
+        )]));
+        let originated = loc_rib
+            .get(&"10.100.210.0/24".parse().unwrap())
+            .unwrap()
+            .clone();
+
+        // 再起動後のLocRibは、保存されていたルートのうち自分が広告していたルートだけを読み込む。
+        // ピアから学習したルートは、取り消されても消せないため読み込まない。
+        let mut restarted = LocRib::empty();
+        restarted.attach_store(Arc::clone(&store)).unwrap();
+
+        assert_eq!(restarted.iter().collect::<Vec<_>>(), vec![&originated]);
+        assert_eq!(store.load().unwrap(), vec![originated]);
+    }
+}
@@ -1,88 +1,668 @@
-use std::net::{IpAddr, Ipv4Addr};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::Ipv4Addr;
 use std::ops::{Deref, DerefMut};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
 
-use crate::bgp_type::AutonomousSystemNumber;
+use crate::aspa::AspaState;
+use crate::bgp_type::{AddressFamily, AutonomousSystemNumber};
+pub use crate::bgp_type::{Ipv4Network, MAXIMUM_PREFIX_LENGTH};
 use crate::config::Config;
-use crate::error::ConfigParseError;
-use crate::path_attribute::{AsPath, Origin, PathAttribute};
+use crate::error::{ConfigParseError, ConvertBytesToBgpMessageError};
+use crate::fib::{self, AttachedFib, Fib, NoopFib};
+use crate::memory::MemoryUsage;
+use crate::packets::header::Header;
+use crate::packets::update::UpdateMessage;
+use crate::path_attribute::{AsPath, Community, Origin, PathAttribute, PathAttributeTable};
+use crate::prefix_trie::PrefixTrie;
+use crate::rib_store::{AttachedRibStore, RibStore};
+use crate::update_group::NegotiatedCapabilities;
 use anyhow::{Context, Result};
 use bytes::{BufMut, BytesMut};
-use futures::stream::{Next, TryStreamExt};
-use rtnetlink::{new_connection, Handle, IpVersion};
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
-pub struct Ipv4Network(ipnetwork::Ipv4Network);
+// linux/rtnetlink.hで定義されている、rtm_protocolとテーブルIDの値。
+// Linux以外の環境でもビルドできるように、rtnetlinkに依存せずここで定義する。
+const RTPROT_KERNEL: u8 = 2;
+const RTPROT_BOOT: u8 = 3;
+const RTPROT_STATIC: u8 = 4;
+const RTPROT_BGP: u8 = 186;
+const RT_TABLE_DEFAULT: u32 = 253;
+const RT_TABLE_MAIN: u32 = 254;
+const RT_TABLE_LOCAL: u32 = 255;
 
-impl Deref for Ipv4Network {
-    type Target = ipnetwork::Ipv4Network;
+/// カーネルのルーティングテーブル上で、どのルーティングプロトコルが
+/// 書き込んだルートであるかを表す値(rtm_protocol)。
+/// staticやOSPFのルートと区別できるように、デフォルトでは`bgp`を使用する。
+/// カーネルや管理者が書き込むルートと区別できなくなるため、kernel、boot、staticまでの予約された値は使えない。
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct RouteProtocol(u8);
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+impl Default for RouteProtocol {
+    fn default() -> Self {
+        RouteProtocol(RTPROT_BGP)
     }
 }
 
-impl DerefMut for Ipv4Network {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+impl From<RouteProtocol> for u8 {
+    fn from(protocol: RouteProtocol) -> u8 {
+        protocol.0
     }
 }
 
-impl From<ipnetwork::Ipv4Network> for Ipv4Network {
-    fn from(ip_network: ipnetwork::Ipv4Network) -> Self {
-        Self(ip_network)
+impl FromStr for RouteProtocol {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let protocol = match s {
+            "bgp" => RTPROT_BGP,
+            "static" => RTPROT_STATIC,
+            "boot" => RTPROT_BOOT,
+            "kernel" => RTPROT_KERNEL,
+            n => n
+                .parse::<u8>()
+                .context(format!("cannot parse `{n}` as route protocol"))?,
+        };
+        if protocol <= RTPROT_STATIC {
+            return Err(ConfigParseError::OutOfRange {
+                key: "route-protocol",
+                expected: format!("kernel, boot, static以外({}より大きい値)", RTPROT_STATIC),
+            });
+        }
+        Ok(Self(protocol))
     }
 }
 
-impl From<&Ipv4Network> for BytesMut {
-    fn from(network: &Ipv4Network) -> BytesMut {
-        let prefix = network.prefix();
+/// ルートを読み書きするカーネルのルーティングテーブルのID。
+/// ポリシールーティングやVRFで使うテーブルを指定できるように、デフォルトの`main`以外も指定できる。
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct RouteTable(u32);
 
-        let n = network.network().octets();
-        let network_bytes = match prefix {
-            0 => vec![],
-            1..9 => n[0..1].into(),
-            9..17 => n[0..2].into(),
-            17..25 => n[0..3].into(),
-            25..33 => n[0..4].into(),
-            _ => panic!("prefixが0..32の間ではありません！"),
-        };
-        let mut bytes = BytesMut::new();
-        bytes.put_u8(prefix);
-        bytes.put(&network_bytes[..]);
-        bytes
+impl Default for RouteTable {
+    fn default() -> Self {
+        RouteTable(RT_TABLE_MAIN)
     }
 }
 
-impl FromStr for Ipv4Network {
+impl From<RouteTable> for u32 {
+    fn from(table: RouteTable) -> u32 {
+        table.0
+    }
+}
+
+impl FromStr for RouteTable {
     type Err = ConfigParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let network = s
-            .parse::<ipnetwork::Ipv4Network>()
-            .context("s: {:?}を、Ipv4Networkにparse出来ませんでした")?;
-        Ok(Self(network))
+        let table = match s {
+            "main" => RT_TABLE_MAIN,
+            "default" => RT_TABLE_DEFAULT,
+            "local" => RT_TABLE_LOCAL,
+            n => n
+                .parse::<u32>()
+                .context(format!("cannot parse `{n}` as route table"))?,
+        };
+        Ok(Self(table))
     }
 }
 
-impl Ipv4Network {
-    pub fn bytes_len(&self) -> usize {
-        match self.prefix() {
-            0..9 => 2,
-            9..17 => 3,
-            17..25 => 4,
-            25..33 => 5,
-            _ => panic!("prefixが0..32の間ではありません！"),
-        }
-    }
-}
+/// LOCAL_PREFが付いていないルートの、ベストパスの選択で使うLOCAL_PREF。
+pub const DEFAULT_LOCAL_PREF: u32 = 100;
+
+/// メンテナンス中にeBGPのピアへ広告するルートのAS Pathに、追加で自AS番号を並べる数。
+const MAINTENANCE_AS_PATH_PREPEND: usize = 3;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
-pub struct LocRib(Vec<RibEntry>);
+pub struct LocRib {
+    /// AFI/SAFIごとのルートのテーブル。各ネットワークのベストパスを持つ。
+    tables: BTreeMap<AddressFamily, PrefixTrie<RibEntry>>,
+    /// AFI/SAFIとネットワークごとの、ベストパスの候補。ピアごとに1つずつ、
+    /// 自分が広告しているルートも含めて到着した順に持つ。
+    /// 候補が変わるたびに、この中からベストパスを選び直してtablesに入れる。
+    candidates: BTreeMap<AddressFamily, BTreeMap<Ipv4Network, Vec<RibEntry>>>,
+    /// LocRibが変更されるたびに増える値。
+    /// LocRibから生成したデータが最新であるかの判定に使う。
+    version: u64,
+    /// 各ピアのAdjRibInとLocRibで共有するPath Attributeの組。
+    path_attribute_table: PathAttributeTable,
+    /// IPv6 Unicastのテーブル。カーネルのルーティングテーブルに存在した、広告するIPv6のネットワーク。
+    /// RibEntryがIPv6のNLRIを持てるようになるまでは、ネットワークだけを持ちUpdateMessageでは広告しない。
+    ipv6_routes: Vec<ipnetwork::Ipv6Network>,
+    /// ピアから学習したルートの保存先。設定されていればルートを変更するたびに書き込む。
+    store: AttachedRibStore,
+    /// ルートを読み書きするカーネルのルーティングテーブル。
+    fib: AttachedFib,
+    /// カーネルのルーティングテーブルへの書き込みを前回予約してから、
+    /// ベストパスが変わったIPv4 Unicastのネットワーク。
+    fib_changes: BTreeSet<Ipv4Network>,
+    /// メンテナンス中であるか。メンテナンス中は広告するルートの優先度を下げる。
+    maintenance: bool,
+    /// 起動直後のupdate-delayの間であるか。この間はルートを受信するだけで、
+    /// UpdateMessageの送信とカーネルのルーティングテーブルへの書き込みを行わない。
+    read_only: bool,
+    /// HAのSecondaryが処理を引き継いだ時刻。これより前に受信したルートは、Primaryから同期したものである。
+    replicated_since: Option<SystemTime>,
+    /// 処理を引き継いだ時点でPrimaryから同期していた、ピアごとの学習したルートのネットワーク。
+    replicated: BTreeMap<Ipv4Addr, BTreeSet<Ipv4Network>>,
+}
 
 impl LocRib {
+    /// カーネルのルーティングテーブルを参照せずに、空のLocRibを作成する。
+    pub fn empty() -> Self {
+        Self {
+            tables: BTreeMap::new(),
+            candidates: BTreeMap::new(),
+            version: 0,
+            path_attribute_table: PathAttributeTable::new(),
+            ipv6_routes: vec![],
+            store: AttachedRibStore::default(),
+            fib: AttachedFib::new(Arc::new(NoopFib)),
+            fib_changes: BTreeSet::new(),
+            maintenance: false,
+            read_only: false,
+            replicated_since: None,
+            replicated: BTreeMap::new(),
+        }
+    }
+
+    /// storeに保存されているルートを読み込み、以降のルートの変更をstoreに書き込む。
+    /// 設定から作成した自分のネットワークのルートは、保存されていたルートより優先する。
+    /// 読み込むのは自分が広告していたルートだけで、ピアから学習したルートは読み込まずに
+    /// storeから削除する。学習したルートはピアが取り消しても消せないため、
+    /// セッションを張り直して受信し直すまで待つ。
+    /// storeにはIPv4 Unicastのルートだけを保存している。
+    pub fn attach_store(&mut self, store: Arc<dyn RibStore>) -> Result<()> {
+        let family = AddressFamily::IPV4_UNICAST;
+        for mut route in store.load()? {
+            if self.get(&route.network_address).is_some() {
+                continue;
+            }
+            if !route.is_originated_locally() {
+                store.remove(&route.network_address)?;
+                continue;
+            }
+            route.path_attributes = self.path_attribute_table.intern(&route.path_attributes);
+            let network = route.network_address;
+            self.add_candidate(family, route);
+            self.select_best_path(family, network);
+        }
+        self.store = AttachedRibStore::new(store);
+        self.version += 1;
+        Ok(())
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn in_maintenance(&self) -> bool {
+        self.maintenance
+    }
+
+    /// メンテナンスを開始、終了する。
+    /// すべてのピアのAdjRibOutを作り直させるため、変わった場合はversionを進める。
+    pub fn set_maintenance(&mut self, maintenance: bool) {
+        if self.maintenance != maintenance {
+            self.maintenance = maintenance;
+            self.version += 1;
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// HAのSecondaryが処理を引き継ぐときに、Primaryから同期したピアのルートをstaleとする。
+    /// LLGRと同じく、ピアから受信し直すまではそのまま使い、flush_replicated_routesで削除する。
+    pub fn mark_replicated_routes_stale(&mut self) {
+        self.replicated_since = Some(SystemTime::now());
+        let candidates = self.candidates.get(&AddressFamily::IPV4_UNICAST);
+        for path in candidates.into_iter().flat_map(|c| c.values().flatten()) {
+            if let Some(peer) = path.source_peer() {
+                self.replicated
+                    .entry(peer)
+                    .or_default()
+                    .insert(path.network_address);
+            }
+        }
+    }
+
+    /// Primaryから同期したpeerのルートのうち、adj_rib_inにないルートを削除する。
+    /// peerからEnd-of-RIBを受信したときに、受信し直さなかったルートを削除するために使う。
+    /// 削除したルートを返す。
+    pub fn flush_replicated_routes_of(
+        &mut self,
+        peer: Ipv4Addr,
+        adj_rib_in: &AdjRibIn,
+    ) -> Vec<RibEntry> {
+        let networks = self.replicated.remove(&peer).unwrap_or_default();
+        let networks = networks
+            .into_iter()
+            .filter(|network| !adj_rib_in.contains(AddressFamily::IPV4_UNICAST, network));
+        self.flush_replicated(peer, networks.collect())
+    }
+
+    /// Primaryから同期したルートのうち、処理を引き継いだ後にピアから受信し直していないルートをすべて削除する。
+    /// 削除したルートを返す。
+    pub fn flush_replicated_routes(&mut self) -> Vec<RibEntry> {
+        let mut removed = vec![];
+        for (peer, networks) in std::mem::take(&mut self.replicated) {
+            removed.extend(self.flush_replicated(peer, networks));
+        }
+        self.replicated_since = None;
+        removed
+    }
+
+    /// networksの、処理を引き継ぐ前に受信していたpeerの候補を削除し、ベストパスを選び直す。
+    fn flush_replicated(
+        &mut self,
+        peer: Ipv4Addr,
+        networks: BTreeSet<Ipv4Network>,
+    ) -> Vec<RibEntry> {
+        let Some(since) = self.replicated_since else {
+            return vec![];
+        };
+        let family = AddressFamily::IPV4_UNICAST;
+        let mut removed = vec![];
+        for network in networks {
+            let replicated = |path: &RibEntry| matches!(path.provenance, Some(p) if p.peer == peer && p.received_at < since);
+            if self.remove_candidates(family, &network, |path| !replicated(path)) {
+                removed.extend(self.select_best_path(family, network));
+            }
+        }
+        self.path_attribute_table.remove_unused();
+        removed
+    }
+
+    /// update-delayを開始、終了する。終了したときは、受信済みのルートで
+    /// すべてのピアのAdjRibOutを作り直させるため、versionを進める。
+    pub fn set_read_only(&mut self, read_only: bool) {
+        if self.read_only != read_only {
+            self.read_only = read_only;
+            self.version += 1;
+        }
+    }
+
+    /// すべてのAFI/SAFIのルートの数。
+    pub fn len(&self) -> usize {
+        self.tables.values().map(|table| table.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// ルートを持つAFI/SAFI。
+    pub fn families(&self) -> impl Iterator<Item = AddressFamily> + '_ {
+        self.tables
+            .iter()
+            .filter(|(_, table)| !table.is_empty())
+            .map(|(family, _)| *family)
+    }
+
+    /// AFI/SAFIの順に、各テーブルの中ではネットワークアドレスの順にルートを返す。
+    pub fn iter(&self) -> impl Iterator<Item = &RibEntry> {
+        self.tables
+            .values()
+            .flat_map(|table| table.iter().map(|(_, entry)| entry))
+    }
+
+    /// familyのテーブルのルートを、ネットワークアドレスの順に返す。
+    pub fn routes(&self, family: AddressFamily) -> impl Iterator<Item = &RibEntry> {
+        self.tables
+            .get(&family)
+            .into_iter()
+            .flat_map(|table| table.iter().map(|(_, entry)| entry))
+    }
+
+    /// IPv4 Unicastのルートのうち、network_addressと完全に一致するルートを返す。
+    pub fn get(&self, network_address: &Ipv4Network) -> Option<&RibEntry> {
+        self.tables
+            .get(&AddressFamily::IPV4_UNICAST)?
+            .get(network_address)
+    }
+
+    /// IPv4 Unicastのnetworkのルートの候補に、ピアから学習したルートがあるか。
+    pub fn has_learned_route(&self, network_address: &Ipv4Network) -> bool {
+        matches!(
+            self.candidates
+                .get(&AddressFamily::IPV4_UNICAST)
+                .and_then(|candidates| candidates.get(network_address)),
+            Some(paths) if paths.iter().any(|path| !path.is_originated_locally())
+        )
+    }
+
+    /// IPv4 Unicastのnetworkのルートの候補に、自分が広告しているルートがあるか。
+    pub fn has_originated_route(&self, network_address: &Ipv4Network) -> bool {
+        matches!(
+            self.candidates
+                .get(&AddressFamily::IPV4_UNICAST)
+                .and_then(|candidates| candidates.get(network_address)),
+            Some(paths) if paths.iter().any(|path| path.is_originated_locally())
+        )
+    }
+
+    /// IPv4 Unicastのルートのうち、addressを含みprefix長が最も長いルートを返す。
+    pub fn lookup(&self, address: Ipv4Addr) -> Option<&RibEntry> {
+        self.tables
+            .get(&AddressFamily::IPV4_UNICAST)?
+            .longest_match(address)
+            .map(|(_, entry)| entry)
+    }
+
+    pub fn ipv6_routes(&self) -> &[ipnetwork::Ipv6Network] {
+        &self.ipv6_routes
+    }
+
+    /// LocRibとAdjRibInで共有しているPath Attributeの組の数。
+    pub fn path_attribute_sets(&self) -> usize {
+        self.path_attribute_table.len()
+    }
+
+    /// すべてのAFI/SAFIのルートのメモリ使用量。
+    /// Path Attributeの組は共有しているため、path_attribute_memory_usageで別に数える。
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage::of::<RibEntry>(self.len())
+    }
+
+    /// LocRibとAdjRibInで共有しているPath Attributeの組のメモリ使用量。
+    pub fn path_attribute_memory_usage(&self) -> MemoryUsage {
+        MemoryUsage::new(
+            self.path_attribute_table.len(),
+            self.path_attribute_table.estimated_bytes(),
+        )
+    }
+
+    /// AdjRibInのルートをベストパスの候補に加え、そのネットワークのベストパスを選び直す。
+    /// 同じピアから学習した候補は、より悪いルートであっても置き換える。
+    /// AdjRibInのPath Attributeは、同じ内容の組を他のピアのルートと共有するように置き換える。
+    /// ルートはAdjRibInと同じAFI/SAFIのテーブルに追加する。
+    pub fn install_from_adj_rib_in(&mut self, adj_rib_in: &mut AdjRibIn) {
+        self.intern_path_attributes(adj_rib_in);
+        for (family, entries) in &adj_rib_in.0 {
+            for entry in entries.values() {
+                self.add_candidate(*family, entry.clone());
+                self.select_best_path(*family, entry.network_address);
+            }
+        }
+        self.path_attribute_table.remove_unused();
+    }
+
+    /// peerから学習した候補を、peerのAdjRibInの今の内容に合わせる。
+    /// AdjRibInからなくなったネットワークの候補は取り消されたものとして削除し、
+    /// 変わった候補のネットワークのベストパスを選び直す。
+    /// どのピアの候補もなくなり、LocRibから削除したルートを返す。
+    pub fn update_from_adj_rib_in(
+        &mut self,
+        peer: Ipv4Addr,
+        adj_rib_in: &mut AdjRibIn,
+    ) -> Vec<RibEntry> {
+        self.intern_path_attributes(adj_rib_in);
+        let mut changed = BTreeSet::new();
+        for (family, candidates) in self.candidates.iter_mut() {
+            for (network, paths) in candidates.iter_mut() {
+                if adj_rib_in.contains(*family, network) {
+                    continue;
+                }
+                let before = paths.len();
+                paths.retain(|path| path.source_peer() != Some(peer));
+                if paths.len() != before {
+                    changed.insert((*family, *network));
+                }
+            }
+        }
+        for (family, entries) in &adj_rib_in.0 {
+            for entry in entries.values() {
+                if self.add_candidate(*family, entry.clone()) {
+                    changed.insert((*family, entry.network_address));
+                }
+            }
+        }
+        let removed = changed
+            .into_iter()
+            .filter_map(|(family, network)| self.select_best_path(family, network))
+            .collect();
+        self.path_attribute_table.remove_unused();
+        removed
+    }
+
+    /// peerから学習したIPv4 Unicastの候補のうち、networksのものだけをpeerのAdjRibInの今の内容に合わせる。
+    /// 受信したUpdateMessageに含まれていたネットワークだけを渡すことで、
+    /// LocRibやAdjRibInのルートの数によらずに反映できる。
+    /// どのピアの候補もなくなり、LocRibから削除したルートを返す。
+    pub fn update_networks_from_adj_rib_in<'a>(
+        &mut self,
+        peer: Ipv4Addr,
+        adj_rib_in: &mut AdjRibIn,
+        networks: impl IntoIterator<Item = &'a Ipv4Network>,
+    ) -> Vec<RibEntry> {
+        let family = AddressFamily::IPV4_UNICAST;
+        let mut removed = vec![];
+        for network in networks {
+            let changed = match adj_rib_in.get_mut(family, network) {
+                Some(entry) => {
+                    entry.path_attributes =
+                        self.path_attribute_table.intern(&entry.path_attributes);
+                    self.add_candidate(family, entry.clone())
+                }
+                None => {
+                    self.remove_candidates(family, network, |path| path.source_peer() != Some(peer))
+                }
+            };
+            if changed {
+                removed.extend(self.select_best_path(family, *network));
+            }
+        }
+        self.path_attribute_table.remove_unused();
+        removed
+    }
+
+    /// AdjRibInのPath Attributeを、同じ内容の組を他のピアのルートと共有するように置き換える。
+    fn intern_path_attributes(&mut self, adj_rib_in: &mut AdjRibIn) {
+        for (_, entries) in adj_rib_in.0.iter_mut() {
+            for entry in entries.values_mut() {
+                entry.path_attributes = self.path_attribute_table.intern(&entry.path_attributes);
+            }
+        }
+    }
+
+    /// pathをそのネットワークのベストパスの候補に加える。同じピアから学習した候補があれば置き換える。
+    /// 候補が変わった場合はtrueを返す。同じ内容で受信し直しただけのルートは、変わったものとしない。
+    fn add_candidate(&mut self, family: AddressFamily, path: RibEntry) -> bool {
+        let paths = self
+            .candidates
+            .entry(family)
+            .or_default()
+            .entry(path.network_address)
+            .or_default();
+        match paths
+            .iter_mut()
+            .find(|p| p.source_peer() == path.source_peer())
+        {
+            Some(p) if p.is_same_route(&path) => {
+                *p = path;
+                false
+            }
+            Some(p) => {
+                *p = path;
+                true
+            }
+            None => {
+                paths.push(path);
+                true
+            }
+        }
+    }
+
+    /// networkの候補のうち、keepがfalseを返した候補を削除する。削除した場合はtrueを返す。
+    fn remove_candidates<F>(
+        &mut self,
+        family: AddressFamily,
+        network: &Ipv4Network,
+        keep: F,
+    ) -> bool
+    where
+        F: FnMut(&RibEntry) -> bool,
+    {
+        match self
+            .candidates
+            .get_mut(&family)
+            .and_then(|candidates| candidates.get_mut(network))
+        {
+            Some(paths) => {
+                let before = paths.len();
+                paths.retain(keep);
+                paths.len() != before
+            }
+            None => false,
+        }
+    }
+
+    /// networkの候補からベストパスを選び直し、tablesとstoreに反映する。
+    /// 同じ優先度の候補が複数あれば、先に到着した候補を選ぶ。
+    /// ベストパスが変わった場合はversionを進める。候補がなくなったために削除したルートを返す。
+    fn select_best_path(
+        &mut self,
+        family: AddressFamily,
+        network: Ipv4Network,
+    ) -> Option<RibEntry> {
+        let candidates = self.candidates.entry(family).or_default();
+        let best = candidates.get(&network).and_then(|paths| {
+            paths
+                .iter()
+                .fold(None, |best: Option<&RibEntry>, path| match best {
+                    Some(best) if !path.is_better_than(best) => Some(best),
+                    _ => Some(path),
+                })
+                .cloned()
+        });
+        if best.is_none() {
+            candidates.remove(&network);
+        }
+        let table = self.tables.entry(family).or_default();
+        match (table.get(&network), best.as_ref()) {
+            (None, None) => return None,
+            // 同じ内容で受信し直したルートは、受信した時刻だけを更新する。
+            (Some(current), Some(best)) if current.is_same_route(best) => {
+                table.insert(network, best.clone());
+                return None;
+            }
+            _ => (),
+        }
+        self.version += 1;
+        if family == AddressFamily::IPV4_UNICAST {
+            self.fib_changes.insert(network);
+        }
+        match best {
+            Some(best) => {
+                self.store.insert(&best);
+                table.insert(network, best);
+                None
+            }
+            None => {
+                self.store.remove(&network);
+                table.remove(&network)
+            }
+        }
+    }
+
+    /// LocRibのIPv4 Unicastのルートをすべてroutesで置き換える。
+    /// HAのSecondaryが、Primaryから受信したLocRibの内容を反映するのに使う。
+    pub fn replace_routes(&mut self, routes: Vec<RibEntry>) {
+        let table = self.tables.entry(AddressFamily::IPV4_UNICAST).or_default();
+        for (network_address, _) in table.iter() {
+            self.store.remove(network_address);
+            self.fib_changes.insert(*network_address);
+        }
+        *table = PrefixTrie::new();
+        let candidates = self
+            .candidates
+            .entry(AddressFamily::IPV4_UNICAST)
+            .or_default();
+        candidates.clear();
+        for mut entry in routes {
+            entry.path_attributes = self.path_attribute_table.intern(&entry.path_attributes);
+            self.store.insert(&entry);
+            self.fib_changes.insert(entry.network_address);
+            candidates.insert(entry.network_address, vec![entry.clone()]);
+            table.insert(entry.network_address, entry);
+        }
+        self.path_attribute_table.remove_unused();
+        self.version += 1;
+    }
+
+    /// LocRibのIPv4 Unicastのルートのうち、withdrawnを削除し、routesを追加するか置き換える。
+    /// HAのSecondaryが、Primaryから受信したLocRibの差分を反映するのに使う。
+    pub fn update_routes(&mut self, routes: Vec<RibEntry>, withdrawn: &[Ipv4Network]) {
+        let table = self.tables.entry(AddressFamily::IPV4_UNICAST).or_default();
+        let candidates = self
+            .candidates
+            .entry(AddressFamily::IPV4_UNICAST)
+            .or_default();
+        for network_address in withdrawn {
+            self.store.remove(network_address);
+            self.fib_changes.insert(*network_address);
+            candidates.remove(network_address);
+            table.remove(network_address);
+        }
+        for mut entry in routes {
+            entry.path_attributes = self.path_attribute_table.intern(&entry.path_attributes);
+            self.store.insert(&entry);
+            self.fib_changes.insert(entry.network_address);
+            candidates.insert(entry.network_address, vec![entry.clone()]);
+            table.insert(entry.network_address, entry);
+        }
+        self.path_attribute_table.remove_unused();
+        self.version += 1;
+    }
+
+    /// 自分が広告するルートとして、next_hopとcommunitiesを付けたnetworkのルートを候補に加え、
+    /// ベストパスを選び直す。VIPのように、実行中に広告を始めるルートに使う。
+    pub fn originate(
+        &mut self,
+        network: Ipv4Network,
+        next_hop: Ipv4Addr,
+        communities: Vec<Community>,
+    ) {
+        let mut path_attributes = vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::AsSequence(vec![])),
+            PathAttribute::NextHop(next_hop),
+        ];
+        if !communities.is_empty() {
+            path_attributes.push(PathAttribute::Communities(communities));
+        }
+        let entry = RibEntry {
+            network_address: network,
+            path_attributes: self.path_attribute_table.intern(&Arc::new(path_attributes)),
+            aspa_state: AspaState::default(),
+            weight: 0,
+            provenance: None,
+        };
+        self.add_candidate(AddressFamily::IPV4_UNICAST, entry);
+        self.select_best_path(AddressFamily::IPV4_UNICAST, network);
+        self.path_attribute_table.remove_unused();
+    }
+
+    /// originateで追加したような、自分が広告しているnetworkのルートを削除し、ベストパスを選び直す。
+    /// 自分が広告しているルートがなければ何もせずにfalseを返す。
+    pub fn withdraw_originated(&mut self, network: &Ipv4Network) -> bool {
+        let family = AddressFamily::IPV4_UNICAST;
+        if !self.remove_candidates(family, network, |path| !path.is_originated_locally()) {
+            return false;
+        }
+        self.select_best_path(family, *network);
+        self.path_attribute_table.remove_unused();
+        true
+    }
+
     pub async fn new(config: &Config) -> Result<Self> {
-        let path_attributes = vec![
+        Self::with_fib(config, fib::default_fib()).await
+    }
+
+    /// fibのルーティングテーブルから、広告するネットワークのルートを読み込んでLocRibを作成する。
+    pub async fn with_fib(config: &Config, fib: Arc<dyn Fib>) -> Result<Self> {
+        let mut path_attributes = vec![
             PathAttribute::Origin(Origin::Igp),
             // AS Pathは、ほかのピアから受信したルートと統一的に扱うために、
             // LocRib -> AdjRibOutにルートを送るときに、自分のAS番号を
@@ -90,79 +670,902 @@ impl LocRib {
             PathAttribute::AsPath(AsPath::AsSequence(vec![])),
             PathAttribute::NextHop(config.local_ip),
         ];
+        if let Some(metric) = config.aigp {
+            path_attributes.push(PathAttribute::Aigp(metric));
+        }
+        let path_attributes = Arc::new(path_attributes);
 
-        let mut rib = vec![];
-        for network in &config.networks {
-            let routes = Self::lookup_kernel_routing_table(*network).await?;
-            for route in routes {
-                rib.push(RibEntry {
-                    network_address: route,
-                    path_attributes: path_attributes.clone(),
-                })
-            }
+        let mut rib = PrefixTrie::new();
+        let mut candidates = BTreeMap::new();
+        for route in
+            Self::lookup_kernel_routing_table(&*fib, &config.networks, config.route_table).await?
+        {
+            let entry = RibEntry {
+                network_address: route,
+                path_attributes: path_attributes.clone(),
+                aspa_state: AspaState::default(),
+                weight: 0,
+                provenance: None,
+            };
+            candidates.insert(route, vec![entry.clone()]);
+            rib.insert(route, entry);
         }
-        Ok(Self(rib))
+        let ipv6_routes = Self::lookup_kernel_ipv6_routing_table(
+            &*fib,
+            &config.ipv6_networks,
+            config.route_table,
+        )
+        .await?;
+        Ok(Self {
+            tables: BTreeMap::from([(AddressFamily::IPV4_UNICAST, rib)]),
+            candidates: BTreeMap::from([(AddressFamily::IPV4_UNICAST, candidates)]),
+            version: 0,
+            path_attribute_table: PathAttributeTable::new(),
+            ipv6_routes,
+            store: AttachedRibStore::default(),
+            fib: AttachedFib::new(fib),
+            fib_changes: BTreeSet::new(),
+            maintenance: false,
+            read_only: false,
+            replicated_since: None,
+            replicated: BTreeMap::new(),
+        })
     }
 
+    /// カーネルのルーティングテーブルtableのルートのうち、networksと一致するルートを返す。
     async fn lookup_kernel_routing_table(
-        network_address: Ipv4Network,
-    ) -> Result<(Vec<(Ipv4Network)>)> {
-        let (connection, handle, _) = new_connection()?;
-        tokio::spawn(connection);
-        let mut routes = handle.route().get(IpVersion::V4).execute();
-        let mut results = vec![];
-        while let Some(route) = routes.try_next().await? {
-            let destination = if let Some((IpAddr::V4(addr), prefix)) = route.destination_prefix() {
-                ipnetwork::Ipv4Network::new(addr, prefix)?.into()
-            } else {
-                continue;
-            };
+        fib: &dyn Fib,
+        networks: &[Ipv4Network],
+        table: RouteTable,
+    ) -> Result<Vec<Ipv4Network>> {
+        if networks.is_empty() {
+            return Ok(vec![]);
+        }
+        fib.lookup_ipv4(networks, table).await
+    }
+
+    async fn lookup_kernel_ipv6_routing_table(
+        fib: &dyn Fib,
+        networks: &[ipnetwork::Ipv6Network],
+        table: RouteTable,
+    ) -> Result<Vec<ipnetwork::Ipv6Network>> {
+        if networks.is_empty() {
+            return Ok(vec![]);
+        }
+        fib.lookup_ipv6(networks, table).await
+    }
+
+    /// AdjRibInのルートを学習したピアの候補を削除して、ベストパスを選び直す。
+    /// ほかのピアの候補があるネットワークでは、その候補がベストパスになる。
+    /// どのピアの候補もなくなり、LocRibから削除したルートを返す。
+    /// セッションが切断されたピアから学習したルートを取り除くために使う。
+    pub fn remove_routes_learned_from(&mut self, adj_rib_in: &AdjRibIn) -> Vec<RibEntry> {
+        let mut removed = vec![];
+        for (family, learned_routes) in &adj_rib_in.0 {
+            for learned in learned_routes.values() {
+                let network = learned.network_address;
+                if self.remove_candidates(*family, &network, |path| {
+                    path.source_peer() != learned.source_peer()
+                }) {
+                    removed.extend(self.select_best_path(*family, network));
+                }
+            }
+        }
+        self.path_attribute_table.remove_unused();
+        removed
+    }
+
+    /// セッションが切れたピアから学習したルートを、LLGRでstaleとして保持する。
+    /// AdjRibInと、LocRibにある同じピアの候補にLLGR_STALEを付け、ベストパスを選び直す。
+    /// staleのルートは優先度が最も低いため、ほかのピアの候補があればそちらがベストパスになる。
+    /// NO_LLGRが付いたルートは保持せずに削除し、LocRibから削除したルートを返す。
+    pub fn retain_as_stale(&mut self, adj_rib_in: &mut AdjRibIn) -> Vec<RibEntry> {
+        let mut removed = vec![];
+        for (family, learned_routes) in adj_rib_in.0.iter_mut() {
+            let mut stale_routes = BTreeMap::new();
+            for (_, learned) in std::mem::take(learned_routes) {
+                let network = learned.network_address;
+                if learned.communities().contains(&Community::NO_LLGR) {
+                    self.remove_candidates(*family, &network, |path| {
+                        path.source_peer() != learned.source_peer()
+                    });
+                    removed.extend(self.select_best_path(*family, network));
+                    continue;
+                }
+                let mut stale = learned;
+                stale.add_community(Community::LLGR_STALE);
+                stale.path_attributes = self.path_attribute_table.intern(&stale.path_attributes);
+                self.add_candidate(*family, stale.clone());
+                self.select_best_path(*family, network);
+                stale_routes.insert(network, stale);
+            }
+            *learned_routes = stale_routes;
+        }
+        self.path_attribute_table.remove_unused();
+        self.version += 1;
+        removed
+    }
+
+    /// 前回から変わったネットワークについて、カーネルのルーティングテーブルに書き込むルートを
+    /// fibに予約し、fibを返す。予約したルートは、LocRibのロックを外してからAttachedFib::flushで書き込む。
+    /// ほかのピアから学習したルートだけを書き込み、自分が広告しているルートは
+    /// もともとカーネルのルーティングテーブルから取得したものなので書き込まない。
+    /// backdoorのネットワークのルートも、カーネルのルートを優先するため書き込まない。
+    /// ベストパスがなくなったネットワークや書き込まないルートになったネットワークは、削除するように予約する。
+    /// update-delayの間は何も予約せず、終了した後にまとめて予約する。
+    pub fn stage_kernel_routes(&mut self, config: &Config) -> AttachedFib {
+        if !self.read_only {
+            for network in std::mem::take(&mut self.fib_changes) {
+                let next_hop = self
+                    .get(&network)
+                    .filter(|entry| !entry.is_originated_locally())
+                    .filter(|entry| !config.backdoor_networks.contains(&entry.network_address))
+                    .and_then(|entry| entry.next_hop());
+                self.fib.stage(network, next_hop);
+            }
+        }
+        self.fib.clone()
+    }
+
+    /// 前回の起動で書き込んだまま残っているルートを、このプロセスが書き込んだルートとして引き継ぐ。
+    /// 引き継いだルートは次にカーネルのルーティングテーブルに書き込むときに、学習し直していれば
+    /// そのまま残し、学習し直していなければ削除する。起動してピアを開始する前に呼び出す。
+    pub async fn adopt_kernel_routes(&mut self, config: &Config) -> Result<usize> {
+        let adopted = self.fib.adopt(config).await?;
+        let count = adopted.len();
+        self.fib_changes.extend(adopted);
+        Ok(count)
+    }
+
+    /// stage_kernel_routesで予約したルートを、カーネルのルーティングテーブルに書き込む。
+    pub async fn write_to_kernel_routing_table(&mut self, config: &Config) -> Result<()> {
+        self.stage_kernel_routes(config).flush(config).await
+    }
+}
+
+/// ピアから受信したルートを、AFI/SAFIごとにprefixをkeyとするテーブルで持つ。
+/// UpdateMessageによるルートの置き換えや取り消しを、ルートの数によらずに行える。
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct AdjRibIn(BTreeMap<AddressFamily, BTreeMap<Ipv4Network, RibEntry>>);
+
+/// routesをIPv4 UnicastのルートとするAdjRibIn。同じprefixのルートは後のもので置き換える。
+impl From<Vec<RibEntry>> for AdjRibIn {
+    fn from(routes: Vec<RibEntry>) -> Self {
+        let routes = routes
+            .into_iter()
+            .map(|route| (route.network_address, route))
+            .collect();
+        Self(BTreeMap::from([(AddressFamily::IPV4_UNICAST, routes)]))
+    }
+}
+
+impl AdjRibIn {
+    pub fn new() -> Self {
+        Self(BTreeMap::new())
+    }
+
+    /// すべてのAFI/SAFIのルートの数。
+    pub fn len(&self) -> usize {
+        self.0.values().map(|routes| routes.len()).sum()
+    }
 
-            if destination != network_address {
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// すべてのAFI/SAFIのルートのメモリ使用量。Path AttributeはLocRibと共有しているため含めない。
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage::of::<RibEntry>(self.len())
+    }
+
+    /// familyのテーブルのルートを、ネットワークアドレスの順に返す。
+    pub fn routes(&self, family: AddressFamily) -> impl Iterator<Item = &RibEntry> {
+        self.0
+            .get(&family)
+            .into_iter()
+            .flat_map(|routes| routes.values())
+    }
+
+    /// すべてのAFI/SAFIのルート。
+    pub fn iter(&self) -> impl Iterator<Item = &RibEntry> {
+        self.0.values().flat_map(|routes| routes.values())
+    }
+
+    /// familyのテーブルに、network_addressのルートがあるか。
+    pub fn contains(&self, family: AddressFamily, network_address: &Ipv4Network) -> bool {
+        self.0
+            .get(&family)
+            .is_some_and(|routes| routes.contains_key(network_address))
+    }
+
+    /// familyのテーブルの、network_addressのルート。
+    pub fn get_mut(
+        &mut self,
+        family: AddressFamily,
+        network_address: &Ipv4Network,
+    ) -> Option<&mut RibEntry> {
+        self.0.get_mut(&family)?.get_mut(network_address)
+    }
+
+    /// UpdateMessageのWithdrawn Routesを削除し、NLRIのルートをIPv4 Unicastのテーブルに追加する。
+    /// 既に存在するネットワークのルートは新しいPath Attributeで置き換える。
+    /// Path Attributeが変わっていなければ、置き換えたルートのProvenanceのchanged_atを引き継ぐ。
+    /// importがfalseを返したNLRIは追加せず、破棄した数を返す。
+    /// 破棄したNLRIと同じネットワークのルートは、取り消されたものとして削除する。
+    pub fn install_from_update<F>(&mut self, update: UpdateMessage, mut import: F) -> usize
+    where
+        F: FnMut(&mut RibEntry) -> bool,
+    {
+        let routes = self.0.entry(AddressFamily::IPV4_UNICAST).or_default();
+        let mut previous = HashMap::new();
+        for network in update
+            .withdrawn_routes()
+            .iter()
+            .chain(update.network_layer_reachability_information())
+        {
+            if let Some(RibEntry {
+                provenance: Some(provenance),
+                path_attributes,
+                ..
+            }) = routes.remove(network)
+            {
+                previous.insert(*network, (provenance, path_attributes));
+            }
+        }
+
+        let mut rejected = 0;
+        for mut route in RibEntry::from_update(&update) {
+            let network = route.network_address;
+            if !import(&mut route) {
+                rejected += 1;
                 continue;
             }
+            if let (Some(provenance), Some((old, old_path_attributes))) =
+                (route.provenance.as_mut(), previous.get(&network))
+            {
+                if provenance.peer == old.peer && *old_path_attributes == route.path_attributes {
+                    provenance.changed_at = old.changed_at;
+                }
+            }
+            routes.insert(network, route);
+        }
+        rejected
+    }
+
+    /// import policyを適用する前のルートとして保持していたAdjRibInの各ルートにimportを適用し、
+    /// importがtrueを返したルートからなるAdjRibInを作る。
+    pub fn reimport<F>(&self, mut import: F) -> AdjRibIn
+    where
+        F: FnMut(&mut RibEntry) -> bool,
+    {
+        let mut tables = BTreeMap::new();
+        for (family, routes) in &self.0 {
+            let mut imported = BTreeMap::new();
+            for (network, route) in routes {
+                let mut route = route.clone();
+                if import(&mut route) {
+                    imported.insert(*network, route);
+                }
+            }
+            tables.insert(*family, imported);
+        }
+        Self(tables)
+    }
 
-            results.push((destination));
+    /// LLGRでstaleとして保持しているルートを取り出す。
+    pub fn take_llgr_stale_routes(&mut self) -> AdjRibIn {
+        let mut stale_routes = BTreeMap::new();
+        for (family, routes) in self.0.iter_mut() {
+            let (stale, fresh) = std::mem::take(routes)
+                .into_iter()
+                .partition(|(_, r)| r.is_llgr_stale());
+            *routes = fresh;
+            stale_routes.insert(*family, stale);
         }
-        Ok(results)
+        Self(stale_routes)
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub struct AdjRibOut(pub Vec<RibEntry>);
+/// ピアに広告するルートを、AFI/SAFIごとにprefixをkeyとするテーブルで持つ。
+/// 取り消すルートの判定や同じprefixのルートの置き換えを、ルートの数によらずに行える。
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct AdjRibOut(BTreeMap<AddressFamily, PrefixTrie<RibEntry>>);
+
+/// routesをIPv4 UnicastのルートとするAdjRibOut。同じprefixのルートは後のもので置き換える。
+impl From<Vec<RibEntry>> for AdjRibOut {
+    fn from(routes: Vec<RibEntry>) -> Self {
+        let mut adj_rib_out = Self::new();
+        for route in routes {
+            adj_rib_out.insert(AddressFamily::IPV4_UNICAST, route);
+        }
+        adj_rib_out
+    }
+}
 
 impl AdjRibOut {
     pub fn new() -> Self {
-        Self(vec![])
+        Self(BTreeMap::new())
+    }
+
+    /// すべてのAFI/SAFIのルートの数。
+    pub fn len(&self) -> usize {
+        self.0.values().map(|table| table.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// familyのテーブルのルートを、ネットワークアドレスの順に返す。
+    pub fn routes(&self, family: AddressFamily) -> impl Iterator<Item = &RibEntry> {
+        self.0
+            .get(&family)
+            .into_iter()
+            .flat_map(|table| table.iter().map(|(_, entry)| entry))
+    }
+
+    /// すべてのAFI/SAFIのルート。
+    pub fn iter(&self) -> impl Iterator<Item = &RibEntry> {
+        self.0
+            .values()
+            .flat_map(|table| table.iter().map(|(_, entry)| entry))
+    }
+
+    /// IPv4 Unicastのルートのうち、network_addressと完全に一致するルートを返す。
+    pub fn get(&self, network_address: &Ipv4Network) -> Option<&RibEntry> {
+        self.0
+            .get(&AddressFamily::IPV4_UNICAST)?
+            .get(network_address)
+    }
+
+    /// IPv4 Unicastのテーブルに、network_addressのルートがあるか。
+    pub fn contains(&self, network_address: &Ipv4Network) -> bool {
+        self.get(network_address).is_some()
+    }
+
+    /// advertisedを広告済みのピアに、このAdjRibOutの内容を広告するためのUpdateMessage。
+    /// advertisedにしかないルートを取り消し、追加されたかPath Attributeが変わったルートだけを広告する。
+    pub fn updates_since(&self, advertised: &AdjRibOut) -> Vec<UpdateMessage> {
+        self.updates_for(&self.changed_networks(advertised))
+    }
+
+    /// previousから、追加されたか、取り消されたか、Path Attributeが変わったIPv4 Unicastのネットワーク。
+    pub fn changed_networks(&self, previous: &AdjRibOut) -> Vec<Ipv4Network> {
+        let withdrawn = previous
+            .routes(AddressFamily::IPV4_UNICAST)
+            .filter(|route| !self.contains(&route.network_address));
+        let changed = self.routes(AddressFamily::IPV4_UNICAST).filter(|route| {
+            previous
+                .get(&route.network_address)
+                .is_none_or(|p| p.path_attributes != route.path_attributes)
+        });
+        withdrawn
+            .chain(changed)
+            .map(|route| route.network_address)
+            .collect()
+    }
+
+    /// networksのIPv4 Unicastのルートを、今の内容で広告するためのUpdateMessage。
+    /// このAdjRibOutにないネットワークは取り消す。
+    pub fn updates_for(&self, networks: &[Ipv4Network]) -> Vec<UpdateMessage> {
+        let (announced, withdrawn): (Vec<_>, Vec<_>) = networks
+            .iter()
+            .copied()
+            .partition(|network| self.contains(network));
+        let announced: Vec<RibEntry> = announced
+            .into_iter()
+            .filter_map(|network| self.get(&network).cloned())
+            .collect();
+        let mut updates = UpdateMessage::withdrawals(withdrawn);
+        updates.extend(Vec::<UpdateMessage>::from(&AdjRibOut::from(announced)));
+        updates
+    }
+
+    /// familyのテーブルにrouteを追加する。同じprefixのルートがあれば置き換えて、古いルートを返す。
+    pub fn insert(&mut self, family: AddressFamily, route: RibEntry) -> Option<RibEntry> {
+        self.0
+            .entry(family)
+            .or_default()
+            .insert(route.network_address, route)
+    }
+
+    /// familyのテーブルから、network_addressのルートを削除して返す。
+    pub fn remove(
+        &mut self,
+        family: AddressFamily,
+        network_address: &Ipv4Network,
+    ) -> Option<RibEntry> {
+        let table = self.0.get_mut(&family)?;
+        let removed = table.remove(network_address);
+        if table.is_empty() {
+            self.0.remove(&family);
+        }
+        removed
+    }
+
+    /// LocRibの内容からAdjRibOutを作り直す。ルートはLocRibと同じAFI/SAFIのテーブルに入れる。
+    /// configで交換するとしていないAFI/SAFIのルートは入れない。
+    /// iBGPのピアから学習したルートは、iBGPのピアには広告しない(split horizon)。
+    pub fn install_from_loc_rib(
+        &mut self,
+        loc_rib: &LocRib,
+        config: &Config,
+        negotiated: NegotiatedCapabilities,
+    ) {
+        let routes = Self::export_candidates(loc_rib, config, negotiated);
+        self.install_exported(Self::apply_export_policy(
+            routes,
+            config,
+            loc_rib.in_maintenance(),
+        ));
+    }
+
+    /// LocRibのルートのうちconfigのピアに広告できるものを、export policyを適用する前の形で返す。
+    pub fn export_candidates(
+        loc_rib: &LocRib,
+        config: &Config,
+        negotiated: NegotiatedCapabilities,
+    ) -> Vec<(AddressFamily, RibEntry)> {
+        let mut candidates = vec![];
+        for family in loc_rib.families() {
+            if !config.address_families.contains(&family) {
+                continue;
+            }
+            for r in loc_rib.routes(family) {
+                // LLGRを交渉していないピアは、staleなルートを普通のルートと区別できない。
+                // ignore-well-known-communitiesを設定していても広告しない(RFC 9494)。
+                if r.is_llgr_stale() && !negotiated.llgr {
+                    continue;
+                }
+                if !config.ignore_well_known_communities && !r.may_be_advertised_to(config) {
+                    continue;
+                }
+                if !config.is_ebgp() && r.is_learned_from_ibgp() {
+                    continue;
+                }
+                let mut route = r.clone();
+                // AS内のピアに広告するときは、AS Pathに自AS番号を追加しない。
+                if config.is_ebgp() {
+                    route.append_as_path(config.local_as);
+                }
+                if let Some(next_hop) = route.next_hop() {
+                    route.change_next_hop(config.advertised_next_hop(next_hop));
+                }
+                if !config.aigp_session() {
+                    route.remove_aigp();
+                }
+                // LOCAL_PREFはAS内だけで使う値のため、eBGPのピアには送らない。
+                if config.is_ebgp() {
+                    route.remove_local_pref();
+                }
+                candidates.push((family, route));
+            }
+        }
+        candidates
+    }
+
+    /// export_candidatesで取り出したルートにexport policyを適用し、広告するルートだけを返す。
+    /// in_maintenanceの場合は、広告するルートの優先度を下げる。
+    pub fn apply_export_policy(
+        candidates: Vec<(AddressFamily, RibEntry)>,
+        config: &Config,
+        in_maintenance: bool,
+    ) -> Vec<(AddressFamily, RibEntry)> {
+        candidates
+            .into_iter()
+            .filter_map(|(family, mut route)| {
+                // 自AS番号の追加後にexport policyを適用する。
+                if !config.export_policy.apply(&mut route) {
+                    return None;
+                }
+                if in_maintenance {
+                    route.depreference_for_maintenance(config);
+                }
+                Some((family, route))
+            })
+            .collect()
     }
 
-    pub fn install_from_loc_rib(&mut self, loc_rib: &LocRib, config: &Config) {
-        for r in &loc_rib.0 {
-            let mut route = r.clone();
-            route.append_as_path(config.local_as);
-            route.change_next_hop(config.local_ip);
-            self.0.push(route);
+    /// AdjRibOutの内容をroutesで置き換える。
+    pub fn install_exported(&mut self, routes: Vec<(AddressFamily, RibEntry)>) {
+        self.0.clear();
+        for (family, route) in routes {
+            self.insert(family, route);
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+/// AdjRibOutからUpdateMessageに変換する。
+/// PathAttributeごとにUpdateMessageが分かれるためVec<UpdateMessage>の戻り値にしている。
+/// UpdateMessageのNLRIで広告できるのはIPv4 Unicastのルートだけである。
+impl From<&AdjRibOut> for Vec<UpdateMessage> {
+    fn from(rib: &AdjRibOut) -> Self {
+        let mut hash_map: HashMap<Arc<Vec<PathAttribute>>, Vec<Ipv4Network>> = HashMap::new();
+        for entry in rib.routes(AddressFamily::IPV4_UNICAST) {
+            if let Some(routes) = hash_map.get_mut(&entry.path_attributes) {
+                routes.push(entry.network_address);
+            } else {
+                hash_map.insert(entry.path_attributes.clone(), vec![entry.network_address]);
+            }
+        }
+
+        let mut updates = vec![];
+        for (path_attributes, routes) in hash_map.into_iter() {
+            updates.extend(UpdateMessage::announcements(
+                path_attributes.to_vec(),
+                routes,
+            ));
+        }
+        updates
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub struct RibEntry {
     pub network_address: Ipv4Network,
-    pub path_attributes: Vec<PathAttribute>,
+    /// 同じ内容のPath Attributeの組は複数のRibEntryで共有する。
+    /// 変更するときはArc::make_mutで自分用に複製してから変更する。
+    pub path_attributes: Arc<Vec<PathAttribute>>,
+    /// 受信したときにASPAでAS Pathを検証した結果。
+    pub aspa_state: AspaState,
+    /// ベストパスの選択で最初に比べる、このルータの中だけで使う優先度。大きいほど優先する。
+    /// Path Attributeではないため、どのピアにも広告しない。
+    pub weight: u32,
+    /// ルートをどのピアからいつ受信したか。自分が広告しているルートではNoneである。
+    pub provenance: Option<Provenance>,
+}
+
+/// ルートを学習したピアと、受信した時刻。`show rib`で表示する。
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct Provenance {
+    /// ルートを学習したピアのIPアドレス。
+    pub peer: Ipv4Addr,
+    /// このルートを含むUpdateMessageを最後に受信した時刻。
+    pub received_at: SystemTime,
+    /// Path Attributeが最後に変わった時刻。同じ内容で受信し直しても変わらない。
+    pub changed_at: SystemTime,
+    /// iBGPのピアから学習したか。iBGPのピアから学習したルートは、ほかのiBGPのピアに広告しない。
+    pub internal: bool,
+}
+
+impl Provenance {
+    /// peerから今受信したルートのProvenance。
+    pub fn new(peer: Ipv4Addr) -> Self {
+        let now = SystemTime::now();
+        Self {
+            peer,
+            received_at: now,
+            changed_at: now,
+            internal: false,
+        }
+    }
+}
+
+/// RibEntryのbytes表現。HAの同期やRibStoreへの保存に使う。
+/// [UpdateMessage (NLRIは1つ)]
+/// [Weight (4 octets)]
+/// [ASPA State (1 octet)]
+/// [Provenanceの有無 (1 octet)]
+/// [Peer (4 octets) + Received At (12 octets) + Changed At (12 octets) + Internal (1 octet)]
+///     (Provenanceがある場合のみ)
+/// 時刻はUNIX時間の秒(8 octets)とナノ秒(4 octets)で表す。
+impl From<&RibEntry> for BytesMut {
+    fn from(entry: &RibEntry) -> BytesMut {
+        let update = UpdateMessage::new(
+            entry.path_attributes.to_vec(),
+            vec![entry.network_address],
+            vec![],
+        );
+        let mut bytes: BytesMut = update.into();
+        bytes.put_u32(entry.weight);
+        bytes.put_u8(aspa_state_to_u8(entry.aspa_state));
+        match &entry.provenance {
+            None => bytes.put_u8(0),
+            Some(provenance) => {
+                bytes.put_u8(1);
+                bytes.put(&provenance.peer.octets()[..]);
+                put_system_time(&mut bytes, provenance.received_at);
+                put_system_time(&mut bytes, provenance.changed_at);
+                bytes.put_u8(provenance.internal.into());
+            }
+        }
+        bytes
+    }
+}
+
+/// Provenanceのbytes表現の長さ。
+const PROVENANCE_LENGTH: usize = 4 + 12 + 12 + 1;
+
+fn aspa_state_to_u8(state: AspaState) -> u8 {
+    match state {
+        AspaState::Unknown => 0,
+        AspaState::Valid => 1,
+        AspaState::Invalid => 2,
+    }
+}
+
+fn aspa_state_from_u8(state: u8) -> Result<AspaState, ConvertBytesToBgpMessageError> {
+    match state {
+        0 => Ok(AspaState::Unknown),
+        1 => Ok(AspaState::Valid),
+        2 => Ok(AspaState::Invalid),
+        _ => Err(ConvertBytesToBgpMessageError::InvalidValue {
+            field: "ASPA State",
+            value: state.into(),
+        }),
+    }
+}
+
+fn put_system_time(bytes: &mut BytesMut, time: SystemTime) {
+    let since_epoch = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    bytes.put_u64(since_epoch.as_secs());
+    bytes.put_u32(since_epoch.subsec_nanos());
+}
+
+/// bytesの先頭12 octetsを時刻として読み取る。
+fn get_system_time(bytes: &[u8]) -> SystemTime {
+    let secs = u64::from_be_bytes(bytes[0..8].try_into().unwrap());
+    let nanos = u32::from_be_bytes(bytes[8..12].try_into().unwrap());
+    SystemTime::UNIX_EPOCH + std::time::Duration::new(secs, nanos)
+}
+
+impl TryFrom<&[u8]> for RibEntry {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let update_length = Header::try_from(bytes)?.length() as usize;
+        let update_bytes =
+            bytes
+                .get(..update_length)
+                .ok_or(ConvertBytesToBgpMessageError::Truncated {
+                    field: "RibEntryのUpdateMessage",
+                })?;
+        let update = UpdateMessage::try_from(BytesMut::from(update_bytes))?;
+        let network_address = match update.network_layer_reachability_information()[..] {
+            [network_address] => network_address,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "RibEntryのUpdateMessageには、NLRIが1つだけ含まれている必要があります。"
+                )
+                .into())
+            }
+        };
+        let rest = &bytes[update_length..];
+        let truncated = || ConvertBytesToBgpMessageError::Truncated { field: "RibEntry" };
+        let weight = u32::from_be_bytes(rest.get(0..4).ok_or_else(truncated)?.try_into().unwrap());
+        let aspa_state = aspa_state_from_u8(*rest.get(4).ok_or_else(truncated)?)?;
+        let provenance = match rest.get(5).ok_or_else(truncated)? {
+            0 => None,
+            1 => {
+                let p = rest.get(6..6 + PROVENANCE_LENGTH).ok_or_else(truncated)?;
+                Some(Provenance {
+                    peer: Ipv4Addr::new(p[0], p[1], p[2], p[3]),
+                    received_at: get_system_time(&p[4..16]),
+                    changed_at: get_system_time(&p[16..28]),
+                    internal: p[28] != 0,
+                })
+            }
+            value => {
+                return Err(ConvertBytesToBgpMessageError::InvalidValue {
+                    field: "Provenanceの有無",
+                    value: (*value).into(),
+                })
+            }
+        };
+        Ok(RibEntry {
+            network_address,
+            path_attributes: Arc::new(update.path_attributes().clone()),
+            aspa_state,
+            weight,
+            provenance,
+        })
+    }
 }
 
 impl RibEntry {
+    /// updateのNLRIの、import policyを適用する前のルート。
+    /// 1つのUpdateMessageに含まれるルートは同じPath Attributeの組を共有する。
+    pub fn from_update(update: &UpdateMessage) -> Vec<Self> {
+        let path_attributes = Arc::new(update.path_attributes().clone());
+        update
+            .network_layer_reachability_information()
+            .iter()
+            .map(|network| RibEntry {
+                network_address: *network,
+                path_attributes: Arc::clone(&path_attributes),
+                aspa_state: AspaState::default(),
+                weight: 0,
+                provenance: None,
+            })
+            .collect()
+    }
+
     fn append_as_path(&mut self, as_number: AutonomousSystemNumber) {
-        for path_attribute in &mut self.path_attributes {
+        for path_attribute in Arc::make_mut(&mut self.path_attributes) {
             if let PathAttribute::AsPath(as_path) = path_attribute {
                 as_path.add(as_number)
             };
         }
     }
 
-    fn change_next_hop(&mut self, next_hop: Ipv4Addr) {
-        for path_attribute in &mut self.path_attributes {
+    pub fn prepend_as_path(&mut self, as_numbers: &[AutonomousSystemNumber]) {
+        for path_attribute in Arc::make_mut(&mut self.path_attributes) {
+            if let PathAttribute::AsPath(as_path) = path_attribute {
+                as_path.prepend(as_numbers)
+            };
+        }
+    }
+
+    pub fn as_path(&self) -> Option<&AsPath> {
+        self.path_attributes.iter().find_map(|p| match p {
+            PathAttribute::AsPath(as_path) => Some(as_path),
+            _ => None,
+        })
+    }
+
+    pub fn communities(&self) -> &[Community] {
+        self.path_attributes
+            .iter()
+            .find_map(|p| match p {
+                PathAttribute::Communities(communities) => Some(&communities[..]),
+                _ => None,
+            })
+            .unwrap_or(&[])
+    }
+
+    /// well-known communityに従い、このルートをconfigのピアに広告してよいか。
+    /// Confederationには対応していないため、NO_EXPORT_SUBCONFEDはNO_EXPORTと同じく扱う。
+    fn may_be_advertised_to(&self, config: &Config) -> bool {
+        self.communities().iter().all(|c| match *c {
+            Community::NO_ADVERTISE => false,
+            Community::NO_EXPORT | Community::NO_EXPORT_SUBCONFED => !config.is_ebgp(),
+            _ => true,
+        })
+    }
+
+    /// メンテナンス中に広告するルートを、受信したピアで選ばれにくくする。
+    /// eBGPのピアにはAS Pathを伸ばし、どのピアにもGRACEFUL_SHUTDOWNを付けて
+    /// LOCAL_PREFを下げてもらう。
+    fn depreference_for_maintenance(&mut self, config: &Config) {
+        if config.is_ebgp() {
+            self.prepend_as_path(&[config.local_as; MAINTENANCE_AS_PATH_PREPEND]);
+        }
+        self.add_community(Community::GRACEFUL_SHUTDOWN);
+    }
+
+    /// COMMUNITIESにcommunityを追加する。COMMUNITIESがなければ作成する。
+    pub fn add_community(&mut self, community: Community) {
+        if self.communities().contains(&community) {
+            return;
+        }
+        let path_attributes = Arc::make_mut(&mut self.path_attributes);
+        match path_attributes.iter_mut().find_map(|p| match p {
+            PathAttribute::Communities(communities) => Some(communities),
+            _ => None,
+        }) {
+            Some(communities) => communities.push(community),
+            None => path_attributes.push(PathAttribute::Communities(vec![community])),
+        }
+    }
+
+    /// 受信した時刻のほかは同じルートであるか。同じ内容で受信し直したルートは、変わったものとして扱わない。
+    fn is_same_route(&self, other: &RibEntry) -> bool {
+        let origin =
+            |entry: &RibEntry| entry.provenance.map(|p| (p.peer, p.changed_at, p.internal));
+        self.network_address == other.network_address
+            && self.path_attributes == other.path_attributes
+            && self.aspa_state == other.aspa_state
+            && self.weight == other.weight
+            && origin(self) == origin(other)
+    }
+
+    /// LLGRでstaleとして保持しているルートであるか。
+    pub fn is_llgr_stale(&self) -> bool {
+        self.communities().contains(&Community::LLGR_STALE)
+    }
+
+    pub fn aigp(&self) -> Option<u64> {
+        self.path_attributes.iter().find_map(|p| match p {
+            PathAttribute::Aigp(metric) => Some(*metric),
+            _ => None,
+        })
+    }
+
+    pub fn local_pref(&self) -> Option<u32> {
+        self.path_attributes.iter().find_map(|p| match p {
+            PathAttribute::LocalPref(local_pref) => Some(*local_pref),
+            _ => None,
+        })
+    }
+
+    /// LOCAL_PREFをlocal_prefにする。LOCAL_PREFがなければ追加する。
+    pub fn set_local_pref(&mut self, local_pref: u32) {
+        if self.local_pref() == Some(local_pref) {
+            return;
+        }
+        let path_attributes = Arc::make_mut(&mut self.path_attributes);
+        match path_attributes
+            .iter_mut()
+            .find(|p| matches!(p, PathAttribute::LocalPref(_)))
+        {
+            Some(p) => *p = PathAttribute::LocalPref(local_pref),
+            None => path_attributes.push(PathAttribute::LocalPref(local_pref)),
+        }
+    }
+
+    pub fn remove_local_pref(&mut self) {
+        if self.local_pref().is_some() {
+            Arc::make_mut(&mut self.path_attributes)
+                .retain(|p| !matches!(p, PathAttribute::LocalPref(_)));
+        }
+    }
+
+    pub fn remove_aigp(&mut self) {
+        if self.aigp().is_some() {
+            Arc::make_mut(&mut self.path_attributes)
+                .retain(|p| !matches!(p, PathAttribute::Aigp(_)));
+        }
+    }
+
+    /// 同じネットワークへのルートotherより、このルートのほうが良いルートであるか。
+    /// LLGRでstaleとして保持しているルートは、ほかのどのルートよりも優先度を低くする。
+    /// それ以外ではweightが大きいほうを最優先し、次にLOCAL_PREFが大きいほうを選び、LOCAL_PREFがないルートはDEFAULT_LOCAL_PREFとして扱う。
+    /// 両方がAIGPを持つ場合はAIGPが小さいほうを、そうでなければAS Pathが短いほうを選ぶ。
+    /// 同じ優先度の場合はfalseを返し、先に選ばれたルートを使い続ける。
+    pub fn is_better_than(&self, other: &RibEntry) -> bool {
+        if self.is_llgr_stale() != other.is_llgr_stale() {
+            return other.is_llgr_stale();
+        }
+        if self.weight != other.weight {
+            return self.weight > other.weight;
+        }
+        let local_pref = |r: &RibEntry| r.local_pref().unwrap_or(DEFAULT_LOCAL_PREF);
+        if local_pref(self) != local_pref(other) {
+            return local_pref(self) > local_pref(other);
+        }
+        if let (Some(a), Some(b)) = (self.aigp(), other.aigp()) {
+            if a != b {
+                return a < b;
+            }
+        }
+        let as_path_length = |r: &RibEntry| r.as_path().map_or(0, |p| p.path_length());
+        as_path_length(self) < as_path_length(other)
+    }
+
+    /// 自分が広告しているルートであるか。
+    /// LocRib上では、自分が広告しているルートのAS Pathは空になっている。
+    /// iBGPのピアから学習したルートもAS Pathが空の場合があるため、学習したピアがないことも確かめる。
+    fn is_originated_locally(&self) -> bool {
+        self.provenance.is_none()
+            && self.path_attributes.iter().any(|p| match p {
+                PathAttribute::AsPath(AsPath::AsSequence(seq)) => seq.is_empty(),
+                PathAttribute::AsPath(AsPath::AsSet(set)) => set.is_empty(),
+                _ => false,
+            })
+    }
+
+    /// iBGPのピアから学習したルートであるか。
+    fn is_learned_from_ibgp(&self) -> bool {
+        matches!(self.provenance, Some(p) if p.internal)
+    }
+
+    /// ルートを学習したピア。自分が広告しているルートではNoneである。
+    /// LocRibでは、ベストパスの候補をピアごとに1つにするために使う。
+    fn source_peer(&self) -> Option<Ipv4Addr> {
+        self.provenance.map(|p| p.peer)
+    }
+
+    pub fn next_hop(&self) -> Option<Ipv4Addr> {
+        self.path_attributes.iter().find_map(|p| match p {
+            PathAttribute::NextHop(addr) => Some(*addr),
+            _ => None,
+        })
+    }
+
+    pub fn change_next_hop(&mut self, next_hop: Ipv4Addr) {
+        for path_attribute in Arc::make_mut(&mut self.path_attributes) {
             if let PathAttribute::NextHop(addr) = path_attribute {
                 *addr = next_hop;
             }
@@ -170,11 +1573,744 @@ impl RibEntry {
     }
 }
 
+/// テストで使うRibEntryを組み立てる。RibEntryにフィールドを追加しても、各テストを直さずに済むようにする。
+#[cfg(test)]
+impl RibEntry {
+    /// AS 64513から受信した、NEXT_HOPが10.200.100.3のnetworkのルート。
+    pub(crate) fn for_test(network: &str) -> Self {
+        Self {
+            network_address: network.parse().unwrap(),
+            path_attributes: Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::AsSequence(vec![64513.into()])),
+                PathAttribute::NextHop("10.200.100.3".parse().unwrap()),
+            ]),
+            aspa_state: AspaState::default(),
+            weight: 0,
+            provenance: None,
+        }
+    }
+
+    /// AS_PATHをas_numbersのAS_SEQUENCEにする。
+    pub(crate) fn with_as_path(mut self, as_numbers: &[u32]) -> Self {
+        let as_path = AsPath::AsSequence(as_numbers.iter().map(|a| (*a).into()).collect());
+        for path_attribute in Arc::make_mut(&mut self.path_attributes) {
+            if let PathAttribute::AsPath(p) = path_attribute {
+                *p = as_path.clone();
+            }
+        }
+        self
+    }
+
+    pub(crate) fn with_next_hop(mut self, next_hop: &str) -> Self {
+        self.change_next_hop(next_hop.parse().unwrap());
+        self
+    }
+
+    /// COMMUNITIESをcommunitiesにする。空の場合も、空のCOMMUNITIESを付ける。
+    pub(crate) fn with_communities(mut self, communities: Vec<Community>) -> Self {
+        Arc::make_mut(&mut self.path_attributes).push(PathAttribute::Communities(communities));
+        self
+    }
+
+    pub(crate) fn with_path_attributes(mut self, path_attributes: Vec<PathAttribute>) -> Self {
+        self.path_attributes = Arc::new(path_attributes);
+        self
+    }
+
+    pub(crate) fn with_weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    pub(crate) fn with_provenance(mut self, provenance: Provenance) -> Self {
+        self.provenance = Some(provenance);
+        self
+    }
+
+    /// peerから今受信したルートにする。
+    pub(crate) fn learned_from(self, peer: &str) -> Self {
+        self.with_provenance(Provenance::new(peer.parse().unwrap()))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use tokio::time::{sleep, Duration};
 
+    #[test]
+    fn route_protocol_can_be_parsed_from_name_or_number() {
+        assert_eq!("bgp".parse::<RouteProtocol>().unwrap(), RouteProtocol(186));
+        assert_eq!("200".parse::<RouteProtocol>().unwrap(), RouteProtocol(200));
+        // カーネルや管理者が書き込むルートのprotocolは使えない。
+        for reserved in ["kernel", "boot", "static", "0", "4"] {
+            assert!(reserved.parse::<RouteProtocol>().is_err(), "{}", reserved);
+        }
+        assert!("ospf".parse::<RouteProtocol>().is_err());
+    }
+
+    #[test]
+    fn route_table_can_be_parsed_from_name_or_number() {
+        assert_eq!("main".parse::<RouteTable>().unwrap(), RouteTable(254));
+        assert_eq!("local".parse::<RouteTable>().unwrap(), RouteTable(255));
+        assert_eq!("1000".parse::<RouteTable>().unwrap(), RouteTable(1000));
+        assert!("vrf-red".parse::<RouteTable>().is_err());
+    }
+
+    #[test]
+    fn update_message_from_adj_rib_out() {
+        let path_attributes = vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::AsSequence(vec![64513.into()])),
+            PathAttribute::NextHop("10.200.100.3".parse().unwrap()),
+        ];
+        let adj_rib_out = AdjRibOut::from(vec![RibEntry::for_test("10.100.220.0/24")]);
+        let expected_update_message = UpdateMessage::new(
+            path_attributes,
+            vec!["10.100.220.0/24".parse().unwrap()],
+            vec![],
+        );
+        assert_eq!(
+            Vec::<UpdateMessage>::from(&adj_rib_out),
+            vec![expected_update_message]
+        );
+    }
+
+    #[test]
+    fn adj_rib_out_replaces_and_removes_routes_by_prefix() {
+        let route =
+            |network: &str, as_number: u32| RibEntry::for_test(network).with_as_path(&[as_number]);
+        let network: Ipv4Network = "10.100.220.0/24".parse().unwrap();
+        let mut adj_rib_out = AdjRibOut::from(vec![
+            route("10.100.220.0/24", 64513),
+            route("10.100.0.0/16", 64513),
+        ]);
+        assert_eq!(adj_rib_out.len(), 2);
+        assert!(adj_rib_out.contains(&network));
+        assert!(!adj_rib_out.contains(&"10.100.221.0/24".parse().unwrap()));
+
+        let replaced =
+            adj_rib_out.insert(AddressFamily::IPV4_UNICAST, route("10.100.220.0/24", 64514));
+        assert_eq!(replaced, Some(route("10.100.220.0/24", 64513)));
+        assert_eq!(adj_rib_out.len(), 2);
+        assert_eq!(
+            adj_rib_out.get(&network),
+            Some(&route("10.100.220.0/24", 64514))
+        );
+
+        assert_eq!(
+            adj_rib_out.remove(AddressFamily::IPV4_UNICAST, &network),
+            Some(route("10.100.220.0/24", 64514))
+        );
+        assert_eq!(
+            adj_rib_out.remove(AddressFamily::IPV4_UNICAST, &network),
+            None
+        );
+        assert_eq!(
+            adj_rib_out
+                .routes(AddressFamily::IPV4_UNICAST)
+                .map(|r| r.network_address)
+                .collect::<Vec<_>>(),
+            vec!["10.100.0.0/16".parse().unwrap()]
+        );
+    }
+
+    #[test]
+    fn each_address_family_has_its_own_table() {
+        let ipv4_multicast = AddressFamily::new(1, 2);
+        let route =
+            |as_number: u32| RibEntry::for_test("10.100.220.0/24").with_as_path(&[as_number]);
+        let table = |route: RibEntry| BTreeMap::from([(route.network_address, route)]);
+        let mut loc_rib = LocRib::empty();
+        loc_rib.install_from_adj_rib_in(&mut AdjRibIn(BTreeMap::from([
+            (AddressFamily::IPV4_UNICAST, table(route(64513))),
+            (ipv4_multicast, table(route(64514))),
+        ])));
+
+        assert_eq!(loc_rib.len(), 2);
+        assert_eq!(
+            loc_rib.families().collect::<Vec<_>>(),
+            vec![AddressFamily::IPV4_UNICAST, ipv4_multicast]
+        );
+        assert_eq!(
+            loc_rib.get(&"10.100.220.0/24".parse().unwrap()),
+            Some(&route(64513))
+        );
+        assert_eq!(
+            loc_rib.routes(ipv4_multicast).collect::<Vec<_>>(),
+            vec![&route(64514)]
+        );
+
+        let config: Config = "64512 10.200.100.2 64515 10.200.100.5 active afi-safi 1/1,1/2"
+            .parse()
+            .unwrap();
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(&loc_rib, &config, NegotiatedCapabilities::default());
+        assert_eq!(adj_rib_out.routes(ipv4_multicast).count(), 1);
+        // UpdateMessageのNLRIで広告するのはIPv4 Unicastのルートだけである。
+        let updates: Vec<UpdateMessage> = (&adj_rib_out).into();
+        assert_eq!(updates.len(), 1);
+
+        // 交換するとしていないAFI/SAFIのルートはAdjRibOutに入れない。
+        let config: Config = "64512 10.200.100.2 64515 10.200.100.5 active"
+            .parse()
+            .unwrap();
+        adj_rib_out.install_from_loc_rib(&loc_rib, &config, NegotiatedCapabilities::default());
+        assert!(adj_rib_out.routes(ipv4_multicast).next().is_none());
+        assert_eq!(adj_rib_out.len(), 1);
+    }
+
+    #[test]
+    fn loc_rib_removes_routes_learned_from_adj_rib_in() {
+        let route =
+            |network: &str, as_number: u32| RibEntry::for_test(network).with_as_path(&[as_number]);
+        let mut loc_rib = LocRib::empty();
+        loc_rib.install_from_adj_rib_in(&mut AdjRibIn::from(vec![
+            route("10.100.220.0/24", 64513),
+            route("10.100.221.0/24", 64514),
+        ]));
+        let adj_rib_in = AdjRibIn::from(vec![route("10.100.220.0/24", 64513)]);
+
+        let removed = loc_rib.remove_routes_learned_from(&adj_rib_in);
+        assert_eq!(removed, vec![route("10.100.220.0/24", 64513)]);
+        assert_eq!(
+            loc_rib.iter().cloned().collect::<Vec<_>>(),
+            vec![route("10.100.221.0/24", 64514)]
+        );
+    }
+
+    #[test]
+    fn loc_rib_falls_back_to_alternate_path_when_best_path_is_withdrawn() {
+        let network: Ipv4Network = "10.100.220.0/24".parse().unwrap();
+        let (peer_1, peer_2) = ("127.0.0.2".parse().unwrap(), "127.0.0.3".parse().unwrap());
+        let short = RibEntry::for_test("10.100.220.0/24").learned_from("127.0.0.2");
+        let long = RibEntry::for_test("10.100.220.0/24")
+            .with_as_path(&[64514, 64513])
+            .learned_from("127.0.0.3");
+        let mut loc_rib = LocRib::empty();
+        loc_rib.update_from_adj_rib_in(peer_2, &mut AdjRibIn::from(vec![long.clone()]));
+        loc_rib.update_from_adj_rib_in(peer_1, &mut AdjRibIn::from(vec![short.clone()]));
+        assert_eq!(loc_rib.get(&network), Some(&short));
+
+        // ベストパスが取り消されると、残っている候補がベストパスになる。
+        let version = loc_rib.version();
+        let removed = loc_rib.update_from_adj_rib_in(peer_1, &mut AdjRibIn::new());
+        assert!(removed.is_empty());
+        assert_eq!(loc_rib.get(&network), Some(&long));
+        assert!(loc_rib.version() > version);
+
+        // 候補がなくなったネットワークは削除する。
+        let removed = loc_rib.update_from_adj_rib_in(peer_2, &mut AdjRibIn::new());
+        assert_eq!(removed, vec![long]);
+        assert_eq!(loc_rib.len(), 0);
+    }
+
+    #[test]
+    fn loc_rib_replaces_path_with_worse_path_from_same_peer() {
+        let network: Ipv4Network = "10.100.220.0/24".parse().unwrap();
+        let (peer_1, peer_2) = ("127.0.0.2".parse().unwrap(), "127.0.0.3".parse().unwrap());
+        let route = |as_path: &[u32], peer: &str| {
+            RibEntry::for_test("10.100.220.0/24")
+                .with_as_path(as_path)
+                .learned_from(peer)
+        };
+        let mut loc_rib = LocRib::empty();
+        loc_rib.update_from_adj_rib_in(
+            peer_1,
+            &mut AdjRibIn::from(vec![route(&[64513], "127.0.0.2")]),
+        );
+
+        // 同じピアから受信し直したルートは、より悪くても前のルートを置き換える。
+        let worse = route(&[64513, 64515, 64516], "127.0.0.2");
+        loc_rib.update_from_adj_rib_in(peer_1, &mut AdjRibIn::from(vec![worse.clone()]));
+        assert_eq!(loc_rib.get(&network), Some(&worse));
+
+        // ほかのピアの候補のほうが良くなれば、そちらを選ぶ。
+        let other = route(&[64514, 64513], "127.0.0.3");
+        loc_rib.update_from_adj_rib_in(peer_2, &mut AdjRibIn::from(vec![other.clone()]));
+        assert_eq!(loc_rib.get(&network), Some(&other));
+        assert_eq!(loc_rib.len(), 1);
+    }
+
+    #[test]
+    fn loc_rib_falls_back_to_alternate_path_when_peer_is_flushed() {
+        let network: Ipv4Network = "10.100.220.0/24".parse().unwrap();
+        let best = RibEntry::for_test("10.100.220.0/24").learned_from("127.0.0.2");
+        let alternate = RibEntry::for_test("10.100.220.0/24")
+            .with_as_path(&[64514, 64513])
+            .learned_from("127.0.0.3");
+        let mut loc_rib = LocRib::empty();
+        loc_rib.install_from_adj_rib_in(&mut AdjRibIn::from(vec![best.clone()]));
+        loc_rib.install_from_adj_rib_in(&mut AdjRibIn::from(vec![alternate.clone()]));
+        assert_eq!(loc_rib.get(&network), Some(&best));
+
+        let removed = loc_rib.remove_routes_learned_from(&AdjRibIn::from(vec![best]));
+        assert!(removed.is_empty());
+        assert_eq!(loc_rib.get(&network), Some(&alternate));
+    }
+
+    #[test]
+    fn adj_rib_out_updates_since_contain_only_changes() {
+        let route =
+            |network: &str, as_number: u32| RibEntry::for_test(network).with_as_path(&[as_number]);
+        let advertised = AdjRibOut::from(vec![
+            route("10.100.220.0/24", 64513),
+            route("10.100.221.0/24", 64513),
+            route("10.100.222.0/24", 64513),
+        ]);
+        let adj_rib_out = AdjRibOut::from(vec![
+            route("10.100.220.0/24", 64513),
+            route("10.100.221.0/24", 64514),
+        ]);
+
+        let updates = adj_rib_out.updates_since(&advertised);
+        assert_eq!(
+            updates,
+            vec![
+                UpdateMessage::new(vec![], vec![], vec!["10.100.222.0/24".parse().unwrap()]),
+                UpdateMessage::new(
+                    route("10.100.221.0/24", 64514).path_attributes.to_vec(),
+                    vec!["10.100.221.0/24".parse().unwrap()],
+                    vec![],
+                ),
+            ]
+        );
+        assert!(adj_rib_out.updates_since(&adj_rib_out).is_empty());
+    }
+
+    #[test]
+    fn loc_rib_retains_learned_routes_as_least_preferred_stale_routes() {
+        let route = |network: &str, as_path: Vec<u32>, communities: Vec<Community>| {
+            RibEntry::for_test(network)
+                .with_as_path(&as_path)
+                .with_communities(communities)
+        };
+        let mut adj_rib_in = AdjRibIn::from(vec![
+            route("10.100.220.0/24", vec![64513], vec![]),
+            route("10.100.221.0/24", vec![64513], vec![Community::NO_LLGR]),
+        ]);
+        let mut loc_rib = LocRib::empty();
+        loc_rib.install_from_adj_rib_in(&mut adj_rib_in);
+
+        let removed = loc_rib.retain_as_stale(&mut adj_rib_in);
+        assert_eq!(
+            removed,
+            vec![route(
+                "10.100.221.0/24",
+                vec![64513],
+                vec![Community::NO_LLGR]
+            )]
+        );
+        let stale = route("10.100.220.0/24", vec![64513], vec![Community::LLGR_STALE]);
+        assert_eq!(adj_rib_in, AdjRibIn::from(vec![stale.clone()]));
+        assert_eq!(loc_rib.iter().cloned().collect::<Vec<_>>(), vec![stale]);
+
+        // AS Pathが長くても、staleでないルートのほうを選ぶ。
+        let fresh = route("10.100.220.0/24", vec![64514, 64515, 64513], vec![]);
+        loc_rib.install_from_adj_rib_in(&mut AdjRibIn::from(vec![fresh.clone()]));
+        assert_eq!(loc_rib.iter().cloned().collect::<Vec<_>>(), vec![fresh]);
+    }
+
+    #[test]
+    fn adj_rib_in_rejects_routes_longer_than_max_prefix_length() {
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.install_from_update(
+            UpdateMessage::new(vec![], vec!["10.100.220.0/24".parse().unwrap()], vec![]),
+            |_| true,
+        );
+        let update = UpdateMessage::new(
+            vec![],
+            vec![
+                "10.100.220.0/24".parse().unwrap(),
+                "10.100.221.0/25".parse().unwrap(),
+                "10.100.0.0/16".parse().unwrap(),
+            ],
+            vec![],
+        );
+
+        let rejected = adj_rib_in.install_from_update(update, |r| r.network_address.prefix() <= 16);
+
+        assert_eq!(rejected, 2);
+        let networks: Vec<Ipv4Network> = adj_rib_in.iter().map(|r| r.network_address).collect();
+        assert_eq!(networks, vec!["10.100.0.0/16".parse().unwrap()]);
+    }
+
+    #[test]
+    fn changed_at_is_kept_when_same_path_attributes_are_received_again() {
+        let peer: Ipv4Addr = "10.200.100.3".parse().unwrap();
+        let update = |next_hop: &str| {
+            UpdateMessage::new(
+                vec![PathAttribute::NextHop(next_hop.parse().unwrap())],
+                vec!["10.100.220.0/24".parse().unwrap()],
+                vec![],
+            )
+        };
+        let received = |seconds: u64| {
+            let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(seconds);
+            move |r: &mut RibEntry| {
+                r.provenance = Some(Provenance {
+                    peer,
+                    received_at: time,
+                    changed_at: time,
+                    internal: false,
+                });
+                true
+            }
+        };
+        let provenance = |adj_rib_in: &AdjRibIn| {
+            let p = adj_rib_in.iter().next().unwrap().provenance.unwrap();
+            (
+                p.received_at
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+                p.changed_at
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+            )
+        };
+        let mut adj_rib_in = AdjRibIn::new();
+
+        adj_rib_in.install_from_update(update("10.200.100.3"), received(10));
+        adj_rib_in.install_from_update(update("10.200.100.3"), received(20));
+        assert_eq!(provenance(&adj_rib_in), (20, 10));
+
+        adj_rib_in.install_from_update(update("10.200.100.4"), received(30));
+        assert_eq!(provenance(&adj_rib_in), (30, 30));
+    }
+
+    #[test]
+    fn loc_rib_updates_only_networks_in_update_and_ignores_same_route_received_again() {
+        let peer: Ipv4Addr = "10.200.100.3".parse().unwrap();
+        let (a, b): (Ipv4Network, Ipv4Network) = (
+            "10.100.220.0/24".parse().unwrap(),
+            "10.100.221.0/24".parse().unwrap(),
+        );
+        let update = |seconds: u64, networks: Vec<Ipv4Network>, withdrawn: Vec<Ipv4Network>| {
+            let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(seconds);
+            let update =
+                UpdateMessage::new(vec![PathAttribute::NextHop(peer)], networks, withdrawn);
+            (update, move |r: &mut RibEntry| {
+                r.provenance = Some(Provenance {
+                    peer,
+                    received_at: time,
+                    changed_at: time,
+                    internal: false,
+                });
+                true
+            })
+        };
+        let mut adj_rib_in = AdjRibIn::new();
+        let mut loc_rib = LocRib::empty();
+        let (message, import) = update(10, vec![a, b], vec![]);
+        adj_rib_in.install_from_update(message, import);
+        loc_rib.update_networks_from_adj_rib_in(peer, &mut adj_rib_in, &[a, b]);
+        assert_eq!(loc_rib.len(), 2);
+
+        // 同じ内容で受信し直したルートは、LocRibの変更にならない。
+        let version = loc_rib.version();
+        let (message, import) = update(20, vec![a], vec![]);
+        adj_rib_in.install_from_update(message, import);
+        loc_rib.update_networks_from_adj_rib_in(peer, &mut adj_rib_in, &[a]);
+        assert_eq!(loc_rib.version(), version);
+
+        // UpdateMessageに含まれていたネットワークだけを取り消す。
+        let (message, import) = update(30, vec![], vec![b]);
+        adj_rib_in.install_from_update(message, import);
+        let removed = loc_rib.update_networks_from_adj_rib_in(peer, &mut adj_rib_in, &[b]);
+        assert_eq!(removed.len(), 1);
+        assert!(loc_rib.get(&a).is_some());
+        assert!(loc_rib.get(&b).is_none());
+    }
+
+    #[test]
+    fn replicated_routes_are_kept_until_relearned_or_flushed() {
+        let (peer, other): (Ipv4Addr, Ipv4Addr) = (
+            "10.200.100.3".parse().unwrap(),
+            "10.200.100.4".parse().unwrap(),
+        );
+        let route = |network: &str, peer: Ipv4Addr, received_at: SystemTime| {
+            RibEntry::for_test(network)
+                .with_next_hop(&peer.to_string())
+                .with_provenance(Provenance {
+                    peer,
+                    received_at,
+                    changed_at: received_at,
+                    internal: false,
+                })
+        };
+        let replicated_at = SystemTime::UNIX_EPOCH;
+        let (a, b, c) = (
+            route("10.100.220.0/24", peer, replicated_at),
+            route("10.100.221.0/24", peer, replicated_at),
+            route("10.100.222.0/24", other, replicated_at),
+        );
+        let mut loc_rib = LocRib::empty();
+        loc_rib.replace_routes(vec![a.clone(), b.clone(), c.clone()]);
+        loc_rib.mark_replicated_routes_stale();
+
+        // 処理を引き継いだ後に受信し直したルートは残し、End-of-RIBまでに受信しなかったルートを削除する。
+        let mut adj_rib_in =
+            AdjRibIn::from(vec![route("10.100.220.0/24", peer, SystemTime::now())]);
+        loc_rib.update_networks_from_adj_rib_in(peer, &mut adj_rib_in, &[a.network_address]);
+        let removed = loc_rib.flush_replicated_routes_of(peer, &adj_rib_in);
+        assert_eq!(removed, vec![b.clone()]);
+        assert!(loc_rib.get(&a.network_address).is_some());
+        assert!(loc_rib.get(&c.network_address).is_some());
+
+        // End-of-RIBを送ってこなかったピアのルートは、stale timeの経過で削除する。
+        assert_eq!(loc_rib.flush_replicated_routes(), vec![c]);
+        assert!(loc_rib.get(&a.network_address).is_some());
+        assert!(loc_rib
+            .flush_replicated_routes_of(peer, &adj_rib_in)
+            .is_empty());
+    }
+
+    #[test]
+    fn higher_local_pref_is_preferred_over_shorter_as_path() {
+        let route =
+            |as_path: Vec<u32>| RibEntry::for_test("10.100.220.0/24").with_as_path(&as_path);
+        let short = route(vec![64513]);
+        let mut long = route(vec![64514, 64515]);
+        long.set_local_pref(DEFAULT_LOCAL_PREF);
+        assert!(short.is_better_than(&long));
+
+        long.set_local_pref(200);
+        assert_eq!(long.local_pref(), Some(200));
+        assert_eq!(
+            long.path_attributes
+                .iter()
+                .filter(|p| matches!(p, PathAttribute::LocalPref(_)))
+                .count(),
+            1
+        );
+        assert!(long.is_better_than(&short));
+        long.remove_local_pref();
+        assert_eq!(long.local_pref(), None);
+    }
+
+    #[test]
+    fn weight_is_compared_before_local_pref_but_after_llgr_stale() {
+        let route = |local_pref: u32, weight: u32| {
+            let mut route = RibEntry::for_test("10.100.220.0/24").with_weight(weight);
+            route.set_local_pref(local_pref);
+            route
+        };
+        let preferred_session = route(100, 10);
+        let high_local_pref = route(300, 0);
+        assert!(preferred_session.is_better_than(&high_local_pref));
+
+        let mut stale = preferred_session.clone();
+        stale.add_community(Community::LLGR_STALE);
+        assert!(high_local_pref.is_better_than(&stale));
+    }
+
+    #[test]
+    fn loc_rib_prefers_lower_aigp_over_shorter_as_path() {
+        let route = |as_path: Vec<u32>, aigp: Option<u64>| {
+            let mut route = RibEntry::for_test("10.100.220.0/24").with_as_path(&as_path);
+            Arc::make_mut(&mut route.path_attributes).extend(aigp.map(PathAttribute::Aigp));
+            route
+        };
+        let short = route(vec![64513], None);
+        let long = route(vec![64514, 64515], None);
+        let long_with_low_aigp = route(vec![64514, 64515], Some(10));
+        let short_with_high_aigp = route(vec![64513], Some(100));
+
+        assert!(short.is_better_than(&long));
+        assert!(!long.is_better_than(&short));
+        assert!(long_with_low_aigp.is_better_than(&short_with_high_aigp));
+        // 片方しかAIGPを持たない場合はAS Pathの長さで比べる。
+        assert!(short.is_better_than(&long_with_low_aigp));
+
+        let mut loc_rib = LocRib::empty();
+        loc_rib.install_from_adj_rib_in(&mut AdjRibIn::from(vec![short_with_high_aigp]));
+        loc_rib.install_from_adj_rib_in(&mut AdjRibIn::from(vec![long_with_low_aigp.clone()]));
+        assert_eq!(
+            loc_rib.get(&"10.100.220.0/24".parse().unwrap()),
+            Some(&long_with_low_aigp)
+        );
+    }
+
+    #[test]
+    fn adj_rib_out_is_depreferenced_during_maintenance() {
+        let mut loc_rib = LocRib::empty();
+        loc_rib.install_from_adj_rib_in(&mut AdjRibIn::from(vec![RibEntry::for_test(
+            "10.100.220.0/24",
+        )]));
+        let advertised = |loc_rib: &LocRib, config: &str| {
+            let config: Config = config.parse().unwrap();
+            let mut adj_rib_out = AdjRibOut::new();
+            adj_rib_out.install_from_loc_rib(loc_rib, &config, NegotiatedCapabilities::default());
+            let route = adj_rib_out.routes(AddressFamily::IPV4_UNICAST).next();
+            route.unwrap().clone()
+        };
+        let ebgp = "64512 10.200.100.2 64514 10.200.100.4 active";
+        let ibgp = "64512 10.200.100.2 64512 10.200.100.4 active";
+        let normal = advertised(&loc_rib, ebgp);
+
+        loc_rib.set_maintenance(true);
+        let route = advertised(&loc_rib, ebgp);
+        assert_eq!(route.as_path().unwrap().path_length(), 5);
+        assert_eq!(route.communities(), &[Community::GRACEFUL_SHUTDOWN]);
+        // iBGPのピアには、自AS番号を追加せずに広告する。
+        let route = advertised(&loc_rib, ibgp);
+        assert_eq!(route.as_path().unwrap().path_length(), 1);
+        assert_eq!(route.communities(), &[Community::GRACEFUL_SHUTDOWN]);
+
+        loc_rib.set_maintenance(false);
+        assert_eq!(advertised(&loc_rib, ebgp), normal);
+    }
+
+    #[test]
+    fn routes_learned_from_ibgp_are_not_advertised_to_ibgp_peers() {
+        let ebgp_learned = RibEntry::for_test("10.100.220.0/24").learned_from("10.200.100.3");
+        let mut ibgp_learned = RibEntry::for_test("10.100.221.0/24");
+        ibgp_learned.provenance = Some(Provenance {
+            internal: true,
+            ..Provenance::new("10.200.100.5".parse().unwrap())
+        });
+        let mut loc_rib = LocRib::empty();
+        loc_rib.install_from_adj_rib_in(&mut AdjRibIn::from(vec![ebgp_learned, ibgp_learned]));
+        let advertised = |config: &str| {
+            let mut adj_rib_out = AdjRibOut::new();
+            adj_rib_out.install_from_loc_rib(
+                &loc_rib,
+                &config.parse().unwrap(),
+                NegotiatedCapabilities::default(),
+            );
+            adj_rib_out
+                .routes(AddressFamily::IPV4_UNICAST)
+                .map(|r| {
+                    let as_path_length = r.as_path().unwrap().path_length();
+                    (r.network_address.to_string(), as_path_length)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // iBGPのピアには、eBGPのピアから学習したルートだけを自AS番号を追加せずに広告する。
+        assert_eq!(
+            advertised("64512 10.200.100.2 64512 10.200.100.4 active"),
+            vec![("10.100.220.0/24".to_owned(), 1)]
+        );
+        assert_eq!(
+            advertised("64512 10.200.100.2 64514 10.200.100.4 active"),
+            vec![
+                ("10.100.220.0/24".to_owned(), 2),
+                ("10.100.221.0/24".to_owned(), 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn adj_rib_out_honors_well_known_communities() {
+        let route = |network: &str, community: Community| {
+            RibEntry::for_test(network).with_communities(vec![community])
+        };
+        let mut loc_rib = LocRib::empty();
+        loc_rib.install_from_adj_rib_in(&mut AdjRibIn::from(vec![
+            route("10.100.220.0/24", Community::NO_EXPORT),
+            route("10.100.221.0/24", Community::NO_ADVERTISE),
+            route("10.100.222.0/24", "64513:100".parse().unwrap()),
+        ]));
+        let advertised = |config: &str| {
+            let config: Config = config.parse().unwrap();
+            let mut adj_rib_out = AdjRibOut::new();
+            adj_rib_out.install_from_loc_rib(&loc_rib, &config, NegotiatedCapabilities::default());
+            adj_rib_out
+                .iter()
+                .map(|r| r.network_address.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            advertised("64512 10.200.100.2 64514 10.200.100.4 active"),
+            vec!["10.100.222.0/24"]
+        );
+        assert_eq!(
+            advertised("64512 10.200.100.2 64512 10.200.100.4 active"),
+            vec!["10.100.220.0/24", "10.100.222.0/24"]
+        );
+        assert_eq!(
+            advertised(
+                "64512 10.200.100.2 64514 10.200.100.4 active ignore-well-known-communities"
+            )
+            .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn llgr_stale_routes_are_advertised_only_to_peers_that_negotiated_llgr() {
+        let mut loc_rib = LocRib::empty();
+        loc_rib.install_from_adj_rib_in(&mut AdjRibIn::from(vec![
+            RibEntry::for_test("10.100.220.0/24").with_communities(vec![Community::LLGR_STALE]),
+            RibEntry::for_test("10.100.221.0/24"),
+        ]));
+        let config: Config =
+            "64512 10.200.100.2 64514 10.200.100.4 active ignore-well-known-communities"
+                .parse()
+                .unwrap();
+        let advertised = |llgr: bool| {
+            let mut adj_rib_out = AdjRibOut::new();
+            let negotiated = NegotiatedCapabilities {
+                llgr,
+                ..Default::default()
+            };
+            adj_rib_out.install_from_loc_rib(&loc_rib, &config, negotiated);
+            adj_rib_out
+                .iter()
+                .map(|r| r.network_address.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(advertised(false), vec!["10.100.221.0/24"]);
+        assert_eq!(advertised(true), vec!["10.100.220.0/24", "10.100.221.0/24"]);
+    }
+
+    #[test]
+    fn rib_entry_bytes_keep_weight_aspa_state_and_provenance() {
+        let received_at = SystemTime::UNIX_EPOCH + std::time::Duration::new(1_700_000_000, 123);
+        let learned = RibEntry {
+            weight: 200,
+            aspa_state: AspaState::Invalid,
+            provenance: Some(Provenance {
+                peer: "10.200.100.3".parse().unwrap(),
+                received_at,
+                changed_at: received_at - std::time::Duration::from_secs(60),
+                internal: true,
+            }),
+            ..RibEntry::for_test("10.100.220.0/24")
+        };
+        let originated = RibEntry::for_test("10.100.221.0/24");
+
+        for entry in [learned, originated] {
+            let bytes: BytesMut = (&entry).into();
+            assert_eq!(RibEntry::try_from(&bytes[..]).unwrap(), entry);
+            assert!(RibEntry::try_from(&bytes[..bytes.len() - 1]).is_err());
+        }
+    }
+
+    #[test]
+    fn loc_rib_shares_same_path_attributes_between_peers() {
+        // 2つのピアから、同じ内容のPath Attributeを別々に受信した場合を模擬する。
+        let mut adj_rib_in_1 = AdjRibIn::from(vec![RibEntry::for_test("10.100.220.0/24")]);
+        let mut adj_rib_in_2 = AdjRibIn::from(vec![RibEntry::for_test("10.100.221.0/24")]);
+
+        let mut loc_rib = LocRib::empty();
+        loc_rib.install_from_adj_rib_in(&mut adj_rib_in_1);
+        loc_rib.install_from_adj_rib_in(&mut adj_rib_in_2);
+
+        assert_eq!(loc_rib.path_attribute_sets(), 1);
+        let routes: Vec<&RibEntry> = loc_rib.iter().collect();
+        assert!(Arc::ptr_eq(
+            &routes[0].path_attributes,
+            &routes[1].path_attributes
+        ));
+    }
+
     #[tokio::test]
     async fn loclib_can_lookup_routing_table() {
         // 本テストの値は環境によって異なる。
@@ -182,7 +2318,13 @@ mod tests {
         let network = ipnetwork::Ipv4Network::new("10.200.100.0".parse().unwrap(), 24)
             .unwrap()
             .into();
-        let routes = LocRib::lookup_kernel_routing_table(network).await.unwrap();
+        let routes = LocRib::lookup_kernel_routing_table(
+            &*fib::default_fib(),
+            &[network],
+            RouteTable::default(),
+        )
+        .await
+        .unwrap();
         let expected = vec![network];
         assert_eq!(routes, expected);
     }
@@ -195,18 +2337,11 @@ mod tests {
         let config: Config = "64513 10.200.100.3 64512 10.200.100.2 passive 10.100.220.0/24"
             .parse()
             .unwrap();
-        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let loc_rib = LocRib::new(&config).await.unwrap();
         let mut adj_rib_out = AdjRibOut::new();
-        adj_rib_out.install_from_loc_rib(&mut loc_rib, &config);
+        adj_rib_out.install_from_loc_rib(&loc_rib, &config, NegotiatedCapabilities::default());
 
-        let expected_adj_rib_out = AdjRibOut(vec![RibEntry {
-            network_address: "10.100.220.0/24".parse().unwrap(),
-            path_attributes: vec![
-                PathAttribute::Origin(Origin::Igp),
-                PathAttribute::AsPath(AsPath::AsSequence(vec![64513.into()])),
-                PathAttribute::NextHop("10.200.100.3".parse().unwrap()),
-            ],
-        }]);
+        let expected_adj_rib_out = AdjRibOut::from(vec![RibEntry::for_test("10.100.220.0/24")]);
 
         assert_eq!(adj_rib_out, expected_adj_rib_out);
     }
@@ -0,0 +1,443 @@
+use crate::monitor::format_path_attribute;
+use crate::path_attribute::AsPath;
+use crate::policy::PolicyAction;
+use crate::routing::RibEntry;
+use anyhow::{Context, Result};
+use mlua::{Function, HookTriggers, Lua, LuaOptions, RegistryKey, StdLib, Table, Value};
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// スクリプトが1つのルートの評価で実行できるLuaの命令数。
+/// 超えた場合は評価を打ち切ってエラーにし、無限ループするスクリプトでルートの処理を止めない。
+const SCRIPT_INSTRUCTION_LIMIT: usize = 100_000;
+
+/// 実行した命令数を数える間隔。
+const SCRIPT_HOOK_INTERVAL: u32 = 1000;
+
+/// スクリプトのファイルが変わったかを確かめる間隔。
+const SCRIPT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// スクリプトが確保できるメモリの上限。
+const SCRIPT_MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+
+/// スクリプトがルートに対して返した結果。
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// `accept`: ルートを変更せずに受け入れる。
+    Accept,
+    /// `reject`: ルートを拒否する。
+    Reject,
+    /// `set local-pref 200; set as-path prepend 64512`:
+    /// `;`で区切ったPolicyActionを順に適用する。
+    Modify(Vec<PolicyAction>),
+}
+
+impl FromStr for Verdict {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "accept" => return Ok(Verdict::Accept),
+            "reject" => return Ok(Verdict::Reject),
+            _ => (),
+        }
+        let mut actions = vec![];
+        for statement in s.split(';') {
+            let mut tokens = statement.split_whitespace().peekable();
+            let action = PolicyAction::parse_from_tokens(&mut tokens)?;
+            if let Some(token) = tokens.next() {
+                return Err(anyhow::anyhow!("`{}`以降を読み取れませんでした。", token));
+            }
+            // スクリプトから別のスクリプトを呼び出させると、応答を待ち合って止まるおそれがある。
+            if matches!(action, PolicyAction::CallScript(_)) {
+                return Err(anyhow::anyhow!(
+                    "スクリプトの応答ではcall scriptを使えません。"
+                ));
+            }
+            actions.push(action);
+        }
+        Ok(Verdict::Modify(actions))
+    }
+}
+
+/// スクリプトから取り除く基本ライブラリの関数。
+/// pcallとxpcallは命令数の上限のエラーを捕まえて実行を続けられるため、
+/// load, loadfile, dofileはファイルやバイトコードを読み込めるため使わせない。
+const REMOVED_GLOBALS: [&str; 5] = ["pcall", "xpcall", "load", "loadfile", "dofile"];
+
+/// スクリプトを読み込んだLuaの状態。
+/// スクリプトは`policy(route)`という関数を定義し、ルートのテーブルを受け取ってVerdictの文字列を返す。
+/// ファイルやプロセスを操作できないように、io, os, debugなどのライブラリは読み込まない。
+/// スクリプトはルートごとに新しいグローバル変数のテーブルで実行し直し、
+/// 前に評価したルートによって結果が変わらないようにする。
+struct LuaPolicy {
+    lua: Lua,
+    /// コンパイルしたスクリプト。
+    chunk: RegistryKey,
+    /// 評価しているルートで実行した命令数。hookで数え、ルートごとに0に戻す。
+    executed: Arc<AtomicUsize>,
+}
+
+impl LuaPolicy {
+    fn load(path: &Path) -> Result<Self> {
+        let source = std::fs::read_to_string(path)
+            .context(format!("スクリプト{:?}を読み込めませんでした。", path))?;
+        let lua = Lua::new_with(
+            StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::UTF8,
+            LuaOptions::new(),
+        )?;
+        lua.set_memory_limit(SCRIPT_MEMORY_LIMIT)?;
+        for name in REMOVED_GLOBALS {
+            lua.globals().raw_set(name, Value::Nil)?;
+        }
+        // 文字列のメタテーブルはすべての評価で共有するため、getmetatableで書き換えさせない。
+        lua.load("getmetatable('').__metatable = false").exec()?;
+        let executed = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&executed);
+        let interval = SCRIPT_HOOK_INTERVAL as usize;
+        // 命令数は評価の終わりまで増え続けるため、上限を超えた後はhookのたびにエラーになる。
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(SCRIPT_HOOK_INTERVAL),
+            move |_, _| {
+                if counter.fetch_add(interval, atomic::Ordering::Relaxed) + interval
+                    > SCRIPT_INSTRUCTION_LIMIT
+                {
+                    return Err(mlua::Error::runtime(format!(
+                        "{}命令を実行しても終わりませんでした。",
+                        SCRIPT_INSTRUCTION_LIMIT
+                    )));
+                }
+                Ok(())
+            },
+        );
+        let chunk = lua
+            .load(source.as_str())
+            .set_name(path.to_string_lossy())
+            .into_function()?;
+        let chunk = lua.create_registry_value(chunk)?;
+        Ok(Self {
+            lua,
+            chunk,
+            executed,
+        })
+    }
+
+    fn evaluate(&self, route: &RibEntry) -> Result<Verdict> {
+        self.executed.store(0, atomic::Ordering::Relaxed);
+        let environment = self.environment()?;
+        let chunk: Function = self.lua.registry_value(&self.chunk)?;
+        chunk.set_environment(environment.clone())?;
+        chunk.call::<_, ()>(())?;
+        let policy: Function = environment
+            .get::<_, Option<Function>>("policy")?
+            .context("スクリプトにpolicy関数が定義されていません。")?;
+        let response: String = policy.call(route_to_table(&self.lua, route)?)?;
+        response.parse().context(format!(
+            "スクリプトの応答`{}`を読み取れませんでした。",
+            response
+        ))
+    }
+
+    /// 1つのルートの評価で使う、グローバル変数のテーブル。
+    /// 前の評価で書き換えられたものが残らないように、ライブラリのテーブルも複製する。
+    fn environment(&self) -> mlua::Result<Table<'_>> {
+        let environment = self.lua.create_table()?;
+        for pair in self.lua.globals().pairs::<Value, Value>() {
+            let (name, value) = pair?;
+            let value = match value {
+                Value::Table(library) if library != self.lua.globals() => {
+                    let copy = self.lua.create_table()?;
+                    for pair in library.pairs::<Value, Value>() {
+                        let (key, value) = pair?;
+                        copy.raw_set(key, value)?;
+                    }
+                    Value::Table(copy)
+                }
+                value => value,
+            };
+            environment.raw_set(name, value)?;
+        }
+        environment.raw_set("_G", environment.clone())?;
+        Ok(environment)
+    }
+}
+
+impl std::fmt::Debug for LuaPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LuaPolicy").finish_non_exhaustive()
+    }
+}
+
+/// 読み込んだスクリプトと、読み込んだときのファイルの状態。
+#[derive(Debug, Default)]
+struct ScriptState {
+    /// コンパイルしたスクリプトか、読み込みに失敗したときのエラー。Noneはまだ読み込んでいないことを表す。
+    policy: Option<Result<LuaPolicy, String>>,
+    /// 読み込んだときのファイルの更新時刻。
+    modified: Option<SystemTime>,
+    /// 最後にファイルの更新時刻を確かめた時刻。
+    checked_at: Option<Instant>,
+}
+
+/// `call script`で呼び出すLuaのスクリプト。
+/// コンパイルしたスクリプトは、同じPolicyActionを複製したすべてのピアで共有する。
+/// 設定を読み込み直すと、スクリプトも読み込み直す。
+/// 比較やハッシュにはスクリプトのパスだけを使う。
+#[derive(Debug, Clone)]
+pub struct Script {
+    path: PathBuf,
+    state: Arc<Mutex<ScriptState>>,
+}
+
+impl Script {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            state: Default::default(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// スクリプトにrouteを渡し、その結果を返す。スクリプトをまだ読み込んでいなければ読み込む。
+    /// スクリプトがエラーになった場合や、命令数の上限を超えた場合、応答を読み取れなかった場合はエラーを返す。
+    /// 読み込みに失敗した場合はそのエラーを覚えておき、ファイルが変わるまではルートごとに読み込み直さない。
+    pub fn evaluate(&self, route: &RibEntry) -> Result<Verdict> {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .checked_at
+            .is_none_or(|checked_at| checked_at.elapsed() >= SCRIPT_CHECK_INTERVAL)
+        {
+            let modified = std::fs::metadata(&self.path)
+                .and_then(|metadata| metadata.modified())
+                .ok();
+            if state.policy.is_none() || modified != state.modified {
+                state.policy = Some(LuaPolicy::load(&self.path).map_err(|e| format!("{:#}", e)));
+                state.modified = modified;
+            }
+            state.checked_at = Some(Instant::now());
+        }
+        let result = match state.policy.as_ref() {
+            Some(Ok(policy)) => policy.evaluate(route),
+            Some(Err(e)) => Err(anyhow::anyhow!("{}", e)),
+            None => unreachable!(),
+        };
+        result.context(format!(
+            "スクリプト{:?}でルートを評価できませんでした。",
+            self.path
+        ))
+    }
+}
+
+impl PartialEq for Script {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+
+impl Eq for Script {}
+
+impl Hash for Script {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.path.hash(state);
+    }
+}
+
+impl PartialOrd for Script {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Script {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.path.cmp(&other.path)
+    }
+}
+
+/// スクリプトに渡すルートのテーブル。fromはルートを学習したピアで、自分が広告するルートではnilとなる。
+fn route_to_table<'lua>(lua: &'lua Lua, route: &RibEntry) -> mlua::Result<Table<'lua>> {
+    let as_path: Vec<u32> = match route.as_path() {
+        Some(AsPath::AsSequence(as_numbers)) => as_numbers.iter().map(|&a| a.into()).collect(),
+        Some(AsPath::AsSet(as_numbers)) => as_numbers.iter().map(|&a| a.into()).collect(),
+        None => vec![],
+    };
+    let communities: Vec<String> = route.communities().iter().map(|c| c.to_string()).collect();
+    let path_attributes: Vec<String> = route
+        .path_attributes
+        .iter()
+        .map(format_path_attribute)
+        .collect();
+    let table = lua.create_table()?;
+    table.set("prefix", (*route.network_address).to_string())?;
+    table.set("from", route.provenance.map(|p| p.peer.to_string()))?;
+    table.set("as_path", as_path)?;
+    table.set("next_hop", route.next_hop().map(|n| n.to_string()))?;
+    table.set("local_pref", route.local_pref())?;
+    table.set("communities", communities)?;
+    table.set("weight", route.weight)?;
+    table.set("path_attributes", path_attributes)?;
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route() -> RibEntry {
+        RibEntry::for_test("10.100.220.0/24")
+            .with_as_path(&[64513, 64514])
+            .with_next_hop("127.0.0.2")
+            .with_communities(vec!["64512:666".parse().unwrap()])
+            .learned_from("127.0.0.2")
+    }
+
+    #[test]
+    fn route_is_passed_to_script_as_table() {
+        let lua = Lua::new();
+        lua.globals()
+            .set("route", route_to_table(&lua, &route()).unwrap())
+            .unwrap();
+        let fields: String = lua
+            .load(
+                "route.prefix .. ' ' .. route.from .. ' ' .. table.concat(route.as_path, ',') .. ' ' \
+                 .. route.next_hop .. ' ' .. tostring(route.local_pref) .. ' ' \
+                 .. table.concat(route.communities, ',') .. ' ' .. route.weight .. ' ' \
+                 .. table.concat(route.path_attributes, ',')",
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(
+            fields,
+            "10.100.220.0/24 127.0.0.2 64513,64514 127.0.0.2 nil 64512:666 0 \
+             origin=Igp,as-path=[64513 64514],next-hop=127.0.0.2,communities=[64512:666]"
+        );
+    }
+
+    #[test]
+    fn verdict_is_parsed_from_script_response() {
+        assert_eq!("accept".parse::<Verdict>().unwrap(), Verdict::Accept);
+        assert_eq!("reject\r".parse::<Verdict>().unwrap(), Verdict::Reject);
+        assert_eq!(
+            "set local-pref 200; set next-hop blackhole"
+                .parse::<Verdict>()
+                .unwrap(),
+            Verdict::Modify(vec![
+                PolicyAction::SetLocalPref(200),
+                PolicyAction::SetNextHop(crate::fib::BLACKHOLE_NEXT_HOP),
+            ])
+        );
+        assert!("call script /bin/true".parse::<Verdict>().is_err());
+        assert!("maybe".parse::<Verdict>().is_err());
+    }
+
+    /// nameのスクリプトにbodyを書き込み、パスを返す。
+    fn write_script(name: &str, body: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("howbgp-script-{}-{}", std::process::id(), name));
+        std::fs::write(&path, body).unwrap();
+        path
+    }
+
+    #[test]
+    fn routes_are_evaluated_without_state_left_by_previous_routes() {
+        // communityに64512:666が付いたルートを拒否し、ほかはLOCAL_PREFを評価した回数にする。
+        // グローバル変数もライブラリのテーブルもルートごとに作り直すため、常に1になる。
+        let path = write_script(
+            "policy.lua",
+            "evaluated = (evaluated or 0) + (string.evaluated or 0)\n\
+             function policy(route)\n\
+             \x20 evaluated = evaluated + 1\n\
+             \x20 string.evaluated = evaluated\n\
+             \x20 for _, community in ipairs(route.communities) do\n\
+             \x20   if community == '64512:666' then return 'reject' end\n\
+             \x20 end\n\
+             \x20 return 'set local-pref ' .. evaluated\n\
+             end\n",
+        );
+        let script = Script::new(path.clone());
+
+        assert_eq!(script.evaluate(&route()).unwrap(), Verdict::Reject);
+        let mut other = route();
+        Arc::make_mut(&mut other.path_attributes).pop();
+        assert_eq!(
+            script.clone().evaluate(&other).unwrap(),
+            Verdict::Modify(vec![PolicyAction::SetLocalPref(1)])
+        );
+        let missing = Script::new(path.with_file_name("howbgp-missing-script.lua"));
+        assert!(missing.evaluate(&route()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn script_exceeding_instruction_limit_is_stopped() {
+        let path = write_script(
+            "loop.lua",
+            "function policy(route)\n\
+             \x20 if route.weight == 0 then while true do end end\n\
+             \x20 return 'accept'\n\
+             end\n",
+        );
+        let script = Script::new(path.clone());
+
+        // 無限ループしても評価を打ち切り、次のルートは同じスクリプトで評価する。
+        assert!(script.evaluate(&route()).is_err());
+        assert_eq!(
+            script.evaluate(&route().with_weight(100)).unwrap(),
+            Verdict::Accept
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn failed_script_is_not_loaded_again_until_file_changes() {
+        let path = write_script("broken.lua", "function policy(route\n");
+        let script = Script::new(path.clone());
+        assert!(script.evaluate(&route()).is_err());
+
+        std::fs::write(&path, "function policy(route) return 'accept' end\n").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        // 確かめる間隔が経つまでは、読み込みに失敗したときのエラーを返す。
+        assert!(script.evaluate(&route()).is_err());
+        script.state.lock().unwrap().checked_at = None;
+        assert_eq!(script.evaluate(&route()).unwrap(), Verdict::Accept);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn script_cannot_catch_instruction_limit_with_pcall() {
+        let path = write_script(
+            "pcall.lua",
+            "function policy(route)\n\
+             \x20 while true do pcall(function() while true do end end) end\n\
+             end\n",
+        );
+        assert!(Script::new(path.clone()).evaluate(&route()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn script_cannot_use_os_or_io() {
+        let path = write_script(
+            "os.lua",
+            "function policy(route)\n\
+             \x20 os.execute('true')\n\
+             \x20 return 'accept'\n\
+             end\n",
+        );
+        assert!(Script::new(path.clone()).evaluate(&route()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use crate::config::Config;
+use crate::connection::InMemoryTransport;
+use crate::peer::Peer;
+use crate::routing::LocRib;
+use crate::state::State;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// TCP Connectionやカーネルのルーティングテーブルを使わずに、
+/// インメモリのConnectionでつながった2つのPeerを決まった順番で1ステップずつ動かすためのドライバ。
+/// sleepを挟まずにPeerの状態遷移をテストするために使う。
+pub struct Simulation {
+    pub local: Peer,
+    pub remote: Peer,
+}
+
+impl Simulation {
+    pub fn new(local_config: Config, remote_config: Config) -> Self {
+        let (local_transport, remote_transport) = InMemoryTransport::pair();
+        let mut local = Peer::new(local_config, Arc::new(Mutex::new(LocRib::empty())));
+        let mut remote = Peer::new(remote_config, Arc::new(Mutex::new(LocRib::empty())));
+        local.set_transport(Arc::new(local_transport));
+        remote.set_transport(Arc::new(remote_transport));
+        Self { local, remote }
+    }
+
+    pub fn start(&mut self) {
+        self.local.start();
+        self.remote.start();
+    }
+
+    /// local, remoteの順番にPeerを1ステップずつ進める。
+    pub async fn step(&mut self) {
+        self.local.next().await;
+        self.remote.next().await;
+    }
+
+    /// 両方のPeerが指定した状態になるまで、最大max_steps回ステップを進める。
+    /// 指定した状態になったらtrueを返す。
+    pub async fn run_until_both_in(&mut self, state: State, max_steps: usize) -> bool {
+        for _ in 0..max_steps {
+            if self.local.state() == state && self.remote.state() == state {
+                return true;
+            }
+            self.step().await;
+        }
+        self.local.state() == state && self.remote.state() == state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::notification::{open_message_error, ErrorCode};
+
+    fn simulation() -> Simulation {
+        let local_config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let remote_config = "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
+        Simulation::new(local_config, remote_config)
+    }
+
+    #[tokio::test]
+    async fn peers_transition_to_connect_state_in_one_step() {
+        let mut simulation = simulation();
+        simulation.start();
+        simulation.step().await;
+
+        assert_eq!(simulation.local.state(), State::Connect);
+        assert_eq!(simulation.remote.state(), State::Connect);
+    }
+
+    #[tokio::test]
+    async fn peer_resets_session_when_remote_as_does_not_match() {
+        let local_config = "64512 127.0.0.1 64599 127.0.0.2 active".parse().unwrap();
+        let remote_config = "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
+        let mut simulation = Simulation::new(local_config, remote_config);
+        simulation.start();
+        for _ in 0..5 {
+            simulation.step().await;
+        }
+
+        assert_eq!(simulation.local.state(), State::Idle);
+        let remote_statistics = simulation.remote.statistics();
+        let notification = remote_statistics
+            .lock()
+            .await
+            .last_notification_received
+            .clone()
+            .unwrap();
+        assert_eq!(notification.error_code(), ErrorCode::OpenMessageError);
+        assert_eq!(
+            notification.error_subcode(),
+            open_message_error::BAD_PEER_AS
+        );
+    }
+
+    #[tokio::test]
+    async fn peer_with_wildcard_remote_as_records_as_from_open() {
+        let local_config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let remote_config = "64513 127.0.0.2 any 127.0.0.1 passive".parse().unwrap();
+        let mut simulation = Simulation::new(local_config, remote_config);
+        assert_eq!(simulation.remote.remote_as(), None);
+
+        simulation.start();
+        assert!(simulation.run_until_both_in(State::Established, 10).await);
+        assert_eq!(simulation.remote.remote_as(), Some(64512.into()));
+    }
+
+    #[tokio::test]
+    async fn peer_returns_to_idle_when_remote_closes_connection() {
+        let mut simulation = simulation();
+        simulation.start();
+        assert!(simulation.run_until_both_in(State::Established, 10).await);
+
+        let Simulation { mut local, remote } = simulation;
+        drop(remote);
+        for _ in 0..5 {
+            local.next().await;
+        }
+        assert_eq!(local.state(), State::Idle);
+    }
+
+    #[tokio::test]
+    async fn remote_receives_shutdown_communication_when_local_stops() {
+        let mut simulation = simulation();
+        simulation.start();
+        assert!(simulation.run_until_both_in(State::Established, 10).await);
+
+        simulation.local.stop(Some("maintenance".to_owned()));
+        assert!(simulation.run_until_both_in(State::Idle, 10).await);
+
+        let remote_statistics = simulation.remote.statistics();
+        let notification = remote_statistics
+            .lock()
+            .await
+            .last_notification_received
+            .clone()
+            .unwrap();
+        assert_eq!(notification.error_code(), ErrorCode::Cease);
+        assert_eq!(
+            notification.shutdown_communication(),
+            Some("maintenance".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn peers_transition_to_established_state_without_sockets() {
+        let mut simulation = simulation();
+        simulation.start();
+
+        assert!(simulation.run_until_both_in(State::Established, 10).await);
+    }
+}
@@ -0,0 +1,200 @@
+use crate::history::{StateHistory, StateTransition};
+use crate::packets::message::Message;
+use crate::packets::notification::NotificationMessage;
+use crate::packets::update::UpdateMessage;
+use crate::state::State;
+use std::time::{Duration, Instant, SystemTime};
+
+/// ピアごとの統計情報。
+/// Peerが更新し、コントロールAPIから参照される。
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct PeerStatistics {
+    pub state: State,
+    pub messages_sent: MessageCounters,
+    pub messages_received: MessageCounters,
+    /// 受信したUpdateMessageのNLRIのうち、AdjRibInに受け入れたprefixの数。
+    pub prefixes_accepted: u64,
+    /// 受信したUpdateMessageのNLRIのうち、フィルタなどで破棄したprefixの数。
+    pub prefixes_rejected: u64,
+    /// 送信したUpdateMessageのNLRIに含めたprefixの数。
+    pub prefixes_advertised: u64,
+    pub fsm_transitions: u64,
+    pub last_notification_sent: Option<NotificationMessage>,
+    pub last_notification_received: Option<NotificationMessage>,
+    /// Established状態に遷移した時刻。Established状態でない場合はNone。
+    pub established_at: Option<Instant>,
+    pub state_history: StateHistory,
+    /// Peerのキューに溜まっていて、まだ処理していないEventの数。
+    pub queued_events: usize,
+    pub outbound_queue: OutboundQueueStatistics,
+    /// コントロールAPIでセッションを無効にしているか。
+    pub disabled: bool,
+    /// AdjRibInのルートの数が、max-prefixの警告の閾値以上であるか。
+    pub max_prefix_warning: bool,
+    /// 現在のセッションで、ピアからEnd-of-RIBを受信したか。update-delayを早く終えるために使う。
+    pub end_of_rib_received: bool,
+}
+
+/// ピアへの送信キューの状態。送信が遅いピアを見つけるために使う。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct OutboundQueueStatistics {
+    /// 書き込みタスクに渡して、まだ送信していないデータの数。
+    pub depth: usize,
+    /// キューが満杯だったため、空くのを待ったか、AdjRibOutの送信を先送りした回数。
+    pub stalls: u64,
+    /// 書き込みタスクが終了していたため、送信できずに捨てたデータの数。
+    pub drops: u64,
+}
+
+impl Default for PeerStatistics {
+    fn default() -> Self {
+        Self {
+            state: State::Idle,
+            messages_sent: Default::default(),
+            messages_received: Default::default(),
+            prefixes_accepted: 0,
+            prefixes_rejected: 0,
+            prefixes_advertised: 0,
+            fsm_transitions: 0,
+            last_notification_sent: None,
+            last_notification_received: None,
+            established_at: None,
+            state_history: StateHistory::default(),
+            queued_events: 0,
+            outbound_queue: OutboundQueueStatistics::default(),
+            disabled: false,
+            max_prefix_warning: false,
+            end_of_rib_received: false,
+        }
+    }
+}
+
+impl PeerStatistics {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_state_history_size(size: usize) -> Self {
+        Self {
+            state_history: StateHistory::new(size),
+            ..Default::default()
+        }
+    }
+
+    pub fn record_sent(&mut self, message: &Message) {
+        self.messages_sent.count(message);
+        match message {
+            Message::Update(update) => {
+                self.prefixes_advertised +=
+                    update.network_layer_reachability_information().len() as u64
+            }
+            Message::Notification(notification) => {
+                self.last_notification_sent = Some(notification.clone())
+            }
+            _ => (),
+        }
+    }
+
+    /// Update Groupでまとめて送信したUpdateMessageを記録する。
+    pub fn record_sent_updates(&mut self, updates: &[UpdateMessage]) {
+        for update in updates {
+            self.messages_sent.update += 1;
+            self.prefixes_advertised +=
+                update.network_layer_reachability_information().len() as u64;
+        }
+    }
+
+    pub fn record_received(&mut self, message: &Message) {
+        self.messages_received.count(message);
+        if let Message::Notification(notification) = message {
+            self.last_notification_received = Some(notification.clone());
+        }
+    }
+
+    pub fn record_transition(&mut self, state: State, event: &'static str, error: Option<String>) {
+        self.state_history.push(StateTransition {
+            timestamp: SystemTime::now(),
+            old_state: self.state,
+            new_state: state,
+            event,
+            error,
+        });
+        self.fsm_transitions += 1;
+        self.established_at = match state {
+            State::Established => Some(Instant::now()),
+            _ => None,
+        };
+        if state != State::Established {
+            self.end_of_rib_received = false;
+        }
+        self.state = state;
+    }
+
+    /// Established状態になってからの経過時間。
+    pub fn uptime(&self) -> Option<Duration> {
+        self.established_at.map(|t| t.elapsed())
+    }
+}
+
+/// メッセージのTypeごとの送受信数。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct MessageCounters {
+    pub open: u64,
+    pub update: u64,
+    pub notification: u64,
+    pub keepalive: u64,
+}
+
+impl MessageCounters {
+    fn count(&mut self, message: &Message) {
+        match message {
+            Message::Open(_) => self.open += 1,
+            Message::Update(_) => self.update += 1,
+            Message::Notification(_) => self.notification += 1,
+            Message::Keepalive(_) => self.keepalive += 1,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.open + self.update + self.notification + self.keepalive
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::notification::ErrorCode;
+    use bytes::BytesMut;
+
+    #[test]
+    fn count_sent_and_received_messages_by_type() {
+        let mut statistics = PeerStatistics::new();
+        statistics.record_sent(&Message::new_keepalive());
+        statistics.record_sent(&Message::new_keepalive());
+        statistics.record_received(&Message::new_notification(
+            ErrorCode::Cease,
+            2,
+            BytesMut::new(),
+        ));
+
+        assert_eq!(statistics.messages_sent.keepalive, 2);
+        assert_eq!(statistics.messages_sent.total(), 2);
+        assert_eq!(statistics.messages_received.notification, 1);
+        assert_eq!(
+            statistics
+                .last_notification_received
+                .map(|n| n.error_code()),
+            Some(ErrorCode::Cease)
+        );
+    }
+
+    #[test]
+    fn uptime_is_measured_only_while_established() {
+        let mut statistics = PeerStatistics::new();
+        statistics.record_transition(State::Established, "KeepAliveMsg", None);
+        assert!(statistics.uptime().is_some());
+        statistics.record_transition(State::Idle, "ManualStop", None);
+        assert!(statistics.uptime().is_none());
+        assert_eq!(statistics.fsm_transitions, 2);
+    }
+}
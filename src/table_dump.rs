@@ -0,0 +1,199 @@
+use crate::config::{Config, RemoteAs};
+use crate::log_error;
+use crate::mrt::{MrtPeer, TableDump};
+use crate::routing::{AdjRibIn, LocRib};
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+/// RouteViewsと同じく、2時間ごとにテーブルダンプを書き出す。
+pub const DEFAULT_MRT_DUMP_INTERVAL: u64 = 7200;
+/// 2時間ごとに書き出した場合の、1日分のファイル数。
+pub const DEFAULT_MRT_DUMP_FILES: usize = 12;
+
+const LOC_RIB_FILE_PREFIX: &str = "rib";
+const ADJ_RIB_IN_FILE_PREFIX: &str = "adj-rib-in";
+
+/// LocRibと各ピアのAdjRibInを、一定間隔でTABLE_DUMP_V2形式のMRTファイルに書き出す。
+/// ファイル名にはダンプした時刻を含め、保持するファイル数を超えた古いファイルは削除する。
+#[derive(Debug)]
+pub struct TableDumpScheduler {
+    directory: PathBuf,
+    interval: Duration,
+    max_files: usize,
+    /// LocRibのダンプでは、自分自身をルートを持つピアとして扱う。
+    local: MrtPeer,
+    loc_rib: Arc<Mutex<LocRib>>,
+    adj_rib_ins: Vec<(MrtPeer, Arc<Mutex<AdjRibIn>>)>,
+}
+
+impl TableDumpScheduler {
+    /// configのmrt-dump-dirにLocRibを書き出すスケジューラを作成する。
+    /// mrt-dump-dirが設定されていない場合はNoneを返す。
+    pub fn new(config: &Config, loc_rib: Arc<Mutex<LocRib>>) -> Option<Self> {
+        Some(Self {
+            directory: config.mrt_dump_dir.clone()?,
+            interval: Duration::from_secs(config.mrt_dump_interval),
+            max_files: config.mrt_dump_files,
+            local: MrtPeer {
+                bgp_id: config.local_ip,
+                ip: config.local_ip,
+                as_number: config.local_as,
+            },
+            loc_rib,
+            adj_rib_ins: vec![],
+        })
+    }
+
+    /// configのピアのAdjRibInもダンプに含める。
+    /// 対向機器のBGP Identifierは保持していないため、ピアのIPで代用する。
+    /// remote-asにAS番号を指定していないピアは、AS番号を0とする。
+    pub fn add_adj_rib_in(&mut self, config: &Config, adj_rib_in: Arc<Mutex<AdjRibIn>>) {
+        let as_number = match config.remote_as {
+            RemoteAs::Number(as_number) => as_number,
+            _ => 0.into(),
+        };
+        let peer = MrtPeer {
+            bgp_id: config.remote_ip,
+            ip: config.remote_ip,
+            as_number,
+        };
+        self.adj_rib_ins.push((peer, adj_rib_in));
+    }
+
+    /// intervalごとにdumpを呼び出し続ける。
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as u32)
+                .unwrap_or(0);
+            if let Err(e) = self.dump(timestamp).await {
+                log_error!("MRTのテーブルダンプを書き出せませんでした。{:?}", e);
+            }
+        }
+    }
+
+    /// 現在のLocRib(とAdjRibIn)をファイルに書き出し、書き出したファイルのパスを返す。
+    pub async fn dump(&self, timestamp: u32) -> Result<Vec<PathBuf>> {
+        let mut loc_rib_dump = TableDump::new(self.local.bgp_id, "loc-rib");
+        let peer_index = loc_rib_dump.add_peer(self.local);
+        for route in self.loc_rib.lock().await.iter() {
+            loc_rib_dump.add_route(peer_index, route);
+        }
+        let mut paths = vec![self.write(LOC_RIB_FILE_PREFIX, &loc_rib_dump, timestamp)?];
+
+        if !self.adj_rib_ins.is_empty() {
+            let mut adj_rib_in_dump = TableDump::new(self.local.bgp_id, "adj-rib-in");
+            for (peer, adj_rib_in) in &self.adj_rib_ins {
+                let peer_index = adj_rib_in_dump.add_peer(*peer);
+                for route in adj_rib_in.lock().await.iter() {
+                    adj_rib_in_dump.add_route(peer_index, route);
+                }
+            }
+            paths.push(self.write(ADJ_RIB_IN_FILE_PREFIX, &adj_rib_in_dump, timestamp)?);
+        }
+        Ok(paths)
+    }
+
+    fn write(&self, prefix: &str, dump: &TableDump, timestamp: u32) -> Result<PathBuf> {
+        // 時刻の桁数をそろえ、ファイル名の順番が書き出した順番と一致するようにする。
+        let path = self
+            .directory
+            .join(format!("{}.{:010}.mrt", prefix, timestamp));
+        std::fs::write(&path, dump.to_bytes(timestamp))
+            .context(format!("{:?}に書き込めませんでした。", path))?;
+        self.rotate(prefix)?;
+        Ok(path)
+    }
+
+    /// prefixから始まるファイルのうち、新しいものからmax_files個だけを残す。
+    fn rotate(&self, prefix: &str) -> Result<()> {
+        if self.max_files == 0 {
+            return Ok(());
+        }
+        let mut files = dump_files(&self.directory, prefix)?;
+        files.sort();
+        let excess = files.len().saturating_sub(self.max_files);
+        for file in &files[..excess] {
+            std::fs::remove_file(file).context(format!("{:?}を削除できませんでした。", file))?;
+        }
+        Ok(())
+    }
+}
+
+fn dump_files(directory: &Path, prefix: &str) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in
+        std::fs::read_dir(directory).context(format!("{:?}を読み込めませんでした。", directory))?
+    {
+        let path = entry?.path();
+        let is_dump_file = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.starts_with(&format!("{}.", prefix)) && n.ends_with(".mrt"));
+        if is_dump_file {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mrt::{MrtReader, MrtRecord, TABLE_DUMP_V2};
+    use crate::routing::RibEntry;
+
+    #[tokio::test]
+    async fn dump_writes_table_dump_v2_files_and_rotates_old_ones() {
+        let directory = std::env::temp_dir().join(format!("howbgp-mrt-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let config: Config = format!(
+            "64512 127.0.0.1 64513 127.0.0.2 active mrt-dump-dir {} mrt-dump-files 2 \
+             mrt-dump-adj-rib-in",
+            directory.display()
+        )
+        .parse()
+        .unwrap();
+        let route = RibEntry::for_test("10.100.220.0/24").with_next_hop("127.0.0.2");
+        let adj_rib_in = Arc::new(Mutex::new(AdjRibIn::from(vec![route.clone()])));
+        let mut loc_rib = LocRib::empty();
+        loc_rib.install_from_adj_rib_in(&mut AdjRibIn::from(vec![route]));
+        let mut scheduler =
+            TableDumpScheduler::new(&config, Arc::new(Mutex::new(loc_rib))).unwrap();
+        scheduler.add_adj_rib_in(&config, adj_rib_in);
+
+        for timestamp in [1650000000, 1650007200, 1650014400] {
+            scheduler.dump(timestamp).await.unwrap();
+        }
+
+        let mut files = dump_files(&directory, LOC_RIB_FILE_PREFIX).unwrap();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                directory.join("rib.1650007200.mrt"),
+                directory.join("rib.1650014400.mrt")
+            ]
+        );
+        assert_eq!(
+            dump_files(&directory, ADJ_RIB_IN_FILE_PREFIX)
+                .unwrap()
+                .len(),
+            2
+        );
+        let records: Vec<MrtRecord> = MrtReader::new(std::fs::File::open(&files[1]).unwrap())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        // PEER_INDEX_TABLEと、1つのprefixのRIB_IPV4_UNICAST。
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|r| r.type_ == TABLE_DUMP_V2));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,199 @@
+//! codecの型のproptestのStrategy。
+//! 生成する値はすべてbytes列に変換して読み取ると元に戻るため、
+//! codecに追加した型のround-tripのproperty testに使える。
+//!
+//! ```ignore
+//! use how_to_create_bgp::test_utils::message;
+//! use proptest::prelude::*;
+//!
+//! proptest! {
+//!     #[test]
+//!     fn my_test(message in message()) {
+//!         // ...
+//!     }
+//! }
+//! ```
+
+use crate::bgp_type::{AutonomousSystemNumber, HoldTime, Ipv4Network, MAXIMUM_PREFIX_LENGTH};
+use crate::packets::keepalive::KeepaliveMessage;
+use crate::packets::message::Message;
+use crate::packets::notification::{ErrorCode, NotificationMessage};
+use crate::packets::open::OpenMessage;
+use crate::packets::update::UpdateMessage;
+use crate::path_attribute::{AsPath, Community, Origin, PathAttribute, PrefixSid};
+use proptest::collection::vec;
+use proptest::prelude::*;
+use std::net::Ipv4Addr;
+
+/// 生成するAS番号、COMMUNITYの最大の数。
+pub const MAXIMUM_GENERATED_ITEMS: usize = 32;
+
+/// 生成するUpdateMessageのNLRI、Withdrawn Routesの最大の数。
+/// Path Attributeと合わせても、メッセージの最大長を超えない数にしている。
+pub const MAXIMUM_GENERATED_ROUTES: usize = 16;
+
+pub fn ipv4_addr() -> impl Strategy<Value = Ipv4Addr> {
+    any::<u32>().prop_map(Ipv4Addr::from)
+}
+
+/// ホスト部が0のネットワーク。NLRIから読み取ったネットワークと同じ形になる。
+pub fn ipv4_network() -> impl Strategy<Value = Ipv4Network> {
+    (any::<u32>(), 0..=MAXIMUM_PREFIX_LENGTH).prop_map(|(addr, prefix)| {
+        let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+        ipnetwork::Ipv4Network::new(Ipv4Addr::from(addr & mask), prefix)
+            .expect("prefix長は0..=32の範囲で生成している。")
+            .into()
+    })
+}
+
+pub fn autonomous_system_number() -> impl Strategy<Value = AutonomousSystemNumber> {
+    any::<u16>().prop_map(|a| AutonomousSystemNumber::from(u32::from(a)))
+}
+
+/// RFC 4271で受け入れられるHold Time(0か3秒以上)。
+pub fn hold_time() -> impl Strategy<Value = HoldTime> {
+    prop_oneof![Just(0), 3..=u16::MAX].prop_map(HoldTime::from)
+}
+
+pub fn origin() -> impl Strategy<Value = Origin> {
+    prop_oneof![
+        Just(Origin::Igp),
+        Just(Origin::Egp),
+        Just(Origin::Incomplete)
+    ]
+}
+
+pub fn as_path() -> impl Strategy<Value = AsPath> {
+    let as_numbers = || vec(autonomous_system_number(), 0..=MAXIMUM_GENERATED_ITEMS);
+    prop_oneof![
+        as_numbers().prop_map(AsPath::AsSequence),
+        as_numbers().prop_map(|as_numbers| AsPath::AsSet(as_numbers.into_iter().collect())),
+    ]
+}
+
+pub fn communities() -> impl Strategy<Value = Vec<Community>> {
+    vec(
+        any::<u32>().prop_map(Community),
+        0..=MAXIMUM_GENERATED_ITEMS,
+    )
+}
+
+/// 対応しているPath Attributeのいずれか。DontKnowは生成しない。
+pub fn path_attribute() -> impl Strategy<Value = PathAttribute> {
+    prop_oneof![
+        origin().prop_map(PathAttribute::Origin),
+        as_path().prop_map(PathAttribute::AsPath),
+        ipv4_addr().prop_map(PathAttribute::NextHop),
+        any::<u32>().prop_map(PathAttribute::LocalPref),
+        communities().prop_map(PathAttribute::Communities),
+        any::<u64>().prop_map(PathAttribute::Aigp),
+        any::<u32>().prop_map(|label_index| PathAttribute::PrefixSid(PrefixSid::new(label_index))),
+    ]
+}
+
+/// ピアから受信するルートのPath Attributeの組。
+/// ORIGIN、AS_PATH、NEXT_HOPを必ず含み、それ以外のPath Attributeは0個か1個含む。
+pub fn path_attributes() -> impl Strategy<Value = Vec<PathAttribute>> {
+    (
+        (origin(), as_path(), ipv4_addr()),
+        vec(communities(), 0..=1),
+        vec(any::<u64>(), 0..=1),
+        vec(any::<u32>(), 0..=1),
+    )
+        .prop_map(
+            |((origin, as_path, next_hop), communities, aigp, prefix_sid)| {
+                let mut path_attributes = vec![
+                    PathAttribute::Origin(origin),
+                    PathAttribute::AsPath(as_path),
+                    PathAttribute::NextHop(next_hop),
+                ];
+                path_attributes.extend(communities.into_iter().map(PathAttribute::Communities));
+                path_attributes.extend(aigp.into_iter().map(PathAttribute::Aigp));
+                path_attributes.extend(
+                    prefix_sid
+                        .into_iter()
+                        .map(|label_index| PathAttribute::PrefixSid(PrefixSid::new(label_index))),
+                );
+                path_attributes
+            },
+        )
+}
+
+pub fn open_message() -> impl Strategy<Value = OpenMessage> {
+    (autonomous_system_number(), ipv4_addr(), hold_time()).prop_map(
+        |(my_as_number, my_ip_addr, hold_time)| {
+            OpenMessage::new(my_as_number, my_ip_addr, hold_time)
+        },
+    )
+}
+
+/// Path Attributeの組を持つ広告と、経路の取り消しの両方を含みうるUpdateMessage。
+pub fn update_message() -> impl Strategy<Value = UpdateMessage> {
+    (
+        path_attributes(),
+        vec(ipv4_network(), 0..=MAXIMUM_GENERATED_ROUTES),
+        vec(ipv4_network(), 0..=MAXIMUM_GENERATED_ROUTES),
+    )
+        .prop_map(|(path_attributes, nlri, withdrawn_routes)| {
+            // NLRIがなければPath Attributeは送らない。
+            let path_attributes = if nlri.is_empty() {
+                vec![]
+            } else {
+                path_attributes
+            };
+            UpdateMessage::new(path_attributes, nlri, withdrawn_routes)
+        })
+}
+
+pub fn notification_message() -> impl Strategy<Value = NotificationMessage> {
+    (any::<u8>(), any::<u8>(), vec(any::<u8>(), 0..=64)).prop_map(
+        |(error_code, error_subcode, data)| {
+            NotificationMessage::new(ErrorCode::from(error_code), error_subcode, data)
+        },
+    )
+}
+
+pub fn message() -> impl Strategy<Value = Message> {
+    prop_oneof![
+        open_message().prop_map(Message::Open),
+        Just(Message::Keepalive(KeepaliveMessage::new())),
+        update_message().prop_map(Message::Update),
+        notification_message().prop_map(Message::Notification),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::header::MAXIMUM_MESSAGE_LENGTH;
+    use bytes::BytesMut;
+
+    proptest! {
+        #[test]
+        fn ipv4_network_round_trips_through_nlri(
+            networks in vec(ipv4_network(), 0..=MAXIMUM_GENERATED_ROUTES)
+        ) {
+            let mut bytes = BytesMut::new();
+            for network in &networks {
+                bytes.extend_from_slice(&BytesMut::from(network));
+            }
+            prop_assert_eq!(Ipv4Network::from_u8_slice(&bytes).unwrap(), networks);
+        }
+
+        #[test]
+        fn path_attribute_round_trips(path_attribute in path_attribute()) {
+            let bytes = BytesMut::from(&path_attribute);
+            prop_assert_eq!(
+                PathAttribute::from_u8_slice(&bytes).unwrap(),
+                vec![path_attribute]
+            );
+        }
+
+        #[test]
+        fn message_round_trips(message in message()) {
+            let bytes: BytesMut = message.clone().into();
+            prop_assert!(bytes.len() <= MAXIMUM_MESSAGE_LENGTH as usize);
+            prop_assert_eq!(Message::try_from(bytes).unwrap(), message);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use crate::clock::{Clock, SystemClock};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// start_with_jitterで、タイマーの間隔に掛ける係数の下限。
+/// RFC 4271の10章で推奨されている、75%から100%の範囲にする。
+const MINIMUM_JITTER_FACTOR: f64 = 0.75;
+
+/// Peer::nextが呼ばれるたびに期限切れかどうかを確認するタイマー。
+#[derive(Debug, Clone)]
+pub struct Timer {
+    deadline: Option<Instant>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// clockの時刻で期限切れを判定するタイマーを作成する。
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            deadline: None,
+            clock,
+        }
+    }
+
+    pub fn start(&mut self, duration: Duration) {
+        self.deadline = Some(self.clock.now() + duration);
+    }
+
+    /// durationの75%から100%の間のランダムな時間で開始する。
+    /// 同じ設定の多数のピアが、同じタイミングでまとめてMessageを送信しないようにする。
+    pub fn start_with_jitter(&mut self, duration: Duration) {
+        self.start(jittered(duration));
+    }
+
+    pub fn stop(&mut self) {
+        self.deadline = None;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.deadline.is_some()
+    }
+
+    /// 期限切れであればタイマーを止めてtrueを返す。
+    /// 1回の期限切れに対してtrueを返すのは1度だけである。
+    pub fn expired(&mut self) -> bool {
+        match self.deadline {
+            Some(deadline) if deadline <= self.clock.now() => {
+                self.deadline = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// durationにMINIMUM_JITTER_FACTORから1までのランダムな係数を掛ける。
+fn jittered(duration: Duration) -> Duration {
+    // 暗号論的に安全な乱数である必要はないため、HashMapのランダムなキーで代用する。
+    let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+    duration.mul_f64(MINIMUM_JITTER_FACTOR + (1.0 - MINIMUM_JITTER_FACTOR) * random)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn timer_expires_when_mock_clock_advances() {
+        let clock = Arc::new(MockClock::new());
+        let mut timer = Timer::with_clock(clock.clone());
+        timer.start(Duration::from_secs(90));
+
+        clock.advance(Duration::from_secs(89));
+        assert!(!timer.expired());
+        clock.advance(Duration::from_secs(1));
+        assert!(timer.expired());
+    }
+
+    #[test]
+    fn jittered_timer_expires_between_75_and_100_percent_of_duration() {
+        for _ in 0..100 {
+            let clock = Arc::new(MockClock::new());
+            let mut timer = Timer::with_clock(clock.clone());
+            timer.start_with_jitter(Duration::from_secs(60));
+
+            clock.advance(Duration::from_secs(45) - Duration::from_millis(1));
+            assert!(!timer.expired());
+            clock.advance(Duration::from_secs(15) + Duration::from_millis(1));
+            assert!(timer.expired());
+        }
+    }
+
+    #[test]
+    fn timer_expires_only_once() {
+        let mut timer = Timer::new();
+        assert!(!timer.expired());
+        timer.start(Duration::from_secs(0));
+        assert!(timer.is_running());
+        assert!(timer.expired());
+        assert!(!timer.expired());
+        assert!(!timer.is_running());
+    }
+
+    #[test]
+    fn timer_does_not_expire_before_deadline() {
+        let mut timer = Timer::new();
+        timer.start(Duration::from_secs(60));
+        assert!(!timer.expired());
+        timer.stop();
+        assert!(!timer.is_running());
+    }
+}
@@ -0,0 +1,218 @@
+#[cfg(feature = "api-tls")]
+use anyhow::Context;
+use anyhow::Result;
+#[cfg(feature = "api-tls")]
+use rustls::pki_types::pem::PemObject;
+#[cfg(feature = "api-tls")]
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+#[cfg(feature = "api-tls")]
+use rustls::server::WebPkiClientVerifier;
+#[cfg(feature = "api-tls")]
+use rustls::{RootCertStore, ServerConfig};
+use std::path::Path;
+#[cfg(feature = "api-tls")]
+use std::sync::Arc;
+#[cfg(feature = "api-tls")]
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+#[cfg(feature = "api-tls")]
+use tokio_rustls::TlsAcceptor;
+
+/// クライアントがTLSのハンドシェイクを終えるまで待つ最大の時間。
+#[cfg(feature = "api-tls")]
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// HTTPのAPIをTLSで提供するための設定。
+#[cfg(feature = "api-tls")]
+pub type TlsConfig = Arc<ServerConfig>;
+
+/// api-tls featureを無効にしてビルドした場合は、TLSの設定を作れない。
+#[cfg(not(feature = "api-tls"))]
+#[derive(Debug, Clone)]
+pub enum TlsConfig {}
+
+/// HTTPのAPIのクライアントとの接続。TLSを設定していればTLSの接続、なければTCPの接続である。
+pub trait HttpStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> HttpStream for T {}
+
+/// HTTPのAPIをTLSで提供するための設定を、PEMの証明書と秘密鍵のファイルから作る。
+/// client_caを指定した場合は、そのCAが署名した証明書を提示したクライアントだけを受け付ける(mTLS)。
+#[cfg(feature = "api-tls")]
+pub fn server_config(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<TlsConfig> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .context(format!(
+            "証明書のファイル{:?}を読み込めませんでした。",
+            cert
+        ))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .context(format!("秘密鍵のファイル{:?}を読み込めませんでした。", key))?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()?;
+    let builder = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in CertificateDer::pem_file_iter(path).context(format!(
+                "CAの証明書のファイル{:?}を読み込めませんでした。",
+                path
+            ))? {
+                roots.add(ca?)?;
+            }
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(certs, key)
+        .context("証明書と秘密鍵を設定できませんでした。")?;
+    Ok(Arc::new(config))
+}
+
+#[cfg(not(feature = "api-tls"))]
+pub fn server_config(_cert: &Path, _key: &Path, _client_ca: Option<&Path>) -> Result<TlsConfig> {
+    Err(anyhow::anyhow!(
+        "HTTPのAPIをTLSで提供するには、api-tls featureを有効にしてビルドする必要があります。"
+    ))
+}
+
+/// configがあれば、streamでTLSのハンドシェイクを行う。
+pub(crate) async fn accept(
+    config: Option<&TlsConfig>,
+    stream: TcpStream,
+) -> Result<Box<dyn HttpStream>> {
+    match config {
+        #[cfg(feature = "api-tls")]
+        Some(config) => {
+            let acceptor = TlsAcceptor::from(Arc::clone(config));
+            let stream = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+                .await
+                .context("TLSのハンドシェイクが時間内に終わりませんでした。")?
+                .context("TLSのハンドシェイクに失敗しました。")?;
+            Ok(Box::new(stream))
+        }
+        #[cfg(not(feature = "api-tls"))]
+        Some(config) => match *config {},
+        None => Ok(Box::new(stream)),
+    }
+}
+
+#[cfg(all(test, feature = "api-tls"))]
+pub(crate) mod tests {
+    use super::*;
+    use rustls::ClientConfig;
+    use std::path::PathBuf;
+    use tokio_rustls::TlsConnector;
+
+    /// テストで使う、自己署名のCAと、CAが署名したサーバとクライアントの証明書。
+    pub(crate) struct TestPki {
+        pub(crate) ca: PathBuf,
+        pub(crate) server_cert: PathBuf,
+        pub(crate) server_key: PathBuf,
+        client_cert: CertificateDer<'static>,
+        client_key: Vec<u8>,
+        ca_cert: CertificateDer<'static>,
+    }
+
+    impl TestPki {
+        pub(crate) fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("howbgp-tls-{}-{}", std::process::id(), name));
+            std::fs::create_dir_all(&dir).unwrap();
+            let mut params = rcgen::CertificateParams::new(vec![]).unwrap();
+            params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+            let ca_key = rcgen::KeyPair::generate().unwrap();
+            let ca = params.self_signed(&ca_key).unwrap();
+            let issue = |name: &str| {
+                let key = rcgen::KeyPair::generate().unwrap();
+                let cert = rcgen::CertificateParams::new(vec![name.to_owned()])
+                    .unwrap()
+                    .signed_by(&key, &ca, &ca_key)
+                    .unwrap();
+                (cert, key)
+            };
+            let (server, server_key) = issue("localhost");
+            let (client, client_key) = issue("client");
+            let write = |file: &str, contents: String| {
+                let path = dir.join(file);
+                std::fs::write(&path, contents).unwrap();
+                path
+            };
+            Self {
+                ca: write("ca.pem", ca.pem()),
+                server_cert: write("server.pem", server.pem()),
+                server_key: write("server.key", server_key.serialize_pem()),
+                client_cert: client.der().clone(),
+                client_key: client_key.serialize_der(),
+                ca_cert: ca.der().clone(),
+            }
+        }
+
+        /// サーバの証明書をCAで検証するクライアント。with_certならばクライアントの証明書を提示する。
+        pub(crate) fn connector(&self, with_cert: bool) -> TlsConnector {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.ca_cert.clone()).unwrap();
+            let builder = ClientConfig::builder_with_provider(Arc::new(
+                rustls::crypto::ring::default_provider(),
+            ))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+            let config = if with_cert {
+                let key = PrivateKeyDer::try_from(self.client_key.clone()).unwrap();
+                builder
+                    .with_client_auth_cert(vec![self.client_cert.clone()], key)
+                    .unwrap()
+            } else {
+                builder.with_no_client_auth()
+            };
+            TlsConnector::from(Arc::new(config))
+        }
+    }
+
+    impl Drop for TestPki {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(self.ca.parent().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn mutual_tls_requires_client_certificate_signed_by_ca() {
+        let pki = TestPki::new("mtls");
+        let config = server_config(&pki.server_cert, &pki.server_key, Some(&pki.ca)).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut results = vec![];
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                results.push(accept(Some(&config), stream).await.is_ok());
+            }
+            results
+        });
+
+        for with_cert in [true, false] {
+            let stream = TcpStream::connect(address).await.unwrap();
+            let name = "localhost".try_into().unwrap();
+            // TLS 1.3ではクライアントの証明書の検証はクライアントのハンドシェイクの後に行われるため、
+            // 結果はサーバ側で確認する。
+            let _ = pki.connector(with_cert).connect(name, stream).await;
+        }
+        assert_eq!(server.await.unwrap(), vec![true, false]);
+    }
+
+    #[test]
+    fn acceptor_reports_missing_certificate() {
+        let e = server_config(
+            Path::new("/nonexistent/cert.pem"),
+            Path::new("/nonexistent/key.pem"),
+            None,
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("/nonexistent/cert.pem"));
+    }
+}
@@ -0,0 +1,177 @@
+use crate::event::Event;
+use crate::log_error;
+use crate::monitor::{json_string, update_to_json};
+use crate::routing::Ipv4Network;
+use crate::state::State;
+use anyhow::{Context, Result};
+use std::fmt::{self, Write as _};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// FSMのEvent、状態遷移、RIBの変更を、1行に1つのJSONとしてファイルに追記するトレース。
+/// 不具合の報告を受けたときに、発生した順序どおりにオフラインで解析、再現するために使う。
+/// すべてのピアで同じTracerを共有し、各行にはピアのIPアドレスと時刻を含める。
+#[derive(Clone)]
+pub struct Tracer(Arc<Mutex<Box<dyn Write + Send>>>);
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Tracer").finish()
+    }
+}
+
+impl Tracer {
+    /// pathのファイルに追記するTracerを作成する。ファイルがなければ作成する。
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context(format!("トレースのファイル{:?}を開けませんでした。", path))?;
+        Ok(Self::new(Box::new(file)))
+    }
+
+    pub fn new(writer: Box<dyn Write + Send>) -> Self {
+        Self(Arc::new(Mutex::new(writer)))
+    }
+
+    /// Peerが処理するEvent。UpdateMessageの場合は、再現できるように内容も含める。
+    pub(crate) fn event(&self, peer: Ipv4Addr, event: &Event) {
+        let mut fields = format!(r#""event":"{}""#, event.name());
+        match event {
+            Event::UpdateMsg(update) => write!(fields, ",{}", update_to_json(update)).unwrap(),
+            Event::NotifMsg(notification)
+            | Event::BgpHeaderErr(notification)
+            | Event::BgpOpenMsgErr(notification)
+            | Event::UpdateMsgErr(notification) => write!(
+                fields,
+                r#","description":{}"#,
+                json_string(&notification.to_string())
+            )
+            .unwrap(),
+            _ => (),
+        }
+        self.write(peer, "event", &fields);
+    }
+
+    pub(crate) fn transition(
+        &self,
+        peer: Ipv4Addr,
+        old_state: State,
+        new_state: State,
+        event: &Event,
+    ) {
+        self.write(
+            peer,
+            "transition",
+            &format!(
+                r#""old_state":"{:?}","new_state":"{:?}","event":"{}""#,
+                old_state,
+                new_state,
+                event.name()
+            ),
+        );
+    }
+
+    /// ribに対するルートの追加と削除。ribは`adj-rib-in`か`loc-rib`である。
+    pub(crate) fn rib_change(
+        &self,
+        peer: Ipv4Addr,
+        rib: &str,
+        announced: &[Ipv4Network],
+        withdrawn: &[Ipv4Network],
+    ) {
+        let networks = |networks: &[Ipv4Network]| {
+            networks
+                .iter()
+                .map(|n| format!(r#""{}""#, **n))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        self.write(
+            peer,
+            "rib",
+            &format!(
+                r#""rib":"{}","announced":[{}],"withdrawn":[{}]"#,
+                rib,
+                networks(announced),
+                networks(withdrawn)
+            ),
+        );
+    }
+
+    /// AdjRibInのルートをLocRibに反映した後のLocRibのversionとルートの数。
+    pub(crate) fn loc_rib_updated(&self, peer: Ipv4Addr, version: u64, routes: usize) {
+        self.write(
+            peer,
+            "loc-rib-updated",
+            &format!(r#""version":{},"routes":{}"#, version, routes),
+        );
+    }
+
+    fn write(&self, peer: Ipv4Addr, type_: &str, fields: &str) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let line = format!(
+            r#"{{"timestamp":{:.6},"peer":"{}","type":"{}",{}}}"#,
+            timestamp, peer, type_, fields
+        );
+        let mut writer = self.0.lock().unwrap();
+        if let Err(e) = writeln!(writer, "{}", line).and_then(|_| writer.flush()) {
+            log_error!("トレースを書き込めませんでした。{:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 書き込まれた内容をテストから読み出せるWrite。
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn each_record_is_written_as_one_json_line() {
+        let buffer = SharedBuffer::default();
+        let tracer = Tracer::new(Box::new(buffer.clone()));
+        let peer = "127.0.0.2".parse().unwrap();
+
+        tracer.event(peer, &Event::ManualStart);
+        tracer.transition(peer, State::Idle, State::Connect, &Event::ManualStart);
+        tracer.rib_change(
+            peer,
+            "adj-rib-in",
+            &["10.100.220.0/24".parse().unwrap()],
+            &[],
+        );
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with(r#"{"timestamp":"#));
+        assert!(lines[0].ends_with(r#""peer":"127.0.0.2","type":"event","event":"ManualStart"}"#));
+        assert!(lines[1].ends_with(
+            r#""type":"transition","old_state":"Idle","new_state":"Connect","event":"ManualStart"}"#
+        ));
+        assert!(lines[2].ends_with(
+            r#""type":"rib","rib":"adj-rib-in","announced":["10.100.220.0/24"],"withdrawn":[]}"#
+        ));
+    }
+}
@@ -0,0 +1,120 @@
+use crate::clock::{Clock, SystemClock};
+use crate::control::NeighborList;
+use crate::log_info;
+use crate::routing::LocRib;
+use crate::statistics::PeerStatistics;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// update-delayを終えられるかを確認する間隔。
+const UPDATE_DELAY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 無効にしているピアを除いたすべてのピアから、End-of-RIBを受信したか。
+/// 対象のピアが1つもない場合は、delayが経過するまで待つためfalseを返す。
+fn all_peers_sent_end_of_rib(statistics: &[PeerStatistics]) -> bool {
+    let mut enabled = statistics.iter().filter(|s| !s.disabled).peekable();
+    enabled.peek().is_some() && enabled.all(|s| s.end_of_rib_received)
+}
+
+/// 起動直後にLocRibを読み取り専用にしている間、delayが経過するか、すべてのピアから
+/// End-of-RIBを受信するまで待つ。その後に読み取り専用を解除し、すべてのピアに
+/// 受信済みのルートを広告させ、カーネルのルーティングテーブルに書き込ませる。
+/// 収束していない途中のルーティングテーブルを広告しないために使う。
+pub async fn run(delay: Duration, loc_rib: Arc<Mutex<LocRib>>, neighbors: NeighborList) {
+    run_with_clock(delay, loc_rib, neighbors, Arc::new(SystemClock)).await
+}
+
+/// runと同じだが、delayの経過をclockの時刻で判断する。
+pub(crate) async fn run_with_clock(
+    delay: Duration,
+    loc_rib: Arc<Mutex<LocRib>>,
+    neighbors: NeighborList,
+    clock: Arc<dyn Clock>,
+) {
+    let started = clock.now();
+    let reason = loop {
+        let mut statistics = vec![];
+        for neighbor in neighbors.snapshot() {
+            statistics.push(neighbor.statistics.lock().await.clone());
+        }
+        if all_peers_sent_end_of_rib(&statistics) {
+            break "すべてのピアからEnd-of-RIBを受信しました";
+        }
+        let elapsed = clock.now().saturating_duration_since(started);
+        if elapsed >= delay {
+            break "update-delayが経過しました";
+        }
+        tokio::time::sleep(UPDATE_DELAY_CHECK_INTERVAL.min(delay - elapsed)).await;
+    };
+    let routes = {
+        let mut loc_rib = loc_rib.lock().await;
+        loc_rib.set_read_only(false);
+        loc_rib.len()
+    };
+    for neighbor in &neighbors.snapshot() {
+        neighbor.handle.notify_loc_rib_changed();
+    }
+    log_info!("{}。{}個のルートの広告を開始します。", reason, routes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn end_of_rib_is_required_from_all_enabled_peers() {
+        let statistics = |end_of_rib_received: bool, disabled: bool| PeerStatistics {
+            end_of_rib_received,
+            disabled,
+            ..Default::default()
+        };
+        assert!(!all_peers_sent_end_of_rib(&[]));
+        assert!(!all_peers_sent_end_of_rib(&[
+            statistics(true, false),
+            statistics(false, false),
+        ]));
+        assert!(all_peers_sent_end_of_rib(&[
+            statistics(true, false),
+            statistics(false, true),
+        ]));
+        assert!(!all_peers_sent_end_of_rib(&[statistics(false, true)]));
+    }
+
+    #[tokio::test]
+    async fn read_only_period_ends_when_delay_expires() {
+        let mut loc_rib = LocRib::empty();
+        loc_rib.set_read_only(true);
+        let loc_rib = Arc::new(Mutex::new(loc_rib));
+        run(
+            Duration::from_millis(10),
+            Arc::clone(&loc_rib),
+            NeighborList::default(),
+        )
+        .await;
+        assert!(!loc_rib.lock().await.is_read_only());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delay_is_measured_with_injected_clock() {
+        let mut loc_rib = LocRib::empty();
+        loc_rib.set_read_only(true);
+        let loc_rib = Arc::new(Mutex::new(loc_rib));
+        let clock = Arc::new(MockClock::new());
+        let task = tokio::spawn(run_with_clock(
+            Duration::from_secs(60),
+            Arc::clone(&loc_rib),
+            NeighborList::default(),
+            clock.clone(),
+        ));
+
+        // 時計が進まない間は、tokioの時間が経過しても読み取り専用のままである。
+        tokio::time::sleep(Duration::from_secs(120)).await;
+        assert!(loc_rib.lock().await.is_read_only());
+
+        clock.advance(Duration::from_secs(60));
+        task.await.unwrap();
+        assert!(!loc_rib.lock().await.is_read_only());
+    }
+}
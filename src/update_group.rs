@@ -0,0 +1,364 @@
+use crate::bgp_type::{AddressFamily, AutonomousSystemNumber};
+use crate::config::Config;
+use crate::packets::update::UpdateMessage;
+use crate::policy::{self, Policy};
+use crate::routing::{AdjRibOut, Ipv4Network, LocRib, RibEntry};
+use bytes::{Bytes, BytesMut};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// OpenMessageの交換で決まり、AdjRibOutの内容やエンコードを左右するセッションの設定。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, Default)]
+pub struct NegotiatedCapabilities {
+    /// 自分と対向機器の両方が4オクテットAS Capabilityを広告したか。
+    /// trueの場合は、AS_PATHのAS番号を4オクテットで表して送る。
+    pub four_octet_as: bool,
+    /// 自分と対向機器の両方がLLGRを広告したか。
+    /// falseの場合は、LLGRでstaleとして保持しているルートを広告しない。
+    pub llgr: bool,
+}
+
+/// AdjRibOutの内容を決める設定の組。
+/// これが同じピア同士は同じUpdateMessageを受け取るため、同じUpdate Groupに入れる。
+#[derive(PartialEq, Eq, Debug, Clone, Hash)]
+pub struct UpdateGroupKey {
+    local_as: AutonomousSystemNumber,
+    local_ip: Ipv4Addr,
+    export_policy: Policy,
+    /// eBGPとiBGPではNEXT_HOPの扱いが異なる。
+    ebgp: bool,
+    next_hop_self: bool,
+    shared_subnet: Option<Ipv4Network>,
+    aigp_session: bool,
+    ignore_well_known_communities: bool,
+    /// 交換するAFI/SAFI。設定した順序によらず同じ組になるように並べ替えておく。
+    address_families: Vec<AddressFamily>,
+    negotiated: NegotiatedCapabilities,
+}
+
+impl UpdateGroupKey {
+    pub fn new(config: &Config, negotiated: NegotiatedCapabilities) -> Self {
+        let mut address_families = config.address_families.clone();
+        address_families.sort();
+        Self {
+            local_as: config.local_as,
+            local_ip: config.local_ip,
+            export_policy: config.export_policy.clone(),
+            ebgp: config.is_ebgp(),
+            next_hop_self: config.next_hop_self,
+            shared_subnet: config.shared_subnet,
+            aigp_session: config.aigp_session(),
+            ignore_well_known_communities: config.ignore_well_known_communities,
+            address_families,
+            negotiated,
+        }
+    }
+}
+
+/// 同じexport policyを持つピアの間で、AdjRibOutとUpdateMessageの生成を共有する。
+/// LocRibが変わるたびに1度だけUpdateMessageを作ってエンコードし、
+/// 各ピアはエンコード済みのbytesをそのまま自分のConnectionに送信する。
+#[derive(Debug)]
+pub struct UpdateGroup {
+    config: Config,
+    negotiated: NegotiatedCapabilities,
+    /// adj_rib_outを作成したときのLocRibのversion。
+    loc_rib_version: Option<u64>,
+    adj_rib_out: AdjRibOut,
+    updates: Vec<UpdateMessage>,
+    bytes: Bytes,
+    /// refreshでadj_rib_outのルートが変わるたびに増える値。
+    generation: u64,
+    /// IPv4 Unicastのネットワークごとの、adj_rib_outのルートが最後に変わったgeneration。
+    changed_in: HashMap<Ipv4Network, u64>,
+    /// generationごとの、そのgenerationで最後に変わったネットワーク。
+    /// 各ピアは前回広告したgenerationより後に変わったネットワークだけを送る。
+    changes: BTreeMap<u64, BTreeSet<Ipv4Network>>,
+}
+
+impl UpdateGroup {
+    pub fn new(config: &Config, negotiated: NegotiatedCapabilities) -> Self {
+        Self {
+            config: config.clone(),
+            negotiated,
+            loc_rib_version: None,
+            adj_rib_out: AdjRibOut::new(),
+            updates: vec![],
+            bytes: Bytes::new(),
+            generation: 0,
+            changed_in: HashMap::new(),
+            changes: BTreeMap::new(),
+        }
+    }
+
+    /// LocRibが前回から変わっている場合だけ、AdjRibOutとUpdateMessageを作り直す。
+    /// 作り直した場合はtrueを返す。
+    pub fn refresh(&mut self, loc_rib: &LocRib) -> bool {
+        let Some(pending) = self.prepare_refresh(loc_rib) else {
+            return false;
+        };
+        let routes =
+            AdjRibOut::apply_export_policy(pending.routes, &self.config, pending.in_maintenance);
+        self.install(pending.loc_rib_version, routes);
+        true
+    }
+
+    /// LocRibが前回から変わっている場合だけ、export policyを適用する前のルートを取り出す。
+    /// LocRibのロックを放してからcomplete_refreshに渡し、AdjRibOutを作り直す。
+    pub fn prepare_refresh(&self, loc_rib: &LocRib) -> Option<PendingRefresh> {
+        if self.loc_rib_version == Some(loc_rib.version()) {
+            return None;
+        }
+        Some(PendingRefresh {
+            loc_rib_version: loc_rib.version(),
+            in_maintenance: loc_rib.in_maintenance(),
+            routes: AdjRibOut::export_candidates(loc_rib, &self.config, self.negotiated),
+        })
+    }
+
+    /// pendingにexport policyを適用し、AdjRibOutとUpdateMessageを作り直す。
+    /// export policyがスクリプトを呼び出す場合は、ブロッキング処理用のスレッドで適用する。
+    pub async fn complete_refresh(&mut self, pending: PendingRefresh) {
+        let routes = if self.config.export_policy.calls_script() {
+            let config = self.config.clone();
+            policy::apply_blocking(move || {
+                AdjRibOut::apply_export_policy(pending.routes, &config, pending.in_maintenance)
+            })
+            .await
+        } else {
+            AdjRibOut::apply_export_policy(pending.routes, &self.config, pending.in_maintenance)
+        };
+        self.install(pending.loc_rib_version, routes);
+    }
+
+    /// export policyを適用したroutesでAdjRibOutを置き換え、UpdateMessageを作り直す。
+    fn install(&mut self, loc_rib_version: u64, routes: Vec<(AddressFamily, RibEntry)>) {
+        let previous = std::mem::take(&mut self.adj_rib_out);
+        self.adj_rib_out.install_exported(routes);
+        self.record_changes(self.adj_rib_out.changed_networks(&previous));
+        self.updates = Vec::from(&self.adj_rib_out)
+            .into_iter()
+            .map(|update| update.with_four_octet_as(self.negotiated.four_octet_as))
+            .collect();
+        let mut bytes = BytesMut::new();
+        for update in &self.updates {
+            let update_bytes: BytesMut = update.clone().into();
+            bytes.extend_from_slice(&update_bytes);
+        }
+        self.bytes = bytes.freeze();
+        self.loc_rib_version = Some(loc_rib_version);
+    }
+
+    /// networksが今のgenerationで変わったことを記録する。
+    fn record_changes(&mut self, networks: Vec<Ipv4Network>) {
+        if networks.is_empty() {
+            return;
+        }
+        self.generation += 1;
+        for network in networks {
+            if let Some(old) = self.changed_in.insert(network, self.generation) {
+                if let Some(changes) = self.changes.get_mut(&old) {
+                    changes.remove(&network);
+                    if changes.is_empty() {
+                        self.changes.remove(&old);
+                    }
+                }
+            }
+            self.changes
+                .entry(self.generation)
+                .or_default()
+                .insert(network);
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// generationより後に、adj_rib_outのルートが変わったIPv4 Unicastのネットワーク。
+    pub fn changed_since(&self, generation: u64) -> Vec<Ipv4Network> {
+        self.changes
+            .range(generation + 1..)
+            .flat_map(|(_, networks)| networks.iter().copied())
+            .collect()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn negotiated(&self) -> NegotiatedCapabilities {
+        self.negotiated
+    }
+
+    pub fn adj_rib_out(&self) -> &AdjRibOut {
+        &self.adj_rib_out
+    }
+
+    pub fn updates(&self) -> &[UpdateMessage] {
+        &self.updates
+    }
+
+    /// updatesをエンコードして連結したbytes。
+    pub fn bytes(&self) -> Bytes {
+        self.bytes.clone()
+    }
+}
+
+/// LocRibから取り出した、export policyを適用する前のAdjRibOutのルート。
+#[derive(Debug)]
+pub struct PendingRefresh {
+    loc_rib_version: u64,
+    in_maintenance: bool,
+    routes: Vec<(AddressFamily, RibEntry)>,
+}
+
+/// UpdateGroupKeyごとのUpdate Groupの一覧。
+/// ピアはセッションごとに交渉した内容でUpdate Groupに参加し直すため、すべてのピアで共有する。
+#[derive(Debug, Default, Clone)]
+pub struct UpdateGroups(Arc<std::sync::Mutex<HashMap<UpdateGroupKey, Arc<Mutex<UpdateGroup>>>>>);
+
+impl UpdateGroups {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// configとセッションで交渉した内容に対応するUpdate Groupを返す。
+    /// まだ存在しない場合は作成する。
+    pub fn join(
+        &self,
+        config: &Config,
+        negotiated: NegotiatedCapabilities,
+    ) -> Arc<Mutex<UpdateGroup>> {
+        let mut groups = self.0.lock().unwrap();
+        let group = groups
+            .entry(UpdateGroupKey::new(config, negotiated))
+            .or_insert_with(|| Arc::new(Mutex::new(UpdateGroup::new(config, negotiated))));
+        Arc::clone(group)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routing::{AdjRibIn, RibEntry};
+
+    fn loc_rib_with_route() -> LocRib {
+        let mut loc_rib = LocRib::empty();
+        loc_rib.install_from_adj_rib_in(&mut AdjRibIn::from(vec![RibEntry::for_test(
+            "10.100.220.0/24",
+        )]));
+        loc_rib
+    }
+
+    #[test]
+    fn peers_with_same_export_policy_share_update_group() {
+        let a: Config = "64512 10.200.100.2 64513 10.200.100.3 active"
+            .parse()
+            .unwrap();
+        let b: Config = "64512 10.200.100.2 64514 10.200.100.4 active"
+            .parse()
+            .unwrap();
+        let c: Config =
+            "64512 10.200.100.2 64515 10.200.100.5 active export set as-path prepend 64512"
+                .parse()
+                .unwrap();
+        let groups = UpdateGroups::new();
+        assert!(groups.is_empty());
+
+        let negotiated = NegotiatedCapabilities::default();
+        let group_a = groups.join(&a, negotiated);
+        let group_b = groups.join(&b, negotiated);
+        let group_c = groups.join(&c, negotiated);
+        let group_d = groups.join(
+            &b,
+            NegotiatedCapabilities {
+                four_octet_as: true,
+                ..Default::default()
+            },
+        );
+
+        assert!(Arc::ptr_eq(&group_a, &group_b));
+        assert!(!Arc::ptr_eq(&group_a, &group_c));
+        assert!(!Arc::ptr_eq(&group_b, &group_d));
+        assert_eq!(groups.len(), 3);
+    }
+
+    #[test]
+    fn update_group_encodes_updates_once_per_loc_rib_version() {
+        let config: Config = "64512 10.200.100.2 64513 10.200.100.3 active"
+            .parse()
+            .unwrap();
+        let mut group = UpdateGroup::new(&config, NegotiatedCapabilities::default());
+        let loc_rib = loc_rib_with_route();
+
+        assert!(group.refresh(&loc_rib));
+        assert!(!group.refresh(&loc_rib));
+
+        let expected: BytesMut = group.updates()[0].clone().into();
+        assert_eq!(group.updates().len(), 1);
+        assert_eq!(&group.bytes()[..], &expected[..]);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "scripting")]
+    async fn export_policy_calling_script_is_applied_after_loc_rib_is_released() {
+        let path = std::env::temp_dir().join(format!("howbgp-export-{}.lua", std::process::id()));
+        std::fs::write(
+            &path,
+            "function policy(route) return 'set local-pref 300' end\n",
+        )
+        .unwrap();
+        let config: Config = format!(
+            "64512 10.200.100.2 64512 10.200.100.3 active export call script {}",
+            path.display()
+        )
+        .parse()
+        .unwrap();
+        assert!(config.export_policy.calls_script());
+        let mut group = UpdateGroup::new(&config, NegotiatedCapabilities::default());
+        let loc_rib = Mutex::new(loc_rib_with_route());
+
+        let pending = group.prepare_refresh(&*loc_rib.lock().await).unwrap();
+        // LocRibのロックを放した後でexport policyを適用する。
+        let locked = loc_rib.lock().await;
+        group.complete_refresh(pending).await;
+
+        let network = "10.100.220.0/24".parse().unwrap();
+        let route = group.adj_rib_out().get(&network).unwrap();
+        assert_eq!(route.local_pref(), Some(300));
+        assert!(group.prepare_refresh(&locked).is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn update_group_records_only_networks_changed_since_generation() {
+        let config: Config = "64512 10.200.100.2 64513 10.200.100.3 active"
+            .parse()
+            .unwrap();
+        let mut group = UpdateGroup::new(&config, NegotiatedCapabilities::default());
+        let mut loc_rib = loc_rib_with_route();
+        group.refresh(&loc_rib);
+        let advertised = group.generation();
+
+        let network = "10.100.230.0/24".parse().unwrap();
+        loc_rib.install_from_adj_rib_in(&mut AdjRibIn::from(vec![RibEntry::for_test(
+            "10.100.230.0/24",
+        )]));
+        group.refresh(&loc_rib);
+
+        assert_eq!(group.changed_since(0).len(), 2);
+        assert_eq!(group.changed_since(advertised), vec![network]);
+        assert!(group.changed_since(group.generation()).is_empty());
+    }
+}
@@ -0,0 +1,468 @@
+use crate::control::NeighborList;
+use crate::control_auth::{self, ControlTokens, Role};
+use crate::health::Response;
+use crate::log_error;
+use crate::path_attribute::Community;
+use crate::routing::{Ipv4Network, LocRib};
+use crate::tls;
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+
+/// リクエストの本文として受け付ける最大のバイト数。
+const MAXIMUM_BODY_LENGTH: usize = 4096;
+
+/// ロードバランサなどから、サービスのVIPのホストルートを広告、取り消しするためのHTTPのAPI。
+///
+/// - `GET /vips`: 広告しているVIPの一覧を返す。
+/// - `POST /vips`: 本文の`<prefix> [community ...]`のルートを広告する。
+/// - `DELETE /vips/<prefix>`: 広告したルートを取り消す。
+///
+/// prefixにアドレスだけを指定した場合は/32のホストルートとする。/32以外のprefixは受け付けない。
+/// ピアから学習したルートと同じprefixは、学習したルートを上書きしないように受け付けない。
+/// 設定のnetworkで広告しているprefixも、VIPの取り消しで設定のルートを消さないように受け付けない。
+/// すべてのリクエストで`Authorization: Bearer <token>`のトークンを確認し、
+/// 広告と取り消しにはread-writeのトークンが必要である。
+/// トークンを平文で送らないように、ループバック以外のアドレスではTLSで提供する。
+#[derive(Debug, Clone)]
+pub struct VipServer {
+    neighbors: NeighborList,
+    loc_rib: Arc<Mutex<LocRib>>,
+    tokens: Arc<ControlTokens>,
+    /// VIPのルートのNEXT_HOP。
+    next_hop: Ipv4Addr,
+    /// すべてのVIPのルートに付けるCommunity。
+    communities: Vec<Community>,
+    /// このAPIで広告しているVIPと、リクエストで指定されたCommunity。
+    vips: Arc<Mutex<BTreeMap<Ipv4Network, Vec<Community>>>>,
+    tls: Option<tls::TlsConfig>,
+}
+
+/// HTTPのリクエストのうち、VipServerが使う部分。
+#[derive(Debug, PartialEq, Eq)]
+struct Request {
+    method: String,
+    path: String,
+    /// `Authorization: Bearer <token>`のトークン。
+    token: Option<String>,
+    body: String,
+}
+
+impl VipServer {
+    pub fn new(
+        neighbors: impl Into<NeighborList>,
+        loc_rib: Arc<Mutex<LocRib>>,
+        tokens: ControlTokens,
+        next_hop: Ipv4Addr,
+    ) -> Self {
+        Self {
+            neighbors: neighbors.into(),
+            loc_rib,
+            tokens: Arc::new(tokens),
+            next_hop,
+            communities: vec![],
+            vips: Arc::new(Mutex::new(BTreeMap::new())),
+            tls: None,
+        }
+    }
+
+    /// すべてのVIPのルートに、リクエストで指定したものに加えてcommunitiesを付ける。
+    pub fn set_communities(&mut self, communities: Vec<Community>) {
+        self.communities = communities;
+    }
+
+    /// リクエストをconfigのTLSの接続で受け付ける。
+    pub fn set_tls(&mut self, config: tls::TlsConfig) {
+        self.tls = Some(config);
+    }
+
+    pub async fn serve(self, address: SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(address)
+            .await
+            .context(format!("{}にbindできませんでした。", address))?;
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                let result = match tls::accept(server.tls.as_ref(), stream).await {
+                    Ok(stream) => server.handle_client(stream).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    log_error!("VIPのAPIのリクエストの処理に失敗しました。{:?}", e);
+                }
+            });
+        }
+    }
+
+    /// 1つのリクエストに応答し、接続を閉じる。
+    async fn handle_client<S>(&self, stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut stream = BufReader::new(stream);
+        let request =
+            tokio::time::timeout(control_auth::REQUEST_TIMEOUT, read_request(&mut stream));
+        let response = match request.await {
+            Ok(Ok(request)) => self.handle_request(&request).await,
+            Ok(Err(e)) => Response::new(400, format!("{:#}\n", e)),
+            Err(_) => Response::new(408, "request timeout\n"),
+        };
+        response.write(stream.get_mut(), false).await
+    }
+
+    async fn handle_request(&self, request: &Request) -> Response {
+        let role = match request.token.as_deref().and_then(|t| self.tokens.role(t)) {
+            Some(role) => role,
+            None => return Response::new(401, "unauthorized\n"),
+        };
+        if request.method != "GET" && role != Role::ReadWrite {
+            return Response::new(403, "permission denied\n");
+        }
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/vips") => Response::new(200, self.list().await),
+            ("POST", "/vips") => self.advertise(&request.body).await,
+            ("DELETE", path) if path.starts_with("/vips/") => {
+                self.withdraw(&path["/vips/".len()..]).await
+            }
+            (_, "/vips") => Response::new(405, "method not allowed\n"),
+            _ => Response::new(404, "not found\n"),
+        }
+    }
+
+    async fn list(&self) -> String {
+        let mut output = String::new();
+        for (network, communities) in self.vips.lock().await.iter() {
+            write!(output, "{}", **network).unwrap();
+            for community in communities {
+                write!(output, " {}", community).unwrap();
+            }
+            output += "\n";
+        }
+        output
+    }
+
+    /// `<prefix> [community ...]`のルートを広告する。すでに広告していればCommunityを置き換える。
+    async fn advertise(&self, body: &str) -> Response {
+        let mut tokens = body.split_whitespace();
+        let network = match tokens.next().map(parse_vip) {
+            Some(Ok(network)) => network,
+            Some(Err(e)) => return Response::new(400, format!("{:#}\n", e)),
+            None => return Response::new(400, "prefix is required\n"),
+        };
+        let communities = match tokens
+            .map(|c| c.parse())
+            .collect::<Result<Vec<Community>, _>>()
+        {
+            Ok(communities) => communities,
+            Err(e) => return Response::new(400, format!("{:#}\n", e)),
+        };
+        let mut all_communities = self.communities.clone();
+        all_communities.extend(communities.iter().copied());
+        // VIPの一覧とLocRibが食い違わないように、LocRibを変更し終えるまでVIPの一覧をロックする。
+        let mut vips = self.vips.lock().await;
+        {
+            let mut loc_rib = self.loc_rib.lock().await;
+            if loc_rib.has_learned_route(&network) {
+                return Response::new(
+                    409,
+                    format!("{} is already learned from a peer\n", *network),
+                );
+            }
+            if !vips.contains_key(&network) && loc_rib.has_originated_route(&network) {
+                return Response::new(
+                    409,
+                    format!("{} is already advertised by the configuration\n", *network),
+                );
+            }
+            loc_rib.originate(network, self.next_hop, all_communities);
+        }
+        vips.insert(network, communities);
+        drop(vips);
+        self.notify_loc_rib_changed();
+        Response::new(201, format!("advertised {}\n", *network))
+    }
+
+    async fn withdraw(&self, prefix: &str) -> Response {
+        let network = match parse_vip(prefix) {
+            Ok(network) => network,
+            Err(e) => return Response::new(400, format!("{:#}\n", e)),
+        };
+        let mut vips = self.vips.lock().await;
+        if vips.remove(&network).is_none() {
+            return Response::new(404, format!("{} is not advertised\n", *network));
+        }
+        self.loc_rib.lock().await.withdraw_originated(&network);
+        drop(vips);
+        self.notify_loc_rib_changed();
+        Response::new(200, format!("withdrawn {}\n", *network))
+    }
+
+    fn notify_loc_rib_changed(&self) {
+        for neighbor in &self.neighbors.snapshot() {
+            neighbor.handle.notify_loc_rib_changed();
+        }
+    }
+}
+
+/// `10.0.0.1/32`か、/32のホストルートとする`10.0.0.1`を読み取る。
+fn parse_vip(s: &str) -> Result<Ipv4Network> {
+    let network: Ipv4Network = s
+        .parse()
+        .context(format!("`{}`をprefixとして読み取れませんでした。", s))?;
+    if network.prefix() != 32 {
+        return Err(anyhow::anyhow!(
+            "VIPには/32のホストルートだけを指定できます。(prefix: {})",
+            *network
+        ));
+    }
+    Ok(network)
+}
+
+/// リクエストの行、ヘッダ、Content-Lengthの長さの本文を読み込む。
+async fn read_request<S>(stream: &mut BufReader<S>) -> Result<Request>
+where
+    S: AsyncRead + Unpin,
+{
+    let request_line = control_auth::read_line(stream).await?;
+    let (method, path) = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        [method, path, ..] => (method.to_owned(), path.to_owned()),
+        _ => return Err(anyhow::anyhow!("invalid request line")),
+    };
+    let mut token = None;
+    let mut content_length = 0;
+    for header in control_auth::read_headers(stream).await? {
+        let (name, value) = match header.split_once(':') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+            None => continue,
+        };
+        match name.as_str() {
+            "authorization" => token = value.strip_prefix("Bearer ").map(|t| t.to_owned()),
+            "content-length" => content_length = value.parse().context("invalid content-length")?,
+            _ => (),
+        }
+    }
+    if content_length > MAXIMUM_BODY_LENGTH {
+        return Err(anyhow::anyhow!("body is too large"));
+    }
+    let mut body = vec![0; content_length];
+    stream.read_exact(&mut body).await?;
+    Ok(Request {
+        method,
+        path,
+        token,
+        body: String::from_utf8(body).context("body is not utf-8")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routing::{AdjRibIn, RibEntry};
+    use tokio::io::AsyncWriteExt;
+
+    fn server() -> VipServer {
+        let tokens = "adm1n read-write\nm0n read-only\n".parse().unwrap();
+        let loc_rib = Arc::new(Mutex::new(LocRib::empty()));
+        let mut server = VipServer::new(vec![], loc_rib, tokens, "10.0.0.1".parse().unwrap());
+        server.set_communities(vec![Community::NO_EXPORT]);
+        server
+    }
+
+    fn request(method: &str, path: &str, token: Option<&str>, body: &str) -> Request {
+        Request {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            token: token.map(|t| t.to_owned()),
+            body: body.to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn vips_are_originated_and_withdrawn_with_communities() {
+        let server = server();
+        let response = server
+            .handle_request(&request(
+                "POST",
+                "/vips",
+                Some("adm1n"),
+                "192.0.2.10 64512:100",
+            ))
+            .await;
+        assert_eq!(response, Response::new(201, "advertised 192.0.2.10/32\n"));
+
+        let network = "192.0.2.10/32".parse().unwrap();
+        {
+            let loc_rib = server.loc_rib.lock().await;
+            let route = loc_rib.get(&network).unwrap();
+            assert_eq!(route.next_hop(), Some("10.0.0.1".parse().unwrap()));
+            assert_eq!(
+                route.communities(),
+                [Community::NO_EXPORT, "64512:100".parse().unwrap()]
+            );
+        }
+        assert_eq!(
+            server
+                .handle_request(&request("GET", "/vips", Some("m0n"), ""))
+                .await,
+            Response::new(200, "192.0.2.10/32 64512:100\n")
+        );
+
+        let response = server
+            .handle_request(&request("DELETE", "/vips/192.0.2.10/32", Some("adm1n"), ""))
+            .await;
+        assert_eq!(response, Response::new(200, "withdrawn 192.0.2.10/32\n"));
+        assert!(server.loc_rib.lock().await.get(&network).is_none());
+        assert_eq!(
+            server
+                .handle_request(&request("DELETE", "/vips/192.0.2.10", Some("adm1n"), ""))
+                .await
+                .status,
+            404
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_require_token_with_role() {
+        let server = server();
+        let post = |token| request("POST", "/vips", token, "192.0.2.10");
+        assert_eq!(server.handle_request(&post(None)).await.status, 401);
+        assert_eq!(
+            server.handle_request(&post(Some("unknown"))).await.status,
+            401
+        );
+        assert_eq!(server.handle_request(&post(Some("m0n"))).await.status, 403);
+        assert_eq!(
+            server
+                .handle_request(&request("POST", "/vips", Some("adm1n"), "192.0.2.0/33"))
+                .await
+                .status,
+            400
+        );
+        assert!(server.loc_rib.lock().await.iter().next().is_none());
+    }
+
+    #[tokio::test]
+    async fn only_host_routes_that_are_not_learned_can_be_advertised() {
+        let server = server();
+        let post = |body| request("POST", "/vips", Some("adm1n"), body);
+        assert_eq!(
+            server.handle_request(&post("192.0.2.0/24")).await.status,
+            400
+        );
+
+        // ピアから学習したルートは、VIPで上書きしない。
+        let learned = RibEntry::for_test("192.0.2.10/32").learned_from("10.0.0.2");
+        server
+            .loc_rib
+            .lock()
+            .await
+            .install_from_adj_rib_in(&mut AdjRibIn::from(vec![learned.clone()]));
+        assert_eq!(server.handle_request(&post("192.0.2.10")).await.status, 409);
+        assert_eq!(
+            server.loc_rib.lock().await.get(&learned.network_address),
+            Some(&learned)
+        );
+        assert_eq!(
+            server
+                .handle_request(&request("GET", "/vips", Some("m0n"), ""))
+                .await,
+            Response::new(200, "")
+        );
+    }
+
+    #[tokio::test]
+    async fn networks_in_config_are_neither_advertised_nor_withdrawn_as_vips() {
+        let server = server();
+        let network = "192.0.2.20/32".parse().unwrap();
+        server
+            .loc_rib
+            .lock()
+            .await
+            .originate(network, "10.0.0.1".parse().unwrap(), vec![]);
+        let configured = server.loc_rib.lock().await.get(&network).cloned();
+
+        let response = server
+            .handle_request(&request("POST", "/vips", Some("adm1n"), "192.0.2.20"))
+            .await;
+        assert_eq!(response.status, 409);
+        let response = server
+            .handle_request(&request("DELETE", "/vips/192.0.2.20", Some("adm1n"), ""))
+            .await;
+        assert_eq!(response.status, 404);
+        assert_eq!(
+            server.loc_rib.lock().await.get(&network).cloned(),
+            configured
+        );
+    }
+
+    #[tokio::test]
+    async fn request_body_is_read_by_content_length() {
+        let server = server();
+        let (mut client, stream) = tokio::io::duplex(1024);
+        let handle = tokio::spawn(async move { server.handle_client(stream).await });
+
+        client
+            .write_all(
+                b"POST /vips HTTP/1.1\r\nAuthorization: Bearer adm1n\r\n\
+                  Content-Length: 10\r\n\r\n192.0.2.10",
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        handle.await.unwrap().unwrap();
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert!(response.ends_with("\r\n\r\nadvertised 192.0.2.10/32\n"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_client_is_disconnected_after_request_timeout() {
+        let server = server();
+        let (mut client, stream) = tokio::io::duplex(1024);
+        let handle = tokio::spawn(async move { server.handle_client(stream).await });
+
+        client.write_all(b"GET /vips HTTP/1.1\r\n").await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        handle.await.unwrap().unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "api-tls")]
+    async fn requests_are_served_over_tls() {
+        let pki = crate::tls::tests::TestPki::new("vip");
+        let mut server = server();
+        server
+            .set_tls(tls::server_config(&pki.server_cert, &pki.server_key, Some(&pki.ca)).unwrap());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        tokio::spawn(server.serve(address));
+
+        let stream = loop {
+            match tokio::net::TcpStream::connect(address).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::task::yield_now().await,
+            }
+        };
+        let mut client = pki
+            .connector(true)
+            .connect("localhost".try_into().unwrap(), stream)
+            .await
+            .unwrap();
+        client
+            .write_all(b"GET /vips HTTP/1.1\r\nAuthorization: Bearer m0n\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+}